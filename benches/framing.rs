@@ -0,0 +1,143 @@
+// Reads a stream of frames the way connections used to be read, with a
+// fresh buffer for every read and every message copied out of it, and
+// then through Framed::read_from, which reads into pooled blocks and hands
+// messages out as slices of them.
+//
+//     cargo bench --bench framing
+
+extern crate bytes;
+extern crate mio;
+extern crate rasputin;
+extern crate time;
+
+use std::io::Cursor;
+
+use bytes::{Buf, ByteBuf, MutBuf, MutByteBuf, alloc};
+use mio::TryRead;
+
+use rasputin::{Codec, Framed};
+use rasputin::codec::{HEADER_LEN, array_to_usize};
+
+const MESSAGES: usize = 200000;
+const ROUNDS: usize = 5;
+
+fn stream(body_len: usize) -> Vec<u8> {
+    let codec = Framed::new();
+    let body = vec![7u8; body_len];
+    let mut res = vec![];
+    for _ in 0..MESSAGES {
+        res.extend_from_slice(codec.encode(ByteBuf::from_slice(&body)).bytes());
+    }
+    res
+}
+
+// How Framed decoded before, less what plain frames don't need: each
+// body read into a buffer of its own as soon as its header was in.
+struct Copying {
+    header: MutByteBuf,
+    msg: Option<MutByteBuf>,
+}
+
+impl Copying {
+    fn decode(&mut self, buf: &mut ByteBuf) -> Vec<ByteBuf> {
+        let mut res = vec![];
+        loop {
+            if self.msg.is_none() {
+                let _ = buf.try_read_buf(&mut self.header);
+                if self.header.remaining() != 0 {
+                    break;
+                }
+                let size = {
+                    let h = self.header.bytes();
+                    array_to_usize([h[4], h[5], h[6], h[7]])
+                };
+                self.msg = unsafe {
+                    let mem = alloc::heap(size.next_power_of_two());
+                    Some(ByteBuf::from_mem_ref(mem,
+                                               size as u32,
+                                               0,
+                                               size as u32)
+                             .flip())
+                };
+            }
+            let mut msg = self.msg.take().unwrap();
+            match buf.try_read_buf(&mut msg) {
+                Ok(Some(_)) if msg.remaining() == 0 => {
+                    self.header.clear();
+                    res.push(msg.flip());
+                }
+                _ => {
+                    self.msg = Some(msg);
+                    break;
+                }
+            }
+        }
+        res
+    }
+}
+
+// What ServerConn::readable did before: 1024 bytes at a time into a new
+// buffer, which was then decoded from.
+fn copying(stream: &[u8]) -> usize {
+    let mut conn = Cursor::new(stream);
+    let mut codec = Copying {
+        header: ByteBuf::mut_with_capacity(HEADER_LEN),
+        msg: None,
+    };
+    let mut received = 0;
+    loop {
+        let mut buf = ByteBuf::mut_with_capacity(1024);
+        match conn.try_read_buf(&mut buf) {
+            Ok(Some(0)) | Ok(None) | Err(_) => break,
+            Ok(Some(_)) => (),
+        }
+        for msg in codec.decode(&mut buf.flip()) {
+            received += msg.remaining();
+        }
+    }
+    received
+}
+
+fn pooled(stream: &[u8]) -> usize {
+    let mut conn = Cursor::new(stream);
+    let mut codec = Framed::new();
+    let mut received = 0;
+    loop {
+        match codec.read_from(&mut conn) {
+            Ok(Some(0)) | Ok(None) | Err(_) => break,
+            Ok(Some(_)) => (),
+        }
+        for msg in codec.take_frames() {
+            received += msg.remaining();
+        }
+    }
+    received
+}
+
+fn bench<F>(name: &str, body_len: usize, stream: &[u8], read: F) -> u64
+    where F: Fn(&[u8]) -> usize
+{
+    let mut best = u64::max_value();
+    for _ in 0..ROUNDS {
+        let start = time::precise_time_ns();
+        assert_eq!(read(stream), MESSAGES * body_len);
+        best = std::cmp::min(best, time::precise_time_ns() - start);
+    }
+    println!("{:>8} {:>6} byte messages: {:>6} ns/msg",
+             name,
+             body_len,
+             best / MESSAGES as u64);
+    best
+}
+
+fn main() {
+    for &body_len in &[64, 512, 4096] {
+        let stream = stream(body_len);
+        let copying = bench("copying", body_len, &stream, copying);
+        let pooled = bench("pooled", body_len, &stream, pooled);
+        println!("{:>8} {:>6} byte messages: {:.2}x",
+                 "speedup",
+                 body_len,
+                 copying as f64 / pooled as f64);
+    }
+}
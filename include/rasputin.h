@@ -0,0 +1,66 @@
+/* The C interface to the rasputin client, as defined in src/ffi.rs.
+ *
+ * Calls return RASPUTIN_OK, RASPUTIN_NOT_FOUND, or RASPUTIN_ERROR, in
+ * which case rasputin_last_error() says what went wrong on the calling
+ * thread.  Values handed back belong to the caller, who frees them with
+ * rasputin_free().  A client may only be used by one thread at a time.
+ */
+#ifndef RASPUTIN_H
+#define RASPUTIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define RASPUTIN_OK 0
+#define RASPUTIN_NOT_FOUND 1
+#define RASPUTIN_ERROR (-1)
+
+typedef struct RasputinClient rasputin_client;
+
+/* Called with each record of a scan; anything but zero stops the scan.
+ * key and value are only good until it returns. */
+typedef int (*rasputin_scan_fn)(void *ctx,
+                                const uint8_t *key, size_t key_len,
+                                const uint8_t *value, size_t value_len);
+
+/* servers is a comma-separated list of host:port client addresses.
+ * Returns NULL on failure. */
+rasputin_client *rasputin_open(const char *servers);
+void rasputin_close(rasputin_client *client);
+
+/* For servers started with a root token. */
+int rasputin_set_token(rasputin_client *client, const char *token);
+
+/* Sets *value to a copy of key's value, to be freed with rasputin_free,
+ * or returns RASPUTIN_NOT_FOUND. */
+int rasputin_get(rasputin_client *client,
+                 const uint8_t *key, size_t key_len,
+                 uint8_t **value, size_t *value_len);
+int rasputin_put(rasputin_client *client,
+                 const uint8_t *key, size_t key_len,
+                 const uint8_t *value, size_t value_len);
+int rasputin_delete(rasputin_client *client,
+                    const uint8_t *key, size_t key_len);
+
+/* Calls f with every record from start up to but not including end, or
+ * to the last key if end is NULL, in order and as of one txid. */
+int rasputin_scan(rasputin_client *client,
+                  const uint8_t *start, size_t start_len,
+                  const uint8_t *end, size_t end_len,
+                  rasputin_scan_fn f, void *ctx);
+
+void rasputin_free(uint8_t *data, size_t len);
+
+/* What went wrong with the last call on this thread to fail, good until
+ * the next one does.  NULL if none has. */
+const char *rasputin_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,299 @@
+extern crate rustc_serialize;
+extern crate docopt;
+extern crate rand;
+extern crate rasputin;
+extern crate time;
+
+use std::net::SocketAddr;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use docopt::Docopt;
+use rand::Rng;
+
+use rasputin::Client;
+use rasputin::server::parse_addr;
+
+static USAGE: &'static str = "
+rasputin-bench - workload generator for rasputin.
+
+Drives a cluster with a mix of reads and writes from several clients at
+once, then reports throughput and latency percentiles for each.
+
+Usage:
+    rasputin-bench --help
+    rasputin-bench [--peers=<peers>] [--token=<token>] [--clients=<n>] \
+                   [--duration=<secs>] [--reads=<percent>] [--keys=<n>] \
+                   [--distribution=<dist>] [--value-size=<bytes>]
+
+Options:
+    --help                          Show this help message.
+    --peers=<host1:port1,...>       List of comma-delimited peers, e.g:
+                                    foo.baz.com:8888,bar.baz.com:8888
+                                    IPv6 hosts go in brackets, e.g:
+                                    [fd00::1]:8888
+    --token=<token>                 Authenticate with <token>.
+    --clients=<n>                   How many clients send requests at once
+                                    [default: 4].
+    --duration=<secs>               How long to run for [default: 10].
+    --reads=<percent>               Percentage of requests that are reads
+                                    [default: 50].
+    --keys=<n>                      How many distinct keys to use
+                                    [default: 10000].
+    --distribution=<dist>           How keys are picked: uniform or zipfian,
+                                    where a few keys get most of the requests
+                                    [default: uniform].
+    --value-size=<bytes>            Size of written values, or a range of
+                                    sizes to pick from, e.g: 100 or 64-4096
+                                    [default: 100].
+";
+
+// YCSB's zipfian constant: the most popular key gets about a tenth of the
+// requests when there are a thousand of them.
+const ZIPF_THETA: f64 = 0.99;
+
+fn main() {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.decode())
+        .unwrap_or_else(|e| e.exit());
+
+    let peers = args.flag_peers
+                    .clone()
+                    .unwrap_or("127.0.0.1:8888".to_string());
+    let peers: Vec<SocketAddr> = peers.split(",")
+        .map(|s| {
+            parse_addr(s).unwrap_or_else(|e| {
+                println!("{}", e);
+                process::exit(1);
+            })
+        })
+        .collect();
+
+    if args.flag_reads > 100 || args.flag_keys == 0 || args.flag_clients == 0 {
+        println!("{}", USAGE);
+        process::exit(1);
+    }
+    let keys = match &*args.flag_distribution {
+        "uniform" => Keys::Uniform(args.flag_keys),
+        "zipfian" => Keys::Zipfian(Zipf::new(args.flag_keys, ZIPF_THETA)),
+        other => {
+            println!("unknown key distribution {}", other);
+            process::exit(1);
+        }
+    };
+    let sizes = parse_sizes(&args.flag_value_size).unwrap_or_else(|e| {
+        println!("{}", e);
+        process::exit(1);
+    });
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut workers = vec![];
+    for _ in 0..args.flag_clients {
+        let mut cli = Client::new(peers.clone(), 1);
+        if let Some(ref token) = args.flag_token {
+            cli.set_token(token);
+        }
+        let keys = keys.clone();
+        let done = done.clone();
+        let reads = args.flag_reads;
+        workers.push(thread::spawn(move || run(cli, keys, reads, sizes, done)));
+    }
+
+    let start = time::precise_time_ns();
+    thread::sleep(Duration::from_secs(args.flag_duration));
+    done.store(true, Ordering::Relaxed);
+
+    let mut total = Stats::new();
+    for worker in workers {
+        match worker.join() {
+            Ok(stats) => total.merge(stats),
+            Err(_) => {
+                println!("a client panicked, and is left out of the results")
+            }
+        }
+    }
+    let secs = (time::precise_time_ns() - start) as f64 / 1e9;
+    println!("{} clients for {:.1}s, {}% reads over {} {} keys",
+             args.flag_clients,
+             secs,
+             args.flag_reads,
+             args.flag_keys,
+             args.flag_distribution);
+    report("reads", &mut total.reads, total.read_errors, secs);
+    report("writes", &mut total.writes, total.write_errors, secs);
+}
+
+// One client's requests, sent one after another until told to stop.
+fn run(mut cli: Client,
+       keys: Keys,
+       reads: u32,
+       sizes: (usize, usize),
+       done: Arc<AtomicBool>)
+       -> Stats {
+    let mut rng = rand::thread_rng();
+    let mut stats = Stats::new();
+    while !done.load(Ordering::Relaxed) {
+        let key = format!("bench{:010}", keys.pick(&mut rng)).into_bytes();
+        let start = time::precise_time_ns();
+        if rng.gen_range(0, 100) < reads {
+            // a key that was never written is still an answer
+            let ok = cli.get(&key).is_ok();
+            stats.reads.push(time::precise_time_ns() - start);
+            stats.read_errors += !ok as usize;
+        } else {
+            let size = rng.gen_range(sizes.0, sizes.1 + 1);
+            let value: Vec<u8> = rng.gen_ascii_chars()
+                                    .take(size)
+                                    .map(|c| c as u8)
+                                    .collect();
+            let ok = cli.set(&key, &value)
+                        .map(|res| res.get_success())
+                        .unwrap_or(false);
+            stats.writes.push(time::precise_time_ns() - start);
+            stats.write_errors += !ok as usize;
+        }
+    }
+    stats
+}
+
+fn report(what: &str, latencies_ns: &mut Vec<u64>, errors: usize, secs: f64) {
+    if latencies_ns.is_empty() {
+        println!("{}: none", what);
+        return;
+    }
+    latencies_ns.sort();
+    let ms = |q: f64| {
+        let last = latencies_ns.len() - 1;
+        let i = ((latencies_ns.len() as f64 * q) as usize).min(last);
+        latencies_ns[i] as f64 / 1e6
+    };
+    println!("{}: {} ({:.0}/s), {} failed; latency ms p50 {:.2} p90 {:.2} \
+              p99 {:.2} p99.9 {:.2} max {:.2}",
+             what,
+             latencies_ns.len(),
+             latencies_ns.len() as f64 / secs,
+             errors,
+             ms(0.5),
+             ms(0.9),
+             ms(0.99),
+             ms(0.999),
+             ms(1.0));
+}
+
+// Parses 100 or 64-4096 into the smallest and largest value size.
+fn parse_sizes(s: &str) -> Result<(usize, usize), String> {
+    let bad = || {
+        format!("value size {} should be a number of bytes or a range like \
+                 64-4096",
+                s)
+    };
+    let parts: Vec<&str> = s.splitn(2, "-").collect();
+    let min = try!(parts[0].trim().parse().map_err(|_| bad()));
+    let max = match parts.get(1) {
+        Some(max) => try!(max.trim().parse().map_err(|_| bad())),
+        None => min,
+    };
+    if min > max {
+        return Err(bad());
+    }
+    Ok((min, max))
+}
+
+struct Stats {
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    read_errors: usize,
+    write_errors: usize,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats {
+            reads: vec![],
+            writes: vec![],
+            read_errors: 0,
+            write_errors: 0,
+        }
+    }
+
+    fn merge(&mut self, other: Stats) {
+        self.reads.extend(other.reads);
+        self.writes.extend(other.writes);
+        self.read_errors += other.read_errors;
+        self.write_errors += other.write_errors;
+    }
+}
+
+#[derive(Clone)]
+enum Keys {
+    Uniform(u64),
+    Zipfian(Zipf),
+}
+
+impl Keys {
+    fn pick<R: Rng>(&self, rng: &mut R) -> u64 {
+        match *self {
+            Keys::Uniform(n) => rng.gen_range(0, n),
+            Keys::Zipfian(ref zipf) => zipf.next(rng),
+        }
+    }
+}
+
+// Gray et al.'s "Quickly generating billion-record synthetic databases",
+// as YCSB does it: key 0 is the most popular, key 1 the next, and so on.
+#[derive(Clone)]
+struct Zipf {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipf {
+    fn new(n: u64, theta: f64) -> Zipf {
+        let zeta = |n: u64| {
+            (1..n + 1).fold(0.0, |sum, i| sum + 1.0 / (i as f64).powf(theta))
+        };
+        let zetan = zeta(n);
+        let eta = (1.0 - (2.0 / n as f64).powf(1.0 - theta)) /
+                  (1.0 - zeta(2) / zetan);
+        Zipf {
+            n: n,
+            theta: theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan: zetan,
+            eta: eta,
+        }
+    }
+
+    fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.n - 1);
+        }
+        let scaled = (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        let key = (self.n as f64 * scaled) as u64;
+        key.min(self.n - 1)
+    }
+}
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_help: bool,
+    flag_peers: Option<String>,
+    flag_token: Option<String>,
+    flag_clients: usize,
+    flag_duration: u64,
+    flag_reads: u32,
+    flag_keys: u64,
+    flag_distribution: String,
+    flag_value_size: String,
+}
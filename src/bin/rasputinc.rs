@@ -2,11 +2,16 @@ extern crate rustc_serialize;
 extern crate docopt;
 #[macro_use] extern crate log;
 extern crate rasputin;
+extern crate time;
 
+use std::fs::File;
+use std::io::{self, BufReader, Write};
 use std::net::SocketAddr;
 use std::process;
 
-use rasputin::Client;
+use rasputin::{Client, ExportReq};
+use rasputin::client::{Encoding, Format, write_record};
+use rasputin::server::{convert_for_ingest, parse_addr};
 use docopt::Docopt;
 
 static USAGE: &'static str = "
@@ -16,16 +21,58 @@ This program is the Rasputin DB command line client.
 
 Usage:
     rasputinc --help
-    rasputinc [--peers=<peers>] [--get=<key>] [--set=<key>,<value>] [--cas=<key>,<oldvalue>,<value>] [--del=<key>]
+    rasputinc [--peers=<peers>] [--token=<token>] [--get=<key>] \
+              [--set=<key>,<value>] [--cas=<key>,<oldvalue>,<value>] \
+              [--del=<key>] [--delete-range=<start>,<end>] \
+              [--count=<start>,<end>] [--transfer-leader=<peer-id>] \
+              [--backup=<dir> [--incremental]] \
+              [--watch=<prefix> [--since=<txid>]] [--promote] \
+              [--import=<file>] [--export=<start>,<end> \
+              [--key-prefix=<prefix>] [--value-prefix=<prefix>] [--keys-only] \
+              [--format=<format>] [--encoding=<encoding>]]
 
 Options:
     --help                          Show this help message.
     --peers=<host1:port1,...>       List of comma-delimited peers, e.g:
                                     foo.baz.com:8888,bar.baz.com:8888
+                                    IPv6 hosts go in brackets, e.g:
+                                    [fd00::1]:8888
+    --token=<token>                 Authenticate with <token>.
     --get=<key>                     Get the current value for <key>, if set.
     --set=<key,value>               Set the key <key> to <value>.
     --cas=<key,oldvalue,value>      Attempt an atomic compare and swap.
     --del=<key>                     Delete the current value for <key>, if set.
+    --delete-range=<start,end>      Delete every key from <start> up to but
+                                    not including <end>.
+    --count=<start,end>             Count the keys from <start> up to <end>,
+                                    and their bytes.  Either may be left empty
+                                    for the whole keyspace.
+    --transfer-leader=<peer-id>     Hand leadership to the given peer.
+    --backup=<dir>                  Back up the first peer into <dir> on its
+                                    own filesystem.
+    --incremental                   Only back up what changed since the last
+                                    backup there.
+    --watch=<prefix>                Print every change to keys under <prefix>
+                                    as it's applied.
+    --since=<txid>                  Start with the changes after <txid>
+                                    instead of from now.
+    --promote                       Stop the cluster mirroring another, so it
+                                    takes writes.
+    --import=<file>                 Bulk load <file>, sorted lines of a key,
+                                    a tab and a value.  List every replica
+                                    in --peers.
+    --export=<start,end>            Print every key from <start> up to <end>,
+                                    and its value.  Either may be left empty
+                                    for the whole keyspace.
+    --key-prefix=<prefix>           Only export the keys that start with
+                                    <prefix>.
+    --value-prefix=<prefix>         Only export the keys whose values start
+                                    with <prefix>.
+    --keys-only                     Leave the values out.
+    --format=<format>               json, a JSON object per line, or csv
+                                    [default: json].
+    --encoding=<encoding>           Print keys and values as utf8, hex or
+                                    base64 [default: utf8].
 ";
 
 fn main() {
@@ -35,11 +82,19 @@ fn main() {
 
     let peers: Vec<SocketAddr> = args.flag_peers.unwrap_or("127.0.0.1:8888".to_string())
         .split(",")
-        .map(|s| s.parse().unwrap())
+        .map(|s| {
+            parse_addr(s).unwrap_or_else(|e| {
+                println!("{}", e);
+                process::exit(1);
+            })
+        })
         .collect();
 
     let nthreads = 1;
     let mut cli = Client::new(peers, nthreads);
+    if let Some(ref token) = args.flag_token {
+        cli.set_token(token);
+    }
 
     args.flag_set.map(|kv: String| {
         let kvs: Vec<&str> = kv.splitn(2, ",").take(2).collect();
@@ -50,14 +105,210 @@ fn main() {
         let (k, v) = (kvs[0], kvs[1]);
         cli.set(k.as_bytes(), v.as_bytes()).unwrap();
     });
+
+    args.flag_delete_range.map(|range: String| {
+        let bounds: Vec<&str> = range.splitn(2, ",").collect();
+        if bounds.len() != 2 {
+            println!("{}", USAGE);
+            process::exit(1);
+        }
+        let res = cli.delete_range(bounds[0].as_bytes(), bounds[1].as_bytes())
+                     .unwrap();
+        if !res.get_success() {
+            println!("{}", res.get_err());
+            process::exit(1);
+        }
+        println!("deleted {} keys at txid {}",
+                 res.get_deleted(),
+                 res.get_txid());
+    });
+
+    args.flag_count.map(|range: String| {
+        let (start, end) = match range.find(',') {
+            Some(comma) => (&range[..comma], &range[comma + 1..]),
+            None => (&range[..], ""),
+        };
+        let end = if end.is_empty() {
+            None
+        } else {
+            Some(end.as_bytes())
+        };
+        let res = cli.count(start.as_bytes(), end).unwrap();
+        if !res.get_success() {
+            println!("{}", res.get_err());
+            process::exit(1);
+        }
+        println!("{}{} keys, {} bytes as of txid {}",
+                 if res.get_exact() { "" } else { "about " },
+                 res.get_keys(),
+                 res.get_bytes(),
+                 res.get_txid());
+    });
+
+    args.flag_transfer_leader.map(|target: String| {
+        let res = cli.transfer_leader(&target).unwrap();
+        if !res.get_success() {
+            println!("{}", res.get_err());
+            process::exit(1);
+        }
+    });
+
+    if args.flag_promote {
+        let res = cli.promote().unwrap();
+        if !res.get_success() {
+            println!("{}", res.get_err());
+            process::exit(1);
+        }
+        println!("promoted after mirroring up to txid {}",
+                 res.get_source_txid());
+    }
+
+    args.flag_import.map(|path: String| {
+        let input = File::open(&path).unwrap_or_else(|e| {
+            println!("could not open {}: {}", path, e);
+            process::exit(1);
+        });
+        let mut data = vec![];
+        if let Err(e) = convert_for_ingest(BufReader::new(input), &mut data) {
+            println!("could not convert {}: {}", path, e);
+            process::exit(1);
+        }
+        let now = time::get_time();
+        let file = format!("import-{}-{}", now.sec, now.nsec);
+        let res = cli.bulk_load(&file, &data).unwrap();
+        if !res.get_success() {
+            println!("{}", res.get_err());
+            process::exit(1);
+        }
+        println!("ingested {} keys at txid {}", res.get_keys(), res.get_txid());
+    });
+
+    let (format, encoding) =
+        (args.flag_format.clone(), args.flag_encoding.clone());
+    let (key_prefix, value_prefix) =
+        (args.flag_key_prefix.clone(), args.flag_value_prefix.clone());
+    let keys_only = args.flag_keys_only;
+    args.flag_export.map(|range: String| {
+        let (start, end) = match range.find(',') {
+            Some(comma) => (&range[..comma], &range[comma + 1..]),
+            None => (&range[..], ""),
+        };
+        let format = Format::parse(&format).unwrap_or_else(|| {
+            println!("unknown format {}", format);
+            process::exit(1);
+        });
+        let encoding = Encoding::parse(&encoding).unwrap_or_else(|| {
+            println!("unknown encoding {}", encoding);
+            process::exit(1);
+        });
+        let mut export = ExportReq::new();
+        export.set_start(start.as_bytes().to_vec());
+        if !end.is_empty() {
+            export.set_end(end.as_bytes().to_vec());
+        }
+        if let Some(ref prefix) = key_prefix {
+            export.set_key_prefix(prefix.as_bytes().to_vec());
+        }
+        if let Some(ref prefix) = value_prefix {
+            export.set_value_prefix(prefix.as_bytes().to_vec());
+        }
+        export.set_keys_only(keys_only);
+        let mut exporter = cli.export_with(export).unwrap_or_else(|e| {
+            println!("{}", e);
+            process::exit(1);
+        });
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let mut keys = 0;
+        loop {
+            let records = match exporter.next() {
+                Ok(Some(records)) => records,
+                Ok(None) => break,
+                Err(e) => {
+                    writeln!(io::stderr(),
+                             "export failed after {} keys: {}",
+                             keys,
+                             e)
+                        .unwrap();
+                    process::exit(1);
+                }
+            };
+            for record in &records {
+                let written = write_record(&mut out, record, format, encoding);
+                if let Err(e) = written {
+                    writeln!(io::stderr(), "{}", e).unwrap();
+                    process::exit(1);
+                }
+                keys += 1;
+            }
+        }
+        writeln!(io::stderr(),
+                 "exported {} keys as of txid {}",
+                 keys,
+                 exporter.txid())
+            .unwrap();
+    });
+
+    let incremental = args.flag_incremental;
+    args.flag_backup.map(|dir: String| {
+        let res = if incremental {
+            cli.incremental_backup(&dir).unwrap()
+        } else {
+            cli.backup(&dir).unwrap()
+        };
+        if !res.get_success() {
+            println!("{}", res.get_err());
+            process::exit(1);
+        }
+        println!("{}", res.get_manifest());
+    });
+
+    let since = args.flag_since;
+    args.flag_watch.map(|prefix: String| {
+        let watched = cli.watch(prefix.as_bytes(), true, since, None);
+        let mut watcher = watched.unwrap_or_else(|e| {
+            println!("{}", e);
+            process::exit(1);
+        });
+        loop {
+            let next = watcher.next(time::Duration::seconds(1));
+            let changes = next.unwrap_or_else(|e| {
+                println!("{}", e);
+                process::exit(1);
+            });
+            for change in changes {
+                println!("{} {:?} {} {}",
+                         change.get_version().get_txid(),
+                         change.get_field_type(),
+                         String::from_utf8_lossy(change.get_key()),
+                         String::from_utf8_lossy(change.get_value()));
+            }
+        }
+    });
 }
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
     flag_help: bool,
     flag_peers: Option<String>,
+    flag_token: Option<String>,
     flag_set: Option<String>,
     flag_get: Option<String>,
     flag_cas: Option<String>,
     flag_del: Option<String>,
+    flag_delete_range: Option<String>,
+    flag_count: Option<String>,
+    flag_transfer_leader: Option<String>,
+    flag_backup: Option<String>,
+    flag_incremental: bool,
+    flag_watch: Option<String>,
+    flag_since: Option<u64>,
+    flag_promote: bool,
+    flag_import: Option<String>,
+    flag_export: Option<String>,
+    flag_key_prefix: Option<String>,
+    flag_value_prefix: Option<String>,
+    flag_keys_only: bool,
+    flag_format: String,
+    flag_encoding: String,
 }
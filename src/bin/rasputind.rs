@@ -4,14 +4,15 @@ extern crate docopt;
 #[macro_use]
 extern crate log;
 extern crate rasputin;
+extern crate time;
 
-use std::sync::mpsc::SendError;
+use std::process;
 
 use log::LogLevel;
 use docopt::Docopt;
 
-use rasputin::server::{Server, Envelope};
-use rasputin::RealClock;
+use rasputin::server::{ConfigFile, ServerBuilder, ServerConfig,
+                       reload_on_sighup, shutdown_on_signals};
 
 static USAGE: &'static str = "
 rasputin - HA transactional store with a focus on usability, stability and performance.
@@ -20,16 +21,72 @@ This program is the Rasputin DB server process.
 
 Usage:
     rasputind --help
-    rasputind [--cli-port=<listening port>] [--peer-port=<listening port>] [--seed-peers=<peers>] [--logfile=<file>] [--storage-dir=<directory>]
+    rasputind [--config=<file>] [--listen-ip=<ip>] \
+              [--cli-port=<listening port>] [--peer-port=<listening port>] \
+              [--advertise-peer-addr=<host:port>] \
+              [--advertise-cli-addr=<host:port>] \
+              [--admin-port=<listening port>] [--resp-port=<listening port>] \
+              [--rest-port=<listening port>] \
+              [--memcached-port=<listening port>] [--cluster-id=<id>] \
+              [--no-info] [--witness] [--learner] [--standalone] \
+              [--root-token=<token>] [--drain-timeout=<ms>] \
+              [--seed-peers=<peers>] [--logfile=<file>] \
+              [--storage-dir=<directory>] \
+              [--restore=<manifest> [--restore-until=<ms>]] \
+              [--mirror-from=<peers>]
 
 Options:
     --help                          Show this help message.
+    --config=<path>                 TOML file to read settings from; flags
+                                    override it.  Send SIGHUP to apply
+                                    changes to it without restarting.
+    --listen-ip=<ip>                Address to listen on; defaults to every
+                                    interface, v4 and v6.
     --cli-port=<port>               Listening port for communication between servers.
     --peer-port=<port>              Listening port for communication with clients.
+    --advertise-peer-addr=<addr>    Address for peers to dial, if not the one
+                                    we listen on.
+    --advertise-cli-addr=<addr>     Address clients should be redirected to,
+                                    if not the one we listen on.
+    --admin-port=<port>             Optional listening port for HTTP metrics
+                                    and introspection.
+    --resp-port=<port>              Optional listening port for Redis clients.
+    --rest-port=<port>              Optional listening port for the
+                                    HTTP/JSON API.
+    --memcached-port=<port>         Optional listening port for memcached
+                                    clients.
+    --cluster-id=<id>               Cluster name reported to service
+                                    discovery; defaults to rasputin
+    --no-info                       Don't serve the unauthenticated /info
+                                    endpoint on the admin port.
+    --witness                       Vote and acknowledge writes, but keep no
+                                    data and never lead.
+    --learner                       Replicate from the leader and serve stale
+                                    reads, without voting.  Leave learners
+                                    out of the voters' --seed-peers.
+    --standalone                    Run as the only replica, leading without
+                                    elections; takes no --seed-peers.  Grow
+                                    it into a cluster by restoring its
+                                    backups onto the new nodes, then
+                                    restarting them all with --seed-peers.
+    --root-token=<token>            Require clients to authenticate; this
+                                    token may manage the others.
+    --drain-timeout=<ms>            On SIGTERM or SIGINT, hand off leadership
+                                    for up to this long before shutting down.
     --seed-peers=<host1:port1,...>  List of comma-delimited initial peers, e.g:
                                     foo.baz.com:7777,bar.baz.com:7777
+                                    IPv6 hosts go in brackets, e.g:
+                                    [fd00::1]:7777
     --logfile=<path>                File to log output to instead of stdout.
-    --storage-dir=<path>            Directory to store the persisted data in; defaults to /var/lib/rasputin
+    --storage-dir=<path>            Directory to store the persisted data in;
+                                    defaults to /var/lib/rasputin
+    --restore=<manifest>            Fill the empty storage directory from the
+                                    backups this manifest (or the directory
+                                    holding it) lists before starting.
+    --restore-until=<ms>            Restore no backups taken after this many
+                                    ms since the epoch.
+    --mirror-from=<host:port,...>   Follow another cluster's writes instead
+                                    of taking any of our own, until promoted.
 ";
 
 fn main() {
@@ -37,23 +94,23 @@ fn main() {
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| e.exit());
 
-    rasputin::logging::init_logger(args.flag_logfile, LogLevel::Info).unwrap();
-    print_banner();
-
-    let peer_port: u16 = match args.flag_peer_port {
-        Some(p) => p,
-        None => 7770,
-    };
-
-    let cli_port: u16 = match args.flag_cli_port {
-        Some(p) => p,
-        None => 8880,
+    // flags override whatever the config file says
+    let file = match args.flag_config {
+        Some(ref path) => {
+            ConfigFile::load(path).unwrap_or_else(|e| {
+                println!("{}", e);
+                process::exit(1);
+            })
+        }
+        None => ConfigFile::default(),
     };
 
-    let storage_dir: String = match args.flag_storage_dir {
-        Some(d) => d,
-        None => "/var/lib/rasputin".to_string(),
-    };
+    let logfile = args.flag_logfile.clone().or(file.logfile.clone());
+    rasputin::logging::init_logger(logfile, LogLevel::Info).unwrap();
+    if let Some(format) = file.server.log_format {
+        rasputin::logging::set_format(format);
+    }
+    print_banner();
 
     let seed_peers: Vec<String> = args.flag_seed_peers
         .split(",")
@@ -61,18 +118,122 @@ fn main() {
         .filter(|s| s != "")
         .collect();
 
-    Server::<RealClock, Result<(), SendError<Envelope>>>
-          ::run(peer_port, cli_port, storage_dir, seed_peers);
+    let mut builder = ServerBuilder::new().config_file(&file);
+    if let Some(p) = args.flag_peer_port {
+        builder = builder.peer_port(p);
+    }
+    if let Some(p) = args.flag_cli_port {
+        builder = builder.cli_port(p);
+    }
+    if let Some(ref dir) = args.flag_storage_dir {
+        builder = builder.storage_dir(dir);
+    }
+    if !seed_peers.is_empty() {
+        builder = builder.peers(seed_peers);
+    }
+    if let Some(ref ip) = args.flag_listen_ip {
+        match ip.parse() {
+            Ok(ip) => builder = builder.listen_ip(ip),
+            Err(_) => {
+                println!("--listen-ip must be an IP address, not {}", ip);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(p) = args.flag_admin_port {
+        builder = builder.admin_port(p);
+    }
+    if let Some(p) = args.flag_resp_port {
+        builder = builder.resp_port(p);
+    }
+    if let Some(p) = args.flag_rest_port {
+        builder = builder.rest_port(p);
+    }
+    if let Some(p) = args.flag_memcached_port {
+        builder = builder.memcached_port(p);
+    }
+    if let Some(ref id) = args.flag_cluster_id {
+        builder = builder.cluster_id(id);
+    }
+    if let Some(ref manifest) = args.flag_restore {
+        builder = builder.restore(manifest, args.flag_restore_until);
+    }
+    builder = builder.config(with_flags(&args, file.server.clone()));
+    let server = builder.start().unwrap();
+    let drain_timeout = args.flag_drain_timeout
+                            .map(|ms| time::Duration::milliseconds(ms as i64));
+    shutdown_on_signals(server.shutdown_handle(), drain_timeout).unwrap();
+    if let Some(path) = args.flag_config.clone() {
+        // flags still win over whatever the file now says
+        reload_on_sighup(server.shutdown_handle(),
+                         path,
+                         move |config| with_flags(&args, config))
+            .unwrap();
+    }
+
+    match server.join() {
+        Ok(()) => info!("server shut down"),
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn with_flags(args: &Args, mut config: ServerConfig) -> ServerConfig {
+    if args.flag_no_info {
+        config.expose_info = false;
+    }
+    if args.flag_witness {
+        config.witness = true;
+    }
+    if args.flag_learner {
+        config.learner = true;
+    }
+    if args.flag_standalone {
+        config.standalone = true;
+    }
+    if args.flag_root_token.is_some() {
+        config.root_token = args.flag_root_token.clone();
+    }
+    if args.flag_advertise_peer_addr.is_some() {
+        config.advertise_peer_addr = args.flag_advertise_peer_addr.clone();
+    }
+    if args.flag_advertise_cli_addr.is_some() {
+        config.advertise_cli_addr = args.flag_advertise_cli_addr.clone();
+    }
+    if args.flag_mirror_from.is_some() {
+        config.mirror_from = args.flag_mirror_from.clone();
+    }
+    config
 }
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
     flag_help: bool,
+    flag_config: Option<String>,
+    flag_listen_ip: Option<String>,
     flag_cli_port: Option<u16>,
     flag_peer_port: Option<u16>,
+    flag_advertise_peer_addr: Option<String>,
+    flag_advertise_cli_addr: Option<String>,
+    flag_admin_port: Option<u16>,
+    flag_resp_port: Option<u16>,
+    flag_rest_port: Option<u16>,
+    flag_memcached_port: Option<u16>,
+    flag_cluster_id: Option<String>,
+    flag_no_info: bool,
+    flag_witness: bool,
+    flag_learner: bool,
+    flag_standalone: bool,
+    flag_root_token: Option<String>,
+    flag_drain_timeout: Option<u64>,
     flag_seed_peers: String,
     flag_logfile: Option<String>,
     flag_storage_dir: Option<String>,
+    flag_restore: Option<String>,
+    flag_restore_until: Option<u64>,
+    flag_mirror_from: Option<String>,
 }
 
 fn print_banner() {
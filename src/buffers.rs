@@ -0,0 +1,160 @@
+use std::mem;
+use std::ptr;
+use std::sync::Mutex;
+
+use bytes::{ByteBuf, MutByteBuf};
+use bytes::alloc::{self, Allocator, Mem, MemRef};
+
+// The smallest and largest blocks the pool keeps, as powers of two, and
+// how many spare blocks of each size it holds on to.  Anything bigger
+// comes from the heap and goes back to it.
+const MIN_CLASS: usize = 8;
+const MAX_CLASS: usize = 16;
+const MAX_SPARE: usize = 1024;
+
+// Blocks of one size.  Each is an Allocator of its own, so that a block
+// finds its way back to the right one when the last buffer using it is
+// dropped.
+struct Class {
+    size: usize,
+    // the addresses of blocks nothing uses anymore
+    spare: Mutex<Vec<usize>>,
+}
+
+impl Class {
+    // A block is its Mem header followed by the bytes, kept in u64s so
+    // the header comes out aligned.
+    fn words(&self) -> usize {
+        (mem::size_of::<Mem>() + self.size + 7) / 8
+    }
+}
+
+impl Drop for Class {
+    fn drop(&mut self) {
+        for addr in self.spare.lock().unwrap().drain(..) {
+            unsafe {
+                drop(Vec::from_raw_parts(addr as *mut u64, 0, self.words()));
+            }
+        }
+    }
+}
+
+impl Allocator for Class {
+    fn allocate(&self, _len: usize) -> MemRef {
+        let block = match self.spare.lock().unwrap().pop() {
+            Some(addr) => addr as *mut u64,
+            None => {
+                let mut block: Vec<u64> = Vec::with_capacity(self.words());
+                let ptr = block.as_mut_ptr();
+                mem::forget(block);
+                ptr
+            }
+        };
+        unsafe {
+            ptr::write(block as *mut Mem,
+                       Mem::new(self.size, self as &Allocator));
+        }
+        MemRef::new(block as *mut Mem)
+    }
+
+    fn deallocate(&self, mem: *mut Mem) {
+        let mut spare = self.spare.lock().unwrap();
+        if spare.len() < MAX_SPARE {
+            spare.push(mem as usize);
+        } else {
+            unsafe {
+                drop(Vec::from_raw_parts(mem as *mut u64, 0, self.words()));
+            }
+        }
+    }
+}
+
+// Recycles the memory behind the buffers frames are read into and written
+// from, which otherwise would each be allocated and freed again, once
+// per message or more.  A block goes back to the pool once no ByteBuf
+// refers to it anymore, so one read from a socket can be handed out as
+// many messages without copying them.
+pub struct BufferPool {
+    classes: Vec<Class>,
+}
+
+lazy_static! {
+    pub static ref POOL: BufferPool = BufferPool::new();
+}
+
+impl BufferPool {
+    fn new() -> BufferPool {
+        BufferPool {
+            classes: (MIN_CLASS..MAX_CLASS + 1)
+                         .map(|class| {
+                             Class {
+                                 size: 1 << class,
+                                 spare: Mutex::new(vec![]),
+                             }
+                         })
+                         .collect(),
+        }
+    }
+
+    // A block of at least len bytes, which may be bigger.
+    pub fn alloc(&self, len: usize) -> MemRef {
+        let class = len.next_power_of_two().trailing_zeros() as usize;
+        if class > MAX_CLASS {
+            return alloc::heap(len);
+        }
+        let class = if class < MIN_CLASS {
+            MIN_CLASS
+        } else {
+            class
+        };
+        self.classes[class - MIN_CLASS].allocate(len)
+    }
+
+    // A buffer to write up to len bytes into.
+    pub fn mut_buf(&self, len: usize) -> MutByteBuf {
+        let mem = self.alloc(len);
+        unsafe { ByteBuf::from_mem_ref(mem, len as u32, 0, len as u32).flip() }
+    }
+
+    // how many blocks of len bytes are waiting to be reused
+    pub fn spare(&self, len: usize) -> usize {
+        let class = len.next_power_of_two().trailing_zeros() as usize;
+        if class < MIN_CLASS || class > MAX_CLASS {
+            return 0;
+        }
+        self.classes[class - MIN_CLASS].spare.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, ByteBuf};
+
+    use super::BufferPool;
+
+    #[test]
+    fn test_pool_reuses_blocks() {
+        let pool = BufferPool::new();
+        let mem = pool.alloc(1000);
+        let first = mem.ptr() as usize;
+        assert_eq!(mem.bytes().len(), 1024);
+
+        // a block stays out while any buffer still refers to it
+        let slice = unsafe { ByteBuf::from_mem_ref(mem.clone(), 10, 5, 10) };
+        drop(mem);
+        assert_eq!(pool.spare(1024), 0);
+        assert_eq!(slice.bytes().len(), 5);
+        drop(slice);
+        assert_eq!(pool.spare(1024), 1);
+        assert_eq!(pool.alloc(513).ptr() as usize, first);
+        assert_eq!(pool.spare(1024), 1);
+
+        // small ones are rounded up, and big ones come from the heap
+        assert_eq!(pool.alloc(0).bytes().len(), 256);
+        assert_eq!(pool.alloc(1 << 20).bytes().len(), 1 << 20);
+        assert_eq!(pool.spare(1 << 20), 0);
+        let mut buf = pool.mut_buf(3);
+        assert_eq!(buf.write_slice(b"abcd"), 3);
+        assert_eq!(buf.flip().bytes(), b"abc");
+    }
+}
@@ -0,0 +1,332 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError, channel};
+
+use time;
+
+use {CASReq, CASRes, CliReq, CliRes, ClientError, ConsensusError, DelReq,
+     DelRes, GetReq, GetRes, Priority, ProtocolError, SetReq, SetRes};
+use clock::timespec_to_ms;
+use client::{ConnPool, refusal};
+use client::mux::OnReply;
+
+// A client that doesn't block while its requests are served.  Each server
+// it talks to gets one connection, shared by every request in flight to
+// it, and replies are matched back up to their requests by req_id, in
+// whatever order they come.  A request's outcome is handed to a callback,
+// on the connection's reader thread, or to a Reply that can be waited on
+// or polled.  Requests are redirected to the leader as the servers tell
+// us, but aren't retried after a refusal or a timeout; that's left to
+// whoever made them.  Clones share connections, and the connections may be
+// shared with other clients too.
+#[derive(Clone)]
+pub struct AsyncClient {
+    inner: Arc<Inner>,
+    timeout: time::Duration,
+    priority: Priority,
+}
+
+struct Inner {
+    servers: Vec<SocketAddr>,
+    conns: ConnPool,
+    // whoever last answered us without a redirect
+    leader: Mutex<Option<SocketAddr>>,
+    req_counter: AtomicUsize,
+}
+
+// The outcome of a request, once it's come.
+pub struct Reply<T> {
+    rx: Receiver<Result<T, ClientError>>,
+}
+
+impl<T> Reply<T> {
+    // Blocks until the outcome comes.
+    pub fn wait(self) -> Result<T, ClientError> {
+        self.rx.recv().unwrap_or(Err(ClientError::Unreachable))
+    }
+
+    // The outcome, if it's come, without waiting for it.  Only the first
+    // call to see it gets it.
+    pub fn poll(&mut self) -> Option<Result<T, ClientError>> {
+        match self.rx.try_recv() {
+            Ok(res) => Some(res),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(ClientError::Unreachable))
+            }
+        }
+    }
+}
+
+impl AsyncClient {
+    pub fn new(servers: Vec<SocketAddr>) -> AsyncClient {
+        AsyncClient::with_connections(servers,
+                                      ConnPool::new(time::Duration::seconds(1)))
+    }
+
+    pub fn with_connections(servers: Vec<SocketAddr>,
+                            conns: ConnPool)
+                            -> AsyncClient {
+        AsyncClient {
+            inner: Arc::new(Inner {
+                servers: servers,
+                conns: conns,
+                leader: Mutex::new(None),
+                req_counter: AtomicUsize::new(0),
+            }),
+            timeout: time::Duration::seconds(15),
+            priority: Priority::NORMAL,
+        }
+    }
+
+    pub fn connections(&self) -> ConnPool {
+        self.inner.conns.clone()
+    }
+
+    // For servers started with a root token.
+    pub fn set_token(&self, token: &str) {
+        self.inner.conns.set_token(token);
+    }
+
+    // How long a request made through this handle may take, redirects
+    // included.
+    pub fn set_timeout(&mut self, timeout: time::Duration) {
+        self.timeout = timeout;
+    }
+
+    // How readily an overloaded server may turn requests made through this
+    // handle away.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    // Sends req, which is given a req_id, deadline and priority of its
+    // own, and calls on_reply with the reply.  Redirects are followed, and
+    // error replies turned into the ClientError they stand for, before
+    // on_reply sees them.  on_reply is called exactly once, on whichever
+    // thread has the outcome, so it shouldn't block for long.
+    pub fn send<F>(&self, mut req: CliReq, on_reply: F)
+        where F: FnMut(Result<CliRes, ClientError>) + Send + 'static
+    {
+        let deadline = time::get_time() + self.timeout;
+        let counted = self.inner.req_counter.fetch_add(1, Ordering::SeqCst);
+        req.set_req_id(counted as u64 + 1);
+        req.set_deadline_ms(timespec_to_ms(deadline));
+        req.set_priority(self.priority);
+        dispatch(&self.inner,
+                 Arc::new(req),
+                 deadline,
+                 0,
+                 None,
+                 ClientError::Unreachable,
+                 Box::new(on_reply));
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Reply<SetRes> {
+        let mut set = SetReq::new();
+        set.set_key(key.to_vec());
+        set.set_value(value.to_vec());
+        let mut req = CliReq::new();
+        req.set_set(set);
+        self.reply(req, "set", |cli_res| {
+            if cli_res.has_set() {
+                Some(cli_res.get_set().clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Reply<GetRes> {
+        let mut get = GetReq::new();
+        get.set_key(key.to_vec());
+        let mut req = CliReq::new();
+        req.set_get(get);
+        self.reply(req, "get", |cli_res| {
+            if cli_res.has_get() {
+                Some(cli_res.get_get().clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn cas(&self,
+               key: &[u8],
+               old_value: &[u8],
+               new_value: &[u8])
+               -> Reply<CASRes> {
+        let mut cas = CASReq::new();
+        cas.set_key(key.to_vec());
+        cas.set_old_value(old_value.to_vec());
+        cas.set_new_value(new_value.to_vec());
+        let mut req = CliReq::new();
+        req.set_cas(cas);
+        self.reply(req, "cas", |cli_res| {
+            if cli_res.has_cas() {
+                Some(cli_res.get_cas().clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn del(&self, key: &[u8]) -> Reply<DelRes> {
+        let mut del = DelReq::new();
+        del.set_key(key.to_vec());
+        let mut req = CliReq::new();
+        req.set_del(del);
+        self.reply(req, "del", |cli_res| {
+            if cli_res.has_del() {
+                Some(cli_res.get_del().clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    fn reply<T, F>(&self, req: CliReq, op: &'static str, pick: F) -> Reply<T>
+        where T: Send + 'static,
+              F: Fn(&CliRes) -> Option<T> + Send + 'static
+    {
+        let (tx, rx) = channel();
+        self.send(req, move |res| {
+            let res = res.and_then(|cli_res| {
+                pick(&cli_res).ok_or_else(|| {
+                    ClientError::from(ProtocolError::Unexpected(
+                        format!("expected a reply to a {}", op)))
+                })
+            });
+            // nobody may be waiting any more
+            let _ = tx.send(res);
+        });
+        Reply { rx: rx }
+    }
+}
+
+impl Inner {
+    // addr let a request down
+    fn failed(&self, addr: &SocketAddr) {
+        self.conns.failed(addr);
+        self.not_leading(addr);
+    }
+
+    fn not_leading(&self, addr: &SocketAddr) {
+        let mut leader = self.leader.lock().unwrap();
+        if *leader == Some(*addr) {
+            *leader = None;
+        }
+    }
+}
+
+// Sends req to the server we were redirected to, or to the leader if we
+// know it, or else to the next server, until one answers it or we've
+// tried them all.  Redirects are followed from the reader thread of the
+// connection they came in on, which waits while a new connection is
+// opened for them.
+fn dispatch(inner: &Arc<Inner>,
+            req: Arc<CliReq>,
+            deadline: time::Timespec,
+            hops: usize,
+            to: Option<SocketAddr>,
+            last_err: ClientError,
+            mut on_reply: OnReply) {
+    if time::get_time() >= deadline {
+        return on_reply(Err(ClientError::DeadlineExceeded));
+    }
+    // every server once, and a couple of redirects besides
+    if hops > inner.servers.len() + 2 || inner.servers.is_empty() {
+        return on_reply(Err(last_err));
+    }
+    let leader = if hops == 0 {
+        *inner.leader.lock().unwrap()
+    } else {
+        None
+    };
+    let addr = to.or(leader)
+                 .unwrap_or(inner.servers[hops % inner.servers.len()]);
+    let conn = match inner.conns.conn(addr, deadline) {
+        Ok(conn) => conn,
+        // every server checks the same credentials
+        Err(ClientError::Rejected(e)) => {
+            return on_reply(Err(ClientError::Rejected(e)))
+        }
+        Err(e) => {
+            debug!("could not connect to {:?}: {}", addr, e);
+            // which the pool marked it down for
+            inner.not_leading(&addr);
+            return dispatch(inner, req, deadline, hops + 1, None, e, on_reply);
+        }
+    };
+
+    // kept where we can take it back, should the request not get written
+    let slot = Arc::new(Mutex::new(Some(on_reply)));
+    let (reply_slot, reply_inner, reply_req) =
+        (slot.clone(), inner.clone(), req.clone());
+    let sent = conn.send(&req, deadline, Box::new(move |res| {
+        let on_reply = match reply_slot.lock().unwrap().take() {
+            Some(on_reply) => on_reply,
+            None => return,
+        };
+        replied(&reply_inner,
+                reply_req.clone(),
+                deadline,
+                hops,
+                addr,
+                res,
+                on_reply);
+    }));
+    if let Err(e) = sent {
+        debug!("could not send request {} to {:?}: {}",
+               req.get_req_id(),
+               addr,
+               e);
+        inner.failed(&addr);
+        if let Some(on_reply) = slot.lock().unwrap().take() {
+            dispatch(inner, req, deadline, hops + 1, None, e, on_reply);
+        }
+    }
+}
+
+fn replied(inner: &Arc<Inner>,
+           req: Arc<CliReq>,
+           deadline: time::Timespec,
+           hops: usize,
+           addr: SocketAddr,
+           res: Result<CliRes, ClientError>,
+           mut on_reply: OnReply) {
+    match res {
+        Ok(cli_res) => {
+            if cli_res.has_error() {
+                on_reply(Err(refusal(cli_res.get_error())))
+            } else if cli_res.has_redirect() {
+                let redirect = cli_res.get_redirect();
+                debug!("request {} redirected to {}",
+                       req.get_req_id(),
+                       redirect.get_address());
+                // leadership has moved, so forget what we knew
+                *inner.leader.lock().unwrap() = None;
+                let (to, err) = if redirect.get_success() {
+                    (redirect.get_address().parse().ok(),
+                     ClientError::from(ConsensusError::NotLeader {
+                         leader: Some(redirect.get_address().to_string()),
+                     }))
+                } else {
+                    (None, ClientError::from(ConsensusError::NoLeader))
+                };
+                dispatch(inner, req, deadline, hops + 1, to, err, on_reply)
+            } else {
+                *inner.leader.lock().unwrap() = Some(addr);
+                on_reply(Ok(cli_res))
+            }
+        }
+        // the connection broke before a reply came, so try elsewhere
+        Err(ClientError::Io(e)) => {
+            inner.failed(&addr);
+            let err = ClientError::Io(e);
+            dispatch(inner, req, deadline, hops + 1, None, err, on_reply)
+        }
+        Err(e) => on_reply(Err(e)),
+    }
+}
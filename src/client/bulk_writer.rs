@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+
+use {ClientError, Priority, ProtocolError};
+use client::{Client, RetryPolicy};
+
+// Loads a large number of keys without the caller having to worry about
+// ordering, batching or concurrency.  Keys may be added in any order; they
+// are buffered and sorted, cut into chunks of consecutive keys, and the
+// chunks are written by up to max_in_flight connections at once.  Every
+// key currently lives in the same range, so chunks are simply runs of
+// chunk_size keys, which keeps each connection working on a contiguous
+// part of the keyspace.
+pub struct BulkWriter {
+    servers: Vec<SocketAddr>,
+    retry_policy: RetryPolicy,
+    token: Option<String>,
+    chunk_size: usize,
+    max_in_flight: usize,
+    // sorted, and a key added twice keeps its last value
+    buffered: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+// The keys of a chunk that could not be written, along with why.  Keys in
+// the same chunk that aren't listed here were written successfully.
+#[derive(Debug)]
+pub struct ChunkError {
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+    pub failed: Vec<(Vec<u8>, ClientError)>,
+}
+
+impl BulkWriter {
+    pub fn new(servers: Vec<SocketAddr>) -> BulkWriter {
+        BulkWriter {
+            servers: servers,
+            retry_policy: RetryPolicy::default(),
+            token: None,
+            chunk_size: 1000,
+            max_in_flight: 4,
+            buffered: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+    }
+
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.max_in_flight = max_in_flight;
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn set_token(&mut self, token: &str) {
+        self.token = Some(token.to_string());
+    }
+
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        self.buffered.insert(key.to_vec(), value.to_vec());
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffered.len()
+    }
+
+    // Writes everything buffered so far, returning how many keys were
+    // written, or the chunks that had failures.
+    pub fn flush(&mut self) -> Result<usize, Vec<ChunkError>> {
+        let buffered = mem::replace(&mut self.buffered, BTreeMap::new());
+        let total = buffered.len();
+        if total == 0 {
+            return Ok(0);
+        }
+
+        let chunk_size = if self.chunk_size == 0 {
+            1
+        } else {
+            self.chunk_size
+        };
+        let mut chunks = vec![];
+        let mut chunk = vec![];
+        for kv in buffered.into_iter() {
+            chunk.push(kv);
+            if chunk.len() == chunk_size {
+                chunks.push(mem::replace(&mut chunk, vec![]));
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        let nchunks = chunks.len();
+
+        // workers pull chunks in key order until there are none left
+        chunks.reverse();
+        let queue = Arc::new(Mutex::new(chunks));
+        let (tx, rx) = channel();
+        let workers = if self.max_in_flight == 0 {
+            1
+        } else if self.max_in_flight > nchunks {
+            nchunks
+        } else {
+            self.max_in_flight
+        };
+        for _ in 0..workers {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            let mut cli = Client::new(self.servers.clone(), 1);
+            cli.set_retry_policy(self.retry_policy.clone());
+            // a bulk load can wait for the server to catch up
+            cli.set_priority(Priority::LOW);
+            if let Some(ref token) = self.token {
+                cli.set_token(token);
+            }
+            thread::spawn(move || {
+                loop {
+                    let chunk = match queue.lock().unwrap().pop() {
+                        Some(chunk) => chunk,
+                        None => break,
+                    };
+                    // nobody's waiting on the rest if the load returned
+                    if tx.send(write_chunk(&mut cli, chunk)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let mut errors = vec![];
+        for res in rx.iter().take(nchunks) {
+            match res {
+                Ok(()) => (),
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(total)
+        } else {
+            errors.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+            Err(errors)
+        }
+    }
+}
+
+fn write_chunk(cli: &mut Client,
+               chunk: Vec<(Vec<u8>, Vec<u8>)>)
+               -> Result<(), ChunkError> {
+    let first_key = chunk[0].0.clone();
+    let last_key = chunk[chunk.len() - 1].0.clone();
+    let mut failed = vec![];
+    for (key, value) in chunk {
+        match cli.set(&key, &value) {
+            Ok(ref res) if res.get_success() => (),
+            Ok(res) => {
+                let err = ProtocolError::Unexpected(res.get_err().to_string());
+                failed.push((key, ClientError::from(err)));
+            }
+            Err(e) => failed.push((key, e)),
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(ChunkError {
+            first_key: first_key,
+            last_key: last_key,
+            failed: failed,
+        })
+    }
+}
@@ -0,0 +1,217 @@
+use std::cmp;
+
+use rand;
+
+use {ClientError, ProtocolError};
+use client::Client;
+
+// Values longer than a server takes under one key are cut into chunks of
+// chunk_bytes, stored beside the key under key ++ "\x00chunks/", followed
+// by a generation and the chunk's index.  Once every chunk is written the
+// key itself is set to a manifest naming the generation, how many chunks
+// there are and how long the value is, so readers see the old value or
+// the new one and never a mix; the old generation's chunks are deleted
+// after.  Shorter values are stored under the key as they are, unless
+// they could be taken for a manifest.
+const MANIFEST: &'static [u8] = b"\x00rasputin/chunked\x00";
+const MANIFEST_LEN: usize = 18 + 8 + 4 + 8;
+const CHUNKS: &'static [u8] = b"\x00chunks/";
+
+// tries at reading a value that's being overwritten as we read it
+const READ_TRIES: usize = 3;
+
+// A manifest's generation, chunk count and value length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Manifest {
+    generation: u64,
+    chunks: u32,
+    len: u64,
+}
+
+impl Manifest {
+    // Integers are little-endian, as the server stores them.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = MANIFEST.to_vec();
+        for &(n, bytes) in [(self.generation, 8),
+                            (self.chunks as u64, 4),
+                            (self.len, 8)].iter() {
+            for i in 0..bytes {
+                buf.push((n >> (8 * i)) as u8);
+            }
+        }
+        buf
+    }
+
+    fn decode(value: &[u8]) -> Option<Manifest> {
+        if value.len() != MANIFEST_LEN || !value.starts_with(MANIFEST) {
+            return None;
+        }
+        let rest = &value[MANIFEST.len()..];
+        let int =
+            |b: &[u8]| b.iter().rev().fold(0, |n, &byte| n << 8 | byte as u64);
+        Some(Manifest {
+            generation: int(&rest[..8]),
+            chunks: int(&rest[8..12]) as u32,
+            len: int(&rest[12..]),
+        })
+    }
+}
+
+fn chunks_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = key.to_vec();
+    prefix.extend_from_slice(CHUNKS);
+    prefix
+}
+
+fn generation_prefix(key: &[u8], generation: u64) -> Vec<u8> {
+    let mut prefix = chunks_prefix(key);
+    prefix.extend_from_slice(format!("{:016x}/", generation).as_bytes());
+    prefix
+}
+
+fn chunk_key(key: &[u8], generation: u64, index: u32) -> Vec<u8> {
+    let mut chunk = generation_prefix(key, generation);
+    chunk.extend_from_slice(format!("{:010}", index).as_bytes());
+    chunk
+}
+
+// The first key past every one that starts with prefix, which ends in a
+// '/' and so always has a next byte.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    let last = end.pop().unwrap();
+    end.push(last + 1);
+    end
+}
+
+// Sets, gets and deletes through a client, splitting values longer than
+// chunk_bytes across several keys and putting them back together.  Keys
+// set through it should only be read and deleted through it.
+pub struct Chunker<'a> {
+    client: &'a mut Client,
+    chunk_bytes: usize,
+}
+
+impl<'a> Chunker<'a> {
+    pub fn new(client: &'a mut Client, chunk_bytes: usize) -> Chunker<'a> {
+        Chunker {
+            client: client,
+            chunk_bytes: cmp::max(chunk_bytes, 1),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), ClientError> {
+        let old = try!(self.manifest(key));
+        if value.len() <= self.chunk_bytes && !value.starts_with(MANIFEST) {
+            try!(self.set_one(key, value));
+        } else {
+            let chunks: Vec<&[u8]> = value.chunks(self.chunk_bytes).collect();
+            let manifest = Manifest {
+                generation: rand::random(),
+                chunks: chunks.len() as u32,
+                len: value.len() as u64,
+            };
+            for (i, chunk) in chunks.iter().enumerate() {
+                let name = chunk_key(key, manifest.generation, i as u32);
+                try!(self.set_one(&name, chunk));
+            }
+            try!(self.set_one(key, &manifest.encode()));
+        }
+        if let Some(old) = old {
+            let prefix = generation_prefix(key, old.generation);
+            try!(self.client.delete_range(&prefix, &prefix_end(&prefix)));
+        }
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, ClientError> {
+        let mut tries = 0;
+        loop {
+            let res = try!(self.client.get(key));
+            if !res.get_success() {
+                return Ok(None);
+            }
+            let manifest = match Manifest::decode(res.get_value()) {
+                Some(manifest) => manifest,
+                None => return Ok(Some(res.get_value().to_vec())),
+            };
+            let mut value = Vec::with_capacity(manifest.len as usize);
+            for i in 0..manifest.chunks {
+                let name = chunk_key(key, manifest.generation, i);
+                let chunk = try!(self.client.get(&name));
+                if !chunk.get_success() {
+                    break;
+                }
+                value.extend_from_slice(chunk.get_value());
+            }
+            if value.len() as u64 == manifest.len {
+                return Ok(Some(value));
+            }
+            // overwritten while we read it, and its chunks deleted
+            tries += 1;
+            if tries == READ_TRIES {
+                return Err(ClientError::from(ProtocolError::Unexpected(
+                    format!("the chunks of a {} byte value are missing",
+                            manifest.len))));
+            }
+        }
+    }
+
+    pub fn del(&mut self, key: &[u8]) -> Result<(), ClientError> {
+        let res = try!(self.client.del(key));
+        if !res.get_success() {
+            return Err(ClientError::Rejected(res.get_err().to_string()));
+        }
+        let prefix = chunks_prefix(key);
+        try!(self.client.delete_range(&prefix, &prefix_end(&prefix)));
+        Ok(())
+    }
+
+    fn manifest(&mut self,
+                key: &[u8])
+                -> Result<Option<Manifest>, ClientError> {
+        let res = try!(self.client.get(key));
+        Ok(if res.get_success() {
+            Manifest::decode(res.get_value())
+        } else {
+            None
+        })
+    }
+
+    fn set_one(&mut self, key: &[u8], value: &[u8]) -> Result<(), ClientError> {
+        let res = try!(self.client.set(key, value));
+        if res.get_success() {
+            Ok(())
+        } else {
+            Err(ClientError::Rejected(res.get_err().to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MANIFEST_LEN, Manifest, chunk_key, chunks_prefix,
+                generation_prefix, prefix_end};
+
+    #[test]
+    fn test_manifest_and_chunk_keys() {
+        let manifest = Manifest {
+            generation: 0xabcd,
+            chunks: 3,
+            len: 5000,
+        };
+        let encoded = manifest.encode();
+        assert_eq!(encoded.len(), MANIFEST_LEN);
+        assert_eq!(Manifest::decode(&encoded), Some(manifest));
+        assert_eq!(Manifest::decode(b"plain"), None);
+        assert_eq!(Manifest::decode(&encoded[..MANIFEST_LEN - 1]), None);
+
+        assert_eq!(chunk_key(b"k", 0xabcd, 2),
+                   b"k\x00chunks/000000000000abcd/0000000002".to_vec());
+        assert!(chunk_key(b"k", 1, 9) < chunk_key(b"k", 1, 10));
+        let prefix = generation_prefix(b"k", 1);
+        assert!(chunk_key(b"k", 1, 0) < prefix_end(&prefix));
+        assert!(chunk_key(b"k", 2, 0) >= prefix_end(&prefix));
+        assert!(chunk_key(b"k", 2, 0) < prefix_end(&chunks_prefix(b"k")));
+    }
+}
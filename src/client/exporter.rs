@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::str;
+
+use rustc_serialize::base64::{STANDARD, ToBase64};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+use time;
+
+use {CliReq, CliRes, ClientError, ExportRes, KeyValue, ProtocolError};
+use client::replies::Replies;
+
+// How exported records are written out: as newline-delimited JSON objects
+// with a key and a value, or as CSV rows of the two.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+// How keys and values, which are only bytes, are turned into text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    // as they are, refusing any that aren't utf-8
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl Encoding {
+    pub fn parse(s: &str) -> Option<Encoding> {
+        match s {
+            "utf8" => Some(Encoding::Utf8),
+            "hex" => Some(Encoding::Hex),
+            "base64" => Some(Encoding::Base64),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> io::Result<String> {
+        match *self {
+            Encoding::Utf8 => {
+                str::from_utf8(data).map(String::from).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("{}; export as hex or base64 \
+                                            instead",
+                                           e))
+                })
+            }
+            Encoding::Hex => Ok(data.to_hex()),
+            Encoding::Base64 => Ok(data.to_base64(STANDARD)),
+        }
+    }
+}
+
+// Writes a record out as a line of its own.
+pub fn write_record<W: Write>(w: &mut W,
+                              record: &KeyValue,
+                              format: Format,
+                              encoding: Encoding)
+                              -> io::Result<()> {
+    let key = try!(encoding.encode(record.get_key()));
+    let value = try!(encoding.encode(record.get_value()));
+    match format {
+        Format::Json => {
+            let mut o = BTreeMap::new();
+            o.insert("key".to_string(), Json::String(key));
+            o.insert("value".to_string(), Json::String(value));
+            writeln!(w, "{}", Json::Object(o))
+        }
+        Format::Csv => writeln!(w, "{},{}", csv_field(&key), csv_field(&value)),
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace("\"", "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// The records of an export as a server streams them back, a batch at a
+// time, all as of the one txid.
+pub struct Exporter {
+    replies: Replies,
+    // how long to wait for each batch
+    timeout: time::Duration,
+    // the first batch, which came back with the answer to the request
+    first: Option<ExportRes>,
+    txid: u64,
+    done: bool,
+    // where to go on from, if the server stopped at the request's limit
+    cursor: Option<Vec<u8>>,
+}
+
+impl Exporter {
+    pub fn open(servers: &[SocketAddr],
+                token: Option<&str>,
+                req: CliReq,
+                deadline: time::Timespec,
+                timeout: time::Duration)
+                -> Result<Exporter, ClientError> {
+        let (replies, first) =
+            try!(Replies::open(servers, token, &req, deadline));
+        let first = try!(export_res(first));
+        Ok(Exporter {
+            replies: replies,
+            timeout: timeout,
+            txid: first.get_txid(),
+            first: Some(first),
+            done: false,
+            cursor: None,
+        })
+    }
+
+    // The next batch of records, or none once they've all been sent.
+    pub fn next(&mut self) -> Result<Option<Vec<KeyValue>>, ClientError> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut res = match self.first.take() {
+            Some(res) => res,
+            None => {
+                match try!(self.replies.recv(self.timeout)) {
+                    Some(cli_res) => try!(export_res(cli_res)),
+                    None => return Err(ClientError::DeadlineExceeded),
+                }
+            }
+        };
+        self.done = res.get_done();
+        if res.has_cursor() {
+            self.cursor = Some(res.take_cursor());
+        }
+        Ok(Some(res.take_records().into_vec()))
+    }
+
+    // What the export is as of.
+    pub fn txid(&self) -> u64 {
+        self.txid
+    }
+
+    // Once every batch has been read, where the next page starts if the
+    // export stopped short at its limit: an ExportReq with this as its
+    // cursor goes on from there, as of the same snapshot if it had one.
+    pub fn cursor(&self) -> Option<&[u8]> {
+        self.cursor.as_ref().map(|c| &c[..])
+    }
+}
+
+fn export_res(mut cli_res: CliRes) -> Result<ExportRes, ClientError> {
+    if !cli_res.has_export() {
+        return Err(ClientError::from(ProtocolError::Unexpected(
+            "expected the records we're exporting".to_string())));
+    }
+    let res = cli_res.take_export();
+    if !res.get_success() {
+        return Err(ClientError::Rejected(res.get_err().to_string()));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use KeyValue;
+    use super::{Encoding, Format, write_record};
+
+    fn line(key: &[u8],
+            value: &[u8],
+            format: Format,
+            encoding: Encoding)
+            -> String {
+        let mut record = KeyValue::new();
+        record.set_key(key.to_vec());
+        record.set_value(value.to_vec());
+        let mut out = vec![];
+        write_record(&mut out, &record, format, encoding).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_record() {
+        assert_eq!(line(b"k", b"say \"hi\"", Format::Json, Encoding::Utf8),
+                   "{\"key\":\"k\",\"value\":\"say \\\"hi\\\"\"}\n");
+        assert_eq!(line(b"k", b"a,\"b\"", Format::Csv, Encoding::Utf8),
+                   "k,\"a,\"\"b\"\"\"\n");
+        assert_eq!(line(b"\x00\xff", b"hi", Format::Csv, Encoding::Hex),
+                   "00ff,6869\n");
+        assert_eq!(line(b"k", b"hi", Format::Json, Encoding::Base64),
+                   "{\"key\":\"aw==\",\"value\":\"aGk=\"}\n");
+
+        let mut record = KeyValue::new();
+        record.set_key(b"\xff".to_vec());
+        record.set_value(vec![]);
+        assert!(write_record(&mut vec![],
+                             &record,
+                             Format::Json,
+                             Encoding::Utf8).is_err());
+        assert_eq!(Format::parse("csv"), Some(Format::Csv));
+        assert_eq!(Encoding::parse("rot13"), None);
+    }
+}
@@ -1,35 +1,147 @@
+use std::cmp;
 use std::collections::BTreeMap;
 use std::io::{self, Error, ErrorKind};
 use std::net::SocketAddr;
 use std::sync::mpsc::channel;
+use std::thread;
 
 use bytes::{Buf, ByteBuf};
+use time;
 use threadpool::ThreadPool;
 use protobuf::{self, Message};
+use rustc_serialize::json::ToJson;
 use mio::{TryRead, TryWrite};
 use mio::tcp::TcpStream;
 
-use {CliReq, CliRes, GetReq, GetRes, RangeBounds, RedirectRes, SetReq,
-     SetRes, Version, CASReq, CASRes, DelReq, DelRes};
-use codec::{self, Codec, Framed};
+use {AuthReq, BackupReq, BackupRes, CliReq, CliRes, ClientError, ConsensusError,
+     CountReq, CountRes, ErrorCode, ErrorRes, ExportReq, GetReq, GetRes,
+     IngestReq, IngestRes, Priority, PromoteReq, PromoteRes, ProtocolError,
+     PutSequentialReq, PutSequentialRes, RangeBounds, RedirectRes,
+     ReloadConfigReq, ReloadConfigRes, SetReq, SetRes, SnapshotReq, StageReq,
+     StageRes, TraceContext, TransferLeaderReq, TransferLeaderRes, TxnReq,
+     TxnRes, Version, WatchReq, CASReq, CASRes, DelReq, DelRes, DeleteRangeReq,
+     DeleteRangeRes, UpdateOp, UpdateReq, UpdateRes};
+use clock::timespec_to_ms;
+use codec::{Codec, Framed};
+use server::namespace::{self, NamespaceSpec};
+
+pub use self::async_client::{AsyncClient, Reply};
+pub use self::bulk_writer::{BulkWriter, ChunkError};
+pub use self::chunker::Chunker;
+pub use self::exporter::{Encoding, Exporter, Format, write_record};
+pub use self::pool::ConnPool;
+pub use self::retry::RetryPolicy;
+pub use self::route_cache::RouteCache;
+pub use self::scan::ScanIter;
+pub use self::snapshot::Snapshot;
+pub use self::txn::Txn;
+pub use self::typed::{JsonEncoded, KeyPart, Ordered, Proto, Raw, Serializer,
+                      TypedClient, TypedExporter, Utf8, key_prefix,
+                      key_prefix_end};
+pub use self::watcher::Watcher;
+
+mod async_client;
+mod bulk_writer;
+mod chunker;
+mod exporter;
+mod mux;
+mod pool;
+mod replies;
+mod retry;
+mod route_cache;
+mod scan;
+mod snapshot;
+mod txn;
+mod typed;
+mod watcher;
+
+// how much of a file to bulk load is sent to a server at a time
+const STAGE_CHUNK: usize = 4 << 20;
 
 pub struct Client {
     servers: Vec<SocketAddr>,
     ranges: BTreeMap<RangeBounds, SocketAddr>,
+    route_cache: RouteCache,
+    retry_policy: RetryPolicy,
     pool: ThreadPool,
+    conns: ConnPool,
+    // serve stale reads, taken in turn from next_replica
+    replicas: Vec<SocketAddr>,
+    next_replica: usize,
     req_counter: u64,
+    // presented at the start of every connection, if set
+    token: Option<String>,
+    priority: Priority,
+    trace: Option<TraceContext>,
 }
 
 impl Client {
     pub fn new(servers: Vec<SocketAddr>, nthreads: usize) -> Client {
+        let conns = ConnPool::new(time::Duration::seconds(1));
+        Client::with_connections(servers, nthreads, conns)
+    }
+
+    // A client that sends its requests over conns, which other clients,
+    // on other threads, may be using at the same time.
+    pub fn with_connections(servers: Vec<SocketAddr>,
+                            nthreads: usize,
+                            conns: ConnPool)
+                            -> Client {
         Client {
             servers: servers,
             ranges: BTreeMap::new(),
+            route_cache: RouteCache::new(time::Duration::seconds(30)),
+            retry_policy: RetryPolicy::default(),
             pool: ThreadPool::new(nthreads),
+            conns: conns,
+            replicas: vec![],
+            next_replica: 0,
             req_counter: 0,
+            token: None,
+            priority: Priority::NORMAL,
+            trace: None,
         }
     }
 
+    // How long a discovered leader is reused before we go back to asking
+    // the servers in order.  A zero duration disables caching.
+    pub fn set_route_cache_max_age(&mut self, max_age: time::Duration) {
+        self.route_cache.set_max_age(max_age);
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    // The connections our requests go over, for other clients to share.
+    pub fn connections(&self) -> ConnPool {
+        self.conns.clone()
+    }
+
+    // For servers started with a root token.  Every client sharing our
+    // connections presents it from then on.
+    pub fn set_token(&mut self, token: &str) {
+        self.token = Some(token.to_string());
+        self.conns.set_token(token);
+    }
+
+    // Servers that serve reads from what they've learned, like learners,
+    // for get_stale to spread its reads across.
+    pub fn set_replicas(&mut self, replicas: Vec<SocketAddr>) {
+        self.replicas = replicas;
+    }
+
+    // How readily an overloaded server may turn our requests away.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    // Sends trace along with every request until it's cleared, so the
+    // spans the servers record for them can be found by its trace id.
+    pub fn set_trace(&mut self, trace: Option<TraceContext>) {
+        self.trace = trace;
+    }
+
     fn get_id(&mut self) -> u64 {
         self.req_counter += 1;
         self.req_counter
@@ -39,7 +151,7 @@ impl Client {
         &mut self,
         key: &'a [u8],
         value: &'a [u8]
-    ) -> io::Result<SetRes> {
+    ) -> Result<SetRes, ClientError> {
 
         let mut set = SetReq::new();
         set.set_key(key.to_vec());
@@ -61,7 +173,7 @@ impl Client {
     pub fn get<'a>(
         &mut self,
         key: &'a [u8],
-    ) -> io::Result<GetRes> {
+    ) -> Result<GetRes, ClientError> {
 
         let mut get = GetReq::new();
         get.set_key(key.to_vec());
@@ -79,12 +191,47 @@ impl Client {
         })
     }
 
+    // Reads key from the next replica that's up, without going through the
+    // leader, so the value may lag the latest writes.  With no replica
+    // able to serve it, key is read from the leader after all.
+    pub fn get_stale(&mut self, key: &[u8]) -> Result<GetRes, ClientError> {
+        let mut get = GetReq::new();
+        get.set_key(key.to_vec());
+        let mut req = CliReq::new();
+        req.set_get(get);
+        req.set_req_id(self.get_id());
+        let deadline = time::get_time() + self.retry_policy.timeout;
+        req.set_deadline_ms(timespec_to_ms(deadline));
+        req.set_priority(self.priority);
+        if let Some(ref trace) = self.trace {
+            req.set_trace(trace.clone());
+        }
+
+        let n = self.replicas.len();
+        for i in 0..n {
+            let replica = self.replicas[(self.next_replica + i) % n];
+            if !self.conns.healthy(&replica) {
+                continue;
+            }
+            match self.conns.call(replica, &req, deadline) {
+                Ok(ref cli_res) if cli_res.has_get() => {
+                    self.next_replica = (self.next_replica + i + 1) % n;
+                    return Ok(cli_res.get_get().clone());
+                }
+                // redirected or refused
+                Ok(_) => debug!("{:?} would not serve a stale read", replica),
+                Err(e) => debug!("could not read from {:?}: {}", replica, e),
+            }
+        }
+        self.get(key)
+    }
+
     pub fn cas<'a>(
         &mut self,
         key: &'a [u8],
         old_value: &'a [u8],
         new_value: &'a [u8]
-    ) -> io::Result<CASRes> {
+    ) -> Result<CASRes, ClientError> {
 
         let mut cas = CASReq::new();
         cas.set_key(key.to_vec());
@@ -104,10 +251,24 @@ impl Client {
         })
     }
 
+    // Sets key to value only if key has no value yet.
+    pub fn create(&mut self,
+                  key: &[u8],
+                  value: &[u8])
+                  -> Result<CASRes, ClientError> {
+        let mut cas = CASReq::new();
+        cas.set_key(key.to_vec());
+        cas.set_new_value(value.to_vec());
+        let mut req = CliReq::new();
+        req.set_cas(cas);
+        req.set_req_id(self.get_id());
+        self.req(key.to_vec(), req).map(|cli_res| cli_res.get_cas().clone())
+    }
+
     pub fn del<'a>(
         &mut self,
         key: &'a [u8],
-    ) -> io::Result<DelRes> {
+    ) -> Result<DelRes, ClientError> {
 
         let mut del = DelReq::new();
         del.set_key(key.to_vec());
@@ -125,50 +286,661 @@ impl Client {
         })
     }
 
-    fn req(&mut self, key: Vec<u8>, req: CliReq) -> io::Result<CliRes> {
-        // send to a peer, they'll redirect us if we're wrong
-        for peer in self.servers.iter() {
-            debug!("trying peer {:?}", peer);
-            let mut stream_attempt = TcpStream::connect(&peer);
-            if stream_attempt.is_err() {
-                continue;
+    // Deletes every key from start up to but not including end.
+    pub fn delete_range(&mut self,
+                        start: &[u8],
+                        end: &[u8])
+                        -> Result<DeleteRangeRes, ClientError> {
+        let mut range = DeleteRangeReq::new();
+        range.set_start(start.to_vec());
+        range.set_end(end.to_vec());
+        let mut req = CliReq::new();
+        req.set_delete_range(range);
+        req.set_req_id(self.get_id());
+
+        self.req(start.to_vec(), req)
+            .map(|cli_res| cli_res.get_delete_range().clone())
+    }
+
+    // How many keys there are from start up to end, or to the end of the
+    // keyspace, and how many bytes they take up, as the leader's range
+    // statistics have it.  Big spans are estimated rather than scanned.
+    pub fn count(&mut self,
+                 start: &[u8],
+                 end: Option<&[u8]>)
+                 -> Result<CountRes, ClientError> {
+        let mut count = CountReq::new();
+        count.set_start(start.to_vec());
+        if let Some(end) = end {
+            count.set_end(end.to_vec());
+        }
+        let mut req = CliReq::new();
+        req.set_count(count);
+        req.set_req_id(self.get_id());
+
+        self.req(start.to_vec(), req).map(|cli_res| cli_res.get_count().clone())
+    }
+
+    // Adds value onto the end of key's, returning how long it is now.
+    pub fn append(&mut self,
+                  key: &[u8],
+                  value: &[u8])
+                  -> Result<UpdateRes, ClientError> {
+        self.update(key, UpdateOp::APPEND, value, None)
+    }
+
+    // Sets key, returning what it was before.
+    pub fn getset(&mut self,
+                  key: &[u8],
+                  value: &[u8])
+                  -> Result<UpdateRes, ClientError> {
+        self.update(key, UpdateOp::GETSET, value, None)
+    }
+
+    // Overwrites key's value from offset on, as far as value goes.
+    pub fn setrange(&mut self,
+                    key: &[u8],
+                    offset: u64,
+                    value: &[u8])
+                    -> Result<UpdateRes, ClientError> {
+        self.update(key, UpdateOp::SETRANGE, value, Some(offset))
+    }
+
+    fn update(&mut self,
+              key: &[u8],
+              op: UpdateOp,
+              value: &[u8],
+              offset: Option<u64>)
+              -> Result<UpdateRes, ClientError> {
+        let mut update = UpdateReq::new();
+        update.set_key(key.to_vec());
+        update.set_op(op);
+        update.set_value(value.to_vec());
+        if let Some(offset) = offset {
+            update.set_offset(offset);
+        }
+        let mut req = CliReq::new();
+        req.set_update(update);
+        req.set_req_id(self.get_id());
+
+        self.req(key.to_vec(), req).map(|cli_res| cli_res.get_update().clone())
+    }
+
+    // Puts value under prefix and the next number in its series, returning
+    // the key it went under.
+    pub fn put_sequential(&mut self,
+                          prefix: &[u8],
+                          value: &[u8])
+                          -> Result<PutSequentialRes, ClientError> {
+        let mut sequential = PutSequentialReq::new();
+        sequential.set_prefix(prefix.to_vec());
+        sequential.set_value(value.to_vec());
+        let mut req = CliReq::new();
+        req.set_put_sequential(sequential);
+        req.set_req_id(self.get_id());
+
+        self.req(prefix.to_vec(), req)
+            .map(|cli_res| cli_res.get_put_sequential().clone())
+    }
+
+    // Opens a snapshot on the leader as of everything it's applied, for
+    // reads that have to agree with each other without the cost of a
+    // transaction.
+    pub fn snapshot(&mut self) -> Result<Snapshot, ClientError> {
+        let mut req = CliReq::new();
+        req.set_snapshot(SnapshotReq::new());
+        req.set_req_id(self.get_id());
+
+        let (server, cli_res) = try!(self.req_served(vec![], req));
+        let snapshot_res = cli_res.get_snapshot();
+        if !snapshot_res.get_success() {
+            let err = snapshot_res.get_err().to_string();
+            return Err(ClientError::Rejected(err));
+        }
+        Ok(Snapshot::new(self,
+                         server,
+                         snapshot_res.get_id(),
+                         snapshot_res.get_txid()))
+    }
+
+    // Runs f as a transaction: its sets and deletes are committed all
+    // together, and only if none of the keys it read has changed since.
+    // If one has, f is run again, after backing off, up to as many times
+    // as the retry policy allows, so it shouldn't do anything but read and
+    // write through the Txn.  A transaction that only reads is committed
+    // too, which makes sure everything it saw was there at once.
+    pub fn transact<T, F>(&mut self, mut f: F) -> Result<T, ClientError>
+        where F: FnMut(&mut Txn) -> Result<T, ClientError>
+    {
+        let mut attempt = 0;
+        loop {
+            let (value, txn_req) = {
+                let mut txn = Txn::new(self);
+                let value = try!(f(&mut txn));
+                if txn.is_empty() {
+                    return Ok(value);
+                }
+                (value, txn.request())
+            };
+            let txn_res = try!(self.commit(txn_req));
+            if txn_res.get_success() {
+                return Ok(value);
+            }
+            attempt += 1;
+            if !txn_res.has_conflict() ||
+               attempt >= self.retry_policy.max_attempts {
+                let err = txn_res.get_err().to_string();
+                return Err(ClientError::Rejected(err));
             }
+            let backoff = self.retry_policy.backoff(attempt - 1);
+            debug!("retrying transaction in {}ms, {:?} changed under attempt \
+                    {}",
+                   backoff.num_milliseconds(),
+                   String::from_utf8_lossy(txn_res.get_conflict()),
+                   attempt);
+            thread::sleep_ms(backoff.num_milliseconds() as u32);
+        }
+    }
+
+    // Sends a transaction's reads and writes to be checked and committed.
+    pub fn commit(&mut self, txn_req: TxnReq) -> Result<TxnRes, ClientError> {
+        let key = txn_req.get_writes()
+                         .first()
+                         .or(txn_req.get_reads().first())
+                         .map(|first| first.get_key().to_vec())
+                         .unwrap_or(vec![]);
+        let mut req = CliReq::new();
+        req.set_txn(txn_req);
+        req.set_req_id(self.get_id());
 
-            let mut stream = stream_attempt.unwrap();
-            let mut codec = Framed::new();
-            let mut msg =
-                codec.encode(ByteBuf::from_slice(&*req.write_to_bytes()
-                                                      .unwrap()));
+        self.req(key, req).map(|cli_res| cli_res.get_txn().clone())
+    }
 
-            if send_to(&mut stream, &mut msg).is_err() {
-                debug!("could not send");
-                continue;
+    // Sets a namespace aside for spec's members, failing if there's one by
+    // that name already.  Only root may.
+    pub fn create_namespace(&mut self,
+                            name: &str,
+                            spec: &NamespaceSpec)
+                            -> Result<(), ClientError> {
+        try!(namespace::check_name(name).map_err(ClientError::Rejected));
+        let spec = spec.to_json().to_string();
+        let key = namespace::spec_key(name);
+        let res = try!(self.create(&key, spec.as_bytes()));
+        if !res.get_success() {
+            return Err(ClientError::Rejected(match res.get_err() {
+                "compare failure" => {
+                    format!("there's a namespace {} already", name)
+                }
+                err => err.to_string(),
+            }));
+        }
+        Ok(())
+    }
+
+    // Changes who's in a namespace and its quotas, leaving its keys be.
+    pub fn update_namespace(&mut self,
+                            name: &str,
+                            spec: &NamespaceSpec)
+                            -> Result<(), ClientError> {
+        let key = namespace::spec_key(name);
+        let old = try!(self.get(&key));
+        if !old.get_success() {
+            let err = format!("there's no namespace {}", name);
+            return Err(ClientError::Rejected(err));
+        }
+        let spec = spec.to_json().to_string();
+        let res = try!(self.cas(&key, old.get_value(), spec.as_bytes()));
+        if !res.get_success() {
+            return Err(ClientError::Rejected(match res.get_err() {
+                "compare failure" => {
+                    format!("namespace {} changed in the meantime", name)
+                }
+                err => err.to_string(),
+            }));
+        }
+        Ok(())
+    }
+
+    // Deletes a namespace along with every key in it.  Its keys go first,
+    // so none are left for its members to stop guarding; any they write
+    // between the two are anyone's afterwards.
+    pub fn delete_namespace(&mut self, name: &str) -> Result<(), ClientError> {
+        let key = namespace::spec_key(name);
+        if !try!(self.get(&key)).get_success() {
+            let err = format!("there's no namespace {}", name);
+            return Err(ClientError::Rejected(err));
+        }
+        let prefix = namespace::key_prefix(name);
+        let end = key_prefix_end(&prefix).unwrap();
+        let res = try!(self.delete_range(&prefix, &end));
+        if !res.get_success() {
+            return Err(ClientError::Rejected(res.get_err().to_string()));
+        }
+        let res = try!(self.del(&key));
+        if !res.get_success() {
+            return Err(ClientError::Rejected(res.get_err().to_string()));
+        }
+        Ok(())
+    }
+
+    // Asks the current leader to hand leadership to the peer with the
+    // given id, e.g. before taking the leader down for maintenance.
+    pub fn transfer_leader(&mut self,
+                           target: &str)
+                           -> Result<TransferLeaderRes, ClientError> {
+        let mut transfer = TransferLeaderReq::new();
+        transfer.set_target(target.to_string());
+        let mut req = CliReq::new();
+        req.set_transfer_leader(transfer);
+        req.set_req_id(self.get_id());
+
+        self.req(vec![], req).map(|cli_res| {
+            let transfer_res = cli_res.get_transfer_leader();
+            debug!("got response success: {} err: {}",
+                     transfer_res.get_success(),
+                     transfer_res.get_err());
+            transfer_res.clone()
+        })
+    }
+
+    // Changes the settings of whichever server answers, so give this
+    // client only the one server you mean.  Settings are TOML, as in the
+    // [server] table of a config file.
+    pub fn reload_config(&mut self,
+                         settings: &str)
+                         -> Result<ReloadConfigRes, ClientError> {
+        let mut reload = ReloadConfigReq::new();
+        reload.set_settings(settings.to_string());
+        let mut req = CliReq::new();
+        req.set_reload_config(reload);
+        req.set_req_id(self.get_id());
+
+        self.req(vec![], req).map(|cli_res| cli_res.get_reload_config().clone())
+    }
+
+    // Has whichever server answers back up everything it has applied into
+    // dir, on its own filesystem, and add it to the manifest there.  As
+    // with reload_config, give this client only the one server you mean.
+    pub fn backup(&mut self, dir: &str) -> Result<BackupRes, ClientError> {
+        let mut backup = BackupReq::new();
+        backup.set_dir(dir.to_string());
+        self.send_backup(backup)
+    }
+
+    // Backs up only what was written since the last backup in dir, if the
+    // server's log still goes back that far, and everything otherwise.
+    pub fn incremental_backup(&mut self,
+                              dir: &str)
+                              -> Result<BackupRes, ClientError> {
+        let mut backup = BackupReq::new();
+        backup.set_dir(dir.to_string());
+        backup.set_incremental(true);
+        self.send_backup(backup)
+    }
+
+    // Or streams the backup to whatever is listening at addr.
+    pub fn backup_to(&mut self, addr: &str) -> Result<BackupRes, ClientError> {
+        let mut backup = BackupReq::new();
+        backup.set_addr(addr.to_string());
+        self.send_backup(backup)
+    }
+
+    fn send_backup(&mut self,
+                   backup: BackupReq)
+                   -> Result<BackupRes, ClientError> {
+        let mut req = CliReq::new();
+        req.set_backup(backup);
+        req.set_req_id(self.get_id());
+
+        self.req(vec![], req).map(|cli_res| cli_res.get_backup().clone())
+    }
+
+    // Stops a cluster mirroring another, so it takes writes of its own.
+    pub fn promote(&mut self) -> Result<PromoteRes, ClientError> {
+        let mut req = CliReq::new();
+        req.set_promote(PromoteReq::new());
+        req.set_req_id(self.get_id());
+
+        self.req(vec![], req).map(|cli_res| cli_res.get_promote().clone())
+    }
+
+    // Stages a piece of a file to bulk load on whichever server answers,
+    // so as with reload_config, give this client only the one server.
+    pub fn stage(&mut self,
+                 file: &str,
+                 offset: u64,
+                 data: &[u8])
+                 -> Result<StageRes, ClientError> {
+        let mut stage = StageReq::new();
+        stage.set_file(file.to_string());
+        stage.set_offset(offset);
+        stage.set_data(data.to_vec());
+        let mut req = CliReq::new();
+        req.set_stage(stage);
+        req.set_req_id(self.get_id());
+
+        self.req(vec![], req).map(|cli_res| cli_res.get_stage().clone())
+    }
+
+    // Has the leader ingest a file already staged on every replica.
+    pub fn ingest(&mut self, file: &str) -> Result<IngestRes, ClientError> {
+        let mut ingest = IngestReq::new();
+        ingest.set_file(file.to_string());
+        let mut req = CliReq::new();
+        req.set_ingest(ingest);
+        req.set_req_id(self.get_id());
+
+        self.req(vec![], req).map(|cli_res| cli_res.get_ingest().clone())
+    }
+
+    // Bulk loads data, as convert_for_ingest makes it, under the name
+    // file: stages it on each of our servers, which had better be every
+    // replica, then ingests it.
+    pub fn bulk_load(&mut self,
+                     file: &str,
+                     data: &[u8])
+                     -> Result<IngestRes, ClientError> {
+        for server in self.servers.clone() {
+            let mut cli = Client::new(vec![server], 1);
+            cli.retry_policy = self.retry_policy.clone();
+            cli.token = self.token.clone();
+            let mut offset = 0;
+            for chunk in data.chunks(STAGE_CHUNK) {
+                let stage_res = try!(cli.stage(file, offset, chunk));
+                if !stage_res.get_success() {
+                    let mut ingest_res = IngestRes::new();
+                    ingest_res.set_success(false);
+                    ingest_res.set_err(format!("could not stage {} on {}: {}",
+                                               file,
+                                               server,
+                                               stage_res.get_err()));
+                    return Ok(ingest_res);
+                }
+                offset = stage_res.get_bytes();
+            }
+        }
+        self.ingest(file)
+    }
+
+    // Exports the keys from start up to end, or to the end of the
+    // keyspace, as of whatever the first server that answers has applied.
+    pub fn export(&mut self,
+                  start: &[u8],
+                  end: Option<&[u8]>)
+                  -> Result<Exporter, ClientError> {
+        let mut export = ExportReq::new();
+        export.set_start(start.to_vec());
+        if let Some(end) = end {
+            export.set_end(end.to_vec());
+        }
+        self.export_with(export)
+    }
+
+    // Exports as export asks, which can have the server leave out keys
+    // and values that don't start with a prefix, or send keys alone.
+    pub fn export_with(&mut self,
+                       export: ExportReq)
+                       -> Result<Exporter, ClientError> {
+        let mut req = CliReq::new();
+        req.set_export(export);
+        req.set_req_id(self.get_id());
+        let deadline = time::get_time() + self.retry_policy.timeout;
+
+        let token = self.token.clone();
+        Exporter::open(&self.servers,
+                       token.as_ref().map(|t| &**t),
+                       req,
+                       deadline,
+                       self.retry_policy.timeout)
+    }
+
+    // Iterates over the keys from start up to end, or to the end of the
+    // keyspace, all as of one snapshot, a page at a time.
+    pub fn scan_iter(&mut self,
+                     start: &[u8],
+                     end: Option<&[u8]>)
+                     -> Result<ScanIter, ClientError> {
+        let mut export = ExportReq::new();
+        export.set_start(start.to_vec());
+        if let Some(end) = end {
+            export.set_end(end.to_vec());
+        }
+        self.scan_iter_with(export)
+    }
+
+    // Like scan_iter, filtered as export asks.  Its limit, if set, is how
+    // many keys each page holds.
+    pub fn scan_iter_with(&mut self,
+                          export: ExportReq)
+                          -> Result<ScanIter, ClientError> {
+        let snapshot = try!(self.snapshot());
+        Ok(ScanIter::new(snapshot, export))
+    }
+
+    // Follows the changes applied to key, or to every key under it when
+    // recursive, starting after since if given and otherwise from now.
+    // Naming a consumer has the server hold on to what it hasn't been sent
+    // for a while after the watch goes away, so it can be resumed from
+    // the Watcher's last_txid.
+    pub fn watch(&mut self,
+                 key: &[u8],
+                 recursive: bool,
+                 since: Option<u64>,
+                 consumer: Option<&str>)
+                 -> Result<Watcher, ClientError> {
+        let mut watch = WatchReq::new();
+        watch.set_key(key.to_vec());
+        watch.set_recursive(recursive);
+        watch.set_historical(since.is_some());
+        watch.set_last_txid(since.unwrap_or(0));
+        if let Some(consumer) = consumer {
+            watch.set_consumer(consumer.to_string());
+        }
+        let mut req = CliReq::new();
+        req.set_watch(watch);
+        req.set_req_id(self.get_id());
+        req.set_priority(self.priority);
+        let deadline = time::get_time() + self.retry_policy.timeout;
+        req.set_deadline_ms(timespec_to_ms(deadline));
+
+        let token = self.token.clone();
+        Watcher::open(&self.servers,
+                      token.as_ref().map(|t| &**t),
+                      req,
+                      deadline)
+    }
+
+    fn req(&mut self,
+           key: Vec<u8>,
+           req: CliReq)
+           -> Result<CliRes, ClientError> {
+        self.req_served(key, req).map(|(_, cli_res)| cli_res)
+    }
+
+    // Like req, but also says which server answered.
+    fn req_served(&mut self,
+                  key: Vec<u8>,
+                  mut req: CliReq)
+                  -> Result<(SocketAddr, CliRes), ClientError> {
+        let deadline = time::get_time() + self.retry_policy.timeout;
+        req.set_deadline_ms(timespec_to_ms(deadline));
+        req.set_priority(self.priority);
+        if let Some(ref trace) = self.trace {
+            req.set_trace(trace.clone());
+        }
+
+        let mut attempt = 0;
+        loop {
+            let err = match self.try_servers(&req, deadline) {
+                Ok(served) => return Ok(served),
+                Err(e) => e,
+            };
+            attempt += 1;
+
+            let now = time::get_time();
+            if now >= deadline {
+                return Err(ClientError::DeadlineExceeded);
             }
-            match recv_into(&mut stream, &mut codec) {
-                Ok(res_buf) => {
-                    let res: &[u8] = res_buf.bytes();
-                    let cli_res: CliRes = protobuf::parse_from_bytes(res)
-                                              .unwrap();
+            if attempt >= self.retry_policy.max_attempts {
+                return Err(err);
+            }
+
+            // a busy range says how long it expects to need
+            let backoff = match err {
+                ClientError::Busy(_, retry_after) => retry_after,
+                _ => self.retry_policy.backoff(attempt - 1),
+            };
+            let backoff = cmp::min(backoff, deadline - now);
+            debug!("retrying request {} in {}ms (attempt {}, last err: {})",
+                   req.get_req_id(),
+                   backoff.num_milliseconds(),
+                   attempt,
+                   err);
+            thread::sleep_ms(backoff.num_milliseconds() as u32);
+        }
+    }
+
+    // Makes a single pass over the known servers, returning the first
+    // non-redirect response and who sent it, or the most informative
+    // failure we saw.
+    fn try_servers(&mut self,
+                   req: &CliReq,
+                   deadline: time::Timespec)
+                   -> Result<(SocketAddr, CliRes), ClientError> {
+        // try the last known leader first, then fall back to the rest
+        // of the servers, which will redirect us if they aren't leading.
+        let mut candidates = vec![];
+        match self.route_cache.get(time::get_time()) {
+            Some(leader) => candidates.push(leader),
+            None => (),
+        }
+        for peer in self.servers.iter() {
+            if !candidates.contains(peer) {
+                candidates.push(*peer);
+            }
+        }
+
+        // servers marked down are tried last, in case they're all down
+        let (up, down): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            candidates.into_iter().partition(|peer| self.conns.healthy(peer));
+
+        let mut last_err = ClientError::Unreachable;
+        for peer in up.into_iter().chain(down) {
+            debug!("trying peer {:?}", peer);
+            match self.conns.call(peer, req, deadline) {
+                Ok(cli_res) => {
+                    if cli_res.has_error() {
+                        return Err(refusal(cli_res.get_error()));
+                    }
                     if cli_res.has_redirect() {
+                        let redirect = cli_res.get_redirect();
                         debug!("we got redirect to {}!",
-                                 cli_res.get_redirect().get_address());
+                                 redirect.get_address());
+                        // leadership has moved, so forget what we knew
+                        self.route_cache.invalidate(&peer);
+                        last_err = if redirect.get_success() {
+                            ClientError::from(ConsensusError::NotLeader {
+                                leader: Some(redirect.get_address()
+                                                     .to_string()),
+                            })
+                        } else {
+                            ClientError::from(ConsensusError::NoLeader)
+                        };
                         // TODO(tyler) try redirected host next
                         continue;
                     }
-                    return Ok(cli_res);
+                    self.route_cache.update(peer, time::get_time());
+                    return Ok((peer, cli_res));
                 }
                 Err(e) => {
-                    debug!("got err on recv_into: {}", e);
+                    debug!("could not get a reply from {:?}: {}", peer, e);
+                    self.route_cache.invalidate(&peer);
+                    match e {
+                        // every server checks the same credentials
+                        ClientError::Rejected(_) => return Err(e),
+                        // and we should back off before trying any more
+                        ClientError::Throttled(_) => return Err(e),
+                        // nor would another find the quota any roomier
+                        ClientError::QuotaExceeded(_) => return Err(e),
+                        _ => (),
+                    }
+                    last_err = e;
                     continue;
                 }
             }
         }
-        Err(Error::new(ErrorKind::Other, "unable to reach any servers!"))
+        Err(last_err)
+    }
+}
+
+// Presents our token as the first frame on a new connection.
+fn authenticate(stream: &mut TcpStream,
+                codec: &mut Framed,
+                token: &str,
+                req_id: u64,
+                deadline: time::Timespec)
+                -> Result<(), ClientError> {
+    let mut auth = AuthReq::new();
+    auth.set_token(token.to_string());
+    let mut req = CliReq::new();
+    req.set_auth(auth);
+    req.set_req_id(req_id);
+    req.set_deadline_ms(timespec_to_ms(deadline));
+    let bytes = req.write_to_bytes().unwrap();
+    let mut msg = codec.encode(ByteBuf::from_slice(&*bytes));
+    try!(send_to(stream, &mut msg, deadline));
+
+    let res_buf = try!(recv_into(stream, codec, deadline));
+    let cli_res: CliRes = try!(protobuf::parse_from_bytes(res_buf.bytes())
+                                   .map_err(ProtocolError::from));
+    if cli_res.has_error() {
+        return Err(refusal(cli_res.get_error()));
+    }
+    if !cli_res.has_auth() {
+        return Err(ClientError::from(ProtocolError::Unexpected(
+            "expected a reply to our token".to_string())));
+    }
+    let auth_res = cli_res.get_auth();
+    if !auth_res.get_success() {
+        return Err(ClientError::Rejected(auth_res.get_err().to_string()));
+    }
+    debug!("authenticated as {}", auth_res.get_principal());
+    Ok(())
+}
+
+// The ClientError an error reply stands for.
+pub fn refusal(error: &ErrorRes) -> ClientError {
+    match error.get_code() {
+        ErrorCode::THROTTLED if error.has_code() => {
+            ClientError::Throttled(error.get_err().to_string())
+        }
+        ErrorCode::OVERLOADED if error.has_code() => {
+            ClientError::Overloaded(error.get_err().to_string())
+        }
+        ErrorCode::BUSY if error.has_code() => {
+            let retry_after = error.get_retry_after_ms() as i64;
+            ClientError::Busy(error.get_err().to_string(),
+                              time::Duration::milliseconds(retry_after))
+        }
+        ErrorCode::DISK_FULL if error.has_code() => {
+            ClientError::DiskFull(error.get_err().to_string())
+        }
+        ErrorCode::QUOTA_EXCEEDED if error.has_code() => {
+            ClientError::QuotaExceeded(error.get_err().to_string())
+        }
+        _ => ClientError::Rejected(error.get_err().to_string()),
     }
 }
 
-fn send_to(stream: &mut TcpStream, buf: &mut ByteBuf) -> io::Result<()> {
+fn send_to(stream: &mut TcpStream,
+           buf: &mut ByteBuf,
+           deadline: time::Timespec)
+           -> io::Result<()> {
     loop {
+        if time::get_time() >= deadline {
+            return Err(Error::new(ErrorKind::TimedOut,
+                                  "request deadline exceeded"));
+        }
         match stream.try_write_buf(buf) {
             Ok(None) => {
                 continue;
@@ -195,9 +967,14 @@ fn send_to(stream: &mut TcpStream, buf: &mut ByteBuf) -> io::Result<()> {
 }
 
 fn recv_into<T>(stream: &mut TcpStream,
-                codec: &mut Codec<ByteBuf, T>)
+                codec: &mut Codec<ByteBuf, T>,
+                deadline: time::Timespec)
                 -> io::Result<T> {
     loop {
+        if time::get_time() >= deadline {
+            return Err(Error::new(ErrorKind::TimedOut,
+                                  "request deadline exceeded"));
+        }
         let mut res_buf = ByteBuf::mut_with_capacity(1024);
         match stream.try_read_buf(&mut res_buf) {
             Ok(None) => {
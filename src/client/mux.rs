@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bytes::{Buf, ByteBuf};
+use mio::TryRead;
+use mio::tcp::{Shutdown, TcpStream};
+use protobuf::{self, Message};
+use time;
+
+use {CliReq, CliRes, ClientError};
+use client::{authenticate, send_to};
+use codec::{self, Codec, Framed};
+
+// Called once, from the connection's reader thread, with the reply to a
+// request or why there won't be one.
+pub type OnReply = Box<FnMut(Result<CliRes, ClientError>) + Send>;
+
+struct Waiting {
+    deadline: time::Timespec,
+    on_reply: OnReply,
+}
+
+// What a connection shares with its reader thread.
+struct Shared {
+    // req_id -> who's waiting on its reply
+    waiting: Mutex<BTreeMap<u64, Waiting>>,
+    // why the connection can't be used any more, once it can't
+    broken: Mutex<Option<String>>,
+}
+
+// A connection to one server that many requests share.  Each request is
+// written as it's made, and a reader thread of the connection's own hands
+// every reply to whatever waits on its req_id, so replies come back in
+// whatever order the server finishes the requests in.
+pub struct MuxConn {
+    stream: Mutex<TcpStream>,
+    shared: Arc<Shared>,
+}
+
+impl MuxConn {
+    // Connects and greets the server, presenting token if given, before
+    // handing the connection to its reader.
+    pub fn open(addr: SocketAddr,
+                token: Option<&str>,
+                deadline: time::Timespec)
+                -> Result<MuxConn, ClientError> {
+        let mut stream = try!(TcpStream::connect(&addr));
+        let mut codec = Framed::new();
+        let hello = codec.hello().write_to_bytes().unwrap();
+        try!(send_to(&mut stream,
+                     &mut codec.encode_hello(ByteBuf::from_slice(&hello)),
+                     deadline));
+        if let Some(token) = token {
+            try!(authenticate(&mut stream, &mut codec, token, 0, deadline));
+        }
+        let shared = Arc::new(Shared {
+            waiting: Mutex::new(BTreeMap::new()),
+            broken: Mutex::new(None),
+        });
+        let reader = try!(stream.try_clone());
+        let reader_shared = shared.clone();
+        thread::spawn(move || read_replies(reader, codec, reader_shared));
+        Ok(MuxConn {
+            stream: Mutex::new(stream),
+            shared: shared,
+        })
+    }
+
+    pub fn broken(&self) -> Option<String> {
+        self.shared.broken.lock().unwrap().clone()
+    }
+
+    // Writes req and has on_reply called with its reply, or with
+    // DeadlineExceeded once deadline passes without one.  If req can't be
+    // written we say why, and on_reply is dropped uncalled.
+    pub fn send(&self,
+                req: &CliReq,
+                deadline: time::Timespec,
+                on_reply: OnReply)
+                -> Result<(), ClientError> {
+        if let Some(e) = self.broken() {
+            return Err(broken_conn(&e));
+        }
+        let req_id = req.get_req_id();
+        // waiting before it's written, so that a quick reply finds it
+        self.shared.waiting.lock().unwrap().insert(req_id,
+                                                   Waiting {
+                                                       deadline: deadline,
+                                                       on_reply: on_reply,
+                                                   });
+        let bytes = req.write_to_bytes().unwrap();
+        let sent = {
+            let mut stream = self.stream.lock().unwrap();
+            codec::encode_for(None, codec::DEFAULT_MAX_FRAME, &*bytes)
+                .into_iter()
+                .fold(Ok(()), |sent, mut msg| {
+                    sent.and_then(|_| send_to(&mut stream, &mut msg, deadline))
+                })
+        };
+        match sent {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                match self.shared.waiting.lock().unwrap().remove(&req_id) {
+                    Some(_) => Err(ClientError::from(e)),
+                    // the reader gave up on it, and already said why
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MuxConn {
+    fn drop(&mut self) {
+        // the reader notices and fails whatever's still waiting
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+fn read_replies(mut stream: TcpStream, mut codec: Framed, shared: Arc<Shared>) {
+    loop {
+        let mut buf = ByteBuf::mut_with_capacity(64 * 1024);
+        match stream.try_read_buf(&mut buf) {
+            Ok(Some(0)) => {
+                return shared.break_off("the server hung up".to_string())
+            }
+            Ok(Some(_)) => {
+                for msg in codec.decode(&mut buf.flip()) {
+                    let parsed = protobuf::parse_from_bytes(msg.bytes());
+                    let cli_res: CliRes = match parsed {
+                        Ok(cli_res) => cli_res,
+                        Err(e) => {
+                            return shared.break_off(format!("bad reply: {}", e))
+                        }
+                    };
+                    shared.reply(cli_res);
+                }
+                if let Some(e) = codec.broken() {
+                    return shared.break_off(e.to_string());
+                }
+            }
+            Ok(None) => thread::sleep_ms(1),
+            Err(e) => return shared.break_off(e.to_string()),
+        }
+        shared.expire(time::get_time());
+    }
+}
+
+impl Shared {
+    fn reply(&self, cli_res: CliRes) {
+        let waiting =
+            self.waiting.lock().unwrap().remove(&cli_res.get_req_id());
+        match waiting {
+            // called without the lock, so it can send more
+            Some(mut waiting) => (waiting.on_reply)(Ok(cli_res)),
+            None => {
+                debug!("nobody is waiting on request {} any more",
+                       cli_res.get_req_id())
+            }
+        }
+    }
+
+    fn expire(&self, now: time::Timespec) {
+        let expired: Vec<Waiting> = {
+            let mut waiting = self.waiting.lock().unwrap();
+            let ids: Vec<u64> = waiting.iter()
+                                       .filter(|&(_, w)| w.deadline <= now)
+                                       .map(|(id, _)| *id)
+                                       .collect();
+            ids.iter().filter_map(|id| waiting.remove(id)).collect()
+        };
+        for mut waiting in expired {
+            (waiting.on_reply)(Err(ClientError::DeadlineExceeded));
+        }
+    }
+
+    fn break_off(&self, reason: String) {
+        debug!("connection broken: {}", reason);
+        *self.broken.lock().unwrap() = Some(reason.clone());
+        let waiting: Vec<Waiting> = {
+            let mut waiting = self.waiting.lock().unwrap();
+            let ids: Vec<u64> = waiting.keys().cloned().collect();
+            ids.iter().filter_map(|id| waiting.remove(id)).collect()
+        };
+        for mut waiting in waiting {
+            (waiting.on_reply)(Err(broken_conn(&reason)));
+        }
+    }
+}
+
+fn broken_conn(reason: &str) -> ClientError {
+    ClientError::Io(io::Error::new(io::ErrorKind::ConnectionAborted,
+                                   reason.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use std::sync::mpsc::{Receiver, channel};
+
+    use time;
+
+    use {CliRes, ClientError};
+    use super::{OnReply, Shared, Waiting};
+
+    fn at(sec: i64) -> time::Timespec {
+        time::Timespec { sec: sec, nsec: 0 }
+    }
+
+    fn res(req_id: u64) -> CliRes {
+        let mut res = CliRes::new();
+        res.set_req_id(req_id);
+        res
+    }
+
+    // Waits on each of req_ids until its deadline, each reply landing in
+    // the receiver along with the req_id it was waited on under.
+    fn waiting_on(shared: &Shared,
+                  req_ids: &[(u64, time::Timespec)])
+                  -> Receiver<(u64, Result<CliRes, ClientError>)> {
+        let (tx, rx) = channel();
+        for &(req_id, deadline) in req_ids {
+            let tx = tx.clone();
+            let on_reply: OnReply = Box::new(move |res| {
+                tx.send((req_id, res)).unwrap();
+            });
+            let waiting = Waiting {
+                deadline: deadline,
+                on_reply: on_reply,
+            };
+            shared.waiting.lock().unwrap().insert(req_id, waiting);
+        }
+        rx
+    }
+
+    fn shared() -> Shared {
+        Shared {
+            waiting: Mutex::new(BTreeMap::new()),
+            broken: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn test_replies_out_of_order() {
+        let shared = shared();
+        let rx = waiting_on(&shared, &[(1, at(10)), (2, at(10)), (3, at(10))]);
+        shared.reply(res(3));
+        shared.reply(res(1));
+        // nobody's waiting on these, so they go nowhere
+        shared.reply(res(1));
+        shared.reply(res(4));
+        shared.reply(res(2));
+        let replied: Vec<(u64, u64)> = (0..3)
+                                           .map(|_| rx.try_recv().unwrap())
+                                           .map(|(id, res)| {
+                                               (id, res.unwrap().get_req_id())
+                                           })
+                                           .collect();
+        assert_eq!(replied, vec![(3, 3), (1, 1), (2, 2)]);
+        assert!(rx.try_recv().is_err());
+        assert!(shared.waiting.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expiring_and_breaking_off() {
+        let shared = shared();
+        let rx = waiting_on(&shared, &[(1, at(5)), (2, at(10)), (3, at(10))]);
+        shared.expire(at(7));
+        match rx.try_recv().unwrap() {
+            (1, Err(ClientError::DeadlineExceeded)) => (),
+            other => panic!("expected request 1 to expire, not {:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+
+        shared.reply(res(2));
+        shared.break_off("the server hung up".to_string());
+        assert_eq!(rx.try_recv().unwrap().0, 2);
+        match rx.try_recv().unwrap() {
+            (3, Err(ClientError::Io(_))) => (),
+            other => panic!("expected request 3 to fail, not {:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(shared.broken.lock().unwrap().clone(),
+                   Some("the server hung up".to_string()));
+    }
+}
@@ -0,0 +1,235 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::channel;
+use std::thread;
+
+use time;
+
+use {CliReq, CliRes, ClientError};
+use client::mux::MuxConn;
+
+// how long a server that just failed us is left alone, doubling with each
+// failure in a row up to MAX_DOWN_MS
+const MIN_DOWN_MS: i64 = 100;
+const MAX_DOWN_MS: i64 = 5000;
+
+// how long the health checker gives a server to take a connection
+const CHECK_TIMEOUT_MS: i64 = 1000;
+
+struct Server {
+    conn: Option<Arc<MuxConn>>,
+    // failures in a row, and until when we leave it alone because of them
+    failures: u32,
+    down_until: Option<time::Timespec>,
+}
+
+struct Pooled {
+    servers: Mutex<BTreeMap<SocketAddr, Server>>,
+    // presented at the start of every connection, if set
+    token: Mutex<Option<String>>,
+}
+
+// One connection to each server we talk to, kept open between requests
+// and shared by all of them, so requests made at once from several
+// threads are multiplexed over it.  A server whose connection breaks, or
+// that can't be reached, is marked down and skipped for a while, longer
+// after each failure in a row.  In the background, every health_interval,
+// broken connections to servers due another try are opened again, so a
+// server that comes back is ready before a request needs it.  Clones share
+// connections.
+#[derive(Clone)]
+pub struct ConnPool {
+    pooled: Arc<Pooled>,
+}
+
+impl ConnPool {
+    pub fn new(health_interval: time::Duration) -> ConnPool {
+        let pooled = Arc::new(Pooled {
+            servers: Mutex::new(BTreeMap::new()),
+            token: Mutex::new(None),
+        });
+        let weak = Arc::downgrade(&pooled);
+        thread::spawn(move || check_health(weak, health_interval));
+        ConnPool { pooled: pooled }
+    }
+
+    // For servers started with a root token.  Connections already open are
+    // closed, once the requests on them are answered, and opened again
+    // with it.
+    pub fn set_token(&self, token: &str) {
+        *self.pooled.token.lock().unwrap() = Some(token.to_string());
+        for server in self.pooled.servers.lock().unwrap().values_mut() {
+            server.conn = None;
+        }
+    }
+
+    // Whether addr isn't marked down.
+    pub fn healthy(&self, addr: &SocketAddr) -> bool {
+        let servers = self.pooled.servers.lock().unwrap();
+        servers.get(addr)
+               .map_or(true, |server| !server.is_down(time::get_time()))
+    }
+
+    // How many servers we hold open connections to.
+    pub fn connected(&self) -> usize {
+        self.pooled
+            .servers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|server| {
+                server.conn
+                      .as_ref()
+                      .map_or(false, |conn| conn.broken().is_none())
+            })
+            .count()
+    }
+
+    // The open connection to addr, or a new one if it hasn't got one that
+    // works.  Failing to open one marks addr down.
+    pub fn conn(&self,
+                addr: SocketAddr,
+                deadline: time::Timespec)
+                -> Result<Arc<MuxConn>, ClientError> {
+        if let Some(conn) = self.open_conn(&addr) {
+            return Ok(conn);
+        }
+        // opened without the lock, so other servers can still be used
+        let token = self.pooled.token.lock().unwrap().clone();
+        match MuxConn::open(addr, token.as_ref().map(|t| &**t), deadline) {
+            Ok(conn) => {
+                let conn = Arc::new(conn);
+                let mut servers = self.pooled.servers.lock().unwrap();
+                let server = servers.entry(addr).or_insert(Server::new());
+                server.conn = Some(conn.clone());
+                server.failures = 0;
+                server.down_until = None;
+                Ok(conn)
+            }
+            Err(e) => {
+                self.failed(&addr);
+                Err(e)
+            }
+        }
+    }
+
+    // Closes our connection to addr, which let a request down, and leaves
+    // addr alone for a while.
+    pub fn failed(&self, addr: &SocketAddr) {
+        let mut servers = self.pooled.servers.lock().unwrap();
+        let server = servers.entry(*addr).or_insert(Server::new());
+        server.conn = None;
+        server.failures += 1;
+        server.down_until = Some(time::get_time() + down_for(server.failures));
+    }
+
+    // Sends req over our connection to addr and waits for its reply.
+    pub fn call(&self,
+                addr: SocketAddr,
+                req: &CliReq,
+                deadline: time::Timespec)
+                -> Result<CliRes, ClientError> {
+        let conn = try!(self.conn(addr, deadline));
+        let (tx, rx) = channel();
+        let sent = conn.send(req,
+                             deadline,
+                             Box::new(move |res| {
+                                 let _ = tx.send(res);
+                             }));
+        let res = match sent {
+            Ok(()) => rx.recv().unwrap_or(Err(ClientError::Unreachable)),
+            Err(e) => Err(e),
+        };
+        if let Err(ClientError::Io(_)) = res {
+            self.failed(&addr);
+        }
+        res
+    }
+
+    fn open_conn(&self, addr: &SocketAddr) -> Option<Arc<MuxConn>> {
+        let servers = self.pooled.servers.lock().unwrap();
+        servers.get(addr)
+               .and_then(|server| server.conn.as_ref())
+               .and_then(|conn| {
+                   if conn.broken().is_none() {
+                       Some(conn.clone())
+                   } else {
+                       None
+                   }
+               })
+    }
+}
+
+impl Server {
+    fn new() -> Server {
+        Server {
+            conn: None,
+            failures: 0,
+            down_until: None,
+        }
+    }
+
+    fn is_down(&self, now: time::Timespec) -> bool {
+        self.down_until.map_or(false, |until| now < until)
+    }
+}
+
+fn down_for(failures: u32) -> time::Duration {
+    let doublings = cmp::min(failures.saturating_sub(1), 16);
+    time::Duration::milliseconds(cmp::min(MIN_DOWN_MS << doublings,
+                                          MAX_DOWN_MS))
+}
+
+// Runs until the pool is dropped.
+fn check_health(pooled: Weak<Pooled>, interval: time::Duration) {
+    loop {
+        thread::sleep_ms(cmp::max(interval.num_milliseconds(), 1) as u32);
+        let pool = match pooled.upgrade() {
+            Some(pooled) => ConnPool { pooled: pooled },
+            None => return,
+        };
+        let now = time::get_time();
+        let due: Vec<SocketAddr> = {
+            let servers = pool.pooled.servers.lock().unwrap();
+            servers.iter()
+                   .filter(|&(_, server)| {
+                       !server.is_down(now) &&
+                       server.conn
+                             .as_ref()
+                             .map_or(true, |conn| conn.broken().is_some())
+                   })
+                   .map(|(addr, _)| *addr)
+                   .collect()
+        };
+        for addr in due {
+            let timeout = time::Duration::milliseconds(CHECK_TIMEOUT_MS);
+            let deadline = time::get_time() + timeout;
+            if let Err(e) = pool.conn(addr, deadline) {
+                debug!("{:?} is still down: {}", addr, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use super::{ConnPool, down_for};
+
+    #[test]
+    fn test_marking_servers_down() {
+        assert_eq!(down_for(1), Duration::milliseconds(100));
+        assert_eq!(down_for(3), Duration::milliseconds(400));
+        assert_eq!(down_for(40), Duration::milliseconds(5000));
+
+        let pool = ConnPool::new(Duration::seconds(60));
+        let addr = "127.0.0.1:1".parse().unwrap();
+        assert!(pool.healthy(&addr));
+        pool.failed(&addr);
+        assert!(!pool.healthy(&addr));
+        assert_eq!(pool.connected(), 0);
+    }
+}
@@ -0,0 +1,129 @@
+use std::net::SocketAddr;
+use std::thread;
+
+use bytes::{Buf, ByteBuf};
+use mio::TryRead;
+use mio::tcp::TcpStream;
+use protobuf::{self, Message};
+use time;
+
+use {CliReq, CliRes, ClientError, ProtocolError};
+use client::{authenticate, refusal, send_to};
+use codec::{self, Codec, Framed};
+
+// A request that's answered with a stream of replies, over a connection of
+// its own, like a watch or an export.
+pub struct Replies {
+    stream: TcpStream,
+    codec: Framed,
+    req_id: u64,
+    // decoded but not yet handed out
+    received: Vec<ByteBuf>,
+}
+
+impl Replies {
+    // Sends req to the first of servers that answers it, along with that
+    // first answer.
+    pub fn open(servers: &[SocketAddr],
+                token: Option<&str>,
+                req: &CliReq,
+                deadline: time::Timespec)
+                -> Result<(Replies, CliRes), ClientError> {
+        let mut last_err = ClientError::Unreachable;
+        for peer in servers {
+            match Replies::open_on(peer, token, req, deadline) {
+                Ok(opened) => return Ok(opened),
+                // every server checks the same credentials
+                Err(ClientError::Rejected(e)) => {
+                    return Err(ClientError::Rejected(e))
+                }
+                Err(e) => {
+                    debug!("could not send request {} to {:?}: {}",
+                           req.get_req_id(),
+                           peer,
+                           e);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn open_on(peer: &SocketAddr,
+               token: Option<&str>,
+               req: &CliReq,
+               deadline: time::Timespec)
+               -> Result<(Replies, CliRes), ClientError> {
+        let mut stream = try!(TcpStream::connect(peer));
+        let mut codec = Framed::new();
+        let hello = codec.hello().write_to_bytes().unwrap();
+        try!(send_to(&mut stream,
+                     &mut codec.encode_hello(ByteBuf::from_slice(&hello)),
+                     deadline));
+        if let Some(token) = token {
+            try!(authenticate(&mut stream,
+                              &mut codec,
+                              token,
+                              req.get_req_id(),
+                              deadline));
+        }
+        for mut msg in codec::encode_for(None,
+                                         codec::DEFAULT_MAX_FRAME,
+                                         &*req.write_to_bytes().unwrap()) {
+            try!(send_to(&mut stream, &mut msg, deadline));
+        }
+        let mut replies = Replies {
+            stream: stream,
+            codec: codec,
+            req_id: req.get_req_id(),
+            received: vec![],
+        };
+        let timeout = deadline - time::get_time();
+        match try!(replies.recv(timeout)) {
+            Some(res) => Ok((replies, res)),
+            None => Err(ClientError::DeadlineExceeded),
+        }
+    }
+
+    // Waits up to timeout for the next reply, returning none if there
+    // wasn't one by then.
+    pub fn recv(&mut self,
+                timeout: time::Duration)
+                -> Result<Option<CliRes>, ClientError> {
+        let until = time::get_time() + timeout;
+        loop {
+            if !self.received.is_empty() {
+                let buf = self.received.remove(0);
+                let cli_res: CliRes =
+                    try!(protobuf::parse_from_bytes(buf.bytes())
+                             .map_err(ProtocolError::from));
+                if cli_res.has_error() {
+                    return Err(refusal(cli_res.get_error()));
+                }
+                if cli_res.get_req_id() != self.req_id {
+                    let err = format!("expected replies to request {}",
+                                      self.req_id);
+                    let unexpected = ProtocolError::Unexpected(err);
+                    return Err(ClientError::from(unexpected));
+                }
+                return Ok(Some(cli_res));
+            }
+            if time::get_time() >= until {
+                return Ok(None);
+            }
+            let mut buf = ByteBuf::mut_with_capacity(64 * 1024);
+            match self.stream.try_read_buf(&mut buf) {
+                Ok(Some(0)) => {
+                    return Err(ClientError::from(ProtocolError::Unexpected(
+                        "the server stopped replying".to_string())));
+                }
+                Ok(Some(_)) => {
+                    let decoded = self.codec.decode(&mut buf.flip());
+                    self.received.extend(decoded);
+                }
+                Ok(None) => thread::sleep_ms(1),
+                Err(e) => return Err(ClientError::from(e)),
+            }
+        }
+    }
+}
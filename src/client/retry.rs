@@ -0,0 +1,68 @@
+use std::cmp;
+
+use rand::{Rng, thread_rng};
+use time;
+
+// Controls how hard the client tries before giving up on a request.
+// Between passes over the server list we sleep for an exponentially
+// growing, jittered interval, and we never keep trying past the deadline.
+// The deadline is also sent to the server, so it can drop work that
+// nobody is waiting for anymore.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: time::Duration,
+    pub max_backoff: time::Duration,
+    pub timeout: time::Duration,
+}
+
+impl RetryPolicy {
+    pub fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: time::Duration::milliseconds(50),
+            max_backoff: time::Duration::seconds(2),
+            timeout: time::Duration::seconds(15),
+        }
+    }
+
+    // A policy that makes a single pass over the servers, as the client
+    // used to before retries existed.
+    pub fn no_retry() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    // Full jitter: pick uniformly between zero and the capped exponential
+    // backoff for this attempt, so clients that failed together don't
+    // come back together.
+    pub fn backoff(&self, attempt: u32) -> time::Duration {
+        let base = self.initial_backoff.num_milliseconds();
+        let cap = self.max_backoff.num_milliseconds();
+        let exp = base.saturating_mul(1 << cmp::min(attempt, 30));
+        let ceiling = cmp::max(cmp::min(exp, cap), 1);
+        time::Duration::milliseconds(thread_rng().gen_range(0, ceiling + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time;
+
+    use super::RetryPolicy;
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..64 {
+            let backoff = policy.backoff(attempt);
+            assert!(backoff >= time::Duration::zero());
+            assert!(backoff <= policy.max_backoff);
+        }
+        for _ in 0..100 {
+            assert!(policy.backoff(0) <= policy.initial_backoff);
+        }
+    }
+}
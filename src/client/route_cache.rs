@@ -0,0 +1,84 @@
+use std::net::SocketAddr;
+use std::ops::Add;
+
+use time;
+
+// Remembers which server last served our requests as leader, so that we
+// don't have to walk the whole server list (collecting redirects) on every
+// request.  Entries are dropped when the server tells us it is no longer
+// the leader, when it becomes unreachable, or when they reach max_age,
+// whichever happens first.
+pub struct RouteCache {
+    max_age: time::Duration,
+    leader: Option<(SocketAddr, time::Timespec)>,
+}
+
+impl RouteCache {
+    pub fn new(max_age: time::Duration) -> RouteCache {
+        RouteCache {
+            max_age: max_age,
+            leader: None,
+        }
+    }
+
+    pub fn set_max_age(&mut self, max_age: time::Duration) {
+        self.max_age = max_age;
+    }
+
+    pub fn get(&self, now: time::Timespec) -> Option<SocketAddr> {
+        match self.leader {
+            Some((addr, cached_at)) if now < cached_at.add(self.max_age) =>
+                Some(addr),
+            _ => None,
+        }
+    }
+
+    pub fn update(&mut self, addr: SocketAddr, now: time::Timespec) {
+        if self.max_age > time::Duration::zero() {
+            self.leader = Some((addr, now));
+        }
+    }
+
+    pub fn invalidate(&mut self, addr: &SocketAddr) {
+        if self.leader.map(|(cached, _)| cached == *addr).unwrap_or(false) {
+            debug!("invalidating cached leader {}", addr);
+            self.leader = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use time;
+
+    use super::RouteCache;
+
+    fn at(sec: i64) -> time::Timespec {
+        time::Timespec { sec: sec, nsec: 0 }
+    }
+
+    #[test]
+    fn test_route_cache_expiry_and_invalidation() {
+        let a: SocketAddr = "127.0.0.1:8888".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:8889".parse().unwrap();
+        let mut cache = RouteCache::new(time::Duration::seconds(10));
+        assert!(cache.get(at(0)) == None);
+
+        cache.update(a, at(0));
+        assert!(cache.get(at(9)) == Some(a));
+        assert!(cache.get(at(10)) == None);
+
+        // invalidating some other server leaves our entry alone
+        cache.invalidate(&b);
+        assert!(cache.get(at(1)) == Some(a));
+        cache.invalidate(&a);
+        assert!(cache.get(at(1)) == None);
+
+        // a zero max age disables caching entirely
+        cache.set_max_age(time::Duration::zero());
+        cache.update(a, at(0));
+        assert!(cache.get(at(0)) == None);
+    }
+}
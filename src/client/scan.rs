@@ -0,0 +1,76 @@
+use std::vec;
+
+use {ClientError, ExportReq, KeyValue};
+use client::Snapshot;
+
+// how many keys a scan asks for at a time, unless told otherwise
+pub const SCAN_PAGE: u64 = 1000;
+
+// The records of a scan, fetched from the snapshot a page at a time, each
+// page an export going on from the cursor the last one ended with.  The
+// snapshot's held until it's dropped, so a long scan sees no writes made
+// since it started.
+pub struct ScanIter<'a> {
+    snapshot: Snapshot<'a>,
+    export: ExportReq,
+    page: vec::IntoIter<KeyValue>,
+    cursor: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl<'a> ScanIter<'a> {
+    pub fn new(snapshot: Snapshot<'a>, mut export: ExportReq) -> ScanIter<'a> {
+        if !export.has_limit() {
+            export.set_limit(SCAN_PAGE);
+        }
+        ScanIter {
+            snapshot: snapshot,
+            export: export,
+            page: vec![].into_iter(),
+            cursor: None,
+            done: false,
+        }
+    }
+
+    // the txid every record is as of
+    pub fn txid(&self) -> u64 {
+        self.snapshot.txid()
+    }
+
+    fn fetch(&mut self) -> Result<Vec<KeyValue>, ClientError> {
+        let mut export = self.export.clone();
+        if let Some(cursor) = self.cursor.take() {
+            export.set_cursor(cursor);
+        }
+        let mut exporter = try!(self.snapshot.export_with(export));
+        let mut records = vec![];
+        while let Some(batch) = try!(exporter.next()) {
+            records.extend(batch);
+        }
+        self.cursor = exporter.cursor().map(|c| c.to_vec());
+        self.done = self.cursor.is_none();
+        Ok(records)
+    }
+}
+
+impl<'a> Iterator for ScanIter<'a> {
+    type Item = Result<KeyValue, ClientError>;
+
+    fn next(&mut self) -> Option<Result<KeyValue, ClientError>> {
+        loop {
+            if let Some(record) = self.page.next() {
+                return Some(Ok(record));
+            }
+            if self.done {
+                return None;
+            }
+            match self.fetch() {
+                Ok(records) => self.page = records.into_iter(),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
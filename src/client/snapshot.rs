@@ -0,0 +1,110 @@
+use std::net::SocketAddr;
+
+use time;
+
+use {CliReq, CliRes, ClientError, ExportReq, GetReq, GetRes, SnapshotReq};
+use clock::timespec_to_ms;
+use client::{Client, Exporter, refusal};
+
+// A view of everything as of one txid, held open by the server that was
+// leading when Client::snapshot opened it, so that any number of gets and
+// exports through it agree with each other however many writes land in
+// the meantime.  It's released when dropped, or else expires once left
+// unread for the server's snapshot_ttl_ms.  It lasts only as long as that
+// server does: reads fail once it's restarted.
+pub struct Snapshot<'a> {
+    client: &'a mut Client,
+    server: SocketAddr,
+    id: u64,
+    txid: u64,
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn new(client: &'a mut Client,
+               server: SocketAddr,
+               id: u64,
+               txid: u64)
+               -> Snapshot<'a> {
+        Snapshot {
+            client: client,
+            server: server,
+            id: id,
+            txid: txid,
+        }
+    }
+
+    // the txid everything read through it is as of
+    pub fn txid(&self) -> u64 {
+        self.txid
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<GetRes, ClientError> {
+        let mut get = GetReq::new();
+        get.set_key(key.to_vec());
+        get.set_snapshot(self.id);
+        let mut req = CliReq::new();
+        req.set_get(get);
+        self.call(req).map(|cli_res| cli_res.get_get().clone())
+    }
+
+    // Exports the keys from start up to end, or on to the end of the
+    // keyspace, as they were.
+    pub fn export(&mut self,
+                  start: &[u8],
+                  end: Option<&[u8]>)
+                  -> Result<Exporter, ClientError> {
+        let mut export = ExportReq::new();
+        export.set_start(start.to_vec());
+        if let Some(end) = end {
+            export.set_end(end.to_vec());
+        }
+        self.export_with(export)
+    }
+
+    // Like Client::export_with, as things were.
+    pub fn export_with(&mut self,
+                       mut export: ExportReq)
+                       -> Result<Exporter, ClientError> {
+        export.set_snapshot(self.id);
+        let mut req = CliReq::new();
+        req.set_export(export);
+        req.set_req_id(self.client.get_id());
+        let deadline = time::get_time() + self.client.retry_policy.timeout;
+
+        let token = self.client.token.clone();
+        Exporter::open(&[self.server],
+                       token.as_ref().map(|t| &**t),
+                       req,
+                       deadline,
+                       self.client.retry_policy.timeout)
+    }
+
+    // Sends req to the server holding the snapshot, and only it.
+    fn call(&mut self, mut req: CliReq) -> Result<CliRes, ClientError> {
+        req.set_req_id(self.client.get_id());
+        let deadline = time::get_time() + self.client.retry_policy.timeout;
+        req.set_deadline_ms(timespec_to_ms(deadline));
+        req.set_priority(self.client.priority);
+        if let Some(ref trace) = self.client.trace {
+            req.set_trace(trace.clone());
+        }
+        let cli_res = try!(self.client.conns.call(self.server, &req, deadline));
+        if cli_res.has_error() {
+            return Err(refusal(cli_res.get_error()));
+        }
+        Ok(cli_res)
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        let mut release = SnapshotReq::new();
+        release.set_release(self.id);
+        let mut req = CliReq::new();
+        req.set_snapshot(release);
+        // it expires on its own if the server doesn't hear of this
+        if let Err(e) = self.call(req) {
+            debug!("could not release snapshot {}: {}", self.id, e);
+        }
+    }
+}
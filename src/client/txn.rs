@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use protobuf::RepeatedField;
+
+use {ClientError, TxnKey, TxnReq};
+use client::Client;
+
+// What a Client::transact closure reads and writes through.  Reads go to
+// the leader, and each key's first is remembered; writes are only
+// buffered, read back by later gets, until the transaction commits.  It
+// commits only if every key read still holds what it was first read as.
+pub struct Txn<'a> {
+    client: &'a mut Client,
+    // key -> what it held when first read, None if nothing
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // key -> what it's to be set to, None to delete it
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Txn<'a> {
+    pub fn new(client: &'a mut Client) -> Txn<'a> {
+        Txn {
+            client: client,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    // What key holds as of this transaction, None if nothing.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, ClientError> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(key) {
+            return Ok(value.clone());
+        }
+        let res = try!(self.client.get(key));
+        let value = if res.get_success() {
+            Some(res.get_value().to_vec())
+        } else if res.get_err() == "Key not found" {
+            None
+        } else {
+            return Err(ClientError::Rejected(res.get_err().to_string()));
+        };
+        self.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn del(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    // whether it's read or written anything
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty() && self.writes.is_empty()
+    }
+
+    // The request committing everything read and written so far.
+    pub fn request(&self) -> TxnReq {
+        let keys = |keys: &BTreeMap<Vec<u8>, Option<Vec<u8>>>| {
+            keys.iter()
+                .map(|(key, value)| {
+                    let mut txn_key = TxnKey::new();
+                    txn_key.set_key(key.clone());
+                    if let Some(ref value) = *value {
+                        txn_key.set_value(value.clone());
+                    }
+                    txn_key
+                })
+                .collect()
+        };
+        let mut txn_req = TxnReq::new();
+        txn_req.set_reads(RepeatedField::from_vec(keys(&self.reads)));
+        txn_req.set_writes(RepeatedField::from_vec(keys(&self.writes)));
+        txn_req
+    }
+}
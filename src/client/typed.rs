@@ -0,0 +1,428 @@
+use std::str;
+
+use protobuf::{self, MessageStatic};
+use rustc_serialize::{Decodable, Encodable, json};
+
+use {ClientError, DeleteRangeRes, ProtocolError};
+use client::{Client, Exporter};
+
+// Turns values of T into the bytes stored for them, and back.
+pub trait Serializer<T> {
+    fn to_bytes(&self, t: &T) -> Vec<u8>;
+    fn from_bytes(&self, bytes: &[u8]) -> Result<T, ProtocolError>;
+}
+
+// Bytes as they are.
+pub struct Raw;
+
+// Strings as their utf-8, refusing bytes that aren't.
+pub struct Utf8;
+
+// Protobuf messages in their wire format.
+pub struct Proto;
+
+// Anything rustc-serialize can encode, as JSON.
+pub struct JsonEncoded;
+
+// Keys that sort as the values they stand for do, so that ranges of them,
+// as exported or deleted, hold what they should.  Integers are big-endian,
+// with the sign bit of signed ones flipped, and strings and bytes are
+// ended by \x00\x00, with any \x00 in them written \x00\xff, so a part
+// ends before anything longer that starts with it.  Tuples are their
+// parts one after another, and sort by the first, then the second, and so
+// on.
+pub struct Ordered;
+
+impl Serializer<Vec<u8>> for Raw {
+    fn to_bytes(&self, t: &Vec<u8>) -> Vec<u8> {
+        t.clone()
+    }
+
+    fn from_bytes(&self, bytes: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Serializer<String> for Utf8 {
+    fn to_bytes(&self, t: &String) -> Vec<u8> {
+        t.as_bytes().to_vec()
+    }
+
+    fn from_bytes(&self, bytes: &[u8]) -> Result<String, ProtocolError> {
+        str::from_utf8(bytes)
+            .map(String::from)
+            .map_err(|e| ProtocolError::Unexpected(e.to_string()))
+    }
+}
+
+impl<M: MessageStatic> Serializer<M> for Proto {
+    fn to_bytes(&self, t: &M) -> Vec<u8> {
+        t.write_to_bytes().unwrap()
+    }
+
+    fn from_bytes(&self, bytes: &[u8]) -> Result<M, ProtocolError> {
+        protobuf::parse_from_bytes(bytes).map_err(ProtocolError::from)
+    }
+}
+
+impl<T: Encodable + Decodable> Serializer<T> for JsonEncoded {
+    fn to_bytes(&self, t: &T) -> Vec<u8> {
+        json::encode(t).unwrap().into_bytes()
+    }
+
+    fn from_bytes(&self, bytes: &[u8]) -> Result<T, ProtocolError> {
+        let s = try!(str::from_utf8(bytes).map_err(|e| {
+            ProtocolError::Unexpected(e.to_string())
+        }));
+        json::decode(s).map_err(|e| ProtocolError::Unexpected(e.to_string()))
+    }
+}
+
+// What Ordered can encode: a part of a key that knows where it ends.
+pub trait KeyPart: Sized {
+    fn write_part(&self, buf: &mut Vec<u8>);
+    // Reads a part off the front of buf, leaving what follows it.
+    fn read_part(buf: &mut &[u8]) -> Result<Self, ProtocolError>;
+}
+
+impl<T: KeyPart> Serializer<T> for Ordered {
+    fn to_bytes(&self, t: &T) -> Vec<u8> {
+        let mut buf = vec![];
+        t.write_part(&mut buf);
+        buf
+    }
+
+    fn from_bytes(&self, mut bytes: &[u8]) -> Result<T, ProtocolError> {
+        let t = try!(T::read_part(&mut bytes));
+        if !bytes.is_empty() {
+            return Err(bad_key(&format!("{} bytes left over", bytes.len())));
+        }
+        Ok(t)
+    }
+}
+
+fn bad_key(why: &str) -> ProtocolError {
+    ProtocolError::Unexpected(format!("not an ordered key: {}", why))
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], ProtocolError> {
+    if buf.len() < n {
+        return Err(bad_key("it ends too soon"));
+    }
+    let (taken, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(taken)
+}
+
+impl KeyPart for u64 {
+    fn write_part(&self, buf: &mut Vec<u8>) {
+        for i in (0..8).rev() {
+            buf.push((*self >> (8 * i)) as u8);
+        }
+    }
+
+    fn read_part(buf: &mut &[u8]) -> Result<u64, ProtocolError> {
+        let bytes = try!(take(buf, 8));
+        Ok(bytes.iter().fold(0, |n, &byte| n << 8 | byte as u64))
+    }
+}
+
+impl KeyPart for i64 {
+    fn write_part(&self, buf: &mut Vec<u8>) {
+        (*self as u64 ^ 1 << 63).write_part(buf)
+    }
+
+    fn read_part(buf: &mut &[u8]) -> Result<i64, ProtocolError> {
+        u64::read_part(buf).map(|n| (n ^ 1 << 63) as i64)
+    }
+}
+
+impl KeyPart for Vec<u8> {
+    fn write_part(&self, buf: &mut Vec<u8>) {
+        for &byte in self {
+            buf.push(byte);
+            if byte == 0 {
+                buf.push(0xff);
+            }
+        }
+        buf.extend_from_slice(&[0, 0]);
+    }
+
+    fn read_part(buf: &mut &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let mut part = vec![];
+        loop {
+            let byte = try!(take(buf, 1))[0];
+            if byte != 0 {
+                part.push(byte);
+                continue;
+            }
+            match try!(take(buf, 1))[0] {
+                0 => return Ok(part),
+                0xff => part.push(0),
+                _ => return Err(bad_key("a \\x00 in it isn't escaped")),
+            }
+        }
+    }
+}
+
+impl KeyPart for String {
+    fn write_part(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().to_vec().write_part(buf)
+    }
+
+    fn read_part(buf: &mut &[u8]) -> Result<String, ProtocolError> {
+        let bytes = try!(Vec::read_part(buf));
+        String::from_utf8(bytes).map_err(|e| bad_key(&e.to_string()))
+    }
+}
+
+impl<A: KeyPart, B: KeyPart> KeyPart for (A, B) {
+    fn write_part(&self, buf: &mut Vec<u8>) {
+        self.0.write_part(buf);
+        self.1.write_part(buf);
+    }
+
+    fn read_part(buf: &mut &[u8]) -> Result<(A, B), ProtocolError> {
+        let a = try!(A::read_part(buf));
+        let b = try!(B::read_part(buf));
+        Ok((a, b))
+    }
+}
+
+impl<A: KeyPart, B: KeyPart, C: KeyPart> KeyPart for (A, B, C) {
+    fn write_part(&self, buf: &mut Vec<u8>) {
+        self.0.write_part(buf);
+        self.1.write_part(buf);
+        self.2.write_part(buf);
+    }
+
+    fn read_part(buf: &mut &[u8]) -> Result<(A, B, C), ProtocolError> {
+        let a = try!(A::read_part(buf));
+        let b = try!(B::read_part(buf));
+        let c = try!(C::read_part(buf));
+        Ok((a, b, c))
+    }
+}
+
+// Where the composite keys that start with first begin, for exporting or
+// deleting all of them: every such key sorts at or after the prefix, and
+// before key_prefix_end.
+pub fn key_prefix<T: KeyPart>(first: &T) -> Vec<u8> {
+    Ordered.to_bytes(first)
+}
+
+// The first key past every one that starts with prefix.  None if there
+// isn't one, because prefix is all \xff.
+pub fn key_prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// A client whose keys are K and values V, turned into bytes and back by
+// the serializers it's given.  Keys set through it should only be read
+// through it, with the same serializers; Ordered keys keep ranges of them
+// in order.
+pub struct TypedClient<K, V> {
+    client: Client,
+    keys: Box<Serializer<K> + Send>,
+    values: Box<Serializer<V> + Send>,
+}
+
+impl<K, V> TypedClient<K, V> {
+    pub fn new<KS, VS>(client: Client,
+                       keys: KS,
+                       values: VS)
+                       -> TypedClient<K, V>
+        where KS: Serializer<K> + Send + 'static,
+              VS: Serializer<V> + Send + 'static
+    {
+        TypedClient {
+            client: client,
+            keys: Box::new(keys),
+            values: Box::new(values),
+        }
+    }
+
+    // For whatever the typed calls don't cover.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    pub fn into_inner(self) -> Client {
+        self.client
+    }
+
+    pub fn set(&mut self, key: &K, value: &V) -> Result<(), ClientError> {
+        let res = try!(self.client.set(&self.keys.to_bytes(key),
+                                       &self.values.to_bytes(value)));
+        if res.get_success() {
+            Ok(())
+        } else {
+            Err(ClientError::Rejected(res.get_err().to_string()))
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>, ClientError> {
+        let res = try!(self.client.get(&self.keys.to_bytes(key)));
+        if !res.get_success() {
+            return Ok(None);
+        }
+        self.values
+            .from_bytes(res.get_value())
+            .map(Some)
+            .map_err(ClientError::from)
+    }
+
+    // Whether key was old_value, and is now new_value.
+    pub fn cas(&mut self,
+               key: &K,
+               old_value: &V,
+               new_value: &V)
+               -> Result<bool, ClientError> {
+        let res = try!(self.client.cas(&self.keys.to_bytes(key),
+                                       &self.values.to_bytes(old_value),
+                                       &self.values.to_bytes(new_value)));
+        Ok(res.get_success())
+    }
+
+    pub fn del(&mut self, key: &K) -> Result<(), ClientError> {
+        let res = try!(self.client.del(&self.keys.to_bytes(key)));
+        if res.get_success() {
+            Ok(())
+        } else {
+            Err(ClientError::Rejected(res.get_err().to_string()))
+        }
+    }
+
+    // Deletes every key from start up to but not including end.
+    pub fn delete_range(&mut self,
+                        start: &K,
+                        end: &K)
+                        -> Result<DeleteRangeRes, ClientError> {
+        self.client
+            .delete_range(&self.keys.to_bytes(start), &self.keys.to_bytes(end))
+    }
+
+    // Exports the keys from start up to but not including end, or to the
+    // last key if there's no end, decoded.
+    pub fn export<'a>(&'a mut self,
+                      start: &K,
+                      end: Option<&K>)
+                      -> Result<TypedExporter<'a, K, V>, ClientError> {
+        let start = self.keys.to_bytes(start);
+        let end = end.map(|end| self.keys.to_bytes(end));
+        self.export_bytes(&start, end.as_ref().map(|end| &**end))
+    }
+
+    // Exports every composite key whose first part is first.
+    pub fn export_prefix<'a, T: KeyPart>(&'a mut self,
+                                         first: &T)
+                                         -> Result<TypedExporter<'a, K, V>,
+                                                   ClientError> {
+        let start = key_prefix(first);
+        let end = key_prefix_end(&start);
+        self.export_bytes(&start, end.as_ref().map(|end| &**end))
+    }
+
+    fn export_bytes<'a>(&'a mut self,
+                        start: &[u8],
+                        end: Option<&[u8]>)
+                        -> Result<TypedExporter<'a, K, V>, ClientError> {
+        let exporter = try!(self.client.export(start, end));
+        Ok(TypedExporter {
+            exporter: exporter,
+            client: self,
+        })
+    }
+}
+
+// An export's records, decoded a batch at a time.
+pub struct TypedExporter<'a, K: 'a, V: 'a> {
+    exporter: Exporter,
+    client: &'a TypedClient<K, V>,
+}
+
+impl<'a, K, V> TypedExporter<'a, K, V> {
+    // The next batch of records, or none once they've all been sent.
+    pub fn next(&mut self) -> Result<Option<Vec<(K, V)>>, ClientError> {
+        let records = match try!(self.exporter.next()) {
+            Some(records) => records,
+            None => return Ok(None),
+        };
+        let mut batch = Vec::with_capacity(records.len());
+        for record in records {
+            let key = try!(self.client.keys.from_bytes(record.get_key()));
+            let value = try!(self.client.values.from_bytes(record.get_value()));
+            batch.push((key, value));
+        }
+        Ok(Some(batch))
+    }
+
+    // What the export is as of.
+    pub fn txid(&self) -> u64 {
+        self.exporter.txid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {GetReq, KeyValue};
+    use super::{JsonEncoded, Ordered, Proto, Serializer, Utf8, key_prefix,
+                key_prefix_end};
+
+    #[test]
+    fn test_ordered_keys() {
+        let ints: Vec<i64> =
+            vec![i64::min_value(), -300, -1, 0, 1, 255, 256, i64::max_value()];
+        for pair in ints.windows(2) {
+            assert!(Ordered.to_bytes(&pair[0]) < Ordered.to_bytes(&pair[1]));
+        }
+        for &n in &ints {
+            let bytes = Ordered.to_bytes(&n);
+            assert_eq!(Serializer::<i64>::from_bytes(&Ordered, &bytes).unwrap(),
+                       n);
+        }
+        assert!(Ordered.to_bytes(&9u64) < Ordered.to_bytes(&10u64));
+
+        let keys: Vec<(String, u64)> = vec![("a".to_string(), 10),
+                                            ("a".to_string(), 200),
+                                            ("a\x00".to_string(), 0),
+                                            ("ab".to_string(), 0),
+                                            ("b".to_string(), 0)];
+        for pair in keys.windows(2) {
+            assert!(Ordered.to_bytes(&pair[0]) < Ordered.to_bytes(&pair[1]));
+        }
+        for key in &keys {
+            let bytes = Ordered.to_bytes(key);
+            let decoded: (String, u64) = Ordered.from_bytes(&bytes).unwrap();
+            assert_eq!(&decoded, key);
+        }
+        let a = key_prefix(&"a".to_string());
+        let end = key_prefix_end(&a).unwrap();
+        assert!(Ordered.to_bytes(&keys[1]) >= a &&
+                Ordered.to_bytes(&keys[1]) < end);
+        assert!(Ordered.to_bytes(&keys[2]) >= end);
+        assert_eq!(key_prefix_end(b"\xff\xff"), None);
+        assert!(Serializer::<(u64, u64)>::from_bytes(&Ordered, b"short")
+                    .is_err());
+        assert!(Serializer::<u64>::from_bytes(&Ordered, &[0; 9]).is_err());
+
+        assert_eq!(Utf8.from_bytes(b"hi").unwrap(), "hi");
+        assert!(Utf8.from_bytes(b"\xff").is_err());
+        let json: Vec<u32> =
+            JsonEncoded.from_bytes(&JsonEncoded.to_bytes(&vec![1u32, 2]))
+                       .unwrap();
+        assert_eq!(json, vec![1, 2]);
+        let mut get = GetReq::new();
+        get.set_key(b"k".to_vec());
+        let decoded: GetReq = Proto.from_bytes(&Proto.to_bytes(&get)).unwrap();
+        assert_eq!(decoded, get);
+        assert!(Serializer::<KeyValue>::from_bytes(&Proto, b"\xff").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use std::net::SocketAddr;
+
+use time;
+
+use {CliReq, CliRes, ClientError, Mutation, ProtocolError, WatchRes};
+use client::replies::Replies;
+
+// Follows the changes a server applies to a key, or to every key under a
+// prefix, over a connection of its own.  Changes arrive in the order they
+// were applied, each with the txid and hlc it was written at.  Any server
+// that keeps data can be followed; after losing one, Client::watch again
+// from last_txid to pick up where this left off on another.
+pub struct Watcher {
+    replies: Replies,
+    last_txid: u64,
+}
+
+impl Watcher {
+    // Sets the watch up on the first of servers that takes it.
+    pub fn open(servers: &[SocketAddr],
+                token: Option<&str>,
+                req: CliReq,
+                deadline: time::Timespec)
+                -> Result<Watcher, ClientError> {
+        // the first reply says whether we're watching
+        let (replies, first) =
+            try!(Replies::open(servers, token, &req, deadline));
+        let res = try!(watch_res(first));
+        if !res.get_success() {
+            return Err(ClientError::Rejected(res.get_err().to_string()));
+        }
+        Ok(Watcher {
+            replies: replies,
+            last_txid: req.get_watch().get_last_txid(),
+        })
+    }
+
+    // Waits up to timeout for more changes, returning none if there
+    // weren't any by then.
+    pub fn next(&mut self,
+                timeout: time::Duration)
+                -> Result<Vec<Mutation>, ClientError> {
+        match try!(self.replies.recv(timeout)) {
+            Some(cli_res) => {
+                let mut res = try!(watch_res(cli_res));
+                let changes = res.take_history().into_vec();
+                if let Some(last) = changes.last() {
+                    self.last_txid = last.get_version().get_txid();
+                }
+                Ok(changes)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    // The txid of the last change received.
+    pub fn last_txid(&self) -> u64 {
+        self.last_txid
+    }
+}
+
+fn watch_res(mut cli_res: CliRes) -> Result<WatchRes, ClientError> {
+    if !cli_res.has_watch() {
+        return Err(ClientError::from(ProtocolError::Unexpected(
+            "expected changes to what we watch".to_string())));
+    }
+    Ok(cli_res.take_watch())
+}
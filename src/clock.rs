@@ -1,4 +1,5 @@
-use std::sync::RwLock;
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 
 use time;
@@ -8,6 +9,11 @@ pub trait Clock {
     fn sleep_ms(&self, ms: u32);
 }
 
+// Deadlines travel over the wire as milliseconds since the unix epoch.
+pub fn timespec_to_ms(ts: time::Timespec) -> u64 {
+    ts.sec as u64 * 1000 + ts.nsec as u64 / 1_000_000
+}
+
 pub struct RealClock;
 
 unsafe impl Sync for RealClock{}
@@ -22,6 +28,81 @@ impl Clock for RealClock {
     }
 }
 
+// How many of a hybrid timestamp's low bits count events within one
+// millisecond, leaving the rest for milliseconds since the unix epoch.
+const LOGICAL_BITS: u64 = 16;
+
+// A hybrid logical clock orders events across nodes the way wall time
+// would if clocks agreed: a timestamp is never behind the wall clock, is
+// later than every one we've handed out, and later than every one we've
+// been sent, so whatever happened because of a write is stamped after it.
+// Timestamps compare as plain integers.
+pub struct HLC<C: Clock> {
+    clock: Arc<C>,
+    last: Mutex<u64>,
+}
+
+impl<C: Clock> HLC<C> {
+    pub fn new(clock: Arc<C>) -> HLC<C> {
+        HLC {
+            clock: clock,
+            last: Mutex::new(0),
+        }
+    }
+
+    // A timestamp for something happening here now.
+    pub fn timestamp(&self) -> u64 {
+        self.update(0)
+    }
+
+    // Takes in a timestamp from a peer, returning one for having received
+    // it.  A counter that runs out of bits spills into the milliseconds,
+    // which the wall clock soon catches up with.
+    pub fn update(&self, remote: u64) -> u64 {
+        let wall = timespec_to_ms(self.clock.now()) << LOGICAL_BITS;
+        let mut last = self.last.lock().unwrap();
+        let seen = cmp::max(*last, remote);
+        *last = if wall > seen {
+            wall
+        } else {
+            seen + 1
+        };
+        *last
+    }
+
+    // The latest timestamp we've handed out.
+    pub fn last(&self) -> u64 {
+        *self.last.lock().unwrap()
+    }
+}
+
+pub fn hlc_wall_ms(ts: u64) -> u64 {
+    ts >> LOGICAL_BITS
+}
+
+pub fn hlc_logical(ts: u64) -> u64 {
+    ts & ((1 << LOGICAL_BITS) - 1)
+}
+
+// Reads as the wall clock, except when a peer has pushed us ahead of it.
+impl<C: Clock> Clock for HLC<C> {
+    fn now(&self) -> time::Timespec {
+        let wall = self.clock.now();
+        let ms = hlc_wall_ms(self.last());
+        if ms <= timespec_to_ms(wall) {
+            return wall;
+        }
+        time::Timespec {
+            sec: (ms / 1000) as i64,
+            nsec: (ms % 1000) as i32 * 1_000_000,
+        }
+    }
+
+    fn sleep_ms(&self, ms: u32) {
+        self.clock.sleep_ms(ms)
+    }
+}
+
 pub struct TestClock {
     inner: RwLock<time::Timespec>,
 }
@@ -48,3 +129,40 @@ impl Clock for TestClock {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Clock, HLC, TestClock, hlc_logical, hlc_wall_ms};
+
+    #[test]
+    fn test_hlc() {
+        let clock = Arc::new(TestClock::new());
+        clock.sleep_ms(5);
+        let hlc = HLC::new(clock.clone());
+
+        let a = hlc.timestamp();
+        assert_eq!(hlc_wall_ms(a), 5);
+        assert_eq!(hlc_logical(a), 0);
+        // the wall clock hasn't moved, so the counter does
+        let b = hlc.timestamp();
+        assert_eq!(hlc_wall_ms(b), 5);
+        assert_eq!(hlc_logical(b), 1);
+
+        // a peer that's ahead pulls us along
+        let remote = (20 << 16) + 7;
+        let c = hlc.update(remote);
+        assert!(c > remote);
+        assert_eq!(hlc_wall_ms(c), 20);
+        assert_eq!(hlc.now().nsec, 20_000_000);
+        // and one that's behind doesn't take us back
+        assert!(hlc.update(1 << 16) > c);
+
+        clock.sleep_ms(30);
+        let d = hlc.timestamp();
+        assert_eq!(hlc_wall_ms(d), 35);
+        assert_eq!(hlc_logical(d), 0);
+        assert_eq!(hlc.now(), clock.now());
+    }
+}
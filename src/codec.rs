@@ -1,7 +1,47 @@
+use std::cmp;
+use std::io;
+use std::mem;
 use std::ops::Add;
 
 use bytes::{Buf, ByteBuf, MutBuf, MutByteBuf, alloc};
+use bytes::alloc::MemRef;
 use mio::{TryRead, TryWrite};
+use protobuf::RepeatedField;
+
+use Hello;
+use buffers::POOL;
+use lz4;
+
+// Every frame starts with a header of two bytes of magic, the protocol
+// version it was written in, a byte of flags, and the length of the body
+// as four big-endian bytes.
+pub const MAGIC: [u8; 2] = [0x52, 0x50];
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+
+// the body is a Hello for the connection itself, not a message
+pub const FLAG_HELLO: u8 = 1;
+// the body is the message's length as four big-endian bytes, followed by
+// the message as an LZ4 block
+pub const FLAG_LZ4: u8 = 2;
+// the body is a chunk of a message that continues in the next frame, for
+// messages too big to send in one
+pub const FLAG_MORE: u8 = 4;
+
+// the optional features this build can use on a connection
+pub const LZ4: &'static str = "lz4";
+pub const FEATURES: &'static [&'static str] = &[LZ4];
+
+// Messages smaller than this aren't worth the time to compress.
+pub const COMPRESS_MIN: usize = 4096;
+
+pub const DEFAULT_MAX_FRAME: usize = 16 << 20;
+pub const DEFAULT_MAX_STREAM: usize = 1 << 30;
+
+// How much is read off a connection at a time.  Its frames are handed out
+// as slices of the block they were read into, so a block is only copied
+// from to carry a frame that didn't fit over to the next one.
+pub const RECV_BLOCK: usize = 16 << 10;
 
 pub trait Codec<In: ?Sized, Out: ?Sized>
 {
@@ -29,80 +69,366 @@ impl <In, Mid, Out> Codec<In, Out> for CodecStack<In, Mid, Out> {
 }
 
 pub struct Framed {
-    sz_buf: MutByteBuf,
-    msg: Option<MutByteBuf>,
+    // the block we read into, of which what's from start up to end hasn't
+    // been decoded yet
+    recv: MemRef,
+    start: usize,
+    end: usize,
+    max_frame: usize,
+    max_stream: usize,
+    // how much of a rejected frame is still to be thrown away
+    skip: usize,
+    // the chunks of a streamed message so far, and whether we've given up
+    // on the rest of it
+    stream: Option<Vec<u8>>,
+    dropping_stream: bool,
+    // frames we refused, and why, until someone takes them
+    rejected: Vec<String>,
+    // the last hello the other side sent, until someone takes it
+    hello: Option<ByteBuf>,
+    // why we stopped decoding, if we have
+    broken: Option<String>,
 }
 
 impl Framed {
     pub fn new() -> Framed {
+        Framed::with_limits(DEFAULT_MAX_FRAME, DEFAULT_MAX_STREAM)
+    }
+
+    // Frames with bodies over max_frame are skipped, as are streamed
+    // messages that add up to more than max_stream.
+    pub fn with_limits(max_frame: usize, max_stream: usize) -> Framed {
         Framed {
-            sz_buf: ByteBuf::mut_with_capacity(4),
-            msg: None,
+            recv: MemRef::none(),
+            start: 0,
+            end: 0,
+            max_frame: max_frame,
+            max_stream: max_stream,
+            skip: 0,
+            stream: None,
+            dropping_stream: false,
+            rejected: vec![],
+            hello: None,
+            broken: None,
         }
     }
-}
 
-impl Codec<ByteBuf, ByteBuf> for Framed {
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
 
-    fn decode(&mut self, buf: &mut ByteBuf) -> Vec<ByteBuf> {
+    pub fn take_hello(&mut self) -> Option<ByteBuf> {
+        self.hello.take()
+    }
+
+    pub fn take_rejected(&mut self) -> Vec<String> {
+        mem::replace(&mut self.rejected, vec![])
+    }
+
+    // Once the stream stops making sense there's no finding the next
+    // frame, so the connection should be dropped.
+    pub fn broken(&self) -> Option<&str> {
+        self.broken.as_ref().map(|reason| &**reason)
+    }
+
+    pub fn encode_hello(&self, hello: ByteBuf) -> ByteBuf {
+        frame(FLAG_HELLO, hello.bytes())
+    }
+
+    // What we tell the other side when a connection opens.
+    pub fn hello(&self) -> Hello {
+        let mut hello = Hello::new();
+        hello.set_version(PROTOCOL_VERSION as u32);
+        let features = FEATURES.iter().map(|f| f.to_string()).collect();
+        hello.set_features(RepeatedField::from_vec(features));
+        hello.set_max_frame(self.max_frame as u64);
+        hello
+    }
+
+    // Given the other side's hello, what we'll both use: the highest
+    // version both speak, the features both support, and the smaller of
+    // our frame limits.
+    pub fn negotiate(&self, theirs: &Hello) -> Hello {
+        let mut agreed = Hello::new();
+        agreed.set_version(cmp::min(theirs.get_version(),
+                                    PROTOCOL_VERSION as u32));
+        agreed.set_features(theirs.get_features()
+                                  .iter()
+                                  .filter(|f| FEATURES.contains(&&***f))
+                                  .cloned()
+                                  .collect());
+        let max_frame = if theirs.has_max_frame() {
+            cmp::min(theirs.get_max_frame(), self.max_frame as u64)
+        } else {
+            self.max_frame as u64
+        };
+        agreed.set_max_frame(max_frame);
+        agreed
+    }
+
+    fn reject(&mut self, reason: String) {
+        if self.stream.take().is_some() {
+            self.dropping_stream = true;
+        }
+        self.rejected.push(reason);
+    }
+
+    fn recv_cap(&self) -> usize {
+        if self.recv.is_none() {
+            0
+        } else {
+            self.recv.bytes().len()
+        }
+    }
+
+    // Makes room to read more into once the block is full, in a new one
+    // big enough for the frame that's partly in this one, which is the
+    // only thing copied over.
+    fn make_room(&mut self) {
+        if self.end < self.recv_cap() {
+            return;
+        }
+        let pending = self.end - self.start;
+        let needed = if pending >= HEADER_LEN {
+            let start = self.start;
+            let header = &self.recv.bytes()[start..start + HEADER_LEN];
+            let size = array_to_usize([header[4],
+                                       header[5],
+                                       header[6],
+                                       header[7]]);
+            HEADER_LEN + cmp::min(size, self.max_frame)
+        } else {
+            HEADER_LEN
+        };
+        let mut recv = POOL.alloc(cmp::max(needed, RECV_BLOCK));
+        if pending > 0 {
+            let kept = &self.recv.bytes()[self.start..self.end];
+            recv.bytes_mut()[..pending].copy_from_slice(kept);
+        }
+        self.recv = recv;
+        self.start = 0;
+        self.end = pending;
+    }
+
+    // Reads as much as there's room for from conn, without blocking;
+    // take_frames then has whatever it completed.
+    pub fn read_from<R: TryRead>(&mut self,
+                                 conn: &mut R)
+                                 -> io::Result<Option<usize>> {
+        self.make_room();
+        let (end, cap) = (self.end, self.recv_cap());
+        let read = try!(conn.try_read(&mut self.recv.bytes_mut()[end..cap]));
+        if let Some(read) = read {
+            self.end += read;
+        }
+        Ok(read)
+    }
+
+    // Every message whose frames have all been read.  Each shares the
+    // block it was read into rather than being copied out of it, so none
+    // should be written to.
+    pub fn take_frames(&mut self) -> Vec<ByteBuf> {
         let mut res = vec![];
-        loop {
-            // read size if we don't have a message yet
-            if self.msg.is_none() {
-                let sz_read = buf.try_read_buf(&mut self.sz_buf);
-                // if we've read 4 bytes for the size, create a msg
-                if self.sz_buf.remaining() != 0 {
+        while self.broken.is_none() {
+            if self.skip > 0 {
+                let skipped = cmp::min(self.skip, self.end - self.start);
+                self.start += skipped;
+                self.skip -= skipped;
+                if self.skip > 0 {
                     break;
                 }
-
-                let sz_buf = self.sz_buf.bytes();
-                let size = array_to_usize([sz_buf[0], sz_buf[1], sz_buf[2],
-                                           sz_buf[3]]);
-                self.msg = unsafe {
-                    // manually create bytebuf so we can have exact cap and lim
-                    Some(ByteBuf::from_mem_ref(alloc::heap(size.next_power_of_two()),
-                                               size as u32, // cap
-                                               0, // pos
-                                               size as u32 /* lim */)
-                             .flip())
-                };
             }
-
-            if self.msg.is_none() {
+            if self.end - self.start < HEADER_LEN {
                 break;
             }
+            let (flags, size) = {
+                let start = self.start;
+                let header = &self.recv.bytes()[start..start + HEADER_LEN];
+                if header[..2] != MAGIC {
+                    self.broken = Some(format!("bad magic {:?}", &header[..2]));
+                    break;
+                }
+                if header[2] != PROTOCOL_VERSION {
+                    self.broken = Some(format!("unsupported protocol version \
+                                                {}",
+                                               header[2]));
+                    break;
+                }
+                let size = array_to_usize([header[4],
+                                           header[5],
+                                           header[6],
+                                           header[7]]);
+                (header[3], size)
+            };
+            if size > self.max_frame {
+                // the header says where the next frame starts, so we can
+                // carry on after this one
+                let max_frame = self.max_frame;
+                self.reject(format!("frame of {} bytes is over the {} byte \
+                                     limit",
+                                    size,
+                                    max_frame));
+                self.dropping_stream = flags & FLAG_MORE != 0;
+                self.start += HEADER_LEN;
+                self.skip = size;
+                continue;
+            }
+            if self.end - self.start < HEADER_LEN + size {
+                break;
+            }
+            let pos = self.start + HEADER_LEN;
+            let lim = pos + size;
+            self.start = lim;
+            let body = unsafe {
+                ByteBuf::from_mem_ref(self.recv.clone(),
+                                      lim as u32,
+                                      pos as u32,
+                                      lim as u32)
+            };
+            self.received(flags, body, &mut res);
+        }
+        res
+    }
+
+    // Handles a whole frame's body.
+    fn received(&mut self, flags: u8, body: ByteBuf, res: &mut Vec<ByteBuf>) {
+        if flags & FLAG_HELLO != 0 {
+            self.hello = Some(body);
+            return;
+        }
+        let body = if flags & FLAG_LZ4 != 0 {
+            match decompress(body.bytes(), self.max_frame) {
+                Ok(body) => body,
+                Err(e) => {
+                    self.broken = Some(e);
+                    return;
+                }
+            }
+        } else {
+            body
+        };
+        if flags & FLAG_MORE == 0 &&
+           self.stream.is_none() &&
+           !self.dropping_stream {
+            res.push(body);
+            return;
+        }
 
-            let mut msg = self.msg.take().unwrap();
-
-            // read actual message
-            match buf.try_read_buf(&mut msg) {
-                Ok(Some(read)) => {
-                    // if we're done, return our Item
-                    if msg.remaining() == 0 {
-                        // get ready to read a new size
-                        self.sz_buf.clear();
-                        // return the message
-                        res.push(msg.flip())
-                    } else {
-                        self.msg = Some(msg);
-                        break
-                    }
+        // part of a streamed message
+        if !self.dropping_stream {
+            let streamed = self.stream.get_or_insert(vec![]).len() +
+                           body.bytes().len();
+            if streamed > self.max_stream {
+                let max_stream = self.max_stream;
+                self.reject(format!("streamed message of over {} bytes is over \
+                                     the {} byte limit",
+                                    streamed,
+                                    max_stream));
+            } else {
+                self.stream.as_mut().unwrap().extend(body.bytes());
+            }
+        }
+        if flags & FLAG_MORE == 0 {
+            self.dropping_stream = false;
+            match self.stream.take() {
+                Some(ref msg) if !msg.is_empty() => {
+                    res.push(ByteBuf::from_slice(msg))
                 }
-                _ => break,
+                _ => (),
             }
         }
+    }
+}
+
+impl Codec<ByteBuf, ByteBuf> for Framed {
+
+    fn decode(&mut self, buf: &mut ByteBuf) -> Vec<ByteBuf> {
+        let mut res = vec![];
+        while buf.remaining() > 0 && self.broken.is_none() {
+            self.make_room();
+            let (end, cap) = (self.end, self.recv_cap());
+            self.end += buf.read_slice(&mut self.recv.bytes_mut()[end..cap]);
+            res.extend(self.take_frames());
+        }
         res
     }
 
     fn encode(&self, item: ByteBuf) -> ByteBuf {
-        let b = item.bytes();
-        let mut res = ByteBuf::mut_with_capacity(4 + b.len());
-        assert!(res.write_slice(&usize_to_array(b.len())) == 4);
-        assert!(res.write_slice(b) == b.len());
-        res.flip()
+        frame(0, item.bytes())
     }
 }
 
+pub fn header(flags: u8, len: usize) -> [u8; HEADER_LEN] {
+    let sz = usize_to_array(len);
+    [MAGIC[0], MAGIC[1], PROTOCOL_VERSION, flags, sz[0], sz[1], sz[2], sz[3]]
+}
+
+// Frames a message for a connection on which we agreed the given hello,
+// compressing it if that was agreed and the message is large enough to be
+// worth it.  A message over max_frame, or the agreed limit if that's
+// lower, is streamed as several frames.
+pub fn encode_for(agreed: Option<&Hello>,
+                  max_frame: usize,
+                  b: &[u8])
+                  -> Vec<ByteBuf> {
+    let lz4_agreed = agreed.map(|hello| {
+                               hello.get_features().iter().any(|f| f == LZ4)
+                           })
+                           .unwrap_or(false);
+    let max_frame = match agreed {
+        Some(hello) if hello.has_max_frame() => {
+            cmp::min(hello.get_max_frame() as usize, max_frame)
+        }
+        _ => max_frame,
+    };
+    if b.len() <= max_frame || max_frame == 0 {
+        return vec![frame_chunk(lz4_agreed, 0, b)];
+    }
+    let chunks = (b.len() + max_frame - 1) / max_frame;
+    b.chunks(max_frame)
+     .enumerate()
+     .map(|(i, chunk)| {
+         let flags = if i + 1 < chunks {
+             FLAG_MORE
+         } else {
+             0
+         };
+         frame_chunk(lz4_agreed, flags, chunk)
+     })
+     .collect()
+}
+
+fn frame_chunk(lz4_agreed: bool, flags: u8, b: &[u8]) -> ByteBuf {
+    if lz4_agreed && b.len() >= COMPRESS_MIN {
+        let mut compressed = usize_to_array(b.len()).to_vec();
+        compressed.extend(lz4::compress(b));
+        if compressed.len() < b.len() {
+            return frame(flags | FLAG_LZ4, &compressed);
+        }
+    }
+    frame(flags, b)
+}
+
+fn decompress(body: &[u8], max_frame: usize) -> Result<ByteBuf, String> {
+    if body.len() < 4 {
+        return Err("compressed frame is missing its length".to_string());
+    }
+    let size = array_to_usize([body[0], body[1], body[2], body[3]]);
+    // nothing small enough to be worth compressing is ever sent compressed,
+    // and nothing is compressed that wouldn't fit in a frame as it was
+    if size < COMPRESS_MIN || size > max_frame {
+        return Err(format!("compressed frame would expand to {} bytes", size));
+    }
+    lz4::decompress(&body[4..], size).map(|msg| ByteBuf::from_slice(&msg))
+}
+
+fn frame(flags: u8, b: &[u8]) -> ByteBuf {
+    let mut res = POOL.mut_buf(HEADER_LEN + b.len());
+    assert!(res.write_slice(&header(flags, b.len())) == HEADER_LEN);
+    assert!(res.write_slice(b) == b.len());
+    res.flip()
+}
+
 pub fn usize_to_array(u: usize) -> [u8; 4] {
     [(u >> 24) as u8, (u >> 16) as u8, (u >> 8) as u8, u as u8]
 }
@@ -115,6 +441,8 @@ pub fn array_to_usize(ip: [u8; 4]) -> usize {
 #[cfg(test)]
 mod tests {
     extern crate quickcheck;
+    use std::io;
+
     use rand::{Rng, thread_rng};
 
     use codec;
@@ -150,4 +478,99 @@ mod tests {
     fn test_framed_codec() {
         quickcheck::quickcheck(framed_prop as fn(usize) -> bool);
     }
+
+    #[test]
+    fn test_framed_rejects_foreign_streams() {
+        let mut c = codec::Framed::new();
+        let mut http = ByteBuf::from_slice(b"GET / HTTP/1.0\r\n\r\n");
+        assert!(c.decode(&mut http).is_empty());
+        assert!(c.broken().is_some());
+
+        let mut c = codec::Framed::new();
+        let mut future = c.encode(ByteBuf::from_slice(b"hi")).bytes().to_vec();
+        future[2] = codec::PROTOCOL_VERSION + 1;
+        assert!(c.decode(&mut ByteBuf::from_slice(&future)).is_empty());
+        assert!(c.broken().unwrap().contains("version"));
+    }
+
+    #[test]
+    fn test_framed_compression() {
+        let c = codec::Framed::new();
+        let mut agreed = c.hello();
+        let max = codec::DEFAULT_MAX_FRAME;
+        let msg: Vec<u8> = (0..20000).map(|i| (i / 7) as u8).collect();
+        let compressed = codec::encode_for(Some(&agreed), max, &msg);
+        assert!(compressed.len() == 1);
+        assert!(compressed[0].bytes()[3] == codec::FLAG_LZ4);
+        assert!(compressed[0].bytes().len() < msg.len() / 2);
+        let mut c = codec::Framed::new();
+        let frames = c.decode(&mut ByteBuf::from_slice(compressed[0].bytes()));
+        assert!(frames.len() == 1 && frames[0].bytes() == &*msg);
+
+        // nothing is compressed unless both sides said they could take it
+        agreed.clear_features();
+        assert!(codec::encode_for(Some(&agreed), max, &msg)[0].bytes()[3] == 0);
+        assert!(codec::encode_for(None, max, &msg)[0].bytes()[3] == 0);
+        let small = codec::encode_for(Some(&c.hello()), max, b"small");
+        assert!(small[0].bytes()[3] == 0);
+    }
+
+    #[test]
+    fn test_framed_limits() {
+        let msg: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        let mut c = codec::Framed::with_limits(1000, 20000);
+
+        // too big to take in one frame, so refused and skipped
+        let mut wire = c.encode(ByteBuf::from_slice(&msg)).bytes().to_vec();
+        wire.extend(c.encode(ByteBuf::from_slice(b"next")).bytes());
+        let frames = c.decode(&mut ByteBuf::from_slice(&wire));
+        assert!(frames.len() == 1 && frames[0].bytes() == b"next");
+        assert!(c.take_rejected().len() == 1);
+
+        // but fine streamed at the limit the other side asked for
+        let theirs = c.hello();
+        let chunks = codec::encode_for(Some(&c.negotiate(&theirs)),
+                                       codec::DEFAULT_MAX_FRAME,
+                                       &msg);
+        assert!(chunks.len() == 10);
+        let mut wire = vec![];
+        for chunk in chunks.iter() {
+            wire.extend(chunk.bytes());
+        }
+        let frames = c.decode(&mut ByteBuf::from_slice(&wire));
+        assert!(frames.len() == 1 && frames[0].bytes() == &*msg);
+        assert!(c.take_rejected().is_empty());
+
+        // unless the stream adds up to too much
+        let mut c = codec::Framed::with_limits(1000, 5000);
+        wire.extend(c.encode(ByteBuf::from_slice(b"next")).bytes());
+        let frames = c.decode(&mut ByteBuf::from_slice(&wire));
+        assert!(frames.len() == 1 && frames[0].bytes() == b"next");
+        assert!(c.take_rejected().len() == 1);
+    }
+
+    #[test]
+    fn test_framed_reads_into_blocks() {
+        // frames straddling the end of a block, and one bigger than a block
+        let c = codec::Framed::new();
+        let mut wire = vec![];
+        let mut sent = vec![];
+        let lens = [100, codec::RECV_BLOCK - 50, 3 * codec::RECV_BLOCK, 7];
+        for len in lens.iter() {
+            let msg: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            wire.extend(c.encode(ByteBuf::from_slice(&msg)).bytes());
+            sent.push(msg);
+        }
+
+        let mut c = codec::Framed::new();
+        let mut conn = io::Cursor::new(&wire[..]);
+        let mut frames = vec![];
+        while c.read_from(&mut conn).unwrap() != Some(0) {
+            frames.extend(c.take_frames());
+        }
+        assert_eq!(frames.len(), sent.len());
+        for (frame, msg) in frames.iter().zip(sent.iter()) {
+            assert!(frame.bytes() == &**msg);
+        }
+    }
 }
@@ -0,0 +1,253 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use protobuf::ProtobufError;
+use time;
+
+// Crate-wide error types.  Each subsystem has its own error enum, and
+// Error wraps all of them so that embedders can either handle failures
+// from one layer specifically or just propagate everything with try!.
+
+#[derive(Debug)]
+pub enum StorageError {
+    // the storage directory could not be opened or initialized
+    Open { path: String, reason: String },
+    // a read or write against the underlying store failed
+    Operation(String),
+    // what was read back isn't what was written
+    Corrupt(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsensusError {
+    // no leader has been elected yet, so nobody can serve the request
+    NoLeader,
+    // this node is following someone else
+    NotLeader { leader: Option<String> },
+    // the leader is handing off to another node, try again shortly
+    Transferring,
+    // a requested leadership transfer did not happen
+    TransferFailed { reason: String },
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    // a frame could not be decoded into the expected message
+    Decode(ProtobufError),
+    // a message was well formed, but not what we were expecting
+    Unexpected(String),
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    // none of the known servers could be reached
+    Unreachable,
+    // the request did not complete before its deadline
+    DeadlineExceeded,
+    // the cluster could not serve the request
+    Consensus(ConsensusError),
+    // the server would not serve us, e.g. for a bad token
+    Rejected(String),
+    // we were sending more than the server allows us; worth retrying
+    // after backing off
+    Throttled(String),
+    // the server is too far behind to take this request on now; worth
+    // retrying after backing off
+    Overloaded(String),
+    // the range is too far behind on the writes it's taken to take more;
+    // worth retrying after waiting as long as it suggested
+    Busy(String, time::Duration),
+    // the leader's disk is too full to take writes; deletes and reads
+    // still work
+    DiskFull(String),
+    // the node or the write's namespace holds as much as its quota
+    // lets it; deletes and reads still work
+    QuotaExceeded(String),
+    Protocol(ProtocolError),
+    Io(io::Error),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Storage(StorageError),
+    Consensus(ConsensusError),
+    Protocol(ProtocolError),
+    Client(ClientError),
+    Io(io::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageError::Open{ref path, ref reason} =>
+                write!(f, "failed to open storage at {}: {}", path, reason),
+            StorageError::Operation(ref e) =>
+                write!(f, "Operational problem encountered: {}", e),
+            StorageError::Corrupt(ref e) =>
+                write!(f, "corrupt data: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ConsensusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConsensusError::NoLeader =>
+                write!(f, "No leader has been elected yet"),
+            ConsensusError::NotLeader{leader: Some(ref leader)} =>
+                write!(f, "not the leader, try {}", leader),
+            ConsensusError::NotLeader{leader: None} =>
+                write!(f, "not the leader"),
+            ConsensusError::Transferring =>
+                write!(f, "leadership is being transferred"),
+            ConsensusError::TransferFailed{ref reason} =>
+                write!(f, "leadership transfer failed: {}", reason),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::Decode(ref e) =>
+                write!(f, "failed to decode message: {:?}", e),
+            ProtocolError::Unexpected(ref e) =>
+                write!(f, "unexpected message: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Unreachable =>
+                write!(f, "unable to reach any servers!"),
+            ClientError::DeadlineExceeded =>
+                write!(f, "request deadline exceeded"),
+            ClientError::Consensus(ref e) => write!(f, "{}", e),
+            ClientError::Rejected(ref e) =>
+                write!(f, "server refused the request: {}", e),
+            ClientError::Throttled(ref e) =>
+                write!(f, "server throttled the request: {}", e),
+            ClientError::Overloaded(ref e) =>
+                write!(f, "server is overloaded: {}", e),
+            ClientError::Busy(ref e, retry_after) =>
+                write!(f,
+                       "server is busy, retry in {}ms: {}",
+                       retry_after.num_milliseconds(),
+                       e),
+            ClientError::DiskFull(ref e) =>
+                write!(f, "server is out of disk: {}", e),
+            ClientError::QuotaExceeded(ref e) =>
+                write!(f, "quota exceeded: {}", e),
+            ClientError::Protocol(ref e) => write!(f, "{}", e),
+            ClientError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Storage(ref e) => write!(f, "{}", e),
+            Error::Consensus(ref e) => write!(f, "{}", e),
+            Error::Protocol(ref e) => write!(f, "{}", e),
+            Error::Client(ref e) => write!(f, "{}", e),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for StorageError {
+    fn description(&self) -> &str {
+        "storage error"
+    }
+}
+
+impl error::Error for ConsensusError {
+    fn description(&self) -> &str {
+        "consensus error"
+    }
+}
+
+impl error::Error for ProtocolError {
+    fn description(&self) -> &str {
+        "protocol error"
+    }
+}
+
+impl error::Error for ClientError {
+    fn description(&self) -> &str {
+        "client error"
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Storage(ref e) => e.description(),
+            Error::Consensus(ref e) => e.description(),
+            Error::Protocol(ref e) => e.description(),
+            Error::Client(ref e) => e.description(),
+            Error::Io(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<ProtobufError> for ProtocolError {
+    fn from(e: ProtobufError) -> ProtocolError {
+        ProtocolError::Decode(e)
+    }
+}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> ClientError {
+        ClientError::Protocol(e)
+    }
+}
+
+impl From<ConsensusError> for ClientError {
+    fn from(e: ConsensusError) -> ClientError {
+        ClientError::Consensus(e)
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        match e.kind() {
+            io::ErrorKind::TimedOut => ClientError::DeadlineExceeded,
+            _ => ClientError::Io(e),
+        }
+    }
+}
+
+impl From<StorageError> for Error {
+    fn from(e: StorageError) -> Error {
+        Error::Storage(e)
+    }
+}
+
+impl From<ConsensusError> for Error {
+    fn from(e: ConsensusError) -> Error {
+        Error::Consensus(e)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Error {
+        Error::Protocol(e)
+    }
+}
+
+impl From<ClientError> for Error {
+    fn from(e: ClientError) -> Error {
+        Error::Client(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
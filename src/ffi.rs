@@ -0,0 +1,304 @@
+// A C interface to Client, declared in include/rasputin.h, for
+// applications that aren't written in Rust.  Calls return RASPUTIN_OK,
+// RASPUTIN_NOT_FOUND, or RASPUTIN_ERROR, in which case
+// rasputin_last_error says what went wrong on the calling thread.  Values
+// handed back are owned by the caller, who frees them with rasputin_free.
+// Panics are caught at the boundary and reported as errors, since they
+// mustn't unwind into C.
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use libc::{c_char, c_int, c_void, size_t};
+
+use Client;
+
+pub const RASPUTIN_OK: c_int = 0;
+pub const RASPUTIN_NOT_FOUND: c_int = 1;
+pub const RASPUTIN_ERROR: c_int = -1;
+
+// Called with each record of a scan; anything but zero stops the scan.
+pub type ScanFn = extern "C" fn(ctx: *mut c_void,
+                                key: *const u8,
+                                key_len: size_t,
+                                value: *const u8,
+                                value_len: size_t)
+                                -> c_int;
+
+pub struct RasputinClient {
+    client: Client,
+}
+
+thread_local!(static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None));
+
+fn set_last_error(err: String) {
+    // an error with a nul in it is cut short there
+    let err = err.split('\0').next().unwrap().to_string();
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(err).ok());
+}
+
+// Runs f, turning a panic or an error into RASPUTIN_ERROR.
+fn guard<F>(f: F) -> c_int
+    where F: FnOnce() -> Result<c_int, String>
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => code,
+        Ok(Err(e)) => {
+            set_last_error(e);
+            RASPUTIN_ERROR
+        }
+        Err(_) => {
+            set_last_error("rasputin panicked".to_string());
+            RASPUTIN_ERROR
+        }
+    }
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: size_t) -> Result<&'a [u8], String> {
+    if data.is_null() {
+        if len == 0 {
+            return Ok(&[]);
+        }
+        return Err("a null pointer was passed for bytes".to_string());
+    }
+    Ok(slice::from_raw_parts(data, len as usize))
+}
+
+unsafe fn client<'a>(client: *mut RasputinClient)
+                     -> Result<&'a mut Client, String> {
+    if client.is_null() {
+        return Err("a null client was passed".to_string());
+    }
+    Ok(&mut (*client).client)
+}
+
+// Hands v to the caller, to be freed with rasputin_free.
+unsafe fn give(v: Vec<u8>, data: *mut *mut u8, len: *mut size_t) {
+    let v = v.into_boxed_slice();
+    *len = v.len() as size_t;
+    *data = Box::into_raw(v) as *mut u8;
+}
+
+// Connects to the servers in a comma-separated list of host:port client
+// addresses, returning null on failure.
+#[no_mangle]
+pub unsafe extern "C" fn rasputin_open(servers: *const c_char)
+                                       -> *mut RasputinClient {
+    let mut opened = ptr::null_mut();
+    guard(|| {
+        if servers.is_null() {
+            return Err("no servers were given".to_string());
+        }
+        let servers = try!(CStr::from_ptr(servers)
+                               .to_str()
+                               .map_err(|e| e.to_string()));
+        let mut addrs = vec![];
+        let named = servers.split(',')
+                           .map(|s| s.trim())
+                           .filter(|s| !s.is_empty());
+        for server in named {
+            let addr: SocketAddr = match server.parse() {
+                Ok(addr) => addr,
+                Err(e) => return Err(format!("bad server {}: {}", server, e)),
+            };
+            addrs.push(addr);
+        }
+        if addrs.is_empty() {
+            return Err("no servers were given".to_string());
+        }
+        let client = RasputinClient { client: Client::new(addrs, 1) };
+        opened = Box::into_raw(Box::new(client));
+        Ok(RASPUTIN_OK)
+    });
+    opened
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rasputin_close(client: *mut RasputinClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+// For servers started with a root token.
+#[no_mangle]
+pub unsafe extern "C" fn rasputin_set_token(client: *mut RasputinClient,
+                                            token: *const c_char)
+                                            -> c_int {
+    guard(|| {
+        let client = try!(self::client(client));
+        if token.is_null() {
+            return Err("a null token was passed".to_string());
+        }
+        let token = try!(CStr::from_ptr(token)
+                             .to_str()
+                             .map_err(|e| e.to_string()));
+        client.set_token(token);
+        Ok(RASPUTIN_OK)
+    })
+}
+
+// Sets *value to a copy of key's value, or returns RASPUTIN_NOT_FOUND.
+#[no_mangle]
+pub unsafe extern "C" fn rasputin_get(client: *mut RasputinClient,
+                                      key: *const u8,
+                                      key_len: size_t,
+                                      value: *mut *mut u8,
+                                      value_len: *mut size_t)
+                                      -> c_int {
+    guard(|| {
+        let client = try!(self::client(client));
+        let key = try!(bytes(key, key_len));
+        if value.is_null() || value_len.is_null() {
+            return Err("nowhere was given to put the value".to_string());
+        }
+        let res = try!(client.get(key).map_err(|e| e.to_string()));
+        if !res.get_success() {
+            return Ok(RASPUTIN_NOT_FOUND);
+        }
+        give(res.get_value().to_vec(), value, value_len);
+        Ok(RASPUTIN_OK)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rasputin_put(client: *mut RasputinClient,
+                                      key: *const u8,
+                                      key_len: size_t,
+                                      value: *const u8,
+                                      value_len: size_t)
+                                      -> c_int {
+    guard(|| {
+        let client = try!(self::client(client));
+        let key = try!(bytes(key, key_len));
+        let value = try!(bytes(value, value_len));
+        let res = try!(client.set(key, value).map_err(|e| e.to_string()));
+        if !res.get_success() {
+            return Err(res.get_err().to_string());
+        }
+        Ok(RASPUTIN_OK)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rasputin_delete(client: *mut RasputinClient,
+                                         key: *const u8,
+                                         key_len: size_t)
+                                         -> c_int {
+    guard(|| {
+        let client = try!(self::client(client));
+        let key = try!(bytes(key, key_len));
+        let res = try!(client.del(key).map_err(|e| e.to_string()));
+        if !res.get_success() {
+            return Err(res.get_err().to_string());
+        }
+        Ok(RASPUTIN_OK)
+    })
+}
+
+// Calls f with every record from start up to but not including end, or
+// to the last key if end is null, in order and all as of one txid, until
+// f returns nonzero.  The pointers f is given are only good until it
+// returns.
+#[no_mangle]
+pub unsafe extern "C" fn rasputin_scan(client: *mut RasputinClient,
+                                       start: *const u8,
+                                       start_len: size_t,
+                                       end: *const u8,
+                                       end_len: size_t,
+                                       f: Option<ScanFn>,
+                                       ctx: *mut c_void)
+                                       -> c_int {
+    guard(|| {
+        let client = try!(self::client(client));
+        let start = try!(bytes(start, start_len));
+        let end = if end.is_null() {
+            None
+        } else {
+            Some(try!(bytes(end, end_len)))
+        };
+        let f = try!(f.ok_or("no function was given to call".to_string()));
+        let mut exporter = try!(client.export(start, end)
+                                      .map_err(|e| e.to_string()));
+        while let Some(records) = try!(exporter.next()
+                                               .map_err(|e| e.to_string())) {
+            for record in records {
+                let (key, value) = (record.get_key(), record.get_value());
+                if f(ctx,
+                     key.as_ptr(),
+                     key.len() as size_t,
+                     value.as_ptr(),
+                     value.len() as size_t) != 0 {
+                    return Ok(RASPUTIN_OK);
+                }
+            }
+        }
+        Ok(RASPUTIN_OK)
+    })
+}
+
+// Frees a value handed back by rasputin_get.
+#[no_mangle]
+pub unsafe extern "C" fn rasputin_free(data: *mut u8, len: size_t) {
+    if !data.is_null() {
+        let data = slice::from_raw_parts_mut(data, len as usize);
+        drop(Box::from_raw(data as *mut [u8]));
+    }
+}
+
+// What went wrong with the last call on this thread to fail, good until
+// the next one does.  Null if none has.
+#[no_mangle]
+pub extern "C" fn rasputin_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow().as_ref().map_or(ptr::null(), |err| err.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    use super::{RASPUTIN_ERROR, rasputin_close, rasputin_free, rasputin_get,
+                rasputin_last_error, rasputin_open, rasputin_put, give};
+
+    #[test]
+    fn test_ffi_errors() {
+        unsafe {
+            assert!(rasputin_open(ptr::null()).is_null());
+            let bad = CString::new("127.0.0.1:1,nope").unwrap();
+            assert!(rasputin_open(bad.as_ptr()).is_null());
+            let err = CStr::from_ptr(rasputin_last_error()).to_str().unwrap();
+            assert!(err.contains("bad server nope"));
+
+            let (mut value, mut value_len) = (ptr::null_mut(), 0);
+            assert_eq!(rasputin_get(ptr::null_mut(),
+                                    ptr::null(),
+                                    0,
+                                    &mut value,
+                                    &mut value_len),
+                       RASPUTIN_ERROR);
+            assert_eq!(rasputin_put(ptr::null_mut(),
+                                    ptr::null(),
+                                    0,
+                                    ptr::null(),
+                                    0),
+                       RASPUTIN_ERROR);
+
+            let servers = CString::new("127.0.0.1:1").unwrap();
+            let client = rasputin_open(servers.as_ptr());
+            assert!(!client.is_null());
+            assert_eq!(rasputin_put(client, ptr::null(), 3, ptr::null(), 0),
+                       RASPUTIN_ERROR);
+            rasputin_close(client);
+
+            give(b"value".to_vec(), &mut value, &mut value_len);
+            assert_eq!(value_len, 5);
+            rasputin_free(value, value_len);
+        }
+    }
+}
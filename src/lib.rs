@@ -1,23 +1,42 @@
 #![crate_id = "rasputin"]
 #![crate_type = "lib"]
 
-pub use serialization::{Append, AppendRes, CASReq, CASRes, CliReq, CliRes,
-                        GetReq, GetRes, Mutation, MutationType, PeerMsg,
-                        RedirectRes, SetReq, SetRes, Version, VoteReq, VoteRes,
-                        WatchReq, WatchRes, DelReq, DelRes};
+pub use serialization::{Append, AppendRes, AuthReq, AuthRes, BackupReq,
+                        BackupRes, CASReq, CASRes, CliReq, CliRes, CountReq,
+                        CountRes, ErrorCode,
+                        ErrorRes, ExportCursor, ExportReq, ExportRes,
+                        GetReq, GetRes, Gossip,
+                        Hello, IngestReq, IngestRes, KeyRange, KeyValue, Member,
+                        Mutation, MutationType, PeerMsg, Priority, PromoteReq,
+                        PromoteRes, RedirectRes, ReloadConfigReq,
+                        ReloadConfigRes, Repair, RepairEntry, RepairReq, Scrub,
+                        ScrubRes, SetReq, SetRes, SnapshotReq, SnapshotRes,
+                        SpanDigest, StageReq,
+                        StageRes, TimeoutNow, TraceContext, TransferLeaderReq,
+                        TransferLeaderRes, Version, VoteReq, VoteRes, WatchReq,
+                        WatchRes, DelReq, DelRes, DeleteRangeReq,
+                        DeleteRangeRes, PutSequentialReq, PutSequentialRes,
+                        TxnKey, TxnReq, TxnRes, UpdateOp, UpdateReq, UpdateRes};
 
 pub use codec::{Codec, Framed};
 
-pub use clock::{Clock, RealClock, TestClock};
+pub use error::{ClientError, ConsensusError, Error, ProtocolError,
+                StorageError};
+
+pub use clock::{Clock, HLC, RealClock, TestClock};
 
 pub use range_bounds::RangeBounds;
 
 pub use client::Client;
 
+pub mod buffers;
 pub mod client;
 pub mod clock;
 pub mod codec;
+pub mod error;
+pub mod ffi;
 pub mod logging;
+pub mod lz4;
 pub mod range_bounds;
 pub mod serialization;
 pub mod server;
@@ -28,9 +47,11 @@ extern crate log;
 #[macro_use]
 extern crate lazy_static;
 extern crate mio;
+extern crate libc;
 extern crate protobuf;
 extern crate rand;
 extern crate rocksdb;
+extern crate rustc_serialize;
 extern crate time;
 extern crate uuid;
 extern crate threadpool;
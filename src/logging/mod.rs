@@ -1,41 +1,190 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
 use std::io::{Error, ErrorKind};
 use std::io::prelude::Write;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
 
 use log::{self, LogLevel, LogLevelFilter, LogMetadata, LogRecord,
           SetLoggerError};
+use rustc_serialize::json::{Json, ToJson};
 use time;
 
-struct StdoutLogger {
-    level: LogLevel,
+use clock::timespec_to_ms;
+
+// the most verbose level logged, which can be changed while running
+static LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
+// how records are written out, as a Format, which can also change
+static FORMAT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // one line of plain text per record
+    Text,
+    // one JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("{} isn't a log format; try text or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Format::Text => write!(f, "text"),
+            Format::Json => write!(f, "json"),
+        }
+    }
 }
 
+pub fn set_format(format: Format) {
+    FORMAT.store(format as usize, Ordering::Relaxed);
+}
+
+fn format() -> Format {
+    if FORMAT.load(Ordering::Relaxed) == Format::Json as usize {
+        Format::Json
+    } else {
+        Format::Text
+    }
+}
+
+// What the thread is working on, attached to everything it logs so that
+// the logs of a cluster's nodes can be merged and followed a request at a
+// time.  The server sets it afresh for each message it handles.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    pub node: Option<String>,
+    pub role: Option<&'static str>,
+    pub term: Option<u64>,
+    pub req_id: Option<u64>,
+    // the start of the key the request is for
+    pub key: Option<String>,
+}
+
+thread_local!(static CONTEXT: RefCell<Context> =
+                  RefCell::new(Context::default()));
+
+pub fn set_context(context: Context) {
+    CONTEXT.with(|c| *c.borrow_mut() = context);
+}
+
+pub fn context() -> Context {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+fn record_line(record: &LogRecord) -> String {
+    line(record.level(),
+         record.location().file().split("/").last().unwrap(),
+         record.location().line(),
+         &record.args().to_string(),
+         &context(),
+         format())
+}
+
+// A record as format has it, without a trailing newline.
+fn line(level: LogLevel,
+        file: &str,
+        line: u32,
+        msg: &str,
+        context: &Context,
+        format: Format)
+        -> String {
+    let now = time::get_time();
+    match format {
+        Format::Text => {
+            let mut fields = String::new();
+            if let Some(ref node) = context.node {
+                fields.push_str(&format!("node={} ", node));
+            }
+            if let Some(role) = context.role {
+                fields.push_str(&format!("role={} ", role));
+            }
+            if let Some(term) = context.term {
+                fields.push_str(&format!("term={} ", term));
+            }
+            if let Some(req_id) = context.req_id {
+                fields.push_str(&format!("req_id={} ", req_id));
+            }
+            if let Some(ref key) = context.key {
+                fields.push_str(&format!("key={} ", key));
+            }
+            format!("{} {} {}:{}] {}{}",
+                    level,
+                    now.sec, // TODO(tyler) logical clock
+                    file,
+                    line,
+                    fields,
+                    msg)
+        }
+        Format::Json => {
+            let mut o = BTreeMap::new();
+            o.insert("level".to_string(), level.to_string().to_json());
+            o.insert("ts_ms".to_string(), timespec_to_ms(now).to_json());
+            o.insert("file".to_string(), file.to_json());
+            o.insert("line".to_string(), line.to_json());
+            o.insert("msg".to_string(), msg.to_json());
+            if let Some(ref node) = context.node {
+                o.insert("node".to_string(), node.to_json());
+            }
+            if let Some(role) = context.role {
+                o.insert("role".to_string(), role.to_json());
+            }
+            if let Some(term) = context.term {
+                o.insert("term".to_string(), term.to_json());
+            }
+            if let Some(req_id) = context.req_id {
+                o.insert("req_id".to_string(), req_id.to_json());
+            }
+            if let Some(ref key) = context.key {
+                o.insert("key".to_string(), key.to_json());
+            }
+            Json::Object(o).to_string()
+        }
+    }
+}
+
+fn enabled(metadata: &LogMetadata) -> bool {
+    metadata.level() as usize <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+struct StdoutLogger;
+
 impl log::Log for StdoutLogger {
     fn enabled(&self, metadata: &LogMetadata) -> bool {
-        metadata.level() <= self.level
+        enabled(metadata)
     }
 
     fn log(&self, record: &LogRecord) {
         if self.enabled(record.metadata()) {
-            println!("{} {} {}:{}] {}",
-                     record.level(),
-                     time::now().to_timespec().sec, // TODO(tyler) logical clock
-                     record.location().file().split("/").last().unwrap(),
-                     record.location().line(),
-                     record.args());
+            println!("{}", record_line(record));
         }
     }
 }
 
 struct FileLogger {
     file: Arc<Mutex<File>>,
-    level: LogLevel,
 }
 
 impl FileLogger {
-    pub fn new(path: &str, level: LogLevel) -> Result<FileLogger, Error> {
+    pub fn new(path: &str) -> Result<FileLogger, Error> {
         let ospath = Path::new(path).parent();
         if ospath.is_none() {
             return Err(Error::new(ErrorKind::Other,
@@ -57,17 +206,14 @@ impl FileLogger {
             .append(true)
             .open(path)
             .map(|file| {
-                FileLogger {
-                    file: Arc::new(Mutex::new(file)),
-                    level: level,
-                }
+                FileLogger { file: Arc::new(Mutex::new(file)) }
             })
     }
 }
 
 impl log::Log for FileLogger {
     fn enabled(&self, metadata: &LogMetadata) -> bool {
-        metadata.level() <= self.level
+        enabled(metadata)
     }
 
     fn log(&self, record: &LogRecord) {
@@ -75,17 +221,7 @@ impl log::Log for FileLogger {
             let mut logfile = self.file.clone();
             logfile.lock()
                    .unwrap()
-                   .write_all(format!("{} {} {}:{}] {}\n",
-                                      record.level(),
-                                      time::now().to_timespec().sec,
-                                      record.location()
-                                            .file()
-                                            .split("/")
-                                            .last()
-                                            .unwrap(),
-                                      record.location().line(),
-                                      record.args())
-                                  .as_bytes());
+                   .write_all(format!("{}\n", record_line(record)).as_bytes());
         }
     }
 }
@@ -94,12 +230,59 @@ pub fn init_logger(path: Option<String>,
                    level: LogLevel)
                    -> Result<(), SetLoggerError> {
     let logger: Box<log::Log> = match path {
-        Some(p) => Box::new(FileLogger::new(p.trim_left(), level).unwrap()),
-        None => Box::new(StdoutLogger { level: level }),
+        Some(p) => Box::new(FileLogger::new(p.trim_left()).unwrap()),
+        None => Box::new(StdoutLogger),
     };
+    set_level(level);
 
     log::set_logger(|max_log_level| {
-        max_log_level.set(LogLevelFilter::Debug);
+        // leave room for set_level to turn things up later
+        max_log_level.set(LogLevelFilter::Trace);
         logger
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevel;
+    use rustc_serialize::json::Json;
+
+    use super::{Context, Format, context, line, set_context};
+
+    #[test]
+    fn test_structured_lines() {
+        let mut ctx = Context::default();
+        assert!(line(LogLevel::Warn, "server.rs", 7, "hi", &ctx, Format::Text)
+                    .ends_with(" server.rs:7] hi"));
+
+        ctx.node = Some("127.0.0.1:7777".to_string());
+        ctx.role = Some("leader");
+        ctx.term = Some(3);
+        ctx.req_id = Some(12);
+        ctx.key = Some("users/1".to_string());
+        assert!(line(LogLevel::Warn, "server.rs", 7, "hi", &ctx, Format::Text)
+                    .ends_with(" server.rs:7] node=127.0.0.1:7777 role=leader \
+                                term=3 req_id=12 key=users/1 hi"));
+
+        let json = Json::from_str(&line(LogLevel::Error,
+                                        "server.rs",
+                                        7,
+                                        "said \"hi\"",
+                                        &ctx,
+                                        Format::Json))
+                       .unwrap();
+        assert_eq!(json["level"], Json::String("ERROR".to_string()));
+        assert_eq!(json["msg"], Json::String("said \"hi\"".to_string()));
+        assert_eq!(json["node"], Json::String("127.0.0.1:7777".to_string()));
+        assert_eq!(json["term"], Json::U64(3));
+        assert_eq!(json["req_id"], Json::U64(12));
+        assert_eq!("json".parse::<Format>(), Ok(Format::Json));
+        assert!("xml".parse::<Format>().is_err());
+
+        // each thread has its own
+        set_context(ctx.clone());
+        assert_eq!(context(), ctx);
+        assert_eq!(::std::thread::spawn(context).join().unwrap(),
+                   Context::default());
+    }
+}
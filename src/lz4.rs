@@ -0,0 +1,199 @@
+// The LZ4 block format: a run of sequences, each some literal bytes
+// followed by a copy of earlier output.  A sequence starts with a token
+// whose high nibble is the literal count and low nibble the copy length
+// less four, either of which spills into following bytes at 15.  The
+// block doesn't record its decompressed size, so callers carry that.
+
+const MIN_MATCH: usize = 4;
+// the format requires the last five bytes be literals, and the last copy
+// to start at least twelve bytes before the end
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65535;
+const HASH_LOG: usize = 12;
+
+pub fn compress(src: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(src.len() / 2 + 16);
+    // where we last saw each hashed four bytes, plus one so zero is empty
+    let mut table = vec![0; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut i = 0;
+    if src.len() > MF_LIMIT {
+        let limit = src.len() - MF_LIMIT;
+        let match_limit = src.len() - LAST_LITERALS;
+        while i < limit {
+            let seq = read_u32(src, i);
+            let h = hash(seq);
+            let candidate = table[h];
+            table[h] = i + 1;
+            if candidate == 0 || i - (candidate - 1) > MAX_OFFSET ||
+               read_u32(src, candidate - 1) != seq {
+                i += 1;
+                continue;
+            }
+            let m = candidate - 1;
+            let mut len = MIN_MATCH;
+            while i + len < match_limit && src[m + len] == src[i + len] {
+                len += 1;
+            }
+            sequence(&mut dst, &src[anchor..i], Some((i - m, len)));
+            i += len;
+            anchor = i;
+        }
+    }
+    sequence(&mut dst, &src[anchor..], None);
+    dst
+}
+
+// Fails rather than producing anything but exactly size bytes, so a bad
+// block can't make us allocate more than we were told to expect.
+pub fn decompress(src: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(size);
+    let mut i = 0;
+    loop {
+        if i >= src.len() {
+            return Err("block is truncated".to_string());
+        }
+        let token = src[i];
+        i += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += try!(read_len(src, &mut i));
+        }
+        if i + literals > src.len() || out.len() + literals > size {
+            return Err("literals run past the end".to_string());
+        }
+        out.extend(&src[i..i + literals]);
+        i += literals;
+        if i == src.len() {
+            break;
+        }
+
+        if i + 2 > src.len() {
+            return Err("block is truncated".to_string());
+        }
+        let offset = src[i] as usize | (src[i + 1] as usize) << 8;
+        i += 2;
+        if offset == 0 || offset > out.len() {
+            return Err(format!("copy from {} bytes back", offset));
+        }
+        let mut len = (token & 15) as usize;
+        if len == 15 {
+            len += try!(read_len(src, &mut i));
+        }
+        len += MIN_MATCH;
+        if out.len() + len > size {
+            return Err("copy runs past the end".to_string());
+        }
+        // byte at a time, since a copy may overlap what it produces
+        let start = out.len() - offset;
+        for k in 0..len {
+            let b = out[start + k];
+            out.push(b);
+        }
+    }
+    if out.len() != size {
+        return Err(format!("decompressed to {} bytes rather than {}",
+                           out.len(),
+                           size));
+    }
+    Ok(out)
+}
+
+fn sequence(dst: &mut Vec<u8>, literals: &[u8], copy: Option<(usize, usize)>) {
+    let lit_nibble = if literals.len() >= 15 {
+        15
+    } else {
+        literals.len()
+    };
+    let len_nibble = match copy {
+        Some((_, len)) if len - MIN_MATCH >= 15 => 15,
+        Some((_, len)) => len - MIN_MATCH,
+        None => 0,
+    };
+    dst.push((lit_nibble << 4 | len_nibble) as u8);
+    if lit_nibble == 15 {
+        write_len(dst, literals.len() - 15);
+    }
+    dst.extend(literals);
+    if let Some((offset, len)) = copy {
+        dst.push(offset as u8);
+        dst.push((offset >> 8) as u8);
+        if len_nibble == 15 {
+            write_len(dst, len - MIN_MATCH - 15);
+        }
+    }
+}
+
+fn write_len(dst: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        dst.push(255);
+        n -= 255;
+    }
+    dst.push(n as u8);
+}
+
+fn read_len(src: &[u8], i: &mut usize) -> Result<usize, String> {
+    let mut n = 0;
+    loop {
+        if *i >= src.len() {
+            return Err("block is truncated".to_string());
+        }
+        let b = src[*i];
+        *i += 1;
+        n += b as usize;
+        if b != 255 {
+            return Ok(n);
+        }
+    }
+}
+
+fn read_u32(src: &[u8], i: usize) -> u32 {
+    src[i] as u32 | (src[i + 1] as u32) << 8 | (src[i + 2] as u32) << 16 |
+    (src[i + 3] as u32) << 24
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate quickcheck;
+    use rand::{Rng, thread_rng};
+
+    use super::{compress, decompress};
+
+    fn roundtrip(v: Vec<u8>) -> bool {
+        decompress(&compress(&v), v.len()) == Ok(v)
+    }
+
+    fn roundtrip_prop(sz: usize) -> bool {
+        // from a small alphabet, so there are copies to find
+        let mut rng = thread_rng();
+        roundtrip(rng.gen_iter::<u8>().map(|b| b % 4).take(sz * 16).collect())
+    }
+
+    #[test]
+    fn test_lz4_roundtrip() {
+        quickcheck::quickcheck(roundtrip_prop as fn(usize) -> bool);
+
+        // long runs exercise the spilled lengths and overlapping copies
+        let mut v = vec![7; 1000];
+        v.extend((0..600).map(|i| (i % 251) as u8));
+        v.extend(b"the same words, and the same words again");
+        let compressed = compress(&v);
+        assert!(compressed.len() < v.len() / 2);
+        assert!(roundtrip(v));
+
+        // a block built by hand from the spec: "abc" literal, then a
+        // copy of length 9 from 3 back, then the last literals
+        let block =
+            [0x35, b'a', b'b', b'c', 3, 0, 0x50, b'v', b'w', b'x', b'y', b'z'];
+        assert_eq!(decompress(&block, 17), Ok(b"abcabcabcabcvwxyz".to_vec()));
+        assert!(decompress(&block, 16).is_err());
+        assert!(decompress(&block[..5], 17).is_err());
+        assert!(decompress(&[0x10, b'a', 2, 0], 8).is_err());
+    }
+}
@@ -8,6 +8,16 @@ pub trait AckedLog<T> {
     fn get(&self, txid: TXID) -> Option<T>;
     fn ack_up_to(&mut self, txid: TXID, peer: PeerID) -> Vec<(Term, TXID)>;
     fn commit_up_to(&mut self, txid: TXID) -> Vec<(Term, TXID)>;
+    // Forgets learned entries before txid, which nobody needs anymore.
+    fn truncate_before(&mut self, txid: TXID);
+    // Where the log starts since it was last truncated; we have nothing
+    // from before this txid.
+    fn collected_before(&self) -> TXID;
+    // Drops the entries after txid, which must not have been learned, for
+    // a new leader's to replace.
+    fn discard_after(&mut self, txid: TXID);
+    // The term of the entry at txid, if we still have it.
+    fn term_of(&self, txid: TXID) -> Option<Term>;
     fn last_learned_term(&self) -> Term;
     fn last_learned_txid(&self) -> TXID;
     fn last_accepted_term(&self) -> Term;
@@ -58,6 +68,7 @@ pub struct InMemoryLog<T> {
     pub last_learned_term: Term,
     pub last_accepted_txid: TXID,
     pub last_accepted_term: Term,
+    pub collected_before: TXID,
 }
 
 unsafe impl<T> Sync for InMemoryLog<T>{}
@@ -91,13 +102,13 @@ impl<T: Clone> AckedLog<T> for InMemoryLog<T> {
     // Used by leaders to know when they've gotten enough acks.
     // returns a set of txid's that have reached quorum
     fn ack_up_to(&mut self, txid: TXID, peer: PeerID) -> Vec<(Term, TXID)> {
-        // append ack
-        for (txid, ent) in self.pending.iter_mut() {
-            if ent.inner.txid <= *txid {
-                if !ent.acks.contains(&peer) {
-                    ent.acks.push(peer)
-                }
-                break
+        // acks are cumulative, so this covers every pending entry up to txid
+        for (_, ent) in self.pending.iter_mut() {
+            if ent.inner.txid > txid {
+                break;
+            }
+            if !ent.acks.contains(&peer) {
+                ent.acks.push(peer.clone())
             }
         }
         let mut reached_quorum = vec![];
@@ -140,11 +151,44 @@ impl<T: Clone> AckedLog<T> for InMemoryLog<T> {
             self.last_learned_term = ent.inner.term;
             self.last_learned_txid = ent.inner.txid;
             reached_quorum.push((ent.inner.term, ent.inner.txid));
-            self.committed.insert(txid, ent.inner);
+            self.committed.insert(next_txid, ent.inner);
         }
         reached_quorum
     }
 
+    fn truncate_before(&mut self, txid: TXID) {
+        let keep = self.committed.split_off(&txid);
+        self.committed = keep;
+        if txid > self.collected_before {
+            self.collected_before = txid;
+        }
+    }
+
+    fn collected_before(&self) -> TXID {
+        self.collected_before
+    }
+
+    fn discard_after(&mut self, txid: TXID) {
+        assert!(txid >= self.last_learned_txid);
+        self.pending.split_off(&(txid + 1));
+        self.last_accepted_term =
+            self.term_of(txid).unwrap_or(self.last_learned_term);
+        self.last_accepted_txid = txid;
+    }
+
+    fn term_of(&self, txid: TXID) -> Option<Term> {
+        if txid == 0 {
+            return Some(0);
+        }
+        if txid == self.last_learned_txid {
+            return Some(self.last_learned_term);
+        }
+        self.pending
+            .get(&txid)
+            .map(|al| al.inner.term)
+            .or(self.committed.get(&txid).map(|l| l.term))
+    }
+
     fn last_learned_term(&self) -> Term {
         self.last_learned_term
     }
@@ -0,0 +1,180 @@
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+
+use libc;
+use mio::tcp::{TcpListener, TcpSocket};
+
+// libc doesn't define it for us
+#[cfg(target_os = "linux")]
+const IPV6_V6ONLY: libc::c_int = 26;
+#[cfg(not(target_os = "linux"))]
+const IPV6_V6ONLY: libc::c_int = 27;
+
+// Parses a host:port.  An IPv6 host is written in brackets, as in
+// [::1]:7770, so the port can't be mistaken for part of it.
+pub fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    let s = s.trim();
+    match s.parse() {
+        Ok(addr) => Ok(unmap(addr)),
+        Err(_) if !s.starts_with('[') && s.matches(':').count() > 1 => {
+            Err(format!("IPv6 address {} needs brackets around the host, as in \
+                         [::1]:7770",
+                        s))
+        }
+        Err(_) => Err(format!("{} isn't an ip:port address", s)),
+    }
+}
+
+// A v4 host connecting to a dual-stack listener shows up as a v4-mapped
+// v6 address.  We want it known by the address it would dial us from, so
+// gossip, throttling and logs all agree on one name for it.
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    if let IpAddr::V6(ip) = addr.ip() {
+        let s = ip.segments();
+        if s[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            let v4 = Ipv4Addr::new((s[6] >> 8) as u8,
+                                   s[6] as u8,
+                                   (s[7] >> 8) as u8,
+                                   s[7] as u8);
+            return SocketAddr::new(IpAddr::V4(v4), addr.port());
+        }
+    }
+    addr
+}
+
+// How to dial port on ip, bracketing a v6 host.
+pub fn join(ip: IpAddr, port: u16) -> String {
+    SocketAddr::new(ip, port).to_string()
+}
+
+// How to reach our own port from this host when listening on ip, which
+// may be the unspecified address.
+pub fn local(ip: IpAddr, port: u16) -> SocketAddr {
+    let loopback = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    let ip = match ip {
+        IpAddr::V4(v4) if v4.is_unspecified() => loopback,
+        // a v6 listener on the unspecified address takes v4 too, or fell
+        // back to listening on v4 alone
+        IpAddr::V6(v6) if v6.is_unspecified() => loopback,
+        ip => ip,
+    };
+    SocketAddr::new(ip, port)
+}
+
+// Listens on ip:port.  The unspecified v6 address takes v4 connections
+// too, regardless of the host's default, except on hosts without IPv6,
+// where we fall back to listening on 0.0.0.0.
+pub fn listen(ip: IpAddr, port: u16) -> io::Result<TcpListener> {
+    listen_on(ip, port, false)
+}
+
+// Like listen, but any number of listeners can share the port, each
+// taking its share of the connections made to it.
+pub fn listen_shared(ip: IpAddr, port: u16) -> io::Result<TcpListener> {
+    listen_on(ip, port, true)
+}
+
+fn listen_on(ip: IpAddr, port: u16, shared: bool) -> io::Result<TcpListener> {
+    let addr = SocketAddr::new(ip, port);
+    if ip != IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)) {
+        return bind(&addr, false, shared);
+    }
+    match bind(&addr, true, shared) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EAFNOSUPPORT) ||
+                      e.kind() == io::ErrorKind::AddrNotAvailable => {
+            warn!("no IPv6 here ({}), so only listening on IPv4 port {}",
+                  e,
+                  port);
+            bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port),
+                 false,
+                 shared)
+        }
+        res => res,
+    }
+}
+
+fn bind(addr: &SocketAddr,
+        dual_stack: bool,
+        shared: bool)
+        -> io::Result<TcpListener> {
+    let sock = try!(match *addr {
+        SocketAddr::V4(..) => TcpSocket::v4(),
+        SocketAddr::V6(..) => TcpSocket::v6(),
+    });
+    try!(sock.set_reuseaddr(true));
+    if dual_stack {
+        try!(set_flag(&sock, libc::IPPROTO_IPV6, IPV6_V6ONLY, false));
+    }
+    if shared {
+        try!(set_flag(&sock, libc::SOL_SOCKET, libc::SO_REUSEPORT, true));
+    }
+    try!(sock.bind(addr));
+    sock.listen(1024)
+}
+
+fn set_flag(sock: &TcpSocket,
+            level: libc::c_int,
+            name: libc::c_int,
+            on: bool)
+            -> io::Result<()> {
+    let value: libc::c_int = if on {
+        1
+    } else {
+        0
+    };
+    let res = unsafe {
+        libc::setsockopt(sock.as_raw_fd(),
+                         level,
+                         name,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use mio::tcp::TcpStream;
+
+    use super::{join, listen, listen_shared, local, parse_addr, unmap};
+
+    #[test]
+    fn test_addrs() {
+        let v4: SocketAddr = "10.0.0.1:7770".parse().unwrap();
+        assert_eq!(parse_addr(" 10.0.0.1:7770"), Ok(v4));
+        assert_eq!(parse_addr("[::1]:7770").unwrap().to_string(), "[::1]:7770");
+        assert!(parse_addr("::1:7770").unwrap_err().contains("brackets"));
+        assert!(parse_addr("fe80::1").unwrap_err().contains("brackets"));
+        assert!(parse_addr("10.0.0.1").is_err());
+        assert_eq!(parse_addr("[::ffff:10.0.0.1]:7770"), Ok(v4));
+        assert_eq!(unmap("[::ffff:10.0.0.1]:7770".parse().unwrap()), v4);
+        assert_eq!(unmap("[fe80::1]:7770".parse().unwrap()).to_string(),
+                   "[fe80::1]:7770");
+
+        let ip: IpAddr = "fe80::1".parse().unwrap();
+        assert_eq!(join(ip, 7770), "[fe80::1]:7770");
+        assert_eq!(join("10.0.0.1".parse().unwrap(), 7770), "10.0.0.1:7770");
+        assert_eq!(local("::".parse().unwrap(), 7770).to_string(),
+                   "127.0.0.1:7770");
+        assert_eq!(local(ip, 7770).to_string(), "[fe80::1]:7770");
+
+        // a dual-stack listener takes v4 connections
+        let listener = listen("::".parse().unwrap(), 0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let local = format!("127.0.0.1:{}", port).parse().unwrap();
+        assert!(TcpStream::connect(&local).is_ok());
+
+        // only listeners that all asked to can share a port
+        let first = listen_shared("127.0.0.1".parse().unwrap(), 0).unwrap();
+        let port = first.local_addr().unwrap().port();
+        assert!(listen_shared("127.0.0.1".parse().unwrap(), port).is_ok());
+        assert!(listen("127.0.0.1".parse().unwrap(), port).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use time;
+
+use Priority;
+
+const WINDOW: usize = 1024;
+// a p99 over fewer samples than this says more about luck than load
+const MIN_SAMPLES: usize = 100;
+// how many samples go by between working out the p99 again
+const RECOMPUTE_EVERY: usize = 64;
+
+lazy_static! {
+    pub static ref READ_LATENCY: LatencyWindow = LatencyWindow::new();
+    pub static ref WRITE_LATENCY: LatencyWindow = LatencyWindow::new();
+}
+
+// The latencies of the last WINDOW requests through one handler, so we
+// can tell how it's doing now rather than since it started.
+pub struct LatencyWindow {
+    // samples in microseconds, and how many we've ever recorded
+    samples: Mutex<(Vec<u64>, usize)>,
+    p99_us: AtomicUsize,
+}
+
+impl LatencyWindow {
+    pub fn new() -> LatencyWindow {
+        LatencyWindow {
+            samples: Mutex::new((Vec::with_capacity(WINDOW), 0)),
+            p99_us: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record(&self, latency: time::Duration) {
+        let us = latency.num_microseconds()
+                        .unwrap_or(i64::max_value())
+                        .max(0) as u64;
+        let mut guard = self.samples.lock().unwrap();
+        let (ref mut samples, ref mut recorded) = *guard;
+        if samples.len() < WINDOW {
+            samples.push(us);
+        } else {
+            samples[*recorded % WINDOW] = us;
+        }
+        *recorded += 1;
+        if samples.len() >= MIN_SAMPLES && *recorded % RECOMPUTE_EVERY == 0 {
+            let mut sorted = samples.clone();
+            sorted.sort();
+            let p99 = sorted[(sorted.len() * 99 / 100).min(sorted.len() - 1)];
+            self.p99_us.store(p99 as usize, Ordering::Relaxed);
+        }
+    }
+
+    // None until there are enough samples to go on.
+    pub fn p99(&self) -> Option<time::Duration> {
+        match self.p99_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(time::Duration::microseconds(us as i64)),
+        }
+    }
+}
+
+// Decides which client requests to turn away early when a handler is
+// falling behind, so that what we do take on finishes in time rather than
+// everything timing out together.  A threshold of zero is never reached.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    pub max_queue: usize,
+    pub max_p99: time::Duration,
+}
+
+impl Admission {
+    // Why a request of this priority should be shed, given the handler's
+    // queue and recent latency, if it should.  LOW requests are shed at
+    // half the thresholds NORMAL ones are.
+    pub fn shed(&self,
+                priority: Priority,
+                queue: usize,
+                p99: Option<time::Duration>)
+                -> Option<String> {
+        let divisor: usize = match priority {
+            Priority::CRITICAL => return None,
+            Priority::NORMAL => 1,
+            Priority::LOW => 2,
+        };
+        if self.max_queue > 0 && queue >= self.max_queue / divisor {
+            return Some(format!("{} requests already waiting", queue));
+        }
+        match p99 {
+            Some(p99) if self.max_p99 > time::Duration::zero() &&
+                         p99 >= self.max_p99 / divisor as i32 => {
+                Some(format!("recent p99 latency is {}ms",
+                             p99.num_milliseconds()))
+            }
+            _ => None,
+        }
+    }
+}
+
+// the longest a BUSY reply suggests waiting, as a multiple of the
+// shortest
+const MAX_BUSY_FACTOR: f64 = 10.0;
+
+// Decides when a range pushes back on new writes because it's behind on
+// the ones it's already taken: too many waiting on a quorum, as when a
+// follower is catching up or the network is slow, or committed but not
+// yet applied, as when the db is stalled compacting or a big ingest is
+// going in.  Queueing more would only make every one of them later.  A
+// threshold of zero is never reached.
+#[derive(Debug, Clone)]
+pub struct Backpressure {
+    pub max_unlearned: u64,
+    pub max_unapplied: u64,
+    pub retry_after: time::Duration,
+}
+
+impl Backpressure {
+    // Why to answer a write BUSY, and how long to suggest waiting before
+    // retrying it, which is longer the further behind the range is.
+    pub fn busy(&self,
+                unlearned: u64,
+                unapplied: u64)
+                -> Option<(String, time::Duration)> {
+        let over = |behind: u64, max: u64, why: &str| {
+            if max > 0 && behind >= max {
+                let why = format!("{} writes are {}", behind, why);
+                Some((behind as f64 / max as f64, why))
+            } else {
+                None
+            }
+        };
+        let unlearned =
+            over(unlearned, self.max_unlearned, "waiting on a quorum");
+        let unapplied = over(unapplied,
+                             self.max_unapplied,
+                             "committed but not yet applied");
+        let (factor, err) = match (unlearned, unapplied) {
+            (Some(a), Some(b)) => {
+                if b.0 > a.0 {
+                    b
+                } else {
+                    a
+                }
+            }
+            (Some(a), None) => a,
+            (None, Some(b)) => b,
+            (None, None) => return None,
+        };
+        let ms = self.retry_after.num_milliseconds() as f64 *
+                 factor.min(MAX_BUSY_FACTOR);
+        Some((err, time::Duration::milliseconds(ms as i64)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time;
+
+    use Priority;
+    use super::{Admission, Backpressure, LatencyWindow};
+
+    #[test]
+    fn test_admission() {
+        let window = LatencyWindow::new();
+        for _ in 0..63 {
+            window.record(time::Duration::milliseconds(1));
+        }
+        assert!(window.p99().is_none());
+        for i in 0..1000 {
+            let ms = if i % 50 == 0 {
+                900
+            } else {
+                1
+            };
+            window.record(time::Duration::milliseconds(ms));
+        }
+        assert_eq!(window.p99(), Some(time::Duration::milliseconds(900)));
+
+        let admission = Admission {
+            max_queue: 100,
+            max_p99: time::Duration::milliseconds(1000),
+        };
+        assert!(admission.shed(Priority::NORMAL, 10, window.p99()).is_none());
+        assert!(admission.shed(Priority::LOW, 10, window.p99()).is_some());
+        assert!(admission.shed(Priority::LOW, 60, None).is_some());
+        assert!(admission.shed(Priority::NORMAL, 100, None).is_some());
+        assert!(admission.shed(Priority::CRITICAL, 1000, window.p99())
+                         .is_none());
+
+        // the further behind, the longer it suggests waiting, up to a point
+        let backpressure = Backpressure {
+            max_unlearned: 100,
+            max_unapplied: 50,
+            retry_after: time::Duration::milliseconds(100),
+        };
+        assert!(backpressure.busy(99, 49).is_none());
+        let (err, after) = backpressure.busy(200, 50).unwrap();
+        assert!(err.contains("quorum"));
+        assert_eq!(after, time::Duration::milliseconds(200));
+        let (err, after) = backpressure.busy(0, 150).unwrap();
+        assert!(err.contains("applied"));
+        assert_eq!(after, time::Duration::milliseconds(300));
+        assert_eq!(backpressure.busy(1 << 20, 0).unwrap().1,
+                   time::Duration::seconds(1));
+        let unbounded = Backpressure {
+            max_unlearned: 0,
+            max_unapplied: 0,
+            ..backpressure
+        };
+        assert!(unbounded.busy(1 << 20, 1 << 20).is_none());
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use server::metrics::Metrics;
+
+// Compares what clients asked us to write with what actually reached the
+// disk.  There is a single keyspace for now, so the report has a single
+// "default" collection; the replication log is still in memory, so its
+// bytes show up here but not in the disk figures.
+pub fn report(storage_dir: &str, metrics: &Metrics) -> Json {
+    let applied = metrics.storage_bytes.get() as u64;
+    let disk = disk_write_bytes();
+
+    let mut c = BTreeMap::new();
+    c.insert("client_bytes".to_string(),
+             (metrics.client_bytes.get() as u64).to_json());
+    c.insert("log_bytes".to_string(),
+             (metrics.log_bytes.get() as u64).to_json());
+    c.insert("applied_bytes".to_string(), applied.to_json());
+    // everything this process wrote to disk: WAL, memtable flushes and
+    // compactions alike
+    c.insert("disk_write_bytes".to_string(), disk.to_json());
+    c.insert("write_amplification".to_string(),
+             ratio(disk, applied).to_json());
+    let size = dir_size(Path::new(storage_dir));
+    c.insert("on_disk_bytes".to_string(), size.to_json());
+
+    let mut collections = BTreeMap::new();
+    collections.insert("default".to_string(), Json::Object(c));
+    let mut o = BTreeMap::new();
+    o.insert("collections".to_string(), Json::Object(collections));
+    Json::Object(o)
+}
+
+fn ratio(num: Option<u64>, denom: u64) -> Option<f64> {
+    match num {
+        Some(n) if denom > 0 => Some(n as f64 / denom as f64),
+        _ => None,
+    }
+}
+
+// The kernel's count of bytes this process caused to be written to the
+// block layer.  Only available on Linux.
+fn disk_write_bytes() -> Option<u64> {
+    let mut io = String::new();
+    let read = File::open("/proc/self/io")
+                   .and_then(|mut f| f.read_to_string(&mut io));
+    match read {
+        Ok(_) => parse_write_bytes(&io),
+        Err(_) => None,
+    }
+}
+
+fn parse_write_bytes(io: &str) -> Option<u64> {
+    io.lines()
+      .filter_map(|line| {
+          let mut parts = line.split(':');
+          match (parts.next(), parts.next()) {
+              (Some("write_bytes"), Some(v)) => v.trim().parse().ok(),
+              _ => None,
+          }
+      })
+      .next()
+}
+
+fn dir_size(dir: &Path) -> Option<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return None,
+    };
+    let mut size = 0;
+    for entry in entries {
+        let meta = match entry.and_then(|e| e.metadata()) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if meta.is_file() {
+            size += meta.len();
+        }
+    }
+    Some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_write_bytes, ratio};
+
+    #[test]
+    fn test_parse_write_bytes() {
+        let io = "rchar: 10\nwchar: 20\nread_bytes: 4096\nwrite_bytes: 8192\n\
+                  cancelled_write_bytes: 0\n";
+        assert_eq!(parse_write_bytes(io), Some(8192));
+        assert_eq!(parse_write_bytes("rchar: 10\n"), None);
+        assert_eq!(ratio(Some(30), 10), Some(3.0));
+        assert_eq!(ratio(Some(30), 0), None);
+        assert_eq!(ratio(None, 10), None);
+    }
+}
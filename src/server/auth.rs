@@ -0,0 +1,55 @@
+use rocksdb::DB;
+
+use StorageError;
+use server::checksum;
+
+// Credentials live in the replicated keyspace, so every node can check
+// them against its own db: the key is this prefix followed by the token,
+// and the value is the principal the token authenticates as.  Only root
+// may read or write under it.
+pub const CREDENTIALS_PREFIX: &'static [u8] = b"\x00credentials/";
+
+// whoever presents the configured root token
+pub const ROOT: &'static str = "root";
+
+// Returns who a token authenticates as, if anyone.
+pub fn principal(db: &DB,
+                 root_token: &str,
+                 token: &str)
+                 -> Result<Option<String>, StorageError> {
+    if constant_time_eq(token.as_bytes(), root_token.as_bytes()) {
+        return Ok(Some(ROOT.to_string()));
+    }
+    let mut key = CREDENTIALS_PREFIX.to_vec();
+    key.extend(token.as_bytes());
+    checksum::get(db, &key).map(|v| {
+        v.map(|v| String::from_utf8_lossy(&v).into_owned())
+    })
+}
+
+pub fn is_credentials_key(key: &[u8]) -> bool {
+    key.starts_with(CREDENTIALS_PREFIX)
+}
+
+// so that how long a comparison takes says nothing about how much of the
+// token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, is_credentials_key};
+
+    #[test]
+    fn test_credentials_keys() {
+        assert!(is_credentials_key(b"\x00credentials/abc"));
+        assert!(!is_credentials_key(b"credentials/abc"));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
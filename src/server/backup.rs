@@ -0,0 +1,635 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+
+use bytes::ByteBuf;
+use protobuf::Message;
+use rocksdb::{DB, Direction, WriteBatch, Writable};
+use rustc_serialize::json::{Json, ToJson};
+use time;
+
+use {BackupReq, BackupRes, CliRes, StorageError};
+use clock::timespec_to_ms;
+use server::{Envelope, PeerID, TXID, Term};
+use server::lanes::Taken;
+use server::checksum::{self, bytes_to_u32, bytes_to_u64, u32_to_bytes,
+                       u64_to_bytes};
+
+// A backup's data, whether written to a file in the target directory or
+// streamed to a socket, is laid out as:
+//
+//   magic    the 8 bytes RSPBAK01
+//   header   u64 txid and u64 term of the last applied entry it includes,
+//            u64 hlc of the node as it was taken, u64 since: zero for a
+//            full backup, or the txid an incremental one follows on from
+//   records  u8 1, u32 key length, key, u32 value length, value
+//            or for a key an incremental backup saw deleted,
+//            u8 2, u32 key length, key
+//   trailer  u8 0, u64 how many records, u32 crc32c over everything
+//            before the trailer's count
+//
+// Integers are little-endian, and values are as the db stores them, with
+// their checksums, so they're verified on restore just as on startup.
+// A directory holds one file per backup, named in the order they were
+// taken, and a MANIFEST.json listing them oldest first.  Restoring one
+// means starting from the last full backup and applying each incremental
+// one after it in turn.
+pub const MAGIC: &'static [u8] = b"RSPBAK01";
+pub const MANIFEST: &'static str = "MANIFEST.json";
+
+// how many restored keys go to the db in each write
+const RESTORE_BATCH: usize = 1000;
+
+const RECORD: u8 = 1;
+const DELETED: u8 = 2;
+const END: u8 = 0;
+
+// A key and its stored value, or None if it's been deleted.
+pub type Record = (Box<[u8]>, Option<Box<[u8]>>);
+
+// What the manifest records about one backup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    // the data file in the directory, or the address it was streamed to
+    pub file: String,
+    pub node: PeerID,
+    pub txid: TXID,
+    pub term: Term,
+    pub hlc: u64,
+    pub since: TXID,
+    pub keys: u64,
+    pub bytes: u64,
+    pub crc: u32,
+    pub taken_ms: u64,
+}
+
+impl ManifestEntry {
+    pub fn from_json(json: &Json) -> Option<ManifestEntry> {
+        let num = |field: &str| json.find(field).and_then(|v| v.as_u64());
+        let string = |field: &str| {
+            json.find(field)
+                .and_then(|v| v.as_string())
+                .map(String::from)
+        };
+        Some(ManifestEntry {
+            file: match string("file") {
+                Some(file) => file,
+                None => return None,
+            },
+            node: string("node").unwrap_or_default(),
+            txid: match num("txid") {
+                Some(txid) => txid,
+                None => return None,
+            },
+            term: num("term").unwrap_or(0),
+            hlc: num("hlc").unwrap_or(0),
+            since: num("since").unwrap_or(0),
+            keys: num("keys").unwrap_or(0),
+            bytes: num("bytes").unwrap_or(0),
+            crc: num("crc").unwrap_or(0) as u32,
+            taken_ms: num("taken_ms").unwrap_or(0),
+        })
+    }
+}
+
+impl ToJson for ManifestEntry {
+    fn to_json(&self) -> Json {
+        let mut o = BTreeMap::new();
+        o.insert("file".to_string(), self.file.to_json());
+        o.insert("node".to_string(), self.node.to_json());
+        o.insert("txid".to_string(), self.txid.to_json());
+        o.insert("term".to_string(), self.term.to_json());
+        o.insert("hlc".to_string(), self.hlc.to_json());
+        o.insert("since".to_string(), self.since.to_json());
+        o.insert("keys".to_string(), self.keys.to_json());
+        o.insert("bytes".to_string(), self.bytes.to_json());
+        o.insert("crc".to_string(), self.crc.to_json());
+        o.insert("taken_ms".to_string(), self.taken_ms.to_json());
+        Json::Object(o)
+    }
+}
+
+// What the header and trailer of a backup's data say.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataHeader {
+    pub txid: TXID,
+    pub term: Term,
+    pub hlc: u64,
+    pub since: TXID,
+    pub keys: u64,
+}
+
+// A backup waiting for the backup worker, which takes a snapshot of the
+// db and tells the server once it has, so that nothing is applied
+// between the server noting where it's at and the snapshot.
+pub struct BackupJob {
+    pub env: Envelope,
+    pub req_id: u64,
+    pub backup: BackupReq,
+    pub node: PeerID,
+    pub txid: TXID,
+    pub term: Term,
+    pub hlc: u64,
+    // for an incremental backup, the txid it follows on from and the
+    // keys written since
+    pub changed: Option<(TXID, BTreeSet<Vec<u8>>)>,
+    pub taken: Taken,
+}
+
+impl BackupJob {
+    pub fn run(mut self, db: &DB) -> Envelope {
+        let snapshot = db.snapshot();
+        self.taken.done();
+        let mut entry = ManifestEntry {
+            file: String::new(),
+            node: self.node.clone(),
+            txid: self.txid,
+            term: self.term,
+            hlc: self.hlc,
+            since: 0,
+            keys: 0,
+            bytes: 0,
+            crc: 0,
+            taken_ms: timespec_to_ms(time::get_time()),
+        };
+        let lookup = |key: &[u8]| {
+            let mut iter = snapshot.iterator();
+            let found = iter.from(key, Direction::forward).next();
+            match found {
+                Some((k, v)) if &*k == key => Some(v),
+                _ => None,
+            }
+        };
+        let mut iter = snapshot.iterator();
+        let records: Box<Iterator<Item = Record>> = match self.changed {
+            Some((since, ref keys)) => {
+                entry.since = since;
+                let record = |k: &Vec<u8>| {
+                    (k.clone().into_boxed_slice(), lookup(k))
+                };
+                Box::new(keys.iter().map(record))
+            }
+            None => Box::new(iter.from_start().map(|(k, v)| (k, Some(v)))),
+        };
+        let written = if self.backup.has_addr() {
+            entry.file = self.backup.get_addr().to_string();
+            TcpStream::connect(self.backup.get_addr())
+                .map_err(io_error)
+                .and_then(|sock| write_data(&mut entry, records, sock))
+        } else {
+            write_dir(self.backup.get_dir(), &mut entry, records)
+        };
+
+        let mut backup_res = BackupRes::new();
+        match written {
+            Ok(()) => {
+                info!("backed up {} keys as of txid {} to {}{}",
+                      entry.keys,
+                      entry.txid,
+                      entry.file,
+                      if entry.since > 0 {
+                          format!(", changed since txid {}", entry.since)
+                      } else {
+                          String::new()
+                      });
+                backup_res.set_success(true);
+                backup_res.set_manifest(entry.to_json().to_string());
+            }
+            Err(e) => {
+                error!("backup failed: {}", e);
+                backup_res.set_success(false);
+                backup_res.set_err(e.to_string());
+            }
+        }
+        let mut res = CliRes::new();
+        res.set_req_id(self.req_id);
+        res.set_backup(backup_res);
+        Envelope {
+            address: self.env.address,
+            tok: self.env.tok,
+            msg: ByteBuf::from_slice(&*res.write_to_bytes().unwrap()),
+        }
+    }
+}
+
+// Writes the records to a new file in dir, synced and renamed into place
+// before the manifest lists it, so a backup only shows up once it's whole.
+pub fn write_dir<I>(dir: &str,
+                    entry: &mut ManifestEntry,
+                    records: I)
+                    -> Result<(), StorageError>
+    where I: Iterator<Item = Record>
+{
+    try!(fs::create_dir_all(dir).map_err(io_error));
+    let mut manifest = try!(read_manifest(dir));
+    entry.file = format!("{:06}.backup", manifest.len() + 1);
+    let path = Path::new(dir).join(&entry.file);
+    let tmp = Path::new(dir).join(format!("{}.tmp", entry.file));
+    let file = try!(File::create(&tmp).map_err(io_error));
+    try!(write_data(entry, records, &file));
+    try!(file.sync_all().map_err(io_error));
+    try!(fs::rename(&tmp, &path).map_err(io_error));
+
+    manifest.push(entry.clone());
+    write_manifest(dir, &manifest)
+}
+
+// The backups in dir, oldest first; none if it has no manifest yet.
+pub fn read_manifest(dir: &str) -> Result<Vec<ManifestEntry>, StorageError> {
+    let mut file = match File::open(Path::new(dir).join(MANIFEST)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(io_error(e)),
+    };
+    let mut contents = String::new();
+    try!(file.read_to_string(&mut contents).map_err(io_error));
+    let bad = || {
+        StorageError::Corrupt(format!("{}/{} isn't a backup manifest",
+                                      dir, MANIFEST))
+    };
+    let json = try!(Json::from_str(&contents).map_err(|_| bad()));
+    let backups =
+        try!(json.find("backups").and_then(|b| b.as_array()).ok_or(bad()));
+    backups.iter().map(|b| ManifestEntry::from_json(b).ok_or(bad())).collect()
+}
+
+fn write_manifest(dir: &str,
+                  manifest: &[ManifestEntry])
+                  -> Result<(), StorageError> {
+    let mut o = BTreeMap::new();
+    let backups = manifest.iter().map(|entry| entry.to_json()).collect();
+    o.insert("backups".to_string(), Json::Array(backups));
+    let tmp = Path::new(dir).join(format!("{}.tmp", MANIFEST));
+    let mut file = try!(File::create(&tmp).map_err(io_error));
+    try!(file.write_all(Json::Object(o).pretty().to_string().as_bytes())
+             .map_err(io_error));
+    try!(file.sync_all().map_err(io_error));
+    fs::rename(&tmp, Path::new(dir).join(MANIFEST)).map_err(io_error)
+}
+
+// Writes the records out in the backup format, filling in how many there
+// were, how many bytes that came to and their checksum.
+pub fn write_data<I, W>(entry: &mut ManifestEntry,
+                        records: I,
+                        w: W)
+                        -> Result<(), StorageError>
+    where I: Iterator<Item = Record>,
+          W: Write
+{
+    let mut out = Summed {
+        inner: BufWriter::new(w),
+        crc: !0,
+        bytes: 0,
+    };
+    try!(out.put(MAGIC));
+    for n in [entry.txid, entry.term, entry.hlc, entry.since].iter() {
+        try!(out.put(&u64_to_bytes(*n)));
+    }
+    let mut keys = 0;
+    for (k, v) in records {
+        try!(out.put(&[if v.is_some() { RECORD } else { DELETED }]));
+        try!(out.put(&u32_to_bytes(k.len() as u32)));
+        try!(out.put(&k));
+        if let Some(v) = v {
+            try!(out.put(&u32_to_bytes(v.len() as u32)));
+            try!(out.put(&v));
+        }
+        keys += 1;
+    }
+    try!(out.put(&[END]));
+    let crc = out.crc ^ !0;
+    try!(out.put(&u64_to_bytes(keys)));
+    try!(out.put(&u32_to_bytes(crc)));
+    try!(out.inner.flush().map_err(io_error));
+    entry.keys = keys;
+    entry.bytes = out.bytes;
+    entry.crc = crc;
+    Ok(())
+}
+
+// Loads the backups a manifest lists into an empty db: the last full
+// backup taken by until_ms if given, or the last of all, and every
+// incremental one that follows on from it up to then.  man may be the
+// manifest itself or the directory holding it.  Returns what the last
+// backup restored was as of.
+pub fn restore(db: &DB,
+               man: &str,
+               until_ms: Option<u64>)
+               -> Result<ManifestEntry, StorageError> {
+    let dir = if Path::new(man).is_dir() {
+        Path::new(man)
+    } else {
+        Path::new(man).parent().unwrap_or(Path::new("."))
+    };
+    let dir = &*dir.to_string_lossy().into_owned();
+    if db.iterator().from_start().next().is_some() {
+        let err = "can only restore into an empty storage directory";
+        return Err(StorageError::Operation(err.to_string()));
+    }
+    let wanted = |entry: &ManifestEntry| {
+        until_ms.map(|until| entry.taken_ms <= until).unwrap_or(true)
+    };
+    let manifest: Vec<ManifestEntry> = try!(read_manifest(dir))
+                                           .into_iter()
+                                           .filter(|entry| wanted(entry))
+                                           .collect();
+    let no_full = format!("no full backup in {} to restore from", dir);
+    let full = try!(manifest.iter()
+                            .rposition(|entry| entry.since == 0)
+                            .ok_or(StorageError::Operation(no_full)));
+    let mut restored: Option<ManifestEntry> = None;
+    for entry in &manifest[full..] {
+        if let Some(ref last) = restored {
+            if entry.since != last.txid {
+                // taken against a different chain; the rest can't follow on
+                warn!("backup {} follows on from txid {}, not {}, so \
+                       restoring no further",
+                      entry.file,
+                      entry.since,
+                      last.txid);
+                break;
+            }
+        }
+        let path = Path::new(dir).join(&entry.file);
+        let file = try!(File::open(path).map_err(io_error));
+        let mut batch = WriteBatch::new();
+        let mut batched = 0;
+        let header = try!(read_data(file, |k, v| {
+            try!(match v {
+                     Some(v) => {
+                         try!(checksum::unseal(&k, &v));
+                         batch.put(&k, &v)
+                     }
+                     None => batch.delete(&k),
+                 }
+                 .map_err(StorageError::Operation));
+            batched += 1;
+            if batched == RESTORE_BATCH {
+                let written = ::std::mem::replace(&mut batch,
+                                                  WriteBatch::new());
+                try!(db.write(written).map_err(StorageError::Operation));
+                batched = 0;
+            }
+            Ok(())
+        }));
+        try!(db.write(batch).map_err(StorageError::Operation));
+        if header.txid != entry.txid || header.since != entry.since {
+            let err = format!("{} isn't the backup its manifest describes",
+                              entry.file);
+            return Err(StorageError::Corrupt(err));
+        }
+        info!("restored {} keys from {} as of txid {}",
+              header.keys,
+              entry.file,
+              entry.txid);
+        restored = Some(entry.clone());
+    }
+    Ok(restored.unwrap())
+}
+
+// Reads backup data back, handing each record to f, and checks that it
+// all arrived intact before saying what it was.
+pub fn read_data<R, F>(r: R, mut f: F) -> Result<DataHeader, StorageError>
+    where R: Read,
+          F: FnMut(Vec<u8>, Option<Vec<u8>>) -> Result<(), StorageError>
+{
+    let mut input = Summed {
+        inner: BufReader::new(r),
+        crc: !0,
+        bytes: 0,
+    };
+    if try!(input.take(MAGIC.len())) != MAGIC {
+        return Err(StorageError::Corrupt("not a rasputin backup".to_string()));
+    }
+    let mut header = DataHeader {
+        txid: try!(input.take_u64()),
+        term: try!(input.take_u64()),
+        hlc: try!(input.take_u64()),
+        since: try!(input.take_u64()),
+        keys: 0,
+    };
+    let mut keys = 0;
+    loop {
+        match try!(input.take(1))[0] {
+            RECORD => {
+                let len = try!(input.take_u32()) as usize;
+                let k = try!(input.take(len));
+                let len = try!(input.take_u32()) as usize;
+                let v = try!(input.take(len));
+                try!(f(k, Some(v)));
+                keys += 1;
+            }
+            DELETED => {
+                let len = try!(input.take_u32()) as usize;
+                let k = try!(input.take(len));
+                try!(f(k, None));
+                keys += 1;
+            }
+            END => break,
+            kind => {
+                let err = format!("unknown backup record kind {}", kind);
+                return Err(StorageError::Corrupt(err));
+            }
+        }
+    }
+    let crc = input.crc ^ !0;
+    let count = try!(input.take_u64());
+    if count != keys || try!(input.take_u32()) != crc {
+        let err = "backup is damaged or incomplete";
+        return Err(StorageError::Corrupt(err.to_string()));
+    }
+    header.keys = keys;
+    Ok(header)
+}
+
+// Keeps a running checksum and count of the bytes that pass through.
+struct Summed<T> {
+    inner: T,
+    crc: u32,
+    bytes: u64,
+}
+
+impl<W: Write> Summed<W> {
+    fn put(&mut self, data: &[u8]) -> Result<(), StorageError> {
+        self.crc = checksum::update(self.crc, data);
+        self.bytes += data.len() as u64;
+        self.inner.write_all(data).map_err(io_error)
+    }
+}
+
+impl<R: Read> Summed<R> {
+    fn take(&mut self, len: usize) -> Result<Vec<u8>, StorageError> {
+        let mut data = vec![0; len];
+        try!(self.inner.read_exact(&mut data).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                StorageError::Corrupt("backup ends early".to_string())
+            } else {
+                io_error(e)
+            }
+        }));
+        self.crc = checksum::update(self.crc, &data);
+        self.bytes += len as u64;
+        Ok(data)
+    }
+
+    fn take_u32(&mut self) -> Result<u32, StorageError> {
+        self.take(4).map(|b| bytes_to_u32(&b))
+    }
+
+    fn take_u64(&mut self) -> Result<u64, StorageError> {
+        self.take(8).map(|b| bytes_to_u64(&b))
+    }
+}
+
+fn io_error(e: io::Error) -> StorageError {
+    StorageError::Operation(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+
+    use StorageError;
+    use server::checksum;
+    use server::rocksdb::new;
+    use super::{ManifestEntry, Record, read_data, read_manifest, restore,
+                write_data, write_dir};
+
+    // every third key deleted
+    fn records(n: usize) -> Vec<Record> {
+        (0..n)
+            .map(|i| {
+                (format!("k{}", i).into_bytes().into_boxed_slice(),
+                 if i % 3 == 2 {
+                     None
+                 } else {
+                     Some(format!("v{}", i).into_bytes().into_boxed_slice())
+                 })
+            })
+            .collect()
+    }
+
+    fn entry(txid: u64) -> ManifestEntry {
+        ManifestEntry {
+            file: String::new(),
+            node: "n1".to_string(),
+            txid: txid,
+            term: 2,
+            hlc: 99,
+            since: 0,
+            keys: 0,
+            bytes: 0,
+            crc: 0,
+            taken_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_backup_round_trip() {
+        let dir = "_rasputin_test_backup";
+        let _ = fs::remove_dir_all(dir);
+
+        let mut first = entry(10);
+        write_dir(dir, &mut first, records(3).into_iter()).unwrap();
+        let mut second = entry(20);
+        write_dir(dir, &mut second, records(5).into_iter()).unwrap();
+        assert_eq!(read_manifest(dir).unwrap(),
+                   vec![first.clone(), second.clone()]);
+        assert_eq!(second.file, "000002.backup");
+        assert_eq!(second.keys, 5);
+
+        let mut data = vec![];
+        File::open(format!("{}/{}", dir, second.file))
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data.len() as u64, second.bytes);
+        let mut got = vec![];
+        let header = read_data(&data[..], |k, v| {
+                         got.push((k, v));
+                         Ok(())
+                     })
+                     .unwrap();
+        assert_eq!((header.txid, header.term, header.hlc, header.keys),
+                   (20, 2, 99, 5));
+        assert_eq!(got[4], (b"k4".to_vec(), Some(b"v4".to_vec())));
+        assert_eq!(got[2], (b"k2".to_vec(), None));
+
+        // anything cut short or flipped is caught
+        for len in 0..data.len() {
+            assert!(read_data(&data[..len], |_, _| Ok(())).is_err());
+        }
+        data[20] ^= 1;
+        match read_data(&data[..], |_, _| Ok(())) {
+            Err(StorageError::Corrupt(_)) => (),
+            r => panic!("damaged backup got through: {:?}", r),
+        }
+
+        let mut streamed = vec![];
+        write_data(&mut entry(20), records(5).into_iter(), &mut streamed)
+            .unwrap();
+        data[20] ^= 1;
+        assert_eq!(streamed, data);
+
+        File::create(format!("{}/MANIFEST.json", dir))
+            .unwrap()
+            .write_all(b"{}")
+            .unwrap();
+        assert!(read_manifest(dir).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore() {
+        let dir = "_rasputin_test_restore_backups";
+        let _ = fs::remove_dir_all(dir);
+
+        let sealed = |k: &str, v: &str| {
+            (k.as_bytes().to_vec().into_boxed_slice(),
+             Some(checksum::seal(k.as_bytes(), v.as_bytes())
+                      .into_boxed_slice()))
+        };
+        let mut full = entry(10);
+        full.taken_ms = 1000;
+        let first = vec![sealed("a", "1"), sealed("b", "1")];
+        write_dir(dir, &mut full, first.into_iter()).unwrap();
+        let mut incremental = entry(20);
+        incremental.since = 10;
+        incremental.taken_ms = 2000;
+        let changes: Vec<Record> =
+            vec![sealed("a", "2"), (b"b".to_vec().into_boxed_slice(), None)];
+        write_dir(dir, &mut incremental, changes.into_iter()).unwrap();
+
+        {
+            let db = new("_rasputin_test_restore_latest".to_string()).unwrap();
+            let restored =
+                restore(&db, &format!("{}/MANIFEST.json", dir), None).unwrap();
+            assert_eq!(restored.txid, 20);
+            assert_eq!(checksum::get(&db, b"a").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(checksum::get(&db, b"b").unwrap(), None);
+            // there's no restoring over what's there
+            assert!(restore(&db, dir, None).is_err());
+        }
+
+        // back to before the incremental one
+        {
+            let db = new("_rasputin_test_restore_until".to_string()).unwrap();
+            assert_eq!(restore(&db, dir, Some(1500)).unwrap().txid, 10);
+            assert_eq!(checksum::get(&db, b"a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(checksum::get(&db, b"b").unwrap(), Some(b"1".to_vec()));
+        }
+        {
+            // nothing had been backed up yet
+            let db = new("_rasputin_test_restore_before".to_string()).unwrap();
+            assert!(restore(&db, dir, Some(500)).is_err());
+        }
+        fs::remove_dir_all(dir).unwrap();
+        for into in &["latest", "until", "before"] {
+            let dir = format!("_rasputin_test_restore_{}", into);
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
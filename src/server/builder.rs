@@ -0,0 +1,936 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::net::{self, IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use bytes::ByteBuf;
+use rocksdb::DB;
+use mio::EventLoop;
+use rand::{Rng, thread_rng};
+use time;
+
+use {Clock, Error, HLC, RealClock};
+use server::{ConfigFile, DRAIN, Envelope, Faults, GcHorizon, InMemoryLog,
+             Membership, Namespaces, NodeStatus, PeerID, ReadJob, SHUTDOWN,
+             SendChannel, Server, ServerConfig, ServerMsg, State, StorageStats,
+             Watermark, WorkerPool};
+use server::addr;
+use server::backup::{self, BackupJob};
+use server::expiry;
+use server::io_loops::IoLoops;
+use server::lanes;
+use server::export::ExportJob;
+use server::snapshot::SnapshotWorker;
+use server::discovery;
+use server::disk;
+use server::memcached;
+use server::embedded::{self, Embedded, Loopback};
+use server::metrics::Metrics;
+use server::mirror;
+use server::resp;
+use server::rest;
+use server::rocksdb::{self, WalSyncer};
+use server::traffic_cop::TrafficCop;
+
+// Number of long-running threads started for each server: the first IO
+// loop, the server actor, and the cron.  Each other IO loop is one more.
+const WORKER_THREADS: usize = 3;
+
+// What's read from storage on startup.
+struct Storage {
+    db: Arc<DB>,
+    id: PeerID,
+    promoted: bool,
+    stats: StorageStats,
+    namespaces: Namespaces,
+}
+
+pub struct ServerBuilder<C: Clock> {
+    listen_ip: IpAddr,
+    peer_port: u16,
+    cli_port: u16,
+    admin_port: Option<u16>,
+    resp_port: Option<u16>,
+    rest_port: Option<u16>,
+    memcached_port: Option<u16>,
+    cluster_id: String,
+    storage_dir: String,
+    peers: Vec<String>,
+    clock: Arc<C>,
+    config: ServerConfig,
+    // a manifest to load backups from before starting, and the time to
+    // restore to
+    restore: Option<(String, Option<u64>)>,
+}
+
+impl ServerBuilder<RealClock> {
+    pub fn new() -> ServerBuilder<RealClock> {
+        ServerBuilder {
+            // every interface, v4 and v6 alike
+            listen_ip: IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+            peer_port: 7770,
+            cli_port: 8880,
+            admin_port: None,
+            resp_port: None,
+            rest_port: None,
+            memcached_port: None,
+            cluster_id: "rasputin".to_string(),
+            storage_dir: "/var/lib/rasputin".to_string(),
+            peers: vec![],
+            clock: Arc::new(RealClock),
+            config: ServerConfig::default(),
+            restore: None,
+        }
+    }
+}
+
+impl<C: Clock + Send + Sync + 'static> ServerBuilder<C> {
+    // Listen only on this address rather than on every interface.
+    pub fn listen_ip(mut self, listen_ip: IpAddr) -> ServerBuilder<C> {
+        self.listen_ip = listen_ip;
+        self
+    }
+
+    pub fn peer_port(mut self, peer_port: u16) -> ServerBuilder<C> {
+        self.peer_port = peer_port;
+        self
+    }
+
+    pub fn cli_port(mut self, cli_port: u16) -> ServerBuilder<C> {
+        self.cli_port = cli_port;
+        self
+    }
+
+    // Serve read-only introspection, such as Prometheus metrics at
+    // /metrics, over HTTP on this port.
+    pub fn admin_port(mut self, admin_port: u16) -> ServerBuilder<C> {
+        self.admin_port = Some(admin_port);
+        self
+    }
+
+    // Speak a subset of the Redis protocol on this port, for Redis
+    // clients to use.
+    pub fn resp_port(mut self, resp_port: u16) -> ServerBuilder<C> {
+        self.resp_port = Some(resp_port);
+        self
+    }
+
+    // Serve keys over HTTP with JSON bodies on this port, for scripts and
+    // curl.
+    pub fn rest_port(mut self, rest_port: u16) -> ServerBuilder<C> {
+        self.rest_port = Some(rest_port);
+        self
+    }
+
+    // Speak the memcached text protocol on this port, keeping items under
+    // the configured memcached_prefix.
+    pub fn memcached_port(mut self, memcached_port: u16) -> ServerBuilder<C> {
+        self.memcached_port = Some(memcached_port);
+        self
+    }
+
+    // Reported to service discovery, so nodes of different clusters
+    // sharing a registry can be told apart.
+    pub fn cluster_id(mut self, cluster_id: &str) -> ServerBuilder<C> {
+        self.cluster_id = cluster_id.to_string();
+        self
+    }
+
+    pub fn storage_dir(mut self, storage_dir: &str) -> ServerBuilder<C> {
+        self.storage_dir = storage_dir.to_string();
+        self
+    }
+
+    pub fn peers(mut self, peers: Vec<String>) -> ServerBuilder<C> {
+        self.peers = peers;
+        self
+    }
+
+    // Rebuilds an empty storage dir from the backups a manifest lists
+    // before starting, going no further than the last one taken by
+    // until_ms if given.  The node then catches up from its peers, or
+    // seeds a new cluster if it has none.
+    pub fn restore(mut self,
+                   manifest: &str,
+                   until_ms: Option<u64>)
+                   -> ServerBuilder<C> {
+        self.restore = Some((manifest.to_string(), until_ms));
+        self
+    }
+
+    pub fn config(mut self, config: ServerConfig) -> ServerBuilder<C> {
+        self.config = config;
+        self
+    }
+
+    // Takes whatever the file sets, so builder calls after this one
+    // override it.
+    pub fn config_file(mut self, file: &ConfigFile) -> ServerBuilder<C> {
+        if let Some(ip) = file.listen_ip {
+            self.listen_ip = ip;
+        }
+        if let Some(port) = file.peer_port {
+            self.peer_port = port;
+        }
+        if let Some(port) = file.cli_port {
+            self.cli_port = port;
+        }
+        if file.admin_port.is_some() {
+            self.admin_port = file.admin_port;
+        }
+        if file.resp_port.is_some() {
+            self.resp_port = file.resp_port;
+        }
+        if file.rest_port.is_some() {
+            self.rest_port = file.rest_port;
+        }
+        if file.memcached_port.is_some() {
+            self.memcached_port = file.memcached_port;
+        }
+        if let Some(ref id) = file.cluster_id {
+            self.cluster_id = id.clone();
+        }
+        if let Some(ref dir) = file.storage_dir {
+            self.storage_dir = dir.clone();
+        }
+        if let Some(ref peers) = file.seed_peers {
+            self.peers = peers.clone();
+        }
+        self.config = file.server.clone();
+        self
+    }
+
+    pub fn clock<C2: Clock>(self, clock: Arc<C2>) -> ServerBuilder<C2> {
+        ServerBuilder {
+            listen_ip: self.listen_ip,
+            peer_port: self.peer_port,
+            cli_port: self.cli_port,
+            admin_port: self.admin_port,
+            resp_port: self.resp_port,
+            rest_port: self.rest_port,
+            memcached_port: self.memcached_port,
+            cluster_id: self.cluster_id,
+            storage_dir: self.storage_dir,
+            peers: self.peers,
+            clock: clock,
+            config: self.config,
+            restore: self.restore,
+        }
+    }
+
+    pub fn start(self) -> Result<ServerHandle, Error> {
+        if self.config.standalone &&
+           (!self.peers.is_empty() || self.config.witness ||
+            self.config.learner) {
+            return Err(invalid_input("a standalone node has no peers, and is \
+                                      neither a witness nor a learner"));
+        }
+        let storage = try!(self.open_storage());
+        let id = storage.id.clone();
+        let db = storage.db.clone();
+
+        // All long-running worker threads get a clone of this
+        // Sender.  When they exit, they send over it.  If the
+        // Receiver ever completes a read before we've been asked to
+        // shut down, it means something unexpectedly exited.  It's
+        // vital that we shut down immediately, so we don't repeat
+        // the ZK bug where the heartbeater keeps running while other
+        // vital threads have exited, falsely communicating healthiness.
+        let (thread_exit_tx, thread_exit_rx) = mpsc::channel();
+
+        // The TrafficCops manage our sockets, put the messages they read
+        // in the server's mailbox, and receive completed responses over
+        // the response channel.
+        let metrics = Arc::new(Metrics::new());
+        let (server_tx, server_rx) =
+            lanes::mailbox(self.config.mailbox_capacity,
+                           self.config.request_queue_depth,
+                           metrics.clone());
+
+        let peer_addrs = discovery::resolve(&self.peers);
+        if peer_addrs.is_empty() && !self.peers.is_empty() {
+            return Err(invalid_input("none of the seed peers resolved"));
+        }
+        let io_loops = cmp::max(self.config.io_threads, 1);
+        let mut tc = try!(TrafficCop::new(self.listen_ip,
+                                          self.peer_port,
+                                          self.cli_port,
+                                          self.admin_port,
+                                          peer_addrs.clone(),
+                                          server_tx.clone(),
+                                          io_loops));
+
+        let mut node_status = NodeStatus::new(id.clone());
+        node_status.cluster_id = self.cluster_id.clone();
+        node_status.storage_dir = self.storage_dir.clone();
+        for &(name, port) in [("peer", Some(self.peer_port)),
+                              ("client", Some(self.cli_port)),
+                              ("admin", self.admin_port),
+                              ("resp", self.resp_port),
+                              ("rest", self.rest_port),
+                              ("memcached", self.memcached_port)]
+                                 .iter() {
+            if let Some(port) = port {
+                node_status.addresses
+                           .insert(name.to_string(),
+                                   addr::join(self.listen_ip, port));
+            }
+        }
+        // discovery should hand out the addresses we can be reached at
+        let advertised = [("peer", &self.config.advertise_peer_addr),
+                          ("client", &self.config.advertise_cli_addr)];
+        for &(name, ref advertised) in advertised.iter() {
+            if let Some(ref advertised) = **advertised {
+                node_status.addresses
+                           .insert(name.to_string(), advertised.clone());
+            }
+        }
+        let status = Arc::new(RwLock::new(node_status));
+        tc.serve_status(status.clone(), self.config.expose_info);
+        tc.limit_frames(self.config.max_frame_bytes,
+                        self.config.max_stream_bytes);
+        tc.limit_in_flight(self.config.max_in_flight);
+        tc.tune_connections(&self.config);
+        tc.throttle_clients(self.config.max_client_conns,
+                            self.config.client_conns_per_sec,
+                            self.config.client_reqs_per_sec);
+        let live_config = Arc::new(RwLock::new(self.config.clone()));
+        tc.follow_config(live_config.clone());
+
+        // Each IO loop is a MIO EventLoop of its own.  The first has every
+        // socket but the client connections the others accept, which the
+        // kernel shares out between them all.
+        let mut cops = vec![tc];
+        for i in 1..io_loops {
+            let cop = try!(cops[0].client_loop(i,
+                                               self.listen_ip,
+                                               self.cli_port));
+            cops.push(cop);
+        }
+        let mut event_loops = vec![];
+        for _ in 0..io_loops {
+            event_loops.push(try!(EventLoop::new()));
+        }
+
+        // All RPC's are sent over the event loops' notification channels,
+        // each to the loop with the connection it's for.
+        let rpc_tx = IoLoops::new(event_loops.iter()
+                                             .map(|el| el.channel())
+                                             .collect());
+
+        let shutdown = ShutdownHandle {
+            requested: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(Mutex::new(None)),
+            rpc_tx: rpc_tx.clone(),
+            status: status.clone(),
+        };
+
+        // IO event loop threads
+        let loops = cops.into_iter().zip(event_loops).enumerate();
+        for (i, (mut tc, mut event_loop)) in loops {
+            // start periodic tasks
+            event_loop.timeout_ms((), thread_rng().gen_range(200, 500))
+                      .unwrap();
+            let tex1 = thread_exit_tx.clone();
+            let name = if i == 0 {
+                "IO loop".to_string()
+            } else {
+                format!("IO loop {}", i)
+            };
+            try!(thread::Builder::new()
+                     .name(name)
+                     .spawn(move || {
+                         match tc.run_event_loop(event_loop) {
+                             Ok(()) => (),
+                             Err(e) => error!("IO loop failed: {}", e),
+                         }
+                         let _ = tex1.send(());
+                     }));
+        }
+
+        let read_pool = if self.config.read_workers > 0 {
+            let read_db = db.clone();
+            let read_tx = rpc_tx.clone();
+            let read = move |job: ReadJob| {
+                let _ = read_tx.send(job.run(&read_db));
+            };
+            Some(try!(WorkerPool::new("read worker",
+                                      self.config.read_workers,
+                                      read)))
+        } else {
+            None
+        };
+        let backup_db = db.clone();
+        let backup_tx = rpc_tx.clone();
+        let back_up = move |job: BackupJob| {
+            let _ = backup_tx.send(job.run(&backup_db));
+        };
+        let backup_pool = try!(WorkerPool::new("backup worker", 1, back_up));
+        let export_db = db.clone();
+        let export_tx = rpc_tx.clone();
+        let export = move |job: ExportJob| {
+            job.run(&export_db, |env| {
+                let _ = export_tx.send(env);
+            });
+        };
+        let export_pool = try!(WorkerPool::new("export worker", 1, export));
+        let snapshot_tx = rpc_tx.clone();
+        let snapshot_ttl_ms = self.config.snapshot_ttl_ms as i64;
+        let snapshot_ttl = time::Duration::milliseconds(snapshot_ttl_ms);
+        let snapshot_worker = try!(SnapshotWorker::new(db.clone(),
+                                                       snapshot_ttl,
+                                                       move |env| {
+            let _ = snapshot_tx.send(env);
+        }));
+
+        let clock = self.clock.clone();
+        let mirror_db = db.clone();
+        let mirror_status = status.clone();
+        let expiry_status = status.clone();
+
+        // as resolved, since quorums are sized from these; a standalone
+        // node is the only replica there is
+        let peers = if self.config.standalone {
+            vec![addr::join(self.listen_ip, self.peer_port)]
+        } else {
+            peer_addrs.iter().map(|addr| addr.to_string()).collect()
+        };
+        let mut server = self.server(storage,
+                                     Box::new(rpc_tx),
+                                     peers,
+                                     status,
+                                     live_config.clone(),
+                                     read_pool,
+                                     Some(backup_pool),
+                                     Some(export_pool),
+                                     Some(snapshot_worker),
+                                     metrics.clone());
+        server.backchannel = Some(server_tx.backchannel());
+
+        // The server's state is owned by a single actor thread, which
+        // handles peer messages, client requests and cron ticks in the
+        // order the lanes of its mailbox give them up.  Once something's
+        // been learned, it keeps handling messages for up to
+        // group_commit_ms, waiting for more if none are there, so that a
+        // burst of writes is applied to the db with a single write.  A
+        // group of group_commit_max writes is written without waiting out
+        // the rest.  A full lane leaves the traffic cop's connections with
+        // a backlog, and the other threads waiting.
+        let tex2 = thread_exit_tx.clone();
+        let actor_clock = clock.clone();
+        try!(thread::Builder::new()
+                 .name("server".to_string())
+                 .spawn(move || {
+                     while let Some(msg) = server_rx.recv() {
+                         server.handle(msg);
+                         let started = actor_clock.now();
+                         let budget = time::Duration::milliseconds(
+                             server.config.group_commit_ms as i64);
+                         loop {
+                             let left = budget - (actor_clock.now() - started);
+                             if left <= time::Duration::zero() ||
+                                server.unapplied.len() >=
+                                server.config.group_commit_max {
+                                 break;
+                             }
+                             let msg = if server.unapplied.is_empty() {
+                                 server_rx.try_recv()
+                             } else {
+                                 server_rx.recv_timeout(left)
+                             };
+                             match msg {
+                                 Some(msg) => server.handle(msg),
+                                 None => break,
+                             }
+                         }
+                         server.flush();
+                     }
+                     server.shutdown();
+                     let _ = tex2.send(());
+                 }));
+
+        let mut threads = WORKER_THREADS + io_loops - 1;
+
+        // peer discovery thread, for seeds that may resolve differently
+        // as time goes on
+        if self.config.discovery_interval_ms > 0 &&
+           discovery::has_names(&self.peers) {
+            let seeds = self.peers.clone();
+            let interval_ms = self.config.discovery_interval_ms as i64;
+            let interval = time::Duration::milliseconds(interval_ms);
+            let discovery_tx = shutdown.rpc_tx.clone();
+            let discovery_shutdown = shutdown.clone();
+            let tex7 = thread_exit_tx.clone();
+            try!(thread::Builder::new()
+                     .name("peer discovery".to_string())
+                     .spawn(move || {
+                         discovery::watch(seeds,
+                                          peer_addrs,
+                                          interval,
+                                          discovery_tx,
+                                          discovery_shutdown);
+                         let _ = tex7.send(());
+                     }));
+            threads += 1;
+        }
+
+        // mirror thread, for a cluster following another
+        if let Some(ref source) = self.config.mirror_from {
+            let source_seeds: Vec<String> = source.split(",")
+                                                  .map(|s| s.to_string())
+                                                  .collect();
+            let source_addrs = discovery::resolve(&source_seeds);
+            if source_addrs.is_empty() {
+                return Err(invalid_input("none of the peers to mirror \
+                                          resolved"));
+            }
+            let token = self.config.mirror_token.clone();
+            // the other cluster holds its log back for us by this name
+            let consumer = format!("mirror {}", self.cluster_id);
+            let mirror_tx = server_tx.clone();
+            let mirror_shutdown = shutdown.clone();
+            let tex8 = thread_exit_tx.clone();
+            try!(thread::Builder::new()
+                     .name("mirror".to_string())
+                     .spawn(move || {
+                         mirror::follow(source_addrs,
+                                        token,
+                                        consumer,
+                                        mirror_db,
+                                        mirror_status,
+                                        mirror_tx,
+                                        mirror_shutdown);
+                         let _ = tex8.send(());
+                     }));
+            threads += 1;
+        }
+
+        // Redis protocol listener, which serves what it's asked through
+        // our client port
+        if let Some(port) = self.resp_port {
+            let addr = SocketAddr::new(self.listen_ip, port);
+            let listener = try!(net::TcpListener::bind(addr));
+            let cli_addr = addr::local(self.listen_ip, self.cli_port);
+            let max_conns = self.config.max_client_conns;
+            let resp_shutdown = shutdown.clone();
+            let tex9 = thread_exit_tx.clone();
+            try!(thread::Builder::new()
+                     .name("resp listener".to_string())
+                     .spawn(move || {
+                         if let Err(e) = resp::serve(listener,
+                                                     cli_addr,
+                                                     max_conns,
+                                                     resp_shutdown) {
+                             error!("resp listener failed: {}", e);
+                         }
+                         let _ = tex9.send(());
+                     }));
+            threads += 1;
+        }
+
+        // expiry sweeper, for the frontends that let keys expire
+        if self.resp_port.is_some() || self.memcached_port.is_some() {
+            let cli_addr = addr::local(self.listen_ip, self.cli_port);
+            let root_token = self.config.root_token.clone();
+            let expiry_shutdown = shutdown.clone();
+            let tex11 = thread_exit_tx.clone();
+            try!(thread::Builder::new()
+                     .name("expiry sweeper".to_string())
+                     .spawn(move || {
+                         expiry::sweep(cli_addr,
+                                       root_token,
+                                       expiry_status,
+                                       expiry_shutdown);
+                         let _ = tex11.send(());
+                     }));
+            threads += 1;
+        }
+
+        // REST listener, which like the Redis one goes through our client
+        // port
+        if let Some(port) = self.rest_port {
+            let addr = SocketAddr::new(self.listen_ip, port);
+            let listener = try!(net::TcpListener::bind(addr));
+            let cli_addr = addr::local(self.listen_ip, self.cli_port);
+            let max_conns = self.config.max_client_conns;
+            let rest_shutdown = shutdown.clone();
+            let tex10 = thread_exit_tx.clone();
+            try!(thread::Builder::new()
+                     .name("rest listener".to_string())
+                     .spawn(move || {
+                         if let Err(e) = rest::serve(listener,
+                                                     cli_addr,
+                                                     max_conns,
+                                                     rest_shutdown) {
+                             error!("rest listener failed: {}", e);
+                         }
+                         let _ = tex10.send(());
+                     }));
+            threads += 1;
+        }
+
+        // memcached listener, through our client port too
+        if let Some(port) = self.memcached_port {
+            let addr = SocketAddr::new(self.listen_ip, port);
+            let listener = try!(net::TcpListener::bind(addr));
+            let cli_addr = addr::local(self.listen_ip, self.cli_port);
+            let prefix = self.config.memcached_prefix.as_bytes().to_vec();
+            let max_conns = self.config.max_client_conns;
+            let memcached_shutdown = shutdown.clone();
+            let tex12 = thread_exit_tx.clone();
+            try!(thread::Builder::new()
+                     .name("memcached listener".to_string())
+                     .spawn(move || {
+                         if let Err(e) = memcached::serve(listener,
+                                                          cli_addr,
+                                                          prefix,
+                                                          max_conns,
+                                                          memcached_shutdown) {
+                             error!("memcached listener failed: {}", e);
+                         }
+                         let _ = tex12.send(());
+                     }));
+            threads += 1;
+        }
+
+        // cron thread
+        let tex5 = thread_exit_tx.clone();
+        let cron_shutdown = shutdown.clone();
+        let storage_dir = self.storage_dir;
+        try!(thread::Builder::new()
+                 .name("server cron".to_string())
+                 .spawn(move || {
+                     let mut rng = thread_rng();
+                     while !cron_shutdown.is_shutting_down() {
+                         let (min_ms, max_ms) = {
+                             let config = live_config.read().unwrap();
+                             (config.cron_min_ms, config.cron_max_ms)
+                         };
+                         // a reload may have left them the wrong way round
+                         let max_ms = cmp::max(max_ms, min_ms + 1);
+                         clock.sleep_ms(rng.gen_range(min_ms, max_ms));
+                         if let Some(config) = cron_shutdown.take_reload() {
+                             let reload = ServerMsg::Reload(config);
+                             if server_tx.send(reload).is_err() {
+                                 break;
+                             }
+                         }
+                         if cron_shutdown.is_draining() {
+                             if server_tx.send(ServerMsg::Drain).is_err() {
+                                 break;
+                             }
+                         }
+                         match disk::space(&storage_dir) {
+                             Ok(space) => {
+                                 let disk = ServerMsg::Disk(space);
+                                 if server_tx.send(disk).is_err() {
+                                     break;
+                                 }
+                             }
+                             Err(e) => {
+                                 debug!("can't tell how full {} is: {}",
+                                        storage_dir,
+                                        e)
+                             }
+                         }
+                         if server_tx.send(ServerMsg::Cron).is_err() {
+                             break;
+                         }
+                     }
+                     let _ = tex5.send(());
+                 }));
+
+        Ok(ServerHandle {
+            shutdown: shutdown,
+            threads: threads,
+            thread_exit_rx: thread_exit_rx,
+            metrics: metrics,
+        })
+    }
+
+    // Runs the server inside this process instead, with no ports open,
+    // as its own only peer, for applications and tests that want to use
+    // it as a library.  Returns once it leads.
+    pub fn embed(self) -> Result<Embedded, Error> {
+        let storage = try!(self.open_storage());
+        let mut node_status = NodeStatus::new(storage.id.clone());
+        node_status.cluster_id = self.cluster_id.clone();
+        node_status.storage_dir = self.storage_dir.clone();
+        let status = Arc::new(RwLock::new(node_status));
+        let live_config = Arc::new(RwLock::new(self.config.clone()));
+
+        // unbounded, since the server sends to itself from its own thread
+        let (server_tx, server_rx) = mpsc::channel();
+        let self_addr = embedded::self_addr(self.peer_port);
+        let loopback = Loopback::new(server_tx, self_addr);
+        let server = self.server(storage,
+                                 Box::new(loopback.clone()),
+                                 vec![self_addr.to_string()],
+                                 status,
+                                 live_config.clone(),
+                                 None,
+                                 None,
+                                 None,
+                                 None,
+                                 Arc::new(Metrics::new()));
+        embedded::start(server,
+                        &loopback,
+                        server_rx,
+                        live_config,
+                        self.storage_dir.clone())
+    }
+
+    // Opens our storage, restoring into it first if asked to, and works
+    // out from it who we are.
+    fn open_storage(&self) -> Result<Storage, Error> {
+        let db = try!(rocksdb::new_with_config(self.storage_dir.clone(),
+                                               &self.config));
+        if let Some((ref manifest, until_ms)) = self.restore {
+            let restored = try!(backup::restore(&db, manifest, until_ms));
+            info!("restored {} from backups up to txid {} taken at {}ms",
+                  self.storage_dir,
+                  restored.txid,
+                  restored.taken_ms);
+        }
+        let keys = try!(rocksdb::verify(&db));
+        info!("recovered {} keys from {}", keys, self.storage_dir);
+        let id = try!(rocksdb::load_or_create_peer_id(&db,
+                                                      &self.storage_dir,
+                                                      self.peer_port));
+        let promoted = try!(mirror::promoted(&db));
+        let mut stats = StorageStats::new();
+        stats.measure(&db);
+        let namespaces = try!(Namespaces::load(&db));
+        Ok(Storage {
+            db: Arc::new(db),
+            id: id,
+            promoted: promoted,
+            stats: stats,
+            namespaces: namespaces,
+        })
+    }
+
+    // The server, before its threads are started, sending what it has
+    // to say over rpc_tx.
+    fn server<RE>(&self,
+                  storage: Storage,
+                  rpc_tx: Box<SendChannel<Envelope, RE> + Send>,
+                  peers: Vec<String>,
+                  status: Arc<RwLock<NodeStatus>>,
+                  live_config: Arc<RwLock<ServerConfig>>,
+                  read_pool: Option<WorkerPool<ReadJob>>,
+                  backup_pool: Option<WorkerPool<BackupJob>>,
+                  export_pool: Option<WorkerPool<ExportJob>>,
+                  snapshot_worker: Option<SnapshotWorker>,
+                  metrics: Arc<Metrics>)
+                  -> Server<C, RE> {
+        // every node currently hosts the one and only range
+        metrics.ranges.set(1);
+        let clock = self.clock.clone();
+        let id = storage.id;
+        let rep_log = Box::new(InMemoryLog {
+            pending: BTreeMap::new(),
+            committed: BTreeMap::new(),
+            quorum: peers.len() / 2 + 1,
+            last_learned_txid: 0, // TODO(tyler) read from rocksdb
+            last_learned_term: 0, // TODO(tyler) read from rocksdb
+            last_accepted_txid: 0, // TODO(tyler) read from rocksdb
+            last_accepted_term: 0, // TODO(tyler) read from rocksdb
+            collected_before: 0,
+        });
+        let mut server = Server {
+            clock: clock.clone(),
+            hlc: HLC::new(clock.clone()),
+            peer_port: self.peer_port,
+            cli_port: self.cli_port,
+            membership: Membership::new(id.clone(),
+                                        self.peer_port,
+                                        self.config.phi_threshold,
+                                        clock.now()),
+            id: id,
+            rpc_tx: rpc_tx,
+            max_generated_txid: 0, // TODO(tyler) read from rocksdb
+            highest_term: 0, // TODO(tyler) read from rocksdb
+            state: State::Init,
+            db: storage.db,
+            read_pool: read_pool,
+            backup_pool: backup_pool,
+            export_pool: export_pool,
+            snapshot_worker: snapshot_worker,
+            rep_log: rep_log,
+            config: self.config.clone(),
+            live_config: live_config,
+            peers: peers,
+            rep_peers: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            traced: BTreeMap::new(),
+            unapplied: vec![],
+            ingest_dir: format!("{}/ingest", self.storage_dir),
+            wal: WalSyncer::new(self.config.durability.clone(),
+                                metrics.clone()),
+            metrics: metrics,
+            horizon: GcHorizon::new(),
+            retain_from: 0,
+            status: status,
+            transfer: None,
+            draining: false,
+            pre_vote: None,
+            sessions: BTreeMap::new(),
+            next_scrub: None,
+            scrub: None,
+            scrub_report: BTreeMap::new(),
+            repair: None,
+            clock_skewed: false,
+            disk_watermark: Watermark::Below,
+            stats: storage.stats,
+            namespaces: storage.namespaces,
+            faults: Faults::default(),
+            watches: vec![],
+            promoted: storage.promoted,
+            unchanged: BTreeSet::new(),
+            backchannel: None,
+            taking: 0,
+        };
+        server.membership.set_witness(server.config.witness);
+        server.membership.advertise(server.config.advertise_peer_addr.clone(),
+                                    server.config.advertise_cli_addr.clone());
+        server
+    }
+}
+
+// Can be cloned and handed to whatever decides when the server should
+// stop, while another thread blocks in ServerHandle::join.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    // passed on to the server by the cron thread, since holding a sender
+    // to its mailbox here would keep it from ever exiting
+    draining: Arc<AtomicBool>,
+    // a config to switch to, passed on the same way
+    reload: Arc<Mutex<Option<ServerConfig>>>,
+    rpc_tx: IoLoops,
+    status: Arc<RwLock<NodeStatus>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("shutting down server");
+        // Stopping the IO loop drops the request channels, which in turn
+        // lets the request handler threads drain and exit.
+        let _ = self.rpc_tx.send(Envelope {
+            address: None,
+            tok: SHUTDOWN,
+            msg: ByteBuf::none(),
+        });
+    }
+
+    // Takes the node out of service before shutting it down: stops
+    // accepting new clients, hands leadership to another node, and once
+    // we're no longer leading (or timeout has passed regardless) shuts
+    // down as usual.  Blocks until the shutdown has been requested.
+    pub fn drain(&self, timeout: time::Duration) {
+        info!("draining server");
+        let _ = self.rpc_tx.send(Envelope {
+            address: None,
+            tok: DRAIN,
+            msg: ByteBuf::none(),
+        });
+        self.draining.store(true, Ordering::SeqCst);
+
+        let deadline = time::get_time() + timeout;
+        while time::get_time() < deadline {
+            let drained = self.status
+                              .read()
+                              .map(|s| {
+                                  s.draining && s.role != "leader" &&
+                                  s.role != "candidate"
+                              })
+                              .unwrap_or(true);
+            if drained {
+                break;
+            }
+            thread::sleep_ms(50);
+        }
+        self.shutdown();
+    }
+
+    // Has the server switch to whatever settings in config can change
+    // while it runs, within a cron tick.
+    pub fn reload(&self, config: ServerConfig) {
+        info!("reloading server config");
+        *self.reload.lock().unwrap() = Some(config);
+    }
+
+    fn take_reload(&self) -> Option<ServerConfig> {
+        self.reload.lock().unwrap().take()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+pub struct ServerHandle {
+    shutdown: ShutdownHandle,
+    threads: usize,
+    thread_exit_rx: mpsc::Receiver<()>,
+    metrics: Arc<Metrics>,
+}
+
+impl ServerHandle {
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+
+    pub fn drain(&self, timeout: time::Duration) {
+        self.shutdown.drain(timeout);
+    }
+
+    pub fn reload(&self, config: ServerConfig) {
+        self.shutdown.reload(config);
+    }
+
+    // The running server's counters, as the admin listener serves them.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    // Blocks until every worker thread has exited.  Returns an error if
+    // any of them exited without a shutdown being requested, in which
+    // case the rest of the server is torn down as well.
+    pub fn join(self) -> Result<(), Error> {
+        for _ in 0..self.threads {
+            let _ = self.thread_exit_rx.recv();
+            if !self.shutdown.is_shutting_down() {
+                let msg = "A worker thread unexpectedly exited! Shutting \
+                           down.";
+                error!("{}", msg);
+                self.shutdown.shutdown();
+                return Err(Error::Io(io::Error::new(io::ErrorKind::Other,
+                                                    msg)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// An error for a server that can't be started as configured.
+fn invalid_input(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
@@ -0,0 +1,168 @@
+use rocksdb::{DB, DBResult};
+
+use {Mutation, StorageError};
+
+// CRC-32C (Castagnoli), the same checksum RocksDB uses for its blocks.
+const POLY: u32 = 0x82f63b78;
+
+lazy_static! {
+    static ref TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        for i in 0..256 {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ POLY
+                } else {
+                    crc >> 1
+                };
+            }
+            table[i] = crc;
+        }
+        table
+    };
+}
+
+// Start a running checksum from !0 and finish it by xoring with !0.
+pub fn update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, b| {
+        TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// Folds in a field along with its length, so that where one field ends
+// and the next begins is covered too.
+pub fn update_field(crc: u32, field: &[u8]) -> u32 {
+    update(update(crc, &u32_to_bytes(field.len() as u32)), field)
+}
+
+// Each stored value is followed by four bytes of checksum over the key and
+// value, so a value that was truncated, flipped, or written under the
+// wrong key doesn't pass.
+pub fn seal(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut sealed = value.to_vec();
+    sealed.extend(&u32_to_bytes(stored_crc(key, value)));
+    sealed
+}
+
+// How long a value is once sealed.
+pub fn sealed_len(value_len: usize) -> usize {
+    value_len + 4
+}
+
+pub fn unseal(key: &[u8], stored: &[u8]) -> Result<Vec<u8>, StorageError> {
+    if stored.len() < 4 {
+        return Err(corrupt(format!("value for {:?} is truncated", key)));
+    }
+    let (value, crc) = stored.split_at(stored.len() - 4);
+    if bytes_to_u32(crc) != stored_crc(key, value) {
+        return Err(corrupt(format!("value for {:?} fails its checksum", key)));
+    }
+    Ok(value.to_vec())
+}
+
+// Reads a value written with seal.
+pub fn get(db: &DB, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+    match db.get(key) {
+        DBResult::Some(v) => unseal(key, &v).map(Some),
+        DBResult::None => Ok(None),
+        DBResult::Error(e) => Err(StorageError::Operation(e)),
+    }
+}
+
+fn stored_crc(key: &[u8], value: &[u8]) -> u32 {
+    update(update_field(!0, key), value) ^ !0
+}
+
+// Covers everything about a replicated mutation except the checksum itself.
+pub fn mutation_crc(mutation: &Mutation) -> u32 {
+    let version = mutation.get_version();
+    let mut crc = update(!0, &[mutation.get_field_type() as u8]);
+    crc = update(crc, &u64_to_bytes(version.get_txid()));
+    crc = update(crc, &u64_to_bytes(version.get_term()));
+    for field in [mutation.get_key(),
+                  mutation.get_value(),
+                  mutation.get_old_value()].iter() {
+        crc = update_field(crc, field);
+    }
+    // an absent old value means something different to a CAS than an
+    // empty one
+    crc = update(crc, &[mutation.has_old_value() as u8]);
+    if mutation.has_source_txid() {
+        crc = update(crc, &u64_to_bytes(mutation.get_source_txid()));
+    }
+    if mutation.has_offset() {
+        crc = update(crc, &u64_to_bytes(mutation.get_offset()));
+    }
+    crc ^ !0
+}
+
+// Mutations from leaders that predate checksums carry none, and are
+// trusted as before.
+pub fn mutation_intact(mutation: &Mutation) -> bool {
+    !mutation.has_crc() || mutation.get_crc() == mutation_crc(mutation)
+}
+
+pub fn corrupt(reason: String) -> StorageError {
+    StorageError::Corrupt(reason)
+}
+
+// Integers are stored little-endian.
+pub fn u32_to_bytes(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+pub fn bytes_to_u32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+pub fn u64_to_bytes(n: u64) -> [u8; 8] {
+    let mut b = [0; 8];
+    for i in 0..8 {
+        b[i] = (n >> (8 * i)) as u8;
+    }
+    b
+}
+
+pub fn bytes_to_u64(b: &[u8]) -> u64 {
+    (0..8).fold(0, |n, i| n | (b[i] as u64) << (8 * i))
+}
+
+#[cfg(test)]
+mod tests {
+    use {Mutation, MutationType, StorageError, Version};
+
+    use super::{mutation_crc, mutation_intact, seal, unseal, update};
+
+    #[test]
+    fn test_checksums_catch_damage() {
+        // the check value from the CRC-32C spec
+        assert_eq!(update(!0, b"123456789") ^ !0, 0xe3069283);
+
+        let sealed = seal(b"k", b"value");
+        assert_eq!(unseal(b"k", &sealed).unwrap(), b"value".to_vec());
+        let mut flipped = sealed.clone();
+        flipped[0] ^= 1;
+        match unseal(b"k", &flipped) {
+            Err(StorageError::Corrupt(_)) => (),
+            r => panic!("flipped bit got through: {:?}", r),
+        }
+        assert!(unseal(b"other", &sealed).is_err());
+        assert!(unseal(b"k", &sealed[..2]).is_err());
+
+        let mut version = Version::new();
+        version.set_txid(7);
+        version.set_term(2);
+        let mut mutation = Mutation::new();
+        mutation.set_field_type(MutationType::KVSET);
+        mutation.set_version(version);
+        mutation.set_key(b"k".to_vec());
+        mutation.set_value(b"v".to_vec());
+        assert!(mutation_intact(&mutation));
+        let crc = mutation_crc(&mutation);
+        mutation.set_crc(crc);
+        assert!(mutation_intact(&mutation));
+        mutation.set_value(b"w".to_vec());
+        assert!(!mutation_intact(&mutation));
+    }
+}
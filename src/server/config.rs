@@ -0,0 +1,914 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use log::LogLevel;
+use rustc_serialize::json::{Json, ToJson};
+
+use logging;
+use server::toml::{self, Value};
+
+// How hard the server works to make learned writes survive a machine
+// crash, as opposed to just a process crash.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Durability {
+    // fsync the WAL before acknowledging each group of writes
+    Always,
+    // fsync the WAL at most this often (ms); a crash can lose writes
+    // acknowledged since the last sync
+    Interval(u32),
+    // leave it to the OS
+    Never,
+}
+
+// How RocksDB merges the files it flushes: level style keeps reads and
+// space in check, universal writes less, and FIFO drops the oldest files
+// once they pass a size, for data that's only ever appended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStyle {
+    Level,
+    Universal,
+    Fifo,
+}
+
+// What RocksDB compresses blocks with.  Whichever is chosen has to have
+// been built into the librocksdb we link against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Snappy,
+    Zlib,
+    Bz2,
+    Lz4,
+    Lz4hc,
+    Zstd,
+}
+
+impl FromStr for CompactionStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CompactionStyle, String> {
+        match s {
+            "level" => Ok(CompactionStyle::Level),
+            "universal" => Ok(CompactionStyle::Universal),
+            "fifo" => Ok(CompactionStyle::Fifo),
+            _ => {
+                Err(format!("{} isn't a compaction style; try level, universal \
+                             or fifo",
+                            s))
+            }
+        }
+    }
+}
+
+impl fmt::Display for CompactionStyle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompactionStyle::Level => write!(f, "level"),
+            CompactionStyle::Universal => write!(f, "universal"),
+            CompactionStyle::Fifo => write!(f, "fifo"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "snappy" => Ok(Compression::Snappy),
+            "zlib" => Ok(Compression::Zlib),
+            "bz2" => Ok(Compression::Bz2),
+            "lz4" => Ok(Compression::Lz4),
+            "lz4hc" => Ok(Compression::Lz4hc),
+            "zstd" => Ok(Compression::Zstd),
+            _ => {
+                Err(format!("{} isn't a compression type; try none, snappy, \
+                             zlib, bz2, lz4, lz4hc or zstd",
+                            s))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Compression::None => "none",
+            Compression::Snappy => "snappy",
+            Compression::Zlib => "zlib",
+            Compression::Bz2 => "bz2",
+            Compression::Lz4 => "lz4",
+            Compression::Lz4hc => "lz4hc",
+            Compression::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Tunables for a running server.  Everything here has a sane default, so
+// callers only need to override the fields they care about.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // bounds on the randomized delay between runs of the server cron,
+    // which drives elections and lease extension.
+    pub cron_min_ms: u32,
+    pub cron_max_ms: u32,
+    // threads serving client reads in parallel with the server thread;
+    // zero serves them inline.
+    pub read_workers: usize,
+    // max log entries per append, and how many appends may be in flight
+    // to a follower before we wait for it to ack.
+    pub append_batch_size: usize,
+    pub append_window: usize,
+    // how long a learned write may wait for others to be written out
+    // with it in one batch, and the most writes a batch holds before it's
+    // written without waiting any longer; zero writes after every message.
+    pub group_commit_ms: u32,
+    pub group_commit_max: usize,
+    pub durability: Durability,
+    // How RocksDB is set up when the db is opened: how much memory it
+    // caches uncompressed blocks in, and buffers writes in before
+    // flushing them (zero block cache turns it off), how it compacts and
+    // compresses, and how many flushes and compactions it runs at once,
+    // a quarter of them flushes.  The compression level is for zlib,
+    // lz4hc and zstd; None leaves it to the library.
+    pub block_cache_mb: usize,
+    pub write_buffer_mb: usize,
+    pub compaction_style: CompactionStyle,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub max_background_jobs: usize,
+    // learned log entries kept in memory for followers that fall behind,
+    // on top of anything protected through the server's GcHorizon.
+    pub log_retention: u64,
+    // how long the log holds on to what a named watch hasn't seen yet
+    // after its client goes away, so a consumer can resume where it left
+    // off; zero holds nothing once it's gone
+    pub watch_retention_ms: u64,
+    // how long a snapshot a client opened is held with nothing read from
+    // it before it's let go
+    pub snapshot_ttl_ms: u64,
+    // serve /info on the admin listener to anyone who asks, for service
+    // discovery integrations
+    pub expose_info: bool,
+    // phi at which gossip suspects a member has failed, and how long past
+    // its lease we keep following a leader that still looks alive
+    pub phi_threshold: f64,
+    pub election_grace_ms: u32,
+    // ask for a quorum of pre-votes before bumping our term to stand for
+    // election; turn off while rolling out to nodes that don't know them
+    pub pre_vote: bool,
+    // how far our clock may be from those of a quorum before we stop
+    // taking or extending leader leases timed by it; zero doesn't check
+    pub max_clock_offset_ms: u64,
+    // A witness votes and acknowledges appends to make up a quorum, but
+    // never writes learned mutations to its db and never leads.  It can
+    // only help elect a data replica that has everything it has accepted.
+    pub witness: bool,
+    // A learner asks the leader for the replicated log and serves reads
+    // from what it has learned, which may be stale.  It never votes, never
+    // leads, and its acks don't count towards the quorum; the voters should
+    // leave it out of their peers.
+    pub learner: bool,
+    // A standalone node is the only replica of its data.  It leads from
+    // the start without an election and learns its writes as soon as it
+    // has logged them, so it has no peers and needs none to be up.
+    pub standalone: bool,
+    // When set, every client session must first authenticate with this
+    // token or one stored under the credentials prefix, and only this one
+    // may touch the credentials or transfer leadership.  None leaves the
+    // client port open to anyone who can reach it.
+    pub root_token: Option<String>,
+    // host:port for peers and clients to dial us at, when that's not the
+    // address we listen on, as behind NAT or in a container.  Peers learn
+    // both through gossip, and redirect clients to our advertised client
+    // address while we lead.
+    pub advertise_peer_addr: Option<String>,
+    pub advertise_cli_addr: Option<String>,
+    // how often seed peers given by name are resolved again, so peers
+    // whose addresses change can still be found; zero only resolves them
+    // at startup.  Newly found addresses are connected to, but the quorum
+    // is sized by what the seeds resolved to at startup.
+    pub discovery_interval_ms: u32,
+    // how often the leader has its followers check their data against its
+    // own, in spans of scrub_span_keys keys; zero never scrubs.  A scrub
+    // walks the whole db on the server thread.
+    pub scrub_interval_ms: u32,
+    pub scrub_span_keys: usize,
+    // replace our spans that diverge from the leader's with its copy
+    pub scrub_repair: bool,
+    // the largest frame body we'll buffer from a connection, and the
+    // largest message we'll put back together from frames streamed at
+    // that size.  We stream what we send at the smaller of our limit and
+    // the other side's.
+    pub max_frame_bytes: usize,
+    pub max_stream_bytes: usize,
+    // the longest key and value a client may write, with anything longer
+    // refused; zero doesn't limit them.  A client's Chunker stores longer
+    // values under several keys.
+    pub max_key_bytes: usize,
+    pub max_value_bytes: usize,
+    // How much of the disk holding our storage directory may be used, as
+    // fractions of it.  Past the soft watermark we warn; past the hard one
+    // we refuse client writes other than deletes, but still serve reads and
+    // take appends from the leader.  Zero turns a watermark off.
+    pub disk_soft_watermark: f64,
+    pub disk_hard_watermark: f64,
+    // the most bytes of keys and values the node may hold, as
+    // StorageStats counts them, past which the leader takes no more
+    // writes but deletes; None doesn't limit it
+    pub max_bytes: Option<u64>,
+    // Bounds on the work waiting for the server thread: messages in each
+    // lane of its mailbox, and of those, requests of each kind read off
+    // sockets.  A connection that finds its lane full isn't read from
+    // again until there's room, and neither is a client connection with
+    // max_in_flight requests unanswered; zero doesn't limit those.
+    // Requests the server drops without a reply, like expired ones, count
+    // against a connection for as long as it stays open.
+    pub mailbox_capacity: usize,
+    pub request_queue_depth: usize,
+    pub max_in_flight: usize,
+    // Limits on client connections in total and new ones a second, and on
+    // requests a second from each client address.  Anything over them is
+    // answered THROTTLED.  Zero doesn't limit anything.
+    pub max_client_conns: usize,
+    pub client_conns_per_sec: f64,
+    pub client_reqs_per_sec: f64,
+    // How long a client connection may go with nothing read from it or
+    // written to it before we hang up, and how long any connection may
+    // leave what we've queued for it unread, which is how one whose other
+    // end has gone looks from here.  Zero doesn't limit either.  Sockets
+    // are probed after tcp_keepalive_secs of silence, so the kernel
+    // notices the ones that went quiet for good; zero doesn't probe.
+    pub client_idle_ms: u64,
+    pub conn_stall_ms: u64,
+    pub tcp_keepalive_secs: u32,
+    // After losing a peer we try it again after a jittered wait that grows
+    // from the first of these to the second while it stays unreachable.
+    pub peer_reconnect_min_ms: u64,
+    pub peer_reconnect_max_ms: u64,
+    // How many IO loops serve client connections, each on a thread of its
+    // own.  Past one they all listen on the client port, and the kernel
+    // shares new connections out between them; peer and admin connections
+    // stay on the first.
+    pub io_threads: usize,
+    // Once this many requests are waiting for the handler a client request
+    // needs, or its recent p99 latency reaches shed_p99_ms, NORMAL client
+    // requests are answered OVERLOADED rather than queued behind the rest.
+    // LOW ones are shed at half of either.  Zero never sheds.
+    pub shed_queue_depth: usize,
+    pub shed_p99_ms: u32,
+    // Once the leader has this many writes waiting on a quorum, or this
+    // many committed but not yet applied, new client writes are answered
+    // BUSY with a suggested delay of busy_retry_ms, longer the further
+    // past either it is, rather than queued.  Zero never pushes back.
+    pub busy_unlearned_txids: u64,
+    pub busy_unapplied_txids: u64,
+    pub busy_retry_ms: u32,
+    // client requests taking at least this long are logged along with
+    // where the time went; zero doesn't log any
+    pub slow_request_ms: u32,
+    // the most verbose messages logged; None leaves the logger as it was
+    // set up
+    pub log_level: Option<LogLevel>,
+    // text or json; None leaves it as it was set up
+    pub log_format: Option<logging::Format>,
+    // Comma-delimited peers of another cluster to mirror.  Our leader
+    // follows every change applied there and replicates it here, and we
+    // take no writes of our own until promoted.  The token is presented
+    // to that cluster, which should be root's so credentials come too.
+    pub mirror_from: Option<String>,
+    pub mirror_token: Option<String>,
+    // Items set through the memcached port are kept under this prefix,
+    // apart from everything else.
+    pub memcached_prefix: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            cron_min_ms: 400,
+            cron_max_ms: 500,
+            read_workers: 4,
+            append_batch_size: 100,
+            append_window: 4,
+            group_commit_ms: 2,
+            group_commit_max: 1000,
+            durability: Durability::Always,
+            block_cache_mb: 512,
+            write_buffer_mb: 64,
+            compaction_style: CompactionStyle::Level,
+            compression: Compression::Snappy,
+            compression_level: None,
+            max_background_jobs: 4,
+            log_retention: 10000,
+            watch_retention_ms: 600000,
+            snapshot_ttl_ms: 60000,
+            expose_info: true,
+            phi_threshold: 8.0,
+            election_grace_ms: 2000,
+            pre_vote: true,
+            max_clock_offset_ms: 500,
+            witness: false,
+            learner: false,
+            standalone: false,
+            root_token: None,
+            advertise_peer_addr: None,
+            advertise_cli_addr: None,
+            discovery_interval_ms: 30000,
+            scrub_interval_ms: 3600000,
+            scrub_span_keys: 1000,
+            scrub_repair: false,
+            max_frame_bytes: 16 << 20,
+            max_stream_bytes: 1 << 30,
+            max_key_bytes: 10 << 10,
+            max_value_bytes: 1 << 20,
+            disk_soft_watermark: 0.85,
+            disk_hard_watermark: 0.95,
+            max_bytes: None,
+            mailbox_capacity: 4096,
+            request_queue_depth: 1024,
+            max_in_flight: 128,
+            max_client_conns: 0,
+            client_conns_per_sec: 0.0,
+            client_reqs_per_sec: 0.0,
+            client_idle_ms: 0,
+            conn_stall_ms: 30000,
+            tcp_keepalive_secs: 60,
+            peer_reconnect_min_ms: 200,
+            peer_reconnect_max_ms: 5000,
+            io_threads: 1,
+            shed_queue_depth: 2048,
+            shed_p99_ms: 2000,
+            busy_unlearned_txids: 10000,
+            busy_unapplied_txids: 10000,
+            busy_retry_ms: 100,
+            slow_request_ms: 1000,
+            log_level: None,
+            log_format: None,
+            mirror_from: None,
+            mirror_token: None,
+            memcached_prefix: "memcached/".to_string(),
+        }
+    }
+}
+
+// Everything a config file can set: where the node listens and keeps its
+// data, who its peers are, and under [server], any of the ServerConfig
+// tunables by field name.  Whatever a file leaves out keeps its default.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFile {
+    pub listen_ip: Option<IpAddr>,
+    pub peer_port: Option<u16>,
+    pub cli_port: Option<u16>,
+    pub admin_port: Option<u16>,
+    pub resp_port: Option<u16>,
+    pub rest_port: Option<u16>,
+    pub memcached_port: Option<u16>,
+    pub cluster_id: Option<String>,
+    pub storage_dir: Option<String>,
+    pub seed_peers: Option<Vec<String>>,
+    pub logfile: Option<String>,
+    pub server: ServerConfig,
+}
+
+impl ConfigFile {
+    pub fn load(path: &str) -> Result<ConfigFile, String> {
+        let mut s = String::new();
+        try!(File::open(path)
+                 .and_then(|mut f| f.read_to_string(&mut s))
+                 .map_err(|e| format!("could not read {}: {}", path, e)));
+        ConfigFile::parse(&s).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(s: &str) -> Result<ConfigFile, String> {
+        let mut file = ConfigFile::default();
+        for (key, value) in try!(toml::parse(s)) {
+            let set = match &*key {
+                "listen_ip" => {
+                    string(&value).and_then(|ip| {
+                        ip.parse()
+                          .map(|ip| file.listen_ip = Some(ip))
+                          .map_err(|_| format!("{} isn't an IP address", ip))
+                    })
+                }
+                "peer_port" => port(&value).map(|p| file.peer_port = Some(p)),
+                "cli_port" => port(&value).map(|p| file.cli_port = Some(p)),
+                "admin_port" => port(&value).map(|p| file.admin_port = Some(p)),
+                "resp_port" => port(&value).map(|p| file.resp_port = Some(p)),
+                "rest_port" => port(&value).map(|p| file.rest_port = Some(p)),
+                "memcached_port" => {
+                    port(&value).map(|p| file.memcached_port = Some(p))
+                }
+                "cluster_id" => {
+                    string(&value).map(|s| file.cluster_id = Some(s))
+                }
+                "storage_dir" => {
+                    string(&value).map(|s| file.storage_dir = Some(s))
+                }
+                "logfile" => string(&value).map(|s| file.logfile = Some(s)),
+                "seed_peers" => {
+                    match value {
+                        Value::Array(ref peers) => {
+                            peers.iter()
+                                 .map(string)
+                                 .collect::<Result<Vec<String>, String>>()
+                                 .map(|peers| file.seed_peers = Some(peers))
+                        }
+                        _ => {
+                            Err(format!("should be an array, not {}",
+                                        value.type_name()))
+                        }
+                    }
+                }
+                _ if key.starts_with("server.") => {
+                    file.server.set(&key["server.".len()..], &value)
+                }
+                _ => Err("isn't a setting".to_string()),
+            };
+            try!(set.map_err(|e| format!("{}: {}", key, e)));
+        }
+        Ok(file)
+    }
+}
+
+impl ServerConfig {
+    // A copy with the settings in s changed, where s is TOML like the
+    // [server] table of a config file, with or without its header.
+    pub fn update(&self, s: &str) -> Result<ServerConfig, String> {
+        let mut config = self.clone();
+        for (key, value) in try!(toml::parse(s)) {
+            let field = if key.starts_with("server.") {
+                &key["server.".len()..]
+            } else {
+                &*key
+            };
+            try!(config.set(field, &value)
+                       .map_err(|e| format!("{}: {}", key, e)));
+        }
+        Ok(config)
+    }
+
+    // Takes the settings from new that a running server can change,
+    // returning the names of those that changed, and of those that differ
+    // but won't change until the server is restarted.
+    pub fn reload(&mut self,
+                  new: &ServerConfig)
+                  -> (Vec<&'static str>, Vec<&'static str>) {
+        let mut changed = vec![];
+        let mut fixed = vec![];
+        macro_rules! live {
+            ($($field:ident),*) => {$(
+                if self.$field != new.$field {
+                    self.$field = new.$field.clone();
+                    changed.push(stringify!($field));
+                }
+            )*}
+        }
+        macro_rules! fixed {
+            ($($field:ident),*) => {$(
+                if self.$field != new.$field {
+                    fixed.push(stringify!($field));
+                }
+            )*}
+        }
+        live!(cron_min_ms,
+              cron_max_ms,
+              append_batch_size,
+              append_window,
+              group_commit_ms,
+              group_commit_max,
+              log_retention,
+              watch_retention_ms,
+              expose_info,
+              phi_threshold,
+              election_grace_ms,
+              pre_vote,
+              max_clock_offset_ms,
+              scrub_interval_ms,
+              scrub_span_keys,
+              scrub_repair,
+              max_key_bytes,
+              max_value_bytes,
+              disk_soft_watermark,
+              disk_hard_watermark,
+              max_bytes,
+              max_client_conns,
+              client_conns_per_sec,
+              client_reqs_per_sec,
+              client_idle_ms,
+              conn_stall_ms,
+              tcp_keepalive_secs,
+              peer_reconnect_min_ms,
+              peer_reconnect_max_ms,
+              shed_queue_depth,
+              shed_p99_ms,
+              busy_unlearned_txids,
+              busy_unapplied_txids,
+              busy_retry_ms,
+              slow_request_ms,
+              log_level,
+              log_format);
+        // these size threads, queues and buffers as the server starts, or
+        // change what the node is
+        fixed!(read_workers,
+               io_threads,
+               snapshot_ttl_ms,
+               durability,
+               block_cache_mb,
+               write_buffer_mb,
+               compaction_style,
+               compression,
+               compression_level,
+               max_background_jobs,
+               witness,
+               learner,
+               standalone,
+               root_token,
+               advertise_peer_addr,
+               advertise_cli_addr,
+               discovery_interval_ms,
+               max_frame_bytes,
+               max_stream_bytes,
+               mailbox_capacity,
+               request_queue_depth,
+               max_in_flight,
+               mirror_from,
+               mirror_token,
+               memcached_prefix);
+        (changed, fixed)
+    }
+
+    // Sets the field named by key, as a config file would.
+    fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        match key {
+            "cron_min_ms" => int(value).map(|v| self.cron_min_ms = v),
+            "cron_max_ms" => int(value).map(|v| self.cron_max_ms = v),
+            "read_workers" => int(value).map(|v| self.read_workers = v),
+            "append_batch_size" => {
+                int(value).map(|v| self.append_batch_size = v)
+            }
+            "append_window" => int(value).map(|v| self.append_window = v),
+            "group_commit_ms" => int(value).map(|v| self.group_commit_ms = v),
+            "group_commit_max" => int(value).map(|v| self.group_commit_max = v),
+            // "always", "never", or how often to sync in ms
+            "durability" => {
+                match *value {
+                    Value::Str(ref s) if s == "always" => {
+                        Ok(Durability::Always)
+                    }
+                    Value::Str(ref s) if s == "never" => Ok(Durability::Never),
+                    Value::Int(_) => int(value).map(Durability::Interval),
+                    _ => {
+                        Err("should be \"always\", \"never\" or a sync \
+                             interval in ms"
+                                .to_string())
+                    }
+                }
+                .map(|v| self.durability = v)
+            }
+            "block_cache_mb" => int(value).map(|v| self.block_cache_mb = v),
+            "write_buffer_mb" => int(value).map(|v| self.write_buffer_mb = v),
+            "compaction_style" => {
+                string(value).and_then(|v| {
+                    v.parse().map(|style| self.compaction_style = style)
+                })
+            }
+            "compression" => {
+                string(value)
+                    .and_then(|v| v.parse().map(|c| self.compression = c))
+            }
+            "compression_level" => {
+                int(value).map(|v| self.compression_level = Some(v))
+            }
+            "max_background_jobs" => {
+                int(value).map(|v| self.max_background_jobs = v)
+            }
+            "log_retention" => int(value).map(|v| self.log_retention = v),
+            "watch_retention_ms" => {
+                int(value).map(|v| self.watch_retention_ms = v)
+            }
+            "snapshot_ttl_ms" => int(value).map(|v| self.snapshot_ttl_ms = v),
+            "expose_info" => boolean(value).map(|v| self.expose_info = v),
+            "phi_threshold" => float(value).map(|v| self.phi_threshold = v),
+            "election_grace_ms" => {
+                int(value).map(|v| self.election_grace_ms = v)
+            }
+            "pre_vote" => boolean(value).map(|v| self.pre_vote = v),
+            "max_clock_offset_ms" => {
+                int(value).map(|v| self.max_clock_offset_ms = v)
+            }
+            "witness" => boolean(value).map(|v| self.witness = v),
+            "learner" => boolean(value).map(|v| self.learner = v),
+            "standalone" => boolean(value).map(|v| self.standalone = v),
+            "root_token" => string(value).map(|v| self.root_token = Some(v)),
+            "advertise_peer_addr" => {
+                string(value).map(|v| self.advertise_peer_addr = Some(v))
+            }
+            "advertise_cli_addr" => {
+                string(value).map(|v| self.advertise_cli_addr = Some(v))
+            }
+            "discovery_interval_ms" => {
+                int(value).map(|v| self.discovery_interval_ms = v)
+            }
+            "scrub_interval_ms" => {
+                int(value).map(|v| self.scrub_interval_ms = v)
+            }
+            "scrub_span_keys" => int(value).map(|v| self.scrub_span_keys = v),
+            "scrub_repair" => boolean(value).map(|v| self.scrub_repair = v),
+            "max_frame_bytes" => int(value).map(|v| self.max_frame_bytes = v),
+            "max_stream_bytes" => int(value).map(|v| self.max_stream_bytes = v),
+            "max_key_bytes" => int(value).map(|v| self.max_key_bytes = v),
+            "max_value_bytes" => int(value).map(|v| self.max_value_bytes = v),
+            "disk_soft_watermark" => {
+                float(value).map(|v| self.disk_soft_watermark = v)
+            }
+            "disk_hard_watermark" => {
+                float(value).map(|v| self.disk_hard_watermark = v)
+            }
+            "max_bytes" => int(value).map(|v| self.max_bytes = Some(v)),
+            "mailbox_capacity" => int(value).map(|v| self.mailbox_capacity = v),
+            "request_queue_depth" => {
+                int(value).map(|v| self.request_queue_depth = v)
+            }
+            "max_in_flight" => int(value).map(|v| self.max_in_flight = v),
+            "max_client_conns" => int(value).map(|v| self.max_client_conns = v),
+            "client_conns_per_sec" => {
+                float(value).map(|v| self.client_conns_per_sec = v)
+            }
+            "client_reqs_per_sec" => {
+                float(value).map(|v| self.client_reqs_per_sec = v)
+            }
+            "client_idle_ms" => int(value).map(|v| self.client_idle_ms = v),
+            "conn_stall_ms" => int(value).map(|v| self.conn_stall_ms = v),
+            "tcp_keepalive_secs" => {
+                int(value).map(|v| self.tcp_keepalive_secs = v)
+            }
+            "peer_reconnect_min_ms" => {
+                int(value).map(|v| self.peer_reconnect_min_ms = v)
+            }
+            "peer_reconnect_max_ms" => {
+                int(value).map(|v| self.peer_reconnect_max_ms = v)
+            }
+            "io_threads" => int(value).map(|v| self.io_threads = v),
+            "shed_queue_depth" => int(value).map(|v| self.shed_queue_depth = v),
+            "shed_p99_ms" => int(value).map(|v| self.shed_p99_ms = v),
+            "busy_unlearned_txids" => {
+                int(value).map(|v| self.busy_unlearned_txids = v)
+            }
+            "busy_unapplied_txids" => {
+                int(value).map(|v| self.busy_unapplied_txids = v)
+            }
+            "busy_retry_ms" => int(value).map(|v| self.busy_retry_ms = v),
+            "slow_request_ms" => int(value).map(|v| self.slow_request_ms = v),
+            "log_level" => {
+                string(value).and_then(|v| {
+                    v.parse()
+                     .map(|level| self.log_level = Some(level))
+                     .map_err(|_| format!("{} isn't a log level", v))
+                })
+            }
+            "log_format" => {
+                string(value).and_then(|v| {
+                    v.parse().map(|format| self.log_format = Some(format))
+                })
+            }
+            "mirror_from" => string(value).map(|v| self.mirror_from = Some(v)),
+            "mirror_token" => {
+                string(value).map(|v| self.mirror_token = Some(v))
+            }
+            "memcached_prefix" => {
+                string(value).map(|v| self.memcached_prefix = v)
+            }
+            _ => Err("isn't a setting".to_string()),
+        }
+    }
+}
+
+// What the admin listener reports.  The root and mirror tokens are only
+// said to be set, not given away.
+impl ToJson for ServerConfig {
+    fn to_json(&self) -> Json {
+        let mut o = BTreeMap::new();
+        o.insert("cron_min_ms".to_string(), self.cron_min_ms.to_json());
+        o.insert("cron_max_ms".to_string(), self.cron_max_ms.to_json());
+        o.insert("read_workers".to_string(), self.read_workers.to_json());
+        o.insert("append_batch_size".to_string(),
+                 self.append_batch_size.to_json());
+        o.insert("append_window".to_string(), self.append_window.to_json());
+        o.insert("group_commit_ms".to_string(), self.group_commit_ms.to_json());
+        o.insert("group_commit_max".to_string(),
+                 self.group_commit_max.to_json());
+        o.insert("durability".to_string(),
+                 match self.durability {
+                     Durability::Always => "always".to_json(),
+                     Durability::Interval(ms) => ms.to_json(),
+                     Durability::Never => "never".to_json(),
+                 });
+        o.insert("block_cache_mb".to_string(), self.block_cache_mb.to_json());
+        o.insert("write_buffer_mb".to_string(), self.write_buffer_mb.to_json());
+        o.insert("compaction_style".to_string(),
+                 self.compaction_style.to_string().to_json());
+        o.insert("compression".to_string(),
+                 self.compression.to_string().to_json());
+        o.insert("compression_level".to_string(),
+                 self.compression_level.to_json());
+        o.insert("max_background_jobs".to_string(),
+                 self.max_background_jobs.to_json());
+        o.insert("log_retention".to_string(), self.log_retention.to_json());
+        o.insert("watch_retention_ms".to_string(),
+                 self.watch_retention_ms.to_json());
+        o.insert("snapshot_ttl_ms".to_string(), self.snapshot_ttl_ms.to_json());
+        o.insert("expose_info".to_string(), self.expose_info.to_json());
+        o.insert("phi_threshold".to_string(), self.phi_threshold.to_json());
+        o.insert("election_grace_ms".to_string(),
+                 self.election_grace_ms.to_json());
+        o.insert("pre_vote".to_string(), self.pre_vote.to_json());
+        o.insert("max_clock_offset_ms".to_string(),
+                 self.max_clock_offset_ms.to_json());
+        o.insert("witness".to_string(), self.witness.to_json());
+        o.insert("learner".to_string(), self.learner.to_json());
+        o.insert("standalone".to_string(), self.standalone.to_json());
+        o.insert("root_token".to_string(), self.root_token.is_some().to_json());
+        o.insert("advertise_peer_addr".to_string(),
+                 self.advertise_peer_addr.to_json());
+        o.insert("advertise_cli_addr".to_string(),
+                 self.advertise_cli_addr.to_json());
+        o.insert("discovery_interval_ms".to_string(),
+                 self.discovery_interval_ms.to_json());
+        o.insert("scrub_interval_ms".to_string(),
+                 self.scrub_interval_ms.to_json());
+        o.insert("scrub_span_keys".to_string(), self.scrub_span_keys.to_json());
+        o.insert("scrub_repair".to_string(), self.scrub_repair.to_json());
+        o.insert("max_frame_bytes".to_string(), self.max_frame_bytes.to_json());
+        o.insert("max_stream_bytes".to_string(),
+                 self.max_stream_bytes.to_json());
+        o.insert("max_key_bytes".to_string(), self.max_key_bytes.to_json());
+        o.insert("max_value_bytes".to_string(), self.max_value_bytes.to_json());
+        o.insert("disk_soft_watermark".to_string(),
+                 self.disk_soft_watermark.to_json());
+        o.insert("disk_hard_watermark".to_string(),
+                 self.disk_hard_watermark.to_json());
+        o.insert("max_bytes".to_string(), self.max_bytes.to_json());
+        o.insert("mailbox_capacity".to_string(),
+                 self.mailbox_capacity.to_json());
+        o.insert("request_queue_depth".to_string(),
+                 self.request_queue_depth.to_json());
+        o.insert("max_in_flight".to_string(), self.max_in_flight.to_json());
+        o.insert("max_client_conns".to_string(),
+                 self.max_client_conns.to_json());
+        o.insert("client_conns_per_sec".to_string(),
+                 self.client_conns_per_sec.to_json());
+        o.insert("client_reqs_per_sec".to_string(),
+                 self.client_reqs_per_sec.to_json());
+        o.insert("client_idle_ms".to_string(), self.client_idle_ms.to_json());
+        o.insert("conn_stall_ms".to_string(), self.conn_stall_ms.to_json());
+        o.insert("tcp_keepalive_secs".to_string(),
+                 self.tcp_keepalive_secs.to_json());
+        o.insert("peer_reconnect_min_ms".to_string(),
+                 self.peer_reconnect_min_ms.to_json());
+        o.insert("peer_reconnect_max_ms".to_string(),
+                 self.peer_reconnect_max_ms.to_json());
+        o.insert("io_threads".to_string(), self.io_threads.to_json());
+        o.insert("shed_queue_depth".to_string(),
+                 self.shed_queue_depth.to_json());
+        o.insert("shed_p99_ms".to_string(), self.shed_p99_ms.to_json());
+        o.insert("busy_unlearned_txids".to_string(),
+                 self.busy_unlearned_txids.to_json());
+        o.insert("busy_unapplied_txids".to_string(),
+                 self.busy_unapplied_txids.to_json());
+        o.insert("busy_retry_ms".to_string(), self.busy_retry_ms.to_json());
+        o.insert("slow_request_ms".to_string(), self.slow_request_ms.to_json());
+        o.insert("log_level".to_string(),
+                 self.log_level
+                     .map(|level| level.to_string().to_lowercase())
+                     .to_json());
+        o.insert("log_format".to_string(),
+                 self.log_format.map(|format| format.to_string()).to_json());
+        o.insert("mirror_from".to_string(), self.mirror_from.to_json());
+        o.insert("mirror_token".to_string(),
+                 self.mirror_token.is_some().to_json());
+        o.insert("memcached_prefix".to_string(),
+                 self.memcached_prefix.to_json());
+        Json::Object(o)
+    }
+}
+
+fn string(value: &Value) -> Result<String, String> {
+    match *value {
+        Value::Str(ref s) => Ok(s.clone()),
+        _ => Err(format!("should be a string, not {}", value.type_name())),
+    }
+}
+
+fn boolean(value: &Value) -> Result<bool, String> {
+    match *value {
+        Value::Bool(b) => Ok(b),
+        _ => Err(format!("should be a boolean, not {}", value.type_name())),
+    }
+}
+
+fn float(value: &Value) -> Result<f64, String> {
+    match *value {
+        Value::Float(f) => Ok(f),
+        Value::Int(i) => Ok(i as f64),
+        _ => Err(format!("should be a number, not {}", value.type_name())),
+    }
+}
+
+// Any unsigned integer field, refusing values that don't fit it.
+fn int<T: FromStr>(value: &Value) -> Result<T, String> {
+    match *value {
+        Value::Int(i) if i >= 0 => {
+            i.to_string().parse().map_err(|_| format!("{} is too large", i))
+        }
+        Value::Int(i) => Err(format!("{} can't be negative", i)),
+        _ => Err(format!("should be an integer, not {}", value.type_name())),
+    }
+}
+
+fn port(value: &Value) -> Result<u16, String> {
+    int(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevel;
+
+    use logging;
+    use super::{CompactionStyle, Compression, ConfigFile, Durability};
+
+    #[test]
+    fn test_config_file() {
+        let file = ConfigFile::parse("listen_ip = \"fd00::1\"\n\
+                                      peer_port = 7771\n\
+                                      seed_peers = [\"a.rasputin:7771\", \
+                                                    \"b.rasputin:7771\"]\n\
+                                      [server]\n\
+                                      read_workers = 8\n\
+                                      durability = 100\n\
+                                      compaction_style = \"universal\"\n\
+                                      compression = \"none\"\n\
+                                      phi_threshold = 10\n\
+                                      root_token = \"hunter2\"\n")
+                       .unwrap();
+        assert_eq!(file.listen_ip, Some("fd00::1".parse().unwrap()));
+        assert_eq!(file.peer_port, Some(7771));
+        assert_eq!(file.cli_port, None);
+        assert_eq!(file.seed_peers.unwrap().len(), 2);
+        assert_eq!(file.server.read_workers, 8);
+        assert_eq!(file.server.durability, Durability::Interval(100));
+        assert_eq!(file.server.compaction_style, CompactionStyle::Universal);
+        assert_eq!(file.server.compression, Compression::None);
+        assert_eq!(file.server.phi_threshold, 10.0);
+        assert_eq!(file.server.root_token, Some("hunter2".to_string()));
+        // and the rest keep their defaults
+        assert_eq!(file.server.append_window, 4);
+
+        assert!(ConfigFile::parse("peer_port = 70000")
+                    .unwrap_err()
+                    .contains("peer_port"));
+        assert!(ConfigFile::parse("[server]\nread_wrokers = 8")
+                    .unwrap_err()
+                    .contains("server.read_wrokers"));
+        assert!(ConfigFile::parse("[server]\npre_vote = 1").is_err());
+        assert!(ConfigFile::parse("[server]\ncron_min_ms = -1").is_err());
+        assert!(ConfigFile::parse("[server]\ncompression = \"lzma\"").is_err());
+        let zstd = ConfigFile::parse("[server]\n\
+                                      compression = \"zstd\"\n\
+                                      compression_level = 9\n")
+                       .unwrap();
+        assert_eq!(zstd.server.compression, Compression::Zstd);
+        assert_eq!(zstd.server.compression_level, Some(9));
+        assert_eq!(file.server.compression_level, None);
+
+        let quota = ConfigFile::parse("[server]\nmax_bytes = 1073741824")
+                        .unwrap();
+        assert_eq!(quota.server.max_bytes, Some(1 << 30));
+        assert_eq!(file.server.max_bytes, None);
+
+        let mut config = file.server.clone();
+        let new = config.update("phi_threshold = 12.5\n\
+                                 log_level = \"debug\"\n\
+                                 log_format = \"json\"\n\
+                                 [server]\n\
+                                 read_workers = 2")
+                        .unwrap();
+        assert_eq!(new.read_workers, 2);
+        assert!(config.update("nope = 1").is_err());
+        let (changed, fixed) = config.reload(&new);
+        assert_eq!(changed, ["phi_threshold", "log_level", "log_format"]);
+        assert_eq!(fixed, ["read_workers"]);
+        assert_eq!(config.phi_threshold, 12.5);
+        assert_eq!(config.log_level, Some(LogLevel::Debug));
+        assert_eq!(config.log_format, Some(logging::Format::Json));
+        assert!(config.update("log_format = \"xml\"").is_err());
+        // what needs a restart is left as it was
+        assert_eq!(config.read_workers, 8);
+    }
+}
@@ -1,13 +1,18 @@
 use std::io::{Error, ErrorKind};
 use std::io;
-use std::sync::mpsc::Sender;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use mio;
 use mio::{EventLoop, EventSet, PollOpt, Token};
 use mio::tcp::{TcpListener, TcpStream};
 use mio::util::Slab;
+use time;
 
-use server::Envelope;
+use codec::Framed;
+use server::lanes::Inbox;
+use server::throttle::Throttle;
 use server::server_conn::ServerConn;
 use server::traffic_cop::TrafficCop;
 
@@ -15,7 +20,21 @@ pub struct ConnSet {
     pub srv_sock: TcpListener,
     pub srv_token: Token,
     pub conns: Slab<ServerConn>,
-    pub req_tx: Sender<Envelope>,
+    pub req_tx: Inbox,
+    // connections with requests waiting for room in req_tx
+    pub backlogged: Vec<Token>,
+    pub max_in_flight: usize,
+    pub max_frame: usize,
+    pub max_stream: usize,
+    // whether refusals are answered with a CliRes
+    pub client_facing: bool,
+    // these are shared by the client connections of every IO loop, so
+    // limits on them hold for the node as a whole
+    pub throttle: Option<Arc<Mutex<Throttle>>>,
+    pub open: Arc<AtomicUsize>,
+    // seconds of silence before the sockets of connections made from now
+    // on are probed, if they are
+    pub keepalive: Option<u32>,
 }
 
 impl ConnSet {
@@ -26,7 +45,25 @@ impl ConnSet {
         debug!("ConnSet accepting socket");
 
         let sock = try!(self.srv_sock.accept());
-        self.register(sock.unwrap(), event_loop).map(|_| ())
+        let open = self.open.load(Ordering::SeqCst);
+        let refusal = match self.throttle {
+            Some(ref throttle) => {
+                let now = time::get_time();
+                throttle.lock().unwrap().admit_conn(open, now).err()
+            }
+            None => None,
+        };
+        let tok = try!(self.register(sock.unwrap(), event_loop));
+        if let Some(reason) = refusal {
+            info!("refusing client connection: {}", reason);
+            let conn = &mut self.conns[tok];
+            conn.refuse(reason);
+            event_loop.reregister(&conn.sock,
+                                  tok,
+                                  conn.interest,
+                                  PollOpt::edge() | PollOpt::oneshot());
+        }
+        Ok(())
     }
 
     pub fn register(&mut self,
@@ -34,7 +71,16 @@ impl ConnSet {
                     event_loop: &mut EventLoop<TrafficCop>)
                     -> io::Result<Token> {
 
-        let conn = ServerConn::new(sock, self.req_tx.clone());
+        if let Err(e) = sock.set_keepalive(self.keepalive) {
+            debug!("can't set keepalive on a new connection: {}", e);
+        }
+        let framed = Framed::with_limits(self.max_frame, self.max_stream);
+        let mut conn = ServerConn::new(sock,
+                                       self.req_tx.clone(),
+                                       framed,
+                                       self.client_facing);
+        conn.max_in_flight = self.max_in_flight;
+        conn.greet();
 
         // Re-register accepting socket
         event_loop.reregister(&self.srv_sock,
@@ -46,10 +92,11 @@ impl ConnSet {
             .insert(conn)
             .map(|tok| {
             // Register the connection
+                self.open.fetch_add(1, Ordering::SeqCst);
                 self.conns[tok].token = Some(tok);
                 event_loop.register_opt(&self.conns[tok].sock,
                                         tok,
-                                        EventSet::readable(),
+                                        self.conns[tok].interest,
                                         PollOpt::edge() | PollOpt::oneshot())
                           .ok()
                           .expect("could not register socket with event loop");
@@ -71,7 +118,47 @@ impl ConnSet {
             return Ok(());
         }
 
-        self.conn(tok).readable(event_loop)
+        let mut throttle = self.throttle
+                               .as_ref()
+                               .map(|throttle| throttle.lock().unwrap());
+        let res = self.conns[tok].readable(event_loop,
+                                           throttle.as_mut().map(|t| &mut **t));
+        drop(throttle);
+        if self.conns[tok].backlog.len() > 0 &&
+           !self.backlogged.contains(&tok) {
+            self.backlogged.push(tok);
+        }
+        res
+    }
+
+    // Forgets a connection, returning whether we had it.
+    pub fn remove(&mut self, tok: Token) -> bool {
+        if self.conns.remove(tok).is_none() {
+            return false;
+        }
+        self.open.fetch_sub(1, Ordering::SeqCst);
+        true
+    }
+
+    // Gives connections that filled the queue another go at it, and lets
+    // them be read again once it takes everything they had waiting.
+    pub fn retry_backlogged(&mut self, event_loop: &mut EventLoop<TrafficCop>) {
+        for tok in mem::replace(&mut self.backlogged, vec![]) {
+            if !self.conns.contains(tok) {
+                continue;
+            }
+            let conn = &mut self.conns[tok];
+            conn.drain_backlog();
+            if !conn.backlog.is_empty() {
+                self.backlogged.push(tok);
+            }
+            if conn.update_interest() {
+                event_loop.reregister(&conn.sock,
+                                      tok,
+                                      conn.interest,
+                                      PollOpt::edge() | PollOpt::oneshot());
+            }
+        }
     }
 
     pub fn conn_writable(&mut self,
@@ -0,0 +1,103 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use bytes::ByteBuf;
+use time;
+
+use server::{ADD_PEER, Envelope, ShutdownHandle};
+use server::addr;
+use server::io_loops::IoLoops;
+
+// Finds the peers behind the seeds, each either an ip:port or a
+// host:port for DNS to resolve.  A name can stand for several peers, as a
+// headless service record does.  Seeds that don't resolve are logged and
+// left out, so one bad name doesn't keep us from reaching the rest.
+pub fn resolve(seeds: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = vec![];
+    for seed in seeds {
+        let resolved = match addr::parse_addr(seed) {
+            Ok(addr) => vec![addr],
+            Err(_) => {
+                match (&**seed).to_socket_addrs() {
+                    Ok(resolved) => resolved.map(addr::unmap).collect(),
+                    Err(e) => {
+                        warn!("could not resolve seed peer {}: {}", seed, e);
+                        continue;
+                    }
+                }
+            }
+        };
+        for addr in resolved {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+// Whether any of the seeds is a name, and so may resolve differently later.
+pub fn has_names(seeds: &[String]) -> bool {
+    seeds.iter().any(|seed| addr::parse_addr(seed).is_err())
+}
+
+// Resolves the seeds again every interval, until the server shuts down,
+// telling the traffic cop about any addresses it hasn't been told of.
+// Addresses that disappear are kept, since a peer that's merely down
+// still needs to be reconnected to once it's back.
+pub fn watch(seeds: Vec<String>,
+             mut known: Vec<SocketAddr>,
+             interval: time::Duration,
+             rpc_tx: IoLoops,
+             shutdown: ShutdownHandle) {
+    // short naps, so we notice a shutdown promptly
+    let nap = Duration::from_millis(200);
+    loop {
+        let mut slept = time::Duration::zero();
+        while slept < interval {
+            if shutdown.is_shutting_down() {
+                return;
+            }
+            thread::sleep(nap);
+            slept = slept + time::Duration::milliseconds(200);
+        }
+        for addr in resolve(&seeds) {
+            if known.contains(&addr) {
+                continue;
+            }
+            info!("discovered peer {}", addr);
+            let add = Envelope {
+                address: Some(addr),
+                tok: ADD_PEER,
+                msg: ByteBuf::none(),
+            };
+            if rpc_tx.send(add).is_err() {
+                return;
+            }
+            known.push(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_names, resolve};
+
+    #[test]
+    fn test_resolve() {
+        let seeds = vec!["10.0.0.1:7770".to_string(),
+                         "[fd00::1]:7770".to_string(),
+                         "10.0.0.1:7770".to_string(),
+                         "localhost:7771".to_string(),
+                         "no-such-host.invalid:7770".to_string()];
+        let addrs = resolve(&seeds);
+        assert_eq!(addrs[0].to_string(), "10.0.0.1:7770");
+        assert_eq!(addrs[1].to_string(), "[fd00::1]:7770");
+        // localhost resolved to something, and the bad name to nothing
+        assert!(addrs.len() >= 3);
+        assert!(addrs[2..].iter().all(|addr| addr.port() == 7771));
+        assert!(has_names(&seeds));
+        assert!(!has_names(&seeds[..3]));
+    }
+}
@@ -0,0 +1,110 @@
+use std::ffi::CString;
+use std::io;
+
+use libc::{c_char, c_int};
+
+// How full the filesystem holding our storage directory is.  The cron
+// thread measures it and hands it to the server thread, which refuses
+// client writes past the hard watermark rather than let the db run out of
+// space partway through one; reads, and appends from the leader, are still
+// served, so the node can catch up once space is freed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiskSpace {
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Watermark {
+    Below,
+    Soft,
+    Hard,
+}
+
+impl DiskSpace {
+    pub fn used(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.free_bytes as f64 / self.total_bytes as f64
+    }
+
+    // Which watermark, given as fractions of the disk used, we're past.
+    // A watermark of zero is never reached.
+    pub fn watermark(&self, soft: f64, hard: f64) -> Watermark {
+        let used = self.used();
+        if hard > 0.0 && used >= hard {
+            Watermark::Hard
+        } else if soft > 0.0 && used >= soft {
+            Watermark::Soft
+        } else {
+            Watermark::Below
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct StatVfs {
+    f_bsize: u64,
+    f_frsize: u64,
+    f_blocks: u64,
+    f_bfree: u64,
+    f_bavail: u64,
+    f_files: u64,
+    f_ffree: u64,
+    f_favail: u64,
+    f_fsid: u64,
+    f_flag: u64,
+    f_namemax: u64,
+    f_spare: [i32; 6],
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn statvfs(path: *const c_char, buf: *mut StatVfs) -> c_int;
+}
+
+// The space on the filesystem holding path, counting as free only what
+// we could write to.
+#[cfg(target_os = "linux")]
+pub fn space(path: &str) -> io::Result<DiskSpace> {
+    let path = try!(CString::new(path).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }));
+    let mut buf: StatVfs = unsafe { ::std::mem::zeroed() };
+    if unsafe { statvfs(path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(DiskSpace {
+        free_bytes: buf.f_bavail * buf.f_frsize,
+        total_bytes: buf.f_blocks * buf.f_frsize,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn space(_: &str) -> io::Result<DiskSpace> {
+    Err(io::Error::new(io::ErrorKind::Other,
+                       "can't measure free space on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiskSpace, Watermark, space};
+
+    #[test]
+    fn test_watermarks() {
+        let disk = DiskSpace {
+            free_bytes: 10,
+            total_bytes: 100,
+        };
+        assert_eq!(disk.watermark(0.85, 0.95), Watermark::Soft);
+        assert_eq!(disk.watermark(0.85, 0.9), Watermark::Hard);
+        assert_eq!(disk.watermark(0.95, 0.0), Watermark::Below);
+        assert_eq!(disk.watermark(0.0, 0.0), Watermark::Below);
+        assert!(Watermark::Hard > Watermark::Soft);
+
+        let here = space(".").unwrap();
+        assert!(here.total_bytes > 0 && here.free_bytes <= here.total_bytes);
+    }
+}
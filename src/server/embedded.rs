@@ -0,0 +1,293 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
+use std::thread;
+
+use bytes::{Buf, ByteBuf};
+use mio::Token;
+use protobuf::{self, Message};
+use rand::{Rng, thread_rng};
+use time;
+
+use {CASReq, CASRes, CliReq, CliRes, ClientError, Clock, ConsensusError,
+     DelReq, DelRes, DeleteRangeReq, DeleteRangeRes, Error, GetReq, GetRes,
+     ProtocolError, SetReq, SetRes};
+use client::refusal;
+use clock::timespec_to_ms;
+use server::{Envelope, LEADER_DURATION, NodeStatus, PEER_BROADCAST,
+             SendChannel, Server, ServerConfig, ServerMsg};
+use server::disk;
+
+// Requests made through an Embedded are given tokens from here up, clear
+// of the one the server knows itself by as a peer.
+const FIRST_REQUEST: usize = 1 << 20;
+const SELF: Token = Token(1);
+
+type Waiting = Arc<Mutex<BTreeMap<usize, Sender<ByteBuf>>>>;
+
+// Where the server is told its only peer, itself, is.
+pub fn self_addr(peer_port: u16) -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), peer_port))
+}
+
+// Stands in for the traffic cop.  What the server sends to its peers goes
+// back into its own mailbox, since it's the only one, and replies to
+// requests go to whoever is waiting on them.  Anything meant for the
+// traffic cop itself is dropped.
+#[derive(Clone)]
+pub struct Loopback {
+    server_tx: Sender<ServerMsg>,
+    self_addr: SocketAddr,
+    waiting: Waiting,
+}
+
+impl Loopback {
+    pub fn new(server_tx: Sender<ServerMsg>,
+               self_addr: SocketAddr)
+               -> Loopback {
+        Loopback {
+            server_tx: server_tx,
+            self_addr: self_addr,
+            waiting: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+}
+
+impl SendChannel<Envelope, ()> for Loopback {
+    fn send_msg(&self, env: Envelope) {
+        if env.tok == PEER_BROADCAST || env.tok == SELF {
+            let _ = self.server_tx.send(ServerMsg::Peer(Envelope {
+                address: Some(self.self_addr),
+                tok: SELF,
+                msg: env.msg,
+            }));
+            return;
+        }
+        let waiting = self.waiting.lock().unwrap().remove(&env.tok.as_usize());
+        match waiting {
+            // nobody may be waiting any more
+            Some(tx) => drop(tx.send(env.msg)),
+            None => {
+                debug!("dropping embedded server message for {:?}", env.tok)
+            }
+        }
+    }
+}
+
+// A single node run inside the process that uses it, without listening
+// on any port.  Requests are handed straight to the server's actor thread,
+// and the server is its own only peer, so it serves everything a cluster
+// would, from a storage dir of its own.  Built with ServerBuilder::embed.
+// Dropping it stops the server.
+pub struct Embedded {
+    server_tx: Sender<ServerMsg>,
+    waiting: Waiting,
+    next_tok: AtomicUsize,
+    status: Arc<RwLock<NodeStatus>>,
+    stopping: Arc<AtomicBool>,
+    threads: Vec<thread::JoinHandle<()>>,
+    timeout: time::Duration,
+}
+
+// Starts the server's actor and cron threads, and waits for it to elect
+// itself.
+pub fn start<C>(mut server: Server<C, ()>,
+                loopback: &Loopback,
+                server_rx: Receiver<ServerMsg>,
+                live_config: Arc<RwLock<ServerConfig>>,
+                storage_dir: String)
+                -> Result<Embedded, Error>
+    where C: Clock + Send + Sync + 'static
+{
+    let stopping = Arc::new(AtomicBool::new(false));
+    let status = server.status.clone();
+    let clock = server.clock.clone();
+
+    let actor_stopping = stopping.clone();
+    let actor = try!(thread::Builder::new()
+                         .name("embedded server".to_string())
+                         .spawn(move || {
+                             // the loopback keeps the mailbox open, so
+                             // we're told when to stop instead
+                             while let Ok(msg) = server_rx.recv() {
+                                 if actor_stopping.load(Ordering::SeqCst) {
+                                     break;
+                                 }
+                                 server.handle(msg);
+                                 while let Ok(msg) = server_rx.try_recv() {
+                                     server.handle(msg);
+                                 }
+                                 server.flush();
+                             }
+                             server.shutdown();
+                         }));
+
+    let cron_tx = loopback.server_tx.clone();
+    let cron_stopping = stopping.clone();
+    let run_cron = move || {
+        let mut rng = thread_rng();
+        while !cron_stopping.load(Ordering::SeqCst) {
+            let (min_ms, max_ms) = {
+                let config = live_config.read().unwrap();
+                (config.cron_min_ms, config.cron_max_ms)
+            };
+            clock.sleep_ms(rng.gen_range(min_ms, cmp::max(max_ms, min_ms + 1)));
+            match disk::space(&storage_dir) {
+                Ok(space) => drop(cron_tx.send(ServerMsg::Disk(space))),
+                Err(e) => {
+                    debug!("can't tell how full {} is: {}", storage_dir, e)
+                }
+            }
+            if cron_tx.send(ServerMsg::Cron).is_err() {
+                break;
+            }
+        }
+    };
+    let cron = try!(thread::Builder::new()
+                        .name("embedded server cron".to_string())
+                        .spawn(run_cron));
+
+    let embedded = Embedded {
+        server_tx: loopback.server_tx.clone(),
+        waiting: loopback.waiting.clone(),
+        next_tok: AtomicUsize::new(FIRST_REQUEST),
+        status: status,
+        stopping: stopping,
+        threads: vec![actor, cron],
+        timeout: time::Duration::seconds(15),
+    };
+    // it has to wait out any lease it granted itself before restarting
+    let deadline = time::get_time() + *LEADER_DURATION * 2;
+    while embedded.status().role != "leader" {
+        if time::get_time() >= deadline {
+            return Err(Error::Consensus(ConsensusError::NoLeader));
+        }
+        thread::sleep_ms(10);
+    }
+    Ok(embedded)
+}
+
+impl Embedded {
+    // How long a request may take.
+    pub fn set_timeout(&mut self, timeout: time::Duration) {
+        self.timeout = timeout;
+    }
+
+    // What the server last published about itself.
+    pub fn status(&self) -> NodeStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    // Hands req to the server, which serves it as it would one that came
+    // over the network.  Error replies come back as the ClientError they
+    // stand for.
+    pub fn call(&self, mut req: CliReq) -> Result<CliRes, ClientError> {
+        let tok = self.next_tok.fetch_add(1, Ordering::SeqCst);
+        let deadline = time::get_time() + self.timeout;
+        req.set_req_id(tok as u64);
+        req.set_deadline_ms(timespec_to_ms(deadline));
+
+        let (tx, rx) = channel();
+        self.waiting.lock().unwrap().insert(tok, tx);
+        let env = Envelope {
+            address: Some(self_addr(0)),
+            tok: Token(tok),
+            msg: ByteBuf::from_slice(&*req.write_to_bytes().unwrap()),
+        };
+        if self.server_tx.send(ServerMsg::Cli(env, time::get_time())).is_err() {
+            self.waiting.lock().unwrap().remove(&tok);
+            return Err(ClientError::Unreachable);
+        }
+        let res = loop {
+            match rx.try_recv() {
+                Ok(res) => break res,
+                Err(TryRecvError::Empty) if time::get_time() < deadline => {
+                    thread::sleep_ms(1)
+                }
+                Err(TryRecvError::Empty) => {
+                    self.waiting.lock().unwrap().remove(&tok);
+                    return Err(ClientError::DeadlineExceeded);
+                }
+                Err(TryRecvError::Disconnected) => {
+                    return Err(ClientError::Unreachable)
+                }
+            }
+        };
+        let cli_res: CliRes = try!(protobuf::parse_from_bytes(res.bytes())
+                                       .map_err(ProtocolError::from));
+        if cli_res.has_error() {
+            return Err(refusal(cli_res.get_error()));
+        }
+        // we're the only node, so we've stopped leading
+        if cli_res.has_redirect() {
+            return Err(ClientError::from(ConsensusError::NoLeader));
+        }
+        Ok(cli_res)
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<SetRes, ClientError> {
+        let mut set = SetReq::new();
+        set.set_key(key.to_vec());
+        set.set_value(value.to_vec());
+        let mut req = CliReq::new();
+        req.set_set(set);
+        self.call(req).map(|mut cli_res| cli_res.take_set())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<GetRes, ClientError> {
+        let mut get = GetReq::new();
+        get.set_key(key.to_vec());
+        let mut req = CliReq::new();
+        req.set_get(get);
+        self.call(req).map(|mut cli_res| cli_res.take_get())
+    }
+
+    pub fn cas(&self,
+               key: &[u8],
+               old_value: &[u8],
+               new_value: &[u8])
+               -> Result<CASRes, ClientError> {
+        let mut cas = CASReq::new();
+        cas.set_key(key.to_vec());
+        cas.set_old_value(old_value.to_vec());
+        cas.set_new_value(new_value.to_vec());
+        let mut req = CliReq::new();
+        req.set_cas(cas);
+        self.call(req).map(|mut cli_res| cli_res.take_cas())
+    }
+
+    pub fn del(&self, key: &[u8]) -> Result<DelRes, ClientError> {
+        let mut del = DelReq::new();
+        del.set_key(key.to_vec());
+        let mut req = CliReq::new();
+        req.set_del(del);
+        self.call(req).map(|mut cli_res| cli_res.take_del())
+    }
+
+    // Deletes every key from start up to but not including end.
+    pub fn delete_range(&self,
+                        start: &[u8],
+                        end: &[u8])
+                        -> Result<DeleteRangeRes, ClientError> {
+        let mut delete_range = DeleteRangeReq::new();
+        delete_range.set_start(start.to_vec());
+        delete_range.set_end(end.to_vec());
+        let mut req = CliReq::new();
+        req.set_delete_range(delete_range);
+        self.call(req).map(|mut cli_res| cli_res.take_delete_range())
+    }
+}
+
+impl Drop for Embedded {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // to wake the actor up to see it
+        let _ = self.server_tx.send(ServerMsg::Cron);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
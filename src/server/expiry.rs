@@ -0,0 +1,162 @@
+use std::net::SocketAddr;
+use std::str;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use time;
+
+use ClientError;
+use client::{Client, key_prefix_end};
+use clock::timespec_to_ms;
+use server::{NodeStatus, ShutdownHandle};
+use server::frontend::POLL_MS;
+
+// Deadlines the frontends set on keys, in ms since the epoch, are kept
+// under here, by the key they're for.
+pub const PREFIX: &'static [u8] = b"\x00expiry/";
+
+// how often expired keys are looked for
+const SWEEP_MS: i64 = 1000;
+
+pub fn now_ms() -> u64 {
+    timespec_to_ms(time::get_time())
+}
+
+fn deadline_key(key: &[u8]) -> Vec<u8> {
+    let mut deadline_key = PREFIX.to_vec();
+    deadline_key.extend_from_slice(key);
+    deadline_key
+}
+
+fn parse_ms(ms: &[u8]) -> Option<u64> {
+    str::from_utf8(ms).ok().and_then(|ms| ms.parse().ok())
+}
+
+// key's value, or None if it has none.
+pub fn lookup(cli: &mut Client,
+              key: &[u8])
+              -> Result<Option<Vec<u8>>, ClientError> {
+    let mut res = try!(cli.get(key));
+    if !res.get_success() {
+        return Ok(None);
+    }
+    Ok(Some(res.take_value()))
+}
+
+pub fn put(cli: &mut Client,
+           key: &[u8],
+           value: &[u8])
+           -> Result<(), ClientError> {
+    let res = try!(cli.set(key, value));
+    if !res.get_success() {
+        return Err(ClientError::Rejected(res.get_err().to_string()));
+    }
+    Ok(())
+}
+
+pub fn remove(cli: &mut Client, key: &[u8]) -> Result<(), ClientError> {
+    let res = try!(cli.del(key));
+    if !res.get_success() {
+        return Err(ClientError::Rejected(res.get_err().to_string()));
+    }
+    Ok(())
+}
+
+// When key expires, if it's been given a deadline.
+pub fn deadline(cli: &mut Client,
+                key: &[u8])
+                -> Result<Option<u64>, ClientError> {
+    let deadline = try!(lookup(cli, &deadline_key(key)));
+    Ok(deadline.and_then(|deadline| parse_ms(&deadline)))
+}
+
+pub fn set_deadline(cli: &mut Client,
+                    key: &[u8],
+                    at_ms: u64)
+                    -> Result<(), ClientError> {
+    put(cli, &deadline_key(key), at_ms.to_string().as_bytes())
+}
+
+// Leaves key to live forever.  Anything setting a key without a deadline
+// clears it first, so the sweeper won't take the new value for the old.
+pub fn clear_deadline(cli: &mut Client, key: &[u8]) -> Result<(), ClientError> {
+    remove(cli, &deadline_key(key))
+}
+
+// Deletes key along with its deadline.
+pub fn forget(cli: &mut Client, key: &[u8]) -> Result<(), ClientError> {
+    try!(remove(cli, key));
+    clear_deadline(cli, key)
+}
+
+// key's value and deadline, unless it has no value or has expired, in
+// which case it's deleted now rather than left for the sweeper.
+pub fn entry(cli: &mut Client,
+             key: &[u8])
+             -> Result<Option<(Vec<u8>, Option<u64>)>, ClientError> {
+    let value = match try!(lookup(cli, key)) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match try!(deadline(cli, key)) {
+        Some(deadline) if deadline <= now_ms() => {
+            try!(forget(cli, key));
+            Ok(None)
+        }
+        deadline => Ok(Some((value, deadline))),
+    }
+}
+
+// Deletes the keys whose deadlines have passed every so often, while we
+// lead; the other nodes leave it to whichever one does.  A key set again
+// between our reading its deadline and deleting it would be lost, so each
+// deadline is looked up again just before, which narrows the window
+// without closing it.  The root token, if there is one, lets keys be
+// swept whatever tokens they were set with.
+pub fn sweep(cli_addr: SocketAddr,
+             root_token: Option<String>,
+             status: Arc<RwLock<NodeStatus>>,
+             shutdown: ShutdownHandle) {
+    let mut cli = Client::new(vec![cli_addr], 1);
+    if let Some(ref token) = root_token {
+        cli.set_token(token);
+    }
+    let mut last = time::get_time();
+    while !shutdown.is_shutting_down() {
+        thread::sleep(Duration::from_millis(POLL_MS));
+        if time::get_time() < last + time::Duration::milliseconds(SWEEP_MS) ||
+           status.read().unwrap().role != "leader" {
+            continue;
+        }
+        last = time::get_time();
+        match sweep_expired(&mut cli) {
+            Ok(0) => (),
+            Ok(swept) => debug!("swept {} expired keys", swept),
+            Err(e) => debug!("could not sweep expired keys: {}", e),
+        }
+    }
+}
+
+fn sweep_expired(cli: &mut Client) -> Result<usize, ClientError> {
+    let end = key_prefix_end(PREFIX).unwrap();
+    let now = now_ms();
+    let mut expired = vec![];
+    let mut exporter = try!(cli.export(PREFIX, Some(&end)));
+    while let Some(batch) = try!(exporter.next()) {
+        for record in batch {
+            let deadline = parse_ms(record.get_value());
+            if deadline.map_or(false, |deadline| deadline <= now) {
+                expired.push(record.get_key()[PREFIX.len()..].to_vec());
+            }
+        }
+    }
+    let mut swept = 0;
+    for key in expired {
+        if try!(deadline(cli, &key)).map_or(false, |deadline| deadline <= now) {
+            try!(forget(cli, &key));
+            swept += 1;
+        }
+    }
+    Ok(swept)
+}
@@ -0,0 +1,291 @@
+use std::cmp;
+
+use bytes::ByteBuf;
+use protobuf::{self, Message, RepeatedField};
+use rocksdb::{DB, Direction};
+use rocksdb::rocksdb::Snapshot;
+
+use {CliRes, ExportCursor, ExportReq, ExportRes, KeyValue};
+use server::{Envelope, TXID};
+use server::lanes::Taken;
+use server::checksum;
+use server::ingest;
+use server::mirror;
+
+// the most keys sent in one reply to an export
+pub const EXPORT_BATCH: usize = 1000;
+
+// Turns an export going on from a cursor into one starting where it says,
+// from the snapshot it was reading, if any.  Without one, each page is as
+// of whatever the node has applied when it's asked for.
+pub fn resume(export: &mut ExportReq) -> Result<(), String> {
+    let cursor: ExportCursor =
+        try!(protobuf::parse_from_bytes(export.get_cursor())
+                 .map_err(|e| format!("bad export cursor: {}", e)));
+    export.set_start(cursor.get_next().to_vec());
+    if cursor.has_snapshot() {
+        export.set_snapshot(cursor.get_snapshot());
+    }
+    export.clear_cursor();
+    Ok(())
+}
+
+// An export waiting for the export worker.  Like a backup, it's exactly as
+// of txid: the server waits for the worker to have its snapshot of the db
+// before applying anything more.  One naming a snapshot a client opened
+// goes to the snapshot worker instead, and is as of that.
+pub struct ExportJob {
+    pub env: Envelope,
+    pub req_id: u64,
+    pub export: ExportReq,
+    pub txid: TXID,
+    pub taken: Taken,
+}
+
+impl ExportJob {
+    pub fn run<F>(mut self, db: &DB, send: F)
+        where F: FnMut(Envelope)
+    {
+        let snapshot = db.snapshot();
+        self.taken.done();
+        self.scan(&snapshot, send);
+    }
+
+    // Scans the snapshot, handing each batch to send as it fills so that
+    // no more than a batch is held at once.  Filtering is done here, so
+    // that only what's asked for crosses the network.
+    pub fn scan<F>(&self, snapshot: &Snapshot, mut send: F)
+        where F: FnMut(Envelope)
+    {
+        let export = &self.export;
+        let prefix = export.get_key_prefix();
+        let mut iter = snapshot.iterator();
+        // the keys with the prefix start at it
+        let scan = if export.has_start() || !prefix.is_empty() {
+            iter.from(cmp::max(export.get_start(), prefix), Direction::forward)
+        } else {
+            iter.from_start()
+        };
+        // a limit of none is no limit
+        let limit = if export.get_limit() > 0 {
+            Some(export.get_limit() as usize)
+        } else {
+            None
+        };
+        let mut cursor = None;
+        let mut batch = vec![];
+        let mut keys = 0;
+        for (k, v) in scan {
+            if export.has_end() && &*k >= export.get_end() {
+                break;
+            }
+            // and stop once past them
+            if !k.starts_with(prefix) {
+                break;
+            }
+            // bookkeeping of ours that means nothing anywhere else
+            if mirror::is_mirror_key(&k) || ingest::is_ingest_key(&k) {
+                continue;
+            }
+            if limit == Some(keys) {
+                // there's more, so the next page starts here
+                cursor = Some(self.cursor(&k));
+                break;
+            }
+            let value = match checksum::unseal(&k, &v) {
+                Ok(value) => value,
+                Err(e) => {
+                    error!("export stopped after {} keys: {}", keys, e);
+                    send(self.reply(false, batch, Some(e.to_string()), true));
+                    return;
+                }
+            };
+            if !value.starts_with(export.get_value_prefix()) {
+                continue;
+            }
+            let mut record = KeyValue::new();
+            record.set_key(k.to_vec());
+            record.set_value(if export.get_keys_only() {
+                vec![]
+            } else {
+                value
+            });
+            batch.push(record);
+            keys += 1;
+            if batch.len() == EXPORT_BATCH {
+                let full = ::std::mem::replace(&mut batch, vec![]);
+                send(self.reply(true, full, None, false));
+            }
+        }
+        info!("exported {} keys as of txid {}", keys, self.txid);
+        let mut export_res = self.res(true, batch, None, true);
+        if let Some(cursor) = cursor {
+            export_res.set_cursor(cursor);
+        }
+        send(self.envelope(export_res));
+    }
+
+    // Where to go on from next, as of the same snapshot if there's one.
+    fn cursor(&self, next: &[u8]) -> Vec<u8> {
+        let mut cursor = ExportCursor::new();
+        cursor.set_next(next.to_vec());
+        if self.export.has_snapshot() {
+            cursor.set_snapshot(self.export.get_snapshot());
+        }
+        cursor.set_txid(self.txid);
+        cursor.write_to_bytes().unwrap()
+    }
+
+    pub fn reply(&self,
+                 success: bool,
+                 records: Vec<KeyValue>,
+                 err: Option<String>,
+                 done: bool)
+                 -> Envelope {
+        self.envelope(self.res(success, records, err, done))
+    }
+
+    fn res(&self,
+           success: bool,
+           records: Vec<KeyValue>,
+           err: Option<String>,
+           done: bool)
+           -> ExportRes {
+        let mut export_res = ExportRes::new();
+        export_res.set_success(success);
+        export_res.set_records(RepeatedField::from_vec(records));
+        export_res.set_done(done);
+        export_res.set_txid(self.txid);
+        if let Some(err) = err {
+            export_res.set_err(err);
+        }
+        export_res
+    }
+
+    fn envelope(&self, export_res: ExportRes) -> Envelope {
+        let mut res = CliRes::new();
+        res.set_req_id(self.req_id);
+        res.set_export(export_res);
+        Envelope {
+            address: self.env.address,
+            tok: self.env.tok,
+            msg: ByteBuf::from_slice(&*res.write_to_bytes().unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bytes::{Buf, ByteBuf};
+    use mio::Token;
+    use protobuf;
+
+    use {CliRes, ExportCursor, ExportReq};
+    use server::Envelope;
+    use server::group_commit::GroupCommit;
+    use server::lanes::Taken;
+    use server::rocksdb::new;
+    use super::{EXPORT_BATCH, ExportJob, resume};
+
+    fn export(db: &::rocksdb::DB, export: ExportReq) -> Vec<CliRes> {
+        let job = ExportJob {
+            env: Envelope {
+                address: None,
+                tok: Token(3),
+                msg: ByteBuf::none(),
+            },
+            req_id: 7,
+            export: export,
+            txid: 12,
+            taken: Taken::none(),
+        };
+        let mut replies = vec![];
+        job.run(db, |env| {
+            replies.push(protobuf::parse_from_bytes(env.msg.bytes()).unwrap())
+        });
+        replies
+    }
+
+    #[test]
+    fn test_export_range_in_batches() {
+        let path = "_rasputin_test_export";
+        let db = new(path.to_string()).unwrap();
+        let mut group = GroupCommit::new();
+        for i in 0..EXPORT_BATCH + 5 {
+            group.put(format!("k{:05}", i).as_bytes(), b"v").unwrap();
+        }
+        group.put(b"\x00mirror/offset", b"x").unwrap();
+        group.commit(&db).unwrap();
+
+        let replies = export(&db, ExportReq::new());
+        assert_eq!(replies.len(), 2);
+        assert!(replies.iter().all(|res| res.get_req_id() == 7 &&
+                                         res.get_export().get_txid() == 12));
+        assert!(!replies[0].get_export().get_done());
+        assert!(replies[1].get_export().get_done());
+        assert_eq!(replies[1].get_export().get_records().len(), 5);
+        let first = &replies[0].get_export().get_records()[0];
+        assert_eq!((first.get_key(), first.get_value()),
+                   (&b"k00000"[..], &b"v"[..]));
+
+        let mut range = ExportReq::new();
+        range.set_start(b"k00010".to_vec());
+        range.set_end(b"k00013".to_vec());
+        let replies = export(&db, range);
+        assert_eq!(replies.len(), 1);
+        let keys: Vec<&[u8]> = replies[0]
+                                   .get_export()
+                                   .get_records()
+                                   .iter()
+                                   .map(|r| r.get_key())
+                                   .collect();
+        assert_eq!(keys, vec![&b"k00010"[..], b"k00011", b"k00012"]);
+
+        // only what matches is sent, starting from the prefix
+        let mut group = GroupCommit::new();
+        group.put(b"k00011", b"other").unwrap();
+        group.commit(&db).unwrap();
+        let mut filtered = ExportReq::new();
+        filtered.set_start(b"k".to_vec());
+        filtered.set_key_prefix(b"k0001".to_vec());
+        filtered.set_value_prefix(b"v".to_vec());
+        filtered.set_keys_only(true);
+        let replies = export(&db, filtered);
+        let records = replies[0].get_export().get_records();
+        assert_eq!(records.len(), 9);
+        assert_eq!(records[1].get_key(), b"k00012");
+        assert!(records.iter().all(|r| r.get_value().is_empty()));
+
+        // a page stops at its limit with where the next one starts
+        let mut page = ExportReq::new();
+        page.set_start(b"k00100".to_vec());
+        page.set_end(b"k00110".to_vec());
+        page.set_limit(4);
+        page.set_snapshot(9);
+        let replies = export(&db, page.clone());
+        assert_eq!(replies.len(), 1);
+        let res = replies[0].get_export();
+        assert_eq!(res.get_records().len(), 4);
+        let cursor: ExportCursor =
+            protobuf::parse_from_bytes(res.get_cursor()).unwrap();
+        assert_eq!(cursor.get_next(), &b"k00104"[..]);
+        assert_eq!((cursor.get_snapshot(), cursor.get_txid()), (9, 12));
+        page.clear_start();
+        page.clear_snapshot();
+        page.set_cursor(res.get_cursor().to_vec());
+        resume(&mut page).unwrap();
+        assert_eq!(page.get_start(), &b"k00104"[..]);
+        assert_eq!(page.get_snapshot(), 9);
+        assert!(!page.has_cursor());
+        page.clear_snapshot();
+        page.set_limit(6);
+        let replies = export(&db, page.clone());
+        assert_eq!(replies[0].get_export().get_records().len(), 6);
+        assert!(!replies[0].get_export().has_cursor());
+        page.set_cursor(b"\xff".to_vec());
+        assert!(resume(&mut page).is_err());
+        let _ = fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,24 @@
+use StorageError;
+
+// Failures a test can switch on in one server, so the paths that handle
+// them actually run.  Servers start without any, and only a harness that
+// builds them field by field can set one.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    // how many of the next writes to the db fail
+    pub failed_writes: usize,
+    // how much longer than it really does each WAL sync takes, as the
+    // server's clock sees it
+    pub sync_delay_ms: u32,
+}
+
+impl Faults {
+    // What the next db write should fail with, if it should.
+    pub fn write_error(&mut self) -> Option<StorageError> {
+        if self.failed_writes == 0 {
+            return None;
+        }
+        self.failed_writes -= 1;
+        Some(StorageError::Operation("injected write failure".to_string()))
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use server::ShutdownHandle;
+
+// A frontend speaks some other protocol on a port of its own, turning what
+// it's asked into requests made with a Client through our client port.
+// Going through the client port means everything else, from
+// authentication and throttling to following the leader, works just as it
+// does for our own clients.  Each connection is served on a thread of its
+// own, so a frontend suits the many small clients a cache protocol sees
+// less than our own client does.
+
+// How often the listener, and connections waiting on their clients, look
+// up to see whether the server is shutting down.
+pub const POLL_MS: u64 = 200;
+
+// A connection to a frontend, which reads as though its client hung up
+// once the server is shutting down.
+pub struct Conn {
+    pub stream: TcpStream,
+    shutdown: ShutdownHandle,
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.shutdown.is_shutting_down() {
+                return Ok(0);
+            }
+            match self.stream.read(buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                              e.kind() == ErrorKind::TimedOut => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Accepts connections until the server shuts down, handing each to handle
+// on a thread of its own.  Past max_conns at once, which zero doesn't
+// limit, connections are sent refusal and closed.
+pub fn serve<F>(name: &'static str,
+                listener: TcpListener,
+                max_conns: usize,
+                refusal: &'static [u8],
+                shutdown: ShutdownHandle,
+                handle: F)
+                -> io::Result<()>
+    where F: Fn(Conn) + Send + Sync + 'static
+{
+    try!(listener.set_nonblocking(true));
+    let handle = Arc::new(handle);
+    let open = Arc::new(AtomicUsize::new(0));
+    while !shutdown.is_shutting_down() {
+        let (mut stream, addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(POLL_MS));
+                continue;
+            }
+            Err(e) => {
+                warn!("failed to accept {} connection: {}", name, e);
+                thread::sleep(Duration::from_millis(POLL_MS));
+                continue;
+            }
+        };
+        if max_conns > 0 && open.load(Ordering::SeqCst) >= max_conns {
+            debug!("turning away {} connection from {}", name, addr);
+            let _ = stream.write_all(refusal);
+            continue;
+        }
+        // accepted sockets don't inherit the listener's nonblocking mode
+        // everywhere, so set both ways explicitly
+        try!(stream.set_nonblocking(false));
+        try!(stream.set_read_timeout(Some(Duration::from_millis(POLL_MS))));
+        try!(stream.set_nodelay(true));
+        let conn = Conn {
+            stream: stream,
+            shutdown: shutdown.clone(),
+        };
+        let (conn_handle, conn_open) = (handle.clone(), open.clone());
+        conn_open.fetch_add(1, Ordering::SeqCst);
+        let spawned = thread::Builder::new()
+                          .name(format!("{} connection", name))
+                          .spawn(move || {
+                              debug!("serving {} connection from {}",
+                                     name,
+                                     addr);
+                              conn_handle(conn);
+                              conn_open.fetch_sub(1, Ordering::SeqCst);
+                          });
+        if let Err(e) = spawned {
+            warn!("no thread for {} connection from {}: {}", name, addr, e);
+            open.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    Ok(())
+}
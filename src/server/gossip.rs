@@ -0,0 +1,373 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::ops::Sub;
+
+use protobuf::RepeatedField;
+use rustc_serialize::json::{Json, ToJson};
+use time;
+
+use {Gossip, Member};
+use clock::timespec_to_ms;
+use server::PeerID;
+use server::addr;
+use server::phi::PhiAccrual;
+
+lazy_static! {
+    // however regular its heartbeats were, a member we haven't heard from
+    // in this long is considered gone
+    pub static ref DEAD_AFTER: time::Duration =
+        time::Duration::seconds(10);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Liveness {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+impl Liveness {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Liveness::Alive => "alive",
+            Liveness::Suspect => "suspect",
+            Liveness::Dead => "dead",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Remote {
+    addr: Option<String>,
+    heartbeat: u64,
+    leader: Option<PeerID>,
+    load: u64,
+    witness: bool,
+    cli_addr: Option<String>,
+    // when we last saw its heartbeat go up
+    updated: time::Timespec,
+    detector: PhiAccrual,
+}
+
+// Our view of the cluster, built by gossip.  Every node periodically
+// sends everything it knows about the live members to its peers, and
+// keeps whatever it hears with a higher heartbeat than it had, so news
+// about a member spreads even to nodes that can't reach it directly.
+pub struct Membership {
+    id: PeerID,
+    peer_port: u16,
+    heartbeat: u64,
+    members: BTreeMap<PeerID, Remote>,
+    // members whose phi reaches this are suspected
+    phi_threshold: f64,
+    witness: bool,
+    // what we tell others to dial us at, when that's not what they'd see
+    advertise_peer: Option<String>,
+    advertise_cli: Option<String>,
+    // peer -> how far its clock is ahead of ours in ms, and when we saw
+    // that
+    offsets: BTreeMap<PeerID, (i64, time::Timespec)>,
+}
+
+impl Membership {
+    pub fn new(id: PeerID,
+               peer_port: u16,
+               phi_threshold: f64,
+               now: time::Timespec)
+               -> Membership {
+        Membership {
+            id: id,
+            peer_port: peer_port,
+            // starting from the wall clock keeps a restarted member's
+            // heartbeats above the ones it sent before
+            heartbeat: timespec_to_ms(now),
+            members: BTreeMap::new(),
+            phi_threshold: phi_threshold,
+            witness: false,
+            advertise_peer: None,
+            advertise_cli: None,
+            offsets: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_phi_threshold(&mut self, phi_threshold: f64) {
+        self.phi_threshold = phi_threshold;
+    }
+
+    pub fn set_witness(&mut self, witness: bool) {
+        self.witness = witness;
+    }
+
+    pub fn advertise(&mut self,
+                     peer_addr: Option<String>,
+                     cli_addr: Option<String>) {
+        self.advertise_peer = peer_addr;
+        self.advertise_cli = cli_addr;
+    }
+
+    // Raises our own heartbeat and returns what we should tell our peers.
+    pub fn tick(&mut self,
+                leader: Option<PeerID>,
+                load: u64,
+                now: time::Timespec)
+                -> Gossip {
+        self.heartbeat = cmp::max(self.heartbeat + 1, timespec_to_ms(now));
+
+        let mut me = Member::new();
+        me.set_id(self.id.clone());
+        me.set_peer_port(self.peer_port as u32);
+        me.set_heartbeat(self.heartbeat);
+        if let Some(leader) = leader {
+            me.set_leader(leader);
+        }
+        me.set_load(load);
+        me.set_witness(self.witness);
+        if let Some(ref addr) = self.advertise_peer {
+            me.set_addr(addr.clone());
+        }
+        if let Some(ref addr) = self.advertise_cli {
+            me.set_cli_addr(addr.clone());
+        }
+
+        let mut members = vec![me];
+        for (id, remote) in self.members.iter() {
+            // passing on the dead would only keep them around forever
+            if self.liveness(remote, now) == Liveness::Dead {
+                continue;
+            }
+            let mut member = Member::new();
+            member.set_id(id.clone());
+            if let Some(ref addr) = remote.addr {
+                member.set_addr(addr.clone());
+            }
+            member.set_heartbeat(remote.heartbeat);
+            if let Some(ref leader) = remote.leader {
+                member.set_leader(leader.clone());
+            }
+            member.set_load(remote.load);
+            member.set_witness(remote.witness);
+            if let Some(ref addr) = remote.cli_addr {
+                member.set_cli_addr(addr.clone());
+            }
+            members.push(member);
+        }
+
+        let mut gossip = Gossip::new();
+        gossip.set_members(RepeatedField::from_vec(members));
+        gossip.set_wall_ms(timespec_to_ms(now));
+        gossip
+    }
+
+    // Folds in what a peer at from told us.
+    pub fn merge(&mut self,
+                 from: Option<SocketAddr>,
+                 gossip: &Gossip,
+                 now: time::Timespec) {
+        for member in gossip.get_members() {
+            if member.get_id() == self.id {
+                continue;
+            }
+            let addr = if member.has_addr() {
+                Some(member.get_addr().to_string())
+            } else {
+                let port = member.get_peer_port() as u16;
+                from.map(|from| addr::join(from.ip(), port))
+            };
+            let mut detector = match self.members.get(member.get_id()) {
+                Some(remote) if member.get_heartbeat() <= remote.heartbeat => {
+                    continue
+                }
+                Some(remote) => remote.detector.clone(),
+                None => PhiAccrual::new(),
+            };
+            detector.heartbeat(now);
+            let leader = if member.has_leader() {
+                Some(member.get_leader().to_string())
+            } else {
+                None
+            };
+            self.members.insert(member.get_id().to_string(),
+                                Remote {
+                                    addr: addr,
+                                    heartbeat: member.get_heartbeat(),
+                                    leader: leader,
+                                    load: member.get_load(),
+                                    witness: member.get_witness(),
+                                    cli_addr: if member.has_cli_addr() {
+                                        Some(member.get_cli_addr().to_string())
+                                    } else {
+                                        None
+                                    },
+                                    updated: now,
+                                    detector: detector,
+                                });
+        }
+    }
+
+    // Notes the clock reading a peer sent with its gossip.  The trip here
+    // makes the peer look a little behind, which is nothing next to the
+    // offsets worth worrying about.
+    pub fn observe_clock(&mut self,
+                         id: &PeerID,
+                         wall_ms: u64,
+                         now: time::Timespec) {
+        let offset = wall_ms as i64 - timespec_to_ms(now) as i64;
+        self.offsets.insert(id.clone(), (offset, now));
+    }
+
+    // The furthest any peer's recent clock reading is from ours, in ms.
+    pub fn max_clock_offset(&self, now: time::Timespec) -> i64 {
+        self.recent_offsets(now).map(|offset| offset.abs()).max().unwrap_or(0)
+    }
+
+    // Whether so many peers' clocks are over max_offset_ms from ours that
+    // no quorum of a cluster of cluster_size could agree with us on the
+    // time.  Peers we have no recent reading from get the benefit of the
+    // doubt, so it takes a quorum of others agreeing with each other to
+    // call us the odd one out.
+    pub fn clock_skewed(&self,
+                        max_offset_ms: u64,
+                        cluster_size: usize,
+                        now: time::Timespec)
+                        -> bool {
+        let off = self.recent_offsets(now)
+                      .filter(|offset| offset.abs() as u64 > max_offset_ms)
+                      .count();
+        cluster_size.saturating_sub(off) < cluster_size / 2 + 1
+    }
+
+    fn recent_offsets<'a>(&'a self,
+                          now: time::Timespec)
+                          -> Box<Iterator<Item = i64> + 'a> {
+        Box::new(self.offsets
+                     .values()
+                     .filter(move |&&(_, seen)| now.sub(seen) < *DEAD_AFTER)
+                     .map(|&(offset, _)| offset))
+    }
+
+    pub fn liveness_of(&self,
+                       id: &PeerID,
+                       now: time::Timespec)
+                       -> Option<Liveness> {
+        self.members.get(id).map(|remote| self.liveness(remote, now))
+    }
+
+    // Whether gossip has told us the member is a witness.
+    pub fn is_witness(&self, id: &PeerID) -> bool {
+        self.members.get(id).map(|remote| remote.witness).unwrap_or(false)
+    }
+
+    // Where the member asked for clients to be sent, if it did.
+    pub fn cli_addr_of(&self, id: &PeerID) -> Option<String> {
+        self.members.get(id).and_then(|remote| remote.cli_addr.clone())
+    }
+
+    // How suspicious the silence from a member is; None if we've never
+    // heard of it.
+    pub fn phi(&self, id: &PeerID, now: time::Timespec) -> Option<f64> {
+        self.members.get(id).map(|remote| remote.detector.phi(now))
+    }
+
+    fn liveness(&self, remote: &Remote, now: time::Timespec) -> Liveness {
+        if now.sub(remote.updated) >= *DEAD_AFTER {
+            Liveness::Dead
+        } else if remote.detector.phi(now) >= self.phi_threshold {
+            Liveness::Suspect
+        } else {
+            Liveness::Alive
+        }
+    }
+
+    pub fn to_json(&self, now: time::Timespec) -> Json {
+        let mut members = vec![];
+        for (id, remote) in &self.members {
+            let mut o = BTreeMap::new();
+            o.insert("id".to_string(), id.to_json());
+            o.insert("addr".to_string(), remote.addr.to_json());
+            o.insert("liveness".to_string(),
+                     self.liveness(remote, now).as_str().to_json());
+            o.insert("phi".to_string(), remote.detector.phi(now).to_json());
+            o.insert("leader".to_string(), remote.leader.to_json());
+            o.insert("load".to_string(), remote.load.to_json());
+            o.insert("witness".to_string(), remote.witness.to_json());
+            o.insert("cli_addr".to_string(), remote.cli_addr.to_json());
+            o.insert("clock_offset_ms".to_string(),
+                     self.offsets.get(id).map(|&(offset, _)| offset).to_json());
+            members.push(Json::Object(o));
+        }
+        Json::Array(members)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Add;
+
+    use time;
+
+    use clock::timespec_to_ms;
+    use super::{DEAD_AFTER, Liveness, Membership};
+
+    #[test]
+    fn test_gossip_spreads_and_ages() {
+        let now = time::get_time();
+        let mut a = Membership::new("a".to_string(), 7770, 8.0, now);
+        let mut b = Membership::new("b".to_string(), 7771, 8.0, now);
+        let mut c = Membership::new("c".to_string(), 7772, 8.0, now);
+        b.advertise(Some("b.rasputin:7771".to_string()),
+                    Some("b.rasputin:8881".to_string()));
+
+        // c only talks to b, but a still hears of it through b
+        let from_c = c.tick(None, 0, now);
+        b.merge(Some("10.0.0.3:40000".parse().unwrap()), &from_c, now);
+        let from_b = b.tick(Some("b".to_string()), 5, now);
+        a.merge(Some("10.0.0.2:40001".parse().unwrap()), &from_b, now);
+
+        let c_id = "c".to_string();
+        assert_eq!(a.liveness_of(&"b".to_string(), now), Some(Liveness::Alive));
+        assert_eq!(a.liveness_of(&c_id, now), Some(Liveness::Alive));
+        assert_eq!(a.members[&c_id].addr, Some("10.0.0.3:7772".to_string()));
+        assert_eq!(a.members["b"].load, 5);
+        // b said where to find it, so what a saw of it doesn't matter
+        assert_eq!(a.members["b"].addr, Some("b.rasputin:7771".to_string()));
+        assert_eq!(a.cli_addr_of(&"b".to_string()),
+                   Some("b.rasputin:8881".to_string()));
+        assert_eq!(a.cli_addr_of(&c_id), None);
+
+        // a stale heartbeat doesn't count as hearing from c again
+        let later = now.add(time::Duration::seconds(3));
+        a.merge(None, &from_b, later);
+        assert_eq!(a.liveness_of(&c_id, later), Some(Liveness::Suspect));
+        assert_eq!(a.liveness_of(&c_id, now.add(*DEAD_AFTER)),
+                   Some(Liveness::Dead));
+
+        // and the dead aren't passed on
+        let from_a = a.tick(None, 0, now.add(*DEAD_AFTER));
+        assert!(from_a.get_members().iter().all(|m| m.get_id() != "c"));
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let now = time::get_time();
+        let mut a = Membership::new("a".to_string(), 7770, 8.0, now);
+        let mut b = Membership::new("b".to_string(), 7771, 8.0, now);
+        let ahead = now.add(time::Duration::seconds(2));
+        let from_b = b.tick(None, 0, ahead);
+        a.observe_clock(&"b".to_string(), from_b.get_wall_ms(), now);
+        assert_eq!(a.max_clock_offset(now), 2000);
+
+        // b alone can't outvote a and c's silence
+        assert!(!a.clock_skewed(500, 3, now));
+        a.observe_clock(&"c".to_string(), timespec_to_ms(ahead), now);
+        assert!(a.clock_skewed(500, 3, now));
+        assert!(!a.clock_skewed(5000, 3, now));
+        // but a is just as much the odd one out of 4
+        assert!(a.clock_skewed(500, 4, now));
+        assert!(!a.clock_skewed(500, 5, now));
+
+        // readings age out like members do
+        assert!(!a.clock_skewed(500, 3, now.add(*DEAD_AFTER)));
+        assert_eq!(a.max_clock_offset(now.add(*DEAD_AFTER)), 0);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rocksdb::{DB, DBResult, Direction, WriteBatch, WriteOptions, Writable};
+
+use StorageError;
+use server::checksum;
+use server::stats::Resized;
+
+// Collects the writes of many learned mutations into a single WriteBatch,
+// so that they hit the WAL (and the disk) together instead of one at a
+// time.  Reads made while the group is open see the group's own writes,
+// which keeps a CAS that follows a SET of the same key in the same group
+// correct.
+pub struct GroupCommit {
+    batch: WriteBatch,
+    // None for keys deleted in this group
+    overlay: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    bytes: usize,
+}
+
+impl GroupCommit {
+    pub fn new() -> GroupCommit {
+        GroupCommit {
+            batch: WriteBatch::new(),
+            overlay: BTreeMap::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn get(&self,
+               db: &DB,
+               key: &[u8])
+               -> Result<Option<Vec<u8>>, StorageError> {
+        match self.overlay.get(key) {
+            Some(v) => return Ok(v.clone()),
+            None => (),
+        }
+        checksum::get(db, key)
+    }
+
+    // The keys from start up to end as of the group's own writes.
+    pub fn keys_in(&self, db: &DB, start: &[u8], end: &[u8]) -> Vec<Vec<u8>> {
+        let mut keys = BTreeSet::new();
+        if start >= end {
+            return vec![];
+        }
+        let mut iter = db.iterator();
+        for (k, _) in iter.from(start, Direction::forward) {
+            if &*k >= end {
+                break;
+            }
+            keys.insert(k.to_vec());
+        }
+        for (k, v) in self.overlay.range(start.to_vec()..end.to_vec()) {
+            if v.is_some() {
+                keys.insert(k.clone());
+            } else {
+                keys.remove(k);
+            }
+        }
+        keys.into_iter().collect()
+    }
+
+    pub fn put(&mut self,
+               key: &[u8],
+               value: &[u8])
+               -> Result<(), StorageError> {
+        let sealed = checksum::seal(key, value);
+        try!(self.batch.put(key, &sealed).map_err(StorageError::Operation));
+        self.bytes += key.len() + sealed.len();
+        self.overlay.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        try!(self.batch.delete(key).map_err(StorageError::Operation));
+        self.bytes += key.len();
+        self.overlay.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    // How each key the group writes changes what the db holds, measured
+    // against the db before the group is committed.
+    pub fn resized(&self, db: &DB) -> Vec<Resized> {
+        let footprint = |key: &[u8], value_len: Option<usize>| {
+            value_len.map(|len| (key.len() + len) as i64)
+        };
+        self.overlay
+            .iter()
+            .filter_map(|(k, v)| {
+                let old = match db.get(k) {
+                    DBResult::Some(v) => footprint(k, Some(v.len())),
+                    _ => None,
+                };
+                let sealed = v.as_ref().map(|v| checksum::sealed_len(v.len()));
+                let new = footprint(k, sealed);
+                if old == new {
+                    return None;
+                }
+                Some(Resized {
+                    key: k.clone(),
+                    bytes: new.unwrap_or(0) - old.unwrap_or(0),
+                    keys: new.is_some() as i64 - old.is_some() as i64,
+                })
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.overlay.is_empty()
+    }
+
+    // What the group's writes add up to in the db, keys and sealed values
+    // both.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn commit(self, db: &DB) -> Result<(), StorageError> {
+        self.commit_synced(db, false)
+    }
+
+    // Writes the group, asking RocksDB to sync its WAL before returning
+    // if sync is set.
+    pub fn commit_synced(self,
+                         db: &DB,
+                         sync: bool)
+                         -> Result<(), StorageError> {
+        if self.overlay.is_empty() {
+            return Ok(());
+        }
+        let mut writeopts = WriteOptions::new();
+        writeopts.set_sync(sync);
+        db.write_opt(self.batch, &writeopts).map_err(StorageError::Operation)
+    }
+}
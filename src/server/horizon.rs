@@ -0,0 +1,74 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use time;
+
+use server::TXID;
+
+// Decides how much learned history the replication log has to hold on to.
+// Anything that consumes history incrementally, like a change feed or a
+// running backup, protects the oldest txid it still needs, and garbage
+// collection never moves past the oldest protection.  Protections carry an
+// expiry, so a consumer that dies without releasing its protection can't
+// pin history forever.
+pub struct GcHorizon {
+    protections: BTreeMap<String, (TXID, time::Timespec)>,
+}
+
+impl GcHorizon {
+    pub fn new() -> GcHorizon {
+        GcHorizon { protections: BTreeMap::new() }
+    }
+
+    // Keeps txid and everything after it around until the given time.
+    // Protecting again under the same owner moves its protection.
+    pub fn protect(&mut self, owner: &str, txid: TXID, until: time::Timespec) {
+        self.protections.insert(owner.to_string(), (txid, until));
+    }
+
+    pub fn release(&mut self, owner: &str) {
+        self.protections.remove(owner);
+    }
+
+    // Returns the oldest txid that must be kept, given how far we've
+    // learned, dropping any protections that have expired.
+    pub fn horizon(&mut self, now: time::Timespec, learned: TXID) -> TXID {
+        let expired: Vec<String> = self.protections
+                                       .iter()
+                                       .filter(|&(_, &(_, until))| until <= now)
+                                       .map(|(owner, _)| owner.clone())
+                                       .collect();
+        for owner in expired {
+            warn!("protection on history held by {} expired", owner);
+            self.protections.remove(&owner);
+        }
+        self.protections
+            .values()
+            .fold(learned, |horizon, &(txid, _)| cmp::min(horizon, txid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time;
+
+    use super::GcHorizon;
+
+    #[test]
+    fn test_horizon_respects_protections() {
+        let now = time::get_time();
+        let later = now + time::Duration::seconds(10);
+        let mut horizon = GcHorizon::new();
+        assert_eq!(horizon.horizon(now, 100), 100);
+
+        horizon.protect("backup", 40, later);
+        horizon.protect("cdc", 70, later);
+        assert_eq!(horizon.horizon(now, 100), 40);
+
+        horizon.release("backup");
+        assert_eq!(horizon.horizon(now, 100), 70);
+
+        // expired protections stop holding history back
+        assert_eq!(horizon.horizon(later, 100), 100);
+    }
+}
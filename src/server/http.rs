@@ -0,0 +1,112 @@
+use std::io;
+
+use mio::{TryRead, TryWrite};
+use mio::tcp::TcpStream;
+
+// Just enough HTTP/1.0 for the admin listener: read one GET request off
+// the socket, write one response, and hang up.  Anything fancier should
+// sit behind a real proxy.
+pub struct HttpConn {
+    pub sock: TcpStream,
+    req: Vec<u8>,
+    res: Vec<u8>,
+    written: usize,
+}
+
+// requests with larger headers than this are rejected outright
+const MAX_REQUEST_BYTES: usize = 8192;
+
+impl HttpConn {
+    pub fn new(sock: TcpStream) -> HttpConn {
+        HttpConn {
+            sock: sock,
+            req: vec![],
+            res: vec![],
+            written: 0,
+        }
+    }
+
+    // Reads whatever is available, returning the request path once the
+    // whole request head has arrived.
+    pub fn readable(&mut self) -> io::Result<Option<String>> {
+        let mut buf = [0; 1024];
+        loop {
+            match try!(self.sock.try_read(&mut buf)) {
+                Some(0) => break,
+                Some(n) => self.req.extend_from_slice(&buf[..n]),
+                None => break,
+            }
+            if self.req.len() > MAX_REQUEST_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "admin request too large"));
+            }
+        }
+        let done = self.req.windows(4).any(|w| w == b"\r\n\r\n");
+        if !done {
+            return Ok(None);
+        }
+        match request_path(&self.req) {
+            Some(path) => Ok(Some(path)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       "malformed admin request")),
+        }
+    }
+
+    pub fn respond(&mut self, status: &str, content_type: &str, body: &str) {
+        self.res = format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: \
+                            {}\r\nConnection: close\r\n\r\n{}",
+                           status,
+                           content_type,
+                           body.len(),
+                           body)
+                       .into_bytes();
+        self.written = 0;
+    }
+
+    // Returns true once the whole response has been written.
+    pub fn writable(&mut self) -> io::Result<bool> {
+        while self.written < self.res.len() {
+            match try!(self.sock.try_write(&self.res[self.written..])) {
+                Some(n) => self.written += n,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
+
+// Pulls the path out of a "GET /path HTTP/1.1" request line.  Only GET
+// is supported, since everything served here is read-only.
+pub fn request_path(req: &[u8]) -> Option<String> {
+    let head = match String::from_utf8(req.to_vec()) {
+        Ok(head) => head,
+        Err(_) => return None,
+    };
+    let line = match head.lines().next() {
+        Some(line) => line.to_string(),
+        None => return None,
+    };
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => {
+            // ignore any query string
+            Some(path.split('?').next().unwrap().to_string())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::request_path;
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n"),
+                   Some("/metrics".to_string()));
+        assert_eq!(request_path(b"GET /status?pretty HTTP/1.0\r\n\r\n"),
+                   Some("/status".to_string()));
+        assert_eq!(request_path(b"POST /metrics HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(request_path(b"\r\n\r\n"), None);
+    }
+}
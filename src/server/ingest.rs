@@ -0,0 +1,258 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use StorageError;
+use server::backup::{self, DataHeader, ManifestEntry, Record};
+use server::checksum::{self, bytes_to_u64, u64_to_bytes};
+use server::group_commit::GroupCommit;
+
+// A bulk load skips the log for all but one entry.  The admin CLI turns a
+// sorted file of keys and values into the backup data format, with values
+// sealed just as the db stores them, and stages it on every replica.  The
+// leader checks its copy through, then replicates a set of PREFIX plus
+// the file's name, holding how many keys it has, and each node loads its
+// staged copy into the db in the same batch as it applies that entry.  So
+// every replica ingests it at the same point in the log, and the marker
+// stays behind to say it did.  A node that finds its copy missing or
+// damaged ingests nothing, which the next scrub will turn up.
+pub const PREFIX: &'static [u8] = b"\x00ingest/";
+
+pub fn is_ingest_key(key: &[u8]) -> bool {
+    key.starts_with(PREFIX)
+}
+
+pub fn marker(file: &str) -> Vec<u8> {
+    let mut key = PREFIX.to_vec();
+    key.extend_from_slice(file.as_bytes());
+    key
+}
+
+// The staged file a marker names.
+pub fn marked_file(key: &[u8]) -> String {
+    String::from_utf8_lossy(&key[PREFIX.len()..]).into_owned()
+}
+
+pub fn marker_value(keys: u64) -> Vec<u8> {
+    u64_to_bytes(keys).to_vec()
+}
+
+pub fn marked_keys(value: &[u8]) -> u64 {
+    bytes_to_u64(value)
+}
+
+// Converts lines of a key, a tab and a value, sorted by key with no key
+// twice, into data ready to stage.  Returns how many keys there were.
+pub fn convert_for_ingest<R, W>(input: R, out: W) -> Result<u64, StorageError>
+    where R: BufRead,
+          W: Write
+{
+    let mut lines = SortedLines {
+        lines: input.split(b'\n'),
+        last: None,
+        line: 0,
+        err: None,
+    };
+    let mut entry = ManifestEntry {
+        file: String::new(),
+        node: String::new(),
+        txid: 0,
+        term: 0,
+        hlc: 0,
+        since: 0,
+        keys: 0,
+        bytes: 0,
+        crc: 0,
+        taken_ms: 0,
+    };
+    let written = backup::write_data(&mut entry, &mut lines, out);
+    match lines.err {
+        Some(e) => Err(e),
+        None => written.map(|_| entry.keys),
+    }
+}
+
+struct SortedLines<R> {
+    lines: io::Split<R>,
+    last: Option<Vec<u8>>,
+    line: usize,
+    // why we stopped early, if we did
+    err: Option<StorageError>,
+}
+
+impl<R: BufRead> Iterator for SortedLines<R> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        if self.err.is_some() {
+            return None;
+        }
+        let line = match self.lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                self.err = Some(StorageError::Operation(e.to_string()));
+                return None;
+            }
+            None => return None,
+        };
+        self.line += 1;
+        let tab = match line.iter().position(|&b| b == b'\t') {
+            Some(tab) => tab,
+            None => {
+                let err = format!("line {} has no tab between its key and \
+                                   value",
+                                  self.line);
+                self.err = Some(StorageError::Operation(err));
+                return None;
+            }
+        };
+        let (key, value) = (&line[..tab], &line[tab + 1..]);
+        if self.last.as_ref().map(|last| &**last >= key).unwrap_or(false) {
+            let err = format!("line {} is out of order; keys must be sorted \
+                               and unique",
+                              self.line);
+            self.err = Some(StorageError::Operation(err));
+            return None;
+        }
+        self.last = Some(key.to_vec());
+        Some((key.to_vec().into_boxed_slice(),
+              Some(checksum::seal(key, value).into_boxed_slice())))
+    }
+}
+
+fn staged(dir: &str, file: &str) -> Result<PathBuf, StorageError> {
+    let named = !file.is_empty() && !file.starts_with('.') &&
+                file.chars().all(|c| c.is_alphanumeric() || "._-".contains(c));
+    if !named {
+        let err = format!("{:?} can't name a staged file", file);
+        return Err(StorageError::Operation(err));
+    }
+    Ok(Path::new(dir).join(file))
+}
+
+// Writes data into the staged file at offset, which must be where the
+// file ends, or zero to start it over.  Returns how long it is now.
+pub fn stage(dir: &str,
+             file: &str,
+             offset: u64,
+             data: &[u8])
+             -> Result<u64, StorageError> {
+    let path = try!(staged(dir, file));
+    try!(fs::create_dir_all(dir).map_err(io_error));
+    let mut f = try!(OpenOptions::new()
+                         .write(true)
+                         .create(true)
+                         .open(&path)
+                         .map_err(io_error));
+    let len = try!(f.metadata().map_err(io_error)).len();
+    if offset > len {
+        let err = format!("{} is only staged up to byte {}, not {}",
+                          file,
+                          len,
+                          offset);
+        return Err(StorageError::Operation(err));
+    }
+    try!(f.set_len(offset).map_err(io_error));
+    try!(f.seek(SeekFrom::Start(offset)).map_err(io_error));
+    try!(f.write_all(data).map_err(io_error));
+    try!(f.sync_all().map_err(io_error));
+    Ok(offset + data.len() as u64)
+}
+
+// Reads a staged file through, checking it arrived whole, that its keys
+// are in order and that every value is sealed intact.
+pub fn check(dir: &str, file: &str) -> Result<DataHeader, StorageError> {
+    let f = try!(File::open(try!(staged(dir, file))).map_err(io_error));
+    let mut last: Option<Vec<u8>> = None;
+    backup::read_data(f, |k, v| {
+        if last.as_ref().map(|last| *last >= k).unwrap_or(false) {
+            return Err(StorageError::Corrupt(format!("{} isn't sorted", file)));
+        }
+        match v {
+            Some(v) => try!(checksum::unseal(&k, &v)),
+            None => {
+                let err = format!("{} deletes keys", file);
+                return Err(StorageError::Corrupt(err));
+            }
+        };
+        last = Some(k);
+        Ok(())
+    })
+}
+
+// Loads a staged file into group, after checking it all first so that a
+// damaged one adds nothing.  Returns how many keys it had.
+pub fn load(dir: &str,
+            file: &str,
+            group: &mut GroupCommit)
+            -> Result<u64, StorageError> {
+    try!(check(dir, file));
+    let f = try!(File::open(try!(staged(dir, file))).map_err(io_error));
+    let header = try!(backup::read_data(f, |k, v| {
+        let value = try!(checksum::unseal(&k, &v.unwrap()));
+        group.put(&k, &value)
+    }));
+    Ok(header.keys)
+}
+
+// Once loaded there's no need to keep it.
+pub fn remove(dir: &str, file: &str) {
+    if let Ok(path) = staged(dir, file) {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("could not remove staged file {:?}: {}", path, e);
+        }
+    }
+}
+
+fn io_error(e: io::Error) -> StorageError {
+    StorageError::Operation(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use server::checksum;
+    use server::group_commit::GroupCommit;
+    use server::rocksdb::new;
+    use super::{check, convert_for_ingest, load, stage};
+
+    #[test]
+    fn test_convert_stage_and_load() {
+        let dir = "_rasputin_test_ingest";
+        let _ = fs::remove_dir_all(dir);
+
+        let mut data = vec![];
+        let lines = &b"a\t1\nb\t\nc\tx\ty"[..];
+        assert_eq!(convert_for_ingest(lines, &mut data).unwrap(), 3);
+        assert!(convert_for_ingest(&b"b\t1\na\t2\n"[..], &mut vec![]).is_err());
+        assert!(convert_for_ingest(&b"a\t1\na\t2\n"[..], &mut vec![]).is_err());
+        assert!(convert_for_ingest(&b"a1\n"[..], &mut vec![]).is_err());
+
+        // staged a piece at a time, and over again from the start
+        let half = data.len() / 2;
+        assert_eq!(stage(dir, "load-1", 0, &data[..half]).unwrap(),
+                   half as u64);
+        assert!(check(dir, "load-1").is_err());
+        assert!(stage(dir, "load-1", half as u64 + 1, &data[half..]).is_err());
+        assert_eq!(stage(dir, "load-1", half as u64, &data[half..]).unwrap(),
+                   data.len() as u64);
+        assert_eq!(stage(dir, "load-1", 0, &data).unwrap(), data.len() as u64);
+        assert_eq!(check(dir, "load-1").unwrap().keys, 3);
+        assert!(stage(dir, "../load-1", 0, &data).is_err());
+
+        let db = new("_rasputin_test_ingest_db".to_string()).unwrap();
+        let mut group = GroupCommit::new();
+        assert_eq!(load(dir, "load-1", &mut group).unwrap(), 3);
+        group.commit(&db).unwrap();
+        assert_eq!(checksum::get(&db, b"b").unwrap(), Some(vec![]));
+        assert_eq!(checksum::get(&db, b"c").unwrap(), Some(b"x\ty".to_vec()));
+
+        data[20] ^= 1;
+        stage(dir, "load-2", 0, &data).unwrap();
+        let mut group = GroupCommit::new();
+        assert!(load(dir, "load-2", &mut group).is_err());
+        fs::remove_dir_all(dir).unwrap();
+        let _ = fs::remove_dir_all("_rasputin_test_ingest_db");
+    }
+}
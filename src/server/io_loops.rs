@@ -0,0 +1,85 @@
+use bytes::ByteBuf;
+use mio::{self, NotifyError, Token};
+
+use server::{DRAIN, Envelope, RELOAD, SHUTDOWN, SendChannel};
+
+// The first IO loop also has the peer and admin listeners, and the tokens
+// below this.  Every other one only has client connections, numbered from
+// its index shifted by this, so a reply's token says which loop its
+// connection is on.
+const LOOP_SHIFT: usize = 16;
+
+// Where the first client connection of IO loop i is numbered from.
+pub fn cli_base(i: usize) -> usize {
+    if i == 0 {
+        1024
+    } else {
+        i << LOOP_SHIFT
+    }
+}
+
+// Which of loops IO loops tok belongs to.
+pub fn loop_of(tok: Token, loops: usize) -> usize {
+    let i = tok.as_usize() >> LOOP_SHIFT;
+    if i < loops {
+        i
+    } else {
+        0
+    }
+}
+
+// Sends replies to the IO loop holding the connection they're for.  What
+// each loop must hear, like a shutdown, goes to all of them, and anything
+// for peers to the first.
+#[derive(Clone)]
+pub struct IoLoops {
+    senders: Vec<mio::Sender<Envelope>>,
+}
+
+impl IoLoops {
+    pub fn new(senders: Vec<mio::Sender<Envelope>>) -> IoLoops {
+        assert!(!senders.is_empty());
+        IoLoops { senders: senders }
+    }
+
+    pub fn send(&self, env: Envelope) -> Result<(), NotifyError<Envelope>> {
+        if env.tok == SHUTDOWN || env.tok == DRAIN || env.tok == RELOAD {
+            for sender in self.senders[1..].iter() {
+                let _ = sender.send(Envelope {
+                    address: env.address,
+                    tok: env.tok,
+                    msg: ByteBuf::none(),
+                });
+            }
+            return self.senders[0].send(env);
+        }
+        self.senders[loop_of(env.tok, self.senders.len())].send(env)
+    }
+}
+
+impl SendChannel<Envelope, Result<(), NotifyError<Envelope>>> for IoLoops {
+    fn send_msg(&self, msg: Envelope) -> Result<(), NotifyError<Envelope>> {
+        self.send(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::Token;
+
+    use server::{PEER_BROADCAST, SHUTDOWN};
+    use super::{cli_base, loop_of};
+
+    #[test]
+    fn test_loop_of() {
+        assert_eq!(loop_of(Token(2), 4), 0);
+        assert_eq!(loop_of(Token(cli_base(0) + 10), 4), 0);
+        assert_eq!(loop_of(Token(cli_base(3)), 4), 3);
+        assert_eq!(loop_of(Token(cli_base(3) + 4095), 4), 3);
+        assert_eq!(loop_of(Token(cli_base(4)), 4), 0);
+        assert_eq!(loop_of(PEER_BROADCAST, 4), 0);
+        assert_eq!(loop_of(SHUTDOWN, 1), 0);
+        // clear of the first loop's admin connections
+        assert!(cli_base(1) > 16384 + 32);
+    }
+}
@@ -0,0 +1,680 @@
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::mpsc::TrySendError;
+use std::time::Duration as StdDuration;
+
+use bytes::Buf;
+use mio::Token;
+use time;
+
+use server::{Envelope, ServerMsg};
+use server::metrics::Metrics;
+
+// The server's mailbox keeps three lanes, so that a flood of one kind of
+// work can't keep another waiting behind it.  System carries what keeps
+// the cluster running: consensus traffic between peers, the cron, and the
+// few client requests that administer it or are marked CRITICAL.  Bulk
+// carries what moves a lot and can wait: LOW priority requests, loads,
+// exports, backups, counts, scrubs and repairs, and what we mirror.
+// Normal is everything else clients send.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lane {
+    System,
+    Normal,
+    Bulk,
+}
+
+const LANES: usize = 3;
+
+// While both are waiting, the bulk lane is served once for every this
+// many normal messages, so it's slowed down but never stopped.
+pub const NORMAL_PER_BULK: usize = 8;
+
+// Where a message read off a socket came from: each kind gets its own
+// share of every lane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Peer,
+    Cli,
+}
+
+const SOURCES: usize = 2;
+
+// CliReq fields, by number, that decide its lane
+const CLI_TRANSFER_LEADER: u32 = 8;
+const CLI_AUTH: u32 = 9;
+const CLI_PRIORITY: u32 = 10;
+const CLI_RELOAD_CONFIG: u32 = 11;
+const CLI_BACKUP: u32 = 13;
+const CLI_STAGE: u32 = 15;
+const CLI_INGEST: u32 = 16;
+const CLI_EXPORT: u32 = 17;
+const CLI_COUNT: u32 = 23;
+const PRIORITY_LOW: u64 = 1;
+const PRIORITY_CRITICAL: u64 = 3;
+
+// and PeerMsg ones
+const PEER_SCRUB: u32 = 13;
+const PEER_SCRUB_RES: u32 = 14;
+const PEER_REPAIR_REQ: u32 = 15;
+const PEER_REPAIR: u32 = 16;
+
+// The number and, for varints, the value of each field at the top of a
+// serialized message, as far as it makes sense.  The traffic cop sorts
+// messages into lanes with this, which is cheaper than parsing them and
+// leaves telling what's wrong with one that doesn't to the server.
+fn fields(mut msg: &[u8]) -> Vec<(u32, u64)> {
+    fn varint(msg: &mut &[u8]) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in 0..10 {
+            let (&byte, rest) = match msg.split_first() {
+                Some(split) => split,
+                None => return None,
+            };
+            *msg = rest;
+            value |= ((byte & 0x7f) as u64) << (shift * 7);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+    let mut found = vec![];
+    while let Some(tag) = varint(&mut msg) {
+        let skip = match tag & 7 {
+            0 => {
+                match varint(&mut msg) {
+                    Some(value) => {
+                        found.push(((tag >> 3) as u32, value));
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            1 => 8,
+            2 => {
+                match varint(&mut msg) {
+                    Some(len) => len as usize,
+                    None => break,
+                }
+            }
+            5 => 4,
+            _ => break,
+        };
+        if skip > msg.len() {
+            break;
+        }
+        found.push(((tag >> 3) as u32, 0));
+        msg = &msg[skip..];
+    }
+    found
+}
+
+pub fn cli_lane(msg: &[u8]) -> Lane {
+    let mut lane = Lane::Normal;
+    for (field, value) in fields(msg) {
+        match field {
+            CLI_PRIORITY if value == PRIORITY_CRITICAL => return Lane::System,
+            CLI_AUTH | CLI_TRANSFER_LEADER | CLI_RELOAD_CONFIG => {
+                return Lane::System
+            }
+            CLI_PRIORITY if value == PRIORITY_LOW => lane = Lane::Bulk,
+            CLI_BACKUP | CLI_STAGE | CLI_INGEST | CLI_EXPORT | CLI_COUNT => {
+                lane = Lane::Bulk
+            }
+            _ => (),
+        }
+    }
+    lane
+}
+
+// Repairs carry the txid they were read as of, so the server can take
+// them in any order relative to appends.
+pub fn peer_lane(msg: &[u8]) -> Lane {
+    let bulk = fields(msg).iter().any(|&(field, _)| {
+        match field {
+            PEER_SCRUB | PEER_SCRUB_RES | PEER_REPAIR_REQ | PEER_REPAIR => true,
+            _ => false,
+        }
+    });
+    if bulk {
+        Lane::Bulk
+    } else {
+        Lane::System
+    }
+}
+
+fn lane_of(msg: &ServerMsg) -> Lane {
+    match *msg {
+        ServerMsg::Peer(ref env) => peer_lane(env.msg.bytes()),
+        ServerMsg::Cli(ref env, _) => cli_lane(env.msg.bytes()),
+        ServerMsg::Mirror(_) => Lane::Bulk,
+        _ => Lane::System,
+    }
+}
+
+fn source_of(msg: &ServerMsg) -> Option<Source> {
+    match *msg {
+        ServerMsg::Peer(_) => Some(Source::Peer),
+        ServerMsg::Cli(..) => Some(Source::Cli),
+        _ => None,
+    }
+}
+
+struct State {
+    lanes: [VecDeque<ServerMsg>; LANES],
+    // how many of each lane's messages came off sockets of each kind
+    from: [[usize; LANES]; SOURCES],
+    // normal messages served since the bulk lane last was
+    normal_run: usize,
+    // Client requests waiting, by connection.  The traffic cop reuses a
+    // connection's token once it closes, so forgetting a session has to
+    // wait until its requests have been served, which may take a while
+    // in another lane, and the next connection's must wait behind that.
+    queued: BTreeMap<usize, usize>,
+    held: BTreeMap<usize, Vec<ServerMsg>>,
+    senders: usize,
+    listening: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl State {
+    fn enqueue(&mut self, msg: ServerMsg) {
+        let (tok, closed) = match msg {
+            ServerMsg::CliClosed(tok) => (Some(tok.as_usize()), true),
+            ServerMsg::Cli(ref env, _) => (Some(env.tok.as_usize()), false),
+            _ => (None, false),
+        };
+        if let Some(tok) = tok {
+            if let Some(held) = self.held.get_mut(&tok) {
+                held.push(msg);
+                return;
+            }
+            if closed && self.queued.contains_key(&tok) {
+                self.held.insert(tok, vec![]);
+                return;
+            }
+            if !closed {
+                *self.queued.entry(tok).or_insert(0) += 1;
+            }
+        }
+        let lane = lane_of(&msg) as usize;
+        if let Some(source) = source_of(&msg) {
+            self.from[source as usize][lane] += 1;
+        }
+        self.metrics.mailbox_depth.inc();
+        self.lanes[lane].push_back(msg);
+    }
+
+    fn next_lane(&self) -> Option<usize> {
+        let (system, normal, bulk) = (Lane::System as usize,
+                                      Lane::Normal as usize,
+                                      Lane::Bulk as usize);
+        if !self.lanes[system].is_empty() {
+            Some(system)
+        } else if !self.lanes[bulk].is_empty() &&
+                  (self.lanes[normal].is_empty() ||
+                   self.normal_run >= NORMAL_PER_BULK) {
+            Some(bulk)
+        } else if !self.lanes[normal].is_empty() {
+            Some(normal)
+        } else {
+            None
+        }
+    }
+
+    fn dequeue(&mut self) -> Option<ServerMsg> {
+        let lane = match self.next_lane() {
+            Some(lane) => lane,
+            None => return None,
+        };
+        if lane == Lane::Normal as usize {
+            self.normal_run += 1;
+        } else if lane == Lane::Bulk as usize {
+            self.normal_run = 0;
+        }
+        let msg = self.lanes[lane].pop_front().unwrap();
+        self.metrics.mailbox_depth.dec();
+        if let Some(source) = source_of(&msg) {
+            self.from[source as usize][lane] -= 1;
+        }
+        if let ServerMsg::Cli(ref env, _) = msg {
+            let tok = env.tok.as_usize();
+            let served = {
+                let queued = self.queued.get_mut(&tok).unwrap();
+                *queued -= 1;
+                *queued == 0
+            };
+            if served {
+                self.queued.remove(&tok);
+                // its connection's close was waiting on it, and whatever
+                // the next one has sent on that
+                if let Some(held) = self.held.remove(&tok) {
+                    self.enqueue(ServerMsg::CliClosed(Token(tok)));
+                    for msg in held {
+                        self.enqueue(msg);
+                    }
+                }
+            }
+        }
+        Some(msg)
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+    room: Condvar,
+    // the most messages each lane holds, and the most of those that come
+    // off sockets of any one kind
+    capacity: usize,
+    queue_depth: usize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap()
+    }
+}
+
+// What sends to the server thread's mailbox.  It's dropped by the server
+// thread once every Mailbox is gone and everything sent has been served.
+pub struct Mailbox {
+    shared: Arc<Shared>,
+}
+
+pub struct MailboxRx {
+    shared: Arc<Shared>,
+}
+
+pub fn mailbox(capacity: usize,
+               queue_depth: usize,
+               metrics: Arc<Metrics>)
+               -> (Mailbox, MailboxRx) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            lanes: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            from: [[0; LANES]; SOURCES],
+            normal_run: 0,
+            queued: BTreeMap::new(),
+            held: BTreeMap::new(),
+            senders: 1,
+            listening: true,
+            metrics: metrics,
+        }),
+        ready: Condvar::new(),
+        room: Condvar::new(),
+        capacity: capacity,
+        queue_depth: queue_depth,
+    });
+    (Mailbox { shared: shared.clone() }, MailboxRx { shared: shared })
+}
+
+impl Mailbox {
+    // What the server records into, for those sending to it to record
+    // into as well.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.shared.lock().metrics.clone()
+    }
+
+    // Waits for room in msg's lane, handing msg back if the server thread
+    // has gone away.
+    pub fn send(&self, msg: ServerMsg) -> Result<(), ServerMsg> {
+        let lane = lane_of(&msg) as usize;
+        let mut state = self.shared.lock();
+        while state.listening &&
+              state.lanes[lane].len() >= self.shared.capacity {
+            state = self.shared.room.wait(state).unwrap();
+        }
+        if !state.listening {
+            return Err(msg);
+        }
+        state.enqueue(msg);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    // Queues a request read off a socket of the given kind if its lane has
+    // room for it, without waiting.
+    pub fn try_forward(&self,
+                       source: Source,
+                       env: Envelope)
+                       -> Result<(), TrySendError<Envelope>> {
+        let lane = match source {
+            Source::Cli => cli_lane(env.msg.bytes()) as usize,
+            Source::Peer => peer_lane(env.msg.bytes()) as usize,
+        };
+        let mut state = self.shared.lock();
+        if !state.listening {
+            return Err(TrySendError::Disconnected(env));
+        }
+        if state.lanes[lane].len() >= self.shared.capacity ||
+           state.from[source as usize][lane] >= self.shared.queue_depth {
+            return Err(TrySendError::Full(env));
+        }
+        state.enqueue(match source {
+            Source::Peer => ServerMsg::Peer(env),
+            Source::Cli => ServerMsg::Cli(env, time::get_time()),
+        });
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    // Tells the server a client connection went away, once it's served
+    // what the connection sent, which never waits for room.
+    pub fn closed(&self, tok: Token) {
+        let mut state = self.shared.lock();
+        state.enqueue(ServerMsg::CliClosed(tok));
+        self.shared.ready.notify_one();
+    }
+
+    // A way back into the mailbox for the server's own workers.  It isn't
+    // a sender, so it never keeps the server thread running.
+    pub fn backchannel(&self) -> Backchannel {
+        Backchannel { shared: self.shared.clone() }
+    }
+}
+
+impl Clone for Mailbox {
+    fn clone(&self) -> Mailbox {
+        self.shared.lock().senders += 1;
+        Mailbox { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for Mailbox {
+    fn drop(&mut self) {
+        self.shared.lock().senders -= 1;
+        self.shared.ready.notify_all();
+    }
+}
+
+#[derive(Clone)]
+pub struct Backchannel {
+    shared: Arc<Shared>,
+}
+
+impl Backchannel {
+    // Something for a worker to tell the server with once it's taken its
+    // snapshot of the db.
+    pub fn taken(&self) -> Taken {
+        Taken { shared: Some(self.shared.clone()) }
+    }
+}
+
+// Sends ServerMsg::Taken once, when done is called or, should the worker
+// holding it die first, when it's dropped, so the server is never left
+// waiting on a snapshot.  Like a close, it never waits for room.
+pub struct Taken {
+    shared: Option<Arc<Shared>>,
+}
+
+impl Taken {
+    // One nobody waits on, for jobs run on the server thread itself.
+    pub fn none() -> Taken {
+        Taken { shared: None }
+    }
+
+    pub fn done(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            shared.lock().enqueue(ServerMsg::Taken);
+            shared.ready.notify_one();
+        }
+    }
+}
+
+impl Drop for Taken {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+impl MailboxRx {
+    // The next message to handle, waiting for one, or None once there
+    // are none left and nothing that could send more.
+    pub fn recv(&self) -> Option<ServerMsg> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(msg) = state.dequeue() {
+                self.shared.room.notify_all();
+                return Some(msg);
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = self.shared.ready.wait(state).unwrap();
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<ServerMsg> {
+        let msg = self.shared.lock().dequeue();
+        if msg.is_some() {
+            self.shared.room.notify_all();
+        }
+        msg
+    }
+
+    // Like recv, but gives up with None once timeout has passed.
+    pub fn recv_timeout(&self, timeout: time::Duration) -> Option<ServerMsg> {
+        let deadline = time::get_time() + timeout;
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(msg) = state.dequeue() {
+                self.shared.room.notify_all();
+                return Some(msg);
+            }
+            let left = deadline - time::get_time();
+            if state.senders == 0 || left <= time::Duration::zero() {
+                return None;
+            }
+            let ms = cmp::max(left.num_milliseconds(), 1) as u64;
+            state = self.shared
+                        .ready
+                        .wait_timeout(state, StdDuration::from_millis(ms))
+                        .unwrap()
+                        .0;
+        }
+    }
+}
+
+impl Drop for MailboxRx {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.listening = false;
+        let left: usize = state.lanes.iter().map(|lane| lane.len()).sum();
+        for lane in state.lanes.iter_mut() {
+            lane.clear();
+        }
+        for _ in 0..left {
+            state.metrics.mailbox_depth.dec();
+        }
+        self.shared.room.notify_all();
+    }
+}
+
+// A ConnSet's way into the mailbox, for the kind of socket it reads.
+pub struct Inbox {
+    mailbox: Mailbox,
+    source: Source,
+}
+
+impl Inbox {
+    pub fn new(mailbox: Mailbox, source: Source) -> Inbox {
+        Inbox {
+            mailbox: mailbox,
+            source: source,
+        }
+    }
+
+    pub fn try_send(&self,
+                    env: Envelope)
+                    -> Result<(), TrySendError<Envelope>> {
+        self.mailbox.try_forward(self.source, env)
+    }
+
+    pub fn closed(&self, tok: Token) {
+        self.mailbox.closed(tok);
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.mailbox.metrics()
+    }
+}
+
+impl Clone for Inbox {
+    fn clone(&self) -> Inbox {
+        Inbox::new(self.mailbox.clone(), self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc::TrySendError;
+
+    use bytes::{Buf, ByteBuf};
+    use mio::Token;
+    use protobuf::Message;
+    use time;
+
+    use {CliReq, PeerMsg, Priority, ScrubRes};
+    use server::{Envelope, ServerMsg};
+    use server::metrics::Metrics;
+    use super::{Lane, NORMAL_PER_BULK, Source, Taken, cli_lane, mailbox,
+                peer_lane};
+
+    fn cli(tok: usize, priority: Option<Priority>, export: bool) -> Envelope {
+        let mut req = CliReq::new();
+        req.set_req_id(tok as u64);
+        if let Some(priority) = priority {
+            req.set_priority(priority);
+        }
+        if export {
+            req.mut_export().set_start(b"a".to_vec());
+        } else {
+            req.mut_get().set_key(b"k".to_vec());
+        }
+        Envelope {
+            address: Some("127.0.0.1:1".parse().unwrap()),
+            tok: Token(tok),
+            msg: ByteBuf::from_slice(&*req.write_to_bytes().unwrap()),
+        }
+    }
+
+    fn tok_of(msg: ServerMsg) -> (Token, bool) {
+        match msg {
+            ServerMsg::Cli(env, _) => (env.tok, false),
+            ServerMsg::CliClosed(tok) => (tok, true),
+            _ => panic!("not a client message"),
+        }
+    }
+
+    #[test]
+    fn test_lanes() {
+        assert_eq!(cli_lane(cli(1, None, false).msg.bytes()), Lane::Normal);
+        assert_eq!(cli_lane(cli(1, Some(Priority::LOW), false).msg.bytes()),
+                   Lane::Bulk);
+        assert_eq!(cli_lane(cli(1, Some(Priority::CRITICAL), true).msg.bytes()),
+                   Lane::System);
+        assert_eq!(cli_lane(cli(1, None, true).msg.bytes()), Lane::Bulk);
+        assert_eq!(cli_lane(b"\xff\xff"), Lane::Normal);
+
+        let mut peer_msg = PeerMsg::new();
+        peer_msg.set_srvid("a".to_string());
+        peer_msg.set_deadline_ms(7);
+        assert_eq!(peer_lane(&peer_msg.write_to_bytes().unwrap()),
+                   Lane::System);
+        let mut scrub_res = ScrubRes::new();
+        scrub_res.set_txid(7);
+        scrub_res.set_compared(true);
+        peer_msg.set_scrub_res(scrub_res);
+        assert_eq!(peer_lane(&peer_msg.write_to_bytes().unwrap()), Lane::Bulk);
+
+        // critical first, then normal with a bulk one now and then
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = mailbox(100, 100, metrics.clone());
+        for tok in 0..20 {
+            tx.try_forward(Source::Cli, cli(tok, None, tok < 10)).unwrap();
+        }
+        tx.try_forward(Source::Cli, cli(99, Some(Priority::CRITICAL), false))
+          .unwrap();
+        assert_eq!(metrics.mailbox_depth.get(), 21);
+        let order: Vec<usize> = (0..21)
+                                    .map(|_| rx.try_recv().unwrap())
+                                    .map(|msg| tok_of(msg).0.as_usize())
+                                    .collect();
+        assert_eq!(order[0], 99);
+        assert_eq!(&order[1..NORMAL_PER_BULK + 2],
+                   &[10, 11, 12, 13, 14, 15, 16, 17, 0]);
+        assert!(rx.try_recv().is_none());
+        assert_eq!(metrics.mailbox_depth.get(), 0);
+
+        // each kind of socket gets its own share of a lane
+        let (tx, rx) = mailbox(100, 1, Arc::new(Metrics::new()));
+        tx.try_forward(Source::Cli, cli(1, None, false)).unwrap();
+        match tx.try_forward(Source::Cli, cli(2, None, false)) {
+            Err(TrySendError::Full(env)) => assert_eq!(env.tok, Token(2)),
+            _ => panic!("a second one fit"),
+        }
+        tx.try_forward(Source::Cli, cli(3, None, true)).unwrap();
+        rx.try_recv().unwrap();
+        tx.try_forward(Source::Cli, cli(2, None, false)).unwrap();
+    }
+
+    #[test]
+    fn test_closes_wait_for_their_connection() {
+        let (tx, rx) = mailbox(100, 100, Arc::new(Metrics::new()));
+        tx.try_forward(Source::Cli, cli(5, None, true)).unwrap();
+        tx.try_forward(Source::Cli, cli(6, None, false)).unwrap();
+        tx.closed(Token(5));
+        // the next connection to get the token
+        tx.try_forward(Source::Cli, cli(5, None, false)).unwrap();
+        tx.closed(Token(6));
+
+        assert_eq!(tok_of(rx.try_recv().unwrap()), (Token(6), false));
+        assert_eq!(tok_of(rx.try_recv().unwrap()), (Token(6), true));
+        assert_eq!(tok_of(rx.try_recv().unwrap()), (Token(5), false));
+        assert_eq!(tok_of(rx.try_recv().unwrap()), (Token(5), true));
+        assert_eq!(tok_of(rx.try_recv().unwrap()), (Token(5), false));
+        assert!(rx.try_recv().is_none());
+
+        drop(tx);
+        assert!(rx.recv().is_none());
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let (tx, rx) = mailbox(100, 100, Arc::new(Metrics::new()));
+        tx.try_forward(Source::Cli, cli(1, None, false)).unwrap();
+        let wait = time::Duration::milliseconds(20);
+        assert_eq!(tok_of(rx.recv_timeout(wait).unwrap()), (Token(1), false));
+
+        let started = time::get_time();
+        assert!(rx.recv_timeout(wait).is_none());
+        assert!(time::get_time() - started >= wait);
+    }
+
+    #[test]
+    fn test_taken_is_told_once_and_keeps_nobody_waiting() {
+        let (tx, rx) = mailbox(1, 1, Arc::new(Metrics::new()));
+        let backchannel = tx.backchannel();
+        tx.try_forward(Source::Cli, cli(1, None, false)).unwrap();
+        let mut taken = backchannel.taken();
+        taken.done();
+        drop(taken);
+        // and by a worker that died holding it
+        drop(backchannel.taken());
+        Taken::none().done();
+        drop(tx);
+        // ahead of the client, and past a full lane
+        for _ in 0..2 {
+            match rx.recv() {
+                Some(ServerMsg::Taken) => (),
+                _ => panic!("expected a snapshot taken"),
+            }
+        }
+        assert_eq!(tok_of(rx.recv().unwrap()), (Token(1), false));
+        assert!(rx.recv().is_none());
+    }
+}
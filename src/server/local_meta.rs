@@ -0,0 +1,127 @@
+use StorageError;
+use server::checksum::{self, bytes_to_u32, u32_to_bytes};
+
+// What the node keeps about itself in the local_meta column family is
+// stored as
+//
+//     magic | format version | value | crc32c
+//
+// with the checksum over the key, the version and the value, so a value
+// that's damaged, or was written under another key, is noticed rather
+// than taken for the node's identity.  Anything without the magic was
+// written before there was a format at all, and counts as version 0.
+pub const MAGIC: &'static [u8] = b"RSPM";
+pub const FORMAT_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 5;
+
+pub fn wrap(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut stored = MAGIC.to_vec();
+    stored.push(FORMAT_VERSION);
+    stored.extend(value);
+    stored.extend(&u32_to_bytes(crc(key, FORMAT_VERSION, value)));
+    stored
+}
+
+// The value stored under key as the current format has it, and whether
+// it was stored in an older one, and so wants writing back.
+pub fn unwrap(key: &[u8],
+              stored: &[u8])
+              -> Result<(Vec<u8>, bool), StorageError> {
+    let name = String::from_utf8_lossy(key);
+    if !stored.starts_with(MAGIC) {
+        return migrate(0, key, stored.to_vec()).map(|value| (value, true));
+    }
+    if stored.len() < HEADER_LEN + 4 {
+        return Err(checksum::corrupt(format!("local {} is truncated", name)));
+    }
+    let version = stored[MAGIC.len()];
+    let (value, sum) =
+        stored[HEADER_LEN..].split_at(stored.len() - HEADER_LEN - 4);
+    if bytes_to_u32(sum) != crc(key, version, value) {
+        let err = format!("local {} fails its checksum", name);
+        return Err(checksum::corrupt(err));
+    }
+    if version > FORMAT_VERSION {
+        let err = format!("local {} is in format version {}, but this build \
+                           only reads up to {}",
+                          name,
+                          version,
+                          FORMAT_VERSION);
+        return Err(StorageError::Operation(err));
+    }
+    if version < FORMAT_VERSION {
+        return migrate(version, key, value.to_vec()).map(|value| (value, true));
+    }
+    Ok((value.to_vec(), false))
+}
+
+// Brings a value in an older format up to what FORMAT_VERSION stores.  A
+// new version adds a case here for the one before it.
+fn migrate(version: u8,
+           key: &[u8],
+           value: Vec<u8>)
+           -> Result<Vec<u8>, StorageError> {
+    match version {
+        // the values were stored as they are
+        0 => {
+            info!("upgrading local {} to format version {}",
+                  String::from_utf8_lossy(key),
+                  FORMAT_VERSION);
+            Ok(value)
+        }
+        v => {
+            let err = format!("no way to upgrade local {} from format \
+                               version {}",
+                              String::from_utf8_lossy(key),
+                              v);
+            Err(StorageError::Operation(err))
+        }
+    }
+}
+
+fn crc(key: &[u8], version: u8, value: &[u8]) -> u32 {
+    let crc = checksum::update_field(!0, key);
+    checksum::update(checksum::update(crc, &[version]), value) ^ !0
+}
+
+#[cfg(test)]
+mod tests {
+    use StorageError;
+
+    use super::{FORMAT_VERSION, HEADER_LEN, unwrap, wrap};
+
+    #[test]
+    fn test_local_meta_format() {
+        let stored = wrap(b"peer_id", b"abc");
+        assert_eq!(&stored[..4], b"RSPM");
+        assert_eq!(unwrap(b"peer_id", &stored).unwrap(),
+                   (b"abc".to_vec(), false));
+
+        // damage, or a value under the wrong key, is caught
+        let mut flipped = stored.clone();
+        flipped[HEADER_LEN] ^= 1;
+        match unwrap(b"peer_id", &flipped) {
+            Err(StorageError::Corrupt(_)) => (),
+            r => panic!("flipped bit got through: {:?}", r),
+        }
+        assert!(unwrap(b"peer_port", &stored).is_err());
+        assert!(unwrap(b"peer_id", &stored[..6]).is_err());
+
+        // what came before the format is upgraded, and what comes after it
+        // refused
+        assert_eq!(unwrap(b"peer_port", b"7770").unwrap(),
+                   (b"7770".to_vec(), true));
+        let mut newer = b"RSPM".to_vec();
+        newer.push(FORMAT_VERSION + 1);
+        newer.extend(b"abc");
+        let crc = super::crc(b"peer_id", FORMAT_VERSION + 1, b"abc");
+        newer.extend(&super::u32_to_bytes(crc));
+        match unwrap(b"peer_id", &newer) {
+            Err(StorageError::Operation(e)) => {
+                assert!(e.contains("format version 2"))
+            }
+            r => panic!("newer format was read: {:?}", r),
+        }
+    }
+}
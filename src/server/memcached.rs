@@ -0,0 +1,509 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::str;
+
+use ClientError;
+use client::{Client, key_prefix_end};
+use server::ShutdownHandle;
+use server::expiry::{self, forget, now_ms, put};
+use server::frontend::{self, Conn};
+
+// commands with longer lines or data blocks than these are refused
+// rather than buffered
+const MAX_LINE: usize = 2048;
+const MAX_DATA: usize = 64 << 20;
+const MAX_KEY: usize = 250;
+
+// exptimes past this many seconds are unix times rather than from now
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+// how many times a change to an item is tried before giving up on one
+// others keep changing
+const UPDATE_TRIES: usize = 16;
+
+const REFUSAL: &'static [u8] = b"SERVER_ERROR max number of clients \
+                                  reached\r\n";
+const CONTENDED: &'static [u8] = b"SERVER_ERROR too many other writes to the \
+                                    key\r\n";
+
+// the commands followed by a block of data
+const STORAGE_COMMANDS: &'static [&'static str] = &["set",
+                                                    "add",
+                                                    "replace",
+                                                    "append",
+                                                    "prepend",
+                                                    "cas"];
+
+#[derive(Debug, PartialEq)]
+pub struct Command {
+    pub words: Vec<Vec<u8>>,
+    pub data: Option<Vec<u8>>,
+}
+
+// The command at the front of buf, and how much of buf it takes up, or
+// None if it hasn't all arrived yet.
+pub fn parse(buf: &[u8]) -> Result<Option<(Command, usize)>, String> {
+    let end = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if buf.len() > MAX_LINE => return Err("line too long".to_string()),
+        None => return Ok(None),
+    };
+    let line = if end > 0 && buf[end - 1] == b'\r' {
+        &buf[..end - 1]
+    } else {
+        &buf[..end]
+    };
+    let words: Vec<Vec<u8>> = line.split(|&b| b == b' ')
+                                  .filter(|word| !word.is_empty())
+                                  .map(|word| word.to_vec())
+                                  .collect();
+    let storage = words.first().map_or(false, |name| {
+        STORAGE_COMMANDS.iter().any(|c| c.as_bytes() == &**name)
+    });
+    if !storage {
+        return Ok(Some((Command { words: words, data: None }, end + 1)));
+    }
+    let len = match words.get(4).and_then(|len| number(len)) {
+        Some(len) => len as usize,
+        None => return Err("bad data chunk".to_string()),
+    };
+    if len > MAX_DATA {
+        return Err("object too large for cache".to_string());
+    }
+    let start = end + 1;
+    if buf.len() < start + len + 2 {
+        return Ok(None);
+    }
+    if &buf[start + len..start + len + 2] != b"\r\n" {
+        return Err("bad data chunk".to_string());
+    }
+    let data = buf[start..start + len].to_vec();
+    Ok(Some((Command { words: words, data: Some(data) }, start + len + 2)))
+}
+
+fn number(s: &[u8]) -> Option<u64> {
+    str::from_utf8(s).ok().and_then(|s| s.parse().ok())
+}
+
+// Items are stored as their flags, four bytes big-endian, then their
+// data.
+fn item(flags: u32, data: &[u8]) -> Vec<u8> {
+    let mut item = vec![(flags >> 24) as u8,
+                        (flags >> 16) as u8,
+                        (flags >> 8) as u8,
+                        flags as u8];
+    item.extend_from_slice(data);
+    item
+}
+
+// An item's flags and data.  A value that wasn't stored by us is all data.
+fn unpack(item: &[u8]) -> (u32, &[u8]) {
+    if item.len() < 4 {
+        return (0, item);
+    }
+    let flags = (item[0] as u32) << 24 | (item[1] as u32) << 16 |
+                (item[2] as u32) << 8 | item[3] as u32;
+    (flags, &item[4..])
+}
+
+// When an item stored with exptime expires: never for zero, and otherwise
+// that many seconds from now, or at that unix time for longer than a
+// month.  A negative one has already passed.
+fn deadline(exptime: i64) -> Option<u64> {
+    if exptime == 0 {
+        None
+    } else if exptime < 0 {
+        Some(0)
+    } else if exptime <= MAX_RELATIVE_EXPTIME {
+        Some(now_ms() + exptime as u64 * 1000)
+    } else {
+        Some(exptime as u64 * 1000)
+    }
+}
+
+fn expired(deadline: Option<u64>) -> bool {
+    deadline.map_or(false, |deadline| deadline <= now_ms())
+}
+
+// What came of trying to change an item.
+enum Change {
+    Missing,
+    // the change can't be made to what's there
+    Invalid,
+    // others kept changing it first
+    Contended,
+    Made(Vec<u8>),
+}
+
+// One connection's client, and where its items are kept.
+struct Session {
+    cli: Client,
+    prefix: Vec<u8>,
+}
+
+impl Session {
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed = self.prefix.clone();
+        prefixed.extend_from_slice(key);
+        prefixed
+    }
+
+    // Appends the reply to cmd to out, unless it was asked for none.
+    fn command(&mut self, cmd: Command, out: &mut Vec<u8>) {
+        let noreply = cmd.words
+                         .last()
+                         .map_or(false, |word| word == b"noreply");
+        let reply = match self.run(&cmd) {
+            Ok(reply) => reply,
+            Err(e) => {
+                let e = e.to_string().replace("\r\n", " ");
+                format!("SERVER_ERROR {}\r\n", e).into_bytes()
+            }
+        };
+        let name = &*cmd.words[0];
+        if !noreply || name == b"get" || name == b"gets" {
+            out.extend_from_slice(&reply);
+        }
+    }
+
+    fn run(&mut self, cmd: &Command) -> Result<Vec<u8>, ClientError> {
+        let words = &cmd.words;
+        let bad_format = || {
+            b"CLIENT_ERROR bad command line format\r\n".to_vec()
+        };
+        if words.get(1).map_or(false, |key| key.len() > MAX_KEY) {
+            return Ok(bad_format());
+        }
+        let reply = match (&*String::from_utf8_lossy(&words[0]), words.len()) {
+            ("get", n) if n > 1 => {
+                let mut reply = vec![];
+                for key in &words[1..] {
+                    if key.len() > MAX_KEY {
+                        return Ok(bad_format());
+                    }
+                    let prefixed = self.key(key);
+                    let entry = try!(expiry::entry(&mut self.cli, &prefixed));
+                    if let Some((stored, _)) = entry {
+                        let (flags, data) = unpack(&stored);
+                        let header = format!(" {} {}\r\n", flags, data.len());
+                        reply.extend_from_slice(b"VALUE ");
+                        reply.extend_from_slice(key);
+                        reply.extend_from_slice(header.as_bytes());
+                        reply.extend_from_slice(data);
+                        reply.extend_from_slice(b"\r\n");
+                    }
+                }
+                reply.extend_from_slice(b"END\r\n");
+                reply
+            }
+            (name, n) if n >= 5 &&
+                         (name == "set" || name == "add" ||
+                          name == "replace") => {
+                let flags = str::from_utf8(&words[2])
+                                .ok()
+                                .and_then(|flags| flags.parse().ok());
+                let exptime = str::from_utf8(&words[3])
+                                  .ok()
+                                  .and_then(|t| t.parse().ok());
+                match (flags, exptime) {
+                    (Some(flags), Some(exptime)) => {
+                        let key = self.key(&words[1]);
+                        let item = item(flags, cmd.data.as_ref().unwrap());
+                        try!(self.store(name, &key, &item, deadline(exptime)))
+                    }
+                    _ => bad_format(),
+                }
+            }
+            (name, n) if n >= 5 && (name == "append" || name == "prepend") => {
+                let data = cmd.data.as_ref().unwrap();
+                let key = self.key(&words[1]);
+                let change = try!(self.update(&key, |stored| {
+                    let (flags, old) = unpack(stored);
+                    Some(if name == "append" {
+                        item(flags, &[old, &data[..]].concat())
+                    } else {
+                        item(flags, &[&data[..], old].concat())
+                    })
+                }));
+                match change {
+                    Change::Made(_) => b"STORED\r\n".to_vec(),
+                    Change::Contended => CONTENDED.to_vec(),
+                    _ => b"NOT_STORED\r\n".to_vec(),
+                }
+            }
+            ("delete", n) if n >= 2 => {
+                let key = self.key(&words[1]);
+                if try!(expiry::entry(&mut self.cli, &key)).is_some() {
+                    try!(forget(&mut self.cli, &key));
+                    b"DELETED\r\n".to_vec()
+                } else {
+                    b"NOT_FOUND\r\n".to_vec()
+                }
+            }
+            (name, n) if n >= 3 && (name == "incr" || name == "decr") => {
+                let delta = match number(&words[2]) {
+                    Some(delta) => delta,
+                    None => {
+                        return Ok(b"CLIENT_ERROR invalid numeric delta \
+                                    argument\r\n"
+                                      .to_vec())
+                    }
+                };
+                let key = self.key(&words[1]);
+                let change = try!(self.update(&key, |stored| {
+                    let (flags, old) = unpack(stored);
+                    let old = str::from_utf8(old).ok().and_then(|old| {
+                        old.trim_right().parse::<u64>().ok()
+                    });
+                    // incr wraps around, and decr stops at zero
+                    old.map(|old| if name == "incr" {
+                        old.wrapping_add(delta)
+                    } else {
+                        old.saturating_sub(delta)
+                    })
+                    .map(|new| item(flags, new.to_string().as_bytes()))
+                }));
+                match change {
+                    Change::Made(new) => {
+                        let mut reply = unpack(&new).1.to_vec();
+                        reply.extend_from_slice(b"\r\n");
+                        reply
+                    }
+                    Change::Missing => b"NOT_FOUND\r\n".to_vec(),
+                    Change::Invalid => {
+                        b"CLIENT_ERROR cannot increment or decrement \
+                          non-numeric value\r\n"
+                            .to_vec()
+                    }
+                    Change::Contended => CONTENDED.to_vec(),
+                }
+            }
+            ("touch", n) if n >= 3 => {
+                let exptime = str::from_utf8(&words[2])
+                                  .ok()
+                                  .and_then(|t| t.parse().ok());
+                let exptime = match exptime {
+                    Some(exptime) => exptime,
+                    None => return Ok(bad_format()),
+                };
+                let key = self.key(&words[1]);
+                if try!(expiry::entry(&mut self.cli, &key)).is_none() {
+                    b"NOT_FOUND\r\n".to_vec()
+                } else {
+                    let cli = &mut self.cli;
+                    match deadline(exptime) {
+                        Some(deadline) if expired(Some(deadline)) => {
+                            try!(forget(cli, &key))
+                        }
+                        Some(deadline) => {
+                            try!(expiry::set_deadline(cli, &key, deadline))
+                        }
+                        None => try!(expiry::clear_deadline(cli, &key)),
+                    }
+                    b"TOUCHED\r\n".to_vec()
+                }
+            }
+            ("flush_all", _) => {
+                // leaving their deadlines, which go when the sweeper
+                // finds them past.  Our own keys all start with a zero
+                // byte, and stay.
+                let start = if self.prefix.is_empty() {
+                    vec![1]
+                } else {
+                    self.prefix.clone()
+                };
+                let end = key_prefix_end(&self.prefix)
+                              .unwrap_or(vec![0xff; MAX_KEY + 1]);
+                let res = try!(self.cli.delete_range(&start, &end));
+                if !res.get_success() {
+                    let err = res.get_err().to_string();
+                    return Err(ClientError::Rejected(err));
+                }
+                b"OK\r\n".to_vec()
+            }
+            ("version", _) => {
+                format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"))
+                    .into_bytes()
+            }
+            ("verbosity", _) => b"OK\r\n".to_vec(),
+            _ => b"ERROR\r\n".to_vec(),
+        };
+        Ok(reply)
+    }
+
+    // Stores item under key as set, add and replace do.
+    fn store(&mut self,
+             how: &str,
+             key: &[u8],
+             item: &[u8],
+             deadline: Option<u64>)
+             -> Result<Vec<u8>, ClientError> {
+        let old = try!(expiry::entry(&mut self.cli, key)).map(|(old, _)| old);
+        if how == "add" && old.is_some() || how == "replace" && old.is_none() {
+            return Ok(b"NOT_STORED\r\n".to_vec());
+        }
+        // an item that's already expired is as good as gone
+        if expired(deadline) {
+            if old.is_some() {
+                try!(forget(&mut self.cli, key));
+            }
+            return Ok(b"STORED\r\n".to_vec());
+        }
+        if deadline.is_none() {
+            try!(expiry::clear_deadline(&mut self.cli, key));
+        }
+        let stored = match (how, old) {
+            ("add", _) => try!(self.cli.create(key, item)).get_success(),
+            ("replace", Some(old)) => {
+                try!(self.cli.cas(key, &old, item)).get_success()
+            }
+            _ => {
+                try!(put(&mut self.cli, key, item));
+                true
+            }
+        };
+        if !stored {
+            return Ok(b"NOT_STORED\r\n".to_vec());
+        }
+        if let Some(deadline) = deadline {
+            try!(expiry::set_deadline(&mut self.cli, key, deadline));
+        }
+        Ok(b"STORED\r\n".to_vec())
+    }
+
+    // Swaps in what change makes of the item at key, trying again when
+    // another write gets in first.
+    fn update<F>(&mut self,
+                 key: &[u8],
+                 change: F)
+                 -> Result<Change, ClientError>
+        where F: Fn(&[u8]) -> Option<Vec<u8>>
+    {
+        for _ in 0..UPDATE_TRIES {
+            let old = match try!(expiry::entry(&mut self.cli, key)) {
+                Some((old, _)) => old,
+                None => return Ok(Change::Missing),
+            };
+            let new = match change(&old) {
+                Some(new) => new,
+                None => return Ok(Change::Invalid),
+            };
+            if try!(self.cli.cas(key, &old, &new)).get_success() {
+                return Ok(Change::Made(new));
+            }
+        }
+        Ok(Change::Contended)
+    }
+}
+
+// Serves one connection until it's closed, sends quit, or breaks the
+// protocol.  Pipelined commands are answered together.
+fn converse(mut conn: Conn, cli_addr: SocketAddr, prefix: Vec<u8>) {
+    let mut session = Session {
+        cli: Client::new(vec![cli_addr], 1),
+        prefix: prefix,
+    };
+    let mut buf = vec![];
+    let mut chunk = [0; 16 << 10];
+    loop {
+        let (mut out, mut used, mut done) = (vec![], 0, false);
+        loop {
+            match parse(&buf[used..]) {
+                Ok(Some((cmd, len))) => {
+                    used += len;
+                    if cmd.words.is_empty() {
+                        out.extend_from_slice(b"ERROR\r\n");
+                        continue;
+                    }
+                    if cmd.words[0] == b"quit" {
+                        done = true;
+                        break;
+                    }
+                    session.command(cmd, &mut out);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let reply = format!("CLIENT_ERROR {}\r\n", e);
+                    out.extend_from_slice(reply.as_bytes());
+                    done = true;
+                    break;
+                }
+            }
+        }
+        buf.drain(..used);
+        if !out.is_empty() && conn.write_all(&out).is_err() || done {
+            return;
+        }
+        match conn.read(&mut chunk) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+// Speaks the memcached text protocol on listener until the server shuts
+// down, keeping items under prefix through our client port at cli_addr.
+pub fn serve(listener: TcpListener,
+             cli_addr: SocketAddr,
+             prefix: Vec<u8>,
+             max_conns: usize,
+             shutdown: ShutdownHandle)
+             -> io::Result<()> {
+    frontend::serve("memcached",
+                    listener,
+                    max_conns,
+                    REFUSAL,
+                    shutdown,
+                    move |conn| converse(conn, cli_addr, prefix.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, item, parse, unpack};
+
+    fn words(line: &str) -> Vec<Vec<u8>> {
+        line.split(' ').map(|word| word.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        let buf = b"get a b\r\nset k 5 0 2 noreply\r\nhi\r\ndelete k\n";
+        let (cmd, used) = parse(buf).unwrap().unwrap();
+        assert_eq!(cmd,
+                   Command {
+                       words: words("get a b"),
+                       data: None,
+                   });
+        let (cmd, len) = parse(&buf[used..]).unwrap().unwrap();
+        assert_eq!(cmd,
+                   Command {
+                       words: words("set k 5 0 2 noreply"),
+                       data: Some(b"hi".to_vec()),
+                   });
+        let (cmd, _) = parse(&buf[used + len..]).unwrap().unwrap();
+        assert_eq!(cmd.words, words("delete k"));
+    }
+
+    #[test]
+    fn waits_for_whole_commands() {
+        let buf = b"set k 0 0 2\r\nhi\r\n";
+        for len in 0..buf.len() {
+            assert_eq!(parse(&buf[..len]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn refuses_broken_commands() {
+        assert!(parse(b"set k 0 0 x\r\n").is_err());
+        assert!(parse(b"set k 0 0 1\r\nab\r\n").is_err());
+        assert!(parse(b"set k 0 0 999999999999\r\n").is_err());
+        assert!(parse(&[b'a'; 4096]).is_err());
+    }
+
+    #[test]
+    fn packs_flags_with_data() {
+        assert_eq!(item(0x01020304, b"v"), b"\x01\x02\x03\x04v");
+        assert_eq!(unpack(&item(7, b"data")), (7, &b"data"[..]));
+        assert_eq!(unpack(b"v"), (0, &b"v"[..]));
+    }
+}
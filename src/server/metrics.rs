@@ -0,0 +1,517 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+use time;
+
+use StorageError;
+use server::timing;
+
+// A server's counters, gauges and histograms, rendered in the Prometheus
+// text exposition format by the admin HTTP listener.  Each server has its
+// own, shared through an Arc with the threads that record into it, so
+// several in one process (or test) don't add up into each other.
+// Everything is a plain atomic, so recording a sample from any thread is
+// cheap and never blocks.
+
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter(AtomicUsize::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicIsize);
+
+impl Gauge {
+    pub fn new() -> Gauge {
+        Gauge(AtomicIsize::new(0))
+    }
+
+    pub fn set(&self, v: isize) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> isize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// upper bounds of the latency buckets, in milliseconds
+const LATENCY_BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500,
+                                       1000, 2500, 5000];
+
+pub struct Histogram {
+    buckets: Vec<AtomicUsize>,
+    sum_us: AtomicUsize,
+    count: AtomicUsize,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: LATENCY_BUCKETS_MS.iter()
+                                       .map(|_| AtomicUsize::new(0))
+                                       .collect(),
+            sum_us: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: time::Duration) {
+        let us = elapsed.num_microseconds().unwrap_or(0);
+        let us = if us < 0 { 0 } else { us as u64 };
+        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+            if us <= bound * 1000 {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+        }
+        self.sum_us.fetch_add(us as usize, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // Smallest bucket bound (ms) that at least q of the samples fall
+    // under, or None when nothing has been observed yet.
+    pub fn quantile_ms(&self, q: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let want = (count as f64 * q).ceil() as usize;
+        let mut seen = 0;
+        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+            seen += self.buckets[i].load(Ordering::Relaxed);
+            if seen >= want {
+                return Some(*bound);
+            }
+        }
+        // slower than the largest bucket
+        None
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+            cumulative += self.buckets[i].load(Ordering::Relaxed);
+            writeln!(out,
+                     "{}_bucket{{{}{}le=\"{}\"}} {}",
+                     name,
+                     labels,
+                     sep,
+                     *bound as f64 / 1000.0,
+                     cumulative)
+                .unwrap();
+        }
+        writeln!(out,
+                 "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                 name,
+                 labels,
+                 sep,
+                 self.count())
+            .unwrap();
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        writeln!(out,
+                 "{}_sum{} {}",
+                 name,
+                 braced,
+                 self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0)
+            .unwrap();
+        writeln!(out, "{}_count{} {}", name, braced, self.count()).unwrap();
+    }
+}
+
+pub struct Metrics {
+    pub get_requests: Counter,
+    pub set_requests: Counter,
+    pub cas_requests: Counter,
+    pub del_requests: Counter,
+    pub redirects: Counter,
+    pub auth_failures: Counter,
+    pub read_latency: Histogram,
+    pub write_latency: Histogram,
+    // (op, stage) -> how long client requests spent in it
+    stage_latency: BTreeMap<(&'static str, &'static str), Histogram>,
+    pub peer_messages: Counter,
+    pub replication_lag: Gauge,
+    pub ranges: Gauge,
+    pub mailbox_depth: Gauge,
+    pub pending_writes: Gauge,
+    pub watches: Gauge,
+    pub client_bytes: Counter,
+    pub log_bytes: Counter,
+    pub storage_batches: Counter,
+    pub storage_bytes: Counter,
+    pub wal_syncs: Counter,
+    pub corruptions: Counter,
+    pub scrub_divergent_spans: Counter,
+    pub repaired_keys: Counter,
+    pub rejected_frames: Counter,
+    pub paused_reads: Counter,
+    pub idle_conns: Counter,
+    pub stalled_conns: Counter,
+    pub peer_reconnects: Counter,
+    pub throttled_conns: Counter,
+    pub throttled_requests: Counter,
+    pub shed_requests: Counter,
+    pub config_reloads: Counter,
+    pub clock_offset: Gauge,
+    pub clock_skewed: Gauge,
+    pub disk_free_bytes: Gauge,
+    pub disk_watermark: Gauge,
+    pub disk_full_requests: Counter,
+    pub quota_exceeded_requests: Counter,
+    pub busy_requests: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let stage_latency = timing::OPS
+                                .iter()
+                                .flat_map(|op| {
+                                    timing::STAGES.iter().map(move |stage| {
+                                        ((*op, *stage), Histogram::new())
+                                    })
+                                })
+                                .collect();
+        Metrics {
+            get_requests: Counter::new(),
+            set_requests: Counter::new(),
+            cas_requests: Counter::new(),
+            del_requests: Counter::new(),
+            redirects: Counter::new(),
+            auth_failures: Counter::new(),
+            read_latency: Histogram::new(),
+            write_latency: Histogram::new(),
+            stage_latency: stage_latency,
+            peer_messages: Counter::new(),
+            replication_lag: Gauge::new(),
+            ranges: Gauge::new(),
+            mailbox_depth: Gauge::new(),
+            pending_writes: Gauge::new(),
+            watches: Gauge::new(),
+            client_bytes: Counter::new(),
+            log_bytes: Counter::new(),
+            storage_batches: Counter::new(),
+            storage_bytes: Counter::new(),
+            wal_syncs: Counter::new(),
+            corruptions: Counter::new(),
+            scrub_divergent_spans: Counter::new(),
+            repaired_keys: Counter::new(),
+            rejected_frames: Counter::new(),
+            paused_reads: Counter::new(),
+            idle_conns: Counter::new(),
+            stalled_conns: Counter::new(),
+            peer_reconnects: Counter::new(),
+            throttled_conns: Counter::new(),
+            throttled_requests: Counter::new(),
+            shed_requests: Counter::new(),
+            config_reloads: Counter::new(),
+            clock_offset: Gauge::new(),
+            clock_skewed: Gauge::new(),
+            disk_free_bytes: Gauge::new(),
+            disk_watermark: Gauge::new(),
+            disk_full_requests: Counter::new(),
+            quota_exceeded_requests: Counter::new(),
+            busy_requests: Counter::new(),
+        }
+    }
+
+    // Passes a storage result through, counting it if it turned up a
+    // corruption, for whoever read through to the stored bytes.
+    pub fn checked<T>(&self,
+                      result: Result<T, StorageError>)
+                      -> Result<T, StorageError> {
+        if let Err(StorageError::Corrupt(_)) = result {
+            self.corruptions.inc();
+        }
+        result
+    }
+
+    pub fn stage_latency(&self, op: &str, stage: &str) -> Option<&Histogram> {
+        self.stage_latency
+            .iter()
+            .find(|&(&(o, s), _)| o == op && s == stage)
+            .map(|(_, h)| h)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(&mut out,
+               "rasputin_client_requests_total",
+               "counter",
+               "Client requests received, by operation.");
+        for &(op, ref c) in [("get", &self.get_requests),
+                             ("set", &self.set_requests),
+                             ("cas", &self.cas_requests),
+                             ("del", &self.del_requests)]
+                                .iter() {
+            writeln!(out,
+                     "rasputin_client_requests_total{{op=\"{}\"}} {}",
+                     op,
+                     c.get())
+                .unwrap();
+        }
+        counter(&mut out,
+                "rasputin_client_redirects_total",
+                "Client requests redirected because we are not the leader.",
+                &self.redirects);
+        counter(&mut out,
+                "rasputin_client_auth_failures_total",
+                "Client requests refused for a bad token or missing \
+                 permission.",
+                &self.auth_failures);
+        counter(&mut out,
+                "rasputin_client_throttled_connections_total",
+                "Client connections turned away for being over the connection \
+                 limits.",
+                &self.throttled_conns);
+        counter(&mut out,
+                "rasputin_client_throttled_requests_total",
+                "Client requests refused for being over their address's rate \
+                 limit.",
+                &self.throttled_requests);
+        counter(&mut out,
+                "rasputin_client_shed_requests_total",
+                "Client requests turned away early because we were overloaded.",
+                &self.shed_requests);
+        counter(&mut out,
+                "rasputin_config_reloads_total",
+                "Times the server's config has been reloaded while running.",
+                &self.config_reloads);
+
+        header(&mut out,
+               "rasputin_client_request_duration_seconds",
+               "histogram",
+               "Time from receiving a client request to replying to it.");
+        self.read_latency.render(&mut out,
+                                 "rasputin_client_request_duration_seconds",
+                                 "kind=\"read\"");
+        self.write_latency.render(&mut out,
+                                  "rasputin_client_request_duration_seconds",
+                                  "kind=\"write\"");
+        header(&mut out,
+               "rasputin_client_request_stage_duration_seconds",
+               "histogram",
+               "Time client requests spent being parsed, routed, applied and \
+                replied to.");
+        for (&(op, stage), h) in self.stage_latency.iter() {
+            h.render(&mut out,
+                     "rasputin_client_request_stage_duration_seconds",
+                     &format!("op=\"{}\",stage=\"{}\"", op, stage));
+        }
+
+        counter(&mut out,
+                "rasputin_peer_messages_total",
+                "Messages received from peers.",
+                &self.peer_messages);
+        gauge(&mut out,
+              "rasputin_replication_lag_txids",
+              "How far the furthest behind follower trails the leader's log.",
+              &self.replication_lag);
+        gauge(&mut out,
+              "rasputin_clock_max_offset_ms",
+              "How far the furthest off peer clock is from ours, as of its \
+               last gossip.",
+              &self.clock_offset);
+        gauge(&mut out,
+              "rasputin_clock_skewed",
+              "1 while our clock is too far from a quorum's to trust leader \
+               leases.",
+              &self.clock_skewed);
+        gauge(&mut out,
+              "rasputin_disk_free_bytes",
+              "Space we could still write to on the disk holding our storage.",
+              &self.disk_free_bytes);
+        gauge(&mut out,
+              "rasputin_disk_watermark",
+              "0 below the disk watermarks, 1 past the soft one, 2 past the \
+               hard one.",
+              &self.disk_watermark);
+        counter(&mut out,
+                "rasputin_disk_full_requests_total",
+                "Client writes refused for the disk being past its hard \
+                 watermark.",
+                &self.disk_full_requests);
+        counter(&mut out,
+                "rasputin_quota_exceeded_requests_total",
+                "Client writes refused for the node or a namespace at its \
+                 quota of bytes.",
+                &self.quota_exceeded_requests);
+        counter(&mut out,
+                "rasputin_busy_requests_total",
+                "Client writes answered BUSY for the range being too far \
+                 behind.",
+                &self.busy_requests);
+        gauge(&mut out,
+              "rasputin_ranges",
+              "Ranges hosted by this node.",
+              &self.ranges);
+        gauge(&mut out,
+              "rasputin_mailbox_depth",
+              "Messages waiting to be handled by the server thread.",
+              &self.mailbox_depth);
+        gauge(&mut out,
+              "rasputin_pending_writes",
+              "Client writes waiting to be learned.",
+              &self.pending_writes);
+        gauge(&mut out,
+              "rasputin_watches",
+              "Clients following the changes this node applies.",
+              &self.watches);
+        counter(&mut out,
+                "rasputin_client_bytes_written_total",
+                "Key and value bytes of client writes accepted as leader.",
+                &self.client_bytes);
+        counter(&mut out,
+                "rasputin_log_bytes_appended_total",
+                "Encoded mutation bytes appended to the replication log.",
+                &self.log_bytes);
+        counter(&mut out,
+                "rasputin_storage_write_batches_total",
+                "Write batches applied to RocksDB.",
+                &self.storage_batches);
+        counter(&mut out,
+                "rasputin_storage_bytes_written_total",
+                "Key and value bytes applied to RocksDB.",
+                &self.storage_bytes);
+        counter(&mut out,
+                "rasputin_wal_syncs_total",
+                "Times the RocksDB WAL was synced to disk.",
+                &self.wal_syncs);
+        counter(&mut out,
+                "rasputin_corruptions_detected_total",
+                "Stored values or replicated entries that failed their \
+                 checksum.",
+                &self.corruptions);
+        counter(&mut out,
+                "rasputin_scrub_divergent_spans_total",
+                "Spans where a follower's data didn't match the leader's.",
+                &self.scrub_divergent_spans);
+        counter(&mut out,
+                "rasputin_repaired_keys_total",
+                "Keys rewritten or removed to match the leader after a scrub.",
+                &self.repaired_keys);
+        counter(&mut out,
+                "rasputin_rejected_frames_total",
+                "Frames or streamed messages refused for being over the size \
+                 limit.",
+                &self.rejected_frames);
+        counter(&mut out,
+                "rasputin_paused_reads_total",
+                "Times we stopped reading a connection until the server caught \
+                 up with it.",
+                &self.paused_reads);
+        counter(&mut out,
+                "rasputin_idle_connections_closed_total",
+                "Client connections hung up on for going quiet for \
+                 client_idle_ms.",
+                &self.idle_conns);
+        counter(&mut out,
+                "rasputin_stalled_connections_closed_total",
+                "Connections hung up on for leaving what we sent unread for \
+                 conn_stall_ms.",
+                &self.stalled_conns);
+        counter(&mut out,
+                "rasputin_peer_reconnects_total",
+                "Attempts at reconnecting to a peer we'd lost.",
+                &self.peer_reconnects);
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn counter(out: &mut String, name: &str, help: &str, c: &Counter) {
+    header(out, name, "counter", help);
+    writeln!(out, "{} {}", name, c.get()).unwrap();
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, g: &Gauge) {
+    header(out, name, "gauge", help);
+    writeln!(out, "{} {}", name, g.get()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use time;
+
+    use StorageError;
+    use super::{Counter, Histogram, Metrics};
+
+    #[test]
+    fn test_histogram_buckets() {
+        let h = Histogram::new();
+        assert_eq!(h.quantile_ms(0.5), None);
+        h.observe(time::Duration::microseconds(500));
+        h.observe(time::Duration::milliseconds(3));
+        h.observe(time::Duration::milliseconds(40));
+        h.observe(time::Duration::milliseconds(40));
+        assert_eq!(h.count(), 4);
+        assert_eq!(h.quantile_ms(0.25), Some(1));
+        assert_eq!(h.quantile_ms(0.5), Some(5));
+        assert_eq!(h.quantile_ms(1.0), Some(50));
+
+        let mut out = String::new();
+        h.render(&mut out, "lat", "");
+        assert!(out.contains("lat_bucket{le=\"0.005\"} 2\n"));
+        assert!(out.contains("lat_bucket{le=\"+Inf\"} 4\n"));
+        assert!(out.contains("lat_count 4\n"));
+
+        let c = Counter::new();
+        c.inc();
+        c.add(2);
+        assert_eq!(c.get(), 3);
+    }
+
+    #[test]
+    fn test_checked_counts_corruptions() {
+        let metrics = Metrics::new();
+        let other = Metrics::new();
+        assert!(metrics.checked(Ok::<_, StorageError>(1)).is_ok());
+        let failed: Result<(), _> =
+            Err(StorageError::Operation("io".to_string()));
+        assert!(metrics.checked(failed).is_err());
+        assert_eq!(metrics.corruptions.get(), 0);
+        let corrupt: Result<(), _> =
+            Err(StorageError::Corrupt("bad crc".to_string()));
+        assert!(metrics.checked(corrupt).is_err());
+        assert_eq!(metrics.corruptions.get(), 1);
+        // each server keeps its own
+        assert_eq!(other.corruptions.get(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use rocksdb::DB;
+use time;
+
+use StorageError;
+use client::Client;
+use server::{NodeStatus, ServerMsg, ShutdownHandle};
+use server::checksum::{self, bytes_to_u64};
+use server::ingest;
+use server::lanes::Mailbox;
+
+// A cluster can mirror another, in another datacenter say, by having its
+// leader watch everything the other applies and replicate each change as
+// its own.  Mirrored mutations record the txid they were applied at over
+// there, and applying one writes that txid under OFFSET_KEY in the same
+// batch, so how far we've mirrored is exactly as durable as what we've
+// mirrored.  Whichever of our nodes leads picks up from there.  Promoting
+// the cluster writes PROMOTED_KEY, after which mirroring stops for good
+// and we take writes of our own.
+pub const PREFIX: &'static [u8] = b"\x00mirror/";
+pub const OFFSET_KEY: &'static [u8] = b"\x00mirror/offset";
+pub const PROMOTED_KEY: &'static [u8] = b"\x00mirror/promoted";
+
+// how long to wait before trying the other cluster again
+const RETRY_MS: u64 = 1000;
+
+pub fn is_mirror_key(key: &[u8]) -> bool {
+    key.starts_with(PREFIX)
+}
+
+// The last txid of the other cluster's we've applied.
+pub fn mirrored_txid(db: &DB) -> Result<u64, StorageError> {
+    checksum::get(db, OFFSET_KEY).map(|v| {
+        v.map(|v| bytes_to_u64(&v)).unwrap_or(0)
+    })
+}
+
+pub fn promoted(db: &DB) -> Result<bool, StorageError> {
+    checksum::get(db, PROMOTED_KEY).map(|v| v.is_some())
+}
+
+// Runs until the server shuts down, feeding the server the changes the
+// other cluster applies while we lead and haven't been promoted.
+pub fn follow(source: Vec<SocketAddr>,
+              token: Option<String>,
+              consumer: String,
+              db: Arc<DB>,
+              status: Arc<RwLock<NodeStatus>>,
+              server_tx: Mailbox,
+              shutdown: ShutdownHandle) {
+    let nap = Duration::from_millis(200);
+    while !shutdown.is_shutting_down() {
+        if !should_follow(&db, &status) {
+            thread::sleep(nap);
+            continue;
+        }
+        let from = match mirrored_txid(&db) {
+            Ok(from) => from,
+            Err(e) => {
+                error!("can't tell how far we've mirrored: {}", e);
+                thread::sleep(Duration::from_millis(RETRY_MS));
+                continue;
+            }
+        };
+        let mut cli = Client::new(source.clone(), 1);
+        if let Some(ref token) = token {
+            cli.set_token(token);
+        }
+        let watched = cli.watch(b"", true, Some(from), Some(&consumer));
+        let mut watcher = match watched {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!("can't mirror from txid {}: {}", from, e);
+                thread::sleep(Duration::from_millis(RETRY_MS));
+                continue;
+            }
+        };
+        info!("mirroring from txid {}", from);
+        while !shutdown.is_shutting_down() && should_follow(&db, &status) {
+            let wait = time::Duration::milliseconds(200);
+            let changes = match watcher.next(wait) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    warn!("lost the cluster we mirror after txid {}: {}",
+                          watcher.last_txid(),
+                          e);
+                    break;
+                }
+            };
+            // its own mirroring, if it does any, is none of ours, and
+            // neither are the markers of its bulk loads, whose data never
+            // passes through a watch
+            let changes: Vec<_> = changes.into_iter()
+                                         .filter(|change| {
+                                             let key = change.get_key();
+                                             !is_mirror_key(key) &&
+                                             !ingest::is_ingest_key(key)
+                                         })
+                                         .collect();
+            if changes.is_empty() {
+                continue;
+            }
+            if server_tx.send(ServerMsg::Mirror(changes)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn should_follow(db: &DB, status: &RwLock<NodeStatus>) -> bool {
+    let leading = status.read()
+                        .map(|status| status.role == "leader")
+                        .unwrap_or(false);
+    leading && !promoted(db).unwrap_or(true)
+}
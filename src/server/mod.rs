@@ -1,14 +1,78 @@
 mod server;
+mod builder;
+mod config;
 mod connset;
 mod server_conn;
 mod traffic_cop;
 mod acked_log;
+mod addr;
+mod admission;
+mod amplification;
+mod auth;
+mod backup;
+mod checksum;
+mod discovery;
+mod disk;
+mod embedded;
+mod expiry;
+mod export;
+mod faults;
+mod frontend;
+mod resp;
+mod rest;
+mod scrub;
+mod sequence;
+mod gossip;
+mod phi;
+mod worker_pool;
+mod group_commit;
+mod horizon;
+mod http;
+mod memcached;
+mod ingest;
+mod io_loops;
+mod lanes;
+mod local_meta;
+mod mirror;
+mod signals;
+mod snapshot;
+mod stats;
+mod status;
+mod throttle;
+mod timing;
+mod toml;
+mod tracing;
+mod txn;
+mod update;
+mod watch;
+pub mod metrics;
+pub mod namespace;
 pub mod rocksdb;
 
-pub use server::server::Server;
+pub use server::server::{ReadJob, Server};
+pub use server::builder::{ServerBuilder, ServerHandle, ShutdownHandle};
+pub use server::embedded::Embedded;
+pub use server::config::{CompactionStyle, Compression, ConfigFile, Durability,
+                         ServerConfig};
+pub use server::faults::Faults;
+pub use server::disk::{DiskSpace, Watermark};
 pub use server::connset::ConnSet;
 pub use server::server_conn::ServerConn;
 pub use server::acked_log::{AckedLog, InMemoryLog, LogEntry};
+pub use server::worker_pool::WorkerPool;
+pub use server::horizon::GcHorizon;
+pub use server::metrics::Metrics;
+pub use server::gossip::{Liveness, Membership};
+pub use server::status::NodeStatus;
+pub use server::stats::{StorageStats, Usage};
+pub use server::signals::{reload_on_sighup, shutdown_on_signals};
+pub use server::auth::CREDENTIALS_PREFIX;
+pub use server::namespace::{Access, NamespaceSpec, Namespaces};
+pub use server::backup::{DataHeader, ManifestEntry, read_data, read_manifest};
+pub use server::ingest::convert_for_ingest;
+pub use server::addr::parse_addr;
+pub use server::mirror::mirrored_txid;
+pub use server::tracing::{LogSink, Span, TraceSink, set_trace_sink};
 
 use std::io::{Error, ErrorKind};
 use std::io;
@@ -31,15 +95,26 @@ use protobuf;
 use protobuf::Message;
 use time;
 
+use Mutation;
+
 pub const SERVER_CLIENTS: Token = Token(0);
 pub const SERVER_PEERS: Token = Token(1);
 pub const PEER_BROADCAST: Token = Token(usize::MAX);
+pub const SHUTDOWN: Token = Token(usize::MAX - 1);
+pub const SERVER_ADMIN: Token = Token(usize::MAX - 3);
+pub const DRAIN: Token = Token(usize::MAX - 4);
+// asks the traffic cop to start connecting to the envelope's address
+pub const ADD_PEER: Token = Token(usize::MAX - 5);
+// tells the traffic cop the server's config has been reloaded
+pub const RELOAD: Token = Token(usize::MAX - 6);
 
 lazy_static! {
     pub static ref LEADER_DURATION: time::Duration =
         time::Duration::seconds(12);
     pub static ref LEADER_REFRESH: time::Duration =
         time::Duration::seconds(6);
+    pub static ref PRE_VOTE_DURATION: time::Duration =
+        time::Duration::seconds(2);
 }
 
 pub type TXID = u64;
@@ -62,6 +137,28 @@ impl Clone for Envelope {
     }
 }
 
+// Everything the server can be asked to do.  A server's state is owned by
+// a single thread that drains these in order, so it needs no locking.
+pub enum ServerMsg {
+    Peer(Envelope),
+    // and when it went into the mailbox
+    Cli(Envelope, time::Timespec),
+    // a client connection went away
+    CliClosed(Token),
+    Cron,
+    // stop leading, ahead of being shut down
+    Drain,
+    // switch to what can change of this config while running
+    Reload(ServerConfig),
+    // changes applied by the cluster we mirror, in the order it applied
+    // them
+    Mirror(Vec<Mutation>),
+    // how full our disk is, as the cron thread last found it
+    Disk(DiskSpace),
+    // a worker has taken the snapshot of the db a job asked for
+    Taken,
+}
+
 pub trait SendChannel<M: Send, E> {
     fn send_msg(&self, msg: M) -> E;
 }
@@ -82,6 +179,9 @@ impl<M: Send> SendChannel<M, Result<(), SendError<M>>> for Sender<M> {
 pub struct Peer {
     addr: SocketAddr,
     sock: Option<Token>,
+    // tries at reaching it since it last answered, and when to try next
+    failures: u32,
+    retry_at: time::Timespec,
 }
 
 #[derive(Debug, PartialEq)]
@@ -89,9 +189,14 @@ pub struct RepPeer {
     last_accepted_term: Term,
     last_accepted_txid: TXID,
     max_sent_txid: TXID,
+    max_sent_learned_txid: TXID,
     tok: Token,
     id: PeerID,
     addr: Option<SocketAddr>,
+    // gets the log, but its acks don't count towards learning anything
+    learner: bool,
+    // whether it's answered an append since we started leading
+    heard: bool,
 }
 
 #[derive(Debug, Clone)]
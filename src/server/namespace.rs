@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+
+use rocksdb::{DB, Direction};
+use rustc_serialize::json::{Json, ToJson};
+use time;
+
+use StorageError;
+use client::key_prefix_end;
+use server::checksum;
+use server::stats::Resized;
+use server::throttle::TokenBucket;
+
+// A namespace sets part of the keyspace aside for one application: the
+// keys under its name and a slash, which only its members may touch, and
+// they nothing else, within quotas of its own.  Definitions live in the
+// replicated keyspace like credentials do, the key this prefix followed by
+// the name and the value a NamespaceSpec as JSON, so every node enforces
+// the same ones.  Only root may define them.
+pub const PREFIX: &'static [u8] = b"\x00namespaces/";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NamespaceSpec {
+    // principal -> what it may do with the namespace's keys
+    pub members: BTreeMap<String, Access>,
+    // the most bytes its keys and values may take up, as StorageStats
+    // counts them; zero doesn't limit it
+    pub max_bytes: u64,
+    // requests a second each node serves it, with bursts of up to a
+    // second's worth; zero doesn't limit it
+    pub ops_per_sec: f64,
+}
+
+impl NamespaceSpec {
+    pub fn parse(value: &[u8]) -> Result<NamespaceSpec, String> {
+        let json = try!(String::from_utf8(value.to_vec())
+                            .map_err(|e| e.to_string())
+                            .and_then(|s| {
+                                Json::from_str(&s).map_err(|e| e.to_string())
+                            }));
+        let mut spec = NamespaceSpec::default();
+        if let Some(members) = json.find("members") {
+            let err = "members isn't an object".to_string();
+            let members = try!(members.as_object().ok_or(err));
+            for (principal, access) in members {
+                let access = match access.as_string() {
+                    Some("read") => Access::Read,
+                    Some("write") => Access::Write,
+                    _ => {
+                        return Err(format!("{}'s access isn't read or write",
+                                           principal))
+                    }
+                };
+                spec.members.insert(principal.clone(), access);
+            }
+        }
+        if let Some(max_bytes) = json.find("max_bytes") {
+            let err = "max_bytes isn't a count".to_string();
+            spec.max_bytes = try!(max_bytes.as_u64().ok_or(err));
+        }
+        if let Some(ops_per_sec) = json.find("ops_per_sec") {
+            let err = "ops_per_sec isn't a number".to_string();
+            spec.ops_per_sec = try!(ops_per_sec.as_f64().ok_or(err));
+        }
+        Ok(spec)
+    }
+}
+
+impl ToJson for NamespaceSpec {
+    fn to_json(&self) -> Json {
+        let members = self.members
+                          .iter()
+                          .map(|(principal, access)| {
+                              let access = match *access {
+                                  Access::Read => "read",
+                                  Access::Write => "write",
+                              };
+                              (principal.clone(), access.to_json())
+                          })
+                          .collect();
+        let mut o = BTreeMap::new();
+        o.insert("members".to_string(), Json::Object(members));
+        o.insert("max_bytes".to_string(), self.max_bytes.to_json());
+        o.insert("ops_per_sec".to_string(), self.ops_per_sec.to_json());
+        Json::Object(o)
+    }
+}
+
+// Why a namespace can't be called name, if it can't.
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        Err("a namespace needs a name".to_string())
+    } else if name.contains('/') {
+        Err(format!("namespace {} has a slash in its name", name))
+    } else if name.starts_with('\x00') {
+        Err(format!("namespace {:?} starts with a reserved byte", name))
+    } else {
+        Ok(())
+    }
+}
+
+// where a namespace's definition is kept
+pub fn spec_key(name: &str) -> Vec<u8> {
+    let mut key = PREFIX.to_vec();
+    key.extend_from_slice(name.as_bytes());
+    key
+}
+
+// what every key in a namespace starts with
+pub fn key_prefix(name: &str) -> Vec<u8> {
+    let mut prefix = name.as_bytes().to_vec();
+    prefix.push(b'/');
+    prefix
+}
+
+pub fn is_namespace_key(key: &[u8]) -> bool {
+    key.starts_with(PREFIX)
+}
+
+// Whether the keys from start up to end, or on past every key if there's
+// no end, take in any of the ones from prefix up to prefix_end.
+fn overlaps(start: &[u8],
+            end: Option<&[u8]>,
+            prefix: &[u8],
+            prefix_end: &[u8])
+            -> bool {
+    start < prefix_end && end.map_or(true, |end| prefix < end)
+}
+
+struct Namespace {
+    spec: NamespaceSpec,
+    prefix: Vec<u8>,
+    prefix_end: Vec<u8>,
+    live_bytes: u64,
+    ops: Option<TokenBucket>,
+}
+
+// Every namespace defined as of what this node has applied, with how much
+// each holds, which is kept up to date the same way StorageStats is.
+// Kept by the server thread, which reloads it whenever a definition
+// changes.
+pub struct Namespaces {
+    namespaces: BTreeMap<String, Namespace>,
+}
+
+impl Namespaces {
+    pub fn new() -> Namespaces {
+        Namespaces { namespaces: BTreeMap::new() }
+    }
+
+    // Reads every definition from the db, and measures how much each
+    // namespace holds.  A definition that doesn't parse is left out, so
+    // its keys are anyone's until it's fixed, and logged.
+    pub fn load(db: &DB) -> Result<Namespaces, StorageError> {
+        let mut namespaces = Namespaces::new();
+        let end = key_prefix_end(PREFIX).unwrap();
+        let mut defined = vec![];
+        for (k, _) in db.iterator().from(PREFIX, Direction::forward) {
+            if &*k >= &end[..] {
+                break;
+            }
+            defined.push(k.to_vec());
+        }
+        for key in defined {
+            let name = String::from_utf8_lossy(&key[PREFIX.len()..])
+                           .into_owned();
+            let value = match try!(checksum::get(db, &key)) {
+                Some(value) => value,
+                None => continue,
+            };
+            match NamespaceSpec::parse(&value) {
+                Ok(spec) => namespaces.define(&name, spec),
+                Err(e) => {
+                    error!("namespace {} has a bad definition: {}", name, e)
+                }
+            }
+        }
+        for (_, namespace) in namespaces.namespaces.iter_mut() {
+            let mut iter = db.iterator();
+            for (k, v) in iter.from(&namespace.prefix, Direction::forward) {
+                if &*k >= &namespace.prefix_end[..] {
+                    break;
+                }
+                namespace.live_bytes += (k.len() + v.len()) as u64;
+            }
+        }
+        Ok(namespaces)
+    }
+
+    fn define(&mut self, name: &str, spec: NamespaceSpec) {
+        let prefix = key_prefix(name);
+        let ops = if spec.ops_per_sec > 0.0 {
+            Some(TokenBucket::new(spec.ops_per_sec, time::get_time()))
+        } else {
+            None
+        };
+        self.namespaces.insert(name.to_string(),
+                               Namespace {
+                                   spec: spec,
+                                   prefix_end: key_prefix_end(&prefix).unwrap(),
+                                   prefix: prefix,
+                                   live_bytes: 0,
+                                   ops: ops,
+                               });
+    }
+
+    // the namespace key is in, if any
+    pub fn of(&self, key: &[u8]) -> Option<&str> {
+        self.namespaces
+            .iter()
+            .find(|&(_, namespace)| key.starts_with(&namespace.prefix))
+            .map(|(name, _)| &name[..])
+    }
+
+    // Whether principal may touch the keys from start up to end, or on
+    // past every key if there's no end: all of them must be in a
+    // namespace it's a member of, with write access to change them, and
+    // if it's a member of none, none of them may be in any.
+    pub fn permits(&self,
+                   principal: &str,
+                   start: &[u8],
+                   end: Option<&[u8]>,
+                   write: bool)
+                   -> bool {
+        let membership = self.namespaces
+                             .values()
+                             .filter_map(|namespace| {
+                                 namespace.spec
+                                          .members
+                                          .get(principal)
+                                          .map(|access| (namespace, *access))
+                             })
+                             .collect::<Vec<_>>();
+        if membership.is_empty() {
+            return !self.namespaces
+                        .values()
+                        .any(|namespace| {
+                            overlaps(start,
+                                     end,
+                                     &namespace.prefix,
+                                     &namespace.prefix_end)
+                        });
+        }
+        membership.iter().any(|&(namespace, access)| {
+            start >= &namespace.prefix[..] &&
+            end.map_or(false, |end| end <= &namespace.prefix_end[..]) &&
+            (access == Access::Write || !write)
+        })
+    }
+
+    // Spends one of name's requests this second, if it has one left.
+    pub fn admit(&mut self, name: &str, now: time::Timespec) -> bool {
+        let ops = self.namespaces
+                      .get_mut(name)
+                      .and_then(|namespace| namespace.ops.as_mut());
+        match ops {
+            Some(ops) => ops.take(now),
+            None => true,
+        }
+    }
+
+    // Whether name holds as much as its quota lets it already.
+    pub fn full(&self, name: &str) -> bool {
+        self.namespaces.get(name).map_or(false, |namespace| {
+            namespace.spec.max_bytes > 0 &&
+            namespace.live_bytes >= namespace.spec.max_bytes
+        })
+    }
+
+    pub fn spec(&self, name: &str) -> Option<&NamespaceSpec> {
+        self.namespaces.get(name).map(|namespace| &namespace.spec)
+    }
+
+    // What each namespace holds, and its quotas, for the stats report.
+    pub fn report(&self) -> Json {
+        let namespaces = self.namespaces
+                             .iter()
+                             .map(|(name, namespace)| {
+                                 let mut o = BTreeMap::new();
+                                 o.insert("live_bytes".to_string(),
+                                          namespace.live_bytes.to_json());
+                                 o.insert("max_bytes".to_string(),
+                                          namespace.spec.max_bytes.to_json());
+                                 o.insert("ops_per_sec".to_string(),
+                                          namespace.spec.ops_per_sec.to_json());
+                                 (name.clone(), Json::Object(o))
+                             })
+                             .collect();
+        Json::Object(namespaces)
+    }
+
+    pub fn resized(&mut self, resized: &Resized) {
+        for namespace in self.namespaces.values_mut() {
+            if resized.key.starts_with(&namespace.prefix) {
+                let live_bytes = namespace.live_bytes as i64 + resized.bytes;
+                namespace.live_bytes = live_bytes.max(0) as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rustc_serialize::json::ToJson;
+    use time;
+
+    use super::{Access, NamespaceSpec, Namespaces, check_name,
+                is_namespace_key};
+    use server::stats::Resized;
+
+    #[test]
+    fn test_namespaces() {
+        let mut members = BTreeMap::new();
+        members.insert("alice".to_string(), Access::Write);
+        members.insert("bob".to_string(), Access::Read);
+        let spec = NamespaceSpec {
+            members: members,
+            max_bytes: 10,
+            ops_per_sec: 2.0,
+        };
+        let json = spec.to_json().to_string();
+        assert_eq!(NamespaceSpec::parse(json.as_bytes()), Ok(spec.clone()));
+        let bad = b"{\"members\": {\"eve\": \"all\"}}";
+        assert!(NamespaceSpec::parse(bad).is_err());
+        assert!(check_name("app").is_ok());
+        assert!(check_name("a/b").is_err());
+        assert!(check_name("").is_err());
+        assert!(is_namespace_key(b"\x00namespaces/app"));
+
+        let mut namespaces = Namespaces::new();
+        namespaces.define("app", spec);
+        assert_eq!(namespaces.of(b"app/k"), Some("app"));
+        assert_eq!(namespaces.of(b"apple"), None);
+
+        // members stay in their namespace, and only writers change it
+        assert!(namespaces.permits("alice",
+                                   b"app/k",
+                                   Some(b"app/k\x00"),
+                                   true));
+        assert!(namespaces.permits("bob", b"app/k", Some(b"app/k\x00"), false));
+        assert!(!namespaces.permits("bob", b"app/k", Some(b"app/k\x00"), true));
+        assert!(!namespaces.permits("alice", b"k", Some(b"k\x00"), false));
+        assert!(!namespaces.permits("alice", b"app/", None, false));
+        // and everyone else stays out of it
+        assert!(namespaces.permits("carol", b"k", Some(b"k\x00"), true));
+        assert!(!namespaces.permits("carol",
+                                    b"app/k",
+                                    Some(b"app/k\x00"),
+                                    false));
+        assert!(!namespaces.permits("carol", b"", None, false));
+        assert!(namespaces.permits("carol", b"apq", None, false));
+
+        let now = time::get_time();
+        assert!(namespaces.admit("app", now));
+        assert!(namespaces.admit("app", now));
+        assert!(!namespaces.admit("app", now));
+        assert!(namespaces.admit("other", now));
+
+        assert!(!namespaces.full("app"));
+        namespaces.resized(&Resized {
+            key: b"app/k".to_vec(),
+            bytes: 10,
+            keys: 1,
+        });
+        assert!(namespaces.full("app"));
+        let report = namespaces.report();
+        let live_bytes = report.find_path(&["app", "live_bytes"]);
+        assert_eq!(live_bytes.and_then(|b| b.as_u64()), Some(10));
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::ops::Sub;
+
+use time;
+
+// inter-arrival times kept to estimate the next one from
+const WINDOW: usize = 100;
+// a perfectly regular sender would otherwise make the smallest delay
+// look like a failure
+const MIN_STD_DEV_MS: f64 = 100.0;
+// what we assume about a member's heartbeats before we've seen two
+const FIRST_INTERVAL_MS: f64 = 1000.0;
+
+// A phi-accrual failure detector, as in Hayashibara et al.  Rather than
+// deciding alive or dead after a fixed timeout, it learns how regularly
+// a member's heartbeats arrive and says how unlikely the current silence
+// is: phi is -log10 of the chance that a heartbeat is merely late, so a
+// phi of 8 means about a one in 10^8 chance we're wrong to suspect it.
+#[derive(Debug, Clone)]
+pub struct PhiAccrual {
+    intervals: VecDeque<f64>,
+    last: Option<time::Timespec>,
+}
+
+impl PhiAccrual {
+    pub fn new() -> PhiAccrual {
+        PhiAccrual {
+            intervals: VecDeque::new(),
+            last: None,
+        }
+    }
+
+    pub fn heartbeat(&mut self, now: time::Timespec) {
+        if let Some(last) = self.last {
+            let interval = now.sub(last).num_milliseconds() as f64;
+            if self.intervals.len() == WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(interval);
+        }
+        self.last = Some(now);
+    }
+
+    pub fn phi(&self, now: time::Timespec) -> f64 {
+        let last = match self.last {
+            Some(last) => last,
+            None => return 0.0,
+        };
+        let (mean, std_dev) = if self.intervals.is_empty() {
+            (FIRST_INTERVAL_MS, FIRST_INTERVAL_MS / 4.0)
+        } else {
+            let n = self.intervals.len() as f64;
+            let mean = self.intervals.iter().fold(0.0, |sum, i| sum + i) / n;
+            let squares = self.intervals
+                              .iter()
+                              .map(|i| (i - mean) * (i - mean))
+                              .fold(0.0, |sum, square| sum + square);
+            let variance = squares / n;
+            (mean, variance.sqrt().max(MIN_STD_DEV_MS))
+        };
+        let silent = now.sub(last).num_milliseconds() as f64;
+
+        // the normal distribution's tail, by a logistic approximation
+        let y = (silent - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if silent > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Add;
+
+    use time;
+
+    use super::PhiAccrual;
+
+    #[test]
+    fn test_phi_grows_with_silence() {
+        let start = time::get_time();
+        let mut detector = PhiAccrual::new();
+        assert_eq!(detector.phi(start), 0.0);
+
+        let mut now = start;
+        for _ in 0..20 {
+            detector.heartbeat(now);
+            now = now.add(time::Duration::milliseconds(500));
+        }
+        let last = now.add(time::Duration::milliseconds(-500));
+
+        // a heartbeat that's a little late is nothing to worry about
+        let late = detector.phi(last.add(time::Duration::milliseconds(600)));
+        assert!(late < 1.0);
+        // but a few missed in a row is
+        let gone = detector.phi(last.add(time::Duration::milliseconds(2000)));
+        assert!(gone > 8.0);
+        assert!(gone > late);
+    }
+}
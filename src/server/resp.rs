@@ -0,0 +1,683 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::str;
+use std::usize;
+
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use ClientError;
+use client::Client;
+use server::ShutdownHandle;
+use server::expiry::{self, forget, now_ms, put};
+use server::frontend::{self, Conn};
+
+// bulk strings and commands past these are refused rather than buffered
+const MAX_BULK: usize = 64 << 20;
+const MAX_ARGS: usize = 1 << 20;
+const MAX_INLINE: usize = 64 << 10;
+
+// how many times INCR tries to swap in its sum before giving up on a key
+// others keep changing
+const INCR_TRIES: usize = 16;
+
+const REFUSAL: &'static [u8] = b"-ERR max number of clients reached\r\n";
+
+// Each command we know, with the fewest and most arguments it takes,
+// counting its name.  QUIT is handled by the connection.
+const COMMANDS: &'static [(&'static str, usize, usize)] = &[
+    ("auth", 2, 3),
+    ("command", 1, usize::MAX),
+    ("decr", 2, 2),
+    ("decrby", 3, 3),
+    ("del", 2, usize::MAX),
+    ("discard", 1, 1),
+    ("echo", 2, 2),
+    ("exec", 1, 1),
+    ("exists", 2, usize::MAX),
+    ("expire", 3, 3),
+    ("get", 2, 2),
+    ("incr", 2, 2),
+    ("incrby", 3, 3),
+    ("multi", 1, 1),
+    ("persist", 2, 2),
+    ("pexpire", 3, 3),
+    ("ping", 1, 2),
+    ("pttl", 2, 2),
+    ("scan", 2, usize::MAX),
+    ("select", 2, 2),
+    ("set", 3, usize::MAX),
+    ("ttl", 2, 2)];
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Status(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Reply::Status(status) => {
+                out.push(b'+');
+                out.extend_from_slice(status.as_bytes());
+            }
+            Reply::Error(ref err) => {
+                out.push(b'-');
+                // an error is a line of its own
+                out.extend(err.bytes().map(|b| {
+                    if b == b'\r' || b == b'\n' { b' ' } else { b }
+                }));
+            }
+            Reply::Int(n) => {
+                out.extend_from_slice(format!(":{}", n).as_bytes())
+            }
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1"),
+            Reply::Bulk(Some(ref value)) => {
+                let header = format!("${}\r\n", value.len());
+                out.extend_from_slice(header.as_bytes());
+                out.extend_from_slice(value);
+            }
+            Reply::Array(ref replies) => {
+                let header = format!("*{}\r\n", replies.len());
+                out.extend_from_slice(header.as_bytes());
+                for reply in replies {
+                    reply.encode(out);
+                }
+                return;
+            }
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+fn ok() -> Reply {
+    Reply::Status("OK")
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+fn not_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_string())
+}
+
+fn integer(s: &[u8]) -> Option<i64> {
+    str::from_utf8(s).ok().and_then(|s| s.parse().ok())
+}
+
+// The length on the line at pos, which starts with kind, and where the
+// line after it starts.
+fn header(buf: &[u8],
+          pos: usize,
+          kind: u8)
+          -> Result<Option<(i64, usize)>, String> {
+    if pos >= buf.len() {
+        return Ok(None);
+    }
+    if buf[pos] != kind {
+        return Err(format!("expected '{}', got '{}'",
+                           kind as char,
+                           buf[pos] as char));
+    }
+    let what = if kind == b'*' { "multibulk" } else { "bulk" };
+    match buf[pos..].windows(2).position(|w| w == b"\r\n") {
+        Some(end) => {
+            match integer(&buf[pos + 1..pos + end]) {
+                Some(n) => Ok(Some((n, pos + end + 2))),
+                None => Err(format!("invalid {} length", what)),
+            }
+        }
+        // no length takes this many digits
+        None if buf.len() - pos > 32 => Err(format!("invalid {} length", what)),
+        None => Ok(None),
+    }
+}
+
+// The command at the front of buf, and how much of buf it takes up, or
+// None if it hasn't all arrived yet.  Commands come as arrays of bulk
+// strings, or inline, as words on a line of their own the way they're
+// typed into telnet.  A command of no words at all is empty.
+pub fn parse(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>, String> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        return match buf.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                let args = buf[..end]
+                               .split(|b| b" \t\r".contains(b))
+                               .filter(|word| !word.is_empty())
+                               .map(|word| word.to_vec())
+                               .collect();
+                Ok(Some((args, end + 1)))
+            }
+            None if buf.len() > MAX_INLINE => {
+                Err("too big inline request".to_string())
+            }
+            None => Ok(None),
+        };
+    }
+    let (n, mut pos) = match try!(header(buf, 0, b'*')) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    if n <= 0 {
+        return Ok(Some((vec![], pos)));
+    }
+    if n as usize > MAX_ARGS {
+        return Err("invalid multibulk length".to_string());
+    }
+    let mut args = vec![];
+    for _ in 0..n {
+        let (len, start) = match try!(header(buf, pos, b'$')) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if len < 0 || len as usize > MAX_BULK {
+            return Err("invalid bulk length".to_string());
+        }
+        let end = start + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err("expected CRLF after bulk string".to_string());
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+// Whether key matches pattern, where * stands for any run of bytes, ? for
+// any one, and \ takes the byte after it literally.
+pub fn glob(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((&b'*', mut rest)) => {
+            while rest.first() == Some(&b'*') {
+                rest = &rest[1..];
+            }
+            (0..key.len() + 1).any(|i| glob(rest, &key[i..]))
+        }
+        Some((&b'?', rest)) => !key.is_empty() && glob(rest, &key[1..]),
+        Some((&b'\\', rest)) if !rest.is_empty() => {
+            key.first() == Some(&rest[0]) && glob(&rest[1..], &key[1..])
+        }
+        Some((&b, rest)) => key.first() == Some(&b) && glob(rest, &key[1..]),
+    }
+}
+
+// One connection's client, and the commands it's queued since MULTI.
+// Every session has a client of its own, since AUTH changes the token its
+// connections present.
+struct Session {
+    cli: Client,
+    queued: Option<Vec<Vec<Vec<u8>>>>,
+}
+
+impl Session {
+    fn new(cli_addr: SocketAddr) -> Session {
+        Session {
+            cli: Client::new(vec![cli_addr], 1),
+            queued: None,
+        }
+    }
+
+    fn command(&mut self, args: Vec<Vec<u8>>) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+        match COMMANDS.iter().find(|&&(known, _, _)| known == name) {
+            None => {
+                return Reply::Error(format!("ERR unknown command '{}'", name))
+            }
+            Some(&(_, min, max)) if args.len() < min || args.len() > max => {
+                return Reply::Error(format!("ERR wrong number of arguments \
+                                             for '{}' command",
+                                            name));
+            }
+            Some(_) => (),
+        }
+        if name != "exec" && name != "discard" && name != "multi" {
+            if let Some(ref mut queued) = self.queued {
+                queued.push(args);
+                return Reply::Status("QUEUED");
+            }
+        }
+        match self.run(&name, &args) {
+            Ok(reply) => reply,
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+
+    fn run(&mut self,
+           name: &str,
+           args: &[Vec<u8>])
+           -> Result<Reply, ClientError> {
+        let reply = match name {
+            "ping" if args.len() == 2 => Reply::Bulk(Some(args[1].clone())),
+            "ping" => Reply::Status("PONG"),
+            "echo" => Reply::Bulk(Some(args[1].clone())),
+            "select" if args[1] == b"0" => ok(),
+            "select" => {
+                Reply::Error("ERR DB index is out of range".to_string())
+            }
+            "auth" => {
+                // the token is the password, whatever the username
+                let token = String::from_utf8_lossy(&args[args.len() - 1])
+                                .into_owned();
+                self.cli.set_token(&token);
+                // a token that's refused stays set, failing what comes
+                // after it until it's replaced
+                match expiry::lookup(&mut self.cli, expiry::PREFIX) {
+                    Ok(_) => ok(),
+                    Err(ClientError::Rejected(_)) => {
+                        Reply::Error("WRONGPASS invalid username-password pair"
+                                         .to_string())
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            // clients that ask what we support can find out by trying
+            "command" => Reply::Array(vec![]),
+            "get" => Reply::Bulk(try!(self.value(&args[1]))),
+            "set" => try!(self.set(args)),
+            "del" => {
+                let mut deleted = 0;
+                for key in &args[1..] {
+                    if try!(self.value(key)).is_some() {
+                        try!(forget(&mut self.cli, key));
+                        deleted += 1;
+                    }
+                }
+                Reply::Int(deleted)
+            }
+            "exists" => {
+                let mut found = 0;
+                for key in &args[1..] {
+                    if try!(self.value(key)).is_some() {
+                        found += 1;
+                    }
+                }
+                Reply::Int(found)
+            }
+            "incr" => try!(self.incr_by(&args[1], Some(1))),
+            "decr" => try!(self.incr_by(&args[1], Some(-1))),
+            "incrby" => try!(self.incr_by(&args[1], integer(&args[2]))),
+            "decrby" => {
+                let delta = integer(&args[2]).and_then(|n| n.checked_neg());
+                try!(self.incr_by(&args[1], delta))
+            }
+            "expire" => try!(self.expire(&args[1], &args[2], 1000)),
+            "pexpire" => try!(self.expire(&args[1], &args[2], 1)),
+            "ttl" => try!(self.ttl(&args[1], 1000)),
+            "pttl" => try!(self.ttl(&args[1], 1)),
+            "persist" => {
+                match try!(self.entry(&args[1])) {
+                    Some((_, Some(_))) => {
+                        try!(expiry::clear_deadline(&mut self.cli, &args[1]));
+                        Reply::Int(1)
+                    }
+                    _ => Reply::Int(0),
+                }
+            }
+            "scan" => try!(self.scan(args)),
+            "multi" if self.queued.is_some() => {
+                Reply::Error("ERR MULTI calls can not be nested".to_string())
+            }
+            "multi" => {
+                self.queued = Some(vec![]);
+                ok()
+            }
+            // the queued commands are run in order, but others' may land
+            // in between them
+            "exec" => {
+                match self.queued.take() {
+                    Some(queued) => {
+                        Reply::Array(queued.into_iter()
+                                           .map(|args| self.command(args))
+                                           .collect())
+                    }
+                    None => Reply::Error("ERR EXEC without MULTI".to_string()),
+                }
+            }
+            "discard" => {
+                match self.queued.take() {
+                    Some(_) => ok(),
+                    None => {
+                        Reply::Error("ERR DISCARD without MULTI".to_string())
+                    }
+                }
+            }
+            _ => Reply::Error(format!("ERR unknown command '{}'", name)),
+        };
+        Ok(reply)
+    }
+
+    fn entry(&mut self,
+             key: &[u8])
+             -> Result<Option<(Vec<u8>, Option<u64>)>, ClientError> {
+        expiry::entry(&mut self.cli, key)
+    }
+
+    fn value(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, ClientError> {
+        self.entry(key).map(|entry| entry.map(|(value, _)| value))
+    }
+
+    fn set(&mut self, args: &[Vec<u8>]) -> Result<Reply, ClientError> {
+        let (key, value) = (&args[1], &args[2]);
+        let (mut nx, mut xx, mut ttl_ms) = (false, false, None);
+        let mut i = 3;
+        while i < args.len() {
+            let option = String::from_utf8_lossy(&args[i]).to_lowercase();
+            match &*option {
+                "nx" => nx = true,
+                "xx" => xx = true,
+                "ex" | "px" if i + 1 < args.len() => {
+                    let unit = if option == "ex" { 1000 } else { 1 };
+                    let ms = integer(&args[i + 1])
+                                 .and_then(|n| n.checked_mul(unit));
+                    match ms {
+                        Some(ms) if ms > 0 => ttl_ms = Some(ms as u64),
+                        _ => {
+                            return Ok(Reply::Error("ERR invalid expire time in \
+                                                    'set' command"
+                                                       .to_string()))
+                        }
+                    }
+                    i += 1;
+                }
+                _ => return Ok(syntax_error()),
+            }
+            i += 1;
+        }
+        if nx && xx {
+            return Ok(syntax_error());
+        }
+        if nx || xx {
+            let exists = try!(self.value(key)).is_some();
+            if exists == nx {
+                return Ok(Reply::Bulk(None));
+            }
+        }
+        if ttl_ms.is_none() {
+            try!(expiry::clear_deadline(&mut self.cli, key));
+        }
+        if nx {
+            if !try!(self.cli.create(key, value)).get_success() {
+                return Ok(Reply::Bulk(None));
+            }
+        } else {
+            try!(put(&mut self.cli, key, value));
+        }
+        if let Some(ttl_ms) = ttl_ms {
+            try!(expiry::set_deadline(&mut self.cli, key, now_ms() + ttl_ms));
+        }
+        Ok(ok())
+    }
+
+    // Adds delta to the integer at key, which is taken to be zero if
+    // there's none yet, swapping the sum in so that no other client's
+    // addition is lost.
+    fn incr_by(&mut self,
+               key: &[u8],
+               delta: Option<i64>)
+               -> Result<Reply, ClientError> {
+        let delta = match delta {
+            Some(delta) => delta,
+            None => return Ok(not_integer()),
+        };
+        for _ in 0..INCR_TRIES {
+            let (sum, swapped) = match try!(self.value(key)) {
+                None => {
+                    let new = delta.to_string();
+                    (delta, try!(self.cli.create(key, new.as_bytes())))
+                }
+                Some(old) => {
+                    let sum = match integer(&old) {
+                        Some(n) => n.checked_add(delta),
+                        None => return Ok(not_integer()),
+                    };
+                    let sum = match sum {
+                        Some(sum) => sum,
+                        None => {
+                            return Ok(Reply::Error("ERR increment or decrement \
+                                                    would overflow"
+                                                       .to_string()))
+                        }
+                    };
+                    let new = sum.to_string();
+                    (sum, try!(self.cli.cas(key, &old, new.as_bytes())))
+                }
+            };
+            if swapped.get_success() {
+                return Ok(Reply::Int(sum));
+            }
+        }
+        Ok(Reply::Error("ERR too many other writes to the key".to_string()))
+    }
+
+    fn expire(&mut self,
+              key: &[u8],
+              n: &[u8],
+              unit: i64)
+              -> Result<Reply, ClientError> {
+        let ms = match integer(n).and_then(|n| n.checked_mul(unit)) {
+            Some(ms) => ms,
+            None => return Ok(not_integer()),
+        };
+        if try!(self.value(key)).is_none() {
+            return Ok(Reply::Int(0));
+        }
+        if ms <= 0 {
+            try!(forget(&mut self.cli, key));
+        } else {
+            let deadline = now_ms() + ms as u64;
+            try!(expiry::set_deadline(&mut self.cli, key, deadline));
+        }
+        Ok(Reply::Int(1))
+    }
+
+    fn ttl(&mut self, key: &[u8], unit: u64) -> Result<Reply, ClientError> {
+        let reply = match try!(self.entry(key)) {
+            None => Reply::Int(-2),
+            Some((_, None)) => Reply::Int(-1),
+            Some((_, Some(deadline))) => {
+                let left = deadline.saturating_sub(now_ms());
+                Reply::Int(((left + unit / 2) / unit) as i64)
+            }
+        };
+        Ok(reply)
+    }
+
+    // Returns up to COUNT keys from the cursor on, and where the next SCAN
+    // should pick up, as the hex of the key it stopped at.  Every SCAN
+    // exports from its cursor afresh, so keys set in between are seen if
+    // they're ahead of it.
+    fn scan(&mut self, args: &[Vec<u8>]) -> Result<Reply, ClientError> {
+        let cursor = if args[1] == b"0" {
+            // our own keys, like the deadlines set on others, all start
+            // with a zero byte
+            Some(vec![1])
+        } else {
+            str::from_utf8(&args[1])
+                .ok()
+                .and_then(|cursor| cursor.from_hex().ok())
+        };
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => return Ok(Reply::Error("ERR invalid cursor".to_string())),
+        };
+        let (mut pattern, mut count) = (None, 10);
+        let mut i = 2;
+        while i + 1 < args.len() {
+            match &*String::from_utf8_lossy(&args[i]).to_lowercase() {
+                "match" => pattern = Some(&args[i + 1]),
+                "count" => {
+                    match integer(&args[i + 1]) {
+                        Some(n) if n > 0 => count = n as usize,
+                        _ => return Ok(syntax_error()),
+                    }
+                }
+                _ => return Ok(syntax_error()),
+            }
+            i += 2;
+        }
+        if i != args.len() {
+            return Ok(syntax_error());
+        }
+
+        let mut exporter = try!(self.cli.export(&cursor, None));
+        let (mut keys, mut seen, mut next) = (vec![], 0, None);
+        'batches: while let Some(batch) = try!(exporter.next()) {
+            for record in batch {
+                if seen == count {
+                    next = Some(record.get_key().to_hex());
+                    break 'batches;
+                }
+                seen += 1;
+                let key = record.get_key();
+                if pattern.map_or(true, |pattern| glob(pattern, key)) {
+                    keys.push(Reply::Bulk(Some(record.get_key().to_vec())));
+                }
+            }
+        }
+        let next = next.unwrap_or("0".to_string());
+        Ok(Reply::Array(vec![Reply::Bulk(Some(next.into_bytes())),
+                             Reply::Array(keys)]))
+    }
+}
+
+// Serves one connection until it's closed, sends QUIT, or breaks the
+// protocol.  Pipelined commands are answered together.
+fn converse(mut conn: Conn, cli_addr: SocketAddr) {
+    let mut session = Session::new(cli_addr);
+    let mut buf = vec![];
+    let mut chunk = [0; 16 << 10];
+    loop {
+        let (mut out, mut used, mut done) = (vec![], 0, false);
+        loop {
+            match parse(&buf[used..]) {
+                Ok(Some((args, len))) => {
+                    used += len;
+                    if args.is_empty() {
+                        continue;
+                    }
+                    if args[0].eq_ignore_ascii_case(b"quit") {
+                        ok().encode(&mut out);
+                        done = true;
+                        break;
+                    }
+                    session.command(args).encode(&mut out);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    Reply::Error(format!("ERR Protocol error: {}", e))
+                        .encode(&mut out);
+                    done = true;
+                    break;
+                }
+            }
+        }
+        buf.drain(..used);
+        if !out.is_empty() && conn.write_all(&out).is_err() || done {
+            return;
+        }
+        match conn.read(&mut chunk) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+// Speaks a subset of the Redis protocol on listener until the server
+// shuts down, carrying out each command with requests to our client port
+// at cli_addr.
+pub fn serve(listener: TcpListener,
+             cli_addr: SocketAddr,
+             max_conns: usize,
+             shutdown: ShutdownHandle)
+             -> io::Result<()> {
+    frontend::serve("resp",
+                    listener,
+                    max_conns,
+                    REFUSAL,
+                    shutdown,
+                    move |conn| converse(conn, cli_addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Reply, glob, parse};
+
+    fn args(words: &[&str]) -> Vec<Vec<u8>> {
+        words.iter().map(|word| word.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn parses_arrays_of_bulk_strings() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4\r\nPING\r\n";
+        assert_eq!(parse(buf).unwrap(), Some((args(&["GET", "k"]), 20)));
+        assert_eq!(parse(&buf[20..]).unwrap(), Some((args(&["PING"]), 14)));
+        // a value may hold anything, CRLFs included
+        assert_eq!(parse(b"*1\r\n$4\r\na\r\nb\r\n").unwrap(),
+                   Some((vec![b"a\r\nb".to_vec()], 14)));
+    }
+
+    #[test]
+    fn waits_for_whole_commands() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        for len in 0..buf.len() {
+            assert_eq!(parse(&buf[..len]).unwrap(), None);
+        }
+        assert_eq!(parse(b"GET k").unwrap(), None);
+    }
+
+    #[test]
+    fn parses_inline_commands() {
+        assert_eq!(parse(b"SET  k v\r\nGET k\r\n").unwrap(),
+                   Some((args(&["SET", "k", "v"]), 10)));
+        assert_eq!(parse(b"PING\n").unwrap(), Some((args(&["PING"]), 5)));
+        assert_eq!(parse(b"\r\n").unwrap(), Some((vec![], 2)));
+    }
+
+    #[test]
+    fn refuses_broken_commands() {
+        assert!(parse(b"*x\r\n").is_err());
+        assert!(parse(b"*1\r\n:1\r\n").is_err());
+        assert!(parse(b"*1\r\n$-1\r\n").is_err());
+        assert!(parse(b"*1\r\n$1\r\nab\r\n").is_err());
+        assert!(parse(b"*1\r\n$999999999999\r\n").is_err());
+    }
+
+    #[test]
+    fn encodes_replies() {
+        let mut out = vec![];
+        Reply::Array(vec![Reply::Status("OK"),
+                          Reply::Error("ERR no\r\nway".to_string()),
+                          Reply::Int(-2),
+                          Reply::Bulk(Some(b"v".to_vec())),
+                          Reply::Bulk(None),
+                          Reply::Array(vec![])])
+            .encode(&mut out);
+        let encoded = b"*6\r\n+OK\r\n-ERR no  way\r\n:-2\r\n\
+                        $1\r\nv\r\n$-1\r\n*0\r\n";
+        assert_eq!(&*out, &encoded[..]);
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob(b"user:*", b"user:1"));
+        assert!(glob(b"user:*", b"user:"));
+        assert!(!glob(b"user:*", b"users"));
+        assert!(glob(b"*:?", b"a:b:c"));
+        assert!(!glob(b"*:?", b"a:bc"));
+        assert!(glob(b"**x*", b"x"));
+        assert!(glob(b"a\\*", b"a*"));
+        assert!(!glob(b"a\\*", b"ab"));
+        assert!(glob(b"", b""));
+    }
+}
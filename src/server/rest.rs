@@ -0,0 +1,546 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::str;
+
+use rustc_serialize::base64::{FromBase64, STANDARD, ToBase64};
+use rustc_serialize::json::{Json, ToJson};
+
+use ClientError;
+use client::{Client, key_prefix_end};
+use server::ShutdownHandle;
+use server::frontend::{self, Conn};
+
+// requests with larger heads or bodies than these are refused outright
+const MAX_HEAD_BYTES: usize = 8192;
+const MAX_BODY_BYTES: usize = 64 << 20;
+
+// how many records a scan returns unless asked for fewer, and the most it
+// will return at once
+const SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+// Reads go through the leader unless this header asks for them to be
+// served stale, from what this node has learned, when it can.
+const CONSISTENCY_HEADER: &'static str = "rasputin-consistency";
+
+const REFUSAL: &'static [u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+                                 Content-Length: 0\r\n\
+                                 Connection: close\r\n\r\n";
+
+// Only the default collection exists for now, holding every key.
+const COLLECTIONS: &'static [&'static str] = &["default"];
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    // percent-encoded, as sent
+    pub path: String,
+    pub query: String,
+    // by lowercased name
+    pub headers: BTreeMap<String, String>,
+    pub body: Vec<u8>,
+    // whether the client wants the connection closed after this
+    pub close: bool,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| &**value)
+    }
+
+    // The query parameter name, percent-decoded.
+    fn param(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        for pair in self.query.split('&') {
+            let mut parts = pair.splitn(2, '=');
+            if parts.next() == Some(name) {
+                return unescape(parts.next().unwrap_or(""))
+                           .map(Some)
+                           .ok_or(format!("{} isn't percent-encoded", name));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// The request at the front of buf, and how much of buf it takes up, or
+// None if it hasn't all arrived yet.  A request that can't be served
+// comes back as the status to refuse it with.
+pub fn parse(buf: &[u8])
+             -> Result<Option<(Request, usize)>, (&'static str, String)> {
+    let head_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end + 4,
+        None if buf.len() > MAX_HEAD_BYTES => {
+            return Err(("431 Request Header Fields Too Large",
+                        "request head too large".to_string()))
+        }
+        None => return Ok(None),
+    };
+    let bad = |what: &str| ("400 Bad Request", what.to_string());
+    let head = try!(str::from_utf8(&buf[..head_len])
+                        .map_err(|_| bad("request head isn't UTF-8")));
+    let mut lines = head.split("\r\n");
+    let line: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+    if line.len() != 3 || !line[2].starts_with("HTTP/1.") {
+        return Err(bad("malformed request line"));
+    }
+    let mut headers = BTreeMap::new();
+    for header in lines.filter(|header| !header.is_empty()) {
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap().trim().to_lowercase();
+        let value = try!(parts.next().ok_or(bad("malformed header")))
+                        .trim()
+                        .to_string();
+        headers.insert(name, value);
+    }
+    if headers.contains_key("transfer-encoding") {
+        return Err(("411 Length Required",
+                    "send a Content-Length instead".to_string()));
+    }
+    let body_len = match headers.get("content-length") {
+        Some(len) => {
+            try!(len.parse().map_err(|_| bad("malformed Content-Length")))
+        }
+        None => 0,
+    };
+    if body_len > MAX_BODY_BYTES {
+        return Err(("413 Payload Too Large",
+                    "request body too large".to_string()));
+    }
+    if buf.len() < head_len + body_len {
+        return Ok(None);
+    }
+    let connection = headers.get("connection").map(|c| c.to_lowercase());
+    let close = if line[2] == "HTTP/1.0" {
+        connection != Some("keep-alive".to_string())
+    } else {
+        connection == Some("close".to_string())
+    };
+    let mut target = line[1].splitn(2, '?');
+    let req = Request {
+        method: line[0].to_string(),
+        path: target.next().unwrap().to_string(),
+        query: target.next().unwrap_or("").to_string(),
+        headers: headers,
+        body: buf[head_len..head_len + body_len].to_vec(),
+        close: close,
+    };
+    Ok(Some((req, head_len + body_len)))
+}
+
+// Decodes %XX escapes, and + for a space.
+pub fn unescape(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'%' => {
+                let hex = s.get(i + 1..i + 3)
+                           .and_then(|hex| str::from_utf8(hex).ok());
+                let hex = match hex {
+                    Some(hex) => hex,
+                    None => return None,
+                };
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => out.push(b),
+                    Err(_) => return None,
+                }
+                i += 3;
+                continue;
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    Some(out)
+}
+
+// Escapes all but the characters that never need it, so the result can
+// go anywhere in a URL.
+pub fn escape(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+// Puts bytes in o under name as a string, or base64-encoded under
+// name_base64 if they aren't UTF-8.
+fn insert_bytes(o: &mut BTreeMap<String, Json>, name: &str, bytes: &[u8]) {
+    match str::from_utf8(bytes) {
+        Ok(s) => o.insert(name.to_string(), s.to_json()),
+        Err(_) => {
+            o.insert(format!("{}_base64", name),
+                     bytes.to_base64(STANDARD).to_json())
+        }
+    };
+}
+
+fn error(status: &'static str, err: String) -> (&'static str, Json) {
+    let mut o = BTreeMap::new();
+    o.insert("error".to_string(), err.to_json());
+    (status, Json::Object(o))
+}
+
+fn failed(e: ClientError) -> (&'static str, Json) {
+    let status = match e {
+        ClientError::Rejected(_) => "403 Forbidden",
+        ClientError::Throttled(_) => "429 Too Many Requests",
+        ClientError::DiskFull(_) |
+        ClientError::QuotaExceeded(_) => "507 Insufficient Storage",
+        ClientError::DeadlineExceeded => "504 Gateway Timeout",
+        ClientError::Overloaded(_) |
+        ClientError::Busy(..) |
+        ClientError::Unreachable |
+        ClientError::Consensus(_) => "503 Service Unavailable",
+        ClientError::Protocol(_) | ClientError::Io(_) => "502 Bad Gateway",
+    };
+    error(status, e.to_string())
+}
+
+// A written txid, or the error the write was refused with.
+fn written(success: bool, err: &str, txid: u64) -> (&'static str, Json) {
+    if !success {
+        return failed(ClientError::Rejected(err.to_string()));
+    }
+    let mut o = BTreeMap::new();
+    o.insert("txid".to_string(), txid.to_json());
+    ("200 OK", Json::Object(o))
+}
+
+// The value a PUT's body sets, given as {"value": "..."}, or as
+// {"value_base64": "..."} for one that isn't UTF-8.
+fn body_value(body: &[u8]) -> Result<Vec<u8>, String> {
+    let json = try!(str::from_utf8(body)
+                        .ok()
+                        .and_then(|body| Json::from_str(body).ok())
+                        .ok_or("the body isn't JSON".to_string()));
+    let string = |name| json.find(name).and_then(|value| value.as_string());
+    if let Some(value) = string("value") {
+        return Ok(value.as_bytes().to_vec());
+    }
+    match string("value_base64") {
+        Some(value) => {
+            value.from_base64()
+                 .map_err(|e| format!("bad value_base64: {}", e))
+        }
+        None => {
+            Err("expected an object with a value or value_base64".to_string())
+        }
+    }
+}
+
+// One connection's client.  Every connection has a client of its own,
+// since the token its connections present is taken from the requests.
+struct Session {
+    cli: Client,
+    token: Option<String>,
+}
+
+impl Session {
+    fn new(cli_addr: SocketAddr) -> Session {
+        let mut cli = Client::new(vec![cli_addr], 1);
+        // stale reads are served by this node, if it can
+        cli.set_replicas(vec![cli_addr]);
+        Session {
+            cli: cli,
+            token: None,
+        }
+    }
+
+    fn handle(&mut self, req: &Request) -> (&'static str, Json) {
+        if let Some(auth) = req.header("authorization") {
+            let token = if auth.starts_with("Bearer ") {
+                auth["Bearer ".len()..].trim().to_string()
+            } else {
+                return error("401 Unauthorized",
+                             "expected a bearer token".to_string());
+            };
+            if self.token.as_ref() != Some(&token) {
+                self.cli.set_token(&token);
+                self.token = Some(token);
+            }
+        }
+        let rest = if req.path.starts_with("/v1/collections/") {
+            &req.path["/v1/collections/".len()..]
+        } else {
+            return error("404 Not Found",
+                         format!("no such resource {}", req.path));
+        };
+        let mut parts = rest.splitn(2, '/');
+        let collection = parts.next().unwrap();
+        if !COLLECTIONS.contains(&collection) {
+            return error("404 Not Found",
+                         format!("there's no collection {}; only default \
+                                  exists for now",
+                                 collection));
+        }
+        let stale = match req.header(CONSISTENCY_HEADER) {
+            None | Some("strong") => false,
+            Some("stale") => true,
+            Some(other) => {
+                return error("400 Bad Request",
+                             format!("consistency is strong or stale, not {}",
+                                     other))
+            }
+        };
+        match (&*req.method, parts.next()) {
+            ("GET", Some("keys")) | ("GET", Some("keys/")) => self.scan(req),
+            (method, Some(key)) if key.starts_with("keys/") => {
+                let key = match unescape(&key["keys/".len()..]) {
+                    Some(key) => key,
+                    None => {
+                        return error("400 Bad Request",
+                                     "key isn't percent-encoded".to_string())
+                    }
+                };
+                match method {
+                    "GET" => self.get(&key, stale),
+                    "PUT" => {
+                        let value = match body_value(&req.body) {
+                            Ok(value) => value,
+                            Err(e) => return error("400 Bad Request", e),
+                        };
+                        match self.cli.set(&key, &value) {
+                            Ok(res) => {
+                                written(res.get_success(),
+                                        res.get_err(),
+                                        res.get_txid())
+                            }
+                            Err(e) => failed(e),
+                        }
+                    }
+                    "DELETE" => {
+                        match self.cli.del(&key) {
+                            Ok(res) => {
+                                written(res.get_success(),
+                                        res.get_err(),
+                                        res.get_txid())
+                            }
+                            Err(e) => failed(e),
+                        }
+                    }
+                    _ => {
+                        error("405 Method Not Allowed",
+                              format!("keys can't be {}", method))
+                    }
+                }
+            }
+            _ => {
+                error("404 Not Found",
+                      format!("no such resource {}", req.path))
+            }
+        }
+    }
+
+    fn get(&mut self, key: &[u8], stale: bool) -> (&'static str, Json) {
+        let res = if stale {
+            self.cli.get_stale(key)
+        } else {
+            self.cli.get(key)
+        };
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => return failed(e),
+        };
+        if !res.get_success() {
+            return error("404 Not Found", res.get_err().to_string());
+        }
+        let mut o = BTreeMap::new();
+        insert_bytes(&mut o, "key", key);
+        insert_bytes(&mut o, "value", res.get_value());
+        o.insert("txid".to_string(), res.get_txid().to_json());
+        ("200 OK", Json::Object(o))
+    }
+
+    // Records from start, or from those with prefix, up to limit of them,
+    // and where to start the next scan if it stopped short.
+    fn scan(&mut self, req: &Request) -> (&'static str, Json) {
+        let params: Result<Vec<_>, _> = ["prefix", "start", "end", "limit"]
+                                            .iter()
+                                            .map(|name| req.param(name))
+                                            .collect();
+        let mut params = match params {
+            Ok(params) => params,
+            Err(e) => return error("400 Bad Request", e),
+        };
+        let (mut start, mut end) = (params[1].take(), params[2].take());
+        if let Some(prefix) = params[0].take() {
+            if start.as_ref().map_or(true, |start| *start < prefix) {
+                start = Some(prefix.clone());
+            }
+            let prefix_end = key_prefix_end(&prefix);
+            if end.is_none() || prefix_end.is_some() && prefix_end < end {
+                end = prefix_end;
+            }
+        }
+        let limit = match params[3].take() {
+            Some(limit) => {
+                let limit = str::from_utf8(&limit)
+                                .ok()
+                                .and_then(|limit| limit.parse().ok());
+                match limit {
+                    Some(limit) if limit > 0 && limit <= MAX_SCAN_LIMIT => {
+                        limit
+                    }
+                    _ => {
+                        return error("400 Bad Request",
+                                     format!("limit must be from 1 to {}",
+                                             MAX_SCAN_LIMIT))
+                    }
+                }
+            }
+            None => SCAN_LIMIT,
+        };
+        // our own keys all start with a zero byte
+        let start = start.unwrap_or(vec![1]);
+        match self.export(&start, end.as_ref().map(|end| &**end), limit) {
+            Ok(scanned) => ("200 OK", scanned),
+            Err(e) => failed(e),
+        }
+    }
+
+    fn export(&mut self,
+              start: &[u8],
+              end: Option<&[u8]>,
+              limit: usize)
+              -> Result<Json, ClientError> {
+        let mut exporter = try!(self.cli.export(start, end));
+        let (mut records, mut next) = (vec![], None);
+        'batches: while let Some(batch) = try!(exporter.next()) {
+            for record in batch {
+                if records.len() == limit {
+                    next = Some(escape(record.get_key()));
+                    break 'batches;
+                }
+                let mut o = BTreeMap::new();
+                insert_bytes(&mut o, "key", record.get_key());
+                insert_bytes(&mut o, "value", record.get_value());
+                records.push(Json::Object(o));
+            }
+        }
+        let mut o = BTreeMap::new();
+        o.insert("records".to_string(), Json::Array(records));
+        o.insert("txid".to_string(), exporter.txid().to_json());
+        if let Some(next) = next {
+            o.insert("next".to_string(), next.to_json());
+        }
+        Ok(Json::Object(o))
+    }
+}
+
+fn respond(conn: &mut Conn,
+           status: &str,
+           body: &Json,
+           close: bool)
+           -> io::Result<()> {
+    let body = format!("{}\n", body.pretty());
+    let res = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+                       Content-Length: {}\r\n{}\r\n{}",
+                      status,
+                      body.len(),
+                      if close { "Connection: close\r\n" } else { "" },
+                      body);
+    conn.write_all(res.as_bytes())
+}
+
+// Serves one connection until it's closed, or a request asks for it to
+// be or can't be read.
+fn converse(mut conn: Conn, cli_addr: SocketAddr) {
+    let mut session = Session::new(cli_addr);
+    let mut buf = vec![];
+    let mut chunk = [0; 16 << 10];
+    loop {
+        match parse(&buf) {
+            Ok(Some((req, used))) => {
+                buf.drain(..used);
+                let (status, body) = session.handle(&req);
+                let sent = respond(&mut conn, status, &body, req.close);
+                if sent.is_err() || req.close {
+                    return;
+                }
+                continue;
+            }
+            Ok(None) => (),
+            Err((status, e)) => {
+                let _ = respond(&mut conn, status, &error(status, e).1, true);
+                return;
+            }
+        }
+        match conn.read(&mut chunk) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+// Serves the REST API on listener until the server shuts down, carrying
+// out each request with requests to our client port at cli_addr.
+pub fn serve(listener: TcpListener,
+             cli_addr: SocketAddr,
+             max_conns: usize,
+             shutdown: ShutdownHandle)
+             -> io::Result<()> {
+    frontend::serve("rest",
+                    listener,
+                    max_conns,
+                    REFUSAL,
+                    shutdown,
+                    move |conn| converse(conn, cli_addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, parse, unescape};
+
+    #[test]
+    fn parses_requests() {
+        let buf = b"PUT /v1/collections/default/keys/a%2Fb?x=1 HTTP/1.1\r\n\
+                    Host: localhost\r\nContent-Length: 2\r\n\r\n{}\
+                    GET / HTTP/1.0\r\n\r\n";
+        let (req, used) = parse(buf).unwrap().unwrap();
+        assert_eq!(req.method, "PUT");
+        assert_eq!(req.path, "/v1/collections/default/keys/a%2Fb");
+        assert_eq!(req.query, "x=1");
+        assert_eq!(req.headers["host"], "localhost");
+        assert_eq!(req.body, b"{}");
+        assert!(!req.close);
+        let (req, _) = parse(&buf[used..]).unwrap().unwrap();
+        assert_eq!((&*req.method, &*req.path, req.close), ("GET", "/", true));
+    }
+
+    #[test]
+    fn waits_for_whole_requests() {
+        let buf = b"PUT /k HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+        for len in 0..buf.len() {
+            assert_eq!(parse(&buf[..len]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn refuses_what_it_cant_read() {
+        assert_eq!(parse(b"GET /\r\n\r\n").unwrap_err().0, "400 Bad Request");
+        let chunked = b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(parse(chunked).unwrap_err().0, "411 Length Required");
+        let huge = b"PUT / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n";
+        assert_eq!(parse(huge).unwrap_err().0, "413 Payload Too Large");
+        assert!(parse(&[b'a'; 10000]).is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape("a%2Fb+c%00"), Some(b"a/b c\x00".to_vec()));
+        assert_eq!(unescape("%2"), None);
+        assert_eq!(unescape("%zz"), None);
+        assert_eq!(escape(b"a/b c\xff~"), "a%2Fb%20c%FF~");
+        assert_eq!(unescape(&escape(b"\x00\x01key")),
+                   Some(b"\x00\x01key".to_vec()));
+    }
+}
@@ -1,27 +1,564 @@
-use rocksdb::{DB, Writable};
+use std::cmp;
+use std::fs::{self, OpenOptions};
+use std::i32;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use libc::{c_int, size_t};
+use rocksdb::{DB, DBCompactionStyle, DBResult, Writable, WriteBatch,
+              WriteOptions};
+use rocksdb::rocksdb_ffi::{self, DBCFHandle, DBOptions};
 use rocksdb::Options as RocksDBOptions;
+use time;
+use uuid::Uuid;
 
-pub fn new(storage_dir: String) -> DB {
-    let mut opts = RocksDBOptions::new();
-    let memtable_budget = 1024;
-    opts.optimize_level_style_compaction(memtable_budget);
+use StorageError;
+use server::{CompactionStyle, Compression, Durability, PeerID, ServerConfig};
+use server::checksum;
+use server::local_meta;
+use server::metrics::Metrics;
+
+// column family for the node's own bookkeeping, apart from client data,
+// each value in the format local_meta describes
+const LOCAL_META: &'static str = "local_meta";
+const PEER_ID_KEY: &'static [u8] = b"peer_id";
+const PEER_PORT_KEY: &'static [u8] = b"peer_port";
+// when we last asked RocksDB to sync its WAL, if nothing else did
+const WAL_SYNCED_KEY: &'static [u8] = b"wal_synced_at";
+
+// levels RocksDB keeps, and how many of the first get flushed to and
+// compacted so often that they're left uncompressed
+const LEVELS: usize = 7;
+const UNCOMPRESSED_LEVELS: usize = 2;
+
+// zlib's default window, for compression options that don't set it
+const WINDOW_BITS: c_int = -14;
+
+// declared by librocksdb's C API, but not by our bindings
+extern "C" {
+    fn rocksdb_options_set_compression_per_level(opts: DBOptions,
+                                                 levels: *const c_int,
+                                                 num_levels: size_t);
+    fn rocksdb_options_set_compression_options(opts: DBOptions,
+                                               window_bits: c_int,
+                                               level: c_int,
+                                               strategy: c_int,
+                                               max_dict_bytes: c_int);
+}
+
+pub fn new(storage_dir: String) -> Result<DB, StorageError> {
+    new_with_config(storage_dir, &ServerConfig::default())
+}
+
+pub fn new_with_config(storage_dir: String,
+                       config: &ServerConfig)
+                       -> Result<DB, StorageError> {
+    let mut opts = options(config);
     opts.create_if_missing(true);
-    match DB::open_cf(&opts, &storage_dir, &["storage", "local_meta"]) {
-        Ok(db) => db,
+    if config.durability == Durability::Never {
+        // don't fsync sst files or the manifest either
+        opts.set_disable_data_sync(true);
+    }
+    match DB::open_cf(&opts, &storage_dir, &["storage", LOCAL_META]) {
+        Ok(db) => Ok(db),
         Err(_) => {
             info!("Attempting to initialize data directory at {}", storage_dir);
             match DB::open(&opts, &storage_dir) {
                 Ok(mut db) => {
-                    db.create_cf("storage", &RocksDBOptions::new()).unwrap();
-                    db.create_cf("local_meta", &RocksDBOptions::new()).unwrap();
-                    db
+                    for cf in &["storage", LOCAL_META] {
+                        try!(db.create_cf(cf, &RocksDBOptions::new())
+                               .map_err(|e| {
+                                   StorageError::Open {
+                                       path: storage_dir.clone(),
+                                       reason: e,
+                                   }
+                               }));
+                    }
+                    Ok(db)
                 }
                 Err(e) => {
                     error!("failed to create database at {}", storage_dir);
                     error!("{}", e);
-                    panic!(e);
+                    Err(StorageError::Open {
+                        path: storage_dir.clone(),
+                        reason: e,
+                    })
                 }
             }
         }
     }
 }
+
+// RocksDB's options as the config tunes them.  Left alone, the library
+// buffers a few megabytes of writes, caches eight of blocks and runs a
+// single compaction at a time, which is wrong for most deployments.
+fn options(config: &ServerConfig) -> RocksDBOptions {
+    let mut opts = RocksDBOptions::new();
+    // sizes levels and files to suit memtables of write_buffer_mb, of
+    // which it budgets for four
+    let write_buffer = config.write_buffer_mb << 20;
+    let budget = cmp::min(write_buffer * 4, i32::MAX as usize);
+    opts.optimize_level_style_compaction(budget as i32);
+    opts.set_write_buffer_size(write_buffer as size_t);
+    opts.set_compaction_style(match config.compaction_style {
+        CompactionStyle::Level => DBCompactionStyle::DBLevelCompaction,
+        CompactionStyle::Universal => DBCompactionStyle::DBUniversalCompaction,
+        CompactionStyle::Fifo => DBCompactionStyle::DBFifoCompaction,
+    });
+    set_compression(&mut opts, config.compression, config.compression_level);
+
+    let jobs = cmp::max(config.max_background_jobs, 2);
+    // sizes the thread pools the jobs run on
+    opts.increase_parallelism(jobs as i32);
+    let flushes = cmp::max(jobs / 4, 1);
+    opts.set_max_background_flushes(flushes as i32);
+    opts.set_max_background_compactions((jobs - flushes) as i32);
+
+    unsafe {
+        let table = rocksdb_ffi::rocksdb_block_based_options_create();
+        if config.block_cache_mb == 0 {
+            rocksdb_ffi::rocksdb_block_based_options_set_no_block_cache(table,
+                                                                        true);
+        } else {
+            let bytes = (config.block_cache_mb << 20) as size_t;
+            let cache = rocksdb_ffi::new_cache(bytes);
+            rocksdb_ffi::rocksdb_block_based_options_set_block_cache(table,
+                                                                     cache);
+            // the table options keep their own reference to it
+            rocksdb_ffi::rocksdb_cache_destroy(cache);
+        }
+        rocksdb_ffi::rocksdb_options_set_block_based_table_factory(opts.inner,
+                                                                   table);
+        rocksdb_ffi::rocksdb_block_based_options_destroy(table);
+    }
+    opts
+}
+
+// Level style compaction compresses level by level, overriding any one
+// compression set for the whole db, so the levels are set too.
+fn set_compression(opts: &mut RocksDBOptions,
+                   compression: Compression,
+                   level: Option<u32>) {
+    let code = compression_code(compression);
+    let levels: Vec<c_int> = (0..LEVELS)
+                                 .map(|level| {
+                                     if level < UNCOMPRESSED_LEVELS {
+                                         compression_code(Compression::None)
+                                     } else {
+                                         code
+                                     }
+                                 })
+                                 .collect();
+    unsafe {
+        rocksdb_ffi::rocksdb_options_set_compression(opts.inner, code);
+        rocksdb_options_set_compression_per_level(opts.inner,
+                                                  levels.as_ptr(),
+                                                  LEVELS as size_t);
+        if let Some(level) = level {
+            rocksdb_options_set_compression_options(opts.inner,
+                                                    WINDOW_BITS,
+                                                    level as c_int,
+                                                    0,
+                                                    0);
+        }
+    }
+}
+
+// as RocksDB numbers them
+fn compression_code(compression: Compression) -> c_int {
+    match compression {
+        Compression::None => 0,
+        Compression::Snappy => 1,
+        Compression::Zlib => 2,
+        Compression::Bz2 => 3,
+        Compression::Lz4 => 4,
+        Compression::Lz4hc => 5,
+        Compression::Zstd => 7,
+    }
+}
+
+// Returns the id this node has had since its first boot, generating and
+// storing one if there isn't one yet.  The rest of the cluster knows the
+// id by the address it saw it at, so an identity created for a different
+// peer port is refused rather than reused.
+pub fn load_or_create_peer_id(db: &DB,
+                              storage_dir: &str,
+                              peer_port: u16)
+                              -> Result<PeerID, StorageError> {
+    let cf = match db.cf_handle(LOCAL_META) {
+        Some(cf) => *cf,
+        None => {
+            return Err(StorageError::Open {
+                path: storage_dir.to_string(),
+                reason: format!("missing column family {}", LOCAL_META),
+            })
+        }
+    };
+    let port = peer_port.to_string();
+    match try!(get_string(db, cf, PEER_ID_KEY)) {
+        Some(id) => {
+            let stored_port = try!(get_string(db, cf, PEER_PORT_KEY));
+            if stored_port.as_ref() != Some(&port) {
+                let stored_port = stored_port.unwrap_or("unknown".to_string());
+                return Err(StorageError::Open {
+                    path: storage_dir.to_string(),
+                    reason: format!("peer id {} belongs to peer port {}, not \
+                                     {}",
+                                    id,
+                                    stored_port,
+                                    port),
+                });
+            }
+            Ok(id)
+        }
+        None => {
+            let id = Uuid::new_v4().to_string();
+            info!("first boot, our peer id is {}", id);
+            try!(put_meta(db, cf, PEER_PORT_KEY, port.as_bytes()));
+            // written last, so a crash in between just starts over
+            try!(put_meta(db, cf, PEER_ID_KEY, id.as_bytes()));
+            Ok(id)
+        }
+    }
+}
+
+fn get_string(db: &DB,
+              cf: DBCFHandle,
+              key: &[u8])
+              -> Result<Option<String>, StorageError> {
+    match try!(get_meta(db, cf, key)) {
+        Some(v) => {
+            String::from_utf8(v)
+                .map(Some)
+                .map_err(|_| {
+                    let err = format!("{} is not valid utf8",
+                                      String::from_utf8_lossy(key));
+                    StorageError::Operation(err)
+                })
+        }
+        None => Ok(None),
+    }
+}
+
+// Reads a local_meta value, writing it back in the current format if it
+// was stored in an older one.
+fn get_meta(db: &DB,
+            cf: DBCFHandle,
+            key: &[u8])
+            -> Result<Option<Vec<u8>>, StorageError> {
+    let stored = match db.get_cf(cf, key) {
+        DBResult::Some(v) => v.to_vec(),
+        DBResult::None => return Ok(None),
+        DBResult::Error(e) => return Err(StorageError::Operation(e)),
+    };
+    let (value, stale) = try!(local_meta::unwrap(key, &stored));
+    if stale {
+        try!(put_meta(db, cf, key, &value));
+    }
+    Ok(Some(value))
+}
+
+fn put_meta(db: &DB,
+            cf: DBCFHandle,
+            key: &[u8],
+            value: &[u8])
+            -> Result<(), StorageError> {
+    db.put_cf(cf, key, &local_meta::wrap(key, value))
+      .map_err(StorageError::Operation)
+}
+
+// Decides which writes ask RocksDB to sync its WAL.  A synced write makes
+// everything written before it durable too, so a group of writes that
+// isn't synced gets picked up by the next one that is, or, once writes
+// stop, by a synced write of our own.
+pub struct WalSyncer {
+    durability: Durability,
+    metrics: Arc<Metrics>,
+    last_sync: Option<time::Timespec>,
+    dirty: bool,
+}
+
+impl WalSyncer {
+    pub fn new(durability: Durability, metrics: Arc<Metrics>) -> WalSyncer {
+        WalSyncer {
+            durability: durability,
+            metrics: metrics,
+            last_sync: None,
+            dirty: false,
+        }
+    }
+
+    // Whether the next group of writes should be synced: every one with
+    // Durability::Always, the first once an interval is up otherwise.
+    pub fn sync_due(&self, now: time::Timespec) -> bool {
+        match self.durability {
+            Durability::Always => true,
+            Durability::Interval(ms) => {
+                match self.last_sync {
+                    Some(last) => now - last >=
+                                  time::Duration::milliseconds(ms as i64),
+                    None => true,
+                }
+            }
+            Durability::Never => false,
+        }
+    }
+
+    // Called after each group of writes, with whether it was synced.
+    pub fn wrote(&mut self, now: time::Timespec, synced: bool) {
+        if synced {
+            self.synced(now);
+        } else {
+            self.dirty = true;
+        }
+    }
+
+    // Called periodically, so interval syncs still happen when writes stop.
+    pub fn tick(&mut self,
+                db: &DB,
+                now: time::Timespec)
+                -> Result<(), StorageError> {
+        match self.durability {
+            Durability::Interval(_) if self.dirty && self.sync_due(now) => {
+                self.sync(db, now)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Called on shutdown: whatever the durability setting, nothing we've
+    // written is left unsynced.
+    pub fn close(&mut self,
+                 db: &DB,
+                 now: time::Timespec)
+                 -> Result<(), StorageError> {
+        if self.dirty { self.sync(db, now) } else { Ok(()) }
+    }
+
+    fn sync(&mut self,
+            db: &DB,
+            now: time::Timespec)
+            -> Result<(), StorageError> {
+        let cf = match db.cf_handle(LOCAL_META) {
+            Some(cf) => *cf,
+            None => {
+                let err = format!("missing column family {}", LOCAL_META);
+                return Err(StorageError::Operation(err));
+            }
+        };
+        let batch = WriteBatch::new();
+        let synced_at = now.sec.to_string();
+        let wrapped = local_meta::wrap(WAL_SYNCED_KEY, synced_at.as_bytes());
+        try!(batch.put_cf(cf, WAL_SYNCED_KEY, &wrapped)
+                  .map_err(StorageError::Operation));
+        let mut writeopts = WriteOptions::new();
+        writeopts.set_sync(true);
+        try!(db.write_opt(batch, &writeopts).map_err(StorageError::Operation));
+        self.synced(now);
+        Ok(())
+    }
+
+    fn synced(&mut self, now: time::Timespec) {
+        self.metrics.wal_syncs.inc();
+        self.last_sync = Some(now);
+        self.dirty = false;
+    }
+}
+
+// WAL files are named by increasing number, so the highest one is the one
+// being written to.  Only crash_at needs to know.
+fn live_wal(storage_dir: &str) -> Result<Option<PathBuf>, StorageError> {
+    let entries = try!(fs::read_dir(storage_dir).map_err(|e| {
+        StorageError::Operation(e.to_string())
+    }));
+    let mut wal = None;
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        if path.extension().map(|ext| ext == "log").unwrap_or(false) &&
+           wal.as_ref().map(|w| path > *w).unwrap_or(true) {
+            wal = Some(path);
+        }
+    }
+    Ok(wal)
+}
+
+// Leaves a closed storage directory as a crash would have, had only the
+// first keep bytes of the live WAL reached the disk, tearing whatever
+// write was under way there.  Returns how long the WAL was, so a test can
+// go on to try every offset.  For tests; nothing in the server calls it.
+pub fn crash_at(storage_dir: &str, keep: u64) -> Result<u64, StorageError> {
+    let wal = match try!(live_wal(storage_dir)) {
+        Some(wal) => wal,
+        None => return Ok(0),
+    };
+    let op = |e: ::std::io::Error| StorageError::Operation(e.to_string());
+    let file = try!(OpenOptions::new().write(true).open(&wal).map_err(&op));
+    let len = try!(file.metadata().map_err(&op)).len();
+    if keep < len {
+        try!(file.set_len(keep).map_err(&op));
+        try!(file.sync_all().map_err(&op));
+    }
+    Ok(len)
+}
+
+// Checks every stored value against its checksum, returning how many
+// there are.  Run on startup, so that a recovery which brought back half
+// of a write, or the wrong bytes, stops us before we serve any of it.
+pub fn verify(db: &DB) -> Result<usize, StorageError> {
+    let mut keys = 0;
+    for (k, v) in db.iterator().from_start() {
+        try!(checksum::unseal(&k, &v));
+        keys += 1;
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use rocksdb::{DBResult, Writable};
+    use time;
+
+    use server::Durability;
+    use server::checksum;
+    use server::group_commit::GroupCommit;
+    use server::local_meta;
+    use server::metrics::Metrics;
+    use super::{LOCAL_META, PEER_ID_KEY, PEER_PORT_KEY, WAL_SYNCED_KEY,
+                WalSyncer, crash_at, load_or_create_peer_id, new, verify};
+
+    #[test]
+    fn test_peer_id_survives_restart() {
+        let dir = "_rasputin_test_peer_id".to_string();
+        let _ = fs::remove_dir_all(&dir);
+
+        let first = {
+            let db = new(dir.clone()).unwrap();
+            load_or_create_peer_id(&db, &dir, 7770).unwrap()
+        };
+        let db = new(dir.clone()).unwrap();
+        assert_eq!(load_or_create_peer_id(&db, &dir, 7770).unwrap(), first);
+        // the identity is tied to the address peers knew us by
+        assert!(load_or_create_peer_id(&db, &dir, 7771).is_err());
+
+        // one stored before local_meta had a format is upgraded in place
+        let cf = *db.cf_handle(LOCAL_META).unwrap();
+        db.put_cf(cf, PEER_PORT_KEY, b"7770").unwrap();
+        db.put_cf(cf, PEER_ID_KEY, b"legacy").unwrap();
+        assert_eq!(load_or_create_peer_id(&db, &dir, 7770).unwrap(), "legacy");
+        match db.get_cf(cf, PEER_ID_KEY) {
+            DBResult::Some(v) => assert!(v.starts_with(local_meta::MAGIC)),
+            _ => panic!("peer id went missing"),
+        }
+        assert_eq!(load_or_create_peer_id(&db, &dir, 7770).unwrap(), "legacy");
+
+        drop(db);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_interval_syncs() {
+        let dir = "_rasputin_test_interval_syncs".to_string();
+        let _ = fs::remove_dir_all(&dir);
+        let db = new(dir.clone()).unwrap();
+        let cf = *db.cf_handle(LOCAL_META).unwrap();
+        let start = time::Timespec::new(100, 0);
+        let ms = |ms| start + time::Duration::milliseconds(ms);
+
+        let metrics = Arc::new(Metrics::new());
+        let mut wal =
+            WalSyncer::new(Durability::Interval(100), metrics.clone());
+        assert!(wal.sync_due(start));
+        wal.wrote(start, true);
+        // the writes in between ride on the next synced one
+        assert!(!wal.sync_due(ms(50)));
+        wal.wrote(ms(50), false);
+        wal.tick(&db, ms(60)).unwrap();
+        assert!(db.get_cf(cf, WAL_SYNCED_KEY).is_none());
+        assert!(wal.sync_due(ms(100)));
+
+        // with no more coming, a tick syncs them itself
+        wal.tick(&db, ms(150)).unwrap();
+        assert!(db.get_cf(cf, WAL_SYNCED_KEY).is_some());
+        assert!(!wal.sync_due(ms(200)));
+
+        assert_eq!(metrics.wal_syncs.get(), 2);
+
+        let mut never = WalSyncer::new(Durability::Never, metrics.clone());
+        assert!(!never.sync_due(start));
+        never.wrote(start, false);
+        never.tick(&db, ms(1000)).unwrap();
+        assert!(never.dirty);
+        never.close(&db, ms(1000)).unwrap();
+        assert!(!never.dirty);
+
+        drop(db);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Whatever point a crash tears the WAL at, what comes back verifies,
+    // keeps every group of writes whole, and is the earliest groups.
+    #[test]
+    fn test_torn_wal_recovery() {
+        let dir = "_rasputin_test_torn_wal".to_string();
+        let _ = fs::remove_dir_all(&dir);
+        let groups = 30;
+        {
+            let db = new(dir.clone()).unwrap();
+            for g in 0..groups {
+                let mut group = GroupCommit::new();
+                for k in 0..4 {
+                    let key = format!("g{:02}k{}", g, k);
+                    group.put(key.as_bytes(), &[g as u8; 100]).unwrap();
+                }
+                group.commit(&db).unwrap();
+            }
+        }
+        let len = crash_at(&dir, u64::max_value()).unwrap();
+        assert!(len > 0);
+
+        let mut offset = 0;
+        while offset <= len {
+            let torn = format!("{}_at_{}", dir, offset);
+            let _ = fs::remove_dir_all(&torn);
+            fs::create_dir(&torn).unwrap();
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_str().unwrap();
+                fs::copy(&path, format!("{}/{}", torn, name)).unwrap();
+            }
+            crash_at(&torn, offset).unwrap();
+
+            let db = new(torn.clone()).unwrap();
+            let keys = verify(&db).unwrap();
+            assert_eq!(keys % 4, 0, "torn at {} of {}", offset, len);
+            for g in 0..groups {
+                let present = (0..4).filter(|k| {
+                    let key = format!("g{:02}k{}", g, k);
+                    let got = checksum::get(&db, key.as_bytes()).unwrap();
+                    got == Some(vec![g as u8; 100])
+                }).count();
+                assert_eq!(present == 4,
+                           g < keys / 4,
+                           "torn at {} of {}",
+                           offset,
+                           len);
+            }
+            drop(db);
+            let _ = fs::remove_dir_all(&torn);
+            if offset == len {
+                // nothing torn, nothing lost
+                assert_eq!(keys, groups * 4);
+                break;
+            }
+            offset = (offset + 61).min(len);
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
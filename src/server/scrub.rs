@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+
+use rocksdb::{DB, Direction};
+
+use {KeyRange, RepairEntry, SpanDigest, StorageError};
+use server::checksum;
+use server::group_commit::GroupCommit;
+use server::metrics::Metrics;
+
+// Digests the whole db in spans of span_keys consecutive keys.  The first
+// span starts at the empty key, so every key lands in some span.  This
+// walks every key on the calling thread.
+pub fn digest(db: &DB, span_keys: usize) -> Vec<SpanDigest> {
+    let span_keys = if span_keys == 0 {
+        1
+    } else {
+        span_keys
+    };
+    let mut spans = vec![];
+    let mut start = vec![];
+    let mut keys = 0;
+    let mut crc = !0;
+    for (k, v) in db.iterator().from_start() {
+        if keys == span_keys {
+            spans.push(span(start, keys, crc));
+            start = k.to_vec();
+            keys = 0;
+            crc = !0;
+        }
+        crc = checksum::update_field(checksum::update_field(crc, &k), &v);
+        keys += 1;
+    }
+    spans.push(span(start, keys, crc));
+    spans
+}
+
+// Digests our db over the leader's spans, returning the ranges of those
+// that don't match.
+pub fn divergent(db: &DB, spans: &[SpanDigest]) -> Vec<KeyRange> {
+    if spans.is_empty() {
+        return vec![];
+    }
+    let mut ours: Vec<(usize, u32)> = spans.iter().map(|_| (0, !0)).collect();
+    let mut i = 0;
+    for (k, v) in db.iterator().from_start() {
+        while i + 1 < spans.len() && &*k >= spans[i + 1].get_start() {
+            i += 1;
+        }
+        let (ref mut keys, ref mut crc) = ours[i];
+        *crc = checksum::update_field(checksum::update_field(*crc, &k), &v);
+        *keys += 1;
+    }
+    let mut ranges = vec![];
+    for (i, (keys, crc)) in ours.into_iter().enumerate() {
+        let theirs = &spans[i];
+        if theirs.get_keys() == keys as u64 && theirs.get_crc() == crc ^ !0 {
+            continue;
+        }
+        let mut range = KeyRange::new();
+        range.set_start(theirs.get_start().to_vec());
+        if let Some(next) = spans.get(i + 1) {
+            range.set_end(next.get_start().to_vec());
+        }
+        ranges.push(range);
+    }
+    ranges
+}
+
+// Everything stored in the ranges, as stored.
+pub fn entries(db: &DB, ranges: &[KeyRange]) -> Vec<RepairEntry> {
+    let mut entries = vec![];
+    for range in ranges {
+        let mut iter = db.iterator();
+        for (k, v) in iter.from(range.get_start(), Direction::forward) {
+            if !in_range(range, &k) {
+                break;
+            }
+            let mut entry = RepairEntry::new();
+            entry.set_key(k.to_vec());
+            entry.set_value(v.to_vec());
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+// Makes the ranges in our db hold exactly the entries, returning how many
+// keys were changed.  Entries that fail their checksum are left out, so a
+// leader's own corruption isn't copied, and counted along with ours.
+pub fn repair(db: &DB,
+              ranges: &[KeyRange],
+              entries: &[RepairEntry],
+              metrics: &Metrics)
+              -> Result<usize, StorageError> {
+    let keys: BTreeSet<&[u8]> =
+        entries.iter().map(|entry| entry.get_key()).collect();
+    let mut group = GroupCommit::new();
+    let mut changed = 0;
+    for range in ranges {
+        let mut iter = db.iterator();
+        for (k, _) in iter.from(range.get_start(), Direction::forward) {
+            if !in_range(range, &k) {
+                break;
+            }
+            if !keys.contains(&*k) {
+                try!(group.delete(&k));
+                changed += 1;
+            }
+        }
+    }
+    for entry in entries {
+        if !ranges.iter().any(|range| in_range(range, entry.get_key())) {
+            continue;
+        }
+        let value = match metrics.checked(checksum::unseal(entry.get_key(),
+                                                           entry.get_value())) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("not repairing from the leader's copy: {}", e);
+                continue;
+            }
+        };
+        match metrics.checked(group.get(db, entry.get_key())) {
+            Ok(Some(ref ours)) if *ours == value => continue,
+            // including when ours is corrupt
+            _ => (),
+        }
+        try!(group.put(entry.get_key(), &value));
+        changed += 1;
+    }
+    try!(group.commit(db));
+    Ok(changed)
+}
+
+fn in_range(range: &KeyRange, key: &[u8]) -> bool {
+    key >= range.get_start() && (!range.has_end() || key < range.get_end())
+}
+
+fn span(start: Vec<u8>, keys: usize, crc: u32) -> SpanDigest {
+    let mut span = SpanDigest::new();
+    span.set_start(start);
+    span.set_keys(keys as u64);
+    span.set_crc(crc ^ !0);
+    span
+}
@@ -0,0 +1,62 @@
+use StorageError;
+use server::checksum::{bytes_to_u64, u64_to_bytes};
+
+// Sequential puts are numbered as they're applied, not when the leader
+// takes them on, so every replica hands out the same key for the same
+// entry however leadership changed hands in between.  The last number
+// handed out under a prefix is kept at PREFIX plus that prefix, and goes
+// into the db in the same batch as the key it named, so numbers are never
+// handed out twice, even after the keys they named are deleted.
+pub const PREFIX: &'static [u8] = b"\x00sequence/";
+
+// enough digits for any u64, so keys sort in the order they were numbered
+const DIGITS: usize = 20;
+
+pub fn is_sequence_key(key: &[u8]) -> bool {
+    key.starts_with(PREFIX)
+}
+
+pub fn counter(prefix: &[u8]) -> Vec<u8> {
+    let mut key = PREFIX.to_vec();
+    key.extend_from_slice(prefix);
+    key
+}
+
+// The number after the last one handed out, given what its counter holds.
+pub fn next(last: Option<&[u8]>) -> Result<u64, StorageError> {
+    match last {
+        None => Ok(1),
+        Some(last) if last.len() == 8 => Ok(bytes_to_u64(last) + 1),
+        Some(last) => {
+            Err(StorageError::Corrupt(format!("a sequence counter can't be {} \
+                                               bytes long",
+                                              last.len())))
+        }
+    }
+}
+
+pub fn counter_value(n: u64) -> Vec<u8> {
+    u64_to_bytes(n).to_vec()
+}
+
+pub fn sequential_key(prefix: &[u8], n: u64) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend_from_slice(format!("{:01$}", n, DIGITS).as_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{counter, counter_value, next, sequential_key};
+
+    #[test]
+    fn test_sequential_keys() {
+        assert_eq!(next(None).unwrap(), 1);
+        assert_eq!(next(Some(&counter_value(41))).unwrap(), 42);
+        assert!(next(Some(b"x")).is_err());
+        assert_eq!(sequential_key(b"queue/", 42),
+                   b"queue/00000000000000000042".to_vec());
+        assert!(sequential_key(b"q", 9) < sequential_key(b"q", 10));
+        assert_eq!(counter(b"queue/"), b"\x00sequence/queue/".to_vec());
+    }
+}
@@ -1,31 +1,136 @@
 use std::cmp;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::SocketAddr;
 use std::ops::Add;
-use std::process;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc;
-use std::thread;
+use std::sync::{Arc, RwLock};
 
 use bytes::{Buf, ByteBuf};
-use mio;
-use mio::{EventLoop, Token};
-use rand::{Rng, thread_rng};
-use rocksdb::{DB, DBResult, Writable};
+use mio::Token;
+use rocksdb::{DB, DBResult};
 use protobuf;
-use protobuf::Message;
-use uuid::Uuid;
+use protobuf::{Message, RepeatedField};
+use time;
+
+use {Append, AppendRes, AuthRes, BackupRes, CliReq, CliRes, Clock, ErrorCode,
+     ErrorRes, ExportRes, GetReq, GetRes, HLC, IngestRes, Mutation,
+     MutationType, Priority, PromoteRes, PutSequentialRes, ReloadConfigReq,
+     ReloadConfigRes, Repair, RepairReq, Scrub, ScrubRes, PeerMsg, RedirectRes,
+     SetReq, SetRes, StageReq, StageRes, Version, CASReq, CASRes, CountRes,
+     DelReq, DelRes, DeleteRangeRes, TimeoutNow, TraceContext,
+     TransferLeaderRes, SnapshotRes, TxnRes, UpdateOp, UpdateRes, VoteReq,
+     VoteRes};
+use {ConsensusError, ProtocolError, StorageError};
+use client::key_prefix_end;
+use clock::timespec_to_ms;
+use logging;
+use server::{Envelope, LEADER_DURATION, LEADER_REFRESH, PEER_BROADCAST,
+             PRE_VOTE_DURATION, RELOAD, ServerMsg, State};
+use server::{AckedLog, LogEntry, PeerID, RepPeer, TXID, Term};
+use server::{DiskSpace, Faults, GcHorizon, Membership, Namespaces, SendChannel,
+             ServerConfig, StorageStats, Watermark, WorkerPool};
+use server::auth;
+use server::checksum;
+use server::scrub;
+use server::sequence;
+use server::snapshot::{self, SnapshotJob, SnapshotWorker};
+use server::stats;
+use server::group_commit::GroupCommit;
+use server::admission::{self, Admission, Backpressure};
+use server::backup::{self, BackupJob};
+use server::export::{self, ExportJob};
+use server::ingest;
+use server::lanes::{Backchannel, Taken};
+use server::metrics::Metrics;
+use server::mirror;
+use server::namespace;
+use server::status::{self, FollowerStatus, NodeStatus};
+use server::rocksdb::WalSyncer;
+use server::timing::{self, Timing};
+use server::tracing::{self, Span};
+use server::txn;
+use server::update;
+use server::watch::{self, Watch};
+
+// A client read that can be served by any thread holding the db, without
+// touching the rest of the server's state.
+pub struct ReadJob {
+    pub env: Envelope,
+    pub req_id: u64,
+    pub get: GetReq,
+    pub txid: TXID,
+    pub timing: Timing,
+    // what the server thread was logging under as it took the read
+    pub log_context: logging::Context,
+    pub metrics: Arc<Metrics>,
+}
+
+impl ReadJob {
+    pub fn run(mut self, db: &DB) -> Envelope {
+        logging::set_context(self.log_context.clone());
+        let mut get_res = GetRes::new();
+        match self.metrics.checked(checksum::get(db, self.get.get_key())) {
+            Ok(Some(value)) => {
+                get_res.set_success(true);
+                get_res.set_value(value);
+            }
+            Ok(None) => {
+                get_res.set_success(false);
+                get_res.set_err("Key not found".to_string())
+            }
+            Err(e @ StorageError::Corrupt(_)) => {
+                // rather than hand back something we know is wrong
+                error!("{}", e);
+                get_res.set_success(false);
+                get_res.set_err(e.to_string());
+            }
+            Err(e) => {
+                error!("{}", e);
+                get_res.set_success(false);
+                get_res.set_err("Operational problem encountered".to_string());
+            }
+        }
+        get_res.set_txid(self.txid);
+        self.timing.applied();
+
+        let mut res = CliRes::new();
+        res.set_req_id(self.req_id);
+        res.set_get(get_res);
+        let reply = Envelope {
+            address: self.env.address,
+            tok: self.env.tok,
+            msg: ByteBuf::from_slice(&*res.write_to_bytes().unwrap()),
+        };
+        let latency = self.timing.replied(&self.metrics);
+        self.metrics.read_latency.observe(latency);
+        admission::READ_LATENCY.record(latency);
+        reply
+    }
+}
 
-use {Append, AppendRes, CliReq, CliRes, Clock, GetReq, GetRes, Mutation,
-     MutationType, PeerMsg, RealClock, RedirectRes, SetReq, SetRes, Version,
-     CASReq, CASRes, DelReq, DelRes, VoteReq, VoteRes};
-use server::{Envelope, LEADER_DURATION, PEER_BROADCAST, State};
-use server::{AckedLog, InMemoryLog, LogEntry, PeerID, RepPeer, TXID, Term};
-use server::{SendChannel, rocksdb};
-use server::traffic_cop::TrafficCop;
+// A leadership handoff we're in the middle of: writes are held off until
+// the target has accepted everything we have, then we step down and tell
+// it to take over.
+pub struct LeaderTransfer {
+    pub target: PeerID,
+    // the client that asked for it and its req_id, unless we're draining
+    pub requester: Option<(Envelope, u64)>,
+    pub until: time::Timespec,
+}
+
+// A round of pre-votes for the term we'd stand in.  Nobody changes term
+// over it, so a node that can't reach a quorum, like one that's been
+// partitioned away, keeps failing here instead of inflating its term and
+// disrupting the leader when it rejoins.
+pub struct PreVote {
+    pub term: Term,
+    pub have: Vec<Token>,
+    pub until: time::Timespec,
+}
 
 pub struct Server<C: Clock, RE> {
     pub clock: Arc<C>,
+    // stamps writes and peer messages, following the peers' clocks
+    pub hlc: HLC<C>,
     pub peer_port: u16,
     pub cli_port: u16,
     pub id: PeerID,
@@ -35,176 +140,107 @@ pub struct Server<C: Clock, RE> {
     pub max_generated_txid: TXID,
     pub highest_term: Term,
     pub state: State,
-    pub db: DB,
+    pub db: Arc<DB>,
+    // serves reads off the server thread when configured
+    pub read_pool: Option<WorkerPool<ReadJob>>,
+    // writes out backups off the server thread, one at a time
+    pub backup_pool: Option<WorkerPool<BackupJob>>,
+    // and exports, streaming each back as it scans
+    pub export_pool: Option<WorkerPool<ExportJob>>,
+    // holds the snapshots clients open, and serves reads from them
+    pub snapshot_worker: Option<SnapshotWorker>,
     pub rep_log: Box<AckedLog<Mutation> + Send>,
-    pub pending: BTreeMap<TXID, (Envelope, u64)>,
+    pub config: ServerConfig,
+    // our config as the cron and IO threads see it, updated on reload
+    pub live_config: Arc<RwLock<ServerConfig>>,
+    // txid -> (client envelope, req_id, deadline_ms, timing)
+    pub pending: BTreeMap<TXID, (Envelope, u64, Option<u64>, Timing)>,
+    // traced writes we've accepted from the leader -> their trace, and
+    // when we accepted and learned them, until they're applied
+    pub traced: BTreeMap<TXID, (TraceContext, time::Timespec, time::Timespec)>,
+    // learned txids waiting for the next flush to apply them
+    pub unapplied: Vec<TXID>,
+    // where files to bulk load are staged until they're ingested
+    pub ingest_dir: String,
+    pub wal: WalSyncer,
+    // shared with the threads that record into it and the admin listener
+    // that renders it
+    pub metrics: Arc<Metrics>,
+    pub horizon: GcHorizon,
+    // The oldest txid some replica may still need sent to it, so nobody
+    // collects it.  The leader works it out from its followers' progress
+    // and passes it on in its appends.
+    pub retain_from: TXID,
+    // what the admin listener reports about us
+    pub status: Arc<RwLock<NodeStatus>>,
+    pub transfer: Option<LeaderTransfer>,
+    // set once we've been asked to drain, after which we never lead again
+    pub draining: bool,
+    pub membership: Membership,
+    pub pre_vote: Option<PreVote>,
+    // client connection -> the principal it authenticated as
+    pub sessions: BTreeMap<usize, String>,
+    // when the leader next scrubs, set once it starts leading
+    pub next_scrub: Option<time::Timespec>,
+    // a scrub we're waiting to have applied up to
+    pub scrub: Option<(Envelope, Scrub)>,
+    // follower -> the starts of its spans that diverged in the last scrub
+    pub scrub_report: BTreeMap<PeerID, Vec<Vec<u8>>>,
+    // the leader's copy of our diverged ranges, waiting like a scrub
+    pub repair: Option<Repair>,
+    // whether our clock was too far off the cluster's when last checked
+    pub clock_skewed: bool,
+    // which of the disk watermarks we were past when last measured
+    pub disk_watermark: Watermark,
+    // what each of our ranges holds, and how often it's read and written
+    pub stats: StorageStats,
+    // the namespaces defined as of what we've applied, and what each holds
+    pub namespaces: Namespaces,
+    pub faults: Faults,
+    // clients following the changes we apply
+    pub watches: Vec<Watch>,
+    // set once a cluster that mirrored another has been promoted
+    pub promoted: bool,
+    // learned txids whose mutations changed nothing, like a CAS whose
+    // comparison failed, so watches replaying the log can skip them
+    pub unchanged: BTreeSet<TXID>,
+    // how the workers we hand snapshots to tell us they've taken them,
+    // when they run off the server thread
+    pub backchannel: Option<Backchannel>,
+    // snapshots a worker is still to take, until which nothing's applied
+    pub taking: usize,
 }
 
 unsafe impl<C: Clock, RE> Sync for Server<C, RE>{}
 
 impl<C: Clock, RE> Server<C, RE> {
-
-    pub fn run(peer_port: u16,
-               cli_port: u16,
-               storage_dir: String,
-               peers: Vec<String>) {
-        let db = rocksdb::new(storage_dir);
-
-        // All long-running worker threads get a clone of this
-        // Sender.  When they exit, they send over it.  If the
-        // Receiver ever completes a read, it means something
-        // unexpectedly exited.  It's vital that we shut down
-        // immediately, so we don't repeat the ZK bug where
-        // the heartbeater keeps running while other vital threads
-        // have exited, falsely communicating healthiness.
-        let (thread_exit_tx, thread_exit_rx) = mpsc::channel();
-
-        // The TrafficCop manages our sockets, sends deserialized
-        // messages over the request channel, and receives completed
-        // responses over the response channel.
-        let (peer_req_tx, peer_req_rx) = mpsc::channel();
-        let (cli_req_tx, cli_req_rx) = mpsc::channel();
-
-        let mut tc = TrafficCop::new(
-            peer_port,
-            cli_port,
-            peers.clone(),
-            peer_req_tx,
-            cli_req_tx
-        ).unwrap();
-
-        // A single MIO EventLoop handles our IO
-        let mut event_loop = EventLoop::new().unwrap();
-
-        // All RPC's are sent over the event_loop's
-        // notification channel.
-        let rpc_tx = event_loop.channel();
-
-        // start server periodic tasks
-        event_loop.timeout_ms((), thread_rng().gen_range(200, 500)).unwrap();
-
-        // IO event loop thread
-        let tex1 = thread_exit_tx.clone();
-        thread::Builder::new()
-            .name("IO loop".to_string())
-            .spawn(move || {
-                tc.run_event_loop(event_loop);
-                tex1.send(());
-            });
-
-        let mut rep_log = Box::new(InMemoryLog {
-            pending: BTreeMap::new(),
-            committed: BTreeMap::new(),
-            quorum: peers.len() / 2 + 1,
-            last_learned_txid: 0, // TODO(tyler) read from rocksdb
-            last_learned_term: 0, // TODO(tyler) read from rocksdb
-            last_accepted_txid: 0, // TODO(tyler) read from rocksdb
-            last_accepted_term: 0, // TODO(tyler) read from rocksdb
-        });
-
-        let clock = Arc::new(RealClock);
-
-        let server = Arc::new(Mutex::new(Server {
-            clock: clock.clone(),
-            peer_port: peer_port,
-            cli_port: cli_port,
-            id: Uuid::new_v4().to_string(), // TODO(tyler) read from rocksdb
-            rpc_tx: Box::new(rpc_tx),
-            max_generated_txid: 0, // TODO(tyler) read from rocksdb
-            highest_term: 0, // TODO(tyler) read from rocksdb
-            state: State::Init,
-            db: db,
-            rep_log: rep_log,
-            peers: peers,
-            rep_peers: BTreeMap::new(),
-            pending: BTreeMap::new(),
-        }));
-
-        // peer request handler thread
-        let srv1 = server.clone();
-        let tex2 = thread_exit_tx.clone();
-        thread::Builder::new()
-            .name("peer request handler".to_string())
-            .spawn(move || {
-                for req in peer_req_rx {
-                    match srv1.lock() {
-                        Ok(mut srv) => srv.handle_peer(req),
-                        Err(e) => {
-                            error!("{}", e);
-                            process::exit(1);
-                        }
-                    }
-                }
-                tex2.send(());
-            });
-
-        // cli request handler thread
-        let srv2 = server.clone();
-        let tex3 = thread_exit_tx.clone();
-        thread::Builder::new()
-            .name("cli request handler".to_string())
-            .spawn(move || {
-                for req in cli_req_rx {
-                    match srv2.lock() {
-                        Ok(mut srv) => srv.handle_cli(req),
-                        Err(e) => {
-                            error!("{}", e);
-                            process::exit(1);
-                        }
-                    }
-                }
-                tex3.send(());
-            });
-
-        // cron thread
-        let srv3 = server.clone();
-        let tex4 = thread_exit_tx.clone();
-        thread::Builder::new()
-            .name("server cron".to_string())
-            .spawn(move || {
-                let mut rng = thread_rng();
-                loop {
-                    clock.sleep_ms(rng.gen_range(400, 500));
-                    match srv3.lock() {
-                        Ok(mut srv) => srv.cron(),
-                        Err(e) => {
-                            error!("{}", e);
-                            process::exit(1);
-                        }
-                    }
-                }
-                tex4.send(());
-            });
-
-        // this should never receive
-        thread_exit_rx.recv();
-        let msg = "A worker thread unexpectedly exited! Shutting down.";
-        error!("{}", msg);
-        panic!("A worker thread unexpectedly exited! Shutting down.");
-    }
-
     fn update_rep_peers(&mut self,
                         peer_id: PeerID,
                         addr: Option<SocketAddr>,
-                        tok: Token) {
+                        tok: Token,
+                        learner: bool) {
         // don't send replication traffic to self
         if self.id == peer_id {
             return;
         }
 
         // set up a rep peer for this socket, and
-        // reset possibly old ones
+        // reset possibly old ones.  All we know of a new one is that it
+        // may have what's been learned; its replies tell us the rest.
         match self.rep_peers
                   .insert(peer_id.clone(),
                           RepPeer {
-                              max_sent_txid: self.rep_log.last_accepted_txid(),
+                              max_sent_txid: self.rep_log.last_learned_txid(),
+                              max_sent_learned_txid: 0,
                               last_accepted_txid: self.rep_log
-                                                      .last_accepted_txid(),
+                                                      .last_learned_txid(),
                               last_accepted_term: self.rep_log
-                                                      .last_accepted_term(),
+                                                      .last_learned_term(),
                               tok: tok,
                               id: peer_id.clone(),
                               addr: addr,
+                              learner: learner,
+                              heard: false,
                           }) {
             Some(old_rep_peer) => {
                 // retain previous offset information
@@ -212,6 +248,7 @@ impl<C: Clock, RE> Server<C, RE> {
                 new_rep_peer.max_sent_txid = old_rep_peer.max_sent_txid;
                 new_rep_peer.last_accepted_txid = old_rep_peer.last_accepted_txid;
                 new_rep_peer.last_accepted_term = old_rep_peer.last_accepted_term;
+                new_rep_peer.heard = old_rep_peer.heard;
             }
             _ => (),
         }
@@ -261,7 +298,10 @@ impl<C: Clock, RE> Server<C, RE> {
                     if !new_have.contains(&env.tok) &&
                        vote_res.get_term() == term {
                         new_have.push(env.tok);
-                        self.update_rep_peers(peer_id, env.address, env.tok);
+                        self.update_rep_peers(peer_id,
+                                              env.address,
+                                              env.tok,
+                                              false);
                     }
                     if new_have.len() >= need as usize {
                         // we've ascended to leader!
@@ -307,7 +347,19 @@ impl<C: Clock, RE> Server<C, RE> {
                     if !new_have.contains(&env.tok) &&
                        vote_res.get_term() == term {
                         new_have.push(env.tok);
-                        self.update_rep_peers(peer_id, env.address, env.tok);
+                        if !self.rep_peers.contains_key(&peer_id) {
+                            // it missed the election, so catch it up
+                            self.update_rep_peers(peer_id.clone(),
+                                                  env.address,
+                                                  env.tok,
+                                                  false);
+                            self.send_appends(&peer_id);
+                        } else {
+                            self.update_rep_peers(peer_id,
+                                                  env.address,
+                                                  env.tok,
+                                                  false);
+                        }
                     }
                     if new_have.len() >= need as usize {
                         debug!("{} leadership extended", self.id);
@@ -344,8 +396,7 @@ impl<C: Clock, RE> Server<C, RE> {
                        env: Envelope,
                        peer_id: PeerID,
                        vote_req: &VoteReq) {
-        let mut res = PeerMsg::new();
-        res.set_srvid(self.id.clone());
+        let mut res = self.peer_msg();
         let mut vote_res = VoteRes::new();
         vote_res.set_term(vote_req.get_term());
 
@@ -353,6 +404,27 @@ impl<C: Clock, RE> Server<C, RE> {
             // if we are this node (broadcast is naive) then all is well
             // reply to self but don't change to follower
             vote_res.set_success(true);
+        } else if self.config.learner {
+            // learners have no say in who leads
+            vote_res.set_success(false);
+        } else if vote_req.has_transfer_from() &&
+           self.state.following(vote_req.get_transfer_from().to_string()) &&
+           self.log_up_to_date(vote_req) {
+            // our leader stepped down in favor of this node, so its lease
+            // no longer binds us
+            self.highest_term = cmp::max(self.highest_term,
+                                         vote_req.get_term());
+            info!("leadership handed from {} to {}",
+                  vote_req.get_transfer_from(),
+                  peer_id);
+            self.state = State::Follower {
+                id: peer_id.clone(),
+                term: vote_req.get_term(),
+                tok: env.tok,
+                leader_addr: env.address.unwrap(),
+                until: self.clock.now().add(*LEADER_DURATION),
+            };
+            vote_res.set_success(true);
         } else if self.state.valid_leader(self.clock.now()) &&
            !self.state.following(peer_id.clone()) {
             // if we're already following a different node, reject
@@ -373,7 +445,9 @@ impl<C: Clock, RE> Server<C, RE> {
                     until: _,
                     tok: tok,
                 } => Some(State::Follower {
-                    term: term,
+                    // a leader that handed off to this node already follows
+                    // it, but hasn't seen its new term yet
+                    term: cmp::max(term, vote_req.get_term()),
                     id: id.clone(),
                     leader_addr: leader_addr,
                     until: self.clock.now().add(*LEADER_DURATION),
@@ -411,24 +485,43 @@ impl<C: Clock, RE> Server<C, RE> {
                      env: Envelope,
                      peer_id: PeerID,
                      append: &Append) {
-        if self.state.is_leader() {
-            warn!("Leader got append request!  This shouldn't happen.");
-            return;
-        }
-
-        let mut res = PeerMsg::new();
-        res.set_srvid(self.id.clone());
+        let mut res = self.peer_msg();
         let mut append_res = AppendRes::new();
 
-        // verify that we are following this node
-        if self.state.is_following(peer_id.clone()) {
-            // verify that it links
-            if append.get_from_term() == self.rep_log.last_accepted_term() &&
-               append.get_from_txid() == self.rep_log.last_accepted_txid() {
-
-                let mut max_term = self.rep_log.last_accepted_term();
-                let mut max_txid = self.rep_log.last_accepted_txid();
+        if !self.state.is_following(peer_id.clone()) {
+            // We haven't heard of this leader yet, or still think we're
+            // leading ourselves.  Refuse, so it sends this again once
+            // we follow it, rather than taking it as delivered.
+            warn!("{} got an append from {}, which it isn't following",
+                  self.id,
+                  peer_id);
+            append_res.set_accepted(false);
+            append_res.set_last_accepted_term(self.rep_log.last_learned_term());
+            append_res.set_last_accepted_txid(self.rep_log.last_learned_txid());
+        } else {
+            if append.has_retain_from_txid() {
+                self.retain_from = append.get_retain_from_txid();
+            }
+            // verify that it links to an entry we have
+            let from_txid = append.get_from_txid();
+            if self.rep_log.term_of(from_txid) == Some(append.get_from_term()) {
+
+                // We only answer for, and learn from, the entries this
+                // append shows we share with the leader.  Anything we
+                // have past them may be left over from an old leader.
+                let mut max_term = append.get_from_term();
+                let mut max_txid = from_txid;
+                let mut intact = true;
                 for mutation in append.get_batch() {
+                    if !checksum::mutation_intact(mutation) {
+                        // damaged on the way; have the leader send it again
+                        self.metrics.corruptions.inc();
+                        error!("mutation txid {} from {} fails its checksum",
+                               mutation.get_version().get_txid(),
+                               peer_id);
+                        intact = false;
+                        break;
+                    }
                     let version = mutation.get_version();
                     if version.get_term() < max_term {
                         error!("mutation term: {} our max: {}",
@@ -444,36 +537,66 @@ impl<C: Clock, RE> Server<C, RE> {
                     }
                     max_term = version.get_term();
                     max_txid = version.get_txid();
+                    if max_txid <= self.rep_log.last_accepted_txid() {
+                        if self.rep_log.term_of(max_txid) == Some(max_term) {
+                            // already have it
+                            continue;
+                        }
+                        if max_txid <= self.rep_log.last_learned_txid() {
+                            error!("{} sent txid {} in term {}, which we \
+                                    learned otherwise",
+                                   peer_id,
+                                   max_txid,
+                                   max_term);
+                            intact = false;
+                            break;
+                        }
+                        self.discard_after(max_txid - 1);
+                    }
                     debug!("accepting message txid {}", version.get_txid());
+                    self.metrics
+                        .log_bytes
+                        .add(mutation.compute_size() as usize);
                     self.rep_log.append(version.get_term(),
                                         version.get_txid(),
                                         mutation.clone());
+                    if mutation.has_trace() && !self.config.witness {
+                        let now = time::get_time();
+                        let trace = mutation.get_trace().clone();
+                        self.traced.insert(max_txid, (trace, now, now));
+                    }
                 }
 
-                append_res.set_accepted(true);
+                append_res.set_accepted(intact);
                 append_res.set_last_accepted_term(max_term);
                 append_res.set_last_accepted_txid(max_txid);
 
                 // Bump up generator for future use if we transition to leader.
-                self.max_generated_txid = max_txid;
-
-                for (term, txid) in
-                    self.rep_log.commit_up_to(append.get_last_learned_txid()) {
+                self.max_generated_txid = self.rep_log.last_accepted_txid();
 
+                let learnable = cmp::min(append.get_last_learned_txid(),
+                                         max_txid);
+                for (term, txid) in self.rep_log.commit_up_to(learnable) {
                     debug!("follower learning term {} txid {}", term, txid);
-                    self.learn(term, txid);
+                    self.learn(txid);
                     debug!("learned");
                 }
             } else {
-                // this update doesn't link to our last entry, so tell the
-                // leader where to replicate from.
-                warn!("failed to link msg from: {}", append.get_from_txid());
+                // this update doesn't link to what we have, so tell the
+                // leader where to replicate from: past our last entry if
+                // it's beyond it, otherwise from what we've learned,
+                // which any leader has too.
+                warn!("failed to link msg from: {}", from_txid);
                 warn!("{:?}", self.state);
+                let log = &self.rep_log;
+                let (term, txid) = if from_txid > log.last_accepted_txid() {
+                    (log.last_accepted_term(), log.last_accepted_txid())
+                } else {
+                    (log.last_learned_term(), log.last_learned_txid())
+                };
                 append_res.set_accepted(false);
-                append_res.set_last_accepted_term(self.rep_log
-                                                      .last_accepted_term());
-                append_res.set_last_accepted_txid(self.rep_log
-                                                      .last_accepted_txid());
+                append_res.set_last_accepted_term(term);
+                append_res.set_last_accepted_txid(txid);
             }
         }
 
@@ -495,6 +618,7 @@ impl<C: Clock, RE> Server<C, RE> {
         let mut accepted = vec![];
         match self.rep_peers.get_mut(&peer_id) {
             Some(ref mut rep_peer) => {
+                rep_peer.heard = true;
                 rep_peer.last_accepted_term =
                     append_res.get_last_accepted_term();
                 rep_peer.last_accepted_txid =
@@ -506,23 +630,81 @@ impl<C: Clock, RE> Server<C, RE> {
                         append_res.get_last_accepted_txid();
                 }
 
-                // see if we can mark any updates as accepted
-                accepted = self.rep_log
-                               .ack_up_to(append_res.get_last_accepted_txid(),
-                                          peer_id);
+                // see if we can mark any updates as accepted.  A refusal
+                // tells us where the follower's log ends, which may not
+                // be where ours does, so it doesn't ack anything.
+                if !rep_peer.learner && append_res.get_accepted() {
+                    let txid = append_res.get_last_accepted_txid();
+                    accepted = self.rep_log.ack_up_to(txid, peer_id.clone());
+                }
             }
             None => error!("got AppendRes for non-existent peer!"),
         }
-        for (term, txid) in accepted {
+        let learned_any = !accepted.is_empty();
+        for (_, txid) in accepted {
             debug!("leader learning txid {}", txid);
-            self.learn(term, txid);
+            self.learn(txid);
+        }
+        // stepping down empties rep_peers, so nothing more gets sent below
+        self.try_transfer();
+
+        // keep this follower's pipeline full, and let everyone know
+        // about anything that just became learned.
+        if learned_any {
+            let peer_ids: Vec<PeerID> = self.rep_peers
+                                            .keys()
+                                            .cloned()
+                                            .collect();
+            for id in peer_ids {
+                self.send_appends(&id);
+            }
+        } else {
+            self.send_appends(&peer_id);
+        }
+    }
+
+    pub fn handle(&mut self, msg: ServerMsg) {
+        match msg {
+            ServerMsg::Peer(env) => self.handle_peer(env),
+            ServerMsg::Cli(env, queued) => self.handle_queued_cli(env, queued),
+            ServerMsg::CliClosed(tok) => {
+                self.sessions.remove(&tok.as_usize());
+                self.close_watches(tok);
+            }
+            ServerMsg::Cron => self.cron(),
+            ServerMsg::Drain => self.drain(),
+            ServerMsg::Reload(config) => {
+                self.reload_config(&config);
+            }
+            ServerMsg::Mirror(changes) => self.handle_mirrored(changes),
+            ServerMsg::Disk(space) => self.check_disk(space),
+            ServerMsg::Taken => self.taking = self.taking.saturating_sub(1),
         }
+        self.metrics.pending_writes.set(self.pending.len() as isize);
     }
 
     pub fn handle_peer(&mut self, env: Envelope) {
-        let peer_msg: PeerMsg = protobuf::parse_from_bytes(env.msg.bytes())
-                                    .unwrap();
+        logging::set_context(self.log_context());
+        let peer_msg: PeerMsg =
+            match protobuf::parse_from_bytes(env.msg.bytes()) {
+                Ok(peer_msg) => peer_msg,
+                Err(e) => {
+                    warn!("dropping peer message: {}",
+                          ProtocolError::from(e));
+                    return;
+                }
+            };
         let peer_id = peer_msg.get_srvid();
+        self.metrics.peer_messages.inc();
+        if peer_msg.has_hlc() {
+            self.hlc.update(peer_msg.get_hlc());
+        }
+
+        if peer_msg.has_deadline_ms() &&
+           self.past_deadline(Some(peer_msg.get_deadline_ms())) {
+            debug!("dropping expired peer message from {}", peer_id);
+            return;
+        }
 
         if peer_msg.has_vote_res() {
             self.handle_vote_res(env,
@@ -538,80 +720,324 @@ impl<C: Clock, RE> Server<C, RE> {
             self.handle_append_res(env,
                                    peer_id.to_string(),
                                    peer_msg.get_append_res());
+        } else if peer_msg.has_timeout_now() {
+            self.handle_timeout_now(peer_id.to_string(),
+                                    peer_msg.get_timeout_now());
+        } else if peer_msg.has_pre_vote_req() {
+            self.handle_pre_vote_req(env,
+                                     peer_id.to_string(),
+                                     peer_msg.get_pre_vote_req());
+        } else if peer_msg.has_pre_vote_res() {
+            self.handle_pre_vote_res(env, peer_msg.get_pre_vote_res());
+        } else if peer_msg.has_learner_req() {
+            self.handle_learner_req(env, peer_id.to_string());
+        } else if peer_msg.has_learner_res() {
+            self.handle_learner_res(env,
+                                    peer_id.to_string(),
+                                    peer_msg.get_learner_res());
+        } else if peer_msg.has_scrub() {
+            self.handle_scrub(env, peer_id.to_string(), peer_msg.get_scrub());
+        } else if peer_msg.has_scrub_res() {
+            self.handle_scrub_res(peer_id.to_string(),
+                                  peer_msg.get_scrub_res());
+        } else if peer_msg.has_repair_req() {
+            self.handle_repair_req(env, peer_msg.get_repair_req());
+        } else if peer_msg.has_repair() {
+            self.handle_repair(peer_id.to_string(), peer_msg.get_repair());
+        } else if peer_msg.has_gossip() {
+            let gossip = peer_msg.get_gossip();
+            let now = self.clock.now();
+            if gossip.has_wall_ms() {
+                self.membership.observe_clock(&peer_id.to_string(),
+                                              gossip.get_wall_ms(),
+                                              now);
+            }
+            self.membership.merge(env.address, gossip, now);
         } else {
             error!("got unhandled peer message! {:?}", peer_msg);
         }
     }
 
-    fn handle_cli(&mut self, req: Envelope) {
-        let cli_req: CliReq = protobuf::parse_from_bytes(req.msg.bytes())
-                                  .unwrap();
+    pub fn handle_cli(&mut self, req: Envelope) {
+        self.handle_queued_cli(req, time::get_time());
+    }
+
+    fn handle_queued_cli(&mut self, req: Envelope, queued: time::Timespec) {
+        let received = time::get_time();
+        logging::set_context(self.log_context());
+        let mut cli_req: CliReq =
+            match protobuf::parse_from_bytes(req.msg.bytes()) {
+                Ok(cli_req) => cli_req,
+                Err(e) => {
+                    warn!("dropping client request: {}",
+                          ProtocolError::from(e));
+                    return;
+                }
+            };
+        if cli_req.has_export() && cli_req.get_export().has_cursor() {
+            // goes on where an earlier page left off, and is authorized
+            // and routed as where that is
+            if let Err(e) = export::resume(cli_req.mut_export()) {
+                let mut export_res = ExportRes::new();
+                export_res.set_success(false);
+                export_res.set_err(e);
+                export_res.set_done(true);
+                let mut res = CliRes::new();
+                res.set_req_id(cli_req.get_req_id());
+                res.set_export(export_res);
+                self.reply(req,
+                           ByteBuf::from_slice(&*res.write_to_bytes()
+                                                    .unwrap()));
+                return;
+            }
+        }
+        let mut context = self.log_context();
+        context.req_id = Some(cli_req.get_req_id());
+        if !request_key(&cli_req).is_empty() {
+            context.key = Some(timing::key_prefix(request_key(&cli_req)));
+        }
+        logging::set_context(context);
+        let deadline = if cli_req.has_deadline_ms() {
+            Some(cli_req.get_deadline_ms())
+        } else {
+            None
+        };
+        if self.past_deadline(deadline) {
+            // the client has already given up on this request
+            debug!("dropping expired client request {}", cli_req.get_req_id());
+            return;
+        }
+        if let Some(root_token) = self.config.root_token.clone() {
+            if let Some(res) = self.authorize(&req, &cli_req, &root_token) {
+                self.reply(req,
+                           ByteBuf::from_slice(&*res.write_to_bytes()
+                                                    .unwrap()));
+                return;
+            }
+        }
+        if let Some(reason) = self.shed(&cli_req) {
+            self.metrics.shed_requests.inc();
+            let mut error = ErrorRes::new();
+            error.set_err(reason);
+            error.set_code(ErrorCode::OVERLOADED);
+            let mut res = CliRes::new();
+            res.set_req_id(cli_req.get_req_id());
+            res.set_error(error);
+            self.reply(req,
+                       ByteBuf::from_slice(&*res.write_to_bytes()
+                                                .unwrap()));
+            return;
+        }
+        if cli_req.has_reload_config() {
+            // about this node alone, so it's never redirected
+            let mut res = CliRes::new();
+            res.set_req_id(cli_req.get_req_id());
+            let reload_res = self.handle_reload(cli_req.get_reload_config());
+            res.set_reload_config(reload_res);
+            self.reply(req,
+                       ByteBuf::from_slice(&*res.write_to_bytes()
+                                                .unwrap()));
+            return;
+        }
+        if (cli_req.has_get() && cli_req.get_get().has_snapshot()) ||
+           (cli_req.has_export() && cli_req.get_export().has_snapshot()) ||
+           (cli_req.has_snapshot() && cli_req.get_snapshot().has_release()) {
+            // only the node holding the snapshot can, leading or not
+            self.use_snapshot(req, &cli_req);
+            return;
+        }
+        if cli_req.has_backup() {
+            // likewise
+            self.start_backup(req, &cli_req);
+            return;
+        }
+        if cli_req.has_export() {
+            // as of whatever this node has applied, like a backup
+            self.start_export(req, &cli_req);
+            return;
+        }
+        if cli_req.has_stage() {
+            // every replica needs its own copy of a file to ingest
+            let mut res = CliRes::new();
+            res.set_req_id(cli_req.get_req_id());
+            res.set_stage(self.stage(cli_req.get_stage()));
+            self.reply(req,
+                       ByteBuf::from_slice(&*res.write_to_bytes()
+                                                .unwrap()));
+            return;
+        }
+        if cli_req.has_watch() {
+            // every node applies the same changes in the same order, so a
+            // watch can follow any of them
+            self.start_watch(req, &cli_req);
+            return;
+        }
+        let mut timing = self.timing(&cli_req, queued, received);
+        if cli_req.has_get() {
+            self.metrics.get_requests.inc();
+        } else if cli_req.has_set() {
+            self.metrics.set_requests.inc();
+        } else if cli_req.has_cas() {
+            self.metrics.cas_requests.inc();
+        } else if cli_req.has_del() {
+            self.metrics.del_requests.inc();
+        }
         let mut res = CliRes::new();
         res.set_req_id(cli_req.get_req_id());
-        if !self.state.is_leader() {
+        if !self.state.is_leader() &&
+           !(self.config.learner && cli_req.has_get()) {
             // If we aren't the leader, we must return some sort of
             // a RedirectRes instead of a response.
             let mut redirect_res = RedirectRes::new();
             // If we're a follower, a leader has been elected, so
             // sets the return address.
             if self.state.is_follower() {
-                let leader_address = match self.state {
+                let (leader_id, leader_address) = match self.state {
                     State::Follower{
                         term: _,
-                        id: _,
+                        id: ref id,
                         leader_addr: leader_addr,
                         until: _,
                         tok: _,
-                    } => Some(leader_addr),
+                    } => Some((id.clone(), leader_addr)),
                     _ => None,
                 }
                                          .unwrap();
+                // the leader knows better than we do where clients can
+                // reach it, if it said
+                let address = self.membership
+                                  .cli_addr_of(&leader_id)
+                                  .unwrap_or(leader_address.to_string());
                 redirect_res.set_success(true);
-                redirect_res.set_address(format!("{:?}", leader_address));
+                redirect_res.set_address(address);
             } else {
                 redirect_res.set_success(false);
-                redirect_res.set_err("No leader has been elected yet"
-                                         .to_string());
+                redirect_res.set_err(ConsensusError::NoLeader.to_string());
             }
             res.set_redirect(redirect_res);
-        } else if cli_req.has_get() {
-            let get_req = cli_req.get_get();
-            let mut get_res = GetRes::new();
-            self.db
-                .get(get_req.get_key())
-                .map(|value| {
-                    get_res.set_success(true);
-                    get_res.set_value((*value).to_vec());
-                })
-                .on_absent(|| {
-                    get_res.set_success(false);
-                    get_res.set_err("Key not found".to_string())
-                })
-                .on_error(|e| {
-                    error!("Operational problem encountered: {}", e);
-                    get_res.set_success(false);
-                    get_res.set_err("Operational problem encountered"
-                                        .to_string());
+            self.metrics.redirects.inc();
+        } else if self.transfer.is_some() && !cli_req.has_get() {
+            // hold off writes so the target can catch up; the client will
+            // retry and find the new leader
+            let mut redirect_res = RedirectRes::new();
+            redirect_res.set_success(false);
+            redirect_res.set_err(ConsensusError::Transferring.to_string());
+            res.set_redirect(redirect_res);
+            self.metrics.redirects.inc();
+        } else if let Some(err) = self.refuse_write(&cli_req) {
+            let mut error = ErrorRes::new();
+            error.set_err(err);
+            error.set_code(ErrorCode::REFUSED);
+            res.set_error(error);
+        } else if self.disk_watermark == Watermark::Hard &&
+                  fills_disk(&cli_req) {
+            self.metrics.disk_full_requests.inc();
+            let mut error = ErrorRes::new();
+            error.set_err("the disk is past its hard watermark, so only \
+                           deletes are taken"
+                              .to_string());
+            error.set_code(ErrorCode::DISK_FULL);
+            res.set_error(error);
+        } else if let Some(error) = self.busy(&cli_req) {
+            res.set_error(error);
+        } else if let Some(error) = self.over_quota(&cli_req) {
+            res.set_error(error);
+        } else if cli_req.has_promote() {
+            self.promote(req, cli_req.get_req_id(), deadline, timing);
+            return;
+        } else if cli_req.has_ingest() {
+            self.ingest(req, &cli_req, deadline, timing);
+            return;
+        } else if cli_req.has_transfer_leader() {
+            let target = cli_req.get_transfer_leader().get_target().to_string();
+            let mut transfer_res = TransferLeaderRes::new();
+            if target == self.id {
+                // nothing to do
+                transfer_res.set_success(true);
+            } else if !self.rep_peers.contains_key(&target) {
+                transfer_res.set_success(false);
+                let reason = format!("unknown peer {}", target);
+                transfer_res.set_err(ConsensusError::TransferFailed {
+                                         reason: reason,
+                                     }
+                                     .to_string());
+            } else if self.rep_peers[&target].learner {
+                transfer_res.set_success(false);
+                let reason = format!("{} is a learner", target);
+                transfer_res.set_err(ConsensusError::TransferFailed {
+                                         reason: reason,
+                                     }
+                                     .to_string());
+            } else if self.membership.is_witness(&target) {
+                transfer_res.set_success(false);
+                let reason = format!("{} is a witness", target);
+                transfer_res.set_err(ConsensusError::TransferFailed {
+                                         reason: reason,
+                                     }
+                                     .to_string());
+            } else {
+                info!("{} handing leadership to {}", self.id, target);
+                self.transfer = Some(LeaderTransfer {
+                    target: target,
+                    requester: Some((req, cli_req.get_req_id())),
+                    until: self.clock.now().add(*LEADER_DURATION),
                 });
-            get_res.set_txid(self.rep_log.last_learned_txid());
-            res.set_get(get_res);
+                // the target may well be caught up already
+                self.try_transfer();
+                return;
+            }
+            res.set_transfer_leader(transfer_res);
+        } else if cli_req.has_snapshot() {
+            self.open_snapshot(req, &cli_req);
+            return;
+        } else if cli_req.has_count() {
+            self.count(req, &cli_req);
+            return;
+        } else if cli_req.has_get() {
+            let mut job = ReadJob {
+                env: req,
+                req_id: cli_req.get_req_id(),
+                get: cli_req.get_get().clone(),
+                txid: self.rep_log.last_learned_txid(),
+                timing: timing,
+                log_context: logging::context(),
+                metrics: self.metrics.clone(),
+            };
+            self.stats.read(cli_req.get_get().get_key());
+            // reads only need the db, so hand them off if we can
+            job.timing.routed();
+            let job = match self.read_pool {
+                Some(ref pool) => {
+                    match pool.dispatch(cli_req.get_get().get_key(), job) {
+                        Ok(()) => return,
+                        Err(job) => {
+                            error!("read worker died, serving read inline");
+                            job
+                        }
+                    }
+                }
+                None => job,
+            };
+            let reply = job.run(&self.db);
+            self.rpc_tx.send_msg(reply);
+            return;
         } else if cli_req.has_set() {
             let txid = self.new_txid();
             let set_req = cli_req.get_set();
 
             // replicate the mutation
-            let mut version = Version::new();
-            version.set_txid(txid);
-            version.set_term(self.state.term().unwrap());
-
-            let mut mutation = Mutation::new();
-            mutation.set_field_type(MutationType::KVSET);
-            mutation.set_version(version);
+            let term = self.state.term().unwrap();
+            let mut mutation = self.new_mutation(term,
+                                                 txid,
+                                                 MutationType::KVSET);
             mutation.set_key(set_req.get_key().to_vec());
             mutation.set_value(set_req.get_value().to_vec());
+            copy_trace(&cli_req, &mut mutation);
 
             info!("adding pending entry for txid {}", txid);
-            self.pending.insert(txid, (req, cli_req.get_req_id()));
+            timing.routed();
+            self.pending.insert(txid,
+                                (req, cli_req.get_req_id(), deadline, timing));
             self.replicate(vec![mutation]);
             // send a response later after this txid is learned
             return;
@@ -620,18 +1046,21 @@ impl<C: Clock, RE> Server<C, RE> {
             let cas_req = cli_req.get_cas();
 
             // replicate the mutation
-            let mut version = Version::new();
-            version.set_txid(txid);
-            version.set_term(self.state.term().unwrap());
-
-            let mut mutation = Mutation::new();
-            mutation.set_field_type(MutationType::KVCAS);
-            mutation.set_version(version);
+            let term = self.state.term().unwrap();
+            let mut mutation = self.new_mutation(term,
+                                                 txid,
+                                                 MutationType::KVCAS);
             mutation.set_key(cas_req.get_key().to_vec());
             mutation.set_value(cas_req.get_new_value().to_vec());
-            mutation.set_old_value(cas_req.get_old_value().to_vec());
+            // without one, the value is only set if there's none yet
+            if cas_req.has_old_value() {
+                mutation.set_old_value(cas_req.get_old_value().to_vec());
+            }
+            copy_trace(&cli_req, &mut mutation);
 
-            self.pending.insert(txid, (req, cli_req.get_req_id()));
+            timing.routed();
+            self.pending.insert(txid,
+                                (req, cli_req.get_req_id(), deadline, timing));
             self.replicate(vec![mutation]);
             // send a response later after this txid is learned
             return;
@@ -640,157 +1069,1853 @@ impl<C: Clock, RE> Server<C, RE> {
             let del_req = cli_req.get_del();
 
             // replicate the mutation
-            let mut version = Version::new();
-            version.set_txid(txid);
-            version.set_term(self.state.term().unwrap());
-
-            let mut mutation = Mutation::new();
-            mutation.set_field_type(MutationType::KVDEL);
-            mutation.set_version(version);
+            let term = self.state.term().unwrap();
+            let mut mutation = self.new_mutation(term,
+                                                 txid,
+                                                 MutationType::KVDEL);
             mutation.set_key(del_req.get_key().to_vec());
+            copy_trace(&cli_req, &mut mutation);
 
-            self.pending.insert(txid, (req, cli_req.get_req_id()));
+            timing.routed();
+            self.pending.insert(txid,
+                                (req, cli_req.get_req_id(), deadline, timing));
             self.replicate(vec![mutation]);
             // send a response later after this txid is learned
             return;
+        } else if cli_req.has_delete_range() {
+            let range_req = cli_req.get_delete_range();
+            if range_req.get_start() >= range_req.get_end() {
+                let mut range_res = DeleteRangeRes::new();
+                range_res.set_success(false);
+                range_res.set_txid(self.rep_log.last_learned_txid());
+                range_res.set_err("a range must start before it ends"
+                                      .to_string());
+                res.set_delete_range(range_res);
+            } else {
+                let txid = self.new_txid();
+                // however many keys it covers, it's the one entry
+                let term = self.state.term().unwrap();
+                let mut mutation = self.new_mutation(term,
+                                                     txid,
+                                                     MutationType::KVDELRANGE);
+                mutation.set_key(range_req.get_start().to_vec());
+                mutation.set_value(range_req.get_end().to_vec());
+                copy_trace(&cli_req, &mut mutation);
+
+                timing.routed();
+                let req_id = cli_req.get_req_id();
+                self.pending.insert(txid, (req, req_id, deadline, timing));
+                self.replicate(vec![mutation]);
+                return;
+            }
+        } else if cli_req.has_update() {
+            let update_req = cli_req.get_update();
+            let setrange = update_req.get_op() == UpdateOp::SETRANGE;
+            let len = update_req.get_value().len() as u64;
+            if setrange &&
+               update_req.get_offset().saturating_add(len) >
+               update::MAX_SETRANGE_END {
+                let mut update_res = UpdateRes::new();
+                update_res.set_success(false);
+                update_res.set_txid(self.rep_log.last_learned_txid());
+                update_res.set_err(format!("a value can't run past {} bytes",
+                                           update::MAX_SETRANGE_END));
+                res.set_update(update_res);
+            } else {
+                let txid = self.new_txid();
+                let term = self.state.term().unwrap();
+                // replicas work out the new value as they apply it
+                let kind = update::mutation_type(update_req.get_op());
+                let mut mutation = self.new_mutation(term, txid, kind);
+                mutation.set_key(update_req.get_key().to_vec());
+                mutation.set_value(update_req.get_value().to_vec());
+                if setrange {
+                    mutation.set_offset(update_req.get_offset());
+                }
+                copy_trace(&cli_req, &mut mutation);
+
+                timing.routed();
+                let req_id = cli_req.get_req_id();
+                self.pending.insert(txid, (req, req_id, deadline, timing));
+                self.replicate(vec![mutation]);
+                return;
+            }
+        } else if cli_req.has_put_sequential() {
+            let txid = self.new_txid();
+            let sequential_req = cli_req.get_put_sequential();
+
+            let term = self.state.term().unwrap();
+            let mut mutation = self.new_mutation(term,
+                                                 txid,
+                                                 MutationType::KVSEQUENTIAL);
+            mutation.set_key(sequential_req.get_prefix().to_vec());
+            mutation.set_value(sequential_req.get_value().to_vec());
+            copy_trace(&cli_req, &mut mutation);
+
+            timing.routed();
+            let req_id = cli_req.get_req_id();
+            self.pending.insert(txid, (req, req_id, deadline, timing));
+            self.replicate(vec![mutation]);
+            return;
+        } else if cli_req.has_txn() {
+            let txid = self.new_txid();
+
+            // reads are checked as it's applied, against whatever came
+            // before it in the log
+            let term = self.state.term().unwrap();
+            let mut mutation = self.new_mutation(term,
+                                                 txid,
+                                                 MutationType::KVTXN);
+            mutation.set_key(request_key(&cli_req).to_vec());
+            mutation.set_value(cli_req.get_txn().write_to_bytes().unwrap());
+            copy_trace(&cli_req, &mut mutation);
+
+            timing.routed();
+            let req_id = cli_req.get_req_id();
+            self.pending.insert(txid, (req, req_id, deadline, timing));
+            self.replicate(vec![mutation]);
+            return;
         }
 
         self.reply(req, ByteBuf::from_slice(&*res.write_to_bytes().unwrap()));
     }
 
-    pub fn cron(&mut self) {
-        debug!("{} state: {:?}", self.id, self.state);
-        debug!("{} log: {:?}", self.id, self.rep_log);
-        // become candidate if we need to
-        if !self.state.valid_leader(self.clock.now()) &&
-           !self.state.valid_candidate(self.clock.now()) {
-            info!("{} transitioning to candidate state", self.id);
-            self.highest_term += 1;
-            self.state = State::Candidate {
-                term: self.highest_term,
-                until: self.clock.now().add(*LEADER_DURATION),
-                need: (self.peers.len() / 2 + 1) as u8,
-                have: vec![],
-            };
-            info!("{:?}", self.state);
+    // Why a client write can't be taken, if it can't: we only take writes
+    // from the cluster we mirror until we're promoted, and the mirroring
+    // bookkeeping is ours alone.
+    fn refuse_write(&self, cli_req: &CliReq) -> Option<String> {
+        if !writes(cli_req) {
+            None
+        } else if written_keys(cli_req)
+                      .iter()
+                      .any(|key| mirror::is_mirror_key(key)) {
+            Some("keys under the mirror prefix are reserved".to_string())
+        } else if written_keys(cli_req)
+                      .iter()
+                      .any(|key| ingest::is_ingest_key(key)) {
+            Some("keys under the ingest prefix are reserved".to_string())
+        } else if written_keys(cli_req)
+                      .iter()
+                      .any(|key| sequence::is_sequence_key(key)) {
+            Some("keys under the sequence prefix are reserved".to_string())
+        } else if self.mirroring() {
+            Some(format!("this cluster mirrors {} and takes no writes until \
+                          promoted",
+                         self.config.mirror_from.clone().unwrap()))
+        } else {
+            self.oversized(cli_req)
         }
+    }
 
-        // request or extend leadership
-        if self.state.should_extend_leadership(self.clock.now()) ||
-           self.state.valid_candidate(self.clock.now()) {
+    // The error to answer a request with if the node or its namespace is
+    // past a quota, spending one of the namespace's requests this second
+    // if not.  Every node serving a namespace keeps its own count of
+    // those, like the limit on each client address.  Our own bookkeeping
+    // counts towards the node's bytes but is never refused for them.
+    fn over_quota(&mut self, cli_req: &CliReq) -> Option<ErrorRes> {
+        let fills = fills_disk(cli_req);
+        let written = written_keys(cli_req);
+        let mut error = ErrorRes::new();
+        if let Some(max_bytes) = self.config.max_bytes {
+            if fills && self.stats.live_bytes() >= max_bytes &&
+               written.iter().any(|key| !reserved_key(key)) {
+                self.metrics.quota_exceeded_requests.inc();
+                error.set_err(format!("this node already holds its quota of {} \
+                                       bytes, so only deletes are taken",
+                                      max_bytes));
+                error.set_code(ErrorCode::QUOTA_EXCEEDED);
+                return Some(error);
+            }
+        }
+        let mut names: Vec<String> =
+            request_keys(cli_req)
+                .iter()
+                .filter_map(|key| self.namespaces.of(key))
+                .map(|name| name.to_string())
+                .collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            if !self.namespaces.admit(&name, self.clock.now()) {
+                self.metrics.throttled_requests.inc();
+                let spec = self.namespaces.spec(&name).unwrap();
+                error.set_err(format!("namespace {} is past its {} requests a \
+                                       second",
+                                      name,
+                                      spec.ops_per_sec));
+                error.set_code(ErrorCode::THROTTLED);
+                return Some(error);
+            }
+            if fills && self.namespaces.full(&name) &&
+               written.iter()
+                      .any(|key| self.namespaces.of(key) == Some(&name[..])) {
+                self.metrics.quota_exceeded_requests.inc();
+                let spec = self.namespaces.spec(&name).unwrap();
+                error.set_err(format!("namespace {} already holds its quota of \
+                                       {} bytes, so only deletes are taken",
+                                      name,
+                                      spec.max_bytes));
+                error.set_code(ErrorCode::QUOTA_EXCEEDED);
+                return Some(error);
+            }
+        }
+        None
+    }
 
-            debug!("broadcasting VoteReq");
-            let mut req = PeerMsg::new();
-            req.set_srvid(self.id.clone());
-            let mut vote_req = VoteReq::new();
-            vote_req.set_term(self.state.term().unwrap());
-            vote_req.set_last_accepted_term(self.rep_log.last_accepted_term());
-            vote_req.set_last_accepted_txid(self.rep_log.last_accepted_txid());
-            vote_req.set_last_learned_term(self.rep_log.last_learned_term());
-            vote_req.set_last_learned_txid(self.rep_log.last_learned_txid());
-            req.set_vote_req(vote_req);
-            self.peer_broadcast(ByteBuf::from_slice(&*req.write_to_bytes()
-                                                         .unwrap()));
+    // The BUSY error to answer a write with if we're too far behind on
+    // those we've already taken.  CRITICAL ones are always taken, as they
+    // are when shedding.
+    fn busy(&self, cli_req: &CliReq) -> Option<ErrorRes> {
+        if !writes(cli_req) || cli_req.get_priority() == Priority::CRITICAL {
+            return None;
         }
+        let retry_ms = self.config.busy_retry_ms as i64;
+        let backpressure = Backpressure {
+            max_unlearned: self.config.busy_unlearned_txids,
+            max_unapplied: self.config.busy_unapplied_txids,
+            retry_after: time::Duration::milliseconds(retry_ms),
+        };
+        let unlearned = self.rep_log
+                            .last_accepted_txid()
+                            .saturating_sub(self.rep_log.last_learned_txid());
+        let unapplied = self.unapplied.len() as u64;
+        backpressure.busy(unlearned, unapplied).map(|(err, retry_after)| {
+            self.metrics.busy_requests.inc();
+            let mut error = ErrorRes::new();
+            error.set_err(err);
+            error.set_code(ErrorCode::BUSY);
+            error.set_retry_after_ms(retry_after.num_milliseconds() as u64);
+            error
+        })
+    }
 
-        // TODO(tyler) decide on whether to use heartbeats
-        /*
-        // heartbeat
-        if self.state.is_leader() {
-            let mut version = Version::new();
-            version.set_txid(self.new_txid());
-            version.set_term(self.state.term().unwrap());
+    // Why a write's key or value is too long, if either is.  Whether an
+    // APPEND runs its value past the limit goes by what we've applied of
+    // it so far, so appends still in flight can take it a little over.
+    fn oversized(&self, cli_req: &CliReq) -> Option<String> {
+        let (keys, value_len): (Vec<&[u8]>, u64) = if cli_req.has_set() {
+            let set_req = cli_req.get_set();
+            (vec![set_req.get_key()], set_req.get_value().len() as u64)
+        } else if cli_req.has_cas() {
+            let cas_req = cli_req.get_cas();
+            (vec![cas_req.get_key()], cas_req.get_new_value().len() as u64)
+        } else if cli_req.has_delete_range() {
+            let range_req = cli_req.get_delete_range();
+            (vec![range_req.get_start(), range_req.get_end()], 0)
+        } else if cli_req.has_update() {
+            let update_req = cli_req.get_update();
+            let len = update_req.get_value().len() as u64;
+            let value_len = match update_req.get_op() {
+                UpdateOp::APPEND => {
+                    let old = checksum::get(&self.db, update_req.get_key());
+                    match self.metrics.checked(old) {
+                        Ok(Some(old_val)) => old_val.len() as u64 + len,
+                        _ => len,
+                    }
+                }
+                UpdateOp::GETSET => len,
+                UpdateOp::SETRANGE => {
+                    update_req.get_offset().saturating_add(len)
+                }
+            };
+            (vec![update_req.get_key()], value_len)
+        } else if cli_req.has_put_sequential() {
+            let sequential_req = cli_req.get_put_sequential();
+            let key = sequence::sequential_key(sequential_req.get_prefix(), 0);
+            return self.too_long(key.len() as u64,
+                                 sequential_req.get_value().len() as u64);
+        } else if cli_req.has_txn() {
+            let writes = cli_req.get_txn().get_writes();
+            (written_keys(cli_req),
+             writes.iter()
+                   .map(|write| write.get_value().len() as u64)
+                   .max()
+                   .unwrap_or(0))
+        } else {
+            (vec![request_key(cli_req)], 0)
+        };
+        let key_len = keys.iter().map(|k| k.len() as u64).max().unwrap_or(0);
+        self.too_long(key_len, value_len)
+    }
 
-            let mut mutation = Mutation::new();
-            mutation.set_field_type(MutationType::KVSET);
-            mutation.set_version(version);
-            mutation.set_key(b"heartbeat".to_vec());
-            mutation.set_value(format!("{}", self.clock.now().sec)
-                             .as_bytes()
-                             .to_vec());
+    fn too_long(&self, key_len: u64, value_len: u64) -> Option<String> {
+        let (max_key, max_value) = (self.config.max_key_bytes as u64,
+                                    self.config.max_value_bytes as u64);
+        if max_key > 0 && key_len > max_key {
+            Some(format!("a key of {} bytes is over the limit of {}",
+                         key_len,
+                         max_key))
+        } else if max_value > 0 && value_len > max_value {
+            Some(format!("a value of {} bytes is over the limit of {}",
+                         value_len,
+                         max_value))
+        } else {
+            None
+        }
+    }
 
+    fn mirroring(&self) -> bool {
+        self.config.mirror_from.is_some() && !self.promoted
+    }
 
-            self.replicate(vec![mutation]);
+    // Replicates changes the cluster we mirror applied as our own, each
+    // noting the txid it had there.  They're in the order that cluster
+    // applied them, and keep it in our log.
+    fn handle_mirrored(&mut self, changes: Vec<Mutation>) {
+        if !self.state.is_leader() || !self.mirroring() {
+            debug!("dropping {} mirrored changes now that we don't mirror",
+                   changes.len());
+            return;
         }
-        */
+        let mut mutations = vec![];
+        for change in changes {
+            let txid = self.new_txid();
+            let term = self.state.term().unwrap();
+            let mut mutation = self.new_mutation(term,
+                                                 txid,
+                                                 change.get_field_type());
+            mutation.set_key(change.get_key().to_vec());
+            if change.has_value() {
+                mutation.set_value(change.get_value().to_vec());
+            }
+            if change.has_offset() {
+                mutation.set_offset(change.get_offset());
+            }
+            mutation.set_source_txid(change.get_version().get_txid());
+            mutations.push(mutation);
+        }
+        self.replicate(mutations);
     }
 
-    fn new_txid(&mut self) -> TXID {
-        self.max_generated_txid += 1;
-        info!("generating txid {}, {:?}",
-              self.max_generated_txid,
-              self.rep_log);
-        self.max_generated_txid
+    // Stops mirroring, cluster-wide and for good, once every node has
+    // applied what we've mirrored so far.
+    fn promote(&mut self,
+               req: Envelope,
+               req_id: u64,
+               deadline: Option<u64>,
+               mut timing: Timing) {
+        if !self.mirroring() {
+            let mut promote_res = PromoteRes::new();
+            promote_res.set_success(false);
+            promote_res.set_err("this cluster doesn't mirror another"
+                                    .to_string());
+            let mut res = CliRes::new();
+            res.set_req_id(req_id);
+            res.set_promote(promote_res);
+            self.reply(req,
+                       ByteBuf::from_slice(&*res.write_to_bytes()
+                                                .unwrap()));
+            return;
+        }
+        let txid = self.new_txid();
+        let term = self.state.term().unwrap();
+        let mut mutation = self.new_mutation(term, txid, MutationType::KVSET);
+        mutation.set_key(mirror::PROMOTED_KEY.to_vec());
+        mutation.set_value(vec![]);
+
+        info!("{} promoting the cluster to take its own writes", self.id);
+        timing.routed();
+        self.pending.insert(txid, (req, req_id, deadline, timing));
+        self.replicate(vec![mutation]);
     }
 
-    fn reply(&mut self, req: Envelope, res_buf: ByteBuf) {
-        self.rpc_tx.send_msg(Envelope {
-            address: req.address,
-            tok: req.tok,
-            msg: res_buf,
-        });
+    fn stage(&self, stage: &StageReq) -> StageRes {
+        let mut stage_res = StageRes::new();
+        if self.config.witness {
+            stage_res.set_success(false);
+            stage_res.set_err("a witness keeps no data to load".to_string());
+            return stage_res;
+        }
+        if self.disk_watermark == Watermark::Hard {
+            stage_res.set_success(false);
+            stage_res.set_err("the disk is past its hard watermark"
+                                  .to_string());
+            return stage_res;
+        }
+        match ingest::stage(&self.ingest_dir,
+                            stage.get_file(),
+                            stage.get_offset(),
+                            stage.get_data()) {
+            Ok(bytes) => {
+                stage_res.set_success(true);
+                stage_res.set_bytes(bytes);
+            }
+            Err(e) => {
+                warn!("could not stage {}: {}", stage.get_file(), e);
+                stage_res.set_success(false);
+                stage_res.set_err(e.to_string());
+            }
+        }
+        stage_res
     }
 
-    fn peer_broadcast(&mut self, msg: ByteBuf) {
-        self.rpc_tx.send_msg(Envelope {
-            address: None,
-            tok: PEER_BROADCAST,
-            msg: msg,
-        });
+    // Replicates the entry that has every node load a staged file, once
+    // we've checked our own copy of it.
+    fn ingest(&mut self,
+              req: Envelope,
+              cli_req: &CliReq,
+              deadline: Option<u64>,
+              mut timing: Timing) {
+        let file = cli_req.get_ingest().get_file();
+        let keys = match ingest::check(&self.ingest_dir, file) {
+            Ok(header) => header.keys,
+            Err(e) => {
+                let mut ingest_res = IngestRes::new();
+                ingest_res.set_success(false);
+                ingest_res.set_err(format!("can't ingest {}: {}", file, e));
+                let mut res = CliRes::new();
+                res.set_req_id(cli_req.get_req_id());
+                res.set_ingest(ingest_res);
+                self.reply(req,
+                           ByteBuf::from_slice(&*res.write_to_bytes()
+                                                    .unwrap()));
+                return;
+            }
+        };
+        let txid = self.new_txid();
+        let term = self.state.term().unwrap();
+        let mut mutation = self.new_mutation(term, txid, MutationType::KVSET);
+        mutation.set_key(ingest::marker(file));
+        mutation.set_value(ingest::marker_value(keys));
+
+        info!("{} ingesting {} keys from {} at txid {}",
+              self.id,
+              keys,
+              file,
+              txid);
+        timing.routed();
+        let req_id = cli_req.get_req_id();
+        self.pending.insert(txid, (req, req_id, deadline, timing));
+        self.replicate(vec![mutation]);
     }
 
-    fn replicate(&mut self, mutations: Vec<Mutation>) {
-        if mutations.len() > 0 {
-            for mutation in mutations {
-                let txid = mutation.get_version().get_txid();
-                self.rep_log.append(mutation.get_version().get_term(),
-                                    txid,
-                                    mutation);
-
-                // this should only be learned on single replica collections
-                let accepted = self.rep_log.ack_up_to(txid, self.id.clone());
-                for (term, txid) in accepted {
-                    debug!("leader learning txid {}", txid);
-                    self.learn(term, txid);
+    // Applies the entry fencing in a bulk load, loading the file it names
+    // into the same group.  Nothing's applied if the file can't be loaded.
+    fn apply_ingest(&self,
+                    group: &mut GroupCommit,
+                    mutation: &Mutation)
+                    -> CliRes {
+        let file = ingest::marked_file(mutation.get_key());
+        let mut ingest_res = IngestRes::new();
+        ingest_res.set_txid(mutation.get_version().get_txid());
+        let loaded = if !checksum::mutation_intact(mutation) {
+            let txid = mutation.get_version().get_txid();
+            Err(StorageError::Corrupt(format!("mutation txid {} fails its \
+                                               checksum",
+                                              txid)))
+        } else {
+            ingest::load(&self.ingest_dir, &file, group)
+        };
+        let loaded = self.metrics.checked(loaded);
+        match loaded {
+            Ok(keys) => {
+                let res = self.apply(group, mutation);
+                ingest_res.set_success(res.get_set().get_success());
+                if res.get_set().has_err() {
+                    ingest_res.set_err(res.get_set().get_err().to_string());
                 }
+                ingest_res.set_keys(keys);
             }
+            Err(e) => {
+                error!("{} not ingesting {}, so diverging from those that \
+                        did: {}",
+                       self.id,
+                       file,
+                       e);
+                ingest_res.set_success(false);
+                ingest_res.set_err(e.to_string());
+                ingest_res.set_keys(ingest::marked_keys(mutation.get_value()));
+            }
+        }
+        let mut res = CliRes::new();
+        res.set_ingest(ingest_res);
+        res
+    }
+
+    // Who we are and what we're doing, for everything we log.
+    fn log_context(&self) -> logging::Context {
+        logging::Context {
+            node: Some(self.id.clone()),
+            role: Some(status::role(&self.state)),
+            term: self.state.term(),
+            ..logging::Context::default()
+        }
+    }
+
+    // Starts timing a key-value request, as of when it was queued for us.
+    fn timing(&self,
+              cli_req: &CliReq,
+              queued: time::Timespec,
+              received: time::Timespec)
+              -> Timing {
+        let (op, key) = if cli_req.has_get() {
+            ("get", cli_req.get_get().get_key())
+        } else if cli_req.has_set() {
+            ("set", cli_req.get_set().get_key())
+        } else if cli_req.has_cas() {
+            ("cas", cli_req.get_cas().get_key())
+        } else if cli_req.has_delete_range() {
+            ("delete_range", cli_req.get_delete_range().get_start())
+        } else if cli_req.has_update() {
+            ("update", cli_req.get_update().get_key())
+        } else if cli_req.has_put_sequential() {
+            ("put_sequential", cli_req.get_put_sequential().get_prefix())
+        } else if cli_req.has_txn() {
+            ("txn", request_key(cli_req))
+        } else {
+            ("del", cli_req.get_del().get_key())
+        };
+        let mut timing = Timing::new(op,
+                                     key,
+                                     queued,
+                                     received,
+                                     self.config.slow_request_ms);
+        if cli_req.has_trace() {
+            timing.trace(&self.id, cli_req.get_trace());
+        }
+        timing
+    }
+
+    // Why to turn a request away because the handler it needs is falling
+    // behind, if we should.  Signing in, handing off leadership,
+    // reloading config, backing up, bulk loading and exporting are never
+    // shed.
+    fn shed(&self, cli_req: &CliReq) -> Option<String> {
+        if cli_req.has_auth() || cli_req.has_transfer_leader() ||
+           cli_req.has_reload_config() || cli_req.has_backup() ||
+           cli_req.has_stage() || cli_req.has_ingest() || cli_req.has_export() {
+            return None;
+        }
+        let priority = if cli_req.has_priority() {
+            cli_req.get_priority()
+        } else {
+            Priority::NORMAL
+        };
+        let p99_ms = self.config.shed_p99_ms as i64;
+        let admission = Admission {
+            max_queue: self.config.shed_queue_depth,
+            max_p99: time::Duration::milliseconds(p99_ms),
+        };
+        let queued = cmp::max(self.metrics.mailbox_depth.get(), 0) as usize;
+        if cli_req.has_get() || cli_req.has_watch() {
+            admission.shed(priority, queued, admission::READ_LATENCY.p99())
+        } else {
+            admission.shed(priority,
+                           queued + self.pending.len(),
+                           admission::WRITE_LATENCY.p99())
+        }
+    }
+
+    // Backs up everything we've applied so far.  The backup worker writes
+    // it out, and we apply nothing more until it has its snapshot of the
+    // db, so that the backup is exactly as of the txid it's recorded with.
+    // We go on answering everything else meanwhile.
+    fn start_backup(&mut self, req: Envelope, cli_req: &CliReq) {
+        let backup = cli_req.get_backup();
+        if backup.has_dir() == backup.has_addr() {
+            let mut backup_res = BackupRes::new();
+            backup_res.set_success(false);
+            backup_res.set_err("a backup needs either a dir or an addr"
+                                   .to_string());
+            let mut res = CliRes::new();
+            res.set_req_id(cli_req.get_req_id());
+            res.set_backup(backup_res);
+            self.reply(req,
+                       ByteBuf::from_slice(&*res.write_to_bytes()
+                                                .unwrap()));
+            return;
+        }
+        let txid = self.applied_txid();
+        let since = if backup.has_since_txid() {
+            Some(backup.get_since_txid())
+        } else if backup.get_incremental() && backup.has_dir() {
+            backup::read_manifest(backup.get_dir())
+                .ok()
+                .and_then(|manifest| manifest.last().map(|entry| entry.txid))
+        } else {
+            None
+        };
+        let changed = since.and_then(|since| {
+            let keys = self.changed_since(since, txid);
+            if keys.is_none() {
+                info!("{} can't tell what changed since txid {}, so backing \
+                       up everything",
+                      self.id,
+                      since);
+            }
+            keys.map(|keys| (since, keys))
+        });
+        let term = self.rep_log
+                       .term_of(txid)
+                       .unwrap_or(self.rep_log.last_learned_term());
+        let job = BackupJob {
+            env: req,
+            req_id: cli_req.get_req_id(),
+            backup: backup.clone(),
+            node: self.id.clone(),
+            txid: txid,
+            term: term,
+            hlc: self.hlc.timestamp(),
+            changed: changed,
+            taken: self.taken(),
+        };
+        info!("{} backing up as of txid {}", self.id, txid);
+        let job = match self.backup_pool {
+            Some(ref pool) => {
+                match pool.dispatch(&[], job) {
+                    Ok(()) => return,
+                    Err(job) => {
+                        error!("backup worker died, backing up inline");
+                        job
+                    }
+                }
+            }
+            None => job,
+        };
+        let reply = job.run(&self.db);
+        self.rpc_tx.send_msg(reply);
+    }
+
+    // Counts the keys a client asked about, as of what we've applied.
+    fn count(&mut self, req: Envelope, cli_req: &CliReq) {
+        let count_req = cli_req.get_count();
+        let end = if count_req.has_end() {
+            Some(count_req.get_end())
+        } else {
+            None
+        };
+        let count = self.stats.count(&self.db,
+                                     count_req.get_start(),
+                                     end,
+                                     stats::EXACT_COUNT_KEYS);
+        let mut count_res = CountRes::new();
+        count_res.set_success(true);
+        count_res.set_keys(count.keys);
+        count_res.set_bytes(count.bytes);
+        count_res.set_exact(count.exact);
+        count_res.set_txid(self.applied_txid());
+        let mut res = CliRes::new();
+        res.set_req_id(cli_req.get_req_id());
+        res.set_count(count_res);
+        self.reply(req, ByteBuf::from_slice(&*res.write_to_bytes().unwrap()));
+    }
+
+    // Opens a snapshot as of what we've applied so far, applying nothing
+    // more until the snapshot worker has taken it, just as for a backup.
+    fn open_snapshot(&mut self, req: Envelope, cli_req: &CliReq) {
+        let txid = self.applied_txid();
+        let job = SnapshotJob::Open {
+            env: req,
+            req_id: cli_req.get_req_id(),
+            txid: txid,
+            taken: self.taken(),
+        };
+        if self.dispatch_snapshot(job) {
+            debug!("{} opened a snapshot as of txid {}", self.id, txid);
+        }
+    }
+
+    // Reads from, or releases, a snapshot a client opened here.
+    fn use_snapshot(&mut self, req: Envelope, cli_req: &CliReq) {
+        let job = if cli_req.has_get() {
+            SnapshotJob::Get {
+                env: req,
+                req_id: cli_req.get_req_id(),
+                get: cli_req.get_get().clone(),
+            }
+        } else if cli_req.has_export() {
+            SnapshotJob::Export(ExportJob {
+                env: req,
+                req_id: cli_req.get_req_id(),
+                export: cli_req.get_export().clone(),
+                txid: 0,
+                taken: Taken::none(),
+            })
+        } else {
+            SnapshotJob::Release {
+                env: req,
+                req_id: cli_req.get_req_id(),
+                id: cli_req.get_snapshot().get_release(),
+            }
+        };
+        self.dispatch_snapshot(job);
+    }
+
+    // What a job taking a snapshot of the db tells us it has with.  We
+    // count each one handed out, and apply nothing until we've heard back
+    // from them all, so that the snapshot is of exactly what we'd applied
+    // without the server thread having to wait for it.  Jobs run on this
+    // thread, as when embedded, have taken theirs before we could apply
+    // anything anyway.
+    fn taken(&mut self) -> Taken {
+        match self.backchannel {
+            Some(ref backchannel) => {
+                self.taking += 1;
+                backchannel.taken()
+            }
+            None => Taken::none(),
+        }
+    }
+
+    // Hands a job to the snapshot worker, returning whether it took it.
+    // Snapshots are held open on its thread, so without one, as when
+    // embedded, they're refused.
+    fn dispatch_snapshot(&mut self, job: SnapshotJob) -> bool {
+        let job = match self.snapshot_worker {
+            Some(ref worker) => {
+                match worker.dispatch(job) {
+                    Ok(()) => return true,
+                    Err(job) => {
+                        error!("snapshot worker died");
+                        job
+                    }
+                }
+            }
+            None => job,
+        };
+        let err = "this node doesn't serve snapshots".to_string();
+        let reply = match job {
+            SnapshotJob::Open { env, req_id, .. } |
+            SnapshotJob::Get { env, req_id, .. } |
+            SnapshotJob::Release { env, req_id, .. } => {
+                let mut snapshot_res = SnapshotRes::new();
+                snapshot_res.set_success(false);
+                snapshot_res.set_err(err);
+                snapshot::snapshot_reply(env, req_id, snapshot_res)
+            }
+            SnapshotJob::Export(job) => {
+                job.reply(false, vec![], Some(err), true)
+            }
+        };
+        self.rpc_tx.send_msg(reply);
+        false
+    }
+
+    // Exports the keys asked for as of what we've applied so far, applying
+    // nothing more until the export worker has its snapshot, just as for
+    // a backup.
+    fn start_export(&mut self, req: Envelope, cli_req: &CliReq) {
+        let job = ExportJob {
+            env: req,
+            req_id: cli_req.get_req_id(),
+            export: cli_req.get_export().clone(),
+            txid: self.applied_txid(),
+            taken: self.taken(),
+        };
+        if self.config.witness {
+            let err = "a witness keeps no data to export".to_string();
+            let reply = job.reply(false, vec![], Some(err), true);
+            self.rpc_tx.send_msg(reply);
+            return;
+        }
+        info!("{} exporting as of txid {}", self.id, job.txid);
+        let job = match self.export_pool {
+            Some(ref pool) => {
+                match pool.dispatch(&[], job) {
+                    Ok(()) => return,
+                    Err(job) => {
+                        error!("export worker died, exporting inline");
+                        job
+                    }
+                }
+            }
+            None => job,
+        };
+        let rpc_tx = &self.rpc_tx;
+        job.run(&self.db, |env| {
+            rpc_tx.send_msg(env);
+        });
+    }
+
+    // Starts sending a client the changes we apply to what it watches,
+    // after any it asked for that we've already applied.
+    fn start_watch(&mut self, req: Envelope, cli_req: &CliReq) {
+        let watch_req = cli_req.get_watch();
+        let applied = self.applied_txid();
+        let credentials = match self.sessions.get(&req.tok.as_usize()) {
+            Some(principal) => principal == auth::ROOT,
+            // nobody signs in without a root token
+            None => self.config.root_token.is_none(),
+        };
+        let mut watch = Watch::new(req,
+                                   cli_req.get_req_id(),
+                                   watch_req,
+                                   credentials,
+                                   applied);
+        let history = if self.config.witness {
+            Err("a witness keeps no data to watch".to_string())
+        } else if watch_req.get_historical() {
+            self.history(watch_req.get_last_txid(), applied)
+        } else {
+            Ok(vec![])
+        };
+        let history = match history {
+            Ok(history) => history,
+            Err(e) => {
+                let reply = watch.reply(watch::reply(false, vec![], Some(e)));
+                self.rpc_tx.send_msg(reply);
+                return;
+            }
+        };
+        info!("{} watching {} from txid {}",
+              self.id,
+              timing::key_prefix(&watch.key),
+              if watch_req.get_historical() {
+                  watch_req.get_last_txid()
+              } else {
+                  applied
+              });
+        let ack = watch.reply(watch::reply(true, vec![], None));
+        self.rpc_tx.send_msg(ack);
+        for reply in watch.send(&history, applied) {
+            self.rpc_tx.send_msg(reply);
+        }
+        watch.protect(&mut self.horizon,
+                      self.clock.now(),
+                      self.config.watch_retention_ms);
+        self.watches.push(watch);
+        self.metrics.watches.set(self.watches.len() as isize);
+    }
+
+    // The mutations applied after since up to txid that changed something,
+    // if our log still has every entry in between.
+    fn history(&self,
+               since: TXID,
+               txid: TXID)
+               -> Result<Vec<Mutation>, String> {
+        let mut history = vec![];
+        for t in since + 1..txid + 1 {
+            match self.rep_log.get(t) {
+                Some(_) if self.unchanged.contains(&t) => (),
+                Some(mutation) => history.push(mutation),
+                None => {
+                    return Err(format!("the log here no longer goes back to \
+                                        txid {}",
+                                       t))
+                }
+            }
+        }
+        Ok(history)
+    }
+
+    // Sends each watch the changes just applied that it covers.
+    fn notify_watches(&mut self, changes: &[Mutation], txid: TXID) {
+        let now = self.clock.now();
+        let mut replies = vec![];
+        for watch in self.watches.iter_mut() {
+            replies.extend(watch.send(changes, txid));
+            watch.protect(&mut self.horizon,
+                          now,
+                          self.config.watch_retention_ms);
+        }
+        for reply in replies {
+            self.rpc_tx.send_msg(reply);
+        }
+    }
+
+    // Drops the watches of a client that's gone away.  Their consumers'
+    // place in the log is held for a while longer, in case they come back.
+    fn close_watches(&mut self, tok: Token) {
+        let (closed, open): (Vec<Watch>, Vec<Watch>) =
+            self.watches
+                .drain(..)
+                .partition(|watch| watch.env.tok == tok);
+        self.watches = open;
+        let now = self.clock.now();
+        for watch in closed {
+            debug!("watch {} went away after txid {}",
+                   watch.req_id,
+                   watch.sent);
+            watch.protect(&mut self.horizon,
+                          now,
+                          self.config.watch_retention_ms);
+        }
+        self.metrics.watches.set(self.watches.len() as isize);
+    }
+
+    // The keys written after since up to txid, if our log still has every
+    // entry in between.  A bulk load, a range delete or a sequential put in
+    // between touches keys the log never names, so there's no telling then
+    // either.
+    fn changed_since(&self,
+                     since: TXID,
+                     txid: TXID)
+                     -> Option<BTreeSet<Vec<u8>>> {
+        if since > txid {
+            return None;
+        }
+        let mut keys = BTreeSet::new();
+        for t in since + 1..txid + 1 {
+            let mutation = match self.rep_log.get(t) {
+                Some(mutation) => mutation,
+                None => return None,
+            };
+            if ingest::is_ingest_key(mutation.get_key()) ||
+               mutation.get_field_type() == MutationType::KVDELRANGE ||
+               mutation.get_field_type() == MutationType::KVSEQUENTIAL {
+                return None;
+            }
+            if mutation.get_field_type() == MutationType::KVTXN {
+                match txn::writes(&mutation) {
+                    Ok(writes) => {
+                        keys.extend(writes.iter().map(|w| w.get_key().to_vec()))
+                    }
+                    Err(_) => return None,
+                }
+                continue;
+            }
+            keys.insert(mutation.get_key().to_vec());
+        }
+        Some(keys)
+    }
+
+    fn handle_reload(&mut self, reload: &ReloadConfigReq) -> ReloadConfigRes {
+        let mut res = ReloadConfigRes::new();
+        match self.config.update(reload.get_settings()) {
+            Ok(config) => {
+                let (changed, fixed) = self.reload_config(&config);
+                res.set_success(true);
+                let changed = changed.iter().map(|f| f.to_string()).collect();
+                let fixed = fixed.iter().map(|f| f.to_string()).collect();
+                res.set_changed(RepeatedField::from_vec(changed));
+                res.set_needs_restart(RepeatedField::from_vec(fixed));
+            }
+            Err(e) => {
+                res.set_success(false);
+                res.set_err(e);
+            }
+        }
+        res
+    }
+
+    // Switches to whatever settings in config can change while we run, all
+    // at once, returning which changed and which need a restart.
+    pub fn reload_config(&mut self,
+                         config: &ServerConfig)
+                         -> (Vec<&'static str>, Vec<&'static str>) {
+        let (changed, fixed) = self.config.reload(config);
+        if !changed.is_empty() {
+            info!("{} reloaded {}", self.id, changed.join(", "));
+        }
+        if !fixed.is_empty() {
+            warn!("{} needs a restart to change {}", self.id, fixed.join(", "));
+        }
+        self.metrics.config_reloads.inc();
+        self.membership.set_phi_threshold(self.config.phi_threshold);
+        if let Some(level) = self.config.log_level {
+            logging::set_level(level);
+        }
+        if let Some(format) = self.config.log_format {
+            logging::set_format(format);
+        }
+        *self.live_config.write().unwrap() = self.config.clone();
+        // for the traffic cop to pick up its limits from live_config
+        self.rpc_tx.send_msg(Envelope {
+            address: None,
+            tok: RELOAD,
+            msg: ByteBuf::none(),
+        });
+        (changed, fixed)
+    }
+
+    // Checks a request against the session it arrived on, returning the
+    // response to send instead of serving it if there is one.
+    fn authorize(&mut self,
+                 req: &Envelope,
+                 cli_req: &CliReq,
+                 root_token: &str)
+                 -> Option<CliRes> {
+        let mut res = CliRes::new();
+        res.set_req_id(cli_req.get_req_id());
+        if cli_req.has_auth() {
+            let mut auth_res = AuthRes::new();
+            let token = cli_req.get_auth().get_token();
+            let principal = auth::principal(&self.db, root_token, token);
+            match self.metrics.checked(principal) {
+                Ok(Some(principal)) => {
+                    debug!("client {:?} authenticated as {}",
+                           req.address,
+                           principal);
+                    auth_res.set_success(true);
+                    auth_res.set_principal(principal.clone());
+                    self.sessions.insert(req.tok.as_usize(), principal);
+                }
+                Ok(None) => {
+                    warn!("client {:?} presented an unknown token",
+                          req.address);
+                    self.metrics.auth_failures.inc();
+                    auth_res.set_success(false);
+                    auth_res.set_err("invalid token".to_string());
+                    self.sessions.remove(&req.tok.as_usize());
+                }
+                Err(e) => {
+                    error!("failed to look up credentials: {}", e);
+                    auth_res.set_success(false);
+                    auth_res.set_err(e.to_string());
+                }
+            }
+            res.set_auth(auth_res);
+            return Some(res);
+        }
+
+        let err = match self.sessions.get(&req.tok.as_usize()) {
+            None => "unauthenticated",
+            Some(principal) if principal != auth::ROOT &&
+                               (cli_req.has_transfer_leader() ||
+                                cli_req.has_reload_config() ||
+                                cli_req.has_backup() ||
+                                cli_req.has_stage() ||
+                                cli_req.has_ingest() ||
+                                cli_req.has_export() ||
+                                request_keys(cli_req).iter().any(|key| {
+                                    auth::is_credentials_key(key) ||
+                                    namespace::is_namespace_key(key)
+                                })) => {
+                "permission denied"
+            }
+            Some(principal) if principal != auth::ROOT => {
+                let spans = request_spans(cli_req);
+                let permitted = spans.iter().all(|span| {
+                    let (ref start, ref end, write) = *span;
+                    self.namespaces.permits(principal,
+                                            start,
+                                            end.as_ref().map(|e| &e[..]),
+                                            write)
+                });
+                if permitted {
+                    return None;
+                }
+                "permission denied"
+            }
+            Some(_) => return None,
+        };
+        self.metrics.auth_failures.inc();
+        let mut error_res = ErrorRes::new();
+        error_res.set_err(err.to_string());
+        res.set_error(error_res);
+        Some(res)
+    }
+
+    pub fn cron(&mut self) {
+        logging::set_context(self.log_context());
+        debug!("{} state: {:?}", self.id, self.state);
+        debug!("{} log: {:?}", self.id, self.rep_log);
+
+        // Stop waiting on writes whose clients have given up.  The
+        // mutations themselves are still applied once learned.
+        self.cancel_expired_pending();
+        self.try_transfer();
+        if self.draining {
+            // retries handing off if the last attempt timed out
+            self.drain();
+        }
+        if let Err(e) = self.wal.tick(&self.db, self.clock.now()) {
+            error!("failed to sync WAL: {}", e);
+        }
+        self.collect_garbage();
+        self.stats.tick(self.clock.now());
+        self.gossip();
+        self.maybe_scrub();
+        if self.state.is_leader() {
+            let accepted = self.rep_log.last_accepted_txid();
+            let lag = self.rep_peers
+                          .values()
+                          .map(|peer| {
+                              accepted.saturating_sub(peer.last_accepted_txid)
+                          })
+                          .max()
+                          .unwrap_or(0);
+            self.metrics.replication_lag.set(lag as isize);
+            self.resend_unacked();
+        }
+        // a lease timed by a clock that's off could outlast the one a new
+        // leader is elected under, so we neither take nor extend one
+        let skewed = self.check_clock();
+        // become candidate if we need to
+        if self.config.learner {
+            self.request_replication();
+        } else if self.config.standalone {
+            if !self.draining {
+                self.lead_alone();
+            }
+        } else if !self.draining && !self.config.witness && !skewed &&
+           !self.state.valid_leader(self.clock.now()) &&
+           !self.state.valid_candidate(self.clock.now()) &&
+           !self.leader_seems_alive() {
+            if self.config.pre_vote {
+                self.pre_vote();
+            } else {
+                self.become_candidate();
+            }
+        }
+
+        // request or extend leadership, unless there's nobody to ask
+        let now = self.clock.now();
+        if !self.config.standalone &&
+           ((self.state.should_extend_leadership(now) && !skewed) ||
+            self.state.valid_candidate(now)) {
+
+            self.request_votes(None);
+        }
+
+        // TODO(tyler) decide on whether to use heartbeats
+        /*
+        // heartbeat
+        if self.state.is_leader() {
+            let mut version = Version::new();
+            version.set_txid(self.new_txid());
+            version.set_term(self.state.term().unwrap());
+
+            let mut mutation = Mutation::new();
+            mutation.set_field_type(MutationType::KVSET);
+            mutation.set_version(version);
+            mutation.set_key(b"heartbeat".to_vec());
+            mutation.set_value(format!("{}", self.clock.now().sec)
+                             .as_bytes()
+                             .to_vec());
+
+
+            self.replicate(vec![mutation]);
+        }
+        */
+    }
+
+    // Notes which disk watermark we're past, complaining as we cross one.
+    fn check_disk(&mut self, space: DiskSpace) {
+        let watermark = space.watermark(self.config.disk_soft_watermark,
+                                        self.config.disk_hard_watermark);
+        self.metrics.disk_free_bytes.set(space.free_bytes as isize);
+        self.metrics.disk_watermark.set(watermark as isize);
+        if watermark != self.disk_watermark {
+            let used = (space.used() * 100.0).round();
+            match watermark {
+                Watermark::Hard => {
+                    error!("disk is {}% used, past the hard watermark; \
+                            refusing writes",
+                           used)
+                }
+                Watermark::Soft => {
+                    warn!("disk is {}% used, past the soft watermark", used)
+                }
+                Watermark::Below => {
+                    info!("disk is {}% used, below the watermarks again", used)
+                }
+            }
+        }
+        self.disk_watermark = watermark;
+    }
+
+    // A lease can lapse just because one extension got lost.  If the
+    // leader is still gossiping as regularly as ever, give it a little
+    // longer to extend it rather than starting an election.
+    fn leader_seems_alive(&self) -> bool {
+        let now = self.clock.now();
+        match self.state {
+            State::Follower{id: ref id, until: until, ..} => {
+                let grace_ms = self.config.election_grace_ms as i64;
+                let grace = time::Duration::milliseconds(grace_ms);
+                now < until.add(grace) &&
+                self.membership
+                    .phi(id, now)
+                    .map(|phi| phi < self.config.phi_threshold)
+                    .unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+
+    // Whether our clock is too far from the cluster's to time leases by,
+    // raising the alarm when that changes.
+    fn check_clock(&mut self) -> bool {
+        let now = self.clock.now();
+        let offset = self.membership.max_clock_offset(now);
+        self.metrics.clock_offset.set(offset as isize);
+        let max_offset = self.config.max_clock_offset_ms;
+        let skewed = max_offset > 0 &&
+                     self.membership
+                         .clock_skewed(max_offset, self.peers.len(), now);
+        if skewed && !self.clock_skewed {
+            error!("{} clock is over {}ms off from a quorum of peers; not \
+                    leading until it's fixed",
+                   self.id,
+                   max_offset);
+        } else if !skewed && self.clock_skewed {
+            info!("{} clock agrees with a quorum of peers again", self.id);
+        }
+        self.clock_skewed = skewed;
+        self.metrics.clock_skewed.set(skewed as isize);
+        skewed
+    }
+
+    fn gossip(&mut self) {
+        let leader = match self.state {
+            State::Follower{id: ref id, ..} => Some(id.clone()),
+            State::Leader{..} => Some(self.id.clone()),
+            _ => None,
+        };
+        let load = self.pending.len() as u64;
+        let gossip = self.membership.tick(leader, load, self.clock.now());
+        let mut peer_msg = self.peer_msg();
+        peer_msg.set_gossip(gossip);
+        self.peer_broadcast(ByteBuf::from_slice(&*peer_msg.write_to_bytes()
+                                                          .unwrap()));
+    }
+
+    fn pre_vote(&mut self) {
+        let now = self.clock.now();
+        let term = self.highest_term + 1;
+        match self.pre_vote {
+            Some(ref pre_vote) if pre_vote.term == term &&
+                                  now < pre_vote.until => return,
+            _ => (),
+        }
+        debug!("{} asking for pre-votes for term {}", self.id, term);
+        self.pre_vote = Some(PreVote {
+            term: term,
+            have: vec![],
+            until: now.add(*PRE_VOTE_DURATION),
+        });
+        let mut req = self.peer_msg();
+        req.set_pre_vote_req(self.vote_req(term));
+        self.peer_broadcast(ByteBuf::from_slice(&*req.write_to_bytes()
+                                                     .unwrap()));
+    }
+
+    fn handle_pre_vote_req(&mut self,
+                           env: Envelope,
+                           peer_id: PeerID,
+                           vote_req: &VoteReq) {
+        // the same as a real vote, minus following anyone
+        let grant = peer_id == self.id ||
+                    (!self.config.learner && self.should_grant_vote(vote_req));
+        let mut vote_res = VoteRes::new();
+        vote_res.set_term(vote_req.get_term());
+        vote_res.set_success(grant);
+        let mut res = self.peer_msg();
+        res.set_pre_vote_res(vote_res);
+        self.reply(env, ByteBuf::from_slice(&*res.write_to_bytes().unwrap()));
+    }
+
+    fn handle_pre_vote_res(&mut self, env: Envelope, vote_res: &VoteRes) {
+        let now = self.clock.now();
+        let need = self.peers.len() / 2 + 1;
+        let won = match self.pre_vote {
+            Some(ref mut pre_vote) if pre_vote.term == vote_res.get_term() &&
+                                      now < pre_vote.until => {
+                if vote_res.get_success() && !pre_vote.have.contains(&env.tok) {
+                    pre_vote.have.push(env.tok);
+                }
+                pre_vote.have.len() >= need
+            }
+            _ => false,
+        };
+        if won && !self.state.valid_leader(now) &&
+           !self.state.valid_candidate(now) {
+            self.pre_vote = None;
+            self.become_candidate();
+            self.request_votes(None);
+        }
+    }
+
+    // A standalone node is its own quorum, so it takes and extends its
+    // lease without asking anyone.  Its writes are learned as soon as
+    // they're logged, with the quorum of one its log was given.
+    fn lead_alone(&mut self) {
+        let now = self.clock.now();
+        let until = now.add(*LEADER_DURATION);
+        let valid = self.state.valid_leader(now);
+        self.state = match self.state.clone() {
+            State::Leader{term: term, need: need, have: have, ..} if valid => {
+                State::Leader {
+                    term: term,
+                    until: until,
+                    need: need,
+                    have: have,
+                }
+            }
+            _ => {
+                self.highest_term += 1;
+                info!("{} leading term {} standalone",
+                      self.id,
+                      self.highest_term);
+                State::Leader {
+                    term: self.highest_term,
+                    until: until,
+                    need: 1,
+                    have: vec![],
+                }
+            }
+        };
+    }
+
+    fn become_candidate(&mut self) {
+        info!("{} transitioning to candidate state", self.id);
+        self.highest_term += 1;
+        self.state = State::Candidate {
+            term: self.highest_term,
+            until: self.clock.now().add(*LEADER_DURATION),
+            need: (self.peers.len() / 2 + 1) as u8,
+            have: vec![],
+        };
+        info!("{:?}", self.state);
+    }
+
+    fn request_votes(&mut self, transfer_from: Option<PeerID>) {
+        debug!("broadcasting VoteReq");
+        let mut req = self.peer_msg();
+        let mut vote_req = self.vote_req(self.state.term().unwrap());
+        if let Some(from) = transfer_from {
+            vote_req.set_transfer_from(from);
+        }
+        req.set_vote_req(vote_req);
+        // a vote is only useful to us until our current lease ends
+        self.state.until().map(|until| {
+            req.set_deadline_ms(timespec_to_ms(until));
+        });
+        self.peer_broadcast(ByteBuf::from_slice(&*req.write_to_bytes()
+                                                     .unwrap()));
+    }
+
+    // Learners don't take part in elections, so they ask whoever is
+    // leading to replicate to them, and renew that like a lease.
+    fn request_replication(&mut self) {
+        let now = self.clock.now();
+        let renew = match self.state {
+            State::Follower{until: until, ..} => {
+                now.add(*LEADER_REFRESH) >= until
+            }
+            _ => true,
+        };
+        if !renew {
+            return;
+        }
+        let mut req = self.peer_msg();
+        req.set_learner_req(self.vote_req(self.highest_term));
+        self.peer_broadcast(ByteBuf::from_slice(&*req.write_to_bytes()
+                                                     .unwrap()));
+    }
+
+    fn handle_learner_req(&mut self, env: Envelope, peer_id: PeerID) {
+        let mut vote_res = VoteRes::new();
+        vote_res.set_term(self.state.term().unwrap_or(self.highest_term));
+        let leading = self.state.is_leader() &&
+                      self.state.valid_leader(self.clock.now());
+        vote_res.set_success(leading && peer_id != self.id);
+        let mut res = self.peer_msg();
+        res.set_learner_res(vote_res);
+        let (address, tok) = (env.address, env.tok);
+        self.reply(env, ByteBuf::from_slice(&*res.write_to_bytes().unwrap()));
+        if leading && peer_id != self.id {
+            if !self.rep_peers.contains_key(&peer_id) {
+                info!("{} replicating to learner {}", self.id, peer_id);
+            }
+            self.update_rep_peers(peer_id.clone(), address, tok, true);
+            self.send_appends(&peer_id);
+        }
+    }
+
+    fn handle_learner_res(&mut self,
+                          env: Envelope,
+                          peer_id: PeerID,
+                          vote_res: &VoteRes) {
+        let now = self.clock.now();
+        if !self.config.learner || !vote_res.get_success() {
+            return;
+        }
+        // a newer leader supersedes the one we were learning from
+        let take = self.state.following(peer_id.clone()) ||
+                   !self.state.valid_leader(now) ||
+                   self.state
+                       .term()
+                       .map(|term| vote_res.get_term() > term)
+                       .unwrap_or(true);
+        if !take {
+            return;
+        }
+        if !self.state.following(peer_id.clone()) {
+            info!("{} learning from {}", self.id, peer_id);
+        }
+        self.highest_term = cmp::max(self.highest_term, vote_res.get_term());
+        self.state = State::Follower {
+            id: peer_id,
+            term: vote_res.get_term(),
+            tok: env.tok,
+            leader_addr: env.address.unwrap(),
+            until: now.add(*LEADER_DURATION),
+        };
+    }
+
+    fn maybe_scrub(&mut self) {
+        if !self.state.is_leader() || self.config.scrub_interval_ms == 0 {
+            self.next_scrub = None;
+            return;
+        }
+        let now = self.clock.now();
+        let interval_ms = self.config.scrub_interval_ms as i64;
+        let interval = time::Duration::milliseconds(interval_ms);
+        match self.next_scrub {
+            Some(next) if now >= next => {
+                self.next_scrub = Some(now.add(interval));
+                self.scrub();
+            }
+            Some(_) => (),
+            // not right as we take over, which is busy enough
+            None => self.next_scrub = Some(now.add(interval)),
+        }
+    }
+
+    // Sends our followers digests of our db as of everything we've
+    // learned, so each can check its own once it has applied as much.
+    pub fn scrub(&mut self) {
+        if !self.state.is_leader() {
+            return;
+        }
+        self.apply_learned();
+        let txid = self.applied_txid();
+        info!("{} scrubbing followers as of txid {}", self.id, txid);
+        let mut scrub = Scrub::new();
+        scrub.set_txid(txid);
+        let spans = scrub::digest(&self.db, self.config.scrub_span_keys);
+        scrub.set_spans(RepeatedField::from_vec(spans));
+        let mut peer_msg = self.peer_msg();
+        peer_msg.set_scrub(scrub);
+        self.peer_broadcast(ByteBuf::from_slice(&*peer_msg.write_to_bytes()
+                                                          .unwrap()));
+    }
+
+    fn handle_scrub(&mut self, env: Envelope, peer_id: PeerID, scrub: &Scrub) {
+        if !self.state.is_following(peer_id) {
+            return;
+        }
+        self.scrub = Some((env, scrub.clone()));
+        let applied = self.applied_txid();
+        self.answer_scrub(applied);
+    }
+
+    // Whether a scrub or repair is waiting to be handled exactly at txid.
+    fn pinned(&self, txid: TXID) -> bool {
+        self.scrub
+            .as_ref()
+            .map(|&(_, ref scrub)| scrub.get_txid() == txid)
+            .unwrap_or(false) ||
+        self.repair
+            .as_ref()
+            .map(|repair| repair.get_txid() == txid)
+            .unwrap_or(false)
+    }
+
+    // Checks a waiting scrub against the db, if applied is where it was
+    // taken.
+    fn answer_scrub(&mut self, applied: TXID) {
+        let ready = match self.scrub {
+            Some((_, ref scrub)) => {
+                scrub.get_txid() <= applied || self.config.witness
+            }
+            None => false,
+        };
+        if !ready {
+            return;
+        }
+        let (env, scrub) = self.scrub.take().unwrap();
+        let mut scrub_res = ScrubRes::new();
+        scrub_res.set_txid(scrub.get_txid());
+        if scrub.get_txid() == applied && !self.config.witness {
+            let divergent = scrub::divergent(&self.db, scrub.get_spans());
+            if !divergent.is_empty() {
+                error!("{} has {} spans diverging from the leader as of txid \
+                        {}",
+                       self.id,
+                       divergent.len(),
+                       applied);
+            }
+            if !divergent.is_empty() && self.config.scrub_repair {
+                let mut repair_req = RepairReq::new();
+                let ranges = divergent.clone();
+                repair_req.set_ranges(RepeatedField::from_vec(ranges));
+                let mut req = self.peer_msg();
+                req.set_repair_req(repair_req);
+                self.reply(env.clone(),
+                           ByteBuf::from_slice(&*req.write_to_bytes()
+                                                    .unwrap()));
+            }
+            let starts = divergent.into_iter()
+                                  .map(|mut range| range.take_start())
+                                  .collect();
+            scrub_res.set_compared(true);
+            scrub_res.set_divergent(RepeatedField::from_vec(starts));
+        } else {
+            debug!("{} can't compare against a scrub as of txid {}",
+                   self.id,
+                   scrub.get_txid());
+            scrub_res.set_compared(false);
+        }
+        let mut res = self.peer_msg();
+        res.set_scrub_res(scrub_res);
+        self.reply(env, ByteBuf::from_slice(&*res.write_to_bytes().unwrap()));
+    }
+
+    fn handle_scrub_res(&mut self, peer_id: PeerID, scrub_res: &ScrubRes) {
+        if !self.state.is_leader() || !scrub_res.get_compared() {
+            return;
+        }
+        let divergent = scrub_res.get_divergent().to_vec();
+        if divergent.is_empty() {
+            info!("{} matches us as of txid {}", peer_id, scrub_res.get_txid());
+        } else {
+            error!("{} diverges from us as of txid {} in spans starting at \
+                    {:?}",
+                   peer_id,
+                   scrub_res.get_txid(),
+                   divergent);
+            self.metrics.scrub_divergent_spans.add(divergent.len());
+        }
+        self.scrub_report.insert(peer_id, divergent);
+    }
+
+    fn handle_repair_req(&mut self, env: Envelope, repair_req: &RepairReq) {
+        if !self.state.is_leader() {
+            return;
+        }
+        self.apply_learned();
+        let mut repair = Repair::new();
+        repair.set_txid(self.applied_txid());
+        repair.set_ranges(repair_req.get_ranges().iter().cloned().collect());
+        let entries = scrub::entries(&self.db, repair_req.get_ranges());
+        repair.set_entries(RepeatedField::from_vec(entries));
+        let mut res = self.peer_msg();
+        res.set_repair(repair);
+        self.reply(env, ByteBuf::from_slice(&*res.write_to_bytes().unwrap()));
+    }
+
+    fn handle_repair(&mut self, peer_id: PeerID, repair: &Repair) {
+        if !self.state.is_following(peer_id) || self.config.witness {
+            return;
+        }
+        self.repair = Some(repair.clone());
+        let applied = self.applied_txid();
+        self.apply_repair(applied);
+    }
+
+    // Installs a waiting repair, if applied is where it was taken.
+    fn apply_repair(&mut self, applied: TXID) {
+        let ready = match self.repair {
+            Some(ref repair) => repair.get_txid() <= applied,
+            None => false,
+        };
+        if !ready {
+            return;
+        }
+        let repair = self.repair.take().unwrap();
+        if repair.get_txid() < applied {
+            warn!("{} already applied past the repair as of txid {}",
+                  self.id,
+                  repair.get_txid());
+            return;
+        }
+        match scrub::repair(&self.db,
+                            repair.get_ranges(),
+                            repair.get_entries(),
+                            &self.metrics) {
+            Ok(changed) => {
+                info!("{} repaired {} keys from the leader's copy",
+                      self.id,
+                      changed);
+                self.metrics.repaired_keys.add(changed);
+                // the repair went around GroupCommit's accounting
+                self.stats.measure(&self.db);
+                self.reload_namespaces();
+            }
+            Err(e) => error!("failed to repair from the leader's copy: {}", e),
+        }
+    }
+
+    fn reload_namespaces(&mut self) {
+        match self.metrics.checked(Namespaces::load(&self.db)) {
+            Ok(namespaces) => self.namespaces = namespaces,
+            Err(e) => error!("failed to load namespaces: {}", e),
+        }
+    }
+
+    fn vote_req(&self, term: Term) -> VoteReq {
+        let mut vote_req = VoteReq::new();
+        vote_req.set_term(term);
+        vote_req.set_last_accepted_term(self.rep_log.last_accepted_term());
+        vote_req.set_last_accepted_txid(self.rep_log.last_accepted_txid());
+        vote_req.set_last_learned_term(self.rep_log.last_learned_term());
+        vote_req.set_last_learned_txid(self.rep_log.last_learned_txid());
+        vote_req
+    }
 
-            debug!("in replicate, we have {} rep_peers", self.rep_peers.len());
-
-            // for each peer, send them their next message
-            for (_, peer) in self.rep_peers.iter_mut() {
-                let mut append = Append::new();
-                append.set_from_txid(peer.last_accepted_txid);
-                append.set_from_term(peer.last_accepted_term);
-                append.set_last_learned_txid(self.rep_log.last_learned_txid());
-                let mut batch = vec![];
-                for txid in peer.max_sent_txid + 1..peer.max_sent_txid + 100 {
-
-                    match self.rep_log.get(txid) {
-                        Some(mutation) => {
-                            // TODO(tyler) can we avoid copies here?
-                            // maybe if multiple Buf implementors could
-                            // hold RC<Box<underlying>>?
-                            batch.push(mutation.clone());
-                            peer.max_sent_txid = mutation.get_version()
-                                                         .get_txid();
-                        }
-                        None => (),
-                    }
-                }
+    // Our leader has stepped down in our favor, so run for election now
+    // instead of waiting for its lease to run out.
+    fn handle_timeout_now(&mut self,
+                          peer_id: PeerID,
+                          timeout_now: &TimeoutNow) {
+        if !self.state.following(peer_id.clone()) ||
+           self.state.term() != Some(timeout_now.get_term()) {
+            warn!("ignoring TimeoutNow from {}, who we don't follow", peer_id);
+            return;
+        }
+        if self.config.witness || self.config.learner {
+            warn!("ignoring TimeoutNow from {}, we don't lead", peer_id);
+            return;
+        }
+        info!("{} taking over leadership from {}", self.id, peer_id);
+        self.become_candidate();
+        self.request_votes(Some(peer_id));
+    }
 
-                append.set_batch(protobuf::RepeatedField::from_vec(batch));
+    // Moves an in-progress leadership transfer along: once the target has
+    // accepted everything in our log we step down, follow it, and tell it
+    // to take over.  Gives up if that doesn't happen within a lease.
+    fn try_transfer(&mut self) {
+        let (caught_up, expired) = match self.transfer {
+            None => return,
+            Some(ref transfer) => {
+                let caught_up = self.state.is_leader() &&
+                                self.rep_peers
+                                    .get(&transfer.target)
+                                    .map(|peer| {
+                                        peer.last_accepted_txid >=
+                                        self.rep_log.last_accepted_txid()
+                                    })
+                                    .unwrap_or(false);
+                (caught_up,
+                 !self.state.is_leader() || self.clock.now() >= transfer.until)
+            }
+        };
+        if !caught_up && !expired {
+            return;
+        }
+        let transfer = self.transfer.take().unwrap();
+        let mut transfer_res = TransferLeaderRes::new();
+        if !caught_up {
+            warn!("giving up on handing leadership to {}", transfer.target);
+            transfer_res.set_success(false);
+            let reason = format!("{} did not catch up in time",
+                                 transfer.target);
+            transfer_res.set_err(ConsensusError::TransferFailed {
+                                     reason: reason,
+                                 }
+                                 .to_string());
+        } else {
+            let term = self.state.term().unwrap();
+            let (tok, addr) = {
+                let peer = self.rep_peers.get(&transfer.target).unwrap();
+                (peer.tok, peer.addr)
+            };
+            let mut timeout_now = TimeoutNow::new();
+            timeout_now.set_term(term);
+            let mut peer_msg = self.peer_msg();
+            peer_msg.set_timeout_now(timeout_now);
+            self.rpc_tx.send_msg(Envelope {
+                address: addr,
+                tok: tok,
+                msg: ByteBuf::from_slice(&*peer_msg.write_to_bytes().unwrap()),
+            });
 
-                let mut peer_msg = PeerMsg::new();
-                peer_msg.set_srvid(self.id.clone());
-                peer_msg.set_append(append);
+            // follow the target right away, so that we don't run against
+            // it when our lease would otherwise have been up
+            info!("{} stepping down in favor of {}", self.id, transfer.target);
+            self.state = match addr {
+                Some(addr) => State::Follower {
+                    term: term,
+                    id: transfer.target.clone(),
+                    tok: tok,
+                    leader_addr: addr,
+                    until: self.clock.now().add(*LEADER_DURATION),
+                },
+                None => State::Init,
+            };
+            self.rep_peers = BTreeMap::new();
+            transfer_res.set_success(true);
+        }
+        if let Some((env, req_id)) = transfer.requester {
+            let mut res = CliRes::new();
+            res.set_req_id(req_id);
+            res.set_transfer_leader(transfer_res);
+            self.reply(env,
+                       ByteBuf::from_slice(&*res.write_to_bytes()
+                                                .unwrap()));
+        }
+    }
 
-                self.rpc_tx.send_msg(Envelope {
-                    address: peer.addr,
-                    tok: peer.tok,
-                    msg: ByteBuf::from_slice(&*peer_msg.write_to_bytes()
-                                                       .unwrap()),
+    // Gets us out of the way so that the node can be taken down: hands
+    // leadership to the most caught up follower, and stops us from
+    // running for election again.  There are no membership changes yet,
+    // so our replicas stay where they are.
+    fn drain(&mut self) {
+        if !self.draining {
+            info!("{} draining", self.id);
+            self.draining = true;
+        }
+        if !self.state.is_leader() || self.transfer.is_some() {
+            return;
+        }
+        let target = self.rep_peers
+                         .values()
+                         .filter(|peer| {
+                             !peer.learner &&
+                             !self.membership.is_witness(&peer.id)
+                         })
+                         .max_by_key(|peer| peer.last_accepted_txid)
+                         .map(|peer| peer.id.clone());
+        match target {
+            Some(target) => {
+                info!("{} handing leadership to {} to drain", self.id, target);
+                self.transfer = Some(LeaderTransfer {
+                    target: target,
+                    requester: None,
+                    until: self.clock.now().add(*LEADER_DURATION),
                 });
+                self.try_transfer();
+            }
+            None => warn!("{} has no followers to hand leadership to", self.id),
+        }
+    }
+
+    fn past_deadline(&self, deadline_ms: Option<u64>) -> bool {
+        match deadline_ms {
+            Some(deadline) => timespec_to_ms(self.clock.now()) >= deadline,
+            None => false,
+        }
+    }
+
+    fn cancel_expired_pending(&mut self) {
+        let expired: Vec<TXID> = self.pending
+                                     .iter()
+                                     .filter(|&(_, &(_, _, deadline, _))| {
+                                         self.past_deadline(deadline)
+                                     })
+                                     .map(|(txid, _)| *txid)
+                                     .collect();
+        for txid in expired {
+            debug!("cancelling expired pending request for txid {}", txid);
+            self.pending.remove(&txid);
+        }
+    }
+
+    // Drops learned log entries that no replica, protected consumer or
+    // retention window still needs.  There's no catching a follower up
+    // without the entries it lacks, so a leader keeps everything its
+    // slowest follower hasn't accepted, and everything at all until every
+    // follower has told it how far it's got.  Followers keep what the
+    // leader tells them to, so whoever leads next has it too.
+    fn collect_garbage(&mut self) {
+        let learned = self.rep_log.last_learned_txid();
+        let protected = self.horizon.horizon(self.clock.now(), learned);
+        let retained = learned.saturating_sub(self.config.log_retention);
+        let mut horizon = cmp::min(protected, retained);
+        if self.state.is_leader() {
+            let heard = self.rep_peers
+                            .values()
+                            .filter(|peer| peer.heard && !peer.learner)
+                            .count();
+            if heard + 1 < self.peers.len() ||
+               self.rep_peers.values().any(|peer| !peer.heard) {
+                horizon = 0;
+            }
+            for peer in self.rep_peers.values() {
+                // we need the entry they last accepted to link new appends to
+                horizon = cmp::min(horizon, peer.last_accepted_txid);
+            }
+            self.retain_from = horizon;
+        } else {
+            horizon = cmp::min(horizon, self.retain_from);
+        }
+        self.rep_log.truncate_before(horizon);
+        self.unchanged = self.unchanged.split_off(&horizon);
+    }
+
+    fn new_txid(&mut self) -> TXID {
+        self.max_generated_txid += 1;
+        info!("generating txid {}, {:?}",
+              self.max_generated_txid,
+              self.rep_log);
+        self.max_generated_txid
+    }
+
+    // A mutation of the given kind, versioned at txid in term and stamped
+    // with our clock.
+    fn new_mutation(&mut self,
+                    term: Term,
+                    txid: TXID,
+                    kind: MutationType)
+                    -> Mutation {
+        let mut version = Version::new();
+        version.set_txid(txid);
+        version.set_term(term);
+        version.set_hlc(self.hlc.timestamp());
+
+        let mut mutation = Mutation::new();
+        mutation.set_field_type(kind);
+        mutation.set_version(version);
+        mutation
+    }
+
+    // Drops our log entries after txid for a new leader's, along with the
+    // clients waiting on them, who'd otherwise hear about whatever ends up
+    // learned in their place.
+    fn discard_after(&mut self, txid: TXID) {
+        warn!("{} discarding unlearned entries after txid {}", self.id, txid);
+        self.rep_log.discard_after(txid);
+        self.pending.split_off(&(txid + 1));
+        self.traced.split_off(&(txid + 1));
+    }
+
+    // A message to a peer, from us as of now.
+    fn peer_msg(&self) -> PeerMsg {
+        let mut peer_msg = PeerMsg::new();
+        peer_msg.set_srvid(self.id.clone());
+        peer_msg.set_hlc(self.hlc.timestamp());
+        peer_msg
+    }
+
+    fn reply(&mut self, req: Envelope, res_buf: ByteBuf) {
+        self.rpc_tx.send_msg(Envelope {
+            address: req.address,
+            tok: req.tok,
+            msg: res_buf,
+        });
+    }
+
+    fn peer_broadcast(&mut self, msg: ByteBuf) {
+        self.rpc_tx.send_msg(Envelope {
+            address: None,
+            tok: PEER_BROADCAST,
+            msg: msg,
+        });
+    }
+
+    fn replicate(&mut self, mutations: Vec<Mutation>) {
+        if mutations.len() > 0 {
+            for mut mutation in mutations {
+                let txid = mutation.get_version().get_txid();
+                let crc = checksum::mutation_crc(&mutation);
+                mutation.set_crc(crc);
+                let len = mutation.get_key().len() + mutation.get_value().len();
+                self.metrics.client_bytes.add(len);
+                self.metrics.log_bytes.add(mutation.compute_size() as usize);
+                self.rep_log.append(mutation.get_version().get_term(),
+                                    txid,
+                                    mutation);
+
+                // this should only be learned on single replica collections
+                let accepted = self.rep_log.ack_up_to(txid, self.id.clone());
+                for (_, txid) in accepted {
+                    debug!("leader learning txid {}", txid);
+                    self.learn(txid);
+                }
+            }
+
+            debug!("in replicate, we have {} rep_peers", self.rep_peers.len());
+
+            let peer_ids: Vec<PeerID> = self.rep_peers
+                                            .keys()
+                                            .cloned()
+                                            .collect();
+            for peer_id in peer_ids {
+                self.send_appends(&peer_id);
             }
         }
 
@@ -804,72 +2929,458 @@ impl<C: Clock, RE> Server<C, RE> {
         debug!("peers: {:?}", peer_ids);
     }
 
-    fn learn(&mut self, term: Term, txid: TXID) {
+    // Appends that went unanswered since the last cron may have been
+    // lost, and nothing else would send them again, so back up to what
+    // each follower last told us it has.
+    fn resend_unacked(&mut self) {
+        let mut resend = vec![];
+        for (id, peer) in self.rep_peers.iter_mut() {
+            if peer.max_sent_txid > peer.last_accepted_txid {
+                peer.max_sent_txid = peer.last_accepted_txid;
+                peer.max_sent_learned_txid = 0;
+                resend.push(id.clone());
+            }
+        }
+        for id in resend {
+            self.send_appends(&id);
+        }
+    }
+
+    // Streams log entries to a follower in batches of append_batch_size,
+    // keeping up to append_window batches in flight beyond what it has
+    // acked.  Each batch links to the end of the previous one, so the
+    // follower can accept them back to back without waiting on us.  If
+    // there is nothing new to send but the follower hasn't heard about
+    // our latest learned txid, an empty append carries it over.
+    fn send_appends(&mut self, peer_id: &PeerID) {
+        let batch_size = cmp::max(self.config.append_batch_size, 1) as TXID;
+        let window = batch_size *
+                     cmp::max(self.config.append_window, 1) as TXID;
+        let last_accepted_txid = self.rep_log.last_accepted_txid();
+        let last_learned_txid = self.rep_log.last_learned_txid();
+
+        loop {
+            let (append, to_txid, tok, addr) = match self.rep_peers
+                                                        .get(peer_id) {
+                Some(peer) => {
+                    let in_flight =
+                        peer.max_sent_txid
+                            .saturating_sub(peer.last_accepted_txid);
+                    let can_send = peer.max_sent_txid < last_accepted_txid &&
+                                   in_flight < window;
+                    let learned_stale = peer.max_sent_learned_txid <
+                                        last_learned_txid;
+                    if !can_send && !learned_stale {
+                        return;
+                    }
+
+                    let from_txid = peer.max_sent_txid;
+                    if from_txid + 1 < self.rep_log.collected_before() {
+                        // sending what's left would leave it a gap
+                        error!("{} can't catch up {}, which needs txid {} \
+                                from the log we've already collected",
+                               self.id,
+                               peer_id,
+                               from_txid + 1);
+                        return;
+                    }
+                    // the follower has to match our entry, not its own; we
+                    // only lack it if it's been learned and collected
+                    let from_term = self.rep_log
+                                        .term_of(from_txid)
+                                        .unwrap_or(peer.last_accepted_term);
+                    let to_txid = if can_send {
+                        cmp::min(from_txid + batch_size, last_accepted_txid)
+                    } else {
+                        from_txid
+                    };
+
+                    let mut batch = vec![];
+                    for txid in from_txid + 1..to_txid + 1 {
+                        match self.rep_log.get(txid) {
+                            // TODO(tyler) can we avoid copies here?
+                            // maybe if multiple Buf implementors could
+                            // hold RC<Box<underlying>>?
+                            Some(mutation) => batch.push(mutation),
+                            None => (),
+                        }
+                    }
+
+                    let mut append = Append::new();
+                    append.set_from_txid(from_txid);
+                    append.set_from_term(from_term);
+                    append.set_last_learned_txid(last_learned_txid);
+                    append.set_retain_from_txid(self.retain_from);
+                    append.set_batch(protobuf::RepeatedField::from_vec(batch));
+                    (append, to_txid, peer.tok, peer.addr)
+                }
+                None => return,
+            };
+
+            let mut peer_msg = self.peer_msg();
+            peer_msg.set_append(append);
 
+            self.rpc_tx.send_msg(Envelope {
+                address: addr,
+                tok: tok,
+                msg: ByteBuf::from_slice(&*peer_msg.write_to_bytes()
+                                                   .unwrap()),
+            });
+
+            let peer = self.rep_peers.get_mut(peer_id).unwrap();
+            peer.max_sent_txid = to_txid;
+            peer.max_sent_learned_txid = last_learned_txid;
+        }
+    }
+
+    // Learned mutations are queued rather than applied right away, so
+    // that everything learned while handling a burst of messages can be
+    // written to the db together by flush.
+    fn learn(&mut self, txid: TXID) {
         debug!("trying to get txid {} in rep log", txid);
-        let mutation = match self.rep_log.get(txid) {
-            Some(m) => m,
-            None => {
-                debug!("we don't have this tx in our log yet");
-                return
+        if self.rep_log.get(txid).is_none() {
+            debug!("we don't have this tx in our log yet");
+            return
+        }
+        if let Some(pending) = self.pending.get_mut(&txid) {
+            pending.3.learned();
+        }
+        if let Some(traced) = self.traced.get_mut(&txid) {
+            traced.2 = time::get_time();
+        }
+        self.unapplied.push(txid);
+    }
+
+    // Applies everything learned since the last flush, and lets the
+    // admin listener know where we're at.
+    pub fn flush(&mut self) {
+        self.apply_learned();
+        self.publish_status();
+    }
+
+    // Called once the mailbox has closed, so that everything learned is
+    // applied and on disk before we go away.
+    pub fn shutdown(&mut self) {
+        // a worker that hasn't taken its snapshot by now sees these too,
+        // which beats losing them
+        self.taking = 0;
+        self.flush();
+        if let Err(e) = self.wal.close(&self.db, self.clock.now()) {
+            error!("failed to sync WAL on shutdown: {}", e);
+        }
+        info!("{} stopped", self.id);
+    }
+
+    // Applies every queued learned mutation in a single write to the db,
+    // then answers any clients waiting on them.  Nobody hears about a
+    // write until the whole group it belongs to is durable.
+    fn apply_learned(&mut self) {
+        if self.unapplied.is_empty() || self.taking > 0 {
+            return;
+        }
+        let mut txids: Vec<TXID> = self.unapplied.drain(..).collect();
+        if self.config.witness {
+            // nothing to write, and never a client waiting on it
+            return;
+        }
+        // scrubs and repairs work on the db exactly as of their txid, so
+        // stop there to handle them
+        while let Some(i) = txids.iter().position(|txid| self.pinned(*txid)) {
+            let rest = txids.split_off(i + 1);
+            let at = txids[i];
+            self.apply_group(txids);
+            self.answer_scrub(at);
+            self.apply_repair(at);
+            txids = rest;
+        }
+        self.apply_group(txids);
+        let applied = self.applied_txid();
+        self.answer_scrub(applied);
+        self.apply_repair(applied);
+    }
+
+    fn apply_group(&mut self, txids: Vec<TXID>) {
+        if txids.is_empty() {
+            return;
+        }
+        debug!("applying {} learned mutations as a group", txids.len());
+
+        let last = txids[txids.len() - 1];
+        let mut group = GroupCommit::new();
+        let mut responses = vec![];
+        let mut changes = vec![];
+        let mut ingested = vec![];
+        for txid in txids {
+            let mutation = match self.rep_log.get(txid) {
+                Some(m) => m,
+                None => continue,
+            };
+            debug!("got txid {} from rep log", txid);
+            let mut res = if ingest::is_ingest_key(mutation.get_key()) {
+                self.apply_ingest(&mut group, &mutation)
+            } else {
+                self.apply(&mut group, &mutation)
+            };
+            if mutation.get_key() == mirror::PROMOTED_KEY {
+                res = promote_res(res, &group, &self.db);
+            }
+            if res.has_ingest() && res.get_ingest().get_success() {
+                ingested.push(ingest::marked_file(mutation.get_key()));
+            }
+            if changed_db(&res) {
+                changes.push(mutation);
+            } else {
+                self.unchanged.insert(txid);
+            }
+            responses.push((txid, res));
+        }
+
+        let resized = group.resized(&self.db);
+        let wrote = !group.is_empty();
+        let bytes = group.bytes();
+        let sync = wrote && self.wal.sync_due(self.clock.now());
+        let committed = match self.faults.write_error() {
+            Some(e) => Err(e),
+            None => group.commit_synced(&self.db, sync),
+        };
+        if committed.is_ok() {
+            if wrote {
+                self.metrics.storage_batches.inc();
+                self.metrics.storage_bytes.add(bytes);
+                self.wal.wrote(self.clock.now(), sync);
+            }
+            if self.faults.sync_delay_ms > 0 {
+                self.clock.sleep_ms(self.faults.sync_delay_ms);
+            }
+        }
+        if committed.is_ok() && !self.watches.is_empty() {
+            self.notify_watches(&changes, last);
+        }
+        if committed.is_ok() {
+            for file in ingested {
+                ingest::remove(&self.ingest_dir, &file);
+            }
+            for resized in resized.iter() {
+                self.stats.resized(resized);
+                self.namespaces.resized(resized);
+            }
+            if resized.iter().any(|r| namespace::is_namespace_key(&r.key)) {
+                self.reload_namespaces();
+            }
+            for mutation in changes.iter() {
+                self.stats.wrote(mutation.get_key());
+            }
+        }
+        for (txid, mut res) in responses {
+            if let Err(ref e) = committed {
+                error!("{}", e);
+                fail_response(&mut res, e.to_string());
+            }
+            if res.has_promote() && res.get_promote().get_success() &&
+               !self.promoted {
+                info!("{} promoted after mirroring up to txid {}",
+                      self.id,
+                      res.get_promote().get_source_txid());
+                self.promoted = true;
+            }
+
+            let traced = self.traced.remove(&txid);
+            if let Some((trace, accepted, learned)) = traced {
+                let applied = time::get_time();
+                tracing::record(Span::new(&trace,
+                                          &self.id,
+                                          "replicate",
+                                          accepted,
+                                          learned));
+                tracing::record(Span::new(&trace,
+                                          &self.id,
+                                          "apply",
+                                          learned,
+                                          applied));
+            }
+
+            // TODO(tyler) use persisted crash-proof logic
+            let pending = self.pending.remove(&txid);
+            match pending {
+                Some((_, _, deadline, _)) if self.past_deadline(deadline) => {
+                    debug!("client for txid {} has already given up", txid);
+                }
+                Some((env, req_id, _, mut timing)) => {
+                    info!("found pending listener");
+                    timing.applied();
+                    // If there's a pending client request associated with this,
+                    // then send them a response.
+                    res.set_req_id(req_id);
+                    self.reply(env,
+                               ByteBuf::from_slice(&*res.write_to_bytes()
+                                                        .unwrap()));
+                    let latency = timing.replied(&self.metrics);
+                    self.metrics.write_latency.observe(latency);
+                    admission::WRITE_LATENCY.record(latency);
+                }
+                None => {
+                    info!("could not find pending for this learned request");
+                },
             }
+        }
+    }
+
+    // learned and written to the db
+    fn applied_txid(&self) -> TXID {
+        match self.unapplied.first() {
+            Some(txid) => txid - 1,
+            None => self.rep_log.last_learned_txid(),
+        }
+    }
+
+    fn publish_status(&self) {
+        let mut status = match self.status.write() {
+            Ok(status) => status,
+            Err(_) => return,
+        };
+        status.id = self.id.clone();
+        status.role = status::role(&self.state);
+        status.draining = self.draining;
+        status.witness = self.config.witness;
+        status.learner = self.config.learner;
+        status.term = self.state.term();
+        status.leader = match self.state {
+            State::Follower{leader_addr: addr, ..} => Some(addr.to_string()),
+            State::Leader{..} => Some(self.id.clone()),
+            _ => None,
         };
-        debug!("got txid {} from rep log", txid);
+        status.last_accepted_txid = self.rep_log.last_accepted_txid();
+        status.last_learned_txid = self.rep_log.last_learned_txid();
+        status.applied_txid = self.applied_txid();
+        status.members = self.membership.to_json(self.clock.now());
+        status.scrub = status::scrub_json(&self.scrub_report);
+        status.ranges = self.stats.ranges();
+        status.namespaces = self.namespaces.report();
+        status.followers = self.rep_peers
+                               .values()
+                               .map(|peer| {
+                                   let accepted = peer.last_accepted_txid;
+                                   FollowerStatus {
+                                       id: peer.id.clone(),
+                                       last_accepted_txid: accepted,
+                                       max_sent_txid: peer.max_sent_txid,
+                                       learner: peer.learner,
+                                   }
+                               })
+                               .collect();
+    }
 
+    fn apply(&self, group: &mut GroupCommit, mutation: &Mutation) -> CliRes {
         let mut res = CliRes::new();
+        let txid = mutation.get_version().get_txid();
+
+        if !checksum::mutation_intact(mutation) {
+            self.metrics.corruptions.inc();
+            let e = StorageError::Corrupt(format!("mutation txid {} fails \
+                                                   its checksum",
+                                                  txid));
+            error!("not applying: {}", e);
+            match mutation.get_field_type() {
+                MutationType::KVSET => {
+                    let mut set_res = SetRes::new();
+                    set_res.set_txid(txid);
+                    res.set_set(set_res);
+                }
+                MutationType::KVCAS => {
+                    let mut cas_res = CASRes::new();
+                    cas_res.set_txid(txid);
+                    res.set_cas(cas_res);
+                }
+                MutationType::KVDEL => {
+                    let mut del_res = DelRes::new();
+                    del_res.set_txid(txid);
+                    del_res.set_value(vec![]);
+                    res.set_del(del_res);
+                }
+                MutationType::KVDELRANGE => {
+                    let mut range_res = DeleteRangeRes::new();
+                    range_res.set_txid(txid);
+                    res.set_delete_range(range_res);
+                }
+                MutationType::KVAPPEND |
+                MutationType::KVGETSET |
+                MutationType::KVSETRANGE => {
+                    let mut update_res = UpdateRes::new();
+                    update_res.set_txid(txid);
+                    res.set_update(update_res);
+                }
+                MutationType::KVSEQUENTIAL => {
+                    let mut sequential_res = PutSequentialRes::new();
+                    sequential_res.set_txid(txid);
+                    res.set_put_sequential(sequential_res);
+                }
+                MutationType::KVTXN => {
+                    let mut txn_res = TxnRes::new();
+                    txn_res.set_txid(txid);
+                    res.set_txn(txn_res);
+                }
+            }
+            fail_response(&mut res, e.to_string());
+            return res;
+        }
 
         info!("matching field type {:?}", mutation.get_field_type());
         match mutation.get_field_type() {
             MutationType::KVSET => {
                 info!("processing set!");
                 let mut set_res = SetRes::new();
-                match self.db.put(mutation.get_key(), mutation.get_value()) {
+                match group.put(mutation.get_key(),
+                                mutation.get_value()) {
                     Ok(_) => set_res.set_success(true),
                     Err(e) => {
-                        error!("Operational problem encountered: {}", e);
+                        error!("{}", e);
                         set_res.set_success(false);
-                        set_res.set_err("Operational problem encountered".to_string());
+                        set_res.set_err(e.to_string());
                     }
                 }
+                set_res.set_txid(txid);
                 res.set_set(set_res);
             },
             MutationType::KVCAS => {
                 let mut cas_res = CASRes::new();
-                match self.db.get(mutation.get_key()) {
-                    DBResult::Some(old_val) => {
+                let old = group.get(&self.db, mutation.get_key());
+                match self.metrics.checked(old) {
+                    Ok(Some(old_val)) => {
                         if mutation.has_old_value() &&
-                            *old_val == *mutation.get_old_value() {
+                            old_val == mutation.get_old_value() {
 
                             // compare succeeded, let's try to set
-                            match self.db.put(mutation.get_key(), mutation.get_value()) {
+                            match group.put(mutation.get_key(),
+                                            mutation.get_value()) {
                                 Ok(_) => {
                                     cas_res.set_success(true);
                                     cas_res.set_value(mutation.get_value().to_vec());
                                 },
                                 Err(e) => {
-                                    error!("Operational problem encountered: {}", e);
+                                    error!("{}", e);
                                     cas_res.set_success(false);
-                                    cas_res.set_err("Operational problem encountered".to_string());
-                                    cas_res.set_value(old_val.to_vec());
+                                    cas_res.set_err(e.to_string());
+                                    cas_res.set_value(old_val);
                                 }
                             }
                         } else {
                             cas_res.set_success(false);
                             cas_res.set_err("compare failure".to_string());
-                            cas_res.set_value(old_val.to_vec());
+                            cas_res.set_value(old_val);
                         }
                     },
-                    DBResult::None => {
+                    Ok(None) => {
                         if !mutation.has_old_value() {
-                            match self.db.put(mutation.get_key(), mutation.get_value()) {
+                            match group.put(mutation.get_key(),
+                                            mutation.get_value()) {
                                 Ok(_) => {
                                     cas_res.set_success(true);
                                     cas_res.set_value(mutation.get_value().to_vec());
                                 },
                                 Err(e) => {
-                                    error!("Operational problem encountered: {}", e);
+                                    error!("{}", e);
                                     cas_res.set_success(false);
-                                    cas_res.set_err(format!("Operational problem encountered: {}", e));
+                                    cas_res.set_err(e.to_string());
                                 }
                             }
                         } else {
@@ -877,53 +3388,188 @@ impl<C: Clock, RE> Server<C, RE> {
                             cas_res.set_err("compare failure".to_string());
                         }
                     },
-                    DBResult::Error(e) => {
+                    Err(e) => {
                         cas_res.set_success(false);
-                        error!("Operational problem encountered: {}", e);
-                        cas_res.set_err(format!("Operational problem encountered: {}", e));
+                        error!("{}", e);
+                        cas_res.set_err(e.to_string());
                     },
                 }
-                cas_res.set_txid(self.rep_log.last_learned_txid());
+                cas_res.set_txid(txid);
                 res.set_cas(cas_res);
             },
             MutationType::KVDEL => {
                 let mut del_res = DelRes::new();
-                // If the value exists, return it.
-                match self.db.get(mutation.get_key()) {
-                    DBResult::Some(old_val) => {
-                        del_res.set_value(old_val.to_vec());
+                // If the value exists, return it.  The value is required
+                // on the wire, so send an empty one otherwise.
+                let old = group.get(&self.db, mutation.get_key());
+                let old = self.metrics.checked(old);
+                let deleted = match old {
+                    Ok(old_val) => {
+                        del_res.set_value(old_val.unwrap_or(vec![]));
+                        group.delete(mutation.get_key())
                     }
-                    DBResult::None => (), // we don't care
-                    DBResult::Error(e) => (), // we don't care, but we probably should
-                }
-                match self.db.delete(mutation.get_key()) {
+                    Err(e) => {
+                        del_res.set_value(vec![]);
+                        Err(e)
+                    }
+                };
+                match deleted {
                     Ok(_) => del_res.set_success(true),
                     Err(e) => {
-                        error!("Operational problem encountered: {}", e);
+                        error!("{}", e);
                         del_res.set_success(false);
-                        del_res.set_err(format!("Operational problem encountered: {}", e));
+                        del_res.set_err(e.to_string());
                     }
                 }
+                del_res.set_txid(txid);
                 res.set_del(del_res);
             },
+            MutationType::KVDELRANGE => {
+                let mut range_res = DeleteRangeRes::new();
+                let keys = group.keys_in(&self.db,
+                                         mutation.get_key(),
+                                         mutation.get_value());
+                let mut deleted = 0;
+                range_res.set_success(true);
+                for key in keys.iter().filter(|key| !reserved_key(key)) {
+                    if let Err(e) = group.delete(key) {
+                        error!("{}", e);
+                        range_res.set_success(false);
+                        range_res.set_err(e.to_string());
+                        break;
+                    }
+                    deleted += 1;
+                }
+                range_res.set_deleted(deleted);
+                range_res.set_txid(txid);
+                res.set_delete_range(range_res);
+            },
+            MutationType::KVAPPEND |
+            MutationType::KVGETSET |
+            MutationType::KVSETRANGE => {
+                let mut update_res = UpdateRes::new();
+                update_res.set_txid(txid);
+                let old = group.get(&self.db, mutation.get_key());
+                match self.metrics.checked(old) {
+                    Ok(old_val) => {
+                        let old = old_val.as_ref().map(|v| &v[..]);
+                        let new_val = update::updated(mutation.get_field_type(),
+                                                      old,
+                                                      mutation.get_value(),
+                                                      mutation.get_offset());
+                        match group.put(mutation.get_key(), &new_val) {
+                            Ok(_) => {
+                                update_res.set_success(true);
+                                update_res.set_length(new_val.len() as u64);
+                            }
+                            Err(e) => {
+                                error!("{}", e);
+                                update_res.set_success(false);
+                                update_res.set_err(e.to_string());
+                            }
+                        }
+                        if let Some(old_val) = old_val {
+                            update_res.set_old_value(old_val);
+                        }
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        update_res.set_success(false);
+                        update_res.set_err(e.to_string());
+                    }
+                }
+                res.set_update(update_res);
+            },
+            MutationType::KVSEQUENTIAL => {
+                let mut sequential_res = PutSequentialRes::new();
+                sequential_res.set_txid(txid);
+                match self.put_sequential(group,
+                                          mutation.get_key(),
+                                          mutation.get_value()) {
+                    Ok(key) => {
+                        sequential_res.set_success(true);
+                        sequential_res.set_key(key);
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        sequential_res.set_success(false);
+                        sequential_res.set_err(e.to_string());
+                    }
+                }
+                res.set_put_sequential(sequential_res);
+            },
+            MutationType::KVTXN => {
+                let mut txn_res = TxnRes::new();
+                txn_res.set_txid(txid);
+                match self.apply_txn(group, mutation) {
+                    Ok(None) => txn_res.set_success(true),
+                    Ok(Some(conflict)) => {
+                        txn_res.set_success(false);
+                        txn_res.set_err("compare failure".to_string());
+                        txn_res.set_conflict(conflict);
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        txn_res.set_success(false);
+                        txn_res.set_err(e.to_string());
+                    }
+                }
+                res.set_txn(txn_res);
+            },
+        }
+        if mutation.has_source_txid() {
+            // how far we've mirrored goes in along with what we mirrored
+            let offset = checksum::u64_to_bytes(mutation.get_source_txid());
+            if let Err(e) = group.put(mirror::OFFSET_KEY, &offset) {
+                error!("{}", e);
+                fail_response(&mut res, e.to_string());
+            }
         }
+        res
+    }
 
-        // TODO(tyler) use persisted crash-proof logic
-        let pending = self.pending.remove(&txid);
-        match pending {
-            Some((env, req_id)) => {
-                info!("found pending listener");
-                // If there's a pending client request associated with this,
-                // then send them a response.
-                res.set_req_id(req_id);
-                self.reply(env,
-                           ByteBuf::from_slice(&*res.write_to_bytes()
-                                                    .unwrap()));
+    // Writes a transaction's writes if none of its reads has changed,
+    // returning the first that has otherwise.
+    fn apply_txn(&self,
+                 group: &mut GroupCommit,
+                 mutation: &Mutation)
+                 -> Result<Option<Vec<u8>>, StorageError> {
+        let txn_req = try!(txn::request(mutation));
+        for read in txn_req.get_reads() {
+            let value = group.get(&self.db, read.get_key());
+            let value = try!(self.metrics.checked(value));
+            let expected = if read.has_value() {
+                Some(read.get_value())
+            } else {
+                None
+            };
+            if value.as_ref().map(|v| &v[..]) != expected {
+                return Ok(Some(read.get_key().to_vec()));
+            }
+        }
+        for write in txn_req.get_writes() {
+            if write.has_value() {
+                try!(group.put(write.get_key(), write.get_value()));
+            } else {
+                try!(group.delete(write.get_key()));
             }
-            None => {
-                info!("could not find pending for this learned request");   
-            },
         }
+        Ok(None)
+    }
+
+    // Puts value under the next key in prefix's series, returning the key.
+    fn put_sequential(&self,
+                      group: &mut GroupCommit,
+                      prefix: &[u8],
+                      value: &[u8])
+                      -> Result<Vec<u8>, StorageError> {
+        let counter = sequence::counter(prefix);
+        let last = try!(self.metrics.checked(group.get(&self.db, &counter)));
+        let n = try!(sequence::next(last.as_ref().map(|v| &v[..])));
+        let key = sequence::sequential_key(prefix, n);
+        try!(group.put(&key, value));
+        try!(group.put(&counter, &sequence::counter_value(n)));
+        Ok(key)
     }
 
     // These conditions guarantee that we don't lose acked writes
@@ -932,7 +3578,15 @@ impl<C: Clock, RE> Server<C, RE> {
         if self.state.valid_leader(self.clock.now()) {
             // we already have (or are) a valid leader
             false
-        } else if vote_req.get_term() < self.rep_log.last_learned_term() {
+        } else {
+            self.log_up_to_date(vote_req)
+        }
+    }
+
+    // Whether the vote requestor's log is recent enough that electing it
+    // can't lose anything we might have learned.
+    fn log_up_to_date(&self, vote_req: &VoteReq) -> bool {
+        if vote_req.get_term() < self.rep_log.last_learned_term() {
             // This refers to a stale term.  Note that we can still vote for
             // vote requestors with lower terms than we've accepted but not
             // learned, because our acks may not have actually gained quorum.
@@ -965,3 +3619,207 @@ impl<C: Clock, RE> Server<C, RE> {
         }
     }
 }
+
+// Carries a client's trace context over to the mutation it asked for.
+fn copy_trace(cli_req: &CliReq, mutation: &mut Mutation) {
+    if cli_req.has_trace() {
+        mutation.set_trace(cli_req.get_trace().clone());
+    }
+}
+
+// The key a client request reads or writes, if any.
+fn request_key(cli_req: &CliReq) -> &[u8] {
+    if cli_req.has_get() {
+        cli_req.get_get().get_key()
+    } else if cli_req.has_set() {
+        cli_req.get_set().get_key()
+    } else if cli_req.has_cas() {
+        cli_req.get_cas().get_key()
+    } else if cli_req.has_del() {
+        cli_req.get_del().get_key()
+    } else if cli_req.has_delete_range() {
+        cli_req.get_delete_range().get_start()
+    } else if cli_req.has_update() {
+        cli_req.get_update().get_key()
+    } else if cli_req.has_put_sequential() {
+        cli_req.get_put_sequential().get_prefix()
+    } else if cli_req.has_txn() {
+        let txn_req = cli_req.get_txn();
+        txn_req.get_writes()
+               .first()
+               .or(txn_req.get_reads().first())
+               .map(|first| first.get_key())
+               .unwrap_or(&[])
+    } else if cli_req.has_watch() {
+        cli_req.get_watch().get_key()
+    } else if cli_req.has_count() {
+        cli_req.get_count().get_start()
+    } else {
+        &[]
+    }
+}
+
+// Every key a client request names: for a transaction, each it read and
+// each it writes, and otherwise just request_key's.
+fn request_keys(cli_req: &CliReq) -> Vec<&[u8]> {
+    if cli_req.has_txn() {
+        let txn_req = cli_req.get_txn();
+        txn_req.get_reads()
+               .iter()
+               .chain(txn_req.get_writes().iter())
+               .map(|txn_key| txn_key.get_key())
+               .collect()
+    } else {
+        vec![request_key(cli_req)]
+    }
+}
+
+// The keys a client request writes, if it writes any.
+fn written_keys(cli_req: &CliReq) -> Vec<&[u8]> {
+    if cli_req.has_txn() {
+        cli_req.get_txn()
+               .get_writes()
+               .iter()
+               .map(|write| write.get_key())
+               .collect()
+    } else {
+        vec![request_key(cli_req)]
+    }
+}
+
+// The keys a client request could touch, each from the first up to the
+// end, or on past every key if there's no end, and whether it changes
+// them.
+fn request_spans(cli_req: &CliReq) -> Vec<(Vec<u8>, Option<Vec<u8>>, bool)> {
+    let key = request_key(cli_req).to_vec();
+    let one = |key: &[u8], write: bool| {
+        let mut end = key.to_vec();
+        end.push(0);
+        (key.to_vec(), Some(end), write)
+    };
+    if cli_req.has_delete_range() {
+        vec![(key, Some(cli_req.get_delete_range().get_end().to_vec()), true)]
+    } else if cli_req.has_put_sequential() ||
+              (cli_req.has_watch() && cli_req.get_watch().get_recursive()) {
+        let end = key_prefix_end(&key);
+        vec![(key, end, writes(cli_req))]
+    } else if cli_req.has_count() {
+        let count_req = cli_req.get_count();
+        let end = if count_req.has_end() {
+            Some(count_req.get_end().to_vec())
+        } else {
+            None
+        };
+        vec![(key, end, false)]
+    } else if cli_req.has_snapshot() {
+        // opening one reads nothing yet, and each read from it is checked
+        // like any other
+        vec![]
+    } else if cli_req.has_txn() {
+        let txn_req = cli_req.get_txn();
+        txn_req.get_reads()
+               .iter()
+               .map(|read| one(read.get_key(), false))
+               .chain(txn_req.get_writes()
+                             .iter()
+                             .map(|write| one(write.get_key(), true)))
+               .collect()
+    } else {
+        vec![one(&key, writes(cli_req))]
+    }
+}
+
+// Whether a client request changes the db.
+fn writes(cli_req: &CliReq) -> bool {
+    cli_req.has_set() || cli_req.has_cas() || cli_req.has_del() ||
+    cli_req.has_delete_range() || cli_req.has_update() ||
+    cli_req.has_put_sequential() || cli_req.has_ingest() || cli_req.has_txn()
+}
+
+// Whether a client request writes anything more to the db than it frees.
+fn fills_disk(cli_req: &CliReq) -> bool {
+    cli_req.has_set() || cli_req.has_cas() || cli_req.has_update() ||
+    cli_req.has_put_sequential() || cli_req.has_ingest() ||
+    (cli_req.has_txn() &&
+     cli_req.get_txn().get_writes().iter().any(|write| write.has_value()))
+}
+
+// Whether a key is bookkeeping of ours that no range delete touches.
+fn reserved_key(key: &[u8]) -> bool {
+    auth::is_credentials_key(key) || mirror::is_mirror_key(key) ||
+    ingest::is_ingest_key(key) || sequence::is_sequence_key(key) ||
+    namespace::is_namespace_key(key)
+}
+
+// Whether applying a mutation changed the db, going by its response.
+fn changed_db(res: &CliRes) -> bool {
+    if res.has_set() {
+        res.get_set().get_success()
+    } else if res.has_cas() {
+        res.get_cas().get_success()
+    } else if res.has_promote() {
+        res.get_promote().get_success()
+    } else if res.has_ingest() {
+        res.get_ingest().get_success()
+    } else if res.has_delete_range() {
+        res.get_delete_range().get_success()
+    } else if res.has_update() {
+        res.get_update().get_success()
+    } else if res.has_put_sequential() {
+        res.get_put_sequential().get_success()
+    } else if res.has_txn() {
+        res.get_txn().get_success()
+    } else {
+        res.get_del().get_success()
+    }
+}
+
+// Used when the group a response belongs to failed to commit, so none of
+// its writes happened after all.
+fn fail_response(res: &mut CliRes, err: String) {
+    if res.has_set() {
+        res.mut_set().set_success(false);
+        res.mut_set().set_err(err);
+    } else if res.has_cas() {
+        res.mut_cas().set_success(false);
+        res.mut_cas().set_err(err);
+    } else if res.has_del() {
+        res.mut_del().set_success(false);
+        res.mut_del().set_err(err);
+    } else if res.has_promote() {
+        res.mut_promote().set_success(false);
+        res.mut_promote().set_err(err);
+    } else if res.has_ingest() {
+        res.mut_ingest().set_success(false);
+        res.mut_ingest().set_err(err);
+    } else if res.has_delete_range() {
+        res.mut_delete_range().set_success(false);
+        res.mut_delete_range().set_err(err);
+    } else if res.has_update() {
+        res.mut_update().set_success(false);
+        res.mut_update().set_err(err);
+    } else if res.has_put_sequential() {
+        res.mut_put_sequential().set_success(false);
+        res.mut_put_sequential().set_err(err);
+    } else if res.has_txn() {
+        res.mut_txn().set_success(false);
+        res.mut_txn().set_err(err);
+    }
+}
+
+// What applying a promotion says to whoever asked for it.
+fn promote_res(res: CliRes, group: &GroupCommit, db: &DB) -> CliRes {
+    let set_res = res.get_set();
+    let mut promote_res = PromoteRes::new();
+    promote_res.set_success(set_res.get_success());
+    if set_res.has_err() {
+        promote_res.set_err(set_res.get_err().to_string());
+    }
+    // mirrored changes in the same group are as good as applied
+    if let Ok(Some(offset)) = group.get(db, mirror::OFFSET_KEY) {
+        promote_res.set_source_txid(checksum::bytes_to_u64(&offset));
+    }
+    let mut promoted = CliRes::new();
+    promoted.set_promote(promote_res);
+    promoted
+}
@@ -1,37 +1,201 @@
+use std::cmp;
 use std::io;
-use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::mpsc::TrySendError;
 
 use bytes::{Buf, ByteBuf};
 use mio::{EventLoop, EventSet, PollOpt, Token, TryRead, TryWrite};
-use mio::tcp::TcpStream;
+use mio::tcp::{Shutdown, TcpStream};
+use protobuf::{self, Message};
+use time;
 
+use {CliReq, CliRes, ErrorCode, ErrorRes, Hello};
 use codec::{self, Codec};
 use server::Envelope;
+use server::addr;
+use server::lanes::Inbox;
+use server::metrics::Metrics;
+use server::throttle::Throttle;
 use server::traffic_cop::TrafficCop;
 
 pub struct ServerConn {
     pub sock: TcpStream,
-    pub req_tx: Sender<Envelope>,
+    pub req_tx: Inbox,
     pub res_bufs: Vec<ByteBuf>, // TODO(tyler) use proper dequeue
     pub res_remaining: usize,
     pub req_codec: codec::Framed,
     pub token: Option<Token>,
     pub interest: EventSet,
+    // what we and the other side agreed on in our hellos, if it sent one
+    pub negotiated: Option<Hello>,
+    // whether frames we refuse are answered with a CliRes
+    pub client_facing: bool,
+    // Requests read while the queue to the server was full, to be
+    // forwarded in order before anything else is read.
+    pub backlog: Vec<Envelope>,
+    // requests forwarded that haven't been answered yet, and how many we
+    // allow before we stop reading; zero doesn't count them, for
+    // connections whose messages don't each get a reply
+    pub in_flight: usize,
+    pub max_in_flight: usize,
+    // close once everything queued has been written
+    pub hang_up: bool,
+    // When it was opened and we last read anything from it, and when
+    // what we queue for it last got anywhere: some of it was written,
+    // or it was queued with nothing ahead of it.
+    pub opened: time::Timespec,
+    pub last_read: time::Timespec,
+    pub last_written: time::Timespec,
+    metrics: Arc<Metrics>,
 }
 
 impl ServerConn {
-    pub fn new(sock: TcpStream, req_tx: Sender<Envelope>) -> ServerConn {
+    pub fn new(sock: TcpStream,
+               req_tx: Inbox,
+               req_codec: codec::Framed,
+               client_facing: bool)
+               -> ServerConn {
+        let now = time::get_time();
+        let metrics = req_tx.metrics();
         ServerConn {
             sock: sock,
             req_tx: req_tx,
-            req_codec: codec::Framed::new(),
+            req_codec: req_codec,
             res_bufs: vec![],
             res_remaining: 0,
             token: None,
-            interest: EventSet::hup(),
+            // keep reading even when a message gets no reply, like gossip
+            interest: EventSet::hup() | EventSet::readable(),
+            negotiated: None,
+            client_facing: client_facing,
+            backlog: vec![],
+            in_flight: 0,
+            max_in_flight: 0,
+            hang_up: false,
+            opened: now,
+            last_read: now,
+            last_written: now,
+            metrics: metrics,
         }
     }
 
+    fn forward(&mut self, req: Envelope) {
+        if !self.backlog.is_empty() {
+            self.backlog.push(req);
+            return;
+        }
+        match self.req_tx.try_send(req) {
+            Ok(()) => self.forwarded(),
+            Err(TrySendError::Full(req)) => self.backlog.push(req),
+            // the server is going away
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    fn forwarded(&mut self) {
+        if self.max_in_flight > 0 {
+            self.in_flight += 1;
+        }
+    }
+
+    // Forwards as much of the backlog as the queue has room for.
+    pub fn drain_backlog(&mut self) {
+        while !self.backlog.is_empty() {
+            let req = self.backlog.remove(0);
+            match self.req_tx.try_send(req) {
+                Ok(()) => self.forwarded(),
+                Err(TrySendError::Full(req)) => {
+                    self.backlog.insert(0, req);
+                    break;
+                }
+                Err(TrySendError::Disconnected(_)) => self.backlog.clear(),
+            }
+        }
+    }
+
+    // Called for each message the server sends back.  A watch may be
+    // answered more than once, so this never goes below zero.
+    pub fn responded(&mut self) {
+        if self.in_flight > 0 {
+            self.in_flight -= 1;
+        }
+    }
+
+    // Whether it's gone quiet for timeout: nothing read from it, nothing
+    // of ours waiting to be written to it, and nothing it's waiting on.
+    pub fn idle(&self, timeout: time::Duration, now: time::Timespec) -> bool {
+        self.res_remaining == 0 && self.in_flight == 0 &&
+        self.backlog.is_empty() &&
+        now - cmp::max(self.last_read, self.last_written) >= timeout
+    }
+
+    // Whether what we've queued for it has gone unread for timeout, as if
+    // nobody's on the other end anymore.
+    pub fn stalled(&self,
+                   timeout: time::Duration,
+                   now: time::Timespec)
+                   -> bool {
+        self.res_remaining > 0 && now - self.last_written >= timeout
+    }
+
+    pub fn throttled(&self) -> bool {
+        self.hang_up || !self.backlog.is_empty() ||
+        (self.max_in_flight > 0 && self.in_flight >= self.max_in_flight)
+    }
+
+    // Stops reading from the socket while we're throttled, leaving the
+    // rest to wait in the kernel, which pushes back on the sender.
+    // Returns whether that changed.
+    pub fn update_interest(&mut self) -> bool {
+        let reading = self.interest.is_readable();
+        if self.throttled() {
+            self.interest.remove(EventSet::readable());
+            if reading {
+                self.metrics.paused_reads.inc();
+            }
+        } else {
+            self.interest.insert(EventSet::readable());
+        }
+        reading != self.interest.is_readable()
+    }
+
+    // Both sides open a connection with a hello saying what they speak,
+    // without waiting for the other's.
+    pub fn greet(&mut self) {
+        let hello = self.req_codec.hello().write_to_bytes().unwrap();
+        let buf = self.req_codec.encode_hello(ByteBuf::from_slice(&hello));
+        self.queue(buf);
+    }
+
+    // Turns the connection away, hanging up once we've said why.
+    pub fn refuse(&mut self, reason: String) {
+        self.metrics.throttled_conns.inc();
+        self.queue_error(0, ErrorCode::THROTTLED, reason);
+        self.hang_up = true;
+        self.update_interest();
+    }
+
+    fn queue_error(&mut self, req_id: u64, code: ErrorCode, reason: String) {
+        let mut error = ErrorRes::new();
+        error.set_err(reason);
+        error.set_code(code);
+        let mut res = CliRes::new();
+        res.set_req_id(req_id);
+        res.set_error(error);
+        let bytes = res.write_to_bytes().unwrap();
+        let buf = self.req_codec.encode(ByteBuf::from_slice(&bytes));
+        self.queue(buf);
+    }
+
+    pub fn queue(&mut self, buf: ByteBuf) {
+        if self.res_remaining == 0 {
+            self.last_written = time::get_time();
+        }
+        self.res_remaining += buf.remaining();
+        self.res_bufs.push(buf);
+        self.interest.insert(EventSet::writable());
+    }
+
     pub fn writable(&mut self,
                     event_loop: &mut EventLoop<TrafficCop>)
                     -> io::Result<()> {
@@ -49,12 +213,19 @@ impl ServerConn {
             }
             Ok(Some(r)) => {
                 debug!("CONN : we wrote {} bytes!", r);
+                if r > 0 {
+                    self.last_written = time::get_time();
+                }
                 self.res_remaining -= r;
                 debug!("remaining: {}", self.res_remaining);
                 if self.res_remaining == 0 {
                     // we've written the whole response, now let's wait to read
-                    self.interest.insert(EventSet::readable());
                     self.interest.remove(EventSet::writable());
+                    self.update_interest();
+                    if self.hang_up {
+                        // the hup that follows cleans the connection up
+                        try!(self.sock.shutdown(Shutdown::Both));
+                    }
                 }
             }
             Err(e) => {
@@ -81,20 +252,40 @@ impl ServerConn {
                               PollOpt::edge() | PollOpt::oneshot())
     }
 
+    // Writes out as much of our queued responses as the socket will take
+    // without blocking.  Used when the event loop is stopping, and so
+    // won't be telling us when the socket is writable again.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.res_bufs.is_empty() {
+            let mut res_buf = self.res_bufs.remove(0);
+            let written = try!(self.sock.try_write_buf(&mut res_buf));
+            if res_buf.remaining() != 0 {
+                self.res_bufs.insert(0, res_buf);
+            }
+            match written {
+                Some(0) | None => break,
+                Some(r) => self.res_remaining -= r,
+            }
+        }
+        Ok(())
+    }
+
     pub fn readable(&mut self,
-                    event_loop: &mut EventLoop<TrafficCop>)
+                    event_loop: &mut EventLoop<TrafficCop>,
+                    mut throttle: Option<&mut Throttle>)
                     -> io::Result<()> {
 
-        // TODO(tyler) get rid of this double copying and read
-        // directly to codec
-        let mut req_buf = ByteBuf::mut_with_capacity(1024);
-
-        match self.sock.try_read_buf(&mut req_buf) {
+        // read straight into the codec's block, whose frames are then
+        // forwarded as slices of it
+        match self.req_codec.read_from(&mut self.sock) {
             Ok(None) => {
                 panic!("got readable, but can't read from the socket");
             }
             Ok(Some(r)) => {
                 debug!("CONN : we read {} bytes!", r);
+                if r > 0 {
+                    self.last_read = time::get_time();
+                }
                 //T self.interest.remove(EventSet::readable());
             }
             Err(e) => {
@@ -103,12 +294,54 @@ impl ServerConn {
             }
         };
 
-        for req in self.req_codec.decode(&mut req_buf.flip()) {
-            self.req_tx.send(Envelope {
-                address: Some(self.sock.peer_addr().unwrap()),
+        let addr = addr::unmap(self.sock.peer_addr().unwrap());
+        for req in self.req_codec.take_frames() {
+            if let Some(ref mut throttle) = throttle {
+                if !throttle.admit_req(addr.ip(), time::get_time()) {
+                    self.metrics.throttled_requests.inc();
+                    // answered without bothering the server, so the client
+                    // knows to back off
+                    let req_id =
+                        protobuf::parse_from_bytes::<CliReq>(req.bytes())
+                            .map(|cli_req| cli_req.get_req_id())
+                            .unwrap_or(0);
+                    let err = format!("too many requests from {}", addr.ip());
+                    self.queue_error(req_id, ErrorCode::THROTTLED, err);
+                    continue;
+                }
+            }
+            let req = Envelope {
+                address: Some(addr),
                 tok: self.token.unwrap(),
                 msg: req,
-            });
+            };
+            self.forward(req);
+        }
+        self.update_interest();
+        if let Some(hello) = self.req_codec.take_hello() {
+            match protobuf::parse_from_bytes::<Hello>(hello.bytes()) {
+                Ok(theirs) => {
+                    let agreed = self.req_codec.negotiate(&theirs);
+                    debug!("negotiated protocol version {} with features {:?}",
+                           agreed.get_version(),
+                           agreed.get_features());
+                    self.negotiated = Some(agreed);
+                }
+                Err(e) => warn!("ignoring malformed hello: {}", e),
+            }
+        }
+        for reason in self.req_codec.take_rejected() {
+            warn!("refused a frame: {}", reason);
+            self.metrics.rejected_frames.inc();
+            if self.client_facing {
+                self.queue_error(0, ErrorCode::REFUSED, reason);
+            }
+        }
+        if let Some(reason) = self.req_codec.broken() {
+            // the hup that follows cleans the connection up
+            warn!("dropping connection that doesn't speak our protocol: {}",
+                  reason);
+            try!(self.sock.shutdown(Shutdown::Both));
         }
 
         event_loop.reregister(&self.sock,
@@ -117,3 +350,95 @@ impl ServerConn {
                               PollOpt::edge() | PollOpt::oneshot())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::{Buf, ByteBuf};
+    use mio::Token;
+    use mio::tcp::{TcpListener, TcpStream};
+    use time;
+
+    use codec::Framed;
+    use server::{Envelope, ServerMsg};
+    use server::lanes::{self, Inbox, Source};
+    use server::metrics::Metrics;
+
+    use super::ServerConn;
+
+    fn req(n: u8) -> Envelope {
+        Envelope {
+            address: None,
+            tok: Token(1024),
+            msg: ByteBuf::from_slice(&[n]),
+        }
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let listener =
+            TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let sock = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (tx, rx) = lanes::mailbox(100, 1, Arc::new(Metrics::new()));
+        let mut conn = ServerConn::new(sock,
+                                       Inbox::new(tx, Source::Cli),
+                                       Framed::new(),
+                                       true);
+        conn.max_in_flight = 2;
+
+        // the second request finds the queue full, so we stop reading
+        conn.forward(req(1));
+        conn.forward(req(2));
+        assert!(conn.update_interest());
+        assert!(!conn.interest.is_readable());
+
+        // once there's room it goes through, but two are now unanswered
+        let sent = |msg| {
+            match msg {
+                Some(ServerMsg::Cli(env, _)) => env.msg.bytes().to_vec(),
+                _ => panic!("not a client request"),
+            }
+        };
+        assert!(sent(rx.recv()) == [1]);
+        conn.drain_backlog();
+        assert!(sent(rx.recv()) == [2]);
+        assert!(!conn.update_interest());
+
+        conn.responded();
+        assert!(conn.update_interest());
+        assert!(conn.interest.is_readable());
+    }
+
+    #[test]
+    fn test_idle_and_stalled() {
+        let listener =
+            TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let sock = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (tx, _rx) = lanes::mailbox(100, 100, Arc::new(Metrics::new()));
+        let mut conn = ServerConn::new(sock,
+                                       Inbox::new(tx, Source::Cli),
+                                       Framed::new(),
+                                       true);
+        let timeout = time::Duration::seconds(10);
+        let opened = conn.opened;
+        assert!(!conn.idle(timeout, opened + time::Duration::seconds(9)));
+        assert!(conn.idle(timeout, opened + time::Duration::seconds(10)));
+        assert!(!conn.stalled(timeout, opened + time::Duration::seconds(10)));
+
+        // something it hasn't read keeps it from being idle, until it's
+        // been left too long
+        conn.queue(ByteBuf::from_slice(b"hello"));
+        let queued = conn.last_written;
+        assert!(!conn.idle(timeout, queued + time::Duration::seconds(60)));
+        assert!(!conn.stalled(timeout, queued + time::Duration::seconds(9)));
+        assert!(conn.stalled(timeout, queued + time::Duration::seconds(10)));
+
+        // and a request it's waiting on does too
+        conn.res_bufs.clear();
+        conn.res_remaining = 0;
+        conn.max_in_flight = 1;
+        conn.forward(req(1));
+        assert!(!conn.idle(timeout, queued + time::Duration::seconds(60)));
+    }
+}
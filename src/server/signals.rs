@@ -0,0 +1,87 @@
+use std::io;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+use std::thread;
+
+use libc::{self, SIGHUP, SIGINT, SIGTERM};
+use libc::funcs::posix01::signal::signal;
+use time;
+
+use server::{ConfigFile, ServerConfig, ShutdownHandle};
+
+// All a signal handler may safely do is flip this, so a watcher thread
+// polls it and does the actual shutting down.
+static TERMINATE: AtomicBool = ATOMIC_BOOL_INIT;
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+
+// what signal(2) returns on failure; libc doesn't define it for us
+const SIG_ERR: libc::sighandler_t = !0;
+
+extern "C" fn on_terminate(_: libc::c_int) {
+    TERMINATE.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+// Shuts the server down cleanly on SIGTERM or SIGINT, draining it for up
+// to drain_timeout first if one is given.
+pub fn shutdown_on_signals(handle: ShutdownHandle,
+                           drain_timeout: Option<time::Duration>)
+                           -> io::Result<()> {
+    for &sig in [SIGTERM, SIGINT].iter() {
+        let handler = on_terminate as extern "C" fn(libc::c_int);
+        if unsafe { signal(sig, handler as libc::sighandler_t) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    try!(thread::Builder::new()
+             .name("signal watcher".to_string())
+             .spawn(move || {
+                 while !TERMINATE.load(Ordering::SeqCst) {
+                     if handle.is_shutting_down() {
+                         return;
+                     }
+                     thread::sleep_ms(100);
+                 }
+                 info!("received termination signal");
+                 match drain_timeout {
+                     Some(timeout) => handle.drain(timeout),
+                     None => handle.shutdown(),
+                 }
+             }));
+    Ok(())
+}
+
+// Reads the config file at path again on SIGHUP, and has the server
+// switch to what it can of the settings there, once overrides has had
+// its say.
+pub fn reload_on_sighup<F>(handle: ShutdownHandle,
+                           path: String,
+                           overrides: F)
+                           -> io::Result<()>
+    where F: Fn(ServerConfig) -> ServerConfig + Send + 'static
+{
+    let handler = on_hangup as extern "C" fn(libc::c_int);
+    if unsafe { signal(SIGHUP, handler as libc::sighandler_t) } == SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+
+    try!(thread::Builder::new()
+             .name("reload watcher".to_string())
+             .spawn(move || {
+                 while !handle.is_shutting_down() {
+                     thread::sleep_ms(100);
+                     if !RELOAD.swap(false, Ordering::SeqCst) {
+                         continue;
+                     }
+                     info!("received hangup signal");
+                     match ConfigFile::load(&path) {
+                         Ok(file) => handle.reload(overrides(file.server)),
+                         Err(e) => error!("not reloading: {}", e),
+                     }
+                 }
+             }));
+    Ok(())
+}
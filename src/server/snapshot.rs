@@ -0,0 +1,307 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use bytes::ByteBuf;
+use protobuf::Message;
+use rand::{Rng, thread_rng};
+use rocksdb::{DB, Direction};
+use rocksdb::rocksdb::Snapshot;
+use time;
+
+use {CliRes, ErrorCode, ErrorRes, GetReq, GetRes, SnapshotRes};
+use server::{Envelope, TXID};
+use server::checksum;
+use server::export::ExportJob;
+use server::lanes::Taken;
+
+// Something for the snapshot worker to do.  Opening one is exactly as of
+// txid, like a backup: the server waits to be told it's been taken before
+// applying anything more.
+pub enum SnapshotJob {
+    Open {
+        env: Envelope,
+        req_id: u64,
+        txid: TXID,
+        taken: Taken,
+    },
+    Get {
+        env: Envelope,
+        req_id: u64,
+        get: GetReq,
+    },
+    Export(ExportJob),
+    Release {
+        env: Envelope,
+        req_id: u64,
+        id: u64,
+    },
+}
+
+struct Held<'a> {
+    snapshot: Snapshot<'a>,
+    txid: TXID,
+    used: time::Timespec,
+}
+
+// The snapshots of the db clients have open, by id, each kept until it's
+// released or goes unused for ttl.  RocksDB keeps whatever they can see
+// from being compacted away, so they're not held any longer than that.
+pub struct Snapshots<'a> {
+    db: &'a DB,
+    ttl: time::Duration,
+    held: BTreeMap<u64, Held<'a>>,
+}
+
+impl<'a> Snapshots<'a> {
+    pub fn new(db: &'a DB, ttl: time::Duration) -> Snapshots<'a> {
+        Snapshots {
+            db: db,
+            ttl: ttl,
+            held: BTreeMap::new(),
+        }
+    }
+
+    // Takes one as of txid, returning its id, which is random so that one
+    // from before a restart isn't mistaken for one opened since.
+    pub fn open(&mut self, txid: TXID, now: time::Timespec) -> u64 {
+        let mut id = 0;
+        while id == 0 || self.held.contains_key(&id) {
+            id = thread_rng().gen();
+        }
+        self.held.insert(id,
+                         Held {
+                             snapshot: self.db.snapshot(),
+                             txid: txid,
+                             used: now,
+                         });
+        id
+    }
+
+    fn held(&mut self,
+            id: u64,
+            now: time::Timespec)
+            -> Result<&Held<'a>, String> {
+        match self.held.get_mut(&id) {
+            Some(held) => {
+                held.used = now;
+                Ok(held)
+            }
+            None => Err(format!("snapshot {} isn't open here; it may have been \
+                                 released, or expired",
+                                id)),
+        }
+    }
+
+    // What key held as of snapshot id, and the txid that was.
+    pub fn get(&mut self,
+               id: u64,
+               key: &[u8],
+               now: time::Timespec)
+               -> Result<(TXID, Option<Vec<u8>>), String> {
+        let held = try!(self.held(id, now));
+        let mut iter = held.snapshot.iterator();
+        let found = iter.from(key, Direction::forward).next();
+        match found {
+            Some((k, v)) if &*k == key => {
+                checksum::unseal(&k, &v)
+                    .map(|value| (held.txid, Some(value)))
+                    .map_err(|e| e.to_string())
+            }
+            _ => Ok((held.txid, None)),
+        }
+    }
+
+    pub fn release(&mut self, id: u64) -> bool {
+        self.held.remove(&id).is_some()
+    }
+
+    pub fn expire(&mut self, now: time::Timespec) {
+        let ttl = self.ttl;
+        let expired: Vec<u64> = self.held
+                                    .iter()
+                                    .filter(|&(_, held)| held.used + ttl <= now)
+                                    .map(|(id, _)| *id)
+                                    .collect();
+        for id in expired {
+            info!("snapshot {} expired", id);
+            self.held.remove(&id);
+        }
+    }
+
+    pub fn run<F>(&mut self,
+                  job: SnapshotJob,
+                  now: time::Timespec,
+                  send: &mut F)
+        where F: FnMut(Envelope)
+    {
+        match job {
+            SnapshotJob::Open { env, req_id, txid, mut taken } => {
+                let id = self.open(txid, now);
+                taken.done();
+                let mut snapshot_res = SnapshotRes::new();
+                snapshot_res.set_success(true);
+                snapshot_res.set_id(id);
+                snapshot_res.set_txid(txid);
+                send(snapshot_reply(env, req_id, snapshot_res));
+            }
+            SnapshotJob::Get { env, req_id, get } => {
+                let mut get_res = GetRes::new();
+                match self.get(get.get_snapshot(), get.get_key(), now) {
+                    Ok((txid, Some(value))) => {
+                        get_res.set_success(true);
+                        get_res.set_txid(txid);
+                        get_res.set_value(value);
+                    }
+                    Ok((txid, None)) => {
+                        get_res.set_success(false);
+                        get_res.set_txid(txid);
+                        get_res.set_err("Key not found".to_string());
+                    }
+                    Err(e) => {
+                        // so it isn't taken for a key that's not there
+                        let mut error = ErrorRes::new();
+                        error.set_err(e);
+                        error.set_code(ErrorCode::REFUSED);
+                        let mut res = CliRes::new();
+                        res.set_req_id(req_id);
+                        res.set_error(error);
+                        send(reply(env, res));
+                        return;
+                    }
+                }
+                let mut res = CliRes::new();
+                res.set_req_id(req_id);
+                res.set_get(get_res);
+                send(reply(env, res));
+            }
+            SnapshotJob::Export(mut job) => {
+                match self.held(job.export.get_snapshot(), now) {
+                    Ok(held) => {
+                        job.txid = held.txid;
+                        job.scan(&held.snapshot, |env| send(env));
+                    }
+                    Err(e) => send(job.reply(false, vec![], Some(e), true)),
+                }
+            }
+            SnapshotJob::Release { env, req_id, id } => {
+                let mut snapshot_res = SnapshotRes::new();
+                if self.release(id) {
+                    snapshot_res.set_success(true);
+                } else {
+                    snapshot_res.set_success(false);
+                    let err = format!("snapshot {} isn't open here", id);
+                    snapshot_res.set_err(err);
+                }
+                snapshot_res.set_id(id);
+                send(snapshot_reply(env, req_id, snapshot_res));
+            }
+        }
+    }
+}
+
+// Serves snapshots from a thread of its own, which holds every one open.
+pub struct SnapshotWorker {
+    tx: Sender<SnapshotJob>,
+}
+
+impl SnapshotWorker {
+    pub fn new<F>(db: Arc<DB>,
+                  ttl: time::Duration,
+                  mut send: F)
+                  -> io::Result<SnapshotWorker>
+        where F: FnMut(Envelope) + Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        try!(thread::Builder::new()
+                 .name("snapshot worker".to_string())
+                 .spawn(move || {
+                     let mut snapshots = Snapshots::new(&db, ttl);
+                     loop {
+                         // wakes up now and then to let go of expired ones
+                         match rx.recv_timeout(Duration::from_secs(1)) {
+                             Ok(job) => {
+                                 let now = time::get_time();
+                                 snapshots.run(job, now, &mut send)
+                             }
+                             Err(RecvTimeoutError::Timeout) => (),
+                             Err(RecvTimeoutError::Disconnected) => return,
+                         }
+                         snapshots.expire(time::get_time());
+                     }
+                 }));
+        Ok(SnapshotWorker { tx: tx })
+    }
+
+    // Hands the job to the worker, or back to the caller if it's died.
+    pub fn dispatch(&self, job: SnapshotJob) -> Result<(), SnapshotJob> {
+        self.tx.send(job).map_err(|e| e.0)
+    }
+}
+
+pub fn snapshot_reply(env: Envelope,
+                      req_id: u64,
+                      snapshot_res: SnapshotRes)
+                      -> Envelope {
+    let mut res = CliRes::new();
+    res.set_req_id(req_id);
+    res.set_snapshot(snapshot_res);
+    reply(env, res)
+}
+
+fn reply(env: Envelope, res: CliRes) -> Envelope {
+    Envelope {
+        address: env.address,
+        tok: env.tok,
+        msg: ByteBuf::from_slice(&*res.write_to_bytes().unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use time;
+
+    use server::group_commit::GroupCommit;
+    use server::rocksdb::new;
+    use super::Snapshots;
+
+    #[test]
+    fn test_snapshots_keep_their_view() {
+        let path = "_rasputin_test_snapshots";
+        let db = new(path.to_string()).unwrap();
+        let mut group = GroupCommit::new();
+        group.put(b"k", b"1").unwrap();
+        group.commit(&db).unwrap();
+
+        let now = time::get_time();
+        let mut snapshots = Snapshots::new(&db, time::Duration::seconds(10));
+        let id = snapshots.open(4, now);
+        let mut group = GroupCommit::new();
+        group.put(b"k", b"2").unwrap();
+        group.put(b"new", b"x").unwrap();
+        group.commit(&db).unwrap();
+        assert_eq!(snapshots.get(id, b"k", now), Ok((4, Some(b"1".to_vec()))));
+        assert_eq!(snapshots.get(id, b"new", now), Ok((4, None)));
+        assert!(snapshots.get(id + 1, b"k", now).is_err());
+
+        // reading it keeps it open, and leaving it be doesn't
+        snapshots.expire(now + time::Duration::seconds(5));
+        assert!(snapshots.get(id, b"k", now + time::Duration::seconds(5))
+                         .is_ok());
+        snapshots.expire(now + time::Duration::seconds(14));
+        assert!(snapshots.held.contains_key(&id));
+        snapshots.expire(now + time::Duration::seconds(15));
+        assert!(snapshots.get(id, b"k", now + time::Duration::seconds(15))
+                         .is_err());
+
+        let id = snapshots.open(5, now);
+        assert!(snapshots.release(id));
+        assert!(!snapshots.release(id));
+        let _ = fs::remove_dir_all(path);
+    }
+}
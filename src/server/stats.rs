@@ -0,0 +1,397 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::iter;
+
+use rocksdb::{DB, Direction};
+use rustc_serialize::json::{Json, ToJson};
+use time;
+
+use server::status::RangeStatus;
+
+// seconds over which read and write rates are smoothed
+const RATE_WINDOW_SECS: f64 = 10.0;
+
+// the most keys a count scans before estimating the rest instead
+pub const EXACT_COUNT_KEYS: usize = 10000;
+
+// How much a range holds and how hard it's worked: the figures splitting
+// and rebalancing go by.  Live bytes are keys plus their stored values,
+// checksums included, as of what this node has applied, so they leave out
+// whatever compaction has yet to reclaim.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub live_bytes: u64,
+    pub keys: u64,
+    pub reads: u64,
+    pub writes: u64,
+    // per second, smoothed over the last RATE_WINDOW_SECS or so
+    pub read_rate: f64,
+    pub write_rate: f64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.live_bytes += other.live_bytes;
+        self.keys += other.keys;
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.read_rate += other.read_rate;
+        self.write_rate += other.write_rate;
+    }
+}
+
+impl ToJson for Usage {
+    fn to_json(&self) -> Json {
+        let mut o = BTreeMap::new();
+        o.insert("live_bytes".to_string(), self.live_bytes.to_json());
+        o.insert("keys".to_string(), self.keys.to_json());
+        o.insert("reads".to_string(), self.reads.to_json());
+        o.insert("writes".to_string(), self.writes.to_json());
+        o.insert("read_rate".to_string(), self.read_rate.to_json());
+        o.insert("write_rate".to_string(), self.write_rate.to_json());
+        Json::Object(o)
+    }
+}
+
+// The change one committed group made to a key's footprint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resized {
+    pub key: Vec<u8>,
+    pub bytes: i64,
+    pub keys: i64,
+}
+
+// What a CountReq is answered with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Count {
+    pub keys: u64,
+    pub bytes: u64,
+    pub exact: bool,
+}
+
+struct Tracked {
+    range: RangeStatus,
+    // when we last worked out rates, and the counts as of then
+    ticked: Option<(time::Timespec, u64, u64)>,
+}
+
+// Kept by the server thread, which applies every write and routes every
+// read, so plain counters do.  Rates are worked out on cron ticks.
+pub struct StorageStats {
+    ranges: Vec<Tracked>,
+}
+
+impl StorageStats {
+    // every node serves the whole keyspace, in one range of the default
+    // collection, for now
+    pub fn new() -> StorageStats {
+        StorageStats {
+            ranges: vec![Tracked {
+                             range: RangeStatus::whole_keyspace(),
+                             ticked: None,
+                         }],
+        }
+    }
+
+    // Counts what the db holds from scratch, as on startup or after a
+    // repair rewrote keys behind our back.
+    pub fn measure(&mut self, db: &DB) {
+        for tracked in self.ranges.iter_mut() {
+            tracked.range.usage.live_bytes = 0;
+            tracked.range.usage.keys = 0;
+        }
+        for (k, v) in db.iterator().from_start() {
+            if let Some(usage) = self.usage_of(&k) {
+                usage.live_bytes += (k.len() + v.len()) as u64;
+                usage.keys += 1;
+            }
+        }
+    }
+
+    pub fn read(&mut self, key: &[u8]) {
+        if let Some(usage) = self.usage_of(key) {
+            usage.reads += 1;
+        }
+    }
+
+    pub fn wrote(&mut self, key: &[u8]) {
+        if let Some(usage) = self.usage_of(key) {
+            usage.writes += 1;
+        }
+    }
+
+    pub fn resized(&mut self, resized: &Resized) {
+        if let Some(usage) = self.usage_of(&resized.key) {
+            let live_bytes = usage.live_bytes as i64 + resized.bytes;
+            usage.live_bytes = live_bytes.max(0) as u64;
+            usage.keys = (usage.keys as i64 + resized.keys).max(0) as u64;
+        }
+    }
+
+    pub fn tick(&mut self, now: time::Timespec) {
+        for tracked in self.ranges.iter_mut() {
+            let usage = &mut tracked.range.usage;
+            if let Some((then, reads, writes)) = tracked.ticked {
+                let secs = (now - then).num_milliseconds() as f64 / 1000.0;
+                if secs <= 0.0 {
+                    continue;
+                }
+                let weight = (secs / RATE_WINDOW_SECS).min(1.0);
+                let read_rate = (usage.reads - reads) as f64 / secs;
+                let write_rate = (usage.writes - writes) as f64 / secs;
+                usage.read_rate += (read_rate - usage.read_rate) * weight;
+                usage.write_rate += (write_rate - usage.write_rate) * weight;
+            }
+            tracked.ticked = Some((now, usage.reads, usage.writes));
+        }
+    }
+
+    pub fn ranges(&self) -> Vec<RangeStatus> {
+        self.ranges.iter().map(|tracked| tracked.range.clone()).collect()
+    }
+
+    pub fn usage(&self, key: &[u8]) -> Option<&Usage> {
+        self.ranges
+            .iter()
+            .find(|tracked| tracked.range.contains(key))
+            .map(|tracked| &tracked.range.usage)
+    }
+
+    // How many bytes all of this node's ranges hold.
+    pub fn live_bytes(&self) -> u64 {
+        self.ranges.iter().map(|tracked| tracked.range.usage.live_bytes).sum()
+    }
+
+    // How many keys there are from start up to end, or on past every key
+    // if there's no end, and how many bytes they take up.  A range wholly
+    // inside the span is answered from its usage; the part of one that
+    // isn't is scanned, but only up to max_keys, past which the rest is
+    // taken to be as dense as what was scanned, though never more than
+    // the whole range holds.
+    pub fn count(&self,
+                 db: &DB,
+                 start: &[u8],
+                 end: Option<&[u8]>,
+                 max_keys: usize)
+                 -> Count {
+        let mut count = Count {
+            keys: 0,
+            bytes: 0,
+            exact: true,
+        };
+        for tracked in self.ranges.iter() {
+            let range = &tracked.range;
+            let usage = &range.usage;
+            let lower = cmp::max(start, &range.lower[..]);
+            let upper = match end {
+                Some(end) if range.upper.is_empty() ||
+                             end < &range.upper[..] => Some(end),
+                _ if range.upper.is_empty() => None,
+                _ => Some(&range.upper[..]),
+            };
+            if upper.map_or(false, |upper| lower >= upper) {
+                continue;
+            }
+            let whole = if range.upper.is_empty() {
+                end.is_none()
+            } else {
+                end.map_or(true, |end| end >= &range.upper[..])
+            };
+            if lower == &range.lower[..] && whole {
+                count.keys += usage.keys;
+                count.bytes += usage.live_bytes;
+                continue;
+            }
+            let part = count_span(db, lower, upper, max_keys);
+            if part.exact {
+                count.keys += part.keys;
+                count.bytes += part.bytes;
+            } else {
+                count.keys += cmp::min(part.keys, usage.keys);
+                count.bytes += cmp::min(part.bytes, usage.live_bytes);
+                count.exact = false;
+            }
+        }
+        count
+    }
+
+    fn usage_of(&mut self, key: &[u8]) -> Option<&mut Usage> {
+        self.ranges
+            .iter_mut()
+            .find(|tracked| tracked.range.contains(key))
+            .map(|tracked| &mut tracked.range.usage)
+    }
+}
+
+// Scans the keys from start up to end, or on past every key, until it's
+// seen max_keys of them, and estimates from how far those reached if
+// there are more.
+fn count_span(db: &DB,
+              start: &[u8],
+              end: Option<&[u8]>,
+              max_keys: usize)
+              -> Count {
+    let mut count = Count {
+        keys: 0,
+        bytes: 0,
+        exact: true,
+    };
+    let mut next = None;
+    for (k, v) in db.iterator().from(start, Direction::forward) {
+        if end.map_or(false, |end| &*k >= end) {
+            break;
+        }
+        if count.keys as usize == max_keys {
+            next = Some(k.to_vec());
+            break;
+        }
+        count.keys += 1;
+        count.bytes += (k.len() + v.len()) as u64;
+    }
+    if let Some(next) = next {
+        let reached = fraction(start, &next, end);
+        if reached > 0.0 {
+            count.keys = (count.keys as f64 / reached) as u64;
+            count.bytes = (count.bytes as f64 / reached) as u64;
+        }
+        count.exact = false;
+    }
+    count
+}
+
+// How far through the keys from start up to end, or on past every key,
+// key lies, as if keys were spread evenly over the eight bytes after
+// whatever start and end have in common.
+fn fraction(start: &[u8], key: &[u8], end: Option<&[u8]>) -> f64 {
+    let common = end.map_or(0, |end| {
+        start.iter().zip(end).take_while(|&(a, b)| a == b).count()
+    });
+    let position = |key: &[u8]| {
+        let scaled = key.iter()
+                        .skip(common)
+                        .chain(iter::repeat(&0))
+                        .take(8)
+                        .fold(0.0, |position, &b| position * 256.0 + b as f64);
+        scaled / 2f64.powi(64)
+    };
+    let span = end.map_or(1.0, &position) - position(start);
+    if span <= 0.0 {
+        return 1.0;
+    }
+    ((position(key) - position(start)) / span).max(0.0).min(1.0)
+}
+
+// Each range's figures, their sums for each collection, the node's
+// quota, and what each namespace holds.
+pub fn report(ranges: &[RangeStatus],
+              max_bytes: Option<u64>,
+              namespaces: &Json)
+              -> Json {
+    let mut collections: BTreeMap<String, Usage> = BTreeMap::new();
+    for range in ranges {
+        collections.entry(range.collection.clone())
+                   .or_insert(Usage::default())
+                   .add(&range.usage);
+    }
+    let collections = collections.into_iter()
+                                 .map(|(collection, usage)| {
+                                     (collection, usage.to_json())
+                                 })
+                                 .collect();
+    let mut o = BTreeMap::new();
+    o.insert("ranges".to_string(), ranges.to_json());
+    o.insert("collections".to_string(), Json::Object(collections));
+    o.insert("max_bytes".to_string(), max_bytes.to_json());
+    o.insert("namespaces".to_string(), namespaces.clone());
+    Json::Object(o)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use rustc_serialize::json::Json;
+    use time::{Duration, Timespec};
+
+    use super::{Count, Resized, StorageStats, report};
+    use server::group_commit::GroupCommit;
+    use server::rocksdb::new;
+
+    #[test]
+    fn test_storage_stats() {
+        let mut stats = StorageStats::new();
+        stats.resized(&Resized {
+            key: b"k".to_vec(),
+            bytes: 10,
+            keys: 1,
+        });
+        stats.resized(&Resized {
+            key: b"k".to_vec(),
+            bytes: -20,
+            keys: -2,
+        });
+        assert_eq!(stats.usage(b"k").unwrap().live_bytes, 0);
+        assert_eq!(stats.usage(b"k").unwrap().keys, 0);
+
+        let start = Timespec::new(100, 0);
+        stats.tick(start);
+        for _ in 0..50 {
+            stats.read(b"k");
+        }
+        stats.wrote(b"k");
+        // a full window's worth of time takes the new rate as it is
+        stats.tick(start + Duration::seconds(10));
+        assert_eq!(stats.usage(b"k").unwrap().read_rate, 5.0);
+        assert_eq!(stats.usage(b"k").unwrap().write_rate, 0.1);
+        // and a fraction of one moves that far toward it
+        stats.tick(start + Duration::seconds(15));
+        assert_eq!(stats.usage(b"k").unwrap().read_rate, 2.5);
+
+        assert_eq!(stats.live_bytes(), 0);
+
+        let report = report(&stats.ranges(),
+                            Some(100),
+                            &Json::Object(BTreeMap::new()));
+        let default = report.find_path(&["collections", "default"]).unwrap();
+        assert_eq!(default.find("reads"), Some(&Json::U64(50)));
+        assert_eq!(report.find("max_bytes"), Some(&Json::U64(100)));
+        let ranges = report.find("ranges").and_then(|r| r.as_array());
+        assert_eq!(ranges.map(|r| r.len()), Some(1));
+    }
+
+    #[test]
+    fn test_count() {
+        let path = "_rasputin_test_count";
+        let db = new(path.to_string()).unwrap();
+        let mut group = GroupCommit::new();
+        for i in 0..128 {
+            group.put(&[b'e', i * 2], b"v").unwrap();
+        }
+        group.put(b"z", b"v").unwrap();
+        group.commit(&db).unwrap();
+        let mut stats = StorageStats::new();
+        stats.measure(&db);
+        let usage = stats.usage(b"e").unwrap().clone();
+        assert_eq!(usage.keys, 129);
+
+        // a whole range goes by its usage, and a small span is counted
+        assert_eq!(stats.count(&db, b"", None, 10),
+                   Count {
+                       keys: 129,
+                       bytes: usage.live_bytes,
+                       exact: true,
+                   });
+        let few = stats.count(&db, b"e", Some(&[b'e', 10]), 10);
+        assert_eq!((few.keys, few.exact), (5, true));
+        let all = stats.count(&db, b"e", Some(b"f"), 1000);
+        assert_eq!((all.keys, all.exact), (128, true));
+        assert_eq!(few.bytes * 128, all.bytes * 5);
+        // and a big one is estimated from how far the first keys got
+        let many = stats.count(&db, b"e", Some(b"f"), 10);
+        assert_eq!((many.keys, many.exact), (128, false));
+        let rest = stats.count(&db, b"e", None, 10);
+        assert!(!rest.exact && rest.keys <= 129);
+        let _ = fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use server::{PeerID, State, TXID, Term};
+use server::stats::Usage;
+
+// A snapshot of the server's view of itself, published by the server
+// thread so that the admin listener can report on it without touching
+// state it doesn't own.
+#[derive(Debug, Clone)]
+pub struct NodeStatus {
+    pub id: PeerID,
+    pub cluster_id: String,
+    // listener name -> address, as given to the server
+    pub addresses: BTreeMap<String, String>,
+    pub storage_dir: String,
+    pub role: &'static str,
+    pub draining: bool,
+    pub witness: bool,
+    pub learner: bool,
+    pub term: Option<Term>,
+    pub leader: Option<String>,
+    pub last_accepted_txid: TXID,
+    pub last_learned_txid: TXID,
+    // learned and written to the db
+    pub applied_txid: TXID,
+    pub ranges: Vec<RangeStatus>,
+    // namespace -> what it holds and its quotas
+    pub namespaces: Json,
+    pub followers: Vec<FollowerStatus>,
+    // the cluster as gossip has told us about it
+    pub members: Json,
+    // follower -> starts of the spans that diverged in the last scrub
+    pub scrub: Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct RangeStatus {
+    pub collection: String,
+    pub lower: Vec<u8>,
+    pub upper: Vec<u8>,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone)]
+pub struct FollowerStatus {
+    pub id: PeerID,
+    pub last_accepted_txid: TXID,
+    pub max_sent_txid: TXID,
+    pub learner: bool,
+}
+
+impl NodeStatus {
+    pub fn new(id: PeerID) -> NodeStatus {
+        NodeStatus {
+            id: id,
+            cluster_id: String::new(),
+            addresses: BTreeMap::new(),
+            storage_dir: String::new(),
+            role: "init",
+            draining: false,
+            witness: false,
+            learner: false,
+            term: None,
+            leader: None,
+            last_accepted_txid: 0,
+            last_learned_txid: 0,
+            applied_txid: 0,
+            ranges: vec![RangeStatus::whole_keyspace()],
+            followers: vec![],
+            members: Json::Array(vec![]),
+            scrub: Json::Object(BTreeMap::new()),
+            namespaces: Json::Object(BTreeMap::new()),
+        }
+    }
+
+    // The minimal, stable description of this node that service discovery
+    // integrations register it with.
+    pub fn info_json(&self) -> Json {
+        let serving = match self.role {
+            _ if self.draining => "draining",
+            "leader" | "follower" => "serving",
+            _ => "not_serving",
+        };
+        let mut o = BTreeMap::new();
+        o.insert("id".to_string(), self.id.to_json());
+        o.insert("cluster_id".to_string(), self.cluster_id.to_json());
+        o.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_json());
+        o.insert("addresses".to_string(), self.addresses.to_json());
+        o.insert("status".to_string(), serving.to_json());
+        Json::Object(o)
+    }
+}
+
+pub fn role(state: &State) -> &'static str {
+    match *state {
+        State::Leader{..} => "leader",
+        State::Candidate{..} => "candidate",
+        State::Follower{..} => "follower",
+        State::Init => "init",
+    }
+}
+
+impl ToJson for NodeStatus {
+    fn to_json(&self) -> Json {
+        let mut o = BTreeMap::new();
+        o.insert("id".to_string(), self.id.to_json());
+        o.insert("role".to_string(), self.role.to_json());
+        o.insert("draining".to_string(), self.draining.to_json());
+        o.insert("witness".to_string(), self.witness.to_json());
+        o.insert("learner".to_string(), self.learner.to_json());
+        o.insert("term".to_string(), self.term.to_json());
+        o.insert("leader".to_string(), self.leader.to_json());
+        o.insert("last_accepted_txid".to_string(),
+                 self.last_accepted_txid.to_json());
+        o.insert("last_learned_txid".to_string(),
+                 self.last_learned_txid.to_json());
+        o.insert("applied_txid".to_string(), self.applied_txid.to_json());
+        o.insert("ranges".to_string(), self.ranges.to_json());
+        o.insert("followers".to_string(), self.followers.to_json());
+        o.insert("members".to_string(), self.members.clone());
+        o.insert("scrub".to_string(), self.scrub.clone());
+        Json::Object(o)
+    }
+}
+
+pub fn scrub_json(report: &BTreeMap<PeerID, Vec<Vec<u8>>>) -> Json {
+    let o = report.iter()
+                  .map(|(id, divergent)| {
+                      let starts = divergent.iter()
+                                            .map(|start| {
+                                                String::from_utf8_lossy(start)
+                                                    .into_owned()
+                                                    .to_json()
+                                            })
+                                            .collect();
+                      (id.clone(), Json::Array(starts))
+                  })
+                  .collect();
+    Json::Object(o)
+}
+
+impl RangeStatus {
+    // every node serves the whole keyspace for now
+    pub fn whole_keyspace() -> RangeStatus {
+        RangeStatus {
+            collection: "default".to_string(),
+            lower: vec![],
+            upper: vec![],
+            usage: Usage::default(),
+        }
+    }
+
+    // an empty bound is unbounded on that side
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key >= &self.lower[..] &&
+        (self.upper.is_empty() || key < &self.upper[..])
+    }
+}
+
+impl ToJson for RangeStatus {
+    fn to_json(&self) -> Json {
+        let mut o = match self.usage.to_json() {
+            Json::Object(o) => o,
+            _ => BTreeMap::new(),
+        };
+        o.insert("collection".to_string(), self.collection.to_json());
+        o.insert("lower".to_string(),
+                 String::from_utf8_lossy(&self.lower).into_owned().to_json());
+        o.insert("upper".to_string(),
+                 String::from_utf8_lossy(&self.upper).into_owned().to_json());
+        Json::Object(o)
+    }
+}
+
+impl ToJson for FollowerStatus {
+    fn to_json(&self) -> Json {
+        let mut o = BTreeMap::new();
+        o.insert("id".to_string(), self.id.to_json());
+        o.insert("last_accepted_txid".to_string(),
+                 self.last_accepted_txid.to_json());
+        o.insert("max_sent_txid".to_string(), self.max_sent_txid.to_json());
+        o.insert("learner".to_string(), self.learner.to_json());
+        Json::Object(o)
+    }
+}
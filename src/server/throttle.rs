@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use time;
+
+// Tracks our budget for something that happens at up to rate times a
+// second, with bursts of up to a second's worth.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: time::Timespec,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, now: time::Timespec) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            tokens: burst(rate),
+            updated: now,
+        }
+    }
+
+    // Spends one token if there is one.
+    pub fn take(&mut self, now: time::Timespec) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&mut self, now: time::Timespec) {
+        let elapsed = (now - self.updated).num_microseconds().unwrap_or(0);
+        if elapsed > 0 {
+            let earned = self.rate * elapsed as f64 / 1_000_000.0;
+            let refilled = self.tokens + earned;
+            self.tokens = refilled.min(burst(self.rate));
+            self.updated = now;
+        }
+    }
+
+    fn full(&mut self, now: time::Timespec) -> bool {
+        self.refill(now);
+        self.tokens >= burst(self.rate)
+    }
+}
+
+fn burst(rate: f64) -> f64 {
+    rate.max(1.0)
+}
+
+// Limits on client connections, so that one misbehaving client can't
+// starve everyone else.  A limit of zero doesn't limit anything.
+pub struct Throttle {
+    max_conns: usize,
+    accepts: Option<TokenBucket>,
+    reqs_per_sec: f64,
+    // one bucket for each client address we've heard from recently
+    per_addr: BTreeMap<IpAddr, TokenBucket>,
+}
+
+impl Throttle {
+    pub fn new(max_conns: usize,
+               conns_per_sec: f64,
+               reqs_per_sec: f64,
+               now: time::Timespec)
+               -> Throttle {
+        Throttle {
+            max_conns: max_conns,
+            accepts: if conns_per_sec > 0.0 {
+                Some(TokenBucket::new(conns_per_sec, now))
+            } else {
+                None
+            },
+            reqs_per_sec: reqs_per_sec,
+            per_addr: BTreeMap::new(),
+        }
+    }
+
+    // Whether to serve a new connection, given how many are open.
+    pub fn admit_conn(&mut self,
+                      open: usize,
+                      now: time::Timespec)
+                      -> Result<(), String> {
+        if self.max_conns > 0 && open >= self.max_conns {
+            return Err(format!("already serving {} client connections", open));
+        }
+        if let Some(ref mut accepts) = self.accepts {
+            if !accepts.take(now) {
+                return Err("too many new client connections".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn admit_req(&mut self, addr: IpAddr, now: time::Timespec) -> bool {
+        if self.reqs_per_sec <= 0.0 {
+            return true;
+        }
+        if !self.per_addr.contains_key(&addr) {
+            self.forget_idle(now);
+        }
+        let rate = self.reqs_per_sec;
+        self.per_addr
+            .entry(addr)
+            .or_insert_with(|| TokenBucket::new(rate, now))
+            .take(now)
+    }
+
+    // A full bucket is the same as a new one, so there's no need to keep
+    // those around once there are a lot of them.
+    fn forget_idle(&mut self, now: time::Timespec) {
+        if self.per_addr.len() < 1024 {
+            return;
+        }
+        let idle: Vec<IpAddr> = self.per_addr
+                                    .iter_mut()
+                                    .filter_map(|(addr, bucket)| {
+                                        if bucket.full(now) {
+                                            Some(*addr)
+                                        } else {
+                                            None
+                                        }
+                                    })
+                                    .collect();
+        for addr in idle {
+            self.per_addr.remove(&addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Add;
+
+    use time;
+
+    use super::Throttle;
+
+    #[test]
+    fn test_throttle() {
+        let now = time::get_time();
+        let mut throttle = Throttle::new(2, 3.0, 2.0, now);
+
+        assert!(throttle.admit_conn(2, now).is_err());
+        for _ in 0..3 {
+            assert!(throttle.admit_conn(0, now).is_ok());
+        }
+        assert!(throttle.admit_conn(0, now).is_err());
+        let later = now.add(time::Duration::milliseconds(600));
+        assert!(throttle.admit_conn(0, later).is_ok());
+
+        // each address gets its own budget
+        let a = "10.0.0.1".parse().unwrap();
+        let b = "10.0.0.2".parse().unwrap();
+        assert!(throttle.admit_req(a, now));
+        assert!(throttle.admit_req(a, now));
+        assert!(!throttle.admit_req(a, now));
+        assert!(throttle.admit_req(b, now));
+        assert!(throttle.admit_req(a, later));
+
+        let unlimited = &mut Throttle::new(0, 0.0, 0.0, now);
+        for _ in 0..100 {
+            assert!(unlimited.admit_conn(1000, now).is_ok());
+            assert!(unlimited.admit_req(a, now));
+        }
+    }
+}
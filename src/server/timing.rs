@@ -0,0 +1,190 @@
+use time;
+
+use serialization::TraceContext;
+use server::metrics::Metrics;
+use server::tracing::{self, Span};
+
+// the stages a client request's latency is broken down into, in order
+pub const STAGES: [&'static str; 6] = ["queue",
+                                       "parse",
+                                       "route",
+                                       "replicate",
+                                       "apply",
+                                       "reply"];
+pub const OPS: [&'static str; 8] = ["get",
+                                    "set",
+                                    "cas",
+                                    "del",
+                                    "delete_range",
+                                    "update",
+                                    "put_sequential",
+                                    "txn"];
+
+// how much of a slow request's key goes in the log
+const KEY_PREFIX_BYTES: usize = 16;
+
+// When a client request finished each stage of being served: waiting in
+// the server's mailbox, decoding it, deciding where it goes (handing a
+// read to a worker, or proposing a write), being learned (reads skip
+// this), applying it to the db, and replying.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub op: &'static str,
+    pub key: Vec<u8>,
+    // requests taking longer than this many ms are logged; zero never is
+    pub slow_ms: u32,
+    // the node serving it and the client's trace, if it sent one
+    trace: Option<(String, TraceContext)>,
+    queued: time::Timespec,
+    received: time::Timespec,
+    parsed: time::Timespec,
+    routed: time::Timespec,
+    learned: time::Timespec,
+    applied: time::Timespec,
+}
+
+impl Timing {
+    pub fn new(op: &'static str,
+               key: &[u8],
+               queued: time::Timespec,
+               received: time::Timespec,
+               slow_ms: u32)
+               -> Timing {
+        let now = time::get_time();
+        Timing {
+            op: op,
+            key: key.to_vec(),
+            slow_ms: slow_ms,
+            trace: None,
+            queued: queued,
+            received: received,
+            parsed: now,
+            routed: now,
+            learned: now,
+            applied: now,
+        }
+    }
+
+    // Records a span for each stage once the request is replied to.
+    pub fn trace(&mut self, node: &str, trace: &TraceContext) {
+        self.trace = Some((node.to_string(), trace.clone()));
+    }
+
+    pub fn routed(&mut self) {
+        self.routed = time::get_time();
+        self.learned = self.routed;
+        self.applied = self.routed;
+    }
+
+    pub fn learned(&mut self) {
+        self.learned = time::get_time();
+        self.applied = self.learned;
+    }
+
+    pub fn applied(&mut self) {
+        self.applied = time::get_time();
+    }
+
+    // Called once the reply is on its way.  Records how long each stage
+    // took, logs the request if it was slow, and returns its total latency.
+    pub fn replied(&self, metrics: &Metrics) -> time::Duration {
+        let replied = time::get_time();
+        let stages = self.stages(replied);
+        for (stage, took) in STAGES.iter().zip(stages.iter()) {
+            if let Some(h) = metrics.stage_latency(self.op, stage) {
+                h.observe(*took);
+            }
+        }
+        if let Some((ref node, ref trace)) = self.trace {
+            let ends = [self.queued,
+                        self.received,
+                        self.parsed,
+                        self.routed,
+                        self.learned,
+                        self.applied,
+                        replied];
+            for (i, stage) in STAGES.iter().enumerate() {
+                let span = Span::new(trace, node, stage, ends[i], ends[i + 1]);
+                tracing::record(span);
+            }
+        }
+        let zero = time::Duration::zero();
+        let total = stages.iter().fold(zero, |sum, took| sum + *took);
+        if self.slow_ms > 0 &&
+           total >= time::Duration::milliseconds(self.slow_ms as i64) {
+            warn!("slow {} of key {} in range {} took {}ms: queue {}ms \
+                   parse {}ms route {}ms replicate {}ms apply {}ms reply {}ms",
+                  self.op,
+                  key_prefix(&self.key),
+                  // every node hosts the one range, which covers every key
+                  "[\"\", +inf)",
+                  total.num_milliseconds(),
+                  stages[0].num_milliseconds(),
+                  stages[1].num_milliseconds(),
+                  stages[2].num_milliseconds(),
+                  stages[3].num_milliseconds(),
+                  stages[4].num_milliseconds(),
+                  stages[5].num_milliseconds());
+        }
+        total
+    }
+
+    fn stages(&self, replied: time::Timespec) -> [time::Duration; 6] {
+        [self.received - self.queued,
+         self.parsed - self.received,
+         self.routed - self.parsed,
+         self.learned - self.routed,
+         self.applied - self.learned,
+         replied - self.applied]
+    }
+}
+
+// The start of a key, fit for a log line: printable ascii as is, anything
+// else escaped.
+pub fn key_prefix(key: &[u8]) -> String {
+    let mut out = String::new();
+    for b in key.iter().take(KEY_PREFIX_BYTES) {
+        if *b >= 0x20 && *b < 0x7f && *b != b'\\' {
+            out.push(*b as char);
+        } else {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    if key.len() > KEY_PREFIX_BYTES {
+        out.push_str("...");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use time;
+
+    use server::metrics::Metrics;
+    use super::{Timing, key_prefix};
+
+    #[test]
+    fn test_timing() {
+        assert_eq!(key_prefix(b"users/\x00\xff"), "users/\\x00\\xff");
+        assert_eq!(key_prefix(b"0123456789abcdefXYZ"), "0123456789abcdef...");
+
+        let metrics = Metrics::new();
+        let queued = time::get_time() - time::Duration::milliseconds(30);
+        let received = queued + time::Duration::milliseconds(20);
+        let mut timing = Timing::new("cas", b"k", queued, received, 1);
+        timing.routed();
+        timing.learned();
+        timing.applied();
+        // the time before it was parsed counts too, and so does its wait
+        // in the mailbox
+        assert!(timing.replied(&metrics) >= time::Duration::milliseconds(30));
+        assert_eq!(metrics.stage_latency("cas", "apply").unwrap().count(), 1);
+        assert_eq!(metrics.stage_latency("cas", "queue").unwrap().count(), 1);
+        assert_eq!(metrics.stage_latency("set", "apply").unwrap().count(), 0);
+        assert!(metrics.stage_latency("cas", "nope").is_none());
+        assert!(metrics.render()
+                       .contains("rasputin_client_request_stage_\
+                                  duration_seconds_count\
+                                  {op=\"cas\",stage=\"apply\"}"));
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+
+// Just enough TOML for config files: [tables], key = value pairs and
+// comments, where a value is a string, integer, float, boolean or an
+// array of those on one line.  Keys come back prefixed with their table,
+// as in "server.read_workers".
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Str(_) => "a string",
+            Value::Int(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Bool(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+pub fn parse(s: &str) -> Result<BTreeMap<String, Value>, String> {
+    let mut out = BTreeMap::new();
+    let mut table = String::new();
+    for (n, line) in s.lines().enumerate() {
+        let at = |e: String| format!("line {}: {}", n + 1, e);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            let unclosed = at("unclosed table header".to_string());
+            let close = try!(line.find(']').ok_or(unclosed));
+            if !is_comment(&line[close + 1..]) {
+                return Err(at("junk after table header".to_string()));
+            }
+            table = line[1..close].trim().to_string();
+            if table.is_empty() || !table.split('.').all(is_bare_key) {
+                return Err(at(format!("bad table name {}", table)));
+            }
+            continue;
+        }
+        let no_eq = at("expected key = value".to_string());
+        let eq = try!(line.find('=').ok_or(no_eq));
+        let key = line[..eq].trim();
+        if !is_bare_key(key) {
+            return Err(at(format!("bad key {}", key)));
+        }
+        let mut rest = line[eq + 1..].trim_left();
+        let value = try!(parse_value(&mut rest).map_err(&at));
+        if !is_comment(rest) {
+            return Err(at(format!("junk after the value of {}", key)));
+        }
+        let key = if table.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", table, key)
+        };
+        if out.contains_key(&key) {
+            return Err(at(format!("{} is set twice", key)));
+        }
+        out.insert(key, value);
+    }
+    Ok(out)
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty() &&
+    key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn is_comment(s: &str) -> bool {
+    let s = s.trim();
+    s.is_empty() || s.starts_with('#')
+}
+
+// Parses the value at the start of s, leaving s pointing after it.
+fn parse_value(s: &mut &str) -> Result<Value, String> {
+    if s.starts_with('"') || s.starts_with('\'') {
+        return parse_string(s).map(Value::Str);
+    }
+    if s.starts_with('[') {
+        *s = s[1..].trim_left();
+        let mut items = vec![];
+        loop {
+            if s.starts_with(']') {
+                *s = &s[1..];
+                return Ok(Value::Array(items));
+            }
+            items.push(try!(parse_value(s)));
+            *s = s.trim_left();
+            if s.starts_with(',') {
+                *s = s[1..].trim_left();
+            } else if !s.starts_with(']') {
+                return Err("unclosed array".to_string());
+            }
+        }
+    }
+    let end = s.find(|c: char| {
+                   c == ',' || c == ']' || c == '#' || c.is_whitespace()
+               })
+               .unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    *s = rest;
+    match word {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => (),
+    }
+    let digits = word.replace("_", "");
+    if let Ok(i) = digits.parse() {
+        return Ok(Value::Int(i));
+    }
+    match digits.parse() {
+        Ok(f) if digits.chars().any(|c| c.is_digit(10)) => Ok(Value::Float(f)),
+        _ => Err(format!("can't make sense of {}", word)),
+    }
+}
+
+fn parse_string(s: &mut &str) -> Result<String, String> {
+    let quote = s.chars().next().unwrap();
+    let mut out = String::new();
+    let mut chars = s[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            *s = &s[i + 2..];
+            return Ok(out);
+        }
+        // single-quoted strings are literal
+        if c != '\\' || quote == '\'' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, '"')) => '"',
+            Some((_, '\\')) => '\\',
+            Some((_, c)) => return Err(format!("unknown escape \\{}", c)),
+            None => break,
+        });
+    }
+    Err("unclosed string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Value, parse};
+
+    #[test]
+    fn test_toml() {
+        let parsed = parse("# a node\n\
+                            cluster_id = \"prod\" # trailing comment\n\
+                            seed_peers = [ \"10.0.0.1:7770\", \
+                            '[fd00::1]:7770' ]\n\
+                            \n\
+                            [server]\n\
+                            read_workers = 8\n\
+                            phi_threshold = 1_0.5\n\
+                            pre_vote = false\n\
+                            root_token = \"a \\\"quoted\\\" #hash\"\n")
+                         .unwrap();
+        assert_eq!(parsed["cluster_id"], Value::Str("prod".to_string()));
+        let peers = vec![Value::Str("10.0.0.1:7770".to_string()),
+                         Value::Str("[fd00::1]:7770".to_string())];
+        assert_eq!(parsed["seed_peers"], Value::Array(peers));
+        assert_eq!(parsed["server.read_workers"], Value::Int(8));
+        assert_eq!(parsed["server.phi_threshold"], Value::Float(10.5));
+        assert_eq!(parsed["server.pre_vote"], Value::Bool(false));
+        assert_eq!(parsed["server.root_token"],
+                   Value::Str("a \"quoted\" #hash".to_string()));
+
+        assert!(parse("a = 1\na = 2").unwrap_err().contains("line 2"));
+        assert!(parse("a = \"open").is_err());
+        assert!(parse("a = [1, 2").is_err());
+        assert!(parse("a = nope").is_err());
+        assert!(parse("[server\na = 1").is_err());
+        assert!(parse("a = 1 2").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::{Arc, RwLock};
+
+use time;
+
+use serialization::TraceContext;
+
+// How long one stage of serving a traced request took on one node.  The
+// leader records a span for each stage of a request's timing, and each
+// follower one for how long it held a traced write before it was learned
+// and another for applying it, so where cross-node latency went can be
+// pieced together by trace id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub trace_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub node: String,
+    pub name: &'static str,
+    pub start: time::Timespec,
+    pub duration: time::Duration,
+}
+
+impl Span {
+    pub fn new(trace: &TraceContext,
+               node: &str,
+               name: &'static str,
+               start: time::Timespec,
+               end: time::Timespec)
+               -> Span {
+        Span {
+            trace_id: trace.get_trace_id(),
+            parent_span_id: if trace.has_parent_span_id() {
+                Some(trace.get_parent_span_id())
+            } else {
+                None
+            },
+            node: node.to_string(),
+            name: name,
+            start: start,
+            duration: end - start,
+        }
+    }
+}
+
+// Where spans go.  Spans are recorded on the server thread and the read
+// workers, so a sink should hand them off rather than block.
+pub trait TraceSink: Send + Sync {
+    fn record(&self, span: Span);
+}
+
+// The default sink, which logs each span.
+pub struct LogSink;
+
+impl TraceSink for LogSink {
+    fn record(&self, span: Span) {
+        info!("trace {:016x} on {}: {} took {}us",
+              span.trace_id,
+              span.node,
+              span.name,
+              span.duration.num_microseconds().unwrap_or(i64::max_value()));
+    }
+}
+
+lazy_static! {
+    static ref SINK: RwLock<Arc<TraceSink>> = RwLock::new(Arc::new(LogSink));
+}
+
+// Sends every span recorded in this process to sink from now on.
+pub fn set_trace_sink(sink: Arc<TraceSink>) {
+    *SINK.write().unwrap() = sink;
+}
+
+pub fn record(span: Span) {
+    let sink = SINK.read().unwrap().clone();
+    sink.record(span);
+}
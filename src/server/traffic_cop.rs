@@ -1,64 +1,389 @@
-use std::io::{Error, ErrorKind};
+use std::collections::BTreeMap;
 use std::io;
-use std::sync::mpsc::Sender;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::AtomicUsize;
 
-use bytes::{Buf, ByteBuf, alloc};
+use bytes::{Buf, ByteBuf};
 use mio::{EventLoop, EventSet, Handler, PollOpt, Token, TryRead, TryWrite};
-use mio::tcp::{TcpListener, TcpSocket};
+use mio::tcp::{TcpListener, TcpStream};
 use mio::util::Slab;
 use rand::{Rng, thread_rng};
+use rustc_serialize::json::{Json, ToJson};
+use time;
 
 use server::*;
+use server::addr;
+use server::amplification;
+use server::lanes::{Inbox, Mailbox, Source};
+use server::stats;
+use server::http::HttpConn;
+use server::io_loops;
+use server::metrics::Metrics;
+use server::throttle::Throttle;
+use client::RetryPolicy;
 use codec;
 
 pub struct TrafficCop {
+    // which IO loop this is; only the first talks to peers and has the
+    // admin listener, and the rest just client connections
+    io_loop: usize,
     peers: Vec<Peer>,
     cli_handler: ConnSet,
-    peer_handler: ConnSet,
+    peer_handler: Option<ConnSet>,
+    admin: Option<Admin>,
+    status: Option<Arc<RwLock<NodeStatus>>>,
+    expose_info: bool,
+    // the server's config as of its last reload
+    live_config: Option<Arc<RwLock<ServerConfig>>>,
+    // set once shutdown has been requested; the loop stops on the next tick
+    closing: bool,
+    // how long a client connection may go quiet, and any connection leave
+    // what we've sent it unread, before we hang up on it
+    client_idle: Option<time::Duration>,
+    conn_stall: Option<time::Duration>,
+    // how long we wait between tries at a peer we've lost
+    reconnect: RetryPolicy,
+    // the server's metrics, which the admin listener renders
+    metrics: Arc<Metrics>,
+}
+
+// The admin listener speaks HTTP rather than our framed protocol, so its
+// connections are kept apart from the ConnSets.
+struct Admin {
+    srv_sock: TcpListener,
+    conns: Slab<HttpConn>,
 }
 
 impl TrafficCop {
 
-    pub fn new(peer_port: u16,
+    // The first of io_loops IO loops, whose client listener shares its
+    // port with those of the others if there are any.
+    pub fn new(listen_ip: IpAddr,
+               peer_port: u16,
                cli_port: u16,
-               peer_addrs: Vec<String>,
-               peer_req_tx: Sender<Envelope>,
-               cli_req_tx: Sender<Envelope>)
+               admin_port: Option<u16>,
+               peer_addrs: Vec<SocketAddr>,
+               mailbox: Mailbox,
+               io_loops: usize)
                -> io::Result<TrafficCop> {
 
-        let cli_addr = format!("0.0.0.0:{}", cli_port).parse().unwrap();
-        info!("binding to {} for client connections", cli_addr);
-        let cli_srv_sock = try!(TcpListener::bind(&cli_addr));
+        info!("binding to {} for client connections",
+              addr::join(listen_ip, cli_port));
+        let cli_srv_sock = if io_loops > 1 {
+            try!(addr::listen_shared(listen_ip, cli_port))
+        } else {
+            try!(addr::listen(listen_ip, cli_port))
+        };
 
-        let peer_addr = format!("0.0.0.0:{}", peer_port).parse().unwrap();
-        info!("binding to {} for peer connections", peer_addr);
-        let peer_srv_sock = try!(TcpListener::bind(&peer_addr));
+        info!("binding to {} for peer connections",
+              addr::join(listen_ip, peer_port));
+        let peer_srv_sock = try!(addr::listen(listen_ip, peer_port));
+
+        let admin = match admin_port {
+            Some(port) => {
+                info!("binding to {} for admin connections",
+                      addr::join(listen_ip, port));
+                Some(Admin {
+                    srv_sock: try!(addr::listen(listen_ip, port)),
+                    conns: Slab::new_starting_at(Token(16384), 32),
+                })
+            }
+            None => None,
+        };
 
         let mut peers = vec![];
-        for peer in peer_addrs {
+        for peer_addr in peer_addrs {
             peers.push(Peer {
-                addr: peer.parse().unwrap(),
+                addr: peer_addr,
                 sock: None,
+                failures: 0,
+                retry_at: time::get_time(),
             });
         }
 
+        let metrics = mailbox.metrics();
         Ok(TrafficCop {
+            io_loop: 0,
             peers: peers,
             cli_handler: ConnSet {
                 srv_sock: cli_srv_sock,
                 srv_token: SERVER_CLIENTS,
-                conns: Slab::new_starting_at(Token(1024), 4096),
-                req_tx: cli_req_tx,
+                conns: Slab::new_starting_at(Token(io_loops::cli_base(0)),
+                                             MAX_CLIENTS),
+                req_tx: Inbox::new(mailbox.clone(), Source::Cli),
+                backlogged: vec![],
+                max_in_flight: 0,
+                max_frame: codec::DEFAULT_MAX_FRAME,
+                max_stream: codec::DEFAULT_MAX_STREAM,
+                client_facing: true,
+                throttle: None,
+                open: Arc::new(AtomicUsize::new(0)),
+                keepalive: None,
             },
-            peer_handler: ConnSet {
+            peer_handler: Some(ConnSet {
                 srv_sock: peer_srv_sock,
                 srv_token: SERVER_PEERS,
                 conns: Slab::new_starting_at(Token(2), 15),
-                req_tx: peer_req_tx,
+                req_tx: Inbox::new(mailbox, Source::Peer),
+                backlogged: vec![],
+                max_in_flight: 0,
+                max_frame: codec::DEFAULT_MAX_FRAME,
+                max_stream: codec::DEFAULT_MAX_STREAM,
+                client_facing: false,
+                throttle: None,
+                open: Arc::new(AtomicUsize::new(0)),
+                keepalive: None,
+            }),
+            admin: admin,
+            status: None,
+            expose_info: false,
+            live_config: None,
+            closing: false,
+            client_idle: None,
+            conn_stall: None,
+            reconnect: RetryPolicy::default(),
+            metrics: metrics,
+        })
+    }
+
+    // Another IO loop, numbered i, for client connections alone.  It
+    // listens on the same port as this one, and takes its limits and
+    // settings from it as they are now, sharing the throttle, so set those
+    // up first.
+    pub fn client_loop(&self,
+                       i: usize,
+                       listen_ip: IpAddr,
+                       cli_port: u16)
+                       -> io::Result<TrafficCop> {
+        let cli = &self.cli_handler;
+        Ok(TrafficCop {
+            io_loop: i,
+            peers: vec![],
+            cli_handler: ConnSet {
+                srv_sock: try!(addr::listen_shared(listen_ip, cli_port)),
+                srv_token: SERVER_CLIENTS,
+                conns: Slab::new_starting_at(Token(io_loops::cli_base(i)),
+                                             MAX_CLIENTS),
+                req_tx: cli.req_tx.clone(),
+                backlogged: vec![],
+                max_in_flight: cli.max_in_flight,
+                max_frame: cli.max_frame,
+                max_stream: cli.max_stream,
+                client_facing: true,
+                throttle: cli.throttle.clone(),
+                open: cli.open.clone(),
+                keepalive: cli.keepalive,
             },
+            peer_handler: None,
+            admin: None,
+            status: None,
+            expose_info: false,
+            live_config: self.live_config.clone(),
+            closing: false,
+            client_idle: self.client_idle,
+            conn_stall: self.conn_stall,
+            reconnect: self.reconnect.clone(),
+            metrics: self.metrics.clone(),
         })
     }
 
+    // Applies to connections made from now on.
+    pub fn limit_frames(&mut self, max_frame: usize, max_stream: usize) {
+        for conns in Some(&mut self.cli_handler)
+                         .into_iter()
+                         .chain(self.peer_handler.as_mut()) {
+            conns.max_frame = max_frame;
+            conns.max_stream = max_stream;
+        }
+    }
+
+    // How many requests a client connection may have waiting for replies
+    // before we stop reading from it; zero doesn't limit them.  Applies to
+    // connections made from now on.
+    pub fn limit_in_flight(&mut self, max_in_flight: usize) {
+        self.cli_handler.max_in_flight = max_in_flight;
+    }
+
+    pub fn throttle_clients(&mut self,
+                            max_conns: usize,
+                            conns_per_sec: f64,
+                            reqs_per_sec: f64) {
+        let throttle = Throttle::new(max_conns,
+                                     conns_per_sec,
+                                     reqs_per_sec,
+                                     time::get_time());
+        match self.cli_handler.throttle {
+            // so every loop gets the new one
+            Some(ref shared) => *shared.lock().unwrap() = throttle,
+            None => {
+                self.cli_handler.throttle = Some(Arc::new(Mutex::new(throttle)))
+            }
+        }
+    }
+
+    // How long connections may go quiet or stall, how their sockets are
+    // probed, and how we reconnect to peers, as of config.  Keepalive
+    // applies to connections made from now on.
+    pub fn tune_connections(&mut self, config: &ServerConfig) {
+        let millis = |ms: u64| {
+            if ms > 0 {
+                Some(time::Duration::milliseconds(ms as i64))
+            } else {
+                None
+            }
+        };
+        self.client_idle = millis(config.client_idle_ms);
+        self.conn_stall = millis(config.conn_stall_ms);
+        let keepalive = if config.tcp_keepalive_secs > 0 {
+            Some(config.tcp_keepalive_secs)
+        } else {
+            None
+        };
+        for conns in Some(&mut self.cli_handler)
+                         .into_iter()
+                         .chain(self.peer_handler.as_mut()) {
+            conns.keepalive = keepalive;
+        }
+        let min_ms = config.peer_reconnect_min_ms as i64;
+        let max_ms = config.peer_reconnect_max_ms as i64;
+        self.reconnect = RetryPolicy {
+            initial_backoff: time::Duration::milliseconds(min_ms),
+            max_backoff: time::Duration::milliseconds(max_ms),
+            ..RetryPolicy::default()
+        };
+    }
+
+    // Forgets a peer or client connection, whether it hung up or we did.
+    fn drop_conn(&mut self, token: Token) {
+        match token {
+            peer if peer.as_usize() >= 2 && peer.as_usize() <= 16 => {
+                let removed = match self.peer_handler {
+                    Some(ref mut ph) => ph.remove(token),
+                    None => false,
+                };
+                if removed {
+                    let now = time::get_time();
+                    for peer in self.peers.iter_mut() {
+                        if peer.sock == Some(token) {
+                            debug!("dropping disconnected peer socket");
+                            peer.sock = None;
+                            peer.failures += 1;
+                            let backoff =
+                                self.reconnect.backoff(peer.failures - 1);
+                            peer.retry_at = now + backoff;
+                        }
+                    }
+                }
+            }
+            cli if self.is_client(cli) => {
+                if self.cli_handler.remove(token) {
+                    // the token will be reused, so the server must
+                    // forget whoever authenticated on it before it
+                    // hears from the next connection to get it, which
+                    // the mailbox sees to
+                    self.cli_handler.req_tx.closed(token);
+                }
+            }
+            t => panic!("bad token for error/hup: {}", t.as_usize()),
+        }
+    }
+
+    // Hangs up on client connections that have gone quiet, and on any
+    // whose other end has stopped reading what we send it, which is how
+    // one that's gone without a word usually looks.  A peer we hang up on
+    // is reconnected to like one that hung up on us.
+    fn close_unhealthy(&mut self, now: time::Timespec) {
+        let mut unhealthy = vec![];
+        let (client_idle, conn_stall) = (self.client_idle, self.conn_stall);
+        let mut handlers = vec![(&self.cli_handler, client_idle)];
+        if let Some(ref peer_handler) = self.peer_handler {
+            handlers.push((peer_handler, None));
+        }
+        for (handler, idle) in handlers {
+            for conn in handler.conns.iter() {
+                let tok = match conn.token {
+                    Some(tok) => tok,
+                    None => continue,
+                };
+                if conn_stall.map_or(false, |stall| conn.stalled(stall, now)) {
+                    info!("hanging up on a connection that's left {} bytes \
+                           unread",
+                          conn.res_remaining);
+                    self.metrics.stalled_conns.inc();
+                    unhealthy.push(tok);
+                } else if idle.map_or(false, |idle| conn.idle(idle, now)) {
+                    debug!("hanging up on an idle client connection");
+                    self.metrics.idle_conns.inc();
+                    unhealthy.push(tok);
+                }
+            }
+        }
+        for tok in unhealthy {
+            self.drop_conn(tok);
+        }
+    }
+
+    // Tries each peer we're not connected to once its wait is up, and lets
+    // those that have answered since we connected start over.
+    fn reconnect_peers(&mut self,
+                       event_loop: &mut EventLoop<TrafficCop>,
+                       now: time::Timespec) {
+        let peer_handler = match self.peer_handler {
+            Some(ref mut ph) => ph,
+            None => return,
+        };
+        for peer in self.peers.iter_mut() {
+            if let Some(tok) = peer.sock {
+                if let Some(conn) = peer_handler.conns.get(tok) {
+                    if conn.last_read > conn.opened {
+                        peer.failures = 0;
+                    }
+                }
+                continue;
+            }
+            if now < peer.retry_at {
+                continue;
+            }
+            debug!("reestablishing connection with peer");
+            if peer.failures > 0 {
+                self.metrics.peer_reconnects.inc();
+            }
+            let connected = TcpStream::connect(&peer.addr).and_then(|sock| {
+                peer_handler.register(sock, event_loop)
+            });
+            match connected {
+                Ok(tok) => peer.sock = Some(tok),
+                Err(e) => {
+                    debug!("could not connect to peer {}: {}", peer.addr, e);
+                    peer.failures += 1;
+                    let backoff = self.reconnect.backoff(peer.failures - 1);
+                    peer.retry_at = now + backoff;
+                }
+            }
+        }
+    }
+
+    fn retry_backlogged(&mut self, event_loop: &mut EventLoop<TrafficCop>) {
+        self.cli_handler.retry_backlogged(event_loop);
+        if let Some(ref mut ph) = self.peer_handler {
+            ph.retry_backlogged(event_loop);
+        }
+    }
+
+    // Picks up new client limits whenever the server reloads its config.
+    pub fn follow_config(&mut self, live_config: Arc<RwLock<ServerConfig>>) {
+        self.live_config = Some(live_config);
+    }
+
+    // Lets the admin listener report what the server thread publishes.
+    pub fn serve_status(&mut self,
+                        status: Arc<RwLock<NodeStatus>>,
+                        expose_info: bool) {
+        self.status = Some(status);
+        self.expose_info = expose_info;
+    }
+
     pub fn run_event_loop(&mut self,
                           mut event_loop: EventLoop<TrafficCop>)
                           -> io::Result<()> {
@@ -69,21 +394,233 @@ impl TrafficCop {
                                 PollOpt::edge() | PollOpt::oneshot())
                   .unwrap();
 
-        event_loop.register_opt(&self.peer_handler.srv_sock,
-                                SERVER_PEERS,
-                                EventSet::readable(),
-                                PollOpt::edge() | PollOpt::oneshot())
-                  .unwrap();
+        if let Some(ref peer_handler) = self.peer_handler {
+            event_loop.register_opt(&peer_handler.srv_sock,
+                                    SERVER_PEERS,
+                                    EventSet::readable(),
+                                    PollOpt::edge() | PollOpt::oneshot())
+                      .unwrap();
+        }
+
+        if let Some(ref admin) = self.admin {
+            try!(event_loop.register_opt(&admin.srv_sock,
+                                         SERVER_ADMIN,
+                                         EventSet::readable(),
+                                         PollOpt::edge() | PollOpt::oneshot()));
+        }
+
+        // this only returns once a shutdown has been requested
+        try!(event_loop.run(self));
+        info!("IO loop {} shut down", self.io_loop);
+        Ok(())
+    }
 
-        event_loop.run(self).unwrap();
+    fn admin_accept(&mut self, event_loop: &mut EventLoop<TrafficCop>) {
+        let admin = match self.admin {
+            Some(ref mut admin) => admin,
+            None => return,
+        };
+        loop {
+            let sock = match admin.srv_sock.accept() {
+                Ok(Some(sock)) => sock,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("failed to accept admin connection: {}", e);
+                    break;
+                }
+            };
+            match admin.conns.insert(HttpConn::new(sock)) {
+                Ok(tok) => {
+                    let opts = PollOpt::edge() | PollOpt::oneshot();
+                    let _ = event_loop.register_opt(&admin.conns[tok].sock,
+                                                    tok,
+                                                    EventSet::readable(),
+                                                    opts);
+                }
+                Err(_) => warn!("dropping admin connection: all slots full"),
+            }
+        }
+        let _ = event_loop.reregister(&admin.srv_sock,
+                                      SERVER_ADMIN,
+                                      EventSet::readable(),
+                                      PollOpt::edge() | PollOpt::oneshot());
+    }
+
+    fn admin_readable(&mut self,
+                      event_loop: &mut EventLoop<TrafficCop>,
+                      tok: Token) {
+        let read = match self.admin {
+            Some(ref mut admin) if admin.conns.contains(tok) => {
+                admin.conns[tok].readable()
+            }
+            _ => return,
+        };
+        let response = match read {
+            Ok(Some(path)) => Some(self.admin_response(&path)),
+            Ok(None) => None,
+            Err(e) => {
+                debug!("dropping admin connection: {}", e);
+                self.admin.as_mut().map(|a| a.conns.remove(tok));
+                return;
+            }
+        };
+        let admin = self.admin.as_mut().unwrap();
+        let conn = &mut admin.conns[tok];
+        let interest = match response {
+            Some((status, content_type, body)) => {
+                conn.respond(status, content_type, &body);
+                EventSet::writable()
+            }
+            None => EventSet::readable(),
+        };
+        let _ = event_loop.reregister(&conn.sock,
+                                      tok,
+                                      interest,
+                                      PollOpt::edge() | PollOpt::oneshot());
+    }
+
+    fn admin_writable(&mut self,
+                      event_loop: &mut EventLoop<TrafficCop>,
+                      tok: Token) {
+        let admin = match self.admin {
+            Some(ref mut admin) if admin.conns.contains(tok) => admin,
+            _ => return,
+        };
+        match admin.conns[tok].writable() {
+            Ok(false) => {
+                let opts = PollOpt::edge() | PollOpt::oneshot();
+                let _ = event_loop.reregister(&admin.conns[tok].sock,
+                                              tok,
+                                              EventSet::writable(),
+                                              opts);
+            }
+            // done or broken either way, so hang up
+            _ => {
+                admin.conns.remove(tok);
+            }
+        }
+    }
+
+    fn admin_response(&self,
+                      path: &str)
+                      -> (&'static str, &'static str, String) {
+        match path {
+            "/metrics" => ("200 OK",
+                           "text/plain; version=0.0.4",
+                           self.metrics.render()),
+            "/info" if self.expose_info => {
+                let info = self.status
+                               .as_ref()
+                               .and_then(|status| {
+                                   status.read().ok().map(|s| s.info_json())
+                               })
+                               .unwrap_or(Json::Null);
+                ("200 OK", "application/json", format!("{}\n", info))
+            }
+            "/storage" => {
+                let report = self.status
+                                 .as_ref()
+                                 .and_then(|status| {
+                                     status.read().ok().map(|s| {
+                                         amplification::report(&s.storage_dir,
+                                                               &self.metrics)
+                                     })
+                                 })
+                                 .unwrap_or(Json::Null);
+                ("200 OK", "application/json", format!("{}\n", report.pretty()))
+            }
+            "/stats" => {
+                let max_bytes = self.live_config
+                                    .as_ref()
+                                    .and_then(|config| {
+                                        config.read().unwrap().max_bytes
+                                    });
+                let report = self.status
+                                 .as_ref()
+                                 .and_then(|status| {
+                                     status.read().ok().map(|s| {
+                                         stats::report(&s.ranges,
+                                                       max_bytes,
+                                                       &s.namespaces)
+                                     })
+                                 })
+                                 .unwrap_or(Json::Null);
+                ("200 OK", "application/json", format!("{}\n", report.pretty()))
+            }
+            "/config" => {
+                let config = self.live_config
+                                 .as_ref()
+                                 .map(|config| config.read().unwrap().to_json())
+                                 .unwrap_or(Json::Null);
+                ("200 OK", "application/json", format!("{}\n", config.pretty()))
+            }
+            "/status" => ("200 OK",
+                          "application/json",
+                          format!("{}\n", self.status_json().pretty())),
+            _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        }
+    }
+
+    fn close_listeners(&mut self, event_loop: &mut EventLoop<TrafficCop>) {
+        // the client listener is already gone if we were drained
+        let _ = event_loop.deregister(&self.cli_handler.srv_sock);
+        if let Some(ref peer_handler) = self.peer_handler {
+            let _ = event_loop.deregister(&peer_handler.srv_sock);
+        }
+        if let Some(ref admin) = self.admin {
+            let _ = event_loop.deregister(&admin.srv_sock);
+        }
+    }
 
-        Err(Error::new(ErrorKind::Other, "event_loop shouldn't have returned."))
+    // Best effort at getting queued responses and replication acks onto
+    // the wire before the loop stops.
+    fn flush_conns(&mut self) {
+        for handler in Some(&mut self.cli_handler)
+                           .into_iter()
+                           .chain(self.peer_handler.as_mut()) {
+            for conn in handler.conns.iter_mut() {
+                if let Err(e) = conn.flush() {
+                    debug!("failed to flush connection on shutdown: {}", e);
+                }
+            }
+        }
+    }
+
+    fn status_json(&self) -> Json {
+        let mut o = BTreeMap::new();
+        let node = self.status
+                       .as_ref()
+                       .and_then(|status| {
+                           status.read().ok().map(|s| s.to_json())
+                       })
+                       .unwrap_or(Json::Null);
+        o.insert("node".to_string(), node);
+        let peers = self.peers
+                        .iter()
+                        .map(|peer| {
+                            let mut p = BTreeMap::new();
+                            p.insert("addr".to_string(),
+                                     peer.addr.to_string().to_json());
+                            p.insert("connected".to_string(),
+                                     peer.sock.is_some().to_json());
+                            Json::Object(p)
+                        })
+                        .collect();
+        o.insert("peers".to_string(), Json::Array(peers));
+        // there is no META range yet, so nothing is cached
+        o.insert("meta".to_string(), Json::Null);
+        Json::Object(o)
+    }
+
+    fn is_client(&self, tok: Token) -> bool {
+        let base = io_loops::cli_base(self.io_loop);
+        tok.as_usize() >= base && tok.as_usize() < base + MAX_CLIENTS
     }
 
     fn tok_to_sc(&mut self, tok: Token) -> Option<&mut ServerConn> {
         if tok.as_usize() > 1 && tok.as_usize() <= 128 {
-            self.peer_handler.conns.get_mut(tok)
-        } else if tok.as_usize() > 128 && tok.as_usize() <= 4096 {
+            self.peer_handler.as_mut().and_then(|ph| ph.conns.get_mut(tok))
+        } else if self.is_client(tok) {
             self.cli_handler.conns.get_mut(tok)
         } else {
             error!("bad event loop notification message envelope");
@@ -102,35 +639,25 @@ impl Handler for TrafficCop {
              events: EventSet) {
         if events.is_hup() || events.is_error() {
             debug!("clearing error or hup connection");
-            match token {
-                peer if peer.as_usize() >= 2 && peer.as_usize() <= 16 => {
-                    if self.peer_handler.conns.contains(token) {
-                        self.peer_handler.conns.remove(token);
-                        for peer in self.peers.iter_mut() {
-                            if peer.sock == Some(token) {
-                                debug!("dropping disconnected peer socket");
-                                peer.sock = None;
-                            }
-                        }
-                    }
+            if is_admin(token) {
+                if let Some(ref mut a) = self.admin {
+                    a.conns.remove(token);
                 }
-                cli if cli.as_usize() >= 1024 && cli.as_usize() <= 4096 => {
-                    if self.cli_handler.conns.contains(token) {
-                        self.cli_handler.conns.remove(token);
-                    }
-                }
-                t => panic!("bad token for error/hup: {}", t.as_usize()),
+                return;
             }
+            self.drop_conn(token);
         }
 
         if events.is_readable() {
             match token {
                 SERVER_PEERS => {
                     debug!("got SERVER_PEERS accept");
-                    self.peer_handler.accept(event_loop).or_else(|e| {
-                        error!("failed to accept peer: all slots full");
-                        Err(e)
-                    });
+                    if let Some(ref mut ph) = self.peer_handler {
+                        ph.accept(event_loop).or_else(|e| {
+                            error!("failed to accept peer: all slots full");
+                            Err(e)
+                        });
+                    }
                 }
                 SERVER_CLIENTS => {
                     debug!("got SERVER_CLIENTS accept");
@@ -140,11 +667,17 @@ impl Handler for TrafficCop {
                     });
                 }
                 peer if peer.as_usize() >= 2 && peer.as_usize() <= 16 => {
-                    self.peer_handler.conn_readable(event_loop, peer).unwrap();
+                    if let Some(ref mut ph) = self.peer_handler {
+                        ph.conn_readable(event_loop, peer).unwrap();
+                    }
                 }
-                cli if cli.as_usize() >= 1024 && cli.as_usize() <= 4096 => {
+                cli if self.is_client(cli) => {
                     self.cli_handler.conn_readable(event_loop, cli).unwrap();
                 }
+                SERVER_ADMIN => self.admin_accept(event_loop),
+                admin if is_admin(admin) => {
+                    self.admin_readable(event_loop, admin)
+                }
                 t => panic!("unknown token: {}", t.as_usize()),
             }
         }
@@ -154,34 +687,43 @@ impl Handler for TrafficCop {
                 SERVER_PEERS => panic!("received writable for SERVER_PEERS"),
                 SERVER_CLIENTS =>
                     panic!("received writable for token SERVER_CLIENTS"),
-                peer if peer.as_usize() > 1 && peer.as_usize() <= 128 =>
-                    self.peer_handler.conn_writable(event_loop, peer),
-                cli if cli.as_usize() > 128 && cli.as_usize() <= 4096 =>
+                SERVER_ADMIN =>
+                    panic!("received writable for token SERVER_ADMIN"),
+                peer if peer.as_usize() > 1 && peer.as_usize() <= 128 => {
+                    match self.peer_handler {
+                        Some(ref mut ph) => ph.conn_writable(event_loop, peer),
+                        None => Ok(()),
+                    }
+                }
+                cli if self.is_client(cli) =>
                     self.cli_handler.conn_writable(event_loop, cli),
+                admin if is_admin(admin) => {
+                    self.admin_writable(event_loop, admin);
+                    Ok(())
+                }
                 t => panic!("received writable for out-of-range token: {}",
                             t.as_usize()),
             };
         }
     }
 
-    // timeout is triggered periodically to (re)establish connections to peers.
+    // timeout is triggered periodically to (re)establish connections to
+    // peers, and to hang up on connections that have gone bad.
     fn timeout(&mut self,
                event_loop: &mut EventLoop<TrafficCop>,
                timeout: ()) {
-        for peer in self.peers.iter_mut() {
-            if peer.sock.is_none() {
-                debug!("reestablishing connection with peer");
-                let (sock, _) = TcpSocket::v4()
-                                    .unwrap()
-                                    .connect(&peer.addr)
-                                    .unwrap();
-                self.peer_handler.register(sock, event_loop).map(|tok| {
-                    peer.sock = Some(tok);
-                });
-            }
-        }
-        debug!("have {:?} peer connections",
-               self.peer_handler.conns.count());
+        if self.closing {
+            self.flush_conns();
+            event_loop.shutdown();
+            return;
+        }
+        self.retry_backlogged(event_loop);
+        let now = time::get_time();
+        self.close_unhealthy(now);
+        self.reconnect_peers(event_loop, now);
+        if let Some(ref ph) = self.peer_handler {
+            debug!("have {:?} peer connections", ph.conns.count());
+        }
         // if leader is None, try to get promise leases, following-up with
         // an abdication if we fail to get quorum after 2s (randomly picked).
 
@@ -195,6 +737,56 @@ impl Handler for TrafficCop {
     fn notify(&mut self,
               event_loop: &mut EventLoop<TrafficCop>,
               mut msg: Envelope) {
+        if msg.tok == SHUTDOWN {
+            // Stop taking new connections right away, but keep the loop
+            // running until the next tick, so the server can answer what
+            // it has already been sent.
+            info!("closing listeners");
+            self.close_listeners(event_loop);
+            self.closing = true;
+            return;
+        }
+        if msg.tok == ADD_PEER {
+            // connected to on the next tick, like any other peer we've lost
+            if let Some(addr) = msg.address {
+                if !self.peers.iter().any(|peer| peer.addr == addr) {
+                    info!("adding peer {}", addr);
+                    self.peers.push(Peer {
+                        addr: addr,
+                        sock: None,
+                        failures: 0,
+                        retry_at: time::get_time(),
+                    });
+                }
+            }
+            return;
+        }
+        if msg.tok == RELOAD {
+            let config = match self.live_config {
+                Some(ref live_config) => live_config.read().unwrap().clone(),
+                None => return,
+            };
+            // starts every client over with a full budget
+            self.throttle_clients(config.max_client_conns,
+                                  config.client_conns_per_sec,
+                                  config.client_reqs_per_sec);
+            self.expose_info = config.expose_info;
+            self.tune_connections(&config);
+            return;
+        }
+        if msg.tok == DRAIN {
+            // existing clients keep their connections, and get redirected
+            // once we've handed off leadership
+            info!("no longer accepting client connections");
+            if let Err(e) = event_loop.deregister(&self.cli_handler.srv_sock) {
+                error!("failed to stop accepting clients: {}", e);
+            }
+            return;
+        }
+
+        // the server is taking work off the queues, so there may be room
+        self.retry_backlogged(event_loop);
+
         let mut toks = vec![];
         if msg.tok == PEER_BROADCAST {
             for peer in self.peers.iter() {
@@ -210,26 +802,14 @@ impl Handler for TrafficCop {
                 continue;
             }
             let mut sc = sco.unwrap();
-            let m = msg.msg.bytes();
-
-            let size = 4 + m.len();
-            let mut res = unsafe {
-                ByteBuf::from_mem_ref(alloc::heap(size.next_power_of_two()),
-                                      size as u32, // cap
-                                      0, // pos
-                                      size as u32 /* lim */)
-                    .flip()
-            };
-
-            assert!(res.write_slice(&codec::usize_to_array(m.len())) == 4);
-            assert!(res.write_slice(m) == m.len());
-
-            debug!("adding res to sc.res_bufs: {:?}", res.bytes());
-
-            sc.res_remaining += res.bytes().len();
-            sc.res_bufs.push(res.flip());
-
-            sc.interest.insert(EventSet::writable());
+            sc.responded();
+            sc.update_interest();
+            for res in codec::encode_for(sc.negotiated.as_ref(),
+                                         sc.req_codec.max_frame(),
+                                         msg.msg.bytes()) {
+                debug!("adding res to sc.res_bufs: {:?}", res.bytes());
+                sc.queue(res);
+            }
 
             event_loop.reregister(&sc.sock,
                                   tok,
@@ -238,3 +818,10 @@ impl Handler for TrafficCop {
         }
     }
 }
+
+// on each IO loop
+const MAX_CLIENTS: usize = 4096;
+
+fn is_admin(tok: Token) -> bool {
+    tok.as_usize() >= 16384 && tok.as_usize() < 16384 + 32
+}
@@ -0,0 +1,73 @@
+use protobuf;
+
+use {Mutation, MutationType, StorageError, TxnReq};
+
+// The TxnReq a KVTXN mutation carries.  The leader serialized it and the
+// mutation's checksum covers it, so one that doesn't parse is corrupt.
+pub fn request(mutation: &Mutation) -> Result<TxnReq, StorageError> {
+    protobuf::parse_from_bytes(mutation.get_value()).map_err(|e| {
+        let txid = mutation.get_version().get_txid();
+        StorageError::Corrupt(format!("transaction at txid {} doesn't parse: \
+                                       {}",
+                                      txid,
+                                      e))
+    })
+}
+
+// The sets and deletes a transaction wrote, one mutation each at its
+// version, which is how watches are sent it.
+pub fn writes(mutation: &Mutation) -> Result<Vec<Mutation>, StorageError> {
+    let txn_req = try!(request(mutation));
+    Ok(txn_req.get_writes()
+              .iter()
+              .map(|write| {
+                  let mut change = Mutation::new();
+                  change.set_version(mutation.get_version().clone());
+                  change.set_key(write.get_key().to_vec());
+                  if write.has_value() {
+                      change.set_field_type(MutationType::KVSET);
+                      change.set_value(write.get_value().to_vec());
+                  } else {
+                      change.set_field_type(MutationType::KVDEL);
+                  }
+                  change
+              })
+              .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::{Message, RepeatedField};
+
+    use {Mutation, MutationType, TxnKey, TxnReq, Version};
+    use super::writes;
+
+    #[test]
+    fn test_txn_writes() {
+        let mut set = TxnKey::new();
+        set.set_key(b"a".to_vec());
+        set.set_value(b"1".to_vec());
+        let mut del = TxnKey::new();
+        del.set_key(b"b".to_vec());
+        let mut txn_req = TxnReq::new();
+        txn_req.set_writes(RepeatedField::from_vec(vec![set, del]));
+        let mut version = Version::new();
+        version.set_txid(7);
+        version.set_term(1);
+        let mut mutation = Mutation::new();
+        mutation.set_field_type(MutationType::KVTXN);
+        mutation.set_version(version);
+        mutation.set_key(b"a".to_vec());
+        mutation.set_value(txn_req.write_to_bytes().unwrap());
+
+        let changes = writes(&mutation).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].get_field_type(), MutationType::KVSET);
+        assert_eq!(changes[0].get_value(), b"1");
+        assert_eq!(changes[1].get_field_type(), MutationType::KVDEL);
+        assert_eq!(changes[1].get_version().get_txid(), 7);
+
+        mutation.set_value(b"\xff".to_vec());
+        assert!(writes(&mutation).is_err());
+    }
+}
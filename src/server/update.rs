@@ -0,0 +1,66 @@
+use {MutationType, UpdateOp};
+
+// The longest value a SETRANGE may leave, so that a stray offset can't
+// make every replica allocate without bound.
+pub const MAX_SETRANGE_END: u64 = 512 << 20;
+
+pub fn mutation_type(op: UpdateOp) -> MutationType {
+    match op {
+        UpdateOp::APPEND => MutationType::KVAPPEND,
+        UpdateOp::GETSET => MutationType::KVGETSET,
+        UpdateOp::SETRANGE => MutationType::KVSETRANGE,
+    }
+}
+
+// What an update leaves in place of old.  Every replica works it out for
+// itself from the same old value, so it has to depend on nothing else.
+pub fn updated(field_type: MutationType,
+               old: Option<&[u8]>,
+               value: &[u8],
+               offset: u64)
+               -> Vec<u8> {
+    let old = old.unwrap_or(&[]);
+    match field_type {
+        MutationType::KVAPPEND => {
+            let mut new = old.to_vec();
+            new.extend_from_slice(value);
+            new
+        }
+        MutationType::KVSETRANGE => {
+            let offset = offset as usize;
+            let mut new = old.to_vec();
+            if new.len() < offset + value.len() {
+                new.resize(offset + value.len(), 0);
+            }
+            new[offset..offset + value.len()].copy_from_slice(value);
+            new
+        }
+        _ => value.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use MutationType;
+    use super::updated;
+
+    #[test]
+    fn test_updated() {
+        assert_eq!(updated(MutationType::KVAPPEND, Some(b"log:"), b"a", 0),
+                   b"log:a");
+        assert_eq!(updated(MutationType::KVAPPEND, None, b"a", 0), b"a");
+        assert_eq!(updated(MutationType::KVGETSET, Some(b"old"), b"new", 0),
+                   b"new");
+        assert_eq!(updated(MutationType::KVSETRANGE,
+                           Some(b"hello world"),
+                           b"there",
+                           6),
+                   b"hello there");
+        assert_eq!(updated(MutationType::KVSETRANGE, Some(b"hello"), b"ab", 4),
+                   b"hellab");
+        assert_eq!(updated(MutationType::KVSETRANGE, None, b"x", 2),
+                   b"\x00\x00x");
+        assert_eq!(updated(MutationType::KVSETRANGE, Some(b"abc"), b"", 1),
+                   b"abc");
+    }
+}
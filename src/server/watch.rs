@@ -0,0 +1,330 @@
+use bytes::ByteBuf;
+use protobuf::{Message, RepeatedField};
+use time;
+
+use {CliRes, Mutation, MutationType, WatchReq, WatchRes};
+use server::{Envelope, GcHorizon, TXID};
+use server::auth;
+use server::namespace;
+use server::txn;
+
+// the most changes sent in one reply to a watch
+pub const WATCH_BATCH: usize = 100;
+
+// A client's subscription to the changes applied to a key or prefix.  Each
+// node applies the same learned mutations in txid order, so any node that
+// keeps data can serve one, and a consumer that knows the last txid it saw
+// can pick up anywhere else as long as the log there goes back that far.
+// Only mutations that changed something are sent: a CAS whose comparison
+// failed never shows up, and a transaction shows up as the sets and deletes
+// it wrote.
+pub struct Watch {
+    pub env: Envelope,
+    pub req_id: u64,
+    pub key: Vec<u8>,
+    pub recursive: bool,
+    // whoever holds the log back for this watch once it's gone
+    pub consumer: Option<String>,
+    // whether credentials and namespace definitions may be sent, which
+    // only root may see
+    pub credentials: bool,
+    // the last txid this watch has been sent everything up to
+    pub sent: TXID,
+}
+
+impl Watch {
+    pub fn new(env: Envelope,
+               req_id: u64,
+               watch: &WatchReq,
+               credentials: bool,
+               sent: TXID)
+               -> Watch {
+        Watch {
+            env: env,
+            req_id: req_id,
+            key: watch.get_key().to_vec(),
+            recursive: watch.get_recursive(),
+            consumer: if watch.has_consumer() {
+                Some(watch.get_consumer().to_string())
+            } else {
+                None
+            },
+            credentials: credentials,
+            sent: sent,
+        }
+    }
+
+    pub fn covers(&self, key: &[u8]) -> bool {
+        if !self.credentials &&
+           (auth::is_credentials_key(key) || namespace::is_namespace_key(key)) {
+            return false;
+        }
+        if self.recursive {
+            key.starts_with(&self.key)
+        } else {
+            key == &*self.key
+        }
+    }
+
+    // Whether a change touches anything this watch covers, which for a
+    // range delete means any key it could have deleted, and for a
+    // sequential put any key it could have been put under.
+    pub fn covers_change(&self, change: &Mutation) -> bool {
+        match change.get_field_type() {
+            MutationType::KVDELRANGE => {}
+            MutationType::KVSEQUENTIAL => {
+                return self.covers(change.get_key()) ||
+                       self.key.starts_with(change.get_key());
+            }
+            MutationType::KVTXN => {
+                let writes = txn::writes(change).unwrap_or(vec![]);
+                return writes.iter().any(|write| self.covers(write.get_key()));
+            }
+            _ => return self.covers(change.get_key()),
+        }
+        let (start, end) = (change.get_key(), change.get_value());
+        if !self.recursive {
+            return start <= &*self.key && &*self.key < end;
+        }
+        // the keys under our prefix run from it up to the next prefix
+        if &*self.key >= end {
+            return false;
+        }
+        let mut next = self.key.clone();
+        while next.last() == Some(&0xff) {
+            next.pop();
+        }
+        match next.pop() {
+            Some(last) => {
+                next.push(last + 1);
+                start < &*next
+            }
+            None => true,
+        }
+    }
+
+    // The replies carrying whichever of changes this watch covers, and
+    // notes that it's been sent everything up to txid.
+    pub fn send(&mut self, changes: &[Mutation], txid: TXID) -> Vec<Envelope> {
+        let writes = |m: &Mutation| {
+            if m.get_field_type() == MutationType::KVTXN {
+                txn::writes(m).unwrap_or(vec![])
+            } else {
+                vec![m.clone()]
+            }
+        };
+        let covered: Vec<Mutation> = changes.iter()
+                                            .flat_map(writes)
+                                            .filter(|m| self.covers_change(m))
+                                            .map(|m| change(&m))
+                                            .collect();
+        self.sent = txid;
+        covered.chunks(WATCH_BATCH)
+               .map(|batch| self.reply(reply(true, batch.to_vec(), None)))
+               .collect()
+    }
+
+    pub fn reply(&self, watch_res: WatchRes) -> Envelope {
+        let mut res = CliRes::new();
+        res.set_req_id(self.req_id);
+        res.set_watch(watch_res);
+        Envelope {
+            address: self.env.address,
+            tok: self.env.tok,
+            msg: ByteBuf::from_slice(&*res.write_to_bytes().unwrap()),
+        }
+    }
+
+    // Keeps everything after what this watch has been sent in the log,
+    // for as long as its consumer may take to come back for it.
+    pub fn protect(&self,
+                   horizon: &mut GcHorizon,
+                   now: time::Timespec,
+                   retention_ms: u64) {
+        if let Some(ref consumer) = self.consumer {
+            let until = now + time::Duration::milliseconds(retention_ms as i64);
+            horizon.protect(&owner(consumer), self.sent + 1, until);
+        }
+    }
+}
+
+pub fn reply(success: bool,
+             history: Vec<Mutation>,
+             err: Option<String>)
+             -> WatchRes {
+    let mut watch_res = WatchRes::new();
+    watch_res.set_success(success);
+    watch_res.set_history(RepeatedField::from_vec(history));
+    if let Some(err) = err {
+        watch_res.set_err(err);
+    }
+    watch_res
+}
+
+// what a consumer's protection on the log is held under
+pub fn owner(consumer: &str) -> String {
+    format!("watch {}", consumer)
+}
+
+// A mutation as a watch sees it: what was written, when and how, without
+// the leader's bookkeeping.  A CAS or a GETSET is sent as the set it turned
+// out to be, a range delete as the range, with its end as the value, an
+// APPEND or a SETRANGE as the bytes it wrote, and where, and a sequential
+// put as the prefix it was put under.
+fn change(mutation: &Mutation) -> Mutation {
+    let mut change = Mutation::new();
+    change.set_field_type(match mutation.get_field_type() {
+        MutationType::KVDEL => MutationType::KVDEL,
+        MutationType::KVDELRANGE => MutationType::KVDELRANGE,
+        MutationType::KVAPPEND => MutationType::KVAPPEND,
+        MutationType::KVSETRANGE => MutationType::KVSETRANGE,
+        MutationType::KVSEQUENTIAL => MutationType::KVSEQUENTIAL,
+        _ => MutationType::KVSET,
+    });
+    change.set_version(mutation.get_version().clone());
+    change.set_key(mutation.get_key().to_vec());
+    if mutation.has_value() {
+        change.set_value(mutation.get_value().to_vec());
+    }
+    if mutation.has_offset() {
+        change.set_offset(mutation.get_offset());
+    }
+    change
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, ByteBuf};
+    use mio::Token;
+    use protobuf::{self, Message, RepeatedField};
+
+    use {CliRes, Mutation, MutationType, TxnKey, TxnReq, Version, WatchReq};
+    use server::Envelope;
+    use super::{WATCH_BATCH, Watch};
+
+    fn mutation(txid: u64, key: &[u8], kind: MutationType) -> Mutation {
+        let mut version = Version::new();
+        version.set_txid(txid);
+        version.set_term(1);
+        let mut mutation = Mutation::new();
+        mutation.set_field_type(kind);
+        mutation.set_version(version);
+        mutation.set_key(key.to_vec());
+        if kind != MutationType::KVDEL {
+            mutation.set_value(b"v".to_vec());
+        }
+        mutation
+    }
+
+    #[test]
+    fn test_watch_covers_and_batches() {
+        let env = Envelope {
+            address: None,
+            tok: Token(3),
+            msg: ByteBuf::none(),
+        };
+        let mut req = WatchReq::new();
+        req.set_key(b"users/".to_vec());
+        req.set_recursive(true);
+        let mut watch = Watch::new(env, 9, &req, false, 0);
+        assert!(watch.covers(b"users/1"));
+        assert!(!watch.covers(b"groups/1"));
+        watch.key = vec![];
+        // root's secrets stay out of everyone else's watches
+        assert!(!watch.covers(b"\x00credentials/t"));
+        watch.key = b"users/".to_vec();
+
+        let mut changes = vec![mutation(1, b"groups/1", MutationType::KVSET),
+                               mutation(2, b"users/1", MutationType::KVCAS),
+                               mutation(3, b"users/1", MutationType::KVDEL)];
+        let sent = watch.send(&changes, 3);
+        assert_eq!(watch.sent, 3);
+        assert_eq!(sent.len(), 1);
+        let res: CliRes =
+            protobuf::parse_from_bytes(sent[0].msg.bytes()).unwrap();
+        assert_eq!(res.get_req_id(), 9);
+        let history = res.get_watch().get_history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].get_field_type(), MutationType::KVSET);
+        assert_eq!(history[0].get_version().get_txid(), 2);
+        assert!(!history[0].has_old_value());
+        assert_eq!(history[1].get_field_type(), MutationType::KVDEL);
+
+        // nothing covered, nothing sent
+        assert!(watch.send(&changes[..1], 4).is_empty());
+        assert_eq!(watch.sent, 4);
+
+        changes = (0..WATCH_BATCH as u64 + 1)
+                      .map(|i| mutation(5 + i, b"users/2", MutationType::KVSET))
+                      .collect();
+        assert_eq!(watch.send(&changes, 5 + WATCH_BATCH as u64).len(), 2);
+
+        // a range delete reaches whoever it may have deleted from
+        let mut range = mutation(200, b"a", MutationType::KVDELRANGE);
+        for &(start, end, covered) in [(&b"a"[..], &b"users/"[..], false),
+                                       (b"a", b"users/1", true),
+                                       (b"users/x", b"z", true),
+                                       (b"users0", b"z", false)]
+                                          .iter() {
+            range.set_key(start.to_vec());
+            range.set_value(end.to_vec());
+            assert_eq!(watch.covers_change(&range),
+                       covered,
+                       "{:?}",
+                       (start, end));
+        }
+        watch.recursive = false;
+        watch.key = b"users/1".to_vec();
+        assert!(!watch.covers_change(&range));
+        range.set_key(b"users/".to_vec());
+        assert!(watch.covers_change(&range));
+        let sent = watch.send(&[range], 201);
+        let res: CliRes =
+            protobuf::parse_from_bytes(sent[0].msg.bytes()).unwrap();
+        let change = &res.get_watch().get_history()[0];
+        assert_eq!(change.get_field_type(), MutationType::KVDELRANGE);
+        assert_eq!(change.get_key(), &b"users/"[..]);
+        assert_eq!(change.get_value(), &b"z"[..]);
+
+        // a GETSET is only a set, but a SETRANGE keeps where it wrote
+        let mut setrange = mutation(202, b"users/1", MutationType::KVSETRANGE);
+        setrange.set_offset(3);
+        let getset = mutation(201, b"users/1", MutationType::KVGETSET);
+        let sent = watch.send(&[getset, setrange], 202);
+        let res: CliRes =
+            protobuf::parse_from_bytes(sent[0].msg.bytes()).unwrap();
+        let history = res.get_watch().get_history();
+        assert_eq!(history[0].get_field_type(), MutationType::KVSET);
+        assert_eq!(history[1].get_field_type(), MutationType::KVSETRANGE);
+        assert_eq!(history[1].get_offset(), 3);
+
+        // any key under the prefix might be the one it was put under
+        let sequential = mutation(203, b"users/", MutationType::KVSEQUENTIAL);
+        assert!(watch.covers_change(&sequential));
+        watch.key = b"groups/1".to_vec();
+        assert!(!watch.covers_change(&sequential));
+
+        // a transaction is sent as whichever of its writes are covered
+        let mut txn_req = TxnReq::new();
+        let writes = [&b"users/1"[..], b"groups/1"]
+                         .iter()
+                         .map(|key| {
+                             let mut write = TxnKey::new();
+                             write.set_key(key.to_vec());
+                             write
+                         })
+                         .collect();
+        txn_req.set_writes(RepeatedField::from_vec(writes));
+        let mut txn = mutation(204, b"users/1", MutationType::KVTXN);
+        txn.set_value(txn_req.write_to_bytes().unwrap());
+        assert!(watch.covers_change(&txn));
+        let sent = watch.send(&[txn], 204);
+        let res: CliRes =
+            protobuf::parse_from_bytes(sent[0].msg.bytes()).unwrap();
+        let history = res.get_watch().get_history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].get_field_type(), MutationType::KVDEL);
+        assert_eq!(history[0].get_key(), b"groups/1");
+        assert_eq!(history[0].get_version().get_txid(), 204);
+    }
+}
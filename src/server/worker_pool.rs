@@ -0,0 +1,46 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+// A fixed set of threads that each drain their own queue.  Jobs are
+// assigned to a worker by hashing a key, so jobs for the same key are
+// always handled in the order they were dispatched, while jobs for
+// different keys proceed in parallel.
+pub struct WorkerPool<T: Send + 'static> {
+    workers: Vec<Sender<T>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F>(name: &str,
+                  nthreads: usize,
+                  handler: F)
+                  -> io::Result<WorkerPool<T>>
+        where F: Fn(T) + Send + Clone + 'static
+    {
+        let mut workers = vec![];
+        for i in 0..nthreads {
+            let (tx, rx) = mpsc::channel();
+            let handler = handler.clone();
+            try!(thread::Builder::new()
+                     .name(format!("{} {}", name, i))
+                     .spawn(move || {
+                         for job in rx {
+                             handler(job);
+                         }
+                     }));
+            workers.push(tx);
+        }
+        Ok(WorkerPool { workers: workers })
+    }
+
+    // Hands the job to the worker responsible for this key.  If that
+    // worker has died, the job is given back to the caller.
+    pub fn dispatch(&self, key: &[u8], job: T) -> Result<(), T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = (hasher.finish() % self.workers.len() as u64) as usize;
+        self.workers[idx].send(job).map_err(|e| e.0)
+    }
+}
@@ -1,29 +1,58 @@
 extern crate bytes;
 extern crate rand;
 extern crate mio;
-extern crate uuid;
 
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
 use std::fs;
 use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc::{self, Sender, Receiver, SendError};
 
 use self::rand::{StdRng, SeedableRng, Rng};
 use self::bytes::{Buf, ByteBuf};
 use self::mio::Token;
-use rasputin::server::rocksdb as db;
-use rasputin::server::{Server, Envelope, State, Peer, InMemoryLog,
-                       LEADER_DURATION, PEER_BROADCAST};
-use rasputin::{Clock, TestClock, Mutation};
-use self::uuid::Uuid;
+use rasputin::server::rocksdb::{self as db, WalSyncer};
+use rasputin::server::{Server, ServerConfig, Durability, Envelope, Faults,
+                       State, Peer, InMemoryLog, GcHorizon, Membership, Metrics,
+                       Namespaces, NodeStatus, ServerMsg, StorageStats,
+                       Watermark, LEADER_DURATION, PEER_BROADCAST};
+use rasputin::{Clock, HLC, TestClock, Mutation};
+use invariants::InvariantChecker;
 
 // SimCluster facilitates testing a cluster against network failures.
 // This is accomplished by dropping messages, delaying messages, and randomizing
 // which surviving ready messages are chosen in which order (but surviving
 // messages between the same two nodes preserve ordering, because we use a
-// single tcp connection for now)
+// single tcp connection for now, unless SimNetwork says to reorder them).
+// Every random choice comes from one seeded rng, so a run can be replayed
+// exactly from its seed.
 
+// How the simulated network treats peer messages.  Each one takes between
+// min_delay_ms and max_delay_ms to arrive, if it isn't dropped.
+#[derive(Debug, Clone)]
+pub struct SimNetwork {
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // the chance of losing any one message
+    pub drop_rate: f64,
+    // let later messages between two nodes overtake earlier ones
+    pub reorder: bool,
+}
+
+impl Default for SimNetwork {
+    fn default() -> SimNetwork {
+        SimNetwork {
+            min_delay_ms: 1,
+            max_delay_ms: 1,
+            drop_rate: 0.0,
+            reorder: false,
+        }
+    }
+}
+
+#[derive(PartialEq)]
 enum Condition {
     Partition { node1: u16, node2: u16 },
     Paused { node: u16 }
@@ -32,6 +61,21 @@ enum Condition {
 enum Event {
     Cron { node: u16 },
     Receive { to: SocketAddr, env: Envelope },
+    Client { node: u16, env: Envelope },
+}
+
+// stands in for the connection of a client talking to the cluster
+const SIM_CLIENT: Token = Token(1 << 20);
+
+// What can go wrong with one node's network and clock, on top of what the
+// SimNetwork does to everyone.  Storage faults are set on its server.
+#[derive(Debug, Clone, Default)]
+pub struct SimFaults {
+    // the chances of losing, or of delivering twice, a message it sends
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    // its clock stops while everyone else's goes on
+    pub frozen_clock: bool,
 }
 
 pub struct SimServer {
@@ -41,34 +85,46 @@ pub struct SimServer {
     outbound: Receiver<Envelope>,
     pub tok: Token,
     addr: SocketAddr,
+    pub faults: SimFaults,
 }
 
 pub struct SimCluster {
+    seed: usize,
     rng: StdRng,
     clock: u64, // elapsed time in ms
     events: BTreeMap<u64, Vec<Event>>, // times to events
     pub nodes: BTreeMap<u16, SimServer>,
     filters: Vec<Condition>,
+    // what paused nodes would have done by now, for when they resume
+    paused_events: BTreeMap<u16, Vec<Event>>,
+    network: SimNetwork,
+    // (from, to) -> when the last message sent between them arrives
+    links: BTreeMap<(u16, u16), u64>,
+    // checked after every step when set, which by default it is in
+    // debug builds
+    invariants: Option<InvariantChecker>,
+    // replies to client requests, in the order they were sent
+    cli_responses: Vec<Envelope>,
 }
 
 impl SimCluster {
     pub fn new(dir: &str, num_nodes: u16) -> SimCluster {
-        let mut logs = vec![];
-        for i in 0..num_nodes as usize {
-            logs.push(InMemoryLog {
-                pending: BTreeMap::new(),
-                committed: BTreeMap::new(),
-                quorum: num_nodes as usize / 2 + 1,
-                last_learned_txid: 0,
-                last_learned_term: 0,
-                last_accepted_txid: 0,
-                last_accepted_term: 0,
-            });
-        }
-        SimCluster::new_from_logs(dir, logs)
+        SimCluster::new_from_logs(dir, sim_logs(num_nodes))
     }
 
+    // RASPUTIN_SIM_SEED replays a run reported by a failed invariant check
     pub fn new_from_logs(dir: &str, logs: Vec<InMemoryLog<Mutation>>) -> SimCluster {
+        let seed = env::var("RASPUTIN_SIM_SEED")
+                       .ok()
+                       .and_then(|s| s.parse().ok())
+                       .unwrap_or(0);
+        SimCluster::with_seed(dir, logs, seed)
+    }
+
+    pub fn with_seed(dir: &str,
+                     logs: Vec<InMemoryLog<Mutation>>,
+                     seed: usize)
+                     -> SimCluster {
         let mut peers = vec![];
         let mut peer_strings = vec![];
         for i in 0..logs.len() {
@@ -79,6 +135,11 @@ impl SimCluster {
         }
 
         let mut nodes = BTreeMap::new();
+        // ids decide the order servers see each other in, so they come
+        // from the seed too, but from their own rng so the events drawn
+        // from the main one don't shift
+        let id_seed: &[_] = &[seed, 1];
+        let mut id_rng: StdRng = SeedableRng::from_seed(id_seed);
 
         let mut toks = 0;
         for (peer, rep_log) in peers.iter().zip(logs) {
@@ -88,20 +149,62 @@ impl SimCluster {
 
             let state_dir = format!("_rasputin_test/{}/sim_{}",
                                     dir, peer.port());
+            let id = format!("{:016x}-sim-{}",
+                             id_rng.gen::<u64>(),
+                             peer.port());
+            let metrics = Arc::new(Metrics::new());
+            let phi_threshold = ServerConfig::default().phi_threshold;
             let server = Server {
                 clock: clock.clone(),
+                hlc: HLC::new(clock.clone()),
                 peer_port: peer.port(),
                 cli_port: 65535 - peer.port(),
-                id: Uuid::new_v4().to_string(),
+                id: id.clone(),
+                membership: Membership::new(id.clone(),
+                                            peer.port(),
+                                            phi_threshold,
+                                            clock.now()),
                 rpc_tx: Box::new(tx),
                 max_generated_txid: 0,
                 highest_term: 0,
                 state: State::Init,
-                db: db::new(state_dir.clone()),
+                db: Arc::new(db::new(state_dir.clone()).unwrap()),
+                read_pool: None,
+                backup_pool: None,
+                export_pool: None,
+                snapshot_worker: None,
                 rep_log: Box::new(rep_log),
+                config: ServerConfig::default(),
+                live_config: Arc::new(RwLock::new(ServerConfig::default())),
                 peers: peer_strings.clone(),
                 rep_peers: BTreeMap::new(),
                 pending: BTreeMap::new(),
+                traced: BTreeMap::new(),
+                unapplied: vec![],
+                ingest_dir: format!("{}/ingest", state_dir),
+                wal: WalSyncer::new(Durability::Always, metrics.clone()),
+                metrics: metrics,
+                horizon: GcHorizon::new(),
+                retain_from: 0,
+                status: Arc::new(RwLock::new(NodeStatus::new(id))),
+                transfer: None,
+                draining: false,
+                pre_vote: None,
+                sessions: BTreeMap::new(),
+                next_scrub: None,
+                scrub: None,
+                scrub_report: BTreeMap::new(),
+                repair: None,
+                clock_skewed: false,
+                disk_watermark: Watermark::Below,
+                stats: StorageStats::new(),
+                namespaces: Namespaces::new(),
+                faults: Faults::default(),
+                watches: vec![],
+                promoted: false,
+                unchanged: BTreeSet::new(),
+                backchannel: None,
+                taking: 0,
             };
 
             nodes.insert(peer.port(), SimServer {
@@ -111,18 +214,30 @@ impl SimCluster {
                 clock: clock.clone(),
                 outbound: rx,
                 tok: Token(toks),
+                faults: SimFaults::default(),
             });
 
             toks += 1;
         }
 
-        let seed: &[_] = &[0];
+        let invariants = if cfg!(debug_assertions) {
+            Some(InvariantChecker::new())
+        } else {
+            None
+        };
+        let rng_seed: &[_] = &[seed];
         let mut ns = SimCluster{
-            rng: SeedableRng::from_seed(seed),
+            seed: seed,
+            rng: SeedableRng::from_seed(rng_seed),
             clock: 0,
             events: BTreeMap::new(),
             nodes: nodes,
             filters: vec![],
+            paused_events: BTreeMap::new(),
+            network: SimNetwork::default(),
+            links: BTreeMap::new(),
+            invariants: invariants,
+            cli_responses: vec![],
         };
 
         // fire up the servers by queuing their cron
@@ -136,45 +251,216 @@ impl SimCluster {
         ns
     }
 
+    pub fn seed(&self) -> usize {
+        self.seed
+    }
+
+    pub fn set_network(&mut self, network: SimNetwork) {
+        self.network = network;
+    }
+
+    pub fn check_invariants(&mut self, enabled: bool) {
+        self.invariants = if enabled {
+            Some(InvariantChecker::new())
+        } else {
+            None
+        };
+    }
+
     pub fn leaders(&self) -> Vec<u16> {
         self.nodes.iter()
                   .filter(|&(id, n)| n.server.state.is_leader())
                   .map(|(id, n)| *id).collect()
     }
 
+    // A paused node does nothing, as if stopped, until it's unpaused.
+    // What was sent to it waits for it, as it would in its socket buffers.
     pub fn pause_node(&mut self, node: u16) -> Result<(), ()> {
-        // TODO
-        Err(())
+        self.add_condition(node, Condition::Paused { node: node })
     }
 
     pub fn unpause_node(&mut self, node: u16) -> Result<(), ()> {
-        // TODO
-        Err(())
+        try!(self.remove_condition(Condition::Paused { node: node }));
+        let now = self.clock;
+        for event in self.paused_events.remove(&node).unwrap_or(vec![]) {
+            self.push_event(now + 1, event);
+        }
+        Ok(())
     }
 
+    // Messages between partitioned nodes are lost, both ways.
     pub fn partition_two_nodes(&mut self, node1: u16, node2: u16) -> Result<(), ()> {
-        // TODO
-        Err(())
+        if !self.nodes.contains_key(&node2) || self.partitioned(node1, node2) {
+            return Err(());
+        }
+        let partition = Condition::Partition { node1: node1, node2: node2 };
+        self.add_condition(node1, partition)
     }
 
-    pub fn unpartition_two_nodes(&mut self, node1: u16, node2: u16) -> Result<(), ()> {
-        // TODO
-        Err(())
+    pub fn unpartition_two_nodes(&mut self,
+                                 node1: u16,
+                                 node2: u16)
+                                 -> Result<(), ()> {
+        let there = Condition::Partition { node1: node1, node2: node2 };
+        let back = Condition::Partition { node1: node2, node2: node1 };
+        self.remove_condition(there).or_else(|_| self.remove_condition(back))
     }
 
+    // Nobody can reach anybody.
     pub fn partition_all(&mut self) {
+        let nodes: Vec<u16> = self.nodes.keys().cloned().collect();
+        for a in nodes.iter() {
+            for b in nodes.iter().filter(|b| a < *b) {
+                let _ = self.partition_two_nodes(*a, *b);
+            }
+        }
     }
 
     pub fn unpartition_all(&mut self) {
+        self.filters.retain(|c| match *c {
+            Condition::Partition { .. } => false,
+            _ => true,
+        });
+    }
+
+    // Cuts a node off from everyone else.
+    pub fn isolate(&mut self, node: u16) {
+        let others: Vec<u16> =
+            self.nodes.keys().cloned().filter(|n| *n != node).collect();
+        for other in others {
+            let _ = self.partition_two_nodes(node, other);
+        }
+    }
+
+    // Splits the cluster into the given nodes and the rest.
+    pub fn split(&mut self, side: &[u16]) {
+        let nodes: Vec<u16> = self.nodes.keys().cloned().collect();
+        for a in side.iter() {
+            for b in nodes.iter().filter(|b| !side.contains(b)) {
+                let _ = self.partition_two_nodes(*a, *b);
+            }
+        }
+    }
+
+    // Undoes every partition and resumes every paused node.
+    pub fn heal(&mut self) {
+        self.unpartition_all();
+        let nodes: Vec<u16> = self.nodes.keys().cloned().collect();
+        for node in nodes {
+            let _ = self.unpause_node(node);
+        }
+    }
+
+    fn partitioned(&self, node1: u16, node2: u16) -> bool {
+        self.filters.iter().any(|c| match *c {
+            Condition::Partition { node1: a, node2: b } => {
+                (a, b) == (node1, node2) || (a, b) == (node2, node1)
+            }
+            _ => false,
+        })
+    }
+
+    fn paused(&self, node: u16) -> bool {
+        self.filters.contains(&Condition::Paused { node: node })
+    }
+
+    fn add_condition(&mut self,
+                     node: u16,
+                     condition: Condition)
+                     -> Result<(), ()> {
+        if !self.nodes.contains_key(&node) ||
+           self.filters.contains(&condition) {
+            return Err(());
+        }
+        self.filters.push(condition);
+        Ok(())
+    }
+
+    fn remove_condition(&mut self, condition: Condition) -> Result<(), ()> {
+        match self.filters.iter().position(|c| *c == condition) {
+            Some(i) => {
+                self.filters.remove(i);
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
+    // Delivers a client request to a node on the next step.  Whatever it
+    // replies with shows up in take_client_responses.
+    pub fn client_request(&mut self, node: u16, msg: &[u8]) {
+        let arrival = self.clock + 1;
+        self.push_event(arrival, Event::Client {
+            node: node,
+            env: Envelope {
+                address: None,
+                tok: SIM_CLIENT,
+                msg: ByteBuf::from_slice(msg),
+            },
+        });
+    }
+
+    pub fn take_client_responses(&mut self) -> Vec<Envelope> {
+        self.cli_responses.drain(..).collect()
+    }
+
+    // The client's connection to a node goes away.
+    pub fn close_client(&mut self, node: u16) {
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.server.handle(ServerMsg::CliClosed(SIM_CLIENT));
+        }
     }
 
     pub fn advance_time(&mut self, ms: u64) {
         self.clock += ms;
         for (_, node) in self.nodes.iter_mut() {
-            node.clock.sleep_ms(ms as u32);
+            if !node.faults.frozen_clock {
+                node.clock.sleep_ms(ms as u32);
+            }
         }
     }
 
+    // Puts a peer message on the wire, to arrive or not as the network
+    // decides.  The rng is only consulted for what the network leaves to
+    // chance, so a default network replays the same as it always has.
+    fn send(&mut self, from: u16, to: u16, env: Envelope) {
+        if self.partitioned(from, to) {
+            return;
+        }
+        let faults = self.nodes[&from].faults.clone();
+        if self.chance(self.network.drop_rate) ||
+           self.chance(faults.drop_rate) {
+            return;
+        }
+        if self.chance(faults.duplicate_rate) {
+            self.deliver(from, to, env.clone());
+        }
+        self.deliver(from, to, env);
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        rate > 0.0 && self.rng.gen::<f64>() < rate
+    }
+
+    fn deliver(&mut self, from: u16, to: u16, env: Envelope) {
+        let (min, max) = (self.network.min_delay_ms, self.network.max_delay_ms);
+        let delay = if max > min {
+            self.rng.gen_range(min, max + 1)
+        } else {
+            min
+        };
+        let mut arrival = self.clock + delay;
+        if !self.network.reorder {
+            let last = self.links.entry((from, to)).or_insert(0);
+            arrival = cmp::max(arrival, *last);
+            *last = arrival;
+        }
+        self.push_event(arrival, Event::Receive {
+            to: u16_to_socketaddr(to),
+            env: env,
+        });
+    }
+
     fn push_event(&mut self, time: u64, event: Event) {
         match self.events.get_mut(&time) {
             Some(event_vec) => {
@@ -203,9 +489,19 @@ impl SimCluster {
 
         // Perform event
         for event in events.unwrap() {
+            let node = match event {
+                Event::Cron{node:node} | Event::Client{node:node, ..} => node,
+                Event::Receive{to:to, ..} => to.port(),
+            };
+            if self.paused(node) {
+                self.paused_events.entry(node).or_insert(vec![]).push(event);
+                continue;
+            }
             match event {
                 Event::Cron{node:node} => {
-                    self.nodes.get_mut(&node).unwrap().server.cron();
+                    let server = &mut self.nodes.get_mut(&node).unwrap().server;
+                    server.cron();
+                    server.flush();
                     let time = self.rng.gen_range(400,500);
                     self.push_event(
                         after + time,
@@ -215,6 +511,12 @@ impl SimCluster {
                 Event::Receive{to:to, env:env} => {
                     let node = self.nodes.get_mut(&to.port()).unwrap();
                     node.server.handle_peer(env);
+                    node.server.flush();
+                },
+                Event::Client{node:node, env:env} => {
+                    let server = &mut self.nodes.get_mut(&node).unwrap().server;
+                    server.handle_cli(env);
+                    server.flush();
                 },
             }
         }
@@ -229,8 +531,11 @@ impl SimCluster {
                 }
             }
         }
-        // TODO(tyler) apply filters and node selection randomization
         for (addr, env) in outbound {
+            if env.tok == SIM_CLIENT {
+                self.cli_responses.push(env);
+                continue;
+            }
             let env_with_return_address = Envelope {
                 address: Some(addr),
                 tok: Token(addr.port() as usize),
@@ -241,18 +546,22 @@ impl SimCluster {
                 // to all connected peers.
                 let ports = self.nodes.len();
                 for port in 0..ports {
-                    let arrival = self.clock + 1;
-                    self.push_event(arrival, Event::Receive {
-                        to: u16_to_socketaddr(port as u16),
-                        env: env_with_return_address.clone(),
-                    });
+                    self.send(addr.port(),
+                              port as u16,
+                              env_with_return_address.clone());
                 }
             } else {
-                let arrival = self.clock + 1;
-                self.push_event(arrival, Event::Receive {
-                    to: u16_to_socketaddr(env.tok.as_usize() as u16),
-                    env: env_with_return_address,
-                });
+                self.send(addr.port(),
+                          env.tok.as_usize() as u16,
+                          env_with_return_address);
+            }
+        }
+
+        if let Some(ref mut invariants) = self.invariants {
+            if let Err(e) = invariants.check(&self.nodes) {
+                panic!("invariant violated at {}ms: {} (reproduce with \
+                        RASPUTIN_SIM_SEED={})",
+                       self.clock, e, self.seed);
             }
         }
     }
@@ -266,6 +575,23 @@ impl Drop for SimServer {
     }
 }
 
+pub fn sim_logs(num_nodes: u16) -> Vec<InMemoryLog<Mutation>> {
+    let mut logs = vec![];
+    for _ in 0..num_nodes {
+        logs.push(InMemoryLog {
+            pending: BTreeMap::new(),
+            committed: BTreeMap::new(),
+            quorum: num_nodes as usize / 2 + 1,
+            last_learned_txid: 0,
+            last_learned_term: 0,
+            last_accepted_txid: 0,
+            last_accepted_term: 0,
+            collected_before: 0,
+        });
+    }
+    logs
+}
+
 fn u16_to_socketaddr(from: u16) -> SocketAddr {
     let ip = Ipv4Addr::new(1, 0, (from / 256) as u8, (from % 256) as u8);
     SocketAddr::V4(SocketAddrV4::new(ip, from))
@@ -0,0 +1,378 @@
+extern crate bytes;
+extern crate protobuf;
+
+use self::bytes::{Buf, ByteBuf};
+use self::protobuf::{Message, MessageStatic, RepeatedField};
+
+use rasputin::{Append, AppendRes, CASReq, CASRes, CliReq, CliRes, Codec,
+               DelReq, DelRes, Framed, GetReq, GetRes, Hello, Mutation,
+               MutationType, PeerMsg, RedirectRes, SetReq, SetRes, Version,
+               VoteReq, VoteRes, WatchReq, WatchRes};
+use rasputin::codec;
+
+// Golden frames for every message we put on the wire.  Each fixture is the
+// exact byte sequence (header included) that a client or server sends,
+// so any change here is a wire compatibility break and must be deliberate.
+// Fixtures are checked against the client's encoding path (serialize, then
+// frame) and the server's decoding path (unframe, then parse).
+
+// what every connection opens with
+const HELLO: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x08, 0x01, 0x12, 0x03,
+    0x6c, 0x7a, 0x34, 0x18, 0x80, 0x80, 0x80, 0x08,
+];
+
+const SET_REQ: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x08, 0x01, 0x1a, 0x08,
+    0x0a, 0x02, 0x6b, 0x31, 0x12, 0x02, 0x76, 0x31, 0x38, 0xe8, 0x07,
+];
+
+const GET_REQ: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x08, 0x02, 0x12, 0x04,
+    0x0a, 0x02, 0x6b, 0x31,
+];
+
+const CAS_REQ: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x10, 0x08, 0x03, 0x22, 0x0c,
+    0x0a, 0x02, 0x6b, 0x31, 0x12, 0x02, 0x76, 0x32, 0x1a, 0x02, 0x76, 0x31,
+];
+
+const DEL_REQ: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x08, 0x04, 0x2a, 0x04,
+    0x0a, 0x02, 0x6b, 0x31,
+];
+
+const WATCH_REQ: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0d, 0x08, 0x07, 0x32, 0x09,
+    0x0a, 0x01, 0x6b, 0x10, 0x03, 0x18, 0x00, 0x20, 0x01,
+];
+
+const SET_RES: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x08, 0x01, 0x1a, 0x04,
+    0x08, 0x01, 0x10, 0x07,
+];
+
+const GET_RES: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x08, 0x02, 0x12, 0x08,
+    0x08, 0x01, 0x10, 0x07, 0x1a, 0x02, 0x76, 0x31,
+];
+
+const GET_RES_NOT_FOUND: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x17, 0x08, 0x02, 0x12, 0x13,
+    0x08, 0x00, 0x10, 0x07, 0x22, 0x0d, 0x4b, 0x65, 0x79, 0x20, 0x6e, 0x6f,
+    0x74, 0x20, 0x66, 0x6f, 0x75, 0x6e, 0x64,
+];
+
+const CAS_RES_COMPARE_FAILURE: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x1d, 0x08, 0x03, 0x22, 0x19,
+    0x08, 0x00, 0x10, 0x08, 0x1a, 0x02, 0x76, 0x31, 0x22, 0x0f, 0x63, 0x6f,
+    0x6d, 0x70, 0x61, 0x72, 0x65, 0x20, 0x66, 0x61, 0x69, 0x6c, 0x75, 0x72,
+    0x65,
+];
+
+const DEL_RES: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x08, 0x04, 0x2a, 0x08,
+    0x08, 0x01, 0x10, 0x09, 0x1a, 0x02, 0x76, 0x32,
+];
+
+const WATCH_RES: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x13, 0x08, 0x07, 0x32, 0x0f,
+    0x08, 0x01, 0x12, 0x0b, 0x08, 0x03, 0x12, 0x04, 0x08, 0x03, 0x10, 0x01,
+    0x1a, 0x01, 0x6b,
+];
+
+const REDIRECT_RES: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x16, 0x08, 0x05, 0x3a, 0x12,
+    0x08, 0x01, 0x12, 0x0e, 0x31, 0x32, 0x37, 0x2e, 0x30, 0x2e, 0x30, 0x2e,
+    0x31, 0x3a, 0x38, 0x38, 0x38, 0x38,
+];
+
+const REDIRECT_RES_NO_LEADER: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x26, 0x08, 0x06, 0x3a, 0x22,
+    0x08, 0x00, 0x1a, 0x1e, 0x4e, 0x6f, 0x20, 0x6c, 0x65, 0x61, 0x64, 0x65,
+    0x72, 0x20, 0x68, 0x61, 0x73, 0x20, 0x62, 0x65, 0x65, 0x6e, 0x20, 0x65,
+    0x6c, 0x65, 0x63, 0x74, 0x65, 0x64, 0x20, 0x79, 0x65, 0x74,
+];
+
+const VOTE_REQ: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x12, 0x0a, 0x01, 0x61, 0x12,
+    0x0a, 0x08, 0x02, 0x10, 0x01, 0x18, 0x05, 0x20, 0x01, 0x28, 0x06, 0x30,
+    0xe0, 0x5d,
+];
+
+const VOTE_RES: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x09, 0x0a, 0x01, 0x62, 0x1a,
+    0x04, 0x08, 0x01, 0x10, 0x02,
+];
+
+const APPEND: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x1d, 0x0a, 0x01, 0x61, 0x22,
+    0x18, 0x08, 0x06, 0x10, 0x01, 0x1a, 0x10, 0x08, 0x01, 0x12, 0x04, 0x08,
+    0x07, 0x10, 0x02, 0x1a, 0x02, 0x6b, 0x31, 0x22, 0x02, 0x76, 0x31, 0x20,
+    0x06,
+];
+
+const APPEND_RES: &'static [u8] = &[
+    0x52, 0x50, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0b, 0x0a, 0x01, 0x62, 0x2a,
+    0x06, 0x08, 0x01, 0x10, 0x07, 0x18, 0x02,
+];
+
+// Runs each chunk through a single Framed decoder, as the server does when a
+// frame arrives over several reads.
+fn decode_frames(chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+    let mut codec = Framed::new();
+    let mut frames = vec![];
+    for chunk in chunks {
+        let mut buf = ByteBuf::from_slice(chunk);
+        for frame in codec.decode(&mut buf) {
+            frames.push(frame.bytes().to_vec());
+        }
+    }
+    frames
+}
+
+fn check<M: Message + MessageStatic + PartialEq>(msg: M, golden: &[u8]) {
+    let codec = Framed::new();
+    let encoded =
+        codec.encode(ByteBuf::from_slice(&*msg.write_to_bytes().unwrap()));
+    assert!(encoded.bytes() == golden);
+
+    let frames = decode_frames(&[golden]);
+    assert!(frames.len() == 1);
+    let decoded: M = protobuf::parse_from_bytes(&*frames[0]).unwrap();
+    assert!(decoded == msg);
+}
+
+fn version(txid: u64, term: u64) -> Version {
+    let mut version = Version::new();
+    version.set_txid(txid);
+    version.set_term(term);
+    version
+}
+
+fn cli_req(req_id: u64) -> CliReq {
+    let mut req = CliReq::new();
+    req.set_req_id(req_id);
+    req
+}
+
+fn cli_res(req_id: u64) -> CliRes {
+    let mut res = CliRes::new();
+    res.set_req_id(req_id);
+    res
+}
+
+fn peer_msg(srvid: &str) -> PeerMsg {
+    let mut msg = PeerMsg::new();
+    msg.set_srvid(srvid.to_string());
+    msg
+}
+
+#[test]
+fn client_requests() {
+    let mut set = SetReq::new();
+    set.set_key(b"k1".to_vec());
+    set.set_value(b"v1".to_vec());
+    let mut req = cli_req(1);
+    req.set_set(set);
+    req.set_deadline_ms(1000);
+    check(req, SET_REQ);
+
+    let mut get = GetReq::new();
+    get.set_key(b"k1".to_vec());
+    let mut req = cli_req(2);
+    req.set_get(get);
+    check(req, GET_REQ);
+
+    let mut cas = CASReq::new();
+    cas.set_key(b"k1".to_vec());
+    cas.set_new_value(b"v2".to_vec());
+    cas.set_old_value(b"v1".to_vec());
+    let mut req = cli_req(3);
+    req.set_cas(cas);
+    check(req, CAS_REQ);
+
+    let mut del = DelReq::new();
+    del.set_key(b"k1".to_vec());
+    let mut req = cli_req(4);
+    req.set_del(del);
+    check(req, DEL_REQ);
+
+    let mut watch = WatchReq::new();
+    watch.set_key(b"k".to_vec());
+    watch.set_last_txid(3);
+    watch.set_recursive(false);
+    watch.set_historical(true);
+    let mut req = cli_req(7);
+    req.set_watch(watch);
+    check(req, WATCH_REQ);
+}
+
+#[test]
+fn client_responses() {
+    let mut set = SetRes::new();
+    set.set_success(true);
+    set.set_txid(7);
+    let mut res = cli_res(1);
+    res.set_set(set);
+    check(res, SET_RES);
+
+    let mut get = GetRes::new();
+    get.set_success(true);
+    get.set_txid(7);
+    get.set_value(b"v1".to_vec());
+    let mut res = cli_res(2);
+    res.set_get(get);
+    check(res, GET_RES);
+
+    let mut del = DelRes::new();
+    del.set_success(true);
+    del.set_txid(9);
+    del.set_value(b"v2".to_vec());
+    let mut res = cli_res(4);
+    res.set_del(del);
+    check(res, DEL_RES);
+
+    let mut mutation = Mutation::new();
+    mutation.set_field_type(MutationType::KVDEL);
+    mutation.set_version(version(3, 1));
+    mutation.set_key(b"k".to_vec());
+    let mut watch = WatchRes::new();
+    watch.set_success(true);
+    watch.set_history(RepeatedField::from_vec(vec![mutation]));
+    let mut res = cli_res(7);
+    res.set_watch(watch);
+    check(res, WATCH_RES);
+}
+
+#[test]
+fn error_responses() {
+    let mut get = GetRes::new();
+    get.set_success(false);
+    get.set_txid(7);
+    get.set_err("Key not found".to_string());
+    let mut res = cli_res(2);
+    res.set_get(get);
+    check(res, GET_RES_NOT_FOUND);
+
+    let mut cas = CASRes::new();
+    cas.set_success(false);
+    cas.set_txid(8);
+    cas.set_value(b"v1".to_vec());
+    cas.set_err("compare failure".to_string());
+    let mut res = cli_res(3);
+    res.set_cas(cas);
+    check(res, CAS_RES_COMPARE_FAILURE);
+}
+
+#[test]
+fn redirect_responses() {
+    let mut redirect = RedirectRes::new();
+    redirect.set_success(true);
+    redirect.set_address("127.0.0.1:8888".to_string());
+    let mut res = cli_res(5);
+    res.set_redirect(redirect);
+    check(res, REDIRECT_RES);
+
+    let mut redirect = RedirectRes::new();
+    redirect.set_success(false);
+    redirect.set_err("No leader has been elected yet".to_string());
+    let mut res = cli_res(6);
+    res.set_redirect(redirect);
+    check(res, REDIRECT_RES_NO_LEADER);
+}
+
+#[test]
+fn peer_messages() {
+    let mut vote_req = VoteReq::new();
+    vote_req.set_term(2);
+    vote_req.set_last_learned_term(1);
+    vote_req.set_last_learned_txid(5);
+    vote_req.set_last_accepted_term(1);
+    vote_req.set_last_accepted_txid(6);
+    let mut msg = peer_msg("a");
+    msg.set_vote_req(vote_req);
+    msg.set_deadline_ms(12000);
+    check(msg, VOTE_REQ);
+
+    let mut vote_res = VoteRes::new();
+    vote_res.set_success(true);
+    vote_res.set_term(2);
+    let mut msg = peer_msg("b");
+    msg.set_vote_res(vote_res);
+    check(msg, VOTE_RES);
+
+    let mut mutation = Mutation::new();
+    mutation.set_field_type(MutationType::KVSET);
+    mutation.set_version(version(7, 2));
+    mutation.set_key(b"k1".to_vec());
+    mutation.set_value(b"v1".to_vec());
+    let mut append = Append::new();
+    append.set_from_txid(6);
+    append.set_from_term(1);
+    append.set_batch(RepeatedField::from_vec(vec![mutation]));
+    append.set_last_learned_txid(6);
+    let mut msg = peer_msg("a");
+    msg.set_append(append);
+    check(msg, APPEND);
+
+    let mut append_res = AppendRes::new();
+    append_res.set_accepted(true);
+    append_res.set_last_accepted_txid(7);
+    append_res.set_last_accepted_term(2);
+    let mut msg = peer_msg("b");
+    msg.set_append_res(append_res);
+    check(msg, APPEND_RES);
+}
+
+#[test]
+fn framing() {
+    // several frames delivered in a single read
+    let mut both = SET_REQ.to_vec();
+    both.extend(GET_REQ.iter().cloned());
+    let frames = decode_frames(&[&*both]);
+    assert!(frames.len() == 2);
+    assert!(&*frames[0] == &SET_REQ[8..]);
+    assert!(&*frames[1] == &GET_REQ[8..]);
+
+    // a frame split inside its header, and again inside its body
+    let frames =
+        decode_frames(&[&SET_REQ[..2], &SET_REQ[2..13], &SET_REQ[13..]]);
+    assert!(frames.len() == 1);
+    assert!(&*frames[0] == &SET_REQ[8..]);
+
+    // a frame followed by the start of the next one
+    let mut partial = GET_REQ.to_vec();
+    partial.extend(DEL_REQ[..10].iter().cloned());
+    let frames = decode_frames(&[&*partial]);
+    assert!(frames.len() == 1);
+    assert!(&*frames[0] == &GET_REQ[8..]);
+}
+
+#[test]
+fn hello() {
+    let codec = Framed::new();
+    let hello = codec.hello();
+    let hello_bytes = hello.write_to_bytes().unwrap();
+    let encoded = codec.encode_hello(ByteBuf::from_slice(&*hello_bytes));
+    assert!(encoded.bytes() == HELLO);
+
+    // a hello is kept for the connection rather than passed on as a request
+    let mut both = HELLO.to_vec();
+    both.extend(GET_REQ.iter().cloned());
+    let mut codec = Framed::new();
+    let frames = codec.decode(&mut ByteBuf::from_slice(&*both));
+    assert!(frames.len() == 1);
+    assert!(frames[0].bytes() == &GET_REQ[8..]);
+    let taken = codec.take_hello().unwrap();
+    let theirs: Hello = protobuf::parse_from_bytes(taken.bytes()).unwrap();
+    assert!(codec.negotiate(&theirs) == hello);
+
+    // whatever follows a frame we can't make sense of is ignored
+    let mut garbled = GET_REQ.to_vec();
+    garbled[0] = b'G';
+    garbled.extend(DEL_REQ.iter().cloned());
+    let mut codec = Framed::new();
+    assert!(codec.decode(&mut ByteBuf::from_slice(&*garbled)).is_empty());
+    assert!(codec.broken().is_some());
+}
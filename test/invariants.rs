@@ -0,0 +1,148 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use rasputin::server::{TXID, Term};
+use rasputin::Mutation;
+
+use cluster::SimServer;
+
+// Watches a simulated cluster for violations of the Raft safety properties
+// after every step, so that a bug surfaces at the step that caused it
+// rather than in whatever assertion a test happens to make afterward:
+//
+// * Election Safety: at most one node is leader in any given term.
+// * Log Matching: if two logs have an entry with the same txid and term,
+//   the entries are identical, and so are all entries before them.
+// * Commit Monotonicity: a node's last learned txid never goes backwards,
+//   and once a txid is learned anywhere, nobody learns something else
+//   for it.
+pub struct InvariantChecker {
+    leaders: BTreeMap<Term, u16>,
+    last_learned: BTreeMap<u16, TXID>,
+    learned: BTreeMap<TXID, Mutation>,
+}
+
+impl InvariantChecker {
+    pub fn new() -> InvariantChecker {
+        InvariantChecker {
+            leaders: BTreeMap::new(),
+            last_learned: BTreeMap::new(),
+            learned: BTreeMap::new(),
+        }
+    }
+
+    pub fn check(&mut self,
+                 nodes: &BTreeMap<u16, SimServer>)
+                 -> Result<(), String> {
+        try!(self.check_election_safety(nodes));
+        try!(self.check_commit_monotonicity(nodes));
+        self.check_log_matching(nodes)
+    }
+
+    fn check_election_safety(&mut self,
+                             nodes: &BTreeMap<u16, SimServer>)
+                             -> Result<(), String> {
+        for (id, n) in nodes.iter() {
+            if !n.server.state.is_leader() {
+                continue;
+            }
+            let term = n.server.state.term().unwrap();
+            let leader = *self.leaders.entry(term).or_insert(*id);
+            if leader != *id {
+                return Err(format!("election safety: nodes {} and {} were both \
+                                    leader in term {}",
+                                   leader, id, term));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_commit_monotonicity(&mut self,
+                                 nodes: &BTreeMap<u16, SimServer>)
+                                 -> Result<(), String> {
+        for (id, n) in nodes.iter() {
+            let log = &n.server.rep_log;
+            let last = log.last_learned_txid();
+            let prev = *self.last_learned.get(id).unwrap_or(&0);
+            if last < prev {
+                return Err(format!("commit monotonicity: node {} went from \
+                                    learned txid {} back to {}",
+                                   id, prev, last));
+            }
+            for txid in prev + 1..last + 1 {
+                let entry = match log.get(txid) {
+                    Some(entry) => entry,
+                    // txids are not dense, since a new leader skips ahead
+                    None => continue,
+                };
+                match self.learned.get(&txid) {
+                    Some(other) if *other != entry => {
+                        return Err(format!("commit monotonicity: node {} \
+                                            learned a different entry for \
+                                            txid {}",
+                                           id, txid));
+                    }
+                    _ => (),
+                }
+                self.learned.insert(txid, entry);
+            }
+            self.last_learned.insert(*id, last);
+        }
+        Ok(())
+    }
+
+    fn check_log_matching(&self,
+                          nodes: &BTreeMap<u16, SimServer>)
+                          -> Result<(), String> {
+        let ids: Vec<&u16> = nodes.keys().collect();
+        for (i, a) in ids.iter().enumerate() {
+            for b in ids[i + 1..].iter() {
+                let log_a = &nodes[*a].server.rep_log;
+                let log_b = &nodes[*b].server.rep_log;
+                let upto = cmp::min(log_a.last_accepted_txid(),
+                                    log_b.last_accepted_txid());
+
+                // Walk back from the end of the shorter log.  Once we find
+                // an entry both logs agree on, everything before it must
+                // match as well.
+                let collected = cmp::max(log_a.collected_before(),
+                                         log_b.collected_before());
+                let mut matched = false;
+                for txid in (1..upto + 1).rev() {
+                    match (log_a.get(txid), log_b.get(txid)) {
+                        (Some(ea), Some(eb)) => {
+                            let same_term = ea.get_version().get_term() ==
+                                            eb.get_version().get_term();
+                            if matched && ea != eb {
+                                return Err(format!("log matching: nodes {} \
+                                                    and {} diverge at txid \
+                                                    {} before a matching \
+                                                    entry",
+                                                   a, b, txid));
+                            }
+                            if same_term && ea != eb {
+                                let term = ea.get_version().get_term();
+                                return Err(format!("log matching: nodes {} \
+                                                    and {} have different \
+                                                    entries for txid {} in \
+                                                    term {}",
+                                                   a, b, txid, term));
+                            }
+                            matched = matched || same_term;
+                        }
+                        (None, None) => (),
+                        // one side has collected its log this far back
+                        _ if txid < collected => break,
+                        _ if matched => {
+                            return Err(format!("log matching: only one of \
+                                                nodes {} and {} has txid {}",
+                                               a, b, txid));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 extern crate rasputin;
 
 mod cluster;
+mod conformance;
+mod invariants;
 mod test_paxos;
 mod test_client;
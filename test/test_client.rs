@@ -1,29 +1,45 @@
 extern crate log;
-use std::sync::mpsc::SendError;
+extern crate libc;
+use std::ffi::CString;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::thread;
 use std::process;
+use std::ptr;
+use std::slice;
+use std::sync::mpsc::channel;
+use std::time::Duration;
 
-use rasputin::Client;
-use rasputin::server::Server;
+use rasputin::{CliReq, Client, ExportReq, GetReq};
+use rasputin::client::{AsyncClient, BulkWriter, Chunker, Ordered, TypedClient,
+                       Utf8};
+use rasputin::server::{ServerBuilder, ServerConfig, ServerHandle,
+                       shutdown_on_signals};
+use rasputin::ffi;
 use rasputin::logging;
 use rasputin::server::{Envelope, LEADER_DURATION, PEER_BROADCAST, State};
-use rasputin::RealClock;
 use cluster::{SimCluster, SimServer};
 use self::log::LogLevel;
+use self::libc::funcs::posix88::signal::kill;
+use self::libc::{c_int, c_void, size_t};
 
 #[test]
 fn client() {
     //logging::init_logger(None, LogLevel::Info).unwrap();
     
-    thread::spawn( move || {
-        Server::<RealClock, Result<(), SendError<Envelope>>>::run(
-            29999,
-            39999,
-            "_test_client".to_string(),
-            vec!["127.0.0.1:29999".to_string()]
-        );
-    });
-    
+    let server = ServerBuilder::new()
+                     .peer_port(29999)
+                     .cli_port(39999)
+                     .admin_port(49999)
+                     .resp_port(59999)
+                     .rest_port(19999)
+                     .memcached_port(18999)
+                     .storage_dir("_test_client")
+                     .peers(vec!["127.0.0.1:29999".to_string()])
+                     .start()
+                     .unwrap();
+
     thread::sleep_ms(1000);
     let peers = vec!["127.0.0.1:39999".parse().unwrap()];
     let nthreads = 1;
@@ -36,4 +52,517 @@ fn client() {
     assert!(cli.cas(b"k1", b"v12", b"v13").unwrap().get_value() == b"v13");
     assert!(cli.del(b"k1").unwrap().get_value() == b"v13");
     assert!(cli.get(b"k1").unwrap().get_success() == false);
+    assert!(cli.create(b"k1", b"v1").unwrap().get_success());
+    assert!(cli.create(b"k1", b"v2").unwrap().get_success() == false);
+    assert!(cli.del(b"k1").unwrap().get_value() == b"v1");
+
+    // a transaction whose read is overwritten under it runs again
+    cli.set(b"balance", b"10").unwrap();
+    let mut other = Client::new(vec!["127.0.0.1:39999".parse().unwrap()], 1);
+    let mut runs = 0;
+    let paid = cli.transact(|txn| {
+                      runs += 1;
+                      let balance = try!(txn.get(b"balance")).unwrap();
+                      if runs == 1 {
+                          other.set(b"balance", b"20").unwrap();
+                      }
+                      txn.set(b"balance", b"0");
+                      txn.set(b"paid", &balance);
+                      Ok(balance)
+                  })
+                  .unwrap();
+    assert_eq!((runs, paid), (2, b"20".to_vec()));
+    assert!(cli.get(b"balance").unwrap().get_value() == b"0");
+    assert!(cli.get(b"paid").unwrap().get_value() == b"20");
+
+    // reads through a snapshot agree however much is written meanwhile
+    cli.set(b"snap/a", b"1").unwrap();
+    cli.set(b"snap/b", b"1").unwrap();
+    {
+        let mut snapshot = cli.snapshot().unwrap();
+        other.set(b"snap/a", b"2").unwrap();
+        other.del(b"snap/b").unwrap();
+        assert!(snapshot.get(b"snap/a").unwrap().get_value() == b"1");
+        assert!(snapshot.get(b"snap/b").unwrap().get_value() == b"1");
+        let mut export = snapshot.export(b"snap/", Some(b"snap0")).unwrap();
+        assert_eq!(export.txid(), snapshot.txid());
+        let batch = export.next().unwrap().unwrap();
+        let keys: Vec<&[u8]> =
+            batch.iter().map(|record| record.get_key()).collect();
+        assert_eq!(keys, vec![&b"snap/a"[..], b"snap/b"]);
+    }
+    assert!(cli.get(b"snap/a").unwrap().get_value() == b"2");
+
+    // a scan follows its cursor from page to page, as of where it started
+    for i in 0..7 {
+        cli.set(format!("scan/{}", i).as_bytes(), b"v").unwrap();
+    }
+    {
+        let mut scan = ExportReq::new();
+        scan.set_start(b"scan/".to_vec());
+        scan.set_end(b"scan0".to_vec());
+        scan.set_limit(3);
+        let mut iter = cli.scan_iter_with(scan).unwrap();
+        let first = iter.next().unwrap().unwrap();
+        assert!(first.get_key() == b"scan/0");
+        other.del(b"scan/5").unwrap();
+        let rest: Vec<Vec<u8>> =
+            iter.map(|record| record.unwrap().get_key().to_vec())
+                .collect();
+        assert_eq!(rest.len(), 6);
+        assert!(rest[4] == b"scan/5");
+    }
+    let now: Vec<_> =
+        cli.scan_iter(b"scan/", Some(b"scan0")).unwrap().collect();
+    assert_eq!(now.len(), 6);
+    let count = cli.count(b"scan/", Some(b"scan0")).unwrap();
+    assert_eq!((count.get_keys(), count.get_exact()), (6, true));
+    assert!(count.get_bytes() > 6 * 7);
+
+    let mut bulk = BulkWriter::new(vec!["127.0.0.1:39999".parse().unwrap()]);
+    bulk.set_chunk_size(16);
+    bulk.set_max_in_flight(3);
+    for i in (0..100).rev() {
+        bulk.add(format!("bulk{}", i).as_bytes(), format!("v{}", i).as_bytes());
+    }
+    assert!(bulk.flush().unwrap() == 100);
+    assert!(bulk.len() == 0);
+    assert!(cli.get(b"bulk0").unwrap().get_value() == b"v0");
+    assert!(cli.get(b"bulk99").unwrap().get_value() == b"v99");
+
+    // values over the limit are refused, unless they're chunked
+    let big: Vec<u8> = (0..(5 << 19)).map(|i| i as u8).collect();
+    assert!(cli.set(b"big", &big).is_err());
+    assert!(cli.set(&[b'k'; 11 << 10], b"v").is_err());
+    {
+        let mut chunker = Chunker::new(&mut cli, 1 << 20);
+        chunker.set(b"big", &big).unwrap();
+        assert!(chunker.get(b"big").unwrap() == Some(big.clone()));
+        chunker.set(b"big", &big[..10]).unwrap();
+        assert!(chunker.get(b"big").unwrap() == Some(big[..10].to_vec()));
+        chunker.set(b"big", &big).unwrap();
+        chunker.del(b"big").unwrap();
+        assert!(chunker.get(b"big").unwrap() == None);
+    }
+
+    // typed keys keep their order, so a prefix exports just its keys
+    let leader = "127.0.0.1:39999".parse().unwrap();
+    let mut typed =
+        TypedClient::new(Client::new(vec![leader], 1), Ordered, Utf8);
+    let keys = [("ann", 2u64), ("ann", 10), ("bob", 1), ("ann", 1)];
+    for &(user, seq) in keys.iter() {
+        typed.set(&(user.to_string(), seq), &format!("{}{}", user, seq))
+             .unwrap();
+    }
+    assert_eq!(typed.get(&("ann".to_string(), 10)).unwrap(),
+               Some("ann10".to_string()));
+    assert_eq!(typed.get(&("ann".to_string(), 3)).unwrap(), None);
+    let mut seen = vec![];
+    {
+        let mut export = typed.export_prefix(&"ann".to_string()).unwrap();
+        while let Some(batch) = export.next().unwrap() {
+            seen.extend(batch.into_iter().map(|((_, seq), _)| seq));
+        }
+    }
+    assert_eq!(seen, vec![1, 2, 10]);
+    assert!(typed.cas(&("bob".to_string(), 1),
+                      &"bob1".to_string(),
+                      &"b".to_string())
+                 .unwrap());
+    typed.del(&("bob".to_string(), 1)).unwrap();
+    assert_eq!(typed.get(&("bob".to_string(), 1)).unwrap(), None);
+
+    let mut redis = TcpStream::connect("127.0.0.1:59999").unwrap();
+    let redis = &mut redis;
+    resp(redis, "DEL rk rk2 rk3 \x7fa \x7fb\r\n", ":");
+    resp(redis, "SET rk v\r\n", "+OK\r\n");
+    resp(redis, "*2\r\n$3\r\nGET\r\n$2\r\nrk\r\n", "$1\r\nv\r\n");
+    resp(redis, "SET rk w NX\r\n", "$-1\r\n");
+    resp(redis, "SET rk2 1 NX\r\n", "+OK\r\n");
+    resp(redis,
+         "INCR rk2\r\nINCRBY rk2 10\r\nDECR rk2\r\n",
+         ":2\r\n:12\r\n:11\r\n");
+    resp(redis,
+         "INCR rk\r\n",
+         "-ERR value is not an integer or out of range\r\n");
+    resp(redis, "EXISTS rk rk2 nope\r\n", ":2\r\n");
+    resp(redis, "EXPIRE rk 100\r\nTTL rk\r\n", ":1\r\n:100\r\n");
+    resp(redis, "PERSIST rk\r\nTTL rk\r\n", ":1\r\n:-1\r\n");
+    resp(redis, "SET rk3 x PX 50\r\n", "+OK\r\n");
+    thread::sleep_ms(100);
+    resp(redis, "GET rk3\r\nTTL rk3\r\n", "$-1\r\n:-2\r\n");
+    resp(redis, "MULTI\r\nSET rk3 1\r\nINCR rk3\r\nEXEC\r\n",
+         "+OK\r\n+QUEUED\r\n+QUEUED\r\n*2\r\n+OK\r\n:2\r\n");
+    resp(redis, "DEL rk rk2 nope\r\n", ":2\r\n");
+    resp(redis, "SET \x7fa 1\r\nSET \x7fb 2\r\n", "+OK\r\n+OK\r\n");
+    resp(redis,
+         "SCAN 7f COUNT 1\r\n",
+         "*2\r\n$4\r\n7f62\r\n*1\r\n$2\r\n\x7fa\r\n");
+    resp(redis,
+         "SCAN 7f62 MATCH \x7f?\r\n",
+         "*2\r\n$1\r\n0\r\n*1\r\n$2\r\n\x7fb\r\n");
+    resp(redis, "NOPE\r\nQUIT\r\n", "-ERR unknown command 'nope'\r\n+OK\r\n");
+
+    let mut memcached = TcpStream::connect("127.0.0.1:18999").unwrap();
+    let memcached = &mut memcached;
+    resp(memcached, "flush_all\r\n", "OK\r\n");
+    resp(memcached, "set mk 7 0 2\r\nhi\r\n", "STORED\r\n");
+    resp(memcached, "add mk 0 0 1\r\nx\r\n", "NOT_STORED\r\n");
+    resp(memcached, "replace nope 0 0 1\r\nx\r\n", "NOT_STORED\r\n");
+    resp(memcached, "append mk 0 0 1\r\n!\r\n", "STORED\r\n");
+    resp(memcached, "get mk nope\r\n", "VALUE mk 7 3\r\nhi!\r\nEND\r\n");
+    resp(memcached,
+         "set n 0 0 1 noreply\r\n5\r\nincr n 10\r\ndecr n 100\r\n",
+         "15\r\n0\r\n");
+    resp(memcached,
+         "incr mk 1\r\n",
+         "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n");
+    resp(memcached, "set gone 0 0 1\r\nx\r\ntouch gone -1\r\nget gone\r\n",
+         "STORED\r\nTOUCHED\r\nEND\r\n");
+    resp(memcached, "delete mk\r\ndelete mk\r\n", "DELETED\r\nNOT_FOUND\r\n");
+    // kept apart from everything else's keys
+    assert!(cli.get(b"memcached/n").unwrap().get_value() ==
+            b"\x00\x00\x00\x000");
+    resp(memcached, "bogus\r\n", "ERROR\r\n");
+
+    let put = rest("PUT /v1/collections/default/keys/rest%2F1",
+                   "",
+                   "{\"value\": \"v1\"}");
+    assert!(put.starts_with("HTTP/1.1 200 OK"));
+    assert!(put.contains("\"txid\""));
+    rest("PUT /v1/collections/default/keys/rest%2F2",
+         "",
+         "{\"value_base64\": \"/w==\"}");
+    let get = rest("GET /v1/collections/default/keys/rest%2F1", "", "");
+    assert!(get.contains("\"key\": \"rest/1\""));
+    assert!(get.contains("\"value\": \"v1\""));
+    let stale = rest("GET /v1/collections/default/keys/rest%2F1",
+                     "Rasputin-Consistency: stale\r\n",
+                     "");
+    assert!(stale.contains("\"value\": \"v1\""));
+    let scan = rest("GET /v1/collections/default/keys?prefix=rest%2F&limit=1",
+                    "",
+                    "");
+    assert!(scan.contains("\"key\": \"rest/1\""));
+    assert!(scan.contains("\"next\": \"rest%2F2\""));
+    let scan =
+        rest("GET /v1/collections/default/keys?prefix=rest%2F&start=rest%2F2",
+             "",
+             "");
+    assert!(scan.contains("\"value_base64\": \"/w==\""));
+    assert!(!scan.contains("\"next\""));
+    assert!(rest("DELETE /v1/collections/default/keys/rest%2F1", "", "")
+                .starts_with("HTTP/1.1 200 OK"));
+    assert!(rest("GET /v1/collections/default/keys/rest%2F1", "", "")
+                .starts_with("HTTP/1.1 404 Not Found"));
+    assert!(rest("GET /v1/collections/nope/keys/k", "", "")
+                .starts_with("HTTP/1.1 404"));
+    assert!(rest("PUT /v1/collections/default/keys/k", "", "nope")
+                .starts_with("HTTP/1.1 400"));
+
+    let metrics = admin_get("/metrics");
+    assert!(metrics.starts_with("HTTP/1.0 200 OK"));
+    assert!(metrics.contains("rasputin_client_requests_total{op=\"set\"}"));
+    assert!(metrics.contains("rasputin_ranges 1"));
+    let status = admin_get("/status");
+    assert!(status.contains("\"role\": \"leader\""));
+    assert!(status.contains("\"meta\": null"));
+    assert!(status.contains("\"members\""));
+    let storage = admin_get("/storage");
+    assert!(storage.contains("\"default\""));
+    assert!(storage.contains("\"write_amplification\""));
+    assert!(!storage.contains("\"applied_bytes\": 0,"));
+    let stats = admin_get("/stats");
+    assert!(stats.contains("\"live_bytes\""));
+    assert!(stats.contains("\"write_rate\""));
+    assert!(!stats.contains("\"keys\": 0,"));
+    let info = admin_get("/info");
+    assert!(info.contains("\"cluster_id\":\"rasputin\""));
+    assert!(info.contains("\"status\":\"serving\""));
+    assert!(info.contains("\"client\":\"[::]:39999\""));
+    assert!(admin_get("/nope").starts_with("HTTP/1.0 404"));
+
+    let reload = cli.reload_config("shed_p99_ms = 5000\nread_workers = 1")
+                    .unwrap();
+    assert!(reload.get_success());
+    assert_eq!(reload.get_changed(), ["shed_p99_ms"]);
+    assert_eq!(reload.get_needs_restart(), ["read_workers"]);
+    assert!(admin_get("/config").contains("\"shed_p99_ms\": 5000"));
+    assert!(!cli.reload_config("shed_p99_ms = \"soon\"")
+                .unwrap()
+                .get_success());
+
+    // a SIGTERM shuts the server down cleanly
+    shutdown_on_signals(server.shutdown_handle(), None).unwrap();
+    unsafe { kill(libc::getpid(), libc::SIGTERM) };
+    server.join().unwrap();
+}
+
+// The C interface, called as C would.
+#[test]
+fn ffi() {
+    let server = lone_server(29994, 39994, "_test_ffi");
+    unsafe {
+        let servers = CString::new("127.0.0.1:39994").unwrap();
+        let c = ffi::rasputin_open(servers.as_ptr());
+        assert!(!c.is_null());
+        let pairs =
+            [(&b"ffi1"[..], &b"a"[..]), (b"ffi2", b"b"), (b"ffi3", b"c")];
+        for &(k, v) in pairs.iter() {
+            assert_eq!(ffi::rasputin_put(c,
+                                         k.as_ptr(),
+                                         k.len() as size_t,
+                                         v.as_ptr(),
+                                         v.len() as size_t),
+                       ffi::RASPUTIN_OK);
+        }
+        let (mut value, mut value_len) = (ptr::null_mut(), 0);
+        assert_eq!(ffi::rasputin_get(c,
+                                     b"ffi2".as_ptr(),
+                                     4,
+                                     &mut value,
+                                     &mut value_len),
+                   ffi::RASPUTIN_OK);
+        assert_eq!(slice::from_raw_parts(value, value_len as usize), b"b");
+        ffi::rasputin_free(value, value_len);
+        assert_eq!(ffi::rasputin_delete(c, b"ffi2".as_ptr(), 4),
+                   ffi::RASPUTIN_OK);
+        assert_eq!(ffi::rasputin_get(c,
+                                     b"ffi2".as_ptr(),
+                                     4,
+                                     &mut value,
+                                     &mut value_len),
+                   ffi::RASPUTIN_NOT_FOUND);
+        let mut scanned: Vec<Vec<u8>> = vec![];
+        assert_eq!(ffi::rasputin_scan(c,
+                                      b"ffi".as_ptr(),
+                                      3,
+                                      b"ffj".as_ptr(),
+                                      3,
+                                      Some(scanned_key),
+                                      &mut scanned as *mut Vec<Vec<u8>> as
+                                      *mut c_void),
+                   ffi::RASPUTIN_OK);
+        assert_eq!(scanned, vec![b"ffi1".to_vec(), b"ffi3".to_vec()]);
+        ffi::rasputin_close(c);
+    }
+
+    server.shutdown();
+    server.join().unwrap();
+    let _ = fs::remove_dir_all("_test_ffi");
+}
+
+extern "C" fn scanned_key(ctx: *mut c_void,
+                          key: *const u8,
+                          key_len: size_t,
+                          _: *const u8,
+                          _: size_t)
+                          -> c_int {
+    let scanned = unsafe { &mut *(ctx as *mut Vec<Vec<u8>>) };
+    let key = unsafe { slice::from_raw_parts(key, key_len as usize) };
+    scanned.push(key.to_vec());
+    0
+}
+
+// The async client keeps many requests in flight on one connection.
+#[test]
+fn async_client() {
+    let server = lone_server(29996, 39996, "_test_async_client");
+    let async_cli = AsyncClient::new(vec!["127.0.0.1:39996".parse().unwrap()]);
+    let sets: Vec<_> = (0..50)
+                           .map(|i| {
+                               let key = format!("async{}", i);
+                               async_cli.set(key.as_bytes(), b"v")
+                           })
+                           .collect();
+    for set in sets {
+        assert!(set.wait().unwrap().get_success());
+    }
+    let (tx, rx) = channel();
+    for i in 0..50 {
+        let tx = tx.clone();
+        let key = format!("async{}", i);
+        async_cli.send(get_of(key.as_bytes()), move |res| {
+            tx.send(res.unwrap().get_get().get_value().to_vec()).unwrap();
+        });
+    }
+    for _ in 0..50 {
+        assert_eq!(rx.recv().unwrap(), b"v");
+    }
+    let mut cas = async_cli.cas(b"async0", b"v", b"v2");
+    let cas = loop {
+        match cas.poll() {
+            Some(res) => break res,
+            None => thread::sleep_ms(1),
+        }
+    };
+    assert!(cas.unwrap().get_success());
+    assert!(async_cli.del(b"async0").wait().unwrap().get_value() == b"v2");
+    assert!(!async_cli.get(b"async0").wait().unwrap().get_success());
+
+    server.shutdown();
+    server.join().unwrap();
+    let _ = fs::remove_dir_all("_test_async_client");
+}
+
+// Clients may share connections, and spread stale reads over replicas.
+#[test]
+fn shared_connections() {
+    let server = lone_server(29995, 39995, "_test_shared_connections");
+    let leader = "127.0.0.1:39995".parse().unwrap();
+    let dead = "127.0.0.1:1".parse().unwrap();
+    let mut cli = Client::new(vec![leader], 1);
+    cli.set(b"stale1", b"v").unwrap();
+    cli.set(b"stale2", b"v").unwrap();
+    let mut shared = Client::with_connections(vec![leader],
+                                              1,
+                                              cli.connections());
+    shared.set_replicas(vec![dead, leader]);
+    assert!(shared.get_stale(b"stale1").unwrap().get_value() == b"v");
+    assert!(shared.get_stale(b"stale2").unwrap().get_value() == b"v");
+    assert!(!cli.connections().healthy(&dead));
+    assert_eq!(cli.connections().connected(), 1);
+
+    server.shutdown();
+    server.join().unwrap();
+    let _ = fs::remove_dir_all("_test_shared_connections");
+}
+
+#[test]
+fn embedded() {
+    let _ = fs::remove_dir_all("_test_embedded");
+    let db = ServerBuilder::new()
+                 .peer_port(29998)
+                 .storage_dir("_test_embedded")
+                 .embed()
+                 .unwrap();
+    assert!(db.status().role == "leader");
+    db.set(b"k1", b"v1").unwrap();
+    assert!(db.get(b"k1").unwrap().get_value() == b"v1");
+    assert!(db.cas(b"k1", b"v1", b"v2").unwrap().get_success());
+    assert!(db.cas(b"k1", b"v1", b"v3").unwrap().get_success() == false);
+    assert!(db.del(b"k1").unwrap().get_value() == b"v2");
+    assert!(db.get(b"k1").unwrap().get_success() == false);
+
+    for i in 0..10 {
+        db.set(format!("range{}", i).as_bytes(), b"v").unwrap();
+    }
+    db.delete_range(b"range0", b"range5").unwrap();
+    assert!(db.get(b"range4").unwrap().get_success() == false);
+    assert!(db.get(b"range5").unwrap().get_success());
+
+    // anything else can be asked of it directly
+    assert!(db.call(get_of(b"range9")).unwrap().get_get().get_success());
+}
+
+#[test]
+fn io_loops() {
+    let _ = fs::remove_dir_all("_test_io_loops");
+    let mut config = ServerConfig::default();
+    config.io_threads = 3;
+    config.max_client_conns = 10;
+    let server = ServerBuilder::new()
+                     .peer_port(29997)
+                     .cli_port(39997)
+                     .storage_dir("_test_io_loops")
+                     .peers(vec!["127.0.0.1:29997".to_string()])
+                     .config(config)
+                     .start()
+                     .unwrap();
+    thread::sleep_ms(1000);
+
+    // connections land on whichever loop, and are answered from it
+    let mut clients = vec![];
+    for i in 0..8 {
+        let mut cli = Client::new(vec!["127.0.0.1:39997".parse().unwrap()], 1);
+        let key = format!("loop{}", i);
+        cli.set(key.as_bytes(), key.as_bytes()).unwrap();
+        clients.push(cli);
+    }
+    for (i, cli) in clients.iter_mut().enumerate() {
+        let key = format!("loop{}", (i + 1) % 8);
+        assert!(cli.get(key.as_bytes()).unwrap().get_value() == key.as_bytes());
+    }
+
+    // and the connection limit is for all of them together
+    let mut refused = 0;
+    let mut extra = vec![];
+    for _ in 0..4 {
+        let mut sock = TcpStream::connect("127.0.0.1:39997").unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let mut said = vec![];
+        let _ = sock.read_to_end(&mut said);
+        if String::from_utf8_lossy(&said).contains("client connections") {
+            refused += 1;
+        }
+        extra.push(sock);
+    }
+    assert_eq!(refused, 2);
+
+    server.shutdown();
+    server.join().unwrap();
+    let _ = fs::remove_dir_all("_test_io_loops");
+}
+
+// A node of its own for a test, taking clients on cli_port.
+fn lone_server(peer_port: u16, cli_port: u16, dir: &str) -> ServerHandle {
+    let _ = fs::remove_dir_all(dir);
+    let server = ServerBuilder::new()
+                     .peer_port(peer_port)
+                     .cli_port(cli_port)
+                     .storage_dir(dir)
+                     .peers(vec![format!("127.0.0.1:{}", peer_port)])
+                     .start()
+                     .unwrap();
+    thread::sleep_ms(1000);
+    // the blocking client retries until the node is leading
+    let addr = format!("127.0.0.1:{}", cli_port).parse().unwrap();
+    Client::new(vec![addr], 1).get(b"ready").unwrap();
+    server
+}
+
+fn get_of(key: &[u8]) -> CliReq {
+    let mut get = GetReq::new();
+    get.set_key(key.to_vec());
+    let mut req = CliReq::new();
+    req.set_get(get);
+    req
+}
+
+// Sends req to a frontend, and checks that the reply starts with
+// expected.
+fn resp(stream: &mut TcpStream, req: &str, expected: &str) {
+    stream.write_all(req.as_bytes()).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+    // and whatever's left of the line
+    let mut b = [0];
+    while !reply.ends_with(b"\n") {
+        stream.read_exact(&mut b).unwrap();
+        reply.push(b[0]);
+    }
+}
+
+// Makes a request of the REST listener, returning the whole response.
+fn rest(req: &str, headers: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect("127.0.0.1:19999").unwrap();
+    write!(stream,
+           "{} HTTP/1.1\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           req,
+           headers,
+           body.len(),
+           body)
+        .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    res
+}
+
+fn admin_get(path: &str) -> String {
+    let mut stream = TcpStream::connect("127.0.0.1:49999").unwrap();
+    write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    res
 }
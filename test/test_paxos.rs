@@ -1,11 +1,26 @@
 extern crate log;
 extern crate quickcheck;
+extern crate protobuf;
+extern crate bytes;
+extern crate rocksdb;
 
 use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::sync::{Arc, Mutex};
 
-use rasputin::server::Server;
-use rasputin::logging;
-use cluster::{SimCluster, SimServer};
+use rasputin::server::{CREDENTIALS_PREFIX, DiskSpace, Envelope, InMemoryLog,
+                       Server, ServerMsg, Span, State, StorageStats, TraceSink,
+                       convert_for_ingest, mirrored_txid, read_data,
+                       read_manifest, set_trace_sink};
+use rasputin::{AuthReq, BackupReq, CASReq, CliReq, CliRes, Clock, DelReq,
+               DeleteRangeReq, ErrorCode, GetReq, IngestReq, Mutation,
+               MutationType, PromoteReq, PutSequentialReq, SetReq, StageReq,
+               TraceContext, TransferLeaderReq, TxnKey, TxnReq, UpdateOp,
+               UpdateReq, Version, WatchReq, logging};
+use self::bytes::Buf;
+use self::rocksdb::Writable;
+use self::protobuf::{Message, RepeatedField};
+use cluster::{SimCluster, SimNetwork, SimServer, sim_logs};
 use self::log::LogLevel;
 
 /*
@@ -65,6 +80,1624 @@ fn stable_leader_with_no_faults() {
     }
 }
 
+#[test]
+fn leader_transfer() {
+    let mut sim = SimCluster::new("transfer", 5);
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    // give the followers a chance to vote in the new leader
+    for _ in 0..50 {
+        sim.step();
+    }
+    let old = sim.leaders()[0];
+    let target = (old + 1) % 5;
+    let target_id = sim.nodes.get(&target).unwrap().server.id.clone();
+
+    let mut transfer = TransferLeaderReq::new();
+    transfer.set_target(target_id.clone());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_transfer_leader(transfer);
+    sim.client_request(old, &*req.write_to_bytes().unwrap());
+
+    let mut responses = vec![];
+    for _ in 0..200 {
+        sim.step();
+        responses.extend(sim.take_client_responses());
+        if sim.leaders() == vec![target] {
+            break;
+        }
+    }
+    assert_eq!(sim.leaders(), vec![target]);
+    assert_eq!(responses.len(), 1);
+    let res: CliRes =
+        protobuf::parse_from_bytes(responses[0].msg.bytes()).unwrap();
+    assert!(res.get_transfer_leader().get_success());
+    // the old leader follows the node it handed off to
+    match sim.nodes.get(&old).unwrap().server.state {
+        State::Follower{ref id, ..} => assert_eq!(*id, target_id),
+        ref state => panic!("old leader is {:?}", state),
+    }
+}
+
+#[test]
+fn drained_node_stops_leading() {
+    let mut sim = SimCluster::new("drain", 5);
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    let old = sim.leaders()[0];
+    sim.nodes.get_mut(&old).unwrap().server.handle(ServerMsg::Drain);
+
+    for _ in 0..2000 {
+        sim.step();
+        let server = &sim.nodes.get(&old).unwrap().server;
+        assert!(!server.state.is_leader() || server.transfer.is_some());
+        if server.transfer.is_none() {
+            assert!(!sim.leaders().contains(&old));
+        }
+    }
+    // someone else took over, and we're following them
+    assert_eq!(sim.leaders().len(), 1);
+    assert!(sim.nodes.get(&old).unwrap().server.state.term().is_some());
+}
+
+#[test]
+fn grouped_writes_report_their_own_txids() {
+    let mut sim = SimCluster::new("group_txids", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+
+    // with the leader cut off, the writes pile up unlearned, and are
+    // learned together once it's back
+    sim.isolate(leader);
+    for i in 0..5 {
+        let mut set = SetReq::new();
+        set.set_key(format!("k{}", i).into_bytes());
+        set.set_value(b"v".to_vec());
+        let mut req = CliReq::new();
+        req.set_req_id(i + 1);
+        req.set_set(set);
+        sim.client_request(leader, &*req.write_to_bytes().unwrap());
+    }
+    for _ in 0..5 {
+        sim.step();
+    }
+    assert!(sim.take_client_responses().is_empty());
+    sim.heal();
+
+    let mut groups = vec![];
+    for _ in 0..200 {
+        sim.step();
+        let responses = sim.take_client_responses();
+        if !responses.is_empty() {
+            groups.push(responses);
+        }
+    }
+    assert_eq!(groups.len(), 1);
+    let txid_of = |env: &Envelope| {
+        let res: CliRes = protobuf::parse_from_bytes(env.msg.bytes()).unwrap();
+        assert!(res.get_set().get_success());
+        (res.get_req_id(), res.get_set().get_txid())
+    };
+    let mut txids: Vec<(u64, u64)> = groups[0].iter().map(txid_of).collect();
+    txids.sort();
+    assert_eq!(txids.len(), 5);
+    for pair in txids.windows(2) {
+        assert!(pair[0].1 < pair[1].1, "txids {:?}", txids);
+    }
+}
+
+#[test]
+fn del_fails_when_the_old_value_cant_be_read() {
+    let mut sim = SimCluster::new("del_unreadable", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    for (_, node) in sim.nodes.iter() {
+        node.server.db.put(b"k", b"rotten").unwrap();
+    }
+
+    let mut del = DelReq::new();
+    del.set_key(b"k".to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_del(del);
+    sim.client_request(leader, &*req.write_to_bytes().unwrap());
+
+    let mut responses = vec![];
+    for _ in 0..200 {
+        sim.step();
+        responses.extend(sim.take_client_responses());
+    }
+    assert_eq!(responses.len(), 1);
+    let res: CliRes =
+        protobuf::parse_from_bytes(responses[0].msg.bytes()).unwrap();
+    assert!(!res.get_del().get_success());
+    assert!(res.get_del().get_err().contains("checksum"),
+            "{}",
+            res.get_del().get_err());
+    // nothing was deleted behind the failure
+    for (_, node) in sim.nodes.iter() {
+        assert!(node.server.db.get(b"k").is_some());
+    }
+}
+
+#[test]
+fn log_is_kept_for_a_follower_that_fell_behind() {
+    let mut sim = SimCluster::new("retain_for_follower", 3);
+    for (_, node) in sim.nodes.iter_mut() {
+        node.server.config.log_retention = 0;
+    }
+    let leader = await_leader(&mut sim, &[], 0);
+    let behind = (leader + 1) % 3;
+
+    sim.isolate(behind);
+    for i in 0..20 {
+        let mut set = SetReq::new();
+        set.set_key(format!("k{}", i).into_bytes());
+        set.set_value(b"v".to_vec());
+        let mut req = CliReq::new();
+        req.set_req_id(i + 1);
+        req.set_set(set);
+        sim.client_request(leader, &*req.write_to_bytes().unwrap());
+    }
+    for _ in 0..2000 {
+        sim.step();
+    }
+    assert_eq!(sim.take_client_responses().len(), 20);
+
+    // nobody who could lead has collected what it hasn't accepted yet
+    let accepted = sim.nodes[&behind].server.rep_log.last_accepted_txid();
+    for (id, node) in sim.nodes.iter() {
+        if *id != behind {
+            assert!(node.server.rep_log.collected_before() <= accepted + 1,
+                    "{} collected before {} but {} only accepted {}",
+                    id,
+                    node.server.rep_log.collected_before(),
+                    behind,
+                    accepted);
+        }
+    }
+
+    sim.heal();
+    for _ in 0..2000 {
+        sim.step();
+    }
+    assert!(sim.nodes[&behind].server.db.get(b"k19").is_some());
+
+    // once it's caught up, the log goes, and the followers hear so with
+    // the next write
+    let leader = sim.leaders()[0];
+    let mut set = SetReq::new();
+    set.set_key(b"last".to_vec());
+    set.set_value(b"v".to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(21);
+    req.set_set(set);
+    sim.client_request(leader, &*req.write_to_bytes().unwrap());
+    for _ in 0..2000 {
+        sim.step();
+    }
+    let learned = sim.nodes[&leader].server.rep_log.last_learned_txid();
+    for (id, node) in sim.nodes.iter() {
+        assert!(node.server.rep_log.collected_before() > accepted + 1,
+                "{} still has the log from {}",
+                id,
+                node.server.rep_log.collected_before());
+        assert_eq!(node.server.rep_log.last_learned_txid(), learned);
+    }
+}
+
+#[test]
+fn witness_never_leads_or_stores() {
+    let mut sim = SimCluster::new("witness", 3);
+    {
+        let witness = &mut sim.nodes.get_mut(&0).unwrap().server;
+        witness.config.witness = true;
+        witness.membership.set_witness(true);
+    }
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    let leader = sim.leaders()[0];
+    assert!(leader != 0);
+
+    let mut set = SetReq::new();
+    set.set_key(b"k".to_vec());
+    set.set_value(b"v".to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_set(set);
+    sim.client_request(leader, &*req.write_to_bytes().unwrap());
+
+    let mut responses = vec![];
+    for _ in 0..200 {
+        sim.step();
+        responses.extend(sim.take_client_responses());
+        assert!(!sim.nodes.get(&0).unwrap().server.state.is_leader());
+    }
+    assert_eq!(responses.len(), 1);
+    let res: CliRes =
+        protobuf::parse_from_bytes(responses[0].msg.bytes()).unwrap();
+    assert!(res.get_set().get_success());
+
+    // the witness accepted the write, but only the data replicas keep it
+    let witness = &sim.nodes.get(&0).unwrap().server;
+    let leader = &sim.nodes.get(&leader).unwrap().server;
+    assert_eq!(witness.rep_log.last_accepted_txid(),
+               leader.rep_log.last_accepted_txid());
+    assert!(leader.db.get(b"k").is_some());
+    assert!(witness.db.get(b"k").is_none());
+}
+
+#[test]
+fn learner_replicates_without_voting() {
+    let mut logs = vec![];
+    for _ in 0..4 {
+        logs.push(InMemoryLog {
+            pending: BTreeMap::new(),
+            committed: BTreeMap::new(),
+            // three voters
+            quorum: 2,
+            last_learned_txid: 0,
+            last_learned_term: 0,
+            last_accepted_txid: 0,
+            last_accepted_term: 0,
+            collected_before: 0,
+        });
+    }
+    let mut sim = SimCluster::new_from_logs("learner", logs);
+    let voters: Vec<String> =
+        sim.nodes.get(&0).unwrap().server.peers[..3].to_vec();
+    for (port, node) in sim.nodes.iter_mut() {
+        if *port == 3 {
+            node.server.config.learner = true;
+        } else {
+            node.server.peers = voters.clone();
+        }
+    }
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    let leader = sim.leaders()[0];
+    assert!(leader != 3);
+
+    let mut set = SetReq::new();
+    set.set_key(b"k".to_vec());
+    set.set_value(b"v".to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_set(set);
+    sim.client_request(leader, &*req.write_to_bytes().unwrap());
+    for _ in 0..200 {
+        sim.step();
+        assert!(!sim.nodes.get(&3).unwrap().server.state.is_leader());
+    }
+    assert_eq!(sim.take_client_responses().len(), 1);
+
+    // the leader knows it as a learner, and it serves what it has learned
+    let leader_id = sim.nodes.get(&leader).unwrap().server.id.clone();
+    let learner = &sim.nodes.get(&3).unwrap().server;
+    match learner.state {
+        State::Follower{ref id, ..} => assert_eq!(*id, leader_id),
+        ref state => panic!("learner is {:?}", state),
+    }
+    assert!(sim.nodes
+               .get(&leader)
+               .unwrap()
+               .server
+               .rep_peers
+               .get(&learner.id)
+               .is_some());
+    assert!(learner.db.get(b"k").is_some());
+}
+
+#[test]
+fn standalone_node_leads_and_commits_alone() {
+    let mut sim = SimCluster::new("standalone", 1);
+    sim.nodes.get_mut(&0).unwrap().server.config.standalone = true;
+    // not even its own messages get through
+    sim.set_network(SimNetwork { drop_rate: 1.0, ..SimNetwork::default() });
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+
+    let res = call(&mut sim, 0, set_req(b"k", b"v"));
+    assert!(res.get_set().get_success());
+    assert!(sim.nodes.get(&0).unwrap().server.db.get(b"k").is_some());
+
+    // and it keeps its lease without anyone renewing it
+    let term = sim.nodes.get(&0).unwrap().server.state.term();
+    for _ in 0..200 {
+        sim.step();
+    }
+    assert_eq!(sim.leaders(), vec![0]);
+    assert_eq!(sim.nodes.get(&0).unwrap().server.state.term(), term);
+}
+
+#[test]
+fn clients_must_authenticate() {
+    let mut sim = SimCluster::new("auth", 3);
+    for (_, node) in sim.nodes.iter_mut() {
+        node.server.config.root_token = Some("hunter2".to_string());
+    }
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    let leader = sim.leaders()[0];
+
+    let mut alice_key = CREDENTIALS_PREFIX.to_vec();
+    alice_key.extend(b"alice-token");
+
+    // nothing is served before we authenticate
+    let res = call(&mut sim, leader, set_req(b"k", b"v"));
+    assert_eq!(res.get_error().get_err(), "unauthenticated");
+    let res = call(&mut sim, leader, auth_req("wrong"));
+    assert!(!res.get_auth().get_success());
+
+    // root may add credentials for others
+    let res = call(&mut sim, leader, auth_req("hunter2"));
+    assert!(res.get_auth().get_success());
+    assert_eq!(res.get_auth().get_principal(), "root");
+    let res = call(&mut sim, leader, set_req(&alice_key, b"alice"));
+    assert!(res.get_set().get_success());
+
+    // which are replicated, so any node can check them
+    let follower = sim.nodes
+                      .keys()
+                      .cloned()
+                      .filter(|n| *n != leader)
+                      .next()
+                      .unwrap();
+    let res = call(&mut sim, follower, auth_req("alice-token"));
+    assert_eq!(res.get_auth().get_principal(), "alice");
+    let res = call(&mut sim, leader, auth_req("alice-token"));
+    assert!(res.get_auth().get_success());
+    let res = call(&mut sim, leader, set_req(b"k", b"v"));
+    assert!(res.get_set().get_success());
+    // but only root may touch them
+    let res = call(&mut sim, leader, set_req(&alice_key, b"root"));
+    assert_eq!(res.get_error().get_err(), "permission denied");
+}
+
+#[test]
+fn namespaces_keep_tenants_apart() {
+    let mut sim = SimCluster::new("namespaces", 3);
+    for (_, node) in sim.nodes.iter_mut() {
+        node.server.config.root_token = Some("hunter2".to_string());
+    }
+    let leader = await_leader(&mut sim, &[], 0);
+    for _ in 0..50 {
+        sim.step();
+    }
+    assert!(call(&mut sim, leader, auth_req("hunter2"))
+                .get_auth()
+                .get_success());
+    for &(token, principal) in &[("alice-token", "alice"), ("bob-token", "bob"),
+                                 ("carol-token", "carol")] {
+        let mut key = CREDENTIALS_PREFIX.to_vec();
+        key.extend(token.as_bytes());
+        assert!(call(&mut sim, leader, set_req(&key, principal.as_bytes()))
+                    .get_set()
+                    .get_success());
+    }
+    let spec = b"{\"members\": {\"alice\": \"write\", \"bob\": \"read\"}, \
+                 \"max_bytes\": 100}";
+    assert!(call(&mut sim, leader, set_req(b"\x00namespaces/app", spec))
+                .get_set()
+                .get_success());
+
+    // members keep to their namespace, and only writers change it
+    assert!(call(&mut sim, leader, auth_req("alice-token"))
+                .get_auth()
+                .get_success());
+    assert!(call(&mut sim, leader, set_req(b"app/k", b"v"))
+                .get_set()
+                .get_success());
+    let res = call(&mut sim, leader, set_req(b"k", b"v"));
+    assert_eq!(res.get_error().get_err(), "permission denied");
+    assert!(call(&mut sim, leader, auth_req("bob-token"))
+                .get_auth()
+                .get_success());
+    assert_eq!(call(&mut sim, leader, get_req(b"app/k"))
+                   .get_get()
+                   .get_value(),
+               b"v");
+    let res = call(&mut sim, leader, set_req(b"app/k", b"w"));
+    assert_eq!(res.get_error().get_err(), "permission denied");
+
+    // and everyone else keeps out of it, and away from its definition
+    assert!(call(&mut sim, leader, auth_req("carol-token"))
+                .get_auth()
+                .get_success());
+    assert!(call(&mut sim, leader, set_req(b"k", b"v"))
+                .get_set()
+                .get_success());
+    let res = call(&mut sim, leader, get_req(b"app/k"));
+    assert_eq!(res.get_error().get_err(), "permission denied");
+    let res = call(&mut sim, leader, delete_range_req(b"a", b"b"));
+    assert_eq!(res.get_error().get_err(), "permission denied");
+    let res = call(&mut sim, leader, set_req(b"\x00namespaces/app", b"{}"));
+    assert_eq!(res.get_error().get_err(), "permission denied");
+
+    // a namespace holding its quota takes deletes but no more writes
+    assert!(call(&mut sim, leader, auth_req("alice-token"))
+                .get_auth()
+                .get_success());
+    assert!(call(&mut sim, leader, set_req(b"app/big", &[0; 200]))
+                .get_set()
+                .get_success());
+    let res = call(&mut sim, leader, set_req(b"app/k2", b"v"));
+    assert_eq!(res.get_error().get_code(),
+               ErrorCode::QUOTA_EXCEEDED,
+               "{:?}",
+               res);
+    assert!(call(&mut sim, leader, delete_range_req(b"app/big", b"app/big\x00"))
+                .get_delete_range()
+                .get_success());
+    assert!(call(&mut sim, leader, set_req(b"app/k2", b"v"))
+                .get_set()
+                .get_success());
+}
+
+#[test]
+fn scrub_finds_divergent_followers() {
+    let mut sim = SimCluster::new("scrub", 3);
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    let leader = sim.leaders()[0];
+    for i in 0..10 {
+        let key = format!("k{}", i);
+        let res = call(&mut sim, leader, set_req(key.as_bytes(), b"v"));
+        assert!(res.get_set().get_success());
+    }
+    // let the followers learn the last write too
+    for _ in 0..100 {
+        sim.step();
+    }
+
+    let followers: Vec<u16> =
+        sim.nodes.keys().cloned().filter(|n| *n != leader).collect();
+    sim.nodes
+       .get(&followers[0])
+       .unwrap()
+       .server
+       .db
+       .put(b"k7", b"rotten")
+       .unwrap();
+    sim.nodes.get_mut(&leader).unwrap().server.config.scrub_span_keys = 4;
+    sim.nodes.get_mut(&leader).unwrap().server.scrub();
+    for _ in 0..50 {
+        sim.step();
+    }
+
+    let leader = &sim.nodes.get(&leader).unwrap().server;
+    let bad = &sim.nodes.get(&followers[0]).unwrap().server.id;
+    let good = &sim.nodes.get(&followers[1]).unwrap().server.id;
+    // only the span holding k4..k7 differs
+    assert_eq!(leader.scrub_report.get(bad), Some(&vec![b"k4".to_vec()]));
+    assert_eq!(leader.scrub_report.get(good), Some(&vec![]));
+}
+
+#[test]
+fn repair_replaces_divergent_spans() {
+    let mut sim = SimCluster::new("repair", 3);
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    let leader = sim.leaders()[0];
+    for i in 0..10 {
+        let key = format!("k{}", i);
+        let res = call(&mut sim, leader, set_req(key.as_bytes(), b"v"));
+        assert!(res.get_set().get_success());
+    }
+    for _ in 0..100 {
+        sim.step();
+    }
+
+    let follower = sim.nodes
+                      .keys()
+                      .cloned()
+                      .filter(|n| *n != leader)
+                      .next()
+                      .unwrap();
+    {
+        let server = &mut sim.nodes.get_mut(&follower).unwrap().server;
+        server.config.scrub_repair = true;
+        server.db.put(b"k7", b"rotten").unwrap();
+        server.db.put(b"k77", b"stray").unwrap();
+        server.db.delete(b"k1").unwrap();
+    }
+    sim.nodes.get_mut(&leader).unwrap().server.config.scrub_span_keys = 4;
+    sim.nodes.get_mut(&leader).unwrap().server.scrub();
+    for _ in 0..100 {
+        sim.step();
+    }
+
+    {
+        let follower = &sim.nodes.get(&follower).unwrap().server;
+        let leader = &sim.nodes.get(&leader).unwrap().server;
+        for key in [&b"k1"[..], b"k7"].iter() {
+            assert_eq!(follower.db.get(key).unwrap().to_vec(),
+                       leader.db.get(key).unwrap().to_vec());
+        }
+        assert!(follower.db.get(b"k77").is_none());
+    }
+
+    // and the next scrub finds nothing
+    sim.nodes.get_mut(&leader).unwrap().server.scrub();
+    for _ in 0..50 {
+        sim.step();
+    }
+    let leader = &sim.nodes.get(&leader).unwrap().server;
+    assert!(leader.scrub_report.values().all(|divergent| divergent.is_empty()));
+}
+
+#[test]
+fn lossy_network_replays_from_seed() {
+    let network = SimNetwork {
+        min_delay_ms: 1,
+        max_delay_ms: 40,
+        drop_rate: 0.05,
+        reorder: true,
+    };
+    let mut traces = vec![];
+    for run in 0..2 {
+        let mut sim = SimCluster::with_seed(&format!("replay{}", run),
+                                            sim_logs(5),
+                                            7);
+        sim.set_network(network.clone());
+        let mut trace = vec![];
+        for _ in 0..2000 {
+            sim.step();
+            let terms: Vec<_> = sim.nodes
+                                   .values()
+                                   .map(|n| n.server.state.term())
+                                   .collect();
+            trace.push((sim.leaders(), terms));
+        }
+        // a leader got elected despite the losses, and can take writes
+        let leader = *sim.leaders()
+                         .first()
+                         .expect("no leader on a lossy network");
+        let res = call(&mut sim, leader, set_req(b"k", b"v"));
+        assert!(res.get_set().get_success());
+        traces.push(trace);
+    }
+    // and the same seed played out the same way both times
+    assert!(traces[0] == traces[1]);
+}
+
+#[test]
+fn storage_faults_reach_clients() {
+    let mut sim = SimCluster::new("storage_faults", 3);
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    let leader = sim.leaders()[0];
+    {
+        let faults = &mut sim.nodes.get_mut(&leader).unwrap().server.faults;
+        faults.failed_writes = 1;
+        faults.sync_delay_ms = 20;
+    }
+    let res = call(&mut sim, leader, set_req(b"k1", b"v"));
+    assert!(!res.get_set().get_success());
+    assert!(res.get_set().get_err().contains("injected write failure"));
+
+    // the next write goes through, just slowly
+    let res = call(&mut sim, leader, set_req(b"k2", b"v"));
+    assert!(res.get_set().get_success());
+    assert!(sim.nodes.get(&leader).unwrap().server.db.get(b"k2").is_some());
+}
+
+#[test]
+fn frozen_leader_clock_is_replaced() {
+    let mut sim = SimCluster::new("frozen_clock", 3);
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    let old = sim.leaders()[0];
+    let old_term = sim.nodes.get(&old).unwrap().server.state.term().unwrap();
+    sim.nodes.get_mut(&old).unwrap().faults.frozen_clock = true;
+
+    // its lease never runs out by its own clock, so it never renews it,
+    // and the others move on without it
+    let mut replaced = false;
+    for _ in 0..3000 {
+        sim.step();
+        replaced = sim.nodes.iter().any(|(id, n)| {
+            *id != old && n.server.state.is_leader() &&
+            n.server.state.term().unwrap() > old_term
+        });
+        if replaced {
+            break;
+        }
+    }
+    assert!(replaced);
+}
+
+#[test]
+fn duplicated_and_dropped_peer_messages() {
+    let mut sim = SimCluster::new("dup_drop", 3);
+    for (port, node) in sim.nodes.iter_mut() {
+        node.faults.duplicate_rate = 0.3;
+        if *port == 2 {
+            node.faults.drop_rate = 0.3;
+        }
+    }
+    while sim.leaders().is_empty() {
+        sim.step();
+    }
+    for _ in 0..50 {
+        sim.step();
+    }
+    for i in 0..5 {
+        let leader = sim.leaders()[0];
+        let key = format!("k{}", i);
+        let res = call(&mut sim, leader, set_req(key.as_bytes(), b"v"));
+        assert!(res.get_set().get_success());
+    }
+}
+
+#[test]
+fn acknowledged_writes_survive_partitions() {
+    let mut sim = SimCluster::new("partitions", 5);
+    let mut acked = BTreeMap::new();
+    let mut written = 0;
+    let mut write = |sim: &mut SimCluster,
+                     leader: u16,
+                     acked: &mut BTreeMap<Vec<u8>, Vec<u8>>| {
+        for _ in 0..3 {
+            let key = format!("k{}", written).into_bytes();
+            let value = format!("v{}", written).into_bytes();
+            written += 1;
+            let res = call(sim, leader, set_req(&key, &value));
+            assert!(res.get_set().get_success());
+            acked.insert(key, value);
+        }
+    };
+
+    let mut leader = await_leader(&mut sim, &[], 0);
+    write(&mut sim, leader, &mut acked);
+
+    // a leader stuck in the minority can't get anything committed
+    let minority = [leader, (leader + 1) % 5];
+    sim.split(&minority);
+    let res = try_call(&mut sim, leader, set_req(b"lost", b"v"), 300);
+    assert!(res.map(|res| !res.get_set().get_success()).unwrap_or(true));
+    let term = term_of(&sim, leader);
+    leader = await_leader(&mut sim, &minority, term);
+    write(&mut sim, leader, &mut acked);
+    sim.heal();
+
+    // nor can a leader cut off from everyone
+    let term = term_of(&sim, leader);
+    sim.isolate(leader);
+    let old = leader;
+    leader = await_leader(&mut sim, &[old], term);
+    write(&mut sim, leader, &mut acked);
+    sim.heal();
+
+    // and one that stops for a while comes back to find it's been replaced
+    let term = term_of(&sim, leader);
+    sim.pause_node(leader).unwrap();
+    let old = leader;
+    leader = await_leader(&mut sim, &[old], term);
+    write(&mut sim, leader, &mut acked);
+    sim.heal();
+
+    for _ in 0..500 {
+        sim.step();
+    }
+    for (key, value) in acked.iter() {
+        let res = call(&mut sim, leader, get_req(key));
+        assert_eq!(res.get_get().get_value(), &value[..]);
+        for (id, n) in sim.nodes.iter() {
+            assert!(n.server.db.get(key).is_some(),
+                    "node {} lost {:?}",
+                    id,
+                    key);
+        }
+    }
+    assert!(sim.nodes.values().all(|n| n.server.db.get(b"lost").is_none()));
+}
+
+#[test]
+fn cluster_cut_to_pieces_recovers_once_a_majority_can_talk() {
+    let mut sim = SimCluster::new("partition_all", 3);
+    sim.check_invariants(true);
+    let leader = await_leader(&mut sim, &[], 0);
+
+    // with nobody able to reach anybody, nothing gets committed
+    sim.partition_all();
+    let res = try_call(&mut sim, leader, set_req(b"lost", b"v"), 300);
+    assert!(res.map(|res| !res.get_set().get_success()).unwrap_or(true),
+            "seed {}",
+            sim.seed());
+
+    // but two of the three are a majority again once they can talk
+    let (a, b) = ((leader + 1) % 3, (leader + 2) % 3);
+    sim.unpartition_two_nodes(a, b).unwrap();
+    assert!(sim.unpartition_two_nodes(a, b).is_err());
+    let term = term_of(&sim, leader);
+    let leader = await_leader(&mut sim, &[leader], term);
+    let res = call(&mut sim, leader, set_req(b"k", b"v"));
+    assert!(res.get_set().get_success(), "seed {}", sim.seed());
+}
+
+#[test]
+fn traced_writes_record_spans_on_every_node() {
+    let sink = Arc::new(Spans(Mutex::new(vec![])));
+    set_trace_sink(sink.clone());
+
+    let mut sim = SimCluster::new("tracing", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    let mut trace = TraceContext::new();
+    trace.set_trace_id(0x7ace);
+    trace.set_parent_span_id(7);
+    let mut req = set_req(b"k", b"v");
+    req.set_trace(trace);
+    assert!(call(&mut sim, leader, req).get_set().get_success());
+    // give the followers time to learn it
+    for _ in 0..500 {
+        sim.step();
+    }
+
+    let spans: Vec<Span> = sink.0
+                               .lock()
+                               .unwrap()
+                               .iter()
+                               .filter(|span| span.trace_id == 0x7ace)
+                               .cloned()
+                               .collect();
+    assert!(spans.iter().all(|span| span.parent_span_id == Some(7)));
+    for (id, node) in sim.nodes.iter() {
+        let names: Vec<&str> = spans.iter()
+                                    .filter(|span| span.node == node.server.id)
+                                    .map(|span| span.name)
+                                    .collect();
+        if *id == leader {
+            assert_eq!(names,
+                       vec!["queue", "parse", "route", "replicate", "apply",
+                            "reply"]);
+        } else {
+            assert_eq!(names, vec!["replicate", "apply"]);
+        }
+    }
+}
+
+#[test]
+fn backups_are_consistent_and_incremental() {
+    let dir = "_rasputin_test_sim_backup";
+    let _ = fs::remove_dir_all(dir);
+    let mut sim = SimCluster::new("backup", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    for i in 0..10 {
+        let key = format!("k{}", i).into_bytes();
+        assert!(call(&mut sim, leader, set_req(&key, b"v"))
+                    .get_set()
+                    .get_success());
+    }
+
+    // any node can back itself up, leader or not
+    let follower = *sim.nodes.keys().find(|id| **id != leader).unwrap();
+    for _ in 0..200 {
+        sim.step();
+    }
+    let mut backup = BackupReq::new();
+    backup.set_dir(dir.to_string());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_backup(backup);
+    let res = call(&mut sim, follower, req);
+    assert!(res.get_backup().get_success(),
+            res.get_backup().get_err().to_string());
+
+    let manifest = read_manifest(dir).unwrap();
+    assert_eq!(manifest.len(), 1);
+    assert_eq!(manifest[0].node, sim.nodes.get(&follower).unwrap().server.id);
+    let mut keys = vec![];
+    let file = File::open(format!("{}/{}", dir, manifest[0].file)).unwrap();
+    let header = read_data(file, |k, _| {
+                     keys.push(k);
+                     Ok(())
+                 })
+                 .unwrap();
+    assert_eq!(header.txid, manifest[0].txid);
+    assert_eq!(header.keys, 10);
+    // as of an applied txid that covers every write
+    assert!(header.txid >= 10);
+    assert_eq!(keys[3], b"k3".to_vec());
+
+    // the next one only has what changed since, deletes included
+    assert!(call(&mut sim, leader, set_req(b"k3", b"v2"))
+                .get_set()
+                .get_success());
+    assert!(call(&mut sim, leader, set_req(b"new", b"v"))
+                .get_set()
+                .get_success());
+    let mut del = DelReq::new();
+    del.set_key(b"k5".to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_del(del);
+    assert!(call(&mut sim, leader, req).get_del().get_success());
+    for _ in 0..200 {
+        sim.step();
+    }
+    let mut backup = BackupReq::new();
+    backup.set_dir(dir.to_string());
+    backup.set_incremental(true);
+    let mut req = CliReq::new();
+    req.set_req_id(2);
+    req.set_backup(backup);
+    assert!(call(&mut sim, follower, req).get_backup().get_success());
+    let manifest = read_manifest(dir).unwrap();
+    assert_eq!(manifest.len(), 2);
+    assert_eq!(manifest[1].since, manifest[0].txid);
+    let mut changed = vec![];
+    let file = File::open(format!("{}/{}", dir, manifest[1].file)).unwrap();
+    read_data(file, |k, v| {
+        changed.push((k, v.is_some()));
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(changed,
+               vec![(b"k3".to_vec(), true),
+                    (b"k5".to_vec(), false),
+                    (b"new".to_vec(), true)]);
+
+    // a backup needs somewhere to go
+    let mut req = CliReq::new();
+    req.set_req_id(3);
+    req.set_backup(BackupReq::new());
+    assert!(!call(&mut sim, follower, req).get_backup().get_success());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn watches_follow_applied_changes() {
+    let mut sim = SimCluster::new("watch", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    assert!(call(&mut sim, leader, set_req(b"w/a", b"1"))
+                .get_set()
+                .get_success());
+    assert!(call(&mut sim, leader, set_req(b"other", b"1"))
+                .get_set()
+                .get_success());
+    for _ in 0..200 {
+        sim.step();
+    }
+
+    // a follower replays what it's applied, then keeps up as it applies more
+    let follower = *sim.nodes.keys().find(|id| **id != leader).unwrap();
+    let mut watch = WatchReq::new();
+    watch.set_key(b"w/".to_vec());
+    watch.set_recursive(true);
+    watch.set_historical(true);
+    watch.set_last_txid(0);
+    watch.set_consumer("indexer".to_string());
+    let mut req = CliReq::new();
+    req.set_req_id(7);
+    req.set_watch(watch.clone());
+    sim.client_request(follower, &*req.write_to_bytes().unwrap());
+    for _ in 0..10 {
+        sim.step();
+    }
+    let replies = sim.take_client_responses();
+    assert!(parse_res(&replies[0]).get_watch().get_success());
+    assert_eq!(changes(&replies), vec![(b"w/a".to_vec(), MutationType::KVSET)]);
+
+    // a CAS that fails changes nothing, so it isn't sent
+    sim.client_request(leader,
+                       &*cas_req(b"w/a", b"nope", b"2").write_to_bytes()
+                                                       .unwrap());
+    sim.client_request(leader,
+                       &*cas_req(b"w/a", b"1", b"2").write_to_bytes().unwrap());
+    let mut del = DelReq::new();
+    del.set_key(b"w/a".to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_del(del);
+    sim.client_request(leader, &*req.write_to_bytes().unwrap());
+    sim.client_request(leader,
+                       &*set_req(b"other", b"2").write_to_bytes().unwrap());
+    for _ in 0..200 {
+        sim.step();
+    }
+    let replies = sim.take_client_responses();
+    assert_eq!(changes(&replies),
+               vec![(b"w/a".to_vec(), MutationType::KVSET),
+                    (b"w/a".to_vec(), MutationType::KVDEL)]);
+
+    // once the client's gone, the log is held for its consumer to resume
+    let sent = sim.nodes.get(&follower).unwrap().server.watches[0].sent;
+    sim.close_client(follower);
+    {
+        let server = &mut sim.nodes.get_mut(&follower).unwrap().server;
+        assert!(server.watches.is_empty());
+        let now = server.clock.now();
+        assert_eq!(server.horizon.horizon(now, sent + 10), sent + 1);
+    }
+
+    // and the log replays the same changes, still without the failed CAS
+    let mut req = CliReq::new();
+    req.set_req_id(8);
+    req.set_watch(watch);
+    sim.client_request(follower, &*req.write_to_bytes().unwrap());
+    for _ in 0..10 {
+        sim.step();
+    }
+    assert_eq!(changes(&sim.take_client_responses()),
+               vec![(b"w/a".to_vec(), MutationType::KVSET),
+                    (b"w/a".to_vec(), MutationType::KVSET),
+                    (b"w/a".to_vec(), MutationType::KVDEL)]);
+}
+
+#[test]
+fn mirrors_take_no_writes_until_promoted() {
+    let mut sim = SimCluster::new("mirror", 3);
+    for (_, node) in sim.nodes.iter_mut() {
+        node.server.config.mirror_from = Some("10.0.0.1:8888".to_string());
+    }
+    let leader = await_leader(&mut sim, &[], 0);
+    let res = call(&mut sim, leader, set_req(b"k", b"mine"));
+    assert!(res.has_error(), "{:?}", res);
+
+    // what the other cluster applied comes through, offsets and all
+    let mut changes = vec![];
+    for (txid, key) in [(40, b"k"), (41, b"j")].iter() {
+        let mut version = Version::new();
+        version.set_txid(*txid);
+        version.set_term(3);
+        let mut change = Mutation::new();
+        change.set_field_type(MutationType::KVSET);
+        change.set_version(version);
+        change.set_key(key.to_vec());
+        change.set_value(b"theirs".to_vec());
+        changes.push(change);
+    }
+    sim.nodes
+       .get_mut(&leader)
+       .unwrap()
+       .server
+       .handle(ServerMsg::Mirror(changes.clone()));
+    for _ in 0..200 {
+        sim.step();
+    }
+    for (_, node) in sim.nodes.iter() {
+        assert_eq!(mirrored_txid(&node.server.db).unwrap(), 41);
+    }
+    assert_eq!(call(&mut sim, leader, get_req(b"j")).get_get().get_value(),
+               b"theirs");
+
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_promote(PromoteReq::new());
+    let res = call(&mut sim, leader, req.clone());
+    assert!(res.get_promote().get_success(), "{:?}", res);
+    assert_eq!(res.get_promote().get_source_txid(), 41);
+    for _ in 0..200 {
+        sim.step();
+    }
+    assert!(sim.nodes.values().all(|node| node.server.promoted));
+
+    // now it's ours to write, and the other cluster's changes stop
+    assert!(call(&mut sim, leader, set_req(b"k", b"mine"))
+                .get_set()
+                .get_success());
+    sim.nodes
+       .get_mut(&leader)
+       .unwrap()
+       .server
+       .handle(ServerMsg::Mirror(changes));
+    for _ in 0..200 {
+        sim.step();
+    }
+    assert_eq!(call(&mut sim, leader, get_req(b"k")).get_get().get_value(),
+               b"mine");
+    assert!(!call(&mut sim, leader, req).get_promote().get_success());
+    let res = call(&mut sim, leader, set_req(b"\x00mirror/offset", b"0"));
+    assert!(res.has_error(), "{:?}", res);
+}
+
+#[test]
+fn bulk_loads_ingest_beneath_the_log() {
+    let mut sim = SimCluster::new("ingest", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    assert!(call(&mut sim, leader, set_req(b"a", b"before"))
+                .get_set()
+                .get_success());
+
+    let mut data = vec![];
+    convert_for_ingest(&b"a\tloaded\nb\tloaded\n"[..], &mut data).unwrap();
+    let ids: Vec<u16> = sim.nodes.keys().cloned().collect();
+    for id in &ids {
+        let res = call(&mut sim, *id, stage_req("load-1", &data));
+        assert!(res.get_stage().get_success(), "{:?}", res);
+    }
+    let res = call(&mut sim, leader, ingest_req("load-1"));
+    assert!(res.get_ingest().get_success(), "{:?}", res);
+    assert_eq!(res.get_ingest().get_keys(), 2);
+    // writes after the fence land on top of what it loaded
+    assert!(call(&mut sim, leader, set_req(b"b", b"after"))
+                .get_set()
+                .get_success());
+    for _ in 0..200 {
+        sim.step();
+    }
+    assert_eq!(call(&mut sim, leader, get_req(b"a")).get_get().get_value(),
+               b"loaded");
+    assert_eq!(call(&mut sim, leader, get_req(b"b")).get_get().get_value(),
+               b"after");
+    for (_, node) in sim.nodes.iter() {
+        assert!(node.server.db.get(b"a").is_some());
+        assert!(node.server.db.get(b"\x00ingest/load-1").is_some());
+        let staged = format!("{}/load-1", node.server.ingest_dir);
+        assert!(fs::metadata(staged).is_err());
+    }
+    // it's gone once ingested, and nobody ingests what the leader lacks
+    assert!(!call(&mut sim, leader, ingest_req("load-1"))
+                 .get_ingest()
+                 .get_success());
+    assert!(call(&mut sim, leader, set_req(b"\x00ingest/x", b"")).has_error());
+
+    // a replica without its copy loads nothing
+    let mut data = vec![];
+    convert_for_ingest(&b"c\tloaded\n"[..], &mut data).unwrap();
+    assert!(call(&mut sim, leader, stage_req("load-2", &data))
+                .get_stage()
+                .get_success());
+    assert!(call(&mut sim, leader, ingest_req("load-2"))
+                .get_ingest()
+                .get_success());
+    for _ in 0..200 {
+        sim.step();
+    }
+    for (id, node) in sim.nodes.iter() {
+        assert_eq!(node.server.db.get(b"c").is_some(), *id == leader);
+    }
+}
+
+#[test]
+fn range_deletes_are_one_entry() {
+    let mut sim = SimCluster::new("delete_range", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    for key in &[b"a", b"b", b"c", b"d"] {
+        assert!(call(&mut sim, leader, set_req(*key, b"v"))
+                    .get_set()
+                    .get_success());
+    }
+
+    let res = call(&mut sim, leader, delete_range_req(b"b", b"d"));
+    assert!(res.get_delete_range().get_success(), "{:?}", res);
+    assert_eq!(res.get_delete_range().get_deleted(), 2);
+    // the whole range took a single txid
+    let txid = res.get_delete_range().get_txid();
+    let next = call(&mut sim, leader, set_req(b"e", b"v"));
+    assert_eq!(next.get_set().get_txid(), txid + 1);
+    for _ in 0..200 {
+        sim.step();
+    }
+    for (_, node) in sim.nodes.iter() {
+        assert!(node.server.db.get(b"a").is_some());
+        assert!(node.server.db.get(b"b").is_none());
+        assert!(node.server.db.get(b"c").is_none());
+        assert!(node.server.db.get(b"d").is_some());
+    }
+
+    // our own bookkeeping survives a range that spans it
+    for (_, node) in sim.nodes.iter() {
+        node.server.db.put(b"\x00mirror/offset", b"x").unwrap();
+    }
+    let res = call(&mut sim, leader, delete_range_req(b"\x00", b"b"));
+    assert_eq!(res.get_delete_range().get_deleted(), 1);
+    for _ in 0..200 {
+        sim.step();
+    }
+    for (_, node) in sim.nodes.iter() {
+        assert!(node.server.db.get(b"a").is_none());
+        assert!(node.server.db.get(b"\x00mirror/offset").is_some());
+    }
+
+    let res = call(&mut sim, leader, delete_range_req(b"d", b"d"));
+    assert!(!res.get_delete_range().get_success());
+}
+
+#[test]
+fn updates_apply_on_every_replica() {
+    let mut sim = SimCluster::new("update", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    let res = call(&mut sim,
+                   leader,
+                   update_req(b"log", UpdateOp::APPEND, b"a", 0));
+    assert!(res.get_update().get_success(), "{:?}", res);
+    assert!(!res.get_update().has_old_value());
+    let res = call(&mut sim,
+                   leader,
+                   update_req(b"log", UpdateOp::APPEND, b"bc", 0));
+    assert_eq!(res.get_update().get_length(), 3);
+    assert_eq!(res.get_update().get_old_value(), b"a");
+
+    let res = call(&mut sim,
+                   leader,
+                   update_req(b"log", UpdateOp::SETRANGE, b"XY", 4));
+    assert_eq!(res.get_update().get_length(), 6);
+    let res = call(&mut sim,
+                   leader,
+                   update_req(b"log", UpdateOp::GETSET, b"fresh", 0));
+    assert_eq!(res.get_update().get_old_value(), b"abc\x00XY");
+    assert!(call(&mut sim,
+                 leader,
+                 update_req(b"blob", UpdateOp::SETRANGE, b"!", 2))
+                .get_update()
+                .get_success());
+    for _ in 0..200 {
+        sim.step();
+    }
+    for (_, node) in sim.nodes.iter() {
+        assert!(node.server.db.get(b"log").is_some());
+        assert!(node.server.db.get(b"blob").is_some());
+    }
+    assert_eq!(call(&mut sim, leader, get_req(b"log"))
+                   .get_get()
+                   .get_value(),
+               b"fresh");
+    assert_eq!(call(&mut sim, leader, get_req(b"blob"))
+                   .get_get()
+                   .get_value(),
+               b"\x00\x00!");
+
+    // nothing runs the value out past the limit, and bookkeeping is ours
+    let res = call(&mut sim,
+                   leader,
+                   update_req(b"blob", UpdateOp::SETRANGE, b"!", 1 << 40));
+    assert!(!res.get_update().get_success());
+    assert!(call(&mut sim,
+                 leader,
+                 update_req(b"\x00mirror/x", UpdateOp::APPEND, b"!", 0))
+                .has_error());
+}
+
+#[test]
+fn sequential_puts_number_keys_in_order() {
+    let mut sim = SimCluster::new("sequential", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    let mut keys = vec![];
+    for value in &[b"a", b"b", b"c"] {
+        let res = call(&mut sim, leader, sequential_req(b"queue/", *value));
+        assert!(res.get_put_sequential().get_success(), "{:?}", res);
+        keys.push(res.get_put_sequential().get_key().to_vec());
+    }
+    assert_eq!(keys[0], b"queue/00000000000000000001".to_vec());
+    assert!(keys[0] < keys[1] && keys[1] < keys[2]);
+    assert_eq!(call(&mut sim, leader, get_req(&keys[1]))
+                   .get_get()
+                   .get_value(),
+               b"b");
+
+    // a number once handed out isn't handed out again
+    assert!(call(&mut sim, leader, delete_range_req(b"queue/", b"queue0"))
+                .get_delete_range()
+                .get_success());
+    let res = call(&mut sim, leader, sequential_req(b"queue/", b"d"));
+    assert_eq!(res.get_put_sequential().get_key(),
+               b"queue/00000000000000000004");
+    for _ in 0..200 {
+        sim.step();
+    }
+    for (_, node) in sim.nodes.iter() {
+        assert!(node.server.db.get(b"queue/00000000000000000004").is_some());
+        assert!(node.server.db.get(b"\x00sequence/queue/").is_some());
+    }
+    assert!(call(&mut sim, leader, set_req(b"\x00sequence/queue/", b""))
+                .has_error());
+}
+
+#[test]
+fn transactions_commit_only_if_their_reads_hold() {
+    let mut sim = SimCluster::new("transactions", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    assert!(call(&mut sim, leader, set_req(b"from", b"10"))
+                .get_set()
+                .get_success());
+
+    let res = call(&mut sim,
+                   leader,
+                   txn_req(&[(b"from", Some(b"10")), (b"to", None)],
+                           &[(b"from", Some(b"7")), (b"to", Some(b"3"))]));
+    assert!(res.get_txn().get_success(), "{:?}", res);
+    assert_eq!(call(&mut sim, leader, get_req(b"to")).get_get().get_value(),
+               b"3");
+
+    // from has moved on since this read it, so none of it is written
+    let res = call(&mut sim,
+                   leader,
+                   txn_req(&[(b"from", Some(b"10"))],
+                           &[(b"to", None), (b"other", Some(b"x"))]));
+    assert!(!res.get_txn().get_success());
+    assert_eq!(res.get_txn().get_conflict(), b"from");
+    assert!(call(&mut sim, leader, get_req(b"to")).get_get().get_success());
+    assert!(!call(&mut sim, leader, get_req(b"other")).get_get().get_success());
+
+    let res = call(&mut sim,
+                   leader,
+                   txn_req(&[(b"from", Some(b"7"))], &[(b"from", None)]));
+    assert!(res.get_txn().get_success(), "{:?}", res);
+    for _ in 0..200 {
+        sim.step();
+    }
+    for (_, node) in sim.nodes.iter() {
+        assert!(node.server.db.get(b"from").is_none());
+        assert!(node.server.db.get(b"to").is_some());
+    }
+
+    // every key written is checked, not just the first
+    assert!(call(&mut sim,
+                 leader,
+                 txn_req(&[], &[(b"to", None), (b"\x00mirror/x", None)]))
+                .has_error());
+}
+
+#[test]
+fn full_disks_refuse_writes_but_serve_reads() {
+    let mut sim = SimCluster::new("disk_full", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    assert!(call(&mut sim, leader, set_req(b"k", b"v"))
+                .get_set()
+                .get_success());
+    let full = DiskSpace {
+        free_bytes: 1,
+        total_bytes: 100,
+    };
+    let ids: Vec<u16> = sim.nodes.keys().cloned().collect();
+    // a follower past its watermark still takes the leader's appends
+    let follower = *ids.iter().find(|id| **id != leader).unwrap();
+    sim.nodes.get_mut(&follower).unwrap().server.handle(ServerMsg::Disk(full));
+    assert!(call(&mut sim, leader, set_req(b"k2", b"v"))
+                .get_set()
+                .get_success());
+
+    sim.nodes.get_mut(&leader).unwrap().server.handle(ServerMsg::Disk(full));
+    let res = call(&mut sim, leader, set_req(b"k3", b"v"));
+    assert_eq!(res.get_error().get_code(), ErrorCode::DISK_FULL, "{:?}", res);
+    assert!(call(&mut sim,
+                 leader,
+                 update_req(b"k", UpdateOp::APPEND, b"!", 0)).has_error());
+    assert_eq!(call(&mut sim, leader, get_req(b"k")).get_get().get_value(),
+               b"v");
+    assert!(call(&mut sim, leader, delete_range_req(b"k2", b"k3"))
+                .get_delete_range()
+                .get_success());
+
+    let freed = DiskSpace {
+        free_bytes: 50,
+        total_bytes: 100,
+    };
+    sim.nodes.get_mut(&leader).unwrap().server.handle(ServerMsg::Disk(freed));
+    assert!(call(&mut sim, leader, set_req(b"k3", b"v"))
+                .get_set()
+                .get_success());
+}
+
+#[test]
+fn storage_quotas_refuse_writes_but_not_deletes() {
+    let mut sim = SimCluster::new("storage_quota", 3);
+    for (_, node) in sim.nodes.iter_mut() {
+        node.server.config.max_bytes = Some(100);
+    }
+    let leader = await_leader(&mut sim, &[], 0);
+    assert!(call(&mut sim, leader, set_req(b"big", &[0; 200]))
+                .get_set()
+                .get_success());
+    let res = call(&mut sim, leader, set_req(b"k", b"v"));
+    assert_eq!(res.get_error().get_code(),
+               ErrorCode::QUOTA_EXCEEDED,
+               "{:?}",
+               res);
+    assert_eq!(call(&mut sim, leader, get_req(b"big"))
+                   .get_get()
+                   .get_value()
+                   .len(),
+               200);
+    assert!(call(&mut sim, leader, delete_range_req(b"big", b"big\x00"))
+                .get_delete_range()
+                .get_success());
+    assert!(call(&mut sim, leader, set_req(b"k", b"v"))
+                .get_set()
+                .get_success());
+}
+
+#[test]
+fn ranges_far_behind_push_back_on_writes() {
+    let mut sim = SimCluster::new("busy", 3);
+    for (_, node) in sim.nodes.iter_mut() {
+        node.server.config.busy_unlearned_txids = 2;
+    }
+    let leader = await_leader(&mut sim, &[], 0);
+    assert!(call(&mut sim, leader, set_req(b"k", b"v"))
+                .get_set()
+                .get_success());
+
+    // cut off from its followers, nothing the leader takes is committed
+    sim.isolate(leader);
+    for key in &[b"a", b"b"] {
+        assert!(try_call(&mut sim, leader, set_req(*key, b"v"), 5).is_none());
+    }
+    let res = call(&mut sim, leader, set_req(b"c", b"v"));
+    assert_eq!(res.get_error().get_code(), ErrorCode::BUSY, "{:?}", res);
+    assert_eq!(res.get_error().get_retry_after_ms(), 100);
+    // reads are still served
+    assert_eq!(call(&mut sim, leader, get_req(b"k")).get_get().get_value(),
+               b"v");
+    sim.heal();
+
+    // and once it's caught up, or been replaced, writes are taken again
+    for _ in 0..500 {
+        sim.step();
+    }
+    let term = term_of(&sim, leader);
+    let leader = await_leader(&mut sim, &[], term - 1);
+    assert!(call(&mut sim, leader, set_req(b"c", b"v"))
+                .get_set()
+                .get_success());
+}
+
+#[test]
+fn storage_stats_follow_what_replicas_hold() {
+    let mut sim = SimCluster::new("storage_stats", 3);
+    let leader = await_leader(&mut sim, &[], 0);
+    for key in &[b"a", b"b", b"c"] {
+        assert!(call(&mut sim, leader, set_req(*key, b"value"))
+                    .get_set()
+                    .get_success());
+    }
+    assert!(call(&mut sim,
+                 leader,
+                 update_req(b"a", UpdateOp::APPEND, b"more", 0))
+                .get_update()
+                .get_success());
+    assert!(call(&mut sim, leader, delete_range_req(b"b", b"c"))
+                .get_delete_range()
+                .get_success());
+    assert_eq!(call(&mut sim, leader, get_req(b"a")).get_get().get_value(),
+               b"valuemore");
+    for _ in 0..200 {
+        sim.step();
+    }
+    for (id, node) in sim.nodes.iter() {
+        let usage = node.server.stats.usage(b"a").unwrap().clone();
+        // kept up as writes were applied, it matches counting afresh
+        let mut counted = StorageStats::new();
+        counted.measure(&node.server.db);
+        let recount = counted.usage(b"a").unwrap();
+        assert_eq!(usage.keys, 2);
+        assert_eq!((usage.keys, usage.live_bytes),
+                   (recount.keys, recount.live_bytes));
+        assert_eq!(usage.writes, 5);
+        assert_eq!(usage.reads, if *id == leader { 1 } else { 0 });
+    }
+}
+
+// The keys and kinds of the changes sent to watches among replies.
+fn changes(replies: &[Envelope]) -> Vec<(Vec<u8>, MutationType)> {
+    replies.iter()
+           .map(parse_res)
+           .filter(|res| res.has_watch())
+           .flat_map(|res| {
+               res.get_watch()
+                  .get_history()
+                  .iter()
+                  .map(|m| (m.get_key().to_vec(), m.get_field_type()))
+                  .collect::<Vec<_>>()
+           })
+           .collect()
+}
+
+fn parse_res(env: &Envelope) -> CliRes {
+    protobuf::parse_from_bytes(env.msg.bytes()).unwrap()
+}
+
+// Keeps every span recorded, for tests to look through.
+struct Spans(Mutex<Vec<Span>>);
+
+impl TraceSink for Spans {
+    fn record(&self, span: Span) {
+        self.0.lock().unwrap().push(span);
+    }
+}
+
+fn set_req(key: &[u8], value: &[u8]) -> CliReq {
+    let mut set = SetReq::new();
+    set.set_key(key.to_vec());
+    set.set_value(value.to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_set(set);
+    req
+}
+
+fn cas_req(key: &[u8], old_value: &[u8], new_value: &[u8]) -> CliReq {
+    let mut cas = CASReq::new();
+    cas.set_key(key.to_vec());
+    cas.set_old_value(old_value.to_vec());
+    cas.set_new_value(new_value.to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_cas(cas);
+    req
+}
+
+fn stage_req(file: &str, data: &[u8]) -> CliReq {
+    let mut stage = StageReq::new();
+    stage.set_file(file.to_string());
+    stage.set_offset(0);
+    stage.set_data(data.to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_stage(stage);
+    req
+}
+
+fn ingest_req(file: &str) -> CliReq {
+    let mut ingest = IngestReq::new();
+    ingest.set_file(file.to_string());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_ingest(ingest);
+    req
+}
+
+fn delete_range_req(start: &[u8], end: &[u8]) -> CliReq {
+    let mut range = DeleteRangeReq::new();
+    range.set_start(start.to_vec());
+    range.set_end(end.to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_delete_range(range);
+    req
+}
+
+fn update_req(key: &[u8], op: UpdateOp, value: &[u8], offset: u64) -> CliReq {
+    let mut update = UpdateReq::new();
+    update.set_key(key.to_vec());
+    update.set_op(op);
+    update.set_value(value.to_vec());
+    update.set_offset(offset);
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_update(update);
+    req
+}
+
+fn sequential_req(prefix: &[u8], value: &[u8]) -> CliReq {
+    let mut sequential = PutSequentialReq::new();
+    sequential.set_prefix(prefix.to_vec());
+    sequential.set_value(value.to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_put_sequential(sequential);
+    req
+}
+
+fn txn_req(reads: &[(&[u8], Option<&[u8]>)],
+           writes: &[(&[u8], Option<&[u8]>)])
+           -> CliReq {
+    let keys = |keys: &[(&[u8], Option<&[u8]>)]| {
+        keys.iter()
+            .map(|&(key, value)| {
+                let mut txn_key = TxnKey::new();
+                txn_key.set_key(key.to_vec());
+                if let Some(value) = value {
+                    txn_key.set_value(value.to_vec());
+                }
+                txn_key
+            })
+            .collect()
+    };
+    let mut txn = TxnReq::new();
+    txn.set_reads(RepeatedField::from_vec(keys(reads)));
+    txn.set_writes(RepeatedField::from_vec(keys(writes)));
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_txn(txn);
+    req
+}
+
+fn auth_req(token: &str) -> CliReq {
+    let mut auth = AuthReq::new();
+    auth.set_token(token.to_string());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_auth(auth);
+    req
+}
+
+fn get_req(key: &[u8]) -> CliReq {
+    let mut get = GetReq::new();
+    get.set_key(key.to_vec());
+    let mut req = CliReq::new();
+    req.set_req_id(1);
+    req.set_get(get);
+    req
+}
+
+// Sends a client request to a node and waits for its reply.
+fn call(sim: &mut SimCluster, node: u16, req: CliReq) -> CliRes {
+    match try_call(sim, node, req, 200) {
+        Some(res) => res,
+        None => panic!("no response from node {}", node),
+    }
+}
+
+fn try_call(sim: &mut SimCluster,
+            node: u16,
+            req: CliReq,
+            steps: usize)
+            -> Option<CliRes> {
+    sim.take_client_responses();
+    sim.client_request(node, &*req.write_to_bytes().unwrap());
+    for _ in 0..steps {
+        sim.step();
+        if let Some(env) = sim.take_client_responses().pop() {
+            return Some(protobuf::parse_from_bytes(env.msg.bytes()).unwrap());
+        }
+    }
+    None
+}
+
+// Steps until a node outside of avoid leads in a term after the given
+// one, returning it.
+fn await_leader(sim: &mut SimCluster, avoid: &[u16], after_term: u64) -> u16 {
+    for _ in 0..5000 {
+        sim.step();
+        let leader = sim.nodes
+                        .iter()
+                        .filter(|&(id, n)| {
+                            !avoid.contains(id) && n.server.state.is_leader() &&
+                            n.server.state.term().unwrap() > after_term
+                        })
+                        .map(|(id, _)| *id)
+                        .next();
+        if let Some(leader) = leader {
+            // let the followers hear of it
+            for _ in 0..50 {
+                sim.step();
+            }
+            return leader;
+        }
+    }
+    panic!("no leader outside of {:?} after term {}", avoid, after_term);
+}
+
+fn term_of(sim: &SimCluster, node: u16) -> u64 {
+    sim.nodes.get(&node).unwrap().server.state.term().unwrap_or(0)
+}
+
 #[test]
 fn leader_append_only() {
 